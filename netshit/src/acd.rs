@@ -0,0 +1,172 @@
+//! IPv4 address conflict detection (RFC 5227): probing for an address
+//! before using it, then announcing that we have
+use crate::address::InterfaceAddress;
+use crate::eth::Mac6;
+use crate::layer3::ArpPacket;
+use std::hash::{BuildHasher, RandomState};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Longest random delay before the first probe
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u8 = 3;
+/// Probes are spaced between these, at random
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+/// Delay after the last probe before claiming the address
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// Shortest time between defending an address in use
+pub const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Something that happened to an address being, or already, assigned
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AcdEvent {
+    /// Nobody else had the address, so it's now ours
+    Claimed(Ipv4Addr),
+    /// Another host, at `mac`, is using or probing for the address
+    Conflict { address: Ipv4Addr, mac: Mac6 },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum State {
+    /// This many probes sent
+    Probing(u8),
+    /// This many announcements sent; the address is ours
+    Announcing(u8),
+}
+
+/// Detection for one address: probes, then announcements
+#[derive(Debug)]
+pub struct Probe {
+    address: InterfaceAddress,
+    mac: Mac6,
+    state: State,
+    due: Instant,
+    random: RandomState,
+}
+
+impl Probe {
+    /// Start probing for `address` from `mac`
+    pub fn new(address: InterfaceAddress, mac: Mac6, now: Instant) -> Self {
+        let mut probe = Self {
+            address,
+            mac,
+            state: State::Probing(0),
+            due: now,
+            random: RandomState::new(),
+        };
+        probe.due = now + probe.delay(Duration::ZERO, PROBE_WAIT);
+        probe
+    }
+
+    pub const fn address(&self) -> InterfaceAddress {
+        self.address
+    }
+
+    /// Whether probing found no conflict, so the address may be used
+    pub const fn is_claimed(&self) -> bool {
+        matches!(self.state, State::Announcing(_))
+    }
+
+    /// When [Probe::poll] next has something to send, or `None` once
+    /// everything has been
+    pub fn next_deadline(&self) -> Option<Instant> {
+        (self.state != State::Announcing(ANNOUNCE_NUM)).then_some(self.due)
+    }
+
+    /// The probe or announcement due by `now`, if any
+    pub fn poll(&mut self, now: Instant) -> Option<ArpPacket> {
+        if self.next_deadline()? > now {
+            return None;
+        }
+        let address = self.address.address;
+        match self.state {
+            State::Probing(sent) if sent < PROBE_NUM => {
+                self.state = State::Probing(sent + 1);
+                self.due = now
+                    + if sent + 1 < PROBE_NUM {
+                        self.delay(PROBE_MIN, PROBE_MAX)
+                    } else {
+                        ANNOUNCE_WAIT
+                    };
+                Some(ArpPacket::probe(address, self.mac))
+            }
+            State::Probing(_) => Some(self.announce(0, now)),
+            State::Announcing(sent) => Some(self.announce(sent, now)),
+        }
+    }
+
+    fn announce(&mut self, sent: u8, now: Instant) -> ArpPacket {
+        self.state = State::Announcing(sent + 1);
+        self.due = now + ANNOUNCE_INTERVAL;
+        ArpPacket::gratuitous(self.address.address, self.mac)
+    }
+
+    /// Whether `arp`, received while probing, shows someone else has or
+    /// wants the address
+    pub fn conflicts(&self, arp: &ArpPacket) -> bool {
+        let address = self.address.address;
+        let (sender, mac) = arp.sender();
+        if self.is_claimed() || mac == self.mac {
+            return false;
+        }
+        // Someone using it, or probing for it at the same time
+        sender == address || (sender.is_unspecified() && arp.target().0 == address)
+    }
+
+    /// A random delay between `min` and `max`
+    fn delay(&self, min: Duration, max: Duration) -> Duration {
+        let spread = u64::try_from((max - min).as_millis()).unwrap_or(u64::MAX);
+        if spread == 0 {
+            return min;
+        }
+        let random = self.random.hash_one((self.address.address, self.state));
+        min + Duration::from_millis(random % spread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: Mac6 = Mac6::new([2, 0, 0, 0, 0, 1]);
+    const OTHER: Mac6 = Mac6::new([2, 0, 0, 0, 0, 2]);
+
+    fn address() -> InterfaceAddress {
+        "192.168.0.1/24".parse().unwrap()
+    }
+
+    #[test]
+    fn probes_then_announces() {
+        let now = Instant::now();
+        let mut probe = Probe::new(address(), MAC, now);
+        assert!(probe.poll(now - Duration::from_millis(1)).is_none());
+        let mut sent = Vec::new();
+        while let Some(due) = probe.next_deadline() {
+            sent.push((probe.is_claimed(), probe.poll(due).unwrap()));
+        }
+        assert_eq!(sent.len(), usize::from(PROBE_NUM + ANNOUNCE_NUM));
+        for (claimed, arp) in &sent[..3] {
+            assert!(!claimed);
+            assert_eq!(arp.sender(), (Ipv4Addr::UNSPECIFIED, MAC));
+            assert_eq!(arp.target().0, address().address);
+        }
+        for (_, arp) in &sent[3..] {
+            assert_eq!(arp.sender(), (address().address, MAC));
+        }
+        assert!(probe.is_claimed());
+    }
+
+    #[test]
+    fn conflicts() {
+        let probe = Probe::new(address(), MAC, Instant::now());
+        let ip = address().address;
+        assert!(probe.conflicts(&ArpPacket::gratuitous(ip, OTHER)));
+        assert!(probe.conflicts(&ArpPacket::probe(ip, OTHER)));
+        // Our own probes, looped back
+        assert!(!probe.conflicts(&ArpPacket::probe(ip, MAC)));
+        assert!(!probe.conflicts(&ArpPacket::probe(Ipv4Addr::new(192, 168, 0, 2), OTHER)));
+    }
+}
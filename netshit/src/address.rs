@@ -0,0 +1,192 @@
+//! The IPv4 addresses an interface answers to, and which one to send from
+use crate::route::Ipv4Prefix;
+use anyhow::{Result, bail};
+use std::net::Ipv4Addr;
+
+/// An address assigned to an interface, with the length of its network's
+/// prefix
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InterfaceAddress {
+    pub address: Ipv4Addr,
+    pub prefix_length: u8,
+}
+
+impl InterfaceAddress {
+    pub fn new(address: Ipv4Addr, prefix_length: u8) -> Result<Self> {
+        if prefix_length > 32 {
+            bail!("Prefix length {prefix_length} is over 32");
+        }
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+
+    /// The network the address is on
+    pub fn network(&self) -> Ipv4Prefix {
+        Ipv4Prefix::new(self.address, self.prefix_length)
+            .expect("prefix length was checked on creation")
+    }
+
+    /// The network's directed broadcast address, if it has one (/31 and
+    /// /32 networks don't)
+    pub fn broadcast(&self) -> Option<Ipv4Addr> {
+        (self.prefix_length < 31)
+            .then(|| Ipv4Addr::from_bits(self.address.to_bits() | (u32::MAX >> self.prefix_length)))
+    }
+}
+
+impl std::fmt::Display for InterfaceAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+impl std::str::FromStr for InterfaceAddress {
+    type Err = anyhow::Error;
+
+    /// Accepts `a.b.c.d/n`, or a bare address as a /32
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((address, length)) => Self::new(address.parse()?, length.parse()?),
+            None => Self::new(s.parse()?, 32),
+        }
+    }
+}
+
+/// Every address of an interface, one of them primary
+///
+/// There's always at least one.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Addresses {
+    /// The primary address comes first
+    addresses: Vec<InterfaceAddress>,
+}
+
+impl Addresses {
+    pub fn new(primary: InterfaceAddress) -> Self {
+        Self {
+            addresses: vec![primary],
+        }
+    }
+
+    /// The address we send from when no other fits better
+    pub fn primary(&self) -> Ipv4Addr {
+        self.addresses[0].address
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InterfaceAddress> {
+        self.addresses.iter()
+    }
+
+    /// Whether `address` is one of ours
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        self.addresses.iter().any(|entry| entry.address == address)
+    }
+
+    /// Whether `address` is the directed broadcast address of one of our
+    /// networks
+    pub fn is_broadcast(&self, address: Ipv4Addr) -> bool {
+        self.addresses
+            .iter()
+            .any(|entry| entry.broadcast() == Some(address))
+    }
+
+    /// Add a secondary address, or change the prefix length of one we have
+    pub fn add(&mut self, address: InterfaceAddress) {
+        match self
+            .addresses
+            .iter_mut()
+            .find(|entry| entry.address == address.address)
+        {
+            Some(entry) => *entry = address,
+            None => self.addresses.push(address),
+        }
+    }
+
+    /// Remove an address, returning whether we had it
+    ///
+    /// Removing the primary address promotes the oldest secondary one; the
+    /// last address can't be removed.
+    pub fn remove(&mut self, address: Ipv4Addr) -> Result<bool> {
+        let Some(index) = self.position(address) else {
+            return Ok(false);
+        };
+        if self.addresses.len() == 1 {
+            bail!("Can't remove {address}, the only address");
+        }
+        self.addresses.remove(index);
+        Ok(true)
+    }
+
+    /// Make an address we already have the primary one
+    pub fn set_primary(&mut self, address: Ipv4Addr) -> Result<()> {
+        let Some(index) = self.position(address) else {
+            bail!("{address} isn't one of our addresses");
+        };
+        let primary = self.addresses.remove(index);
+        self.addresses.insert(0, primary);
+        Ok(())
+    }
+
+    /// The address to send to `destination` from: the first on the
+    /// destination's network, or else the primary one
+    pub fn source_for(&self, destination: Ipv4Addr) -> Ipv4Addr {
+        self.addresses
+            .iter()
+            .find(|entry| entry.network().contains(destination))
+            .map_or_else(|| self.primary(), |entry| entry.address)
+    }
+
+    fn position(&self, address: Ipv4Addr) -> Option<usize> {
+        self.addresses
+            .iter()
+            .position(|entry| entry.address == address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_address() -> Result<()> {
+        let address: InterfaceAddress = "192.168.0.1/24".parse()?;
+        assert_eq!(address.network(), "192.168.0.0/24".parse()?);
+        assert_eq!(address.broadcast(), Some(Ipv4Addr::new(192, 168, 0, 255)));
+        assert_eq!(address.to_string(), "192.168.0.1/24");
+        assert_eq!("10.0.0.1/31".parse::<InterfaceAddress>()?.broadcast(), None);
+        assert!("10.0.0.1/33".parse::<InterfaceAddress>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_remove_and_select() -> Result<()> {
+        let mut addresses = Addresses::new("192.168.0.1/24".parse()?);
+        addresses.add("10.0.0.1/8".parse()?);
+        addresses.add("10.0.0.1/16".parse()?);
+        assert_eq!(addresses.iter().count(), 2);
+        assert!(addresses.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(addresses.is_broadcast(Ipv4Addr::new(10, 0, 255, 255)));
+        assert!(!addresses.is_broadcast(Ipv4Addr::new(10, 255, 255, 255)));
+
+        assert_eq!(
+            addresses.source_for(Ipv4Addr::new(10, 0, 9, 9)),
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        assert_eq!(
+            addresses.source_for(Ipv4Addr::new(8, 8, 8, 8)),
+            Ipv4Addr::new(192, 168, 0, 1)
+        );
+
+        addresses.set_primary(Ipv4Addr::new(10, 0, 0, 1))?;
+        assert_eq!(addresses.primary(), Ipv4Addr::new(10, 0, 0, 1));
+        assert!(addresses.set_primary(Ipv4Addr::new(8, 8, 8, 8)).is_err());
+
+        assert!(addresses.remove(Ipv4Addr::new(10, 0, 0, 1))?);
+        assert_eq!(addresses.primary(), Ipv4Addr::new(192, 168, 0, 1));
+        assert!(!addresses.remove(Ipv4Addr::new(10, 0, 0, 1))?);
+        assert!(addresses.remove(Ipv4Addr::new(192, 168, 0, 1)).is_err());
+        Ok(())
+    }
+}
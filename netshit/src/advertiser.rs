@@ -0,0 +1,240 @@
+//! Router mode for IPv6: advertising ourselves and the link's prefixes, so
+//! hosts can autoconfigure against us (RFC 4861 §6.2)
+use crate::eth::Mac6;
+use crate::layer3::ndp::{NdpOption, PrefixInformation, RouterAdvertisement};
+use crate::route::Ipv6Prefix;
+use anyhow::{Result, bail};
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(600);
+const DEFAULT_ROUTER_LIFETIME: u16 = 1800;
+const DEFAULT_HOP_LIMIT: u8 = 64;
+/// Lifetimes prefixes are advertised with: 30 days valid, 7 preferred
+const DEFAULT_VALID_LIFETIME: u32 = 30 * 24 * 60 * 60;
+const DEFAULT_PREFERRED_LIFETIME: u32 = 7 * 24 * 60 * 60;
+/// The first few advertisements come quicker, so new hosts configure fast
+const MAX_INITIAL_ADVERTISEMENTS: u32 = 3;
+const MAX_INITIAL_INTERVAL: Duration = Duration::from_secs(16);
+/// Solicited advertisements are delayed by up to this, at random
+const MAX_RA_DELAY: Duration = Duration::from_millis(500);
+/// ...and never sent closer together than this
+const MIN_DELAY_BETWEEN_RAS: Duration = Duration::from_secs(3);
+
+/// Sends router advertisements now and then, and when solicited
+#[derive(Debug)]
+pub struct RouterAdvertiser {
+    prefixes: Vec<PrefixInformation>,
+    mtu: Option<u32>,
+    router_lifetime: u16,
+    hop_limit: u8,
+    min_interval: Duration,
+    max_interval: Duration,
+    /// Advertisements sent so far
+    sent: u32,
+    last_sent: Option<Instant>,
+    due: Option<Instant>,
+    random: RandomState,
+}
+
+impl Default for RouterAdvertiser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RouterAdvertiser {
+    /// Advertise ourselves as a default router, with no prefixes yet
+    pub fn new() -> Self {
+        Self {
+            prefixes: Vec::new(),
+            mtu: None,
+            router_lifetime: DEFAULT_ROUTER_LIFETIME,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            min_interval: DEFAULT_MAX_INTERVAL / 3,
+            max_interval: DEFAULT_MAX_INTERVAL,
+            sent: 0,
+            last_sent: None,
+            due: None,
+            random: RandomState::new(),
+        }
+    }
+
+    /// Advertise `prefix` as on-link, for hosts to form addresses in
+    #[must_use]
+    pub fn add_prefix(mut self, prefix: Ipv6Prefix) -> Self {
+        self.prefixes.push(PrefixInformation {
+            prefix: prefix.address(),
+            prefix_length: prefix.length(),
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: DEFAULT_VALID_LIFETIME,
+            preferred_lifetime: DEFAULT_PREFERRED_LIFETIME,
+        });
+        self
+    }
+
+    /// Advertise the link's MTU
+    #[must_use]
+    pub const fn set_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// How long hosts should use us as a default router; 0 advertises the
+    /// prefixes without offering to route
+    #[must_use]
+    pub const fn set_router_lifetime(mut self, router_lifetime: Duration) -> Self {
+        let seconds = router_lifetime.as_secs();
+        self.router_lifetime = if seconds > u16::MAX as u64 {
+            u16::MAX
+        } else {
+            seconds as u16
+        };
+        self
+    }
+
+    /// Unsolicited advertisements are spaced between these, at random
+    pub fn set_interval(mut self, min: Duration, max: Duration) -> Result<Self> {
+        if min > max || max.is_zero() {
+            bail!("Bad advertisement interval {min:?} to {max:?}");
+        }
+        self.min_interval = min;
+        self.max_interval = max;
+        Ok(self)
+    }
+
+    /// Schedule the first advertisement for `now`
+    pub const fn start(&mut self, now: Instant) {
+        self.due = Some(now);
+    }
+
+    /// When [RouterAdvertiser::poll] next has something to send, if it's
+    /// been started
+    pub const fn next_deadline(&self) -> Option<Instant> {
+        self.due
+    }
+
+    /// A host asked for an advertisement: send one soon, unless one's due
+    /// sooner anyway
+    pub fn solicited(&mut self, now: Instant) {
+        let mut when = now + self.delay(Duration::ZERO, MAX_RA_DELAY);
+        if let Some(last) = self.last_sent {
+            when = when.max(last + MIN_DELAY_BETWEEN_RAS);
+        }
+        self.due = Some(self.due.map_or(when, |due| due.min(when)));
+    }
+
+    /// The advertisement due by `now`, if any, from the router at `mac`
+    pub fn poll(&mut self, now: Instant, mac: Mac6) -> Option<RouterAdvertisement> {
+        if self.due? > now {
+            return None;
+        }
+        self.sent = self.sent.saturating_add(1);
+        self.last_sent = Some(now);
+        let mut interval = self.delay(self.min_interval, self.max_interval);
+        if self.sent < MAX_INITIAL_ADVERTISEMENTS {
+            interval = interval.min(MAX_INITIAL_INTERVAL);
+        }
+        self.due = Some(now + interval);
+        Some(self.advertisement(mac))
+    }
+
+    /// What we advertise, from the router at `mac`
+    pub fn advertisement(&self, mac: Mac6) -> RouterAdvertisement {
+        let mut options = vec![NdpOption::SourceLinkLayerAddress(mac)];
+        options.extend(self.mtu.map(NdpOption::Mtu));
+        options.extend(
+            self.prefixes
+                .iter()
+                .copied()
+                .map(NdpOption::PrefixInformation),
+        );
+        RouterAdvertisement {
+            hop_limit: self.hop_limit,
+            managed: false,
+            other_config: false,
+            router_lifetime: self.router_lifetime,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        }
+    }
+
+    /// A random delay between `min` and `max`
+    fn delay(&self, min: Duration, max: Duration) -> Duration {
+        let spread = u64::try_from((max - min).as_millis()).unwrap_or(u64::MAX);
+        if spread == 0 {
+            return min;
+        }
+        let random = self.random.hash_one((self.sent, self.last_sent));
+        min + Duration::from_millis(random % spread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: Mac6 = Mac6::new([2, 0, 0, 0, 0, 1]);
+
+    fn advertiser() -> RouterAdvertiser {
+        RouterAdvertiser::new()
+            .add_prefix("2001:db8:1::/64".parse().unwrap())
+            .set_mtu(1500)
+    }
+
+    #[test]
+    fn advertises() {
+        let advertisement = advertiser().advertisement(MAC);
+        assert_eq!(advertisement.router_lifetime, DEFAULT_ROUTER_LIFETIME);
+        assert_eq!(advertisement.mtu(), Some(1500));
+        assert_eq!(advertisement.source_mac(), Some(MAC));
+        let prefix = advertisement.prefixes().next().unwrap();
+        assert!(prefix.autonomous && prefix.on_link);
+        assert_eq!(prefix.prefix_length, 64);
+    }
+
+    #[test]
+    fn schedules() {
+        let now = Instant::now();
+        let mut advertiser = advertiser();
+        assert!(advertiser.poll(now, MAC).is_none());
+        advertiser.start(now);
+        let mut sent = Vec::new();
+        while sent.len() < 5 {
+            let due = advertiser.next_deadline().unwrap();
+            assert!(advertiser.poll(due, MAC).is_some());
+            sent.push(due);
+        }
+        for (i, pair) in sent.windows(2).enumerate() {
+            let interval = pair[1] - pair[0];
+            assert!(interval <= DEFAULT_MAX_INTERVAL);
+            if i + 1 < MAX_INITIAL_ADVERTISEMENTS as usize {
+                assert!(interval <= MAX_INITIAL_INTERVAL);
+            }
+        }
+
+        // Solicited ones come soon, but not too soon after the last
+        let last = *sent.last().unwrap();
+        advertiser.solicited(last);
+        let due = advertiser.next_deadline().unwrap();
+        assert!(due >= last + MIN_DELAY_BETWEEN_RAS);
+        assert!(due <= last + MIN_DELAY_BETWEEN_RAS + MAX_RA_DELAY);
+    }
+
+    #[test]
+    fn bad_interval() {
+        let second = Duration::from_secs(1);
+        assert!(
+            RouterAdvertiser::new()
+                .set_interval(second * 2, second)
+                .is_err()
+        );
+        assert!(
+            RouterAdvertiser::new()
+                .set_interval(second, second * 4)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! The ARP cache: which MAC each neighbour's IPv4 address maps to, and how
+//! fresh that knowledge is; IPv6's neighbour cache works the same way
+use crate::eth::Mac6;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// How long a confirmed mapping is trusted, as Linux's `base_reachable_time`
+const DEFAULT_REACHABLE_TIME: Duration = Duration::from_secs(30);
+/// How long an unconfirmed mapping is kept after that
+const DEFAULT_STALE_TIME: Duration = Duration::from_secs(60);
+/// How long to wait for a reply before giving up on an address
+const DEFAULT_INCOMPLETE_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_CAPACITY: usize = 256;
+
+/// What we know about a neighbour
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ArpState {
+    /// We've asked, and are waiting for a reply
+    Incomplete,
+    /// Confirmed recently
+    Reachable,
+    /// Still usable, but unconfirmed for a while, or only overheard
+    Stale,
+    /// Configured, so never ages or changes
+    Permanent,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Entry {
+    /// `None` while incomplete
+    mac: Option<Mac6>,
+    /// When the entry was created or last confirmed
+    updated: Instant,
+    /// Heard about second hand, so never reachable until confirmed
+    unconfirmed: bool,
+}
+
+/// Maps next-hop addresses to MACs, forgetting them as they age
+#[derive(Clone, Debug)]
+pub struct ArpCache<A = Ipv4Addr> {
+    entries: HashMap<A, Entry>,
+    /// Pinned mappings, consulted first
+    statics: HashMap<A, Mac6>,
+    reachable_time: Duration,
+    stale_time: Duration,
+    incomplete_timeout: Duration,
+    capacity: usize,
+}
+
+/// IPv6's equivalent, filled by Neighbour Discovery (RFC 4861)
+pub type NeighbourCache = ArpCache<Ipv6Addr>;
+
+impl<A: Copy + Eq + Hash> Default for ArpCache<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Copy + Eq + Hash> ArpCache<A> {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            statics: HashMap::new(),
+            reachable_time: DEFAULT_REACHABLE_TIME,
+            stale_time: DEFAULT_STALE_TIME,
+            incomplete_timeout: DEFAULT_INCOMPLETE_TIMEOUT,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    /// How long a confirmed mapping stays reachable
+    #[must_use]
+    pub const fn set_reachable_time(mut self, reachable_time: Duration) -> Self {
+        self.reachable_time = reachable_time;
+        self
+    }
+
+    /// How long a mapping stays stale before it's forgotten
+    #[must_use]
+    pub const fn set_stale_time(mut self, stale_time: Duration) -> Self {
+        self.stale_time = stale_time;
+        self
+    }
+
+    /// How long an unanswered address stays incomplete
+    #[must_use]
+    pub const fn set_incomplete_timeout(mut self, incomplete_timeout: Duration) -> Self {
+        self.incomplete_timeout = incomplete_timeout;
+        self
+    }
+
+    /// Most learnt entries kept; the oldest is evicted to make room. Static
+    /// entries don't count.
+    #[must_use]
+    pub const fn set_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.statics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pin `address` to `mac`, until removed; learnt mappings for it are
+    /// ignored meanwhile
+    pub fn add_static(&mut self, address: A, mac: Mac6) {
+        self.entries.remove(&address);
+        self.statics.insert(address, mac);
+    }
+
+    /// The MAC to send to `address` with, if we know it
+    pub fn lookup(&self, address: A, now: Instant) -> Option<Mac6> {
+        if let Some(&mac) = self.statics.get(&address) {
+            return Some(mac);
+        }
+        match self.state(address, now)? {
+            ArpState::Incomplete => None,
+            _ => self.entries[&address].mac,
+        }
+    }
+
+    /// The state of `address`'s entry, or `None` if there isn't one
+    pub fn state(&self, address: A, now: Instant) -> Option<ArpState> {
+        if self.statics.contains_key(&address) {
+            return Some(ArpState::Permanent);
+        }
+        let entry = self.entries.get(&address)?;
+        let age = now.saturating_duration_since(entry.updated);
+        match entry.mac {
+            None if age < self.incomplete_timeout => Some(ArpState::Incomplete),
+            None => None,
+            Some(_) if age < self.reachable_time && !entry.unconfirmed => Some(ArpState::Reachable),
+            Some(_) if age < self.reachable_time + self.stale_time => Some(ArpState::Stale),
+            Some(_) => None,
+        }
+    }
+
+    /// Record a mapping confirmed by a reply (or a request to us), making it
+    /// reachable
+    pub fn confirm(&mut self, address: A, mac: Mac6, now: Instant) {
+        self.set(
+            address,
+            Entry {
+                mac: Some(mac),
+                updated: now,
+                unconfirmed: false,
+            },
+        );
+    }
+
+    /// Record a mapping overheard in other traffic
+    ///
+    /// A new or changed mapping is stale until confirmed; an unchanged one is
+    /// left alone.
+    pub fn observe(&mut self, address: A, mac: Mac6, now: Instant) {
+        if self.state(address, now).is_some() && self.entries[&address].mac == Some(mac) {
+            return;
+        }
+        self.set(
+            address,
+            Entry {
+                mac: Some(mac),
+                updated: now,
+                unconfirmed: true,
+            },
+        );
+    }
+
+    /// Refresh an existing mapping from traffic that names it (RFC 826's
+    /// merge step), returning whether there was one
+    pub fn update(&mut self, address: A, mac: Mac6, now: Instant) -> bool {
+        if self.state(address, now).is_none() {
+            return false;
+        }
+        self.observe(address, mac, now);
+        true
+    }
+
+    /// Mark `address` as being resolved, returning whether it wasn't
+    /// already, i.e. whether a request should go out
+    pub fn start_resolving(&mut self, address: A, now: Instant) -> bool {
+        if self.state(address, now).is_some() {
+            return false;
+        }
+        self.set(
+            address,
+            Entry {
+                mac: None,
+                updated: now,
+                unconfirmed: true,
+            },
+        );
+        true
+    }
+
+    /// Forget `address`, static or not, returning whether we knew it
+    pub fn remove(&mut self, address: A) -> bool {
+        self.statics.remove(&address).is_some() | self.entries.remove(&address).is_some()
+    }
+
+    /// Forget every entry that's timed out
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<A> = self
+            .entries
+            .keys()
+            .copied()
+            .filter(|&address| self.state(address, now).is_none())
+            .collect();
+        for address in expired {
+            self.entries.remove(&address);
+        }
+    }
+
+    /// Every live entry's address, MAC (if known) and state
+    pub fn entries(&self, now: Instant) -> impl Iterator<Item = (A, Option<Mac6>, ArpState)> {
+        let statics = self
+            .statics
+            .iter()
+            .map(|(&address, &mac)| (address, Some(mac), ArpState::Permanent));
+        let learnt = self.entries.iter().filter_map(move |(&address, entry)| {
+            Some((address, entry.mac, self.state(address, now)?))
+        });
+        statics.chain(learnt)
+    }
+
+    fn set(&mut self, address: A, entry: Entry) {
+        if self.statics.contains_key(&address) {
+            return;
+        }
+        if !self.entries.contains_key(&address) && self.entries.len() >= self.capacity {
+            self.expire(entry.updated);
+            if self.entries.len() >= self.capacity
+                && let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.updated)
+                    .map(|(&address, _)| address)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(address, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEIGHBOUR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 5);
+    const MAC: Mac6 = Mac6::new([2, 0, 0, 0, 0, 5]);
+
+    #[test]
+    fn ages() {
+        let now = Instant::now();
+        let mut cache = ArpCache::new();
+        cache.confirm(NEIGHBOUR, MAC, now);
+        assert_eq!(cache.state(NEIGHBOUR, now), Some(ArpState::Reachable));
+        assert_eq!(cache.lookup(NEIGHBOUR, now), Some(MAC));
+
+        let later = now + DEFAULT_REACHABLE_TIME;
+        assert_eq!(cache.state(NEIGHBOUR, later), Some(ArpState::Stale));
+        assert_eq!(cache.lookup(NEIGHBOUR, later), Some(MAC));
+
+        let gone = later + DEFAULT_STALE_TIME;
+        assert_eq!(cache.lookup(NEIGHBOUR, gone), None);
+        cache.expire(gone);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn observed_and_incomplete() {
+        let now = Instant::now();
+        let mut cache = ArpCache::new();
+        assert!(!cache.update(NEIGHBOUR, MAC, now));
+        cache.observe(NEIGHBOUR, MAC, now);
+        assert_eq!(cache.state(NEIGHBOUR, now), Some(ArpState::Stale));
+        assert!(cache.update(NEIGHBOUR, MAC, now));
+
+        let other = Ipv4Addr::new(192, 168, 0, 6);
+        assert!(cache.start_resolving(other, now));
+        assert!(!cache.start_resolving(other, now));
+        assert_eq!(cache.state(other, now), Some(ArpState::Incomplete));
+        assert_eq!(cache.lookup(other, now), None);
+        assert_eq!(cache.state(other, now + DEFAULT_INCOMPLETE_TIMEOUT), None);
+    }
+
+    #[test]
+    fn statics() {
+        let now = Instant::now();
+        let mut cache = ArpCache::new().set_capacity(1);
+        cache.confirm(NEIGHBOUR, Mac6::new([2, 0, 0, 0, 0, 9]), now);
+        cache.add_static(NEIGHBOUR, MAC);
+        cache.confirm(NEIGHBOUR, Mac6::new([2, 0, 0, 0, 0, 9]), now);
+        assert!(!cache.start_resolving(NEIGHBOUR, now));
+
+        let forever = now + Duration::from_secs(1 << 20);
+        cache.expire(forever);
+        assert_eq!(cache.state(NEIGHBOUR, forever), Some(ArpState::Permanent));
+        assert_eq!(cache.lookup(NEIGHBOUR, forever), Some(MAC));
+        // Doesn't take up room for learnt entries
+        cache.confirm(Ipv4Addr::new(192, 168, 0, 6), MAC, now);
+        assert_eq!(cache.entries(now).count(), 2);
+
+        assert!(cache.remove(NEIGHBOUR));
+        assert_eq!(cache.lookup(NEIGHBOUR, now), None);
+    }
+
+    #[test]
+    fn bounded() {
+        let now = Instant::now();
+        let mut cache = ArpCache::new().set_capacity(2);
+        for (i, host) in [1, 2, 3].into_iter().enumerate() {
+            let later = now + Duration::from_secs(i as u64);
+            cache.confirm(Ipv4Addr::new(10, 0, 0, host), MAC, later);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup(Ipv4Addr::new(10, 0, 0, 1), now), None);
+        assert!(cache.remove(Ipv4Addr::new(10, 0, 0, 3)));
+    }
+}
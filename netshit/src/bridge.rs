@@ -0,0 +1,290 @@
+//! A learning bridge, forwarding Ethernet frames between ports like a switch
+//!
+//! Ports are channel pairs, so anything that can move raw frames (a tap
+//! device, a capture, another link) can be attached by pumping frames
+//! between it and a [BridgePort].
+use crate::eth::Mac6;
+use crate::mirror::Mirror;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Default number of MACs to remember
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Default time before a MAC that hasn't been seen is forgotten, as in 802.1D
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Frames queued per port before the bridge starts dropping them
+const PORT_QUEUE: usize = 64;
+
+pub type PortId = usize;
+
+#[derive(Copy, Clone, Debug)]
+struct MacEntry {
+    port: PortId,
+    seen: Instant,
+}
+
+/// Which port each MAC was last seen on, bounded and aging
+#[derive(Debug)]
+pub struct MacTable {
+    entries: HashMap<Mac6, MacEntry>,
+    capacity: usize,
+    max_age: Duration,
+}
+
+impl MacTable {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            max_age,
+        }
+    }
+
+    /// Record that `mac` sent a frame on `port`
+    ///
+    /// When full, expired entries are dropped first, then the oldest.
+    pub fn learn(&mut self, mac: Mac6, port: PortId, now: Instant) {
+        if mac.is_multicast() || self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&mac) && self.entries.len() >= self.capacity {
+            self.expire(now);
+            if self.entries.len() >= self.capacity
+                && let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.seen)
+                    .map(|(mac, _)| *mac)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(mac, MacEntry { port, seen: now });
+    }
+
+    /// The port `mac` lives behind, unless unknown or aged out
+    pub fn lookup(&self, mac: Mac6, now: Instant) -> Option<PortId> {
+        self.entries
+            .get(&mac)
+            .filter(|entry| now.duration_since(entry.seen) < self.max_age)
+            .map(|entry| entry.port)
+    }
+
+    /// Forget entries older than the max age
+    pub fn expire(&mut self, now: Instant) {
+        let max_age = self.max_age;
+        self.entries
+            .retain(|_, entry| now.duration_since(entry.seen) < max_age);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// One side of a port attached to a [Bridge]
+#[derive(Debug)]
+pub struct BridgePort {
+    id: PortId,
+    to_bridge: mpsc::Sender<(PortId, Vec<u8>)>,
+    from_bridge: mpsc::Receiver<Vec<u8>>,
+}
+
+impl BridgePort {
+    pub const fn id(&self) -> PortId {
+        self.id
+    }
+
+    /// Hand a frame received on this port to the bridge
+    pub async fn send(&self, frame: Vec<u8>) -> Result<()> {
+        self.to_bridge
+            .send((self.id, frame))
+            .await
+            .map_err(|_| anyhow!("Bridge stopped"))
+    }
+
+    /// Next frame the bridge wants sent out of this port, or `None` once
+    /// the bridge has stopped
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_bridge.recv().await
+    }
+}
+
+/// A software switch: learns where MACs live and forwards frames to them,
+/// flooding when it doesn't know
+#[derive(Debug)]
+pub struct Bridge {
+    table: MacTable,
+    ports: Vec<mpsc::Sender<Vec<u8>>>,
+    /// Cloned into each port; dropped by [Bridge::run] so it can tell when
+    /// every port is gone
+    incoming_tx: Option<mpsc::Sender<(PortId, Vec<u8>)>>,
+    incoming: mpsc::Receiver<(PortId, Vec<u8>)>,
+    mirror: Mirror,
+}
+
+impl Default for Bridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bridge {
+    pub fn new() -> Self {
+        let (incoming_tx, incoming) = mpsc::channel(PORT_QUEUE);
+        Self {
+            table: MacTable::new(DEFAULT_CAPACITY, DEFAULT_MAX_AGE),
+            ports: Vec::new(),
+            incoming_tx: Some(incoming_tx),
+            incoming,
+            mirror: Mirror::new(),
+        }
+    }
+
+    /// Set the size and aging time of the MAC table
+    #[must_use]
+    pub fn set_table(mut self, capacity: usize, max_age: Duration) -> Self {
+        self.table = MacTable::new(capacity, max_age);
+        self
+    }
+
+    /// Copy every frame received on any port to `mirror`
+    #[must_use]
+    pub fn set_mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Attach a new port
+    pub fn add_port(&mut self) -> BridgePort {
+        let (tx, from_bridge) = mpsc::channel(PORT_QUEUE);
+        self.ports.push(tx);
+        BridgePort {
+            id: self.ports.len() - 1,
+            to_bridge: self
+                .incoming_tx
+                .clone()
+                .expect("ports are added before the bridge runs"),
+            from_bridge,
+        }
+    }
+
+    pub const fn table(&self) -> &MacTable {
+        &self.table
+    }
+
+    /// Learn from a frame received on `from` and decide which ports it
+    /// goes out of
+    pub fn forward(&mut self, from: PortId, frame: &[u8], now: Instant) -> Vec<PortId> {
+        let Some((dst, src)) = macs(frame) else {
+            return Vec::new();
+        };
+        self.table.learn(src, from, now);
+        match self.table.lookup(dst, now) {
+            // Both ends are on the same segment
+            Some(port) if port == from => Vec::new(),
+            Some(port) => vec![port],
+            None => (0..self.ports.len()).filter(|&port| port != from).collect(),
+        }
+    }
+
+    /// Forward frames until every port has been dropped
+    pub async fn run(mut self) -> Result<()> {
+        self.incoming_tx = None;
+        while let Some((from, frame)) = self.incoming.recv().await {
+            // A failed mirror stops mirroring and nothing else; whoever
+            // set it up hears why from their end
+            let _ = self.mirror.tee(&frame);
+            for port in self.forward(from, &frame, Instant::now()) {
+                // Like a switch with a full buffer, drop rather than stall
+                // every other port
+                let _ = self.ports[port].try_send(frame.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Destination and source MACs of a raw frame
+fn macs(frame: &[u8]) -> Option<(Mac6, Mac6)> {
+    let dst: [u8; 6] = frame.get(0..6)?.try_into().ok()?;
+    let src: [u8; 6] = frame.get(6..12)?.try_into().ok()?;
+    (frame.len() >= 14).then(|| (Mac6::from(dst), Mac6::from(src)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dst: u8, src: u8) -> Vec<u8> {
+        let mut frame = vec![2, 0, 0, 0, 0, dst, 2, 0, 0, 0, 0, src, 0x08, 0x00];
+        frame.resize(60, 0);
+        frame
+    }
+
+    #[test]
+    fn learns_and_forwards() {
+        let mut bridge = Bridge::new();
+        for _ in 0..3 {
+            bridge.add_port();
+        }
+        let now = Instant::now();
+
+        // Unknown destination floods
+        assert_eq!(bridge.forward(0, &frame(2, 1), now), [1, 2]);
+        // 1 is now known to be on port 0
+        assert_eq!(bridge.forward(2, &frame(1, 2), now), [0]);
+        assert_eq!(bridge.forward(0, &frame(2, 1), now), [2]);
+        // Same segment: filtered
+        assert_eq!(bridge.forward(0, &frame(1, 3), now), Vec::<PortId>::new());
+
+        let mut broadcast = frame(0, 1);
+        broadcast[..6].fill(0xff);
+        assert_eq!(bridge.forward(0, &broadcast, now), [1, 2]);
+        assert_eq!(bridge.forward(0, &[0; 4], now), Vec::<PortId>::new());
+    }
+
+    #[test]
+    fn table_is_bounded_and_ages() {
+        let mut table = MacTable::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+        let mac = |n| Mac6::from([2, 0, 0, 0, 0, n]);
+
+        table.learn(mac(1), 0, now);
+        table.learn(mac(2), 1, now + Duration::from_secs(1));
+        table.learn(mac(3), 2, now + Duration::from_secs(2));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.lookup(mac(1), now), None);
+        assert_eq!(table.lookup(mac(3), now + Duration::from_secs(2)), Some(2));
+        assert_eq!(table.lookup(mac(3), now + Duration::from_secs(12)), None);
+
+        table.learn(Mac6::from([0x01, 0, 0x5e, 0, 0, 1]), 0, now);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn run_forwards_between_ports() -> Result<()> {
+        let (tx, mut mirrored) = mpsc::channel(4);
+        let mut bridge = Bridge::new().set_mirror(Mirror::new().add(tx));
+        let a = bridge.add_port();
+        let mut b = bridge.add_port();
+        let task = tokio::spawn(bridge.run());
+
+        a.send(frame(2, 1)).await?;
+        assert_eq!(b.recv().await, Some(frame(2, 1)));
+        assert_eq!(mirrored.recv().await, Some(frame(2, 1)));
+
+        drop(a);
+        drop(b);
+        task.await??;
+        Ok(())
+    }
+}
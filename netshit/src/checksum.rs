@@ -0,0 +1,159 @@
+//! Incremental Internet checksum updates (RFC 1624), for rewriting a field
+//! without summing the whole packet again, e.g. when forwarding or NATing,
+//! and which checksums to bother with at all
+
+/// Which ways a checksum is dealt with
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Checksum {
+    /// Verified when parsed, and computed when serialized
+    #[default]
+    Both,
+    /// Only verified, e.g. where hardware fills it in on the way out
+    Rx,
+    /// Only computed, e.g. where hardware has already verified it
+    Tx,
+    /// Neither, e.g. on loopback, where nothing can damage a packet
+    None,
+}
+
+impl Checksum {
+    /// Whether to verify it when parsing
+    pub const fn rx(self) -> bool {
+        matches!(self, Self::Both | Self::Rx)
+    }
+
+    /// Whether to compute it when serializing; if not, it's left zero
+    pub const fn tx(self) -> bool {
+        matches!(self, Self::Both | Self::Tx)
+    }
+}
+
+/// Which checksums to deal with, per layer, so trusted paths can skip work
+/// something else already did or that nothing needs
+///
+/// ICMP and ICMPv6 checksums are always verified and computed; their
+/// messages are too few to be worth skipping.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ChecksumCapabilities {
+    pub ipv4: Checksum,
+    pub tcp: Checksum,
+    /// UDP's, and UDP-Lite's
+    pub udp: Checksum,
+    pub sctp: Checksum,
+}
+
+impl ChecksumCapabilities {
+    /// Neither verify nor compute any checksum
+    pub const fn ignored() -> Self {
+        Self {
+            ipv4: Checksum::None,
+            tcp: Checksum::None,
+            udp: Checksum::None,
+            sctp: Checksum::None,
+        }
+    }
+
+    #[must_use]
+    pub const fn set_ipv4(mut self, ipv4: Checksum) -> Self {
+        self.ipv4 = ipv4;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_tcp(mut self, tcp: Checksum) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_udp(mut self, udp: Checksum) -> Self {
+        self.udp = udp;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_sctp(mut self, sctp: Checksum) -> Self {
+        self.sctp = sctp;
+        self
+    }
+}
+
+/// The checksum after a 16-bit word it covers changes from `old` to `new`
+pub const fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    // HC' = ~(~HC + ~m + m') in ones' complement (RFC 1624 eqn. 3), which,
+    // unlike RFC 1141's version, matches a full recompute even when that's
+    // 0x0000; UDP, where zero means no checksum, must send that as 0xffff
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The checksum after bytes it covers change from `old` to `new`
+///
+/// The bytes must start at an even offset in the checksummed data; an odd
+/// length is treated as padded with a zero.
+pub fn update_bytes(checksum: [u8; 2], old: &[u8], new: &[u8]) -> [u8; 2] {
+    assert_eq!(old.len(), new.len(), "replacement must be the same length");
+    let mut checksum = u16::from_be_bytes(checksum);
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        checksum = update(checksum, word(old), word(new));
+    }
+    checksum.to_be_bytes()
+}
+
+fn word(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        assert!(Checksum::Both.rx() && Checksum::Both.tx());
+        assert!(Checksum::Rx.rx() && !Checksum::Rx.tx());
+        assert!(!Checksum::Tx.rx() && Checksum::Tx.tx());
+        assert!(!Checksum::None.rx() && !Checksum::None.tx());
+
+        let caps = ChecksumCapabilities::default().set_tcp(Checksum::Tx);
+        assert_eq!(caps.ipv4, Checksum::Both);
+        assert_eq!(caps.tcp, Checksum::Tx);
+        assert_eq!(ChecksumCapabilities::ignored().udp, Checksum::None);
+    }
+
+    #[test]
+    fn rfc1624_example() {
+        assert_eq!(update(0xdd2f, 0x5555, 0x3285), 0x0000);
+    }
+
+    #[test]
+    fn matches_full_recompute() {
+        // An IPv4 header, checksum included
+        let mut header = [
+            0x45, 0x00, 0x00, 0x1c, 0x12, 0x34, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 2,
+            10, 0, 0, 1,
+        ];
+        let checksum = internet_checksum::checksum(&header);
+        header[10..12].copy_from_slice(&checksum);
+
+        // Decrement the TTL, rewrite the source
+        let new_ttl = [0x3f, 0x11];
+        let new_source = [192, 168, 0, 1];
+        let mut checksum = update_bytes(checksum, &header[8..10], &new_ttl);
+        checksum = update_bytes(checksum, &header[12..16], &new_source);
+        header[8..10].copy_from_slice(&new_ttl);
+        header[12..16].copy_from_slice(&new_source);
+
+        header[10..12].copy_from_slice(&checksum);
+        assert_eq!(internet_checksum::checksum(&header), [0, 0]);
+
+        // Odd lengths pad with zero, as at the end of a packet
+        let data = [1, 2, 3];
+        let checksum = internet_checksum::checksum(&data);
+        let checksum = update_bytes(checksum, &data[2..], &[9]);
+        assert_eq!(checksum, internet_checksum::checksum(&[1, 2, 9]));
+    }
+}
@@ -0,0 +1,429 @@
+//! Connection tracking: the flows seen passing through, each forgotten once
+//! it's been idle too long, for [Nat](crate::nat::Nat) and the
+//! [Firewall](crate::filter::Firewall) to keep state in
+use crate::layer3::{IpProtocol, Ipv4Packet};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Flows tracked at once, by default
+pub const DEFAULT_MAX_ENTRIES: usize = 16384;
+/// Idle time before a TCP flow is forgotten (RFC 5382: over 2 h 4 min)
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60 + 4 * 60);
+/// Idle time before a TCP flow that's seen a FIN or reset is forgotten
+pub const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// Idle time before a UDP flow is forgotten (RFC 4787 recommends 5 min)
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Idle time before an ICMP query is forgotten (RFC 5508)
+pub const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Idle time before a flow of any other protocol is forgotten
+pub const OTHER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const TCP_FIN: u8 = 0x01;
+const TCP_RST: u8 = 0x04;
+
+/// A flow's protocol and its two ends, in the direction it was first seen
+///
+/// Ports are ICMP echoes' identifier, and 0 for protocols without either.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Flow {
+    pub protocol: IpProtocol,
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+}
+
+impl Flow {
+    pub const fn new(
+        protocol: IpProtocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Self {
+        Self {
+            protocol,
+            source,
+            destination,
+        }
+    }
+
+    /// The flow `packet` is part of, unless it's too short to tell
+    pub fn of(packet: &Ipv4Packet) -> Option<Self> {
+        let data = &packet.data;
+        let (source_port, destination_port) = match packet.protocol {
+            IpProtocol::Tcp | IpProtocol::Udp | IpProtocol::UdpLite => {
+                let &[a, b, c, d] = data.first_chunk::<4>()?;
+                (u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d]))
+            }
+            IpProtocol::Icmp
+                if matches!(data.first(), Some(&(ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY))) =>
+            {
+                let identifier = u16::from_be_bytes([*data.get(4)?, *data.get(5)?]);
+                (identifier, identifier)
+            }
+            _ => (0, 0),
+        };
+        Some(Self::new(
+            packet.protocol,
+            SocketAddrV4::new(packet.source, source_port),
+            SocketAddrV4::new(packet.destination, destination_port),
+        ))
+    }
+
+    /// The same flow, the other way
+    pub const fn reversed(&self) -> Self {
+        Self::new(self.protocol, self.destination, self.source)
+    }
+}
+
+/// Whether `packet` is a TCP segment closing or resetting its connection
+pub fn closes(packet: &Ipv4Packet) -> bool {
+    packet.protocol == IpProtocol::Tcp
+        && packet
+            .data
+            .get(13)
+            .is_some_and(|flags| flags & (TCP_FIN | TCP_RST) != 0)
+}
+
+/// A packet's standing with respect to the flows already tracked
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConnState {
+    /// Of a flow not seen before, or not yet answered
+    New,
+    /// Of a flow that's been answered, or the answer itself
+    Established,
+}
+
+/// A tracked flow, with whatever its user keeps for it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FlowEntry<V> {
+    flow: Flow,
+    value: V,
+    created: Instant,
+    last_seen: Instant,
+    packets: u64,
+    /// Something's come back the other way
+    replied: bool,
+    /// A FIN or reset's been seen
+    closing: bool,
+}
+
+impl<V> FlowEntry<V> {
+    /// The flow, in the direction it was first seen
+    pub const fn flow(&self) -> &Flow {
+        &self.flow
+    }
+
+    pub const fn value(&self) -> &V {
+        &self.value
+    }
+
+    pub const fn created(&self) -> Instant {
+        self.created
+    }
+
+    pub const fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /// Packets seen in either direction
+    pub const fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    pub const fn is_replied(&self) -> bool {
+        self.replied
+    }
+
+    pub const fn is_closing(&self) -> bool {
+        self.closing
+    }
+}
+
+/// A table of flows, each expiring after a timeout depending on its
+/// protocol, and holding at most so many
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Conntrack<V = ()> {
+    flows: HashMap<Flow, FlowEntry<V>>,
+    timeouts: HashMap<IpProtocol, Duration>,
+    max_entries: usize,
+}
+
+impl<V> Default for Conntrack<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Conntrack<V> {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+            timeouts: HashMap::from([
+                (IpProtocol::Tcp, TCP_TIMEOUT),
+                (IpProtocol::Udp, UDP_TIMEOUT),
+                (IpProtocol::Icmp, ICMP_TIMEOUT),
+            ]),
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Forget `protocol` flows after they've been idle for `timeout`
+    #[must_use]
+    pub fn set_timeout(mut self, protocol: IpProtocol, timeout: Duration) -> Self {
+        self.timeouts.insert(protocol, timeout);
+        self
+    }
+
+    /// Track at most `max_entries` flows, evicting old ones for new ones
+    #[must_use]
+    pub const fn set_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// How long `protocol` flows may be idle
+    pub fn timeout(&self, protocol: IpProtocol) -> Duration {
+        self.timeouts
+            .get(&protocol)
+            .copied()
+            .unwrap_or(OTHER_TIMEOUT)
+    }
+
+    /// When `entry` is forgotten, unless it's seen again
+    pub fn expires_at(&self, entry: &FlowEntry<V>) -> Instant {
+        let mut timeout = self.timeout(entry.flow.protocol);
+        if entry.closing {
+            timeout = timeout.min(TCP_CLOSING_TIMEOUT);
+        }
+        entry.last_seen + timeout
+    }
+
+    /// The live entry for `flow`, in either direction
+    pub fn get(&self, flow: &Flow, now: Instant) -> Option<&FlowEntry<V>> {
+        [*flow, flow.reversed()]
+            .iter()
+            .filter_map(|flow| self.flows.get(flow))
+            .find(|entry| self.expires_at(entry) > now)
+    }
+
+    /// The state a packet of `flow` would have, were it seen `now`
+    pub fn classify(&self, flow: &Flow, now: Instant) -> ConnState {
+        match self.get(flow, now) {
+            Some(entry) if entry.replied || entry.flow != *flow => ConnState::Established,
+            _ => ConnState::New,
+        }
+    }
+
+    /// Note a packet of `flow`, in either direction, `closing` it if it's
+    /// a FIN or reset, returning its state; `None` if it's not tracked
+    pub fn see(&mut self, flow: &Flow, closing: bool, now: Instant) -> Option<ConnState> {
+        let state = self.classify(flow, now);
+        let key = self.get(flow, now)?.flow;
+        let entry = self.flows.get_mut(&key)?;
+        entry.last_seen = now;
+        entry.packets += 1;
+        entry.replied |= key != *flow;
+        entry.closing |= closing;
+        Some(state)
+    }
+
+    /// Start tracking `flow`, with `value`, returning the entry evicted to
+    /// make room, if one was
+    ///
+    /// Expired flows go first, then those never answered, then those idle
+    /// longest.
+    pub fn insert(&mut self, flow: Flow, value: V, now: Instant) -> Option<FlowEntry<V>> {
+        let mut evicted = None;
+        if !self.flows.contains_key(&flow) && self.flows.len() >= self.max_entries {
+            let oldest = self
+                .flows
+                .values()
+                .min_by_key(|entry| (self.expires_at(entry) > now, entry.replied, entry.last_seen))
+                .map(|entry| entry.flow);
+            evicted = oldest.and_then(|oldest| self.flows.remove(&oldest));
+        }
+        let entry = FlowEntry {
+            flow,
+            value,
+            created: now,
+            last_seen: now,
+            packets: 1,
+            replied: false,
+            closing: false,
+        };
+        self.flows.insert(flow, entry);
+        evicted
+    }
+
+    pub fn remove(&mut self, flow: &Flow) -> Option<FlowEntry<V>> {
+        self.flows.remove(flow)
+    }
+
+    /// Forget flows idle too long, returning them
+    pub fn expire(&mut self, now: Instant) -> Vec<FlowEntry<V>> {
+        let expired: Vec<_> = self
+            .flows
+            .values()
+            .filter(|entry| self.expires_at(entry) <= now)
+            .map(|entry| entry.flow)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|flow| self.flows.remove(flow))
+            .collect()
+    }
+
+    /// Tracked flows, including any expired and not yet forgotten
+    pub fn flows(&self) -> impl Iterator<Item = &FlowEntry<V>> {
+        self.flows.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+impl<V: Default> Conntrack<V> {
+    /// Note `packet`, tracking its flow if it's new, and return its state
+    pub fn track(&mut self, packet: &Ipv4Packet, now: Instant) -> Option<ConnState> {
+        let flow = Flow::of(packet)?;
+        if let Some(state) = self.see(&flow, closes(packet), now) {
+            return Some(state);
+        }
+        self.insert(flow, V::default(), now);
+        if let Some(entry) = self.flows.get_mut(&flow) {
+            entry.closing = closes(packet);
+        }
+        Some(ConnState::New)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    fn packet(
+        protocol: IpProtocol,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        data: &[u8],
+    ) -> Ipv4Packet {
+        Ipv4Packet::builder(source, destination, protocol)
+            .set_data(data)
+            .build()
+            .unwrap()
+    }
+
+    fn udp(source: Ipv4Addr, destination: Ipv4Addr, ports: [u16; 2]) -> Ipv4Packet {
+        let [a, b] = ports[0].to_be_bytes();
+        let [c, d] = ports[1].to_be_bytes();
+        packet(
+            IpProtocol::Udp,
+            source,
+            destination,
+            &[a, b, c, d, 0, 8, 0, 0],
+        )
+    }
+
+    #[test]
+    fn flows_of_packets() {
+        let flow = Flow::of(&udp(HOST, SERVER, [5000, 53])).unwrap();
+        assert_eq!(flow.source, SocketAddrV4::new(HOST, 5000));
+        assert_eq!(flow.destination, SocketAddrV4::new(SERVER, 53));
+        let reply = Flow::of(&udp(SERVER, HOST, [53, 5000])).unwrap();
+        assert_eq!(reply, flow.reversed());
+
+        // Echoes by identifier, anything else by address
+        let echo = packet(
+            IpProtocol::Icmp,
+            HOST,
+            SERVER,
+            &[8, 0, 0, 0, 0x12, 0x34, 0, 1],
+        );
+        assert_eq!(Flow::of(&echo).unwrap().source.port(), 0x1234);
+        let igmp = packet(IpProtocol::Igmp, HOST, SERVER, &[0x16, 0, 0, 0]);
+        assert_eq!(Flow::of(&igmp).unwrap().source.port(), 0);
+        assert_eq!(
+            Flow::of(&packet(IpProtocol::Tcp, HOST, SERVER, &[0; 3])),
+            None
+        );
+    }
+
+    #[test]
+    fn tracks_and_expires() {
+        let now = Instant::now();
+        let mut conntrack =
+            Conntrack::<()>::new().set_timeout(IpProtocol::Udp, Duration::from_secs(30));
+        let out = udp(HOST, SERVER, [5000, 53]);
+        assert_eq!(conntrack.track(&out, now), Some(ConnState::New));
+        assert_eq!(conntrack.track(&out, now), Some(ConnState::New));
+        let reply = udp(SERVER, HOST, [53, 5000]);
+        assert_eq!(conntrack.track(&reply, now), Some(ConnState::Established));
+        assert_eq!(conntrack.track(&out, now), Some(ConnState::Established));
+
+        let entry = conntrack.flows().next().unwrap();
+        assert_eq!(entry.flow().source, SocketAddrV4::new(HOST, 5000));
+        assert_eq!((entry.packets(), entry.is_replied()), (4, true));
+        let expiry = conntrack.expires_at(entry);
+        assert_eq!(expiry, now + Duration::from_secs(30));
+
+        assert!(conntrack.expire(expiry - Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            conntrack.classify(&Flow::of(&reply).unwrap(), expiry),
+            ConnState::New
+        );
+        assert_eq!(conntrack.expire(expiry).len(), 1);
+        assert!(conntrack.is_empty());
+    }
+
+    #[test]
+    fn closing_tcp() {
+        let now = Instant::now();
+        let mut conntrack = Conntrack::<()>::new();
+        let mut segment = [0; 20];
+        segment[..4].copy_from_slice(&[0x13, 0x88, 0, 80]);
+        let syn = packet(IpProtocol::Tcp, HOST, SERVER, &segment);
+        conntrack.track(&syn, now);
+        let entry = conntrack.flows().next().unwrap();
+        assert_eq!(conntrack.expires_at(entry), now + TCP_TIMEOUT);
+
+        segment[13] = TCP_FIN;
+        let fin = packet(IpProtocol::Tcp, HOST, SERVER, &segment);
+        assert!(closes(&fin));
+        conntrack.track(&fin, now);
+        let entry = conntrack.flows().next().unwrap();
+        assert!(entry.is_closing());
+        assert_eq!(conntrack.expires_at(entry), now + TCP_CLOSING_TIMEOUT);
+    }
+
+    #[test]
+    fn evicts() {
+        let now = Instant::now();
+        let mut conntrack = Conntrack::new().set_max_entries(2);
+        let anywhere = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        let flow = |port| Flow::new(IpProtocol::Udp, SocketAddrV4::new(HOST, port), anywhere);
+        conntrack.insert(flow(1), 'a', now);
+        conntrack.insert(flow(2), 'b', now + Duration::from_secs(1));
+        conntrack.see(&flow(1).reversed(), false, now + Duration::from_secs(2));
+
+        // Unanswered flows go before answered ones
+        let evicted = conntrack.insert(flow(3), 'c', now + Duration::from_secs(3));
+        assert_eq!(evicted.map(|entry| entry.value), Some('b'));
+        let evicted = conntrack.insert(flow(4), 'd', now + Duration::from_secs(4));
+        assert_eq!(evicted.map(|entry| entry.value), Some('c'));
+        assert_eq!(conntrack.len(), 2);
+        assert_eq!(
+            conntrack.get(&flow(1), now).map(FlowEntry::value),
+            Some(&'a')
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! IPv6 duplicate address detection (RFC 4862 §5.4): soliciting for an
+//! address before using it, and giving it up if anyone answers
+use crate::eth::Mac6;
+use crate::layer3::ipv6::Ipv6Packet;
+use crate::layer3::ndp::{NeighbourAdvertisement, NeighbourSolicitation};
+use std::hash::{BuildHasher, RandomState};
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// Solicitations sent for each address, by default
+pub const DEFAULT_TRANSMITS: u8 = 1;
+/// Longest random delay before the first solicitation, so hosts coming up
+/// together don't all solicit at once
+const MAX_DELAY: Duration = Duration::from_secs(1);
+/// Time between solicitations, and after the last before the address is
+/// ours
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+
+/// Something that happened to an address being checked
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DadEvent {
+    /// Nobody else had the address, so it's now ours
+    Assigned(Ipv6Addr),
+    /// Another host is using or checking for the address, so we won't;
+    /// `mac` is theirs, if they said
+    Duplicate {
+        address: Ipv6Addr,
+        mac: Option<Mac6>,
+    },
+}
+
+/// Detection for one tentative address
+#[derive(Debug)]
+pub struct DadProbe {
+    address: Ipv6Addr,
+    /// Solicitations to send
+    transmits: u8,
+    sent: u8,
+    due: Instant,
+}
+
+impl DadProbe {
+    /// Start checking for `address`, sending `transmits` solicitations
+    pub fn new(address: Ipv6Addr, transmits: u8, now: Instant) -> Self {
+        let random = RandomState::new().hash_one(address);
+        let millis = u64::try_from(MAX_DELAY.as_millis()).unwrap_or(u64::MAX);
+        Self {
+            address,
+            transmits,
+            sent: 0,
+            due: now + Duration::from_millis(random % millis),
+        }
+    }
+
+    pub const fn address(&self) -> Ipv6Addr {
+        self.address
+    }
+
+    /// When [DadProbe::poll] next has something to do
+    pub const fn next_deadline(&self) -> Instant {
+        self.due
+    }
+
+    /// Whether every solicitation has gone unanswered for long enough, so
+    /// the address may be used
+    pub fn is_done(&self, now: Instant) -> bool {
+        self.sent >= self.transmits && self.due <= now
+    }
+
+    /// The solicitation due by `now`, if any, to send from the unspecified
+    /// address to the solicited-node group
+    ///
+    /// It carries no MAC: with no address to send from, we can't be
+    /// answered directly.
+    pub fn poll(&mut self, now: Instant) -> Option<NeighbourSolicitation> {
+        if self.due > now || self.sent >= self.transmits {
+            return None;
+        }
+        self.sent += 1;
+        self.due = now + RETRANS_TIMER;
+        Some(NeighbourSolicitation {
+            target: self.address,
+            options: Vec::new(),
+        })
+    }
+
+    /// Whether `advertisement` defends the address against us
+    pub fn defended(&self, advertisement: &NeighbourAdvertisement) -> bool {
+        advertisement.target == self.address
+    }
+
+    /// Whether `solicitation`, in `packet`, is another host checking for
+    /// the address at the same time
+    pub fn contested(&self, packet: &Ipv6Packet, solicitation: &NeighbourSolicitation) -> bool {
+        packet.source.is_unspecified() && solicitation.target == self.address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::IpProtocol;
+    use crate::layer3::ndp;
+
+    const ADDRESS: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+    #[test]
+    fn solicits_then_assigns() {
+        let now = Instant::now();
+        let mut probe = DadProbe::new(ADDRESS, 2, now);
+        assert!(probe.next_deadline() < now + MAX_DELAY);
+        let mut sent = Vec::new();
+        while !probe.is_done(probe.next_deadline()) {
+            let due = probe.next_deadline();
+            sent.extend(probe.poll(due));
+        }
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|solicitation| {
+            solicitation.target == ADDRESS && solicitation.source_mac().is_none()
+        }));
+        assert!(probe.poll(probe.next_deadline()).is_none());
+    }
+
+    #[test]
+    fn conflicts() {
+        let probe = DadProbe::new(ADDRESS, DEFAULT_TRANSMITS, Instant::now());
+        let mac = Mac6::new([2, 0, 0, 0, 0, 2]);
+        assert!(probe.defended(&NeighbourAdvertisement::answer(ADDRESS, mac)));
+        assert!(!probe.defended(&NeighbourAdvertisement::answer(
+            "fe80::2".parse().unwrap(),
+            mac
+        )));
+
+        let solicitation = NeighbourSolicitation {
+            target: ADDRESS,
+            options: Vec::new(),
+        };
+        let from = |source| {
+            Ipv6Packet::new(
+                source,
+                ndp::solicited_node(ADDRESS),
+                IpProtocol::Icmpv6,
+                Vec::new(),
+            )
+        };
+        assert!(probe.contested(&from(Ipv6Addr::UNSPECIFIED), &solicitation));
+        // Just someone resolving us
+        assert!(!probe.contested(&from("fe80::2".parse().unwrap()), &solicitation));
+    }
+}
@@ -0,0 +1,201 @@
+//! Finding the socket a transport-layer packet is for, from its protocol
+//! and the addresses and ports of both ends
+use crate::layer3::IpProtocol;
+use crate::socket::EPHEMERAL_PORTS;
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Stands in for the remote end of a socket that isn't connected
+const ANYWHERE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+
+/// A flow's protocol, and its two ends as we see them
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FiveTuple {
+    pub protocol: IpProtocol,
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+}
+
+impl FiveTuple {
+    pub const fn new(protocol: IpProtocol, local: SocketAddrV4, remote: SocketAddrV4) -> Self {
+        Self {
+            protocol,
+            local,
+            remote,
+        }
+    }
+
+    /// A socket bound to `local` that takes packets from anywhere; its
+    /// address is unspecified if it's bound to all of ours
+    pub const fn bound(protocol: IpProtocol, local: SocketAddrV4) -> Self {
+        Self::new(protocol, local, ANYWHERE)
+    }
+
+    /// Whether this is of a socket bound to a port, rather than connected
+    pub fn is_bound(&self) -> bool {
+        self.remote == ANYWHERE
+    }
+}
+
+/// Sockets, each connected to one remote end or bound to a local port
+///
+/// Finding a packet's socket takes at most three lookups, whatever the
+/// number of sockets.
+#[derive(Debug)]
+pub struct SocketTable<T> {
+    sockets: HashMap<FiveTuple, T>,
+}
+
+impl<T> Default for SocketTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SocketTable<T> {
+    pub fn new() -> Self {
+        Self {
+            sockets: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Add a socket, unless there's one for `tuple` already
+    pub fn insert(&mut self, tuple: FiveTuple, socket: T) -> Result<()> {
+        if self.sockets.contains_key(&tuple) {
+            bail!("{:?} socket for {tuple:?} already open", tuple.protocol);
+        }
+        self.sockets.insert(tuple, socket);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, tuple: &FiveTuple) -> Option<T> {
+        self.sockets.remove(tuple)
+    }
+
+    /// The socket for a packet between `tuple`'s ends, and the tuple it's
+    /// under
+    ///
+    /// A connected socket wins over one bound to the local end, which wins
+    /// over one bound to the port on all of our addresses.
+    pub fn lookup(&self, tuple: &FiveTuple) -> Option<(&FiveTuple, &T)> {
+        let any_address = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, tuple.local.port());
+        [
+            *tuple,
+            FiveTuple::bound(tuple.protocol, tuple.local),
+            FiveTuple::bound(tuple.protocol, any_address),
+        ]
+        .iter()
+        .find_map(|tuple| self.sockets.get_key_value(tuple))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&FiveTuple, &T)> {
+        self.sockets.iter()
+    }
+
+    /// Keep only the sockets `keep` returns true for
+    pub fn retain(&mut self, keep: impl FnMut(&FiveTuple, &mut T) -> bool) {
+        self.sockets.retain(keep);
+    }
+
+    /// `port`, or a free one if it's 0, for a `protocol` socket on
+    /// `address`
+    ///
+    /// Each port is used by one socket, unless they're bound to different
+    /// addresses.
+    pub fn pick_port(&self, protocol: IpProtocol, address: Ipv4Addr, port: u16) -> Result<u16> {
+        let in_use = |port| {
+            self.sockets.keys().any(|tuple| {
+                let local = tuple.local;
+                tuple.protocol == protocol
+                    && local.port() == port
+                    && (*local.ip() == address
+                        || local.ip().is_unspecified()
+                        || address.is_unspecified())
+            })
+        };
+        match port {
+            0 => EPHEMERAL_PORTS
+                .clone()
+                .find(|&port| !in_use(port))
+                .ok_or_else(|| anyhow!("out of ports")),
+            port if in_use(port) => bail!("port {port} in use"),
+            port => Ok(port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const THEM: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn tuple(local: Ipv4Addr, port: u16) -> FiveTuple {
+        FiveTuple::new(
+            IpProtocol::Tcp,
+            SocketAddrV4::new(local, port),
+            SocketAddrV4::new(THEM, 5555),
+        )
+    }
+
+    #[test]
+    fn most_specific_wins() -> Result<()> {
+        let mut table = SocketTable::new();
+        let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 80);
+        table.insert(FiveTuple::bound(IpProtocol::Tcp, any), "listening")?;
+        assert_eq!(table.lookup(&tuple(US, 80)).unwrap().1, &"listening");
+
+        table.insert(tuple(US, 80), "connected")?;
+        assert_eq!(table.lookup(&tuple(US, 80)).unwrap().1, &"connected");
+        let other = FiveTuple {
+            remote: SocketAddrV4::new(THEM, 5556),
+            ..tuple(US, 80)
+        };
+        let (found, _) = table.lookup(&other).unwrap();
+        assert!(found.is_bound());
+
+        // Not for another protocol, or port
+        let udp = FiveTuple {
+            protocol: IpProtocol::Udp,
+            ..other
+        };
+        assert!(table.lookup(&udp).is_none());
+        assert!(table.lookup(&tuple(US, 81)).is_none());
+        assert!(table.insert(tuple(US, 80), "again").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn picks_ports() -> Result<()> {
+        let mut table = SocketTable::new();
+        let bound =
+            |address, port| FiveTuple::bound(IpProtocol::Udp, SocketAddrV4::new(address, port));
+        table.insert(bound(US, 53), ())?;
+        assert!(table.pick_port(IpProtocol::Udp, US, 53).is_err());
+        assert!(
+            table
+                .pick_port(IpProtocol::Udp, Ipv4Addr::UNSPECIFIED, 53)
+                .is_err()
+        );
+        assert_eq!(table.pick_port(IpProtocol::Udp, THEM, 53)?, 53);
+        assert_eq!(table.pick_port(IpProtocol::Tcp, US, 53)?, 53);
+
+        let first = table.pick_port(IpProtocol::Udp, US, 0)?;
+        assert!(EPHEMERAL_PORTS.contains(&first));
+        table.insert(bound(US, first), ())?;
+        assert_ne!(table.pick_port(IpProtocol::Udp, US, 0)?, first);
+        table.retain(|tuple, ()| tuple.local.port() != first);
+        assert_eq!(table.pick_port(IpProtocol::Udp, US, 0)?, first);
+        Ok(())
+    }
+}
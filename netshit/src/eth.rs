@@ -1,15 +1,77 @@
-use crate::layer3::{ArpPacket, Ipv4Packet, Layer3Packet};
-use anyhow::{Result, bail};
+use crate::checksum::ChecksumCapabilities;
+use crate::layer3::{
+    ArpPacket, EapolPacket, Ipv4Packet, Layer3Packet, LlcPacket, LldpPacket, MacsecPacket,
+};
+use crate::limits::ParseLimits;
+use anyhow::{Result, anyhow, bail};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub mod ethtype {
-    pub const IPV4: u16 = 0x0800;
-    pub const ARP: u16 = 0x0806;
-    pub const IPV6: u16 = 0x86dd;
+/// Length of the fixed IPv6 header
+const IPV6_HEADER_LENGTH: usize = 40;
+
+/// Values of the ethtype field below this are 802.3 payload lengths
+const MIN_ETHTYPE: u16 = 0x0600;
+
+/// The protocol carried by an Ethernet II frame
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EtherType {
+    Ipv4,
+    Ipv6,
+    Arp,
+    /// Reverse ARP
+    Rarp,
+    /// Link Layer Discovery Protocol
+    Lldp,
+    WakeOnLan,
+    /// 802.1X EAP over LAN
+    Eapol,
+    /// 802.1AE MAC security
+    Macsec,
+    /// 802.1Q VLAN tag
+    Vlan,
+    Unknown(u16),
+}
+
+impl TryFrom<u16> for EtherType {
+    type Error = anyhow::Error;
+    /// Fails for values that are 802.3 lengths rather than EtherTypes
+    fn try_from(value: u16) -> std::result::Result<Self, Self::Error> {
+        Ok(match value {
+            0x0800 => Self::Ipv4,
+            0x86dd => Self::Ipv6,
+            0x0806 => Self::Arp,
+            0x8035 => Self::Rarp,
+            0x88cc => Self::Lldp,
+            0x0842 => Self::WakeOnLan,
+            0x888e => Self::Eapol,
+            0x88e5 => Self::Macsec,
+            0x8100 => Self::Vlan,
+            ..MIN_ETHTYPE => return Err(anyhow!("0x{value:04x} is a length, not an EtherType")),
+            _ => Self::Unknown(value),
+        })
+    }
+}
+
+impl From<EtherType> for u16 {
+    fn from(value: EtherType) -> Self {
+        match value {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Ipv6 => 0x86dd,
+            EtherType::Arp => 0x0806,
+            EtherType::Rarp => 0x8035,
+            EtherType::Lldp => 0x88cc,
+            EtherType::WakeOnLan => 0x0842,
+            EtherType::Eapol => 0x888e,
+            EtherType::Macsec => 0x88e5,
+            EtherType::Vlan => 0x8100,
+            EtherType::Unknown(value) => value,
+        }
+    }
 }
 
 /// A 48-bit ethernet MAC address
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Mac6 {
     inner: [u8; 6],
 }
@@ -34,7 +96,92 @@ impl std::fmt::Display for Mac6 {
     }
 }
 
+impl std::str::FromStr for Mac6 {
+    type Err = anyhow::Error;
+
+    /// Accepts `aa:bb:cc:dd:ee:ff`, `AA-BB-CC-DD-EE-FF`, or `aabb.ccdd.eeff`
+    fn from_str(s: &str) -> Result<Self> {
+        let groups: Vec<&str> = if s.contains('.') {
+            s.split('.').collect()
+        } else if s.contains('-') {
+            s.split('-').collect()
+        } else {
+            s.split(':').collect()
+        };
+
+        // Six groups of one byte, or three groups of two
+        let group_len = match groups.len() {
+            6 => 2,
+            3 => 4,
+            n => bail!("Bad MAC address {s:?}: expected 6 or 3 groups, found {n}"),
+        };
+
+        let mut inner = [0; 6];
+        let mut bytes = inner.iter_mut();
+        for group in groups {
+            if group.len() != group_len {
+                bail!("Bad MAC address {s:?}: group {group:?} should be {group_len} hex digits");
+            }
+            if !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+                bail!("Bad MAC address {s:?}: {group:?} isn't hex");
+            }
+            for i in (0..group_len).step_by(2) {
+                *bytes.next().expect("group lengths add up to 6 bytes") =
+                    u8::from_str_radix(&group[i..i + 2], 16)?;
+            }
+        }
+        Ok(Self { inner })
+    }
+}
+
 impl Mac6 {
+    /// Every station on the segment
+    pub const BROADCAST: Self = Self::new([0xff; 6]);
+
+    pub const fn new(inner: [u8; 6]) -> Self {
+        Self { inner }
+    }
+
+    /// The MAC an IPv4 multicast group is sent to: 01:00:5e plus the low 23
+    /// bits of the group, or `None` if `addr` isn't multicast
+    pub const fn from_ipv4_multicast(addr: Ipv4Addr) -> Option<Self> {
+        if !addr.is_multicast() {
+            return None;
+        }
+        let [_, b, c, d] = addr.octets();
+        Some(Self::new([0x01, 0x00, 0x5e, b & 0x7f, c, d]))
+    }
+
+    /// The MAC an IPv6 multicast group is sent to: 33:33 plus the low 32
+    /// bits of the group, or `None` if `addr` isn't multicast
+    pub const fn from_ipv6_multicast(addr: Ipv6Addr) -> Option<Self> {
+        if !addr.is_multicast() {
+            return None;
+        }
+        let octets = addr.octets();
+        Some(Self::new([
+            0x33, 0x33, octets[12], octets[13], octets[14], octets[15],
+        ]))
+    }
+
+    /// The modified EUI-64 interface identifier IPv6 addresses can be formed
+    /// with: the MAC with ff:fe in the middle and the universal/local bit
+    /// flipped (RFC 4291 appendix A)
+    pub const fn eui64(self) -> u64 {
+        let [a, b, c, d, e, f] = self.inner;
+        u64::from_be_bytes([a ^ 0x02, b, c, 0xff, 0xfe, d, e, f])
+    }
+
+    /// Our IPv6 link-local address: `fe80::/64` plus [Mac6::eui64]
+    pub const fn link_local(self) -> Ipv6Addr {
+        Ipv6Addr::from_bits((0xfe80 << 112) | self.eui64() as u128)
+    }
+
+    /// True for group (multicast and broadcast) addresses
+    pub const fn is_multicast(&self) -> bool {
+        self.inner[0] & 0x01 != 0
+    }
+
     pub const fn into_inner(self) -> [u8; 6] {
         self.inner
     }
@@ -50,18 +197,159 @@ impl Mac6 {
     }
 }
 
+/// Destination, source, and ethtype
+const HEADER_LENGTH: usize = 14;
+
+/// Largest payload of a standard frame
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Largest payload we handle, for jumbo frames
+pub const MAX_MTU: usize = 9000;
+
+/// Largest frame we handle, FCS included
+pub const MAX_FRAME_LENGTH: usize = HEADER_LENGTH + MAX_MTU + 4;
+
+/// Shortest valid frame, not counting the FCS; shorter payloads are padded
+const MIN_FRAME_LENGTH: usize = 60;
+
+/// CRC used for the frame check sequence
+const FCS: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Error for a frame whose FCS doesn't match its contents
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FcsMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for FcsMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FCS mismatch: frame says 0x{:08x}, contents give 0x{:08x}",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for FcsMismatch {}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct EthFrame {
     /// Destination MAC
     dst: Mac6,
     /// source MAC
     src: Mac6,
-    ethtype: u16,
+    /// `None` for 802.3 frames, where the field holds the payload length
+    ethtype: Option<EtherType>,
     payload: Layer3Packet,
 }
 
 impl EthFrame {
-    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+    /// Build a frame, with the ethtype inferred from the payload
+    ///
+    /// LLC and unknown payloads are sent as 802.3 frames.
+    pub const fn new(dst: Mac6, src: Mac6, payload: Layer3Packet) -> Self {
+        let ethtype = match payload {
+            Layer3Packet::Ipv4(_) => Some(EtherType::Ipv4),
+            Layer3Packet::Arp(_) => Some(EtherType::Arp),
+            Layer3Packet::Rarp(_) => Some(EtherType::Rarp),
+            Layer3Packet::Ipv6(_) => Some(EtherType::Ipv6),
+            Layer3Packet::Lldp(_) => Some(EtherType::Lldp),
+            Layer3Packet::WakeOnLan(_) => Some(EtherType::WakeOnLan),
+            Layer3Packet::Eapol(_) => Some(EtherType::Eapol),
+            Layer3Packet::Macsec(_) => Some(EtherType::Macsec),
+            Layer3Packet::UnknownEthType { ethtype, .. } => Some(ethtype),
+            Layer3Packet::Llc(_) | Layer3Packet::Unknown(_) => None,
+        };
+        Self {
+            dst,
+            src,
+            ethtype,
+            payload,
+        }
+    }
+
+    /// Build a response to this frame, sent back to its source from the MAC
+    /// it was addressed to
+    ///
+    /// For broadcast requests (e.g. ARP), use [EthFrame::new] with our own
+    /// MAC as the source instead.
+    pub const fn reply(&self, payload: Layer3Packet) -> Self {
+        Self::new(self.src, self.dst, payload)
+    }
+
+    /// Destination MAC
+    pub const fn dst(&self) -> Mac6 {
+        self.dst
+    }
+
+    /// Source MAC
+    pub const fn src(&self) -> Mac6 {
+        self.src
+    }
+
+    /// `None` for 802.3 frames
+    pub const fn ethtype(&self) -> Option<EtherType> {
+        self.ethtype
+    }
+
+    pub const fn payload(&self) -> &Layer3Packet {
+        &self.payload
+    }
+
+    pub const fn payload_mut(&mut self) -> &mut Layer3Packet {
+        &mut self.payload
+    }
+
+    /// Parse a frame that ends in an FCS, verifying it
+    ///
+    /// Reads the reader to the end, so give it exactly one frame. A bad FCS
+    /// is reported as an [FcsMismatch] (see `anyhow::Error::downcast_ref`).
+    /// Use [EthFrame::from_reader] for captures without an FCS.
+    pub async fn from_reader_with_fcs(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        let Some(body_len) = raw.len().checked_sub(4) else {
+            bail!("Frame too short to have an FCS: {} bytes", raw.len());
+        };
+
+        let (body, fcs) = raw.split_at(body_len);
+        let actual = u32::from_le_bytes(fcs.try_into()?);
+        let expected = FCS.checksum(body);
+        if actual != expected {
+            return Err(FcsMismatch { expected, actual }.into());
+        }
+        Self::from_reader(body).await
+    }
+
+    /// Parse a frame, ignoring anything after its payload (such as padding
+    /// or an FCS)
+    ///
+    /// Payload length comes from the payload itself (e.g. the IPv4 total
+    /// length), or from the length field of 802.3 frames.
+    pub async fn from_reader(reader: impl AsyncRead + Unpin) -> Result<Self> {
+        Self::from_reader_with_limits(reader, &ParseLimits::default()).await
+    }
+
+    /// Like [EthFrame::from_reader], but failing with
+    /// [TooLarge](crate::limits::TooLarge) for payloads over `limits`
+    pub async fn from_reader_with_limits(
+        reader: impl AsyncRead + Unpin,
+        limits: &ParseLimits,
+    ) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, limits, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [EthFrame::from_reader_with_limits], but verifying only the
+    /// checksums `checksums` says to
+    ///
+    /// The FCS, if any, is another matter; see [EthFrame::from_reader_with_fcs].
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        limits: &ParseLimits,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
         let mut dst = [0; 6];
         reader.read_exact(&mut dst).await?;
 
@@ -70,24 +358,75 @@ impl EthFrame {
 
         let ethtype = reader.read_u16().await?;
 
-        let payload = match ethtype {
-            // Empty frame
-            0 => Layer3Packet::Unknown(Vec::new()),
-            // If it's under 1536 it's the length
-            1..1536 => {
+        let (ethtype, payload) = match EtherType::try_from(ethtype) {
+            // An 802.3 length (or an empty frame)
+            Err(_) => {
+                limits.check_payload("802.3 payload", ethtype.into())?;
                 let mut payload = vec![0; ethtype as usize];
                 reader.read_exact(&mut payload).await?;
-                Layer3Packet::Unknown(payload)
+                // Keep payloads too short or odd to be LLC as they are
+                let payload = match LlcPacket::from_reader(payload.as_slice()).await {
+                    Ok(packet) => Layer3Packet::Llc(packet),
+                    Err(_) => Layer3Packet::Unknown(payload),
+                };
+                (None, payload)
             }
-            ethtype::IPV4 => Layer3Packet::Ipv4(Ipv4Packet::from_reader(&mut reader).await?),
-            ethtype::ARP => Layer3Packet::Arp(ArpPacket::from_reader(&mut reader).await?),
-            _ => {
-                bail!("Unknown eth type: 0x{ethtype:04x}");
+            Ok(EtherType::Ipv4) => (
+                Some(EtherType::Ipv4),
+                Layer3Packet::Ipv4(
+                    Ipv4Packet::from_reader_with_checksums(&mut reader, limits, checksums).await?,
+                ),
+            ),
+            Ok(EtherType::Arp) => (
+                Some(EtherType::Arp),
+                Layer3Packet::Arp(ArpPacket::from_reader_with_limits(&mut reader, limits).await?),
+            ),
+            Ok(EtherType::Rarp) => (
+                Some(EtherType::Rarp),
+                Layer3Packet::Rarp(ArpPacket::from_reader_with_limits(&mut reader, limits).await?),
+            ),
+            Ok(EtherType::Ipv6) => {
+                // Keep the raw packet, trimmed to its payload length
+                let mut packet = vec![0; IPV6_HEADER_LENGTH];
+                reader.read_exact(&mut packet).await?;
+                let payload_length = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+                limits.check_payload("IPv6 payload", payload_length)?;
+                packet.resize(IPV6_HEADER_LENGTH + payload_length, 0);
+                reader.read_exact(&mut packet[IPV6_HEADER_LENGTH..]).await?;
+                (Some(EtherType::Ipv6), Layer3Packet::Ipv6(packet))
+            }
+            Ok(EtherType::Lldp) => (
+                Some(EtherType::Lldp),
+                Layer3Packet::Lldp(LldpPacket::from_reader(&mut reader).await?),
+            ),
+            Ok(EtherType::WakeOnLan) => (
+                Some(EtherType::WakeOnLan),
+                Layer3Packet::WakeOnLan(crate::wol::from_reader(&mut reader).await?),
+            ),
+            Ok(EtherType::Eapol) => (
+                Some(EtherType::Eapol),
+                Layer3Packet::Eapol(EapolPacket::from_reader(&mut reader).await?),
+            ),
+            Ok(EtherType::Macsec) => (
+                Some(EtherType::Macsec),
+                Layer3Packet::Macsec(MacsecPacket::from_reader(&mut reader).await?),
+            ),
+            Ok(ethtype) => {
+                // No length to go on, so take everything (padding included)
+                let mut payload = Vec::new();
+                let max = limits.max_payload as u64;
+                (&mut reader)
+                    .take(max + 1)
+                    .read_to_end(&mut payload)
+                    .await?;
+                limits.check_payload("Payload", payload.len())?;
+                (
+                    Some(ethtype),
+                    Layer3Packet::UnknownEthType { ethtype, payload },
+                )
             }
         };
 
-        //let _crc = reader.read_u32().await?;
-
         Ok(Self {
             dst: Mac6::from(dst),
             src: Mac6::from(src),
@@ -96,16 +435,59 @@ impl EthFrame {
         })
     }
 
-    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+    /// Serialize a frame into a writer, allowing payloads up to [MAX_MTU]
+    pub async fn onto_writer(&mut self, writer: impl AsyncWrite + Unpin) -> Result<()> {
+        self.onto_writer_with_mtu(writer, MAX_MTU).await
+    }
+
+    /// Serialize a frame into a writer, failing if the payload is larger
+    /// than `mtu`
+    ///
+    /// Nothing is written on failure. Oversize payloads aren't fragmented,
+    /// as we always send IPv4 with Don't Fragment set.
+    pub async fn onto_writer_with_mtu(
+        &mut self,
+        writer: impl AsyncWrite + Unpin,
+        mtu: usize,
+    ) -> Result<()> {
+        self.onto_writer_with_checksums(writer, mtu, &ChecksumCapabilities::default())
+            .await
+    }
+
+    /// Like [EthFrame::onto_writer_with_mtu], but computing only the
+    /// checksums `checksums` says to
+    pub async fn onto_writer_with_checksums(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        mtu: usize,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<()> {
+        if mtu > MAX_MTU {
+            bail!("MTU {mtu} is over the maximum of {MAX_MTU}");
+        }
+        let mut payload = Vec::new();
+        self.payload
+            .onto_writer_with_checksums(&mut payload, checksums)
+            .await?;
+        if payload.len() > mtu {
+            bail!("Payload of {} bytes exceeds MTU of {mtu}", payload.len());
+        }
+
         let mut vec = Vec::new();
         vec.write_all(self.dst.as_bytes()).await?;
         vec.write_all(self.src.as_bytes()).await?;
-        vec.write_u16(self.ethtype).await?;
-        self.payload.onto_writer(&mut vec).await?;
+        match self.ethtype {
+            Some(ethtype) => vec.write_u16(ethtype.into()).await?,
+            None => vec.write_u16(payload.len().try_into()?).await?,
+        }
+        vec.write_all(&payload).await?;
+        if vec.len() < MIN_FRAME_LENGTH {
+            vec.resize(MIN_FRAME_LENGTH, 0);
+        }
 
-        let hasher = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let crc = hasher.checksum(&vec);
-        vec.write_u32(crc).await?;
+        // The FCS goes on the wire least significant byte first
+        let fcs = FCS.checksum(&vec);
+        vec.write_u32_le(fcs).await?;
 
         writer.write_all(&vec).await?;
         Ok(())
@@ -115,6 +497,8 @@ impl EthFrame {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::layer3::IpProtocol;
+    use crate::layer3::ipv4::{Dscp, Ecn};
     use anyhow::Result;
 
     #[tokio::test]
@@ -132,7 +516,7 @@ mod tests {
 
         let frame = EthFrame::from_reader(raw_frame.as_slice()).await.unwrap();
 
-        assert_eq!(frame.ethtype, ethtype::IPV4);
+        assert_eq!(frame.ethtype, Some(EtherType::Ipv4));
         let Layer3Packet::Ipv4(packet) = frame.payload else {
             panic!("Wrong packet type!");
         };
@@ -142,12 +526,11 @@ mod tests {
 
     #[tokio::test]
     async fn write_frame() -> Result<()> {
-        let mut frame = EthFrame {
-            src: Mac6::from([1, 2, 3, 4, 5, 6]),
-            dst: Mac6::from([7, 8, 9, 10, 11, 12]),
-            ethtype: 4,
-            payload: Layer3Packet::Unknown(vec![3, 1, 4, 1]),
-        };
+        let mut frame = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Unknown(vec![3, 1]),
+        );
 
         let mut vec = Vec::new();
         frame.onto_writer(&mut vec).await?;
@@ -159,6 +542,277 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn pad_short_frames() -> Result<()> {
+        let mut frame = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Ipv4(Ipv4Packet {
+                dscp: Dscp::CS0,
+                ecn: Ecn::NotEct,
+                identification: 1,
+                ttl: 64,
+                protocol: IpProtocol::Udp,
+                source: "10.0.0.1".parse()?,
+                destination: "10.0.0.2".parse()?,
+                options: Vec::new(),
+                data: vec![0xab; 4],
+                header_checksum: None,
+            }),
+        );
+        assert_eq!(frame.ethtype(), Some(EtherType::Ipv4));
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(vec.len(), MIN_FRAME_LENGTH + 4);
+        assert!(vec[14 + 24..MIN_FRAME_LENGTH].iter().all(|&b| b == 0));
+        assert_eq!(EthFrame::from_reader_with_fcs(vec.as_slice()).await?, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv6_frame() -> Result<()> {
+        // Header with a 2-byte payload, then padding
+        let mut packet = vec![0x60, 0, 0, 0, 0, 2, 17, 64];
+        packet.extend_from_slice(&[0xfe; 32]);
+        packet.extend_from_slice(&[0xaa, 0xbb]);
+        let mut frame = EthFrame::new(
+            Mac6::from([0x33, 0x33, 0, 0, 0, 1]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Ipv6(packet),
+        );
+        assert_eq!(frame.ethtype(), Some(EtherType::Ipv6));
+
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(vec[12..14], [0x86, 0xdd]);
+        assert_eq!(EthFrame::from_reader_with_fcs(vec.as_slice()).await?, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mtu() -> Result<()> {
+        // An IPv6 packet with an 8000 byte payload
+        let mut packet = vec![0x60, 0, 0, 0, 0x1f, 0x40, 59, 64];
+        packet.resize(IPV6_HEADER_LENGTH + 8000, 0);
+        let mut frame = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Ipv6(packet),
+        );
+
+        let mut vec = Vec::new();
+        let result = frame.onto_writer_with_mtu(&mut vec, DEFAULT_MTU).await;
+        assert!(result.is_err());
+        assert!(vec.is_empty());
+        assert!(frame.onto_writer_with_mtu(&mut vec, 9001).await.is_err());
+
+        // Jumbo frames are fine otherwise
+        frame.onto_writer(&mut vec).await?;
+        assert!(vec.len() <= MAX_FRAME_LENGTH);
+        assert_eq!(EthFrame::from_reader_with_fcs(vec.as_slice()).await?, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits() -> Result<()> {
+        let mut packet = vec![0x60, 0, 0, 0, 0x04, 0x00, 59, 64];
+        packet.resize(IPV6_HEADER_LENGTH + 1024, 0);
+        let mut frame = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Ipv6(packet),
+        );
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+
+        let limits = ParseLimits::default().set_max_payload(1000);
+        let err = EthFrame::from_reader_with_limits(vec.as_slice(), &limits)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "IPv6 payload of 1024 bytes is over the limit of 1000"
+        );
+        assert!(EthFrame::from_reader(vec.as_slice()).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rarp() -> Result<()> {
+        let mac = Mac6::new([2, 0, 0, 0, 0, 1]);
+        let mut frame = EthFrame::new(
+            Mac6::BROADCAST,
+            mac,
+            Layer3Packet::Rarp(ArpPacket::rarp_request(mac)),
+        );
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(vec[12..14], [0x80, 0x35]);
+
+        let parsed = EthFrame::from_reader(vec.as_slice()).await?;
+        assert_eq!(parsed.ethtype(), Some(EtherType::Rarp));
+        assert_eq!(parsed.payload(), frame.payload());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_ethtype() -> Result<()> {
+        let mut raw = vec![0xff; 6];
+        raw.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0x88, 0xb5]);
+        raw.extend_from_slice(&[0xaa; 46]);
+        let mut frame = EthFrame::from_reader(raw.as_slice()).await?;
+        assert_eq!(frame.ethtype(), Some(EtherType::Unknown(0x88b5)));
+        let Layer3Packet::UnknownEthType { payload, .. } = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(payload, &[0xaa; 46]);
+
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(vec[..vec.len() - 4], raw);
+
+        // Known but unparsed ethtypes too
+        raw[12..14].copy_from_slice(&[0x81, 0x00]);
+        let frame = EthFrame::from_reader(raw.as_slice()).await?;
+        assert_eq!(frame.ethtype(), Some(EtherType::Vlan));
+        Ok(())
+    }
+
+    #[test]
+    fn reply_swaps_macs() {
+        let request = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Unknown(vec![3, 1]),
+        );
+        let reply = request.reply(Layer3Packet::Unknown(vec![3, 2]));
+        assert_eq!(reply.dst(), request.src());
+        assert_eq!(reply.src(), request.dst());
+        assert_eq!(reply.payload(), &Layer3Packet::Unknown(vec![3, 2]));
+    }
+
+    #[tokio::test]
+    async fn verify_fcs() -> Result<()> {
+        let mut frame = EthFrame::new(
+            Mac6::from([7, 8, 9, 10, 11, 12]),
+            Mac6::from([1, 2, 3, 4, 5, 6]),
+            Layer3Packet::Unknown(vec![3, 1]),
+        );
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(EthFrame::from_reader_with_fcs(vec.as_slice()).await?, frame);
+
+        // The CRC over a frame and its FCS leaves a fixed residue
+        assert_eq!(FCS.checksum(&vec), 0x2144df1c);
+
+        vec[13] ^= 0x01;
+        let err = EthFrame::from_reader_with_fcs(vec.as_slice())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<FcsMismatch>().is_some());
+
+        // Without an FCS, the plain parser still works
+        vec[13] ^= 0x01;
+        vec.truncate(vec.len() - 4);
+        assert_eq!(EthFrame::from_reader(vec.as_slice()).await?, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_llc_frame() -> Result<()> {
+        let mut raw = vec![0x01, 0x80, 0xc2, 0, 0, 0, 1, 2, 3, 4, 5, 6, 0, 7];
+        raw.extend_from_slice(&[0x42, 0x42, 0x03, 0, 0, 0, 0]);
+        let frame = EthFrame::from_reader(raw.as_slice()).await?;
+        assert_eq!(frame.ethtype, None);
+        let Layer3Packet::Llc(packet) = frame.payload else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((packet.dsap, packet.ssap), (0x42, 0x42));
+        assert_eq!(packet.data, [0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn ethertype_conversions() {
+        assert_eq!(EtherType::try_from(0x0806).unwrap(), EtherType::Arp);
+        assert_eq!(
+            EtherType::try_from(0x88b5).unwrap(),
+            EtherType::Unknown(0x88b5)
+        );
+        assert!(EtherType::try_from(0x05dc).is_err());
+        for ethtype in [
+            EtherType::Ipv4,
+            EtherType::Ipv6,
+            EtherType::Rarp,
+            EtherType::Lldp,
+            EtherType::WakeOnLan,
+            EtherType::Eapol,
+            EtherType::Macsec,
+            EtherType::Vlan,
+        ] {
+            assert_eq!(EtherType::try_from(u16::from(ethtype)).unwrap(), ethtype);
+        }
+    }
+
+    #[test]
+    fn parse_mac() {
+        let mac = Mac6::from([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!("aa:bb:cc:dd:ee:ff".parse::<Mac6>().unwrap(), mac);
+        assert_eq!("AA-BB-CC-DD-EE-FF".parse::<Mac6>().unwrap(), mac);
+        assert_eq!("aabb.ccdd.eeff".parse::<Mac6>().unwrap(), mac);
+        assert_eq!(mac.to_string().parse::<Mac6>().unwrap(), mac);
+
+        for bad in [
+            "",
+            "aa:bb:cc:dd:ee",
+            "aa:bb:cc:dd:ee:fg",
+            "aabb.ccdd.eef",
+            "a:bb:cc:dd:ee:ff0",
+        ] {
+            assert!(bad.parse::<Mac6>().is_err(), "{bad:?} parsed");
+        }
+        assert_eq!(
+            "aa:bb:cc:dd:ee:zz".parse::<Mac6>().unwrap_err().to_string(),
+            "Bad MAC address \"aa:bb:cc:dd:ee:zz\": \"zz\" isn't hex"
+        );
+    }
+
+    #[test]
+    fn multicast_macs() {
+        let mdns = Mac6::from_ipv4_multicast(Ipv4Addr::new(224, 0, 0, 251)).unwrap();
+        assert_eq!(mdns.to_string(), "01:00:5E:00:00:FB");
+        assert!(mdns.is_multicast());
+        // The top bit of the second octet doesn't fit
+        let high = Mac6::from_ipv4_multicast(Ipv4Addr::new(239, 255, 0, 1)).unwrap();
+        assert_eq!(high.to_string(), "01:00:5E:7F:00:01");
+        assert_eq!(Mac6::from_ipv4_multicast(Ipv4Addr::new(10, 0, 0, 1)), None);
+
+        let all_nodes: Ipv6Addr = "ff02::1".parse().unwrap();
+        assert_eq!(
+            Mac6::from_ipv6_multicast(all_nodes).unwrap().to_string(),
+            "33:33:00:00:00:01"
+        );
+        let solicited: Ipv6Addr = "ff02::1:ff12:3456".parse().unwrap();
+        assert_eq!(
+            Mac6::from_ipv6_multicast(solicited).unwrap().to_string(),
+            "33:33:FF:12:34:56"
+        );
+        assert_eq!(Mac6::from_ipv6_multicast(Ipv6Addr::LOCALHOST), None);
+    }
+
+    #[test]
+    fn ipv6_addresses() {
+        let mac = Mac6::new([0x00, 0xaa, 0x00, 0x28, 0x9c, 0x5a]);
+        assert_eq!(mac.eui64(), 0x02aa_00ff_fe28_9c5a);
+        assert_eq!(
+            mac.link_local(),
+            "fe80::2aa:ff:fe28:9c5a".parse::<Ipv6Addr>().unwrap()
+        );
+        // Locally administered MACs lose the bit instead
+        let local = Mac6::new([0x02, 0, 0, 0, 0, 1]);
+        assert_eq!(local.link_local().to_string(), "fe80::ff:fe00:1");
+    }
+
     #[test]
     fn format_mac() {
         assert_eq!(
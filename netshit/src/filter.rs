@@ -0,0 +1,522 @@
+//! Predicates deciding which received frames get handled
+//!
+//! Filters see the raw bytes first, so cheap checks can skip parsing, then
+//! the parsed frame. Build them from the matchers here and combine them with
+//! [FrameFilterExt].
+//!
+//! `accept` alone makes the decision; `accept_raw` is only a shortcut, and
+//! may only reject frames `accept` would reject too.
+//!
+//! [Firewall] is the stack's counterpart for IP packets: ordered rules it
+//! checks before delivering or forwarding anything. It tracks the flows it
+//! accepts, so rules may match on whether a packet's part of one.
+use crate::conntrack::{ConnState, Conntrack, Flow};
+use crate::eth::{EthFrame, EtherType, Mac6};
+use crate::layer3::{IpProtocol, Ipv4Packet, Layer3Packet};
+use crate::route::Ipv4Prefix;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+pub trait FrameFilter: Send + Sync {
+    /// Decide on a frame before it's parsed
+    fn accept_raw(&self, _raw: &[u8]) -> bool {
+        true
+    }
+
+    /// Decide on a parsed frame
+    fn accept(&self, frame: &EthFrame) -> bool;
+}
+
+impl<F: Fn(&EthFrame) -> bool + Send + Sync> FrameFilter for F {
+    fn accept(&self, frame: &EthFrame) -> bool {
+        self(frame)
+    }
+}
+
+impl FrameFilter for Box<dyn FrameFilter> {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        (**self).accept_raw(raw)
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        (**self).accept(frame)
+    }
+}
+
+/// Combinators for [FrameFilter]s
+pub trait FrameFilterExt: FrameFilter + Sized {
+    /// Accept frames both filters accept
+    fn and<F: FrameFilter>(self, other: F) -> And<Self, F> {
+        And(self, other)
+    }
+
+    /// Accept frames either filter accepts
+    fn or<F: FrameFilter>(self, other: F) -> Or<Self, F> {
+        Or(self, other)
+    }
+
+    /// Accept frames this filter rejects
+    fn not(self) -> Not<Self> {
+        Not(self)
+    }
+}
+
+impl<T: FrameFilter> FrameFilterExt for T {}
+
+pub struct And<A, B>(A, B);
+
+impl<A: FrameFilter, B: FrameFilter> FrameFilter for And<A, B> {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        self.0.accept_raw(raw) && self.1.accept_raw(raw)
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        self.0.accept(frame) && self.1.accept(frame)
+    }
+}
+
+pub struct Or<A, B>(A, B);
+
+impl<A: FrameFilter, B: FrameFilter> FrameFilter for Or<A, B> {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        self.0.accept_raw(raw) || self.1.accept_raw(raw)
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        self.0.accept(frame) || self.1.accept(frame)
+    }
+}
+
+pub struct Not<A>(A);
+
+impl<A: FrameFilter> FrameFilter for Not<A> {
+    // The inner shortcut can't be negated, so only parsed frames are checked
+    fn accept(&self, frame: &EthFrame) -> bool {
+        !self.0.accept(frame)
+    }
+}
+
+/// Accept everything
+pub struct All;
+
+impl FrameFilter for All {
+    fn accept(&self, _frame: &EthFrame) -> bool {
+        true
+    }
+}
+
+/// Frames to or from a MAC
+pub struct MacFilter(pub Mac6);
+
+impl FrameFilter for MacFilter {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        let mac = self.0.as_bytes();
+        raw.get(0..6) == Some(mac) || raw.get(6..12) == Some(mac)
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        frame.dst() == self.0 || frame.src() == self.0
+    }
+}
+
+/// Frames with an ethtype
+pub struct EtherTypeFilter(pub EtherType);
+
+impl FrameFilter for EtherTypeFilter {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        raw.get(12..14) == Some(&u16::from(self.0).to_be_bytes())
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        frame.ethtype() == Some(self.0)
+    }
+}
+
+/// IPv4 packets to or from an address
+pub struct Ipv4Filter(pub Ipv4Addr);
+
+impl FrameFilter for Ipv4Filter {
+    fn accept_raw(&self, raw: &[u8]) -> bool {
+        EtherTypeFilter(EtherType::Ipv4).accept_raw(raw)
+    }
+
+    fn accept(&self, frame: &EthFrame) -> bool {
+        match frame.payload() {
+            Layer3Packet::Ipv4(packet) => packet.source == self.0 || packet.destination == self.0,
+            _ => false,
+        }
+    }
+}
+
+/// What to do with a packet a [Rule] matches
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Accept,
+    Drop,
+}
+
+/// A firewall rule: packets matching every set field get its action
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Rule {
+    action: Action,
+    source: Option<Ipv4Prefix>,
+    destination: Option<Ipv4Prefix>,
+    protocol: Option<IpProtocol>,
+    source_ports: Option<RangeInclusive<u16>>,
+    destination_ports: Option<RangeInclusive<u16>>,
+    state: Option<ConnState>,
+}
+
+impl Rule {
+    /// A rule matching every packet
+    pub const fn new(action: Action) -> Self {
+        Self {
+            action,
+            source: None,
+            destination: None,
+            protocol: None,
+            source_ports: None,
+            destination_ports: None,
+            state: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn set_source(mut self, source: Ipv4Prefix) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    #[must_use]
+    pub const fn set_destination(mut self, destination: Ipv4Prefix) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    #[must_use]
+    pub const fn set_protocol(mut self, protocol: IpProtocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Only match TCP, UDP and UDP-Lite from these ports
+    #[must_use]
+    pub fn set_source_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.source_ports = Some(ports);
+        self
+    }
+
+    /// Only match TCP, UDP and UDP-Lite to these ports
+    #[must_use]
+    pub fn set_destination_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.destination_ports = Some(ports);
+        self
+    }
+
+    /// Only match packets in this state, as far as the firewall's tracked
+    /// flows go
+    #[must_use]
+    pub const fn set_state(mut self, state: ConnState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub const fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Whether a packet matches, ignoring any state the rule wants
+    pub fn matches(&self, packet: &Ipv4Packet) -> bool {
+        let prefix_matches = |prefix: &Option<Ipv4Prefix>, address| {
+            prefix.is_none_or(|prefix| prefix.contains(address))
+        };
+        if !prefix_matches(&self.source, packet.source)
+            || !prefix_matches(&self.destination, packet.destination)
+            || self
+                .protocol
+                .is_some_and(|protocol| protocol != packet.protocol)
+        {
+            return false;
+        }
+        if self.source_ports.is_none() && self.destination_ports.is_none() {
+            return true;
+        }
+        let Some((source, destination)) = ports(packet) else {
+            return false;
+        };
+        let port_matches = |ports: &Option<RangeInclusive<u16>>, port| {
+            ports.as_ref().is_none_or(|ports| ports.contains(&port))
+        };
+        port_matches(&self.source_ports, source)
+            && port_matches(&self.destination_ports, destination)
+    }
+}
+
+/// Source and destination ports of a TCP, UDP or UDP-Lite packet
+fn ports(packet: &Ipv4Packet) -> Option<(u16, u16)> {
+    if !matches!(
+        packet.protocol,
+        IpProtocol::Tcp | IpProtocol::Udp | IpProtocol::UdpLite
+    ) {
+        return None;
+    }
+    let &[source_high, source_low, destination_high, destination_low] =
+        packet.data.first_chunk::<4>()?;
+    Some((
+        u16::from_be_bytes([source_high, source_low]),
+        u16::from_be_bytes([destination_high, destination_low]),
+    ))
+}
+
+/// Ordered [Rule]s, of which the first to match a packet decides its fate
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Firewall {
+    /// Each rule, with how many packets it's matched
+    rules: Vec<(Rule, u64)>,
+    /// For packets no rule matches
+    policy: Action,
+    /// Flows of the packets accepted
+    conntrack: Conntrack,
+}
+
+impl Default for Firewall {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Firewall {
+    /// A firewall accepting everything
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            policy: Action::Accept,
+            conntrack: Conntrack::new(),
+        }
+    }
+
+    /// Set what happens to packets no rule matches
+    #[must_use]
+    pub const fn set_policy(mut self, policy: Action) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Track flows with `conntrack`, e.g. to time them out differently
+    #[must_use]
+    pub fn set_conntrack(mut self, conntrack: Conntrack) -> Self {
+        self.conntrack = conntrack;
+        self
+    }
+
+    /// Flows of the packets accepted so far
+    pub const fn conntrack(&self) -> &Conntrack {
+        &self.conntrack
+    }
+
+    /// Add a rule after the others
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push((rule, 0));
+    }
+
+    /// Add a rule at position `index`, before the rule there
+    pub fn insert(&mut self, index: usize, rule: Rule) {
+        self.rules.insert(index, (rule, 0));
+    }
+
+    /// Remove the rule at position `index`
+    pub fn remove(&mut self, index: usize) -> Option<Rule> {
+        (index < self.rules.len()).then(|| self.rules.remove(index).0)
+    }
+
+    /// The rules in order, with how many packets each has matched
+    pub fn rules(&self) -> impl Iterator<Item = (&Rule, u64)> {
+        self.rules.iter().map(|(rule, hits)| (rule, *hits))
+    }
+
+    /// Decide on a packet, counting a hit on the rule that matches it, and
+    /// tracking its flow if it's accepted
+    pub fn evaluate(&mut self, packet: &Ipv4Packet, now: Instant) -> Action {
+        let state =
+            Flow::of(packet).map_or(ConnState::New, |flow| self.conntrack.classify(&flow, now));
+        let action = match self.rules.iter_mut().find(|(rule, _)| {
+            rule.matches(packet) && rule.state.is_none_or(|wanted| wanted == state)
+        }) {
+            Some((rule, hits)) => {
+                *hits += 1;
+                rule.action
+            }
+            None => self.policy,
+        };
+        if action == Action::Accept {
+            self.conntrack.track(packet, now);
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dst: u8, src: u8) -> EthFrame {
+        EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, dst]),
+            Mac6::new([2, 0, 0, 0, 0, src]),
+            Layer3Packet::Unknown(vec![3, 1]),
+        )
+    }
+
+    /// Check a frame against both stages of a filter, like the receive loop
+    async fn passes(filter: &impl FrameFilter, mut frame: EthFrame) -> bool {
+        let mut raw = Vec::new();
+        frame.onto_writer(&mut raw).await.unwrap();
+        filter.accept_raw(&raw) && filter.accept(&frame)
+    }
+
+    #[tokio::test]
+    async fn mac_and_ethtype() {
+        let a = MacFilter(Mac6::new([2, 0, 0, 0, 0, 1]));
+        assert!(passes(&a, frame(1, 2)).await);
+        assert!(passes(&a, frame(2, 1)).await);
+        assert!(!passes(&a, frame(2, 3)).await);
+
+        let lldp = EtherTypeFilter(EtherType::Lldp);
+        assert!(!passes(&lldp, frame(1, 2)).await);
+        assert!(!lldp.accept_raw(&[0; 13]));
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let one = || MacFilter(Mac6::new([2, 0, 0, 0, 0, 1]));
+        let two = || MacFilter(Mac6::new([2, 0, 0, 0, 0, 2]));
+        assert!(passes(&one().and(two()), frame(1, 2)).await);
+        assert!(!passes(&one().and(two()), frame(1, 3)).await);
+        assert!(passes(&one().or(two()), frame(3, 2)).await);
+        assert!(!passes(&one().or(two()).not(), frame(3, 2)).await);
+
+        let closure = |frame: &EthFrame| frame.ethtype().is_none();
+        let boxed: Box<dyn FrameFilter> = Box::new(closure.and(All));
+        assert!(passes(&boxed, frame(3, 4)).await);
+    }
+
+    fn udp(source: [u8; 4], source_port: u16, destination_port: u16) -> Ipv4Packet {
+        let mut data = [source_port.to_be_bytes(), destination_port.to_be_bytes()].concat();
+        data.extend_from_slice(&[0, 8, 0, 0]);
+        Ipv4Packet::builder(
+            source.into(),
+            Ipv4Addr::new(192, 168, 0, 1),
+            IpProtocol::Udp,
+        )
+        .set_data(data)
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let lan: Ipv4Prefix = "192.168.0.0/24".parse().unwrap();
+        let dns = Rule::new(Action::Accept)
+            .set_protocol(IpProtocol::Udp)
+            .set_destination_ports(53..=53);
+        assert!(dns.matches(&udp([10, 0, 0, 1], 4000, 53)));
+        assert!(!dns.matches(&udp([10, 0, 0, 1], 53, 4000)));
+        assert!(
+            !dns.clone()
+                .set_protocol(IpProtocol::Tcp)
+                .matches(&udp([10, 0, 0, 1], 4000, 53))
+        );
+
+        let ephemeral = Rule::new(Action::Accept)
+            .set_source(lan)
+            .set_source_ports(1024..=u16::MAX);
+        assert!(ephemeral.matches(&udp([192, 168, 0, 9], 4000, 53)));
+        assert!(!ephemeral.matches(&udp([192, 168, 0, 9], 53, 4000)));
+        assert!(!ephemeral.matches(&udp([10, 0, 0, 1], 4000, 53)));
+
+        // Port rules never match packets without ports
+        let ping = Ipv4Packet::builder(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 1),
+            IpProtocol::Icmp,
+        )
+        .build()
+        .unwrap();
+        assert!(!ephemeral.matches(&ping));
+        assert!(Rule::new(Action::Drop).set_destination(lan).matches(&ping));
+    }
+
+    #[test]
+    fn firewall() {
+        let now = Instant::now();
+        let mut firewall = Firewall::new().set_policy(Action::Drop);
+        firewall.push(Rule::new(Action::Accept).set_destination_ports(53..=53));
+        firewall.insert(
+            0,
+            Rule::new(Action::Drop).set_source("10.0.0.0/8".parse().unwrap()),
+        );
+
+        assert_eq!(
+            firewall.evaluate(&udp([10, 0, 0, 1], 4000, 53), now),
+            Action::Drop
+        );
+        assert_eq!(
+            firewall.evaluate(&udp([172, 16, 0, 1], 4000, 53), now),
+            Action::Accept
+        );
+        assert_eq!(
+            firewall.evaluate(&udp([172, 16, 0, 1], 4000, 80), now),
+            Action::Drop
+        );
+        let hits: Vec<u64> = firewall.rules().map(|(_, hits)| hits).collect();
+        assert_eq!(hits, [1, 1]);
+
+        assert!(firewall.remove(0).is_some());
+        assert!(firewall.remove(1).is_none());
+        assert_eq!(
+            firewall.evaluate(&udp([10, 0, 0, 1], 4000, 53), now),
+            Action::Accept
+        );
+    }
+
+    #[test]
+    fn stateful() {
+        // Let the LAN out, and only replies back in
+        let now = Instant::now();
+        let mut firewall = Firewall::new().set_policy(Action::Drop);
+        firewall.push(Rule::new(Action::Accept).set_state(ConnState::Established));
+        firewall.push(Rule::new(Action::Accept).set_source("192.168.0.0/24".parse().unwrap()));
+
+        let out = Ipv4Packet::builder(
+            Ipv4Addr::new(192, 168, 0, 9),
+            Ipv4Addr::new(10, 0, 0, 1),
+            IpProtocol::Udp,
+        )
+        .set_data(udp([0; 4], 4000, 53).data)
+        .build()
+        .unwrap();
+        let reply = |source_port| {
+            Ipv4Packet::builder(
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(192, 168, 0, 9),
+                IpProtocol::Udp,
+            )
+            .set_data(udp([0; 4], source_port, 4000).data)
+            .build()
+            .unwrap()
+        };
+
+        assert_eq!(firewall.evaluate(&reply(53), now), Action::Drop);
+        assert_eq!(firewall.evaluate(&out, now), Action::Accept);
+        assert_eq!(firewall.conntrack().len(), 1);
+        assert_eq!(firewall.evaluate(&reply(53), now), Action::Accept);
+        assert_eq!(firewall.evaluate(&reply(54), now), Action::Drop);
+        let hits: Vec<u64> = firewall.rules().map(|(_, hits)| hits).collect();
+        assert_eq!(hits, [1, 1]);
+
+        // Until the flow's forgotten
+        let later = now + crate::conntrack::UDP_TIMEOUT;
+        assert_eq!(firewall.evaluate(&reply(53), later), Action::Drop);
+    }
+}
@@ -0,0 +1,233 @@
+//! Generating ICMP errors about packets we couldn't deliver
+//!
+//! Each method returns the error to send back to the packet's source, or
+//! `None` where RFC 1122 forbids one: errors about errors, about broadcast or
+//! multicast, or to a source that can't be answered. Errors are also rate
+//! limited (RFC 1812 §4.3.2.8), with a token bucket, so a flood can't make us
+//! flood in return.
+use crate::layer3::icmp::{self, OriginalDatagram, TimeExceededCode, UnreachableCode};
+use crate::layer3::{IcmpPacket, IpProtocol, Ipv4Packet};
+use crate::rate_limit::TokenBucket;
+use anyhow::Result;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Errors sent at most this often, on average...
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+/// ...in bursts of up to this many
+const DEFAULT_BURST: u32 = 10;
+
+/// Builds ICMP errors, no more often than its bucket allows
+#[derive(Copy, Clone, Debug)]
+pub struct IcmpErrors {
+    bucket: TokenBucket,
+    /// Errors not sent for want of a token
+    limited: u64,
+}
+
+impl Default for IcmpErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IcmpErrors {
+    /// Ten errors a second, in bursts of up to ten
+    pub const fn new() -> Self {
+        Self {
+            bucket: TokenBucket::new(DEFAULT_INTERVAL, DEFAULT_BURST),
+            limited: 0,
+        }
+    }
+
+    /// Send an error every `interval`, in bursts of up to `burst`
+    #[must_use]
+    pub const fn set_rate_limit(mut self, interval: Duration, burst: u32) -> Self {
+        self.bucket = TokenBucket::new(interval, burst);
+        self
+    }
+
+    /// Errors that would have been sent but for the rate limit
+    pub const fn limited(&self) -> u64 {
+        self.limited
+    }
+
+    /// Time Exceeded, for a packet whose TTL ran out while forwarding it
+    pub async fn ttl_exceeded(
+        &mut self,
+        source: Ipv4Addr,
+        packet: &Ipv4Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv4Packet>> {
+        self.error(source, packet, now, |original| IcmpPacket::TimeExceeded {
+            code: TimeExceededCode::Ttl,
+            original,
+        })
+        .await
+    }
+
+    /// Destination Unreachable (port), for a UDP datagram nobody's listening
+    /// for
+    pub async fn port_unreachable(
+        &mut self,
+        source: Ipv4Addr,
+        packet: &Ipv4Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv4Packet>> {
+        self.unreachable(source, packet, UnreachableCode::Port, 0, now)
+            .await
+    }
+
+    /// Destination Unreachable (fragmentation needed), for a packet with
+    /// Don't Fragment set that's too big for a next hop with `mtu`
+    pub async fn fragmentation_needed(
+        &mut self,
+        source: Ipv4Addr,
+        packet: &Ipv4Packet,
+        mtu: u16,
+        now: Instant,
+    ) -> Result<Option<Ipv4Packet>> {
+        self.unreachable(
+            source,
+            packet,
+            UnreachableCode::FragmentationNeeded,
+            mtu,
+            now,
+        )
+        .await
+    }
+
+    async fn unreachable(
+        &mut self,
+        source: Ipv4Addr,
+        packet: &Ipv4Packet,
+        code: UnreachableCode,
+        next_hop_mtu: u16,
+        now: Instant,
+    ) -> Result<Option<Ipv4Packet>> {
+        self.error(source, packet, now, |original| {
+            IcmpPacket::DestinationUnreachable {
+                code,
+                next_hop_mtu,
+                original,
+            }
+        })
+        .await
+    }
+
+    /// An error from `source` about `packet`, if allowed and there's a
+    /// token for it
+    async fn error(
+        &mut self,
+        source: Ipv4Addr,
+        packet: &Ipv4Packet,
+        now: Instant,
+        message: impl FnOnce(OriginalDatagram) -> IcmpPacket,
+    ) -> Result<Option<Ipv4Packet>> {
+        if !may_answer(packet) {
+            return Ok(None);
+        }
+        if !self.bucket.take(now) {
+            self.limited += 1;
+            return Ok(None);
+        }
+        let message = message(OriginalDatagram::quote(packet).await?);
+        Ipv4Packet::builder(source, packet.source, IpProtocol::Icmp)
+            .set_data(message.to_bytes())
+            .build()
+            .map(Some)
+    }
+}
+
+/// Whether an error about `packet` is allowed
+fn may_answer(packet: &Ipv4Packet) -> bool {
+    let source = packet.source;
+    let destination = packet.destination;
+    if source.is_unspecified()
+        || source.is_broadcast()
+        || source.is_multicast()
+        || source.is_loopback()
+        || destination.is_broadcast()
+        || destination.is_multicast()
+    {
+        return false;
+    }
+    !(packet.protocol == IpProtocol::Icmp
+        && packet.data.first().is_some_and(|&t| icmp::is_error_type(t)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const THEM: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn udp(destination: Ipv4Addr) -> Result<Ipv4Packet> {
+        Ipv4Packet::builder(THEM, destination, IpProtocol::Udp)
+            .set_data([0x04, 0xd2, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, 1, 2, 3, 4])
+            .build()
+    }
+
+    async fn parse(error: Option<Ipv4Packet>) -> Result<IcmpPacket> {
+        let error = error.expect("should have generated an error");
+        assert_eq!((error.source, error.destination), (US, THEM));
+        IcmpPacket::from_reader(error.data.as_slice()).await
+    }
+
+    #[tokio::test]
+    async fn generates_errors() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = IcmpErrors::new();
+        let packet = udp(US)?;
+
+        let message = parse(errors.port_unreachable(US, &packet, now).await?).await?;
+        let IcmpPacket::DestinationUnreachable { code, original, .. } = &message else {
+            panic!("Wrong ICMP type!");
+        };
+        assert_eq!(*code, UnreachableCode::Port);
+        assert_eq!(original.payload(), &packet.data[..8]);
+
+        let message = parse(errors.fragmentation_needed(US, &packet, 576, now).await?).await?;
+        assert!(matches!(
+            message,
+            IcmpPacket::DestinationUnreachable {
+                code: UnreachableCode::FragmentationNeeded,
+                next_hop_mtu: 576,
+                ..
+            }
+        ));
+
+        let message = parse(errors.ttl_exceeded(US, &packet, now).await?).await?;
+        assert!(matches!(message, IcmpPacket::TimeExceeded { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn never_answers_errors_or_broadcasts() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = IcmpErrors::new();
+        let broadcast = udp(Ipv4Addr::BROADCAST)?;
+        assert_eq!(errors.port_unreachable(US, &broadcast, now).await?, None);
+
+        let error = errors.ttl_exceeded(US, &udp(US)?, now).await?.unwrap();
+        assert_eq!(errors.ttl_exceeded(US, &error, now).await?, None);
+        // Not sending those doesn't count against the limit
+        assert_eq!(errors.limited(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limited() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = IcmpErrors::new().set_rate_limit(Duration::from_secs(1), 2);
+        let packet = udp(US)?;
+        assert!(errors.port_unreachable(US, &packet, now).await?.is_some());
+        assert!(errors.ttl_exceeded(US, &packet, now).await?.is_some());
+        assert_eq!(errors.port_unreachable(US, &packet, now).await?, None);
+        assert_eq!(errors.limited(), 1);
+        let later = now + Duration::from_secs(1);
+        assert!(errors.port_unreachable(US, &packet, later).await?.is_some());
+        Ok(())
+    }
+}
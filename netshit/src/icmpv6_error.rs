@@ -0,0 +1,273 @@
+//! Generating ICMPv6 errors about packets we couldn't deliver (RFC 4443)
+//!
+//! Each method returns the error to send back to the packet's source, or
+//! `None` where §2.4 forbids one: errors about errors, about packets sent
+//! to groups, or to a source that can't be answered. Errors are also rate
+//! limited, with a token bucket, so a flood can't make us flood in return.
+use crate::layer3::IpProtocol;
+use crate::layer3::icmp::TimeExceededCode;
+use crate::layer3::icmpv6::{self, Icmpv6Packet, UnreachableCode};
+use crate::layer3::ipv6::Ipv6Packet;
+use crate::rate_limit::TokenBucket;
+use anyhow::Result;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// Errors sent at most this often, on average...
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+/// ...in bursts of up to this many
+const DEFAULT_BURST: u32 = 10;
+
+/// Builds ICMPv6 errors, no more often than its bucket allows
+#[derive(Copy, Clone, Debug)]
+pub struct Icmpv6Errors {
+    bucket: TokenBucket,
+    /// Errors not sent for want of a token
+    limited: u64,
+}
+
+impl Default for Icmpv6Errors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Icmpv6Errors {
+    /// Ten errors a second, in bursts of up to ten
+    pub const fn new() -> Self {
+        Self {
+            bucket: TokenBucket::new(DEFAULT_INTERVAL, DEFAULT_BURST),
+            limited: 0,
+        }
+    }
+
+    /// Send an error every `interval`, in bursts of up to `burst`
+    #[must_use]
+    pub const fn set_rate_limit(mut self, interval: Duration, burst: u32) -> Self {
+        self.bucket = TokenBucket::new(interval, burst);
+        self
+    }
+
+    /// Errors that would have been sent but for the rate limit
+    pub const fn limited(&self) -> u64 {
+        self.limited
+    }
+
+    /// Destination Unreachable (no route), for a packet we can't forward
+    pub fn no_route(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.unreachable(source, packet, UnreachableCode::NoRoute, now)
+    }
+
+    /// Destination Unreachable (beyond scope), for a packet from a
+    /// link-local address we'd have to forward off the link
+    pub fn beyond_scope(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.unreachable(source, packet, UnreachableCode::BeyondScope, now)
+    }
+
+    /// Destination Unreachable (port), for a UDP datagram nobody's listening
+    /// for
+    pub fn port_unreachable(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.unreachable(source, packet, UnreachableCode::Port, now)
+    }
+
+    /// Packet Too Big, for a packet bigger than the next hop's `mtu`;
+    /// routers never fragment IPv6
+    ///
+    /// Unlike other errors, this is sent about packets to groups too, so
+    /// path MTU discovery works for multicast.
+    pub fn packet_too_big(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        mtu: u32,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.error(source, packet, true, now, |original| {
+            Icmpv6Packet::PacketTooBig { mtu, original }
+        })
+    }
+
+    /// Time Exceeded, for a packet whose hop limit ran out while forwarding
+    /// it
+    pub fn hop_limit_exceeded(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.time_exceeded(source, packet, TimeExceededCode::Ttl, now)
+    }
+
+    /// Time Exceeded, for a packet not all of whose fragments arrived;
+    /// `first` is the fragment that did, at offset 0
+    pub fn reassembly_timeout(
+        &mut self,
+        source: Ipv6Addr,
+        first: &Ipv6Packet,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.time_exceeded(source, first, TimeExceededCode::Reassembly, now)
+    }
+
+    fn unreachable(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        code: UnreachableCode,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.error(source, packet, false, now, |original| {
+            Icmpv6Packet::DestinationUnreachable { code, original }
+        })
+    }
+
+    fn time_exceeded(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        code: TimeExceededCode,
+        now: Instant,
+    ) -> Result<Option<Ipv6Packet>> {
+        self.error(source, packet, false, now, |original| {
+            Icmpv6Packet::TimeExceeded { code, original }
+        })
+    }
+
+    /// An error from `source` about `packet`, if allowed and there's a
+    /// token for it
+    fn error(
+        &mut self,
+        source: Ipv6Addr,
+        packet: &Ipv6Packet,
+        about_groups: bool,
+        now: Instant,
+        message: impl FnOnce(Vec<u8>) -> Icmpv6Packet,
+    ) -> Result<Option<Ipv6Packet>> {
+        if !may_answer(packet, about_groups) {
+            return Ok(None);
+        }
+        if !self.bucket.take(now) {
+            self.limited += 1;
+            return Ok(None);
+        }
+        let message = message(Icmpv6Packet::quote(packet)?);
+        let destination = packet.source;
+        Ok(Some(Ipv6Packet::new(
+            source,
+            destination,
+            IpProtocol::Icmpv6,
+            message.to_bytes(source, destination),
+        )))
+    }
+}
+
+/// Whether an error about `packet` is allowed; errors about packets sent to
+/// groups only if `about_groups`
+fn may_answer(packet: &Ipv6Packet, about_groups: bool) -> bool {
+    if packet.source.is_unspecified()
+        || packet.source.is_multicast()
+        || (packet.destination.is_multicast() && !about_groups)
+    {
+        return false;
+    }
+    !(packet.next_header == IpProtocol::Icmpv6
+        && packet
+            .data
+            .first()
+            .is_some_and(|&t| icmpv6::is_unanswerable_type(t)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const THEM: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    fn udp(destination: Ipv6Addr) -> Ipv6Packet {
+        Ipv6Packet::new(THEM, destination, IpProtocol::Udp, [0, 1, 0, 2, 0, 8, 0, 0])
+    }
+
+    async fn parse(error: Option<Ipv6Packet>) -> Result<Icmpv6Packet> {
+        let error = error.expect("should have generated an error");
+        assert_eq!((error.source, error.destination), (US, THEM));
+        Icmpv6Packet::from_reader(error.data.as_slice(), US, THEM).await
+    }
+
+    #[tokio::test]
+    async fn generates_errors() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = Icmpv6Errors::new();
+        let packet = udp("2001:db8:1::9".parse()?);
+
+        let message = parse(errors.no_route(US, &packet, now)?).await?;
+        assert_eq!(
+            message,
+            Icmpv6Packet::DestinationUnreachable {
+                code: UnreachableCode::NoRoute,
+                original: packet.to_bytes()?,
+            }
+        );
+        let message = parse(errors.packet_too_big(US, &packet, 1280, now)?).await?;
+        assert!(matches!(
+            message,
+            Icmpv6Packet::PacketTooBig { mtu: 1280, .. }
+        ));
+        let message = parse(errors.hop_limit_exceeded(US, &packet, now)?).await?;
+        assert!(matches!(
+            message,
+            Icmpv6Packet::TimeExceeded {
+                code: TimeExceededCode::Ttl,
+                ..
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn forbidden_errors() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = Icmpv6Errors::new();
+        let group = udp("ff02::fb".parse()?);
+        assert_eq!(errors.port_unreachable(US, &group, now)?, None);
+        // ...but groups hear about packets too big
+        assert!(errors.packet_too_big(US, &group, 1280, now)?.is_some());
+
+        let error = errors.no_route(US, &udp(US), now)?.unwrap();
+        let about_error = Ipv6Packet {
+            source: THEM,
+            ..error
+        };
+        assert_eq!(errors.no_route(US, &about_error, now)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limited() -> Result<()> {
+        let now = Instant::now();
+        let mut errors = Icmpv6Errors::new().set_rate_limit(Duration::from_secs(1), 2);
+        let packet = udp(US);
+        assert!(errors.port_unreachable(US, &packet, now)?.is_some());
+        assert!(errors.port_unreachable(US, &packet, now)?.is_some());
+        assert_eq!(errors.port_unreachable(US, &packet, now)?, None);
+        assert_eq!(errors.limited(), 1);
+        let later = now + Duration::from_secs(1);
+        assert!(errors.port_unreachable(US, &packet, later)?.is_some());
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-use crate::eth::{Mac6, ethtype};
+use crate::eth::{EtherType, Mac6};
+use crate::limits::ParseLimits;
 use anyhow::{Result, anyhow, bail};
 use std::net::Ipv4Addr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -6,12 +7,15 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 const HW_TYPE_ETHERNET: u16 = 1;
 const IPV4_ADDR_SIZE_BYTES: u8 = 4;
 
-// The kind of ARP packet - requeast or reply
+/// The kind of ARP packet - request or reply
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u16)]
-enum ArpOperation {
+pub enum ArpOperation {
     Request = 1,
     Reply = 2,
+    /// Reverse ARP (RFC 903): what's the IPv4 address of this MAC?
+    RarpRequest = 3,
+    RarpReply = 4,
 }
 
 impl TryFrom<u16> for ArpOperation {
@@ -20,11 +24,19 @@ impl TryFrom<u16> for ArpOperation {
         match value {
             1 => Ok(Self::Request),
             2 => Ok(Self::Reply),
+            3 => Ok(Self::RarpRequest),
+            4 => Ok(Self::RarpReply),
             _ => Err(anyhow!("Invalid ARP operation")),
         }
     }
 }
 
+impl From<ArpOperation> for u16 {
+    fn from(operation: ArpOperation) -> Self {
+        operation as u16
+    }
+}
+
 /// A parsed Ipv4/Ethernet ARP packet
 ///
 /// We're only ever using ethernet, and Ipv6 doesn't use ARP
@@ -38,16 +50,71 @@ pub struct ArpPacket {
 }
 
 impl ArpPacket {
+    /// A request from `sender_ip` at `sender_mac` asking who has
+    /// `target_ip`; broadcast it
+    pub const fn request(sender_ip: Ipv4Addr, sender_mac: Mac6, target_ip: Ipv4Addr) -> Self {
+        Self {
+            operation: ArpOperation::Request,
+            sender_hw_address: sender_mac,
+            sender_protocol_address: sender_ip,
+            target_hw_address: Mac6::new([0; 6]),
+            target_protocol_address: target_ip,
+        }
+    }
+
+    /// A reply telling `target_ip` at `target_mac` that `sender_ip` is at
+    /// `sender_mac`
+    pub const fn reply(
+        sender_ip: Ipv4Addr,
+        sender_mac: Mac6,
+        target_ip: Ipv4Addr,
+        target_mac: Mac6,
+    ) -> Self {
+        Self {
+            operation: ArpOperation::Reply,
+            sender_hw_address: sender_mac,
+            sender_protocol_address: sender_ip,
+            target_hw_address: target_mac,
+            target_protocol_address: target_ip,
+        }
+    }
+
+    /// A RARP request from `mac`, asking for its own IPv4 address; broadcast
+    /// it with the RARP ethtype
+    pub const fn rarp_request(mac: Mac6) -> Self {
+        Self {
+            operation: ArpOperation::RarpRequest,
+            sender_hw_address: mac,
+            sender_protocol_address: Ipv4Addr::UNSPECIFIED,
+            target_hw_address: mac,
+            target_protocol_address: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+
     /// Parse an ARP packet from a reader
-    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+    pub async fn from_reader(reader: impl AsyncRead + Unpin) -> Result<Self> {
+        Self::from_reader_with_limits(reader, &ParseLimits::default()).await
+    }
+
+    /// Parse an ARP packet from a reader, failing with
+    /// [TooLarge](crate::limits::TooLarge) if its address lengths make it
+    /// bigger than `limits`
+    pub async fn from_reader_with_limits(
+        mut reader: impl AsyncRead + Unpin,
+        limits: &ParseLimits,
+    ) -> Result<Self> {
         let hw_type = reader.read_u16().await?;
         let protocol_type = reader.read_u16().await?;
         let hw_length = reader.read_u8().await?;
         let protocol_length = reader.read_u8().await?;
+        limits.check_payload(
+            "ARP packet",
+            8 + 2 * (usize::from(hw_length) + usize::from(protocol_length)),
+        )?;
 
         if hw_type != HW_TYPE_ETHERNET {
             bail!("ARP: hardware type not supported: {hw_type}");
-        } else if protocol_type != ethtype::IPV4 {
+        } else if protocol_type != u16::from(EtherType::Ipv4) {
             bail!("ARP: protocol_type type not supported: {protocol_type}");
         } else if hw_length as usize != std::mem::size_of::<Mac6>() {
             bail!("ARP: hardware length not supported: {hw_length}");
@@ -73,11 +140,11 @@ impl ArpPacket {
     /// Serialize an ARP packet into a writer
     pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
         writer.write_u16(HW_TYPE_ETHERNET).await?;
-        writer.write_u16(ethtype::IPV4).await?;
+        writer.write_u16(EtherType::Ipv4.into()).await?;
         writer.write_u8(std::mem::size_of::<Mac6>() as u8).await?;
         writer.write_u8(IPV4_ADDR_SIZE_BYTES).await?;
 
-        writer.write_u16(self.operation as u16).await?;
+        writer.write_u16(self.operation.into()).await?;
         writer
             .write_all(&self.sender_hw_address.into_inner())
             .await?;
@@ -93,6 +160,67 @@ impl ArpPacket {
 
         Ok(())
     }
+
+    /// A gratuitous ARP announcing that `ip` is at `mac`, so neighbours
+    /// update their caches
+    ///
+    /// As RFC 5227 recommends, it's a request with our address as both
+    /// sender and target; broadcast it.
+    pub const fn gratuitous(ip: Ipv4Addr, mac: Mac6) -> Self {
+        Self::request(ip, mac, ip)
+    }
+
+    /// An RFC 5227 probe from `mac`, asking whether anyone has `ip`
+    /// without claiming it ourselves; broadcast it
+    pub const fn probe(ip: Ipv4Addr, mac: Mac6) -> Self {
+        Self::request(Ipv4Addr::UNSPECIFIED, mac, ip)
+    }
+
+    /// The reply to this packet from `mac`, if it's a request
+    ///
+    /// Whether the target address is ours is up to the caller.
+    pub const fn answer(&self, mac: Mac6) -> Option<Self> {
+        match self.operation {
+            ArpOperation::Request => Some(Self::reply(
+                self.target_protocol_address,
+                mac,
+                self.sender_protocol_address,
+                self.sender_hw_address,
+            )),
+            _ => None,
+        }
+    }
+
+    pub const fn operation(&self) -> ArpOperation {
+        self.operation
+    }
+
+    pub const fn sender_hw_address(&self) -> Mac6 {
+        self.sender_hw_address
+    }
+
+    pub const fn sender_protocol_address(&self) -> Ipv4Addr {
+        self.sender_protocol_address
+    }
+
+    /// Zero in requests
+    pub const fn target_hw_address(&self) -> Mac6 {
+        self.target_hw_address
+    }
+
+    pub const fn target_protocol_address(&self) -> Ipv4Addr {
+        self.target_protocol_address
+    }
+
+    /// The sender's IPv4 address and MAC
+    pub const fn sender(&self) -> (Ipv4Addr, Mac6) {
+        (self.sender_protocol_address, self.sender_hw_address)
+    }
+
+    /// The target's IPv4 address and MAC (zero in requests)
+    pub const fn target(&self) -> (Ipv4Addr, Mac6) {
+        (self.target_protocol_address, self.target_hw_address)
+    }
 }
 
 #[cfg(test)]
@@ -113,15 +241,64 @@ mod tests {
         assert_eq!(arp.operation, ArpOperation::Request);
     }
 
+    #[tokio::test]
+    async fn answer() {
+        let raw = [
+            0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x36, 0x1f, 0xb8, 0xa8, 0x1b, 0xc5,
+            0xc0, 0xa8, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x04,
+        ];
+        let request = ArpPacket::from_reader(raw.as_slice()).await.unwrap();
+        let mac = Mac6::new([2, 0, 0, 0, 0, 4]);
+        let reply = request.answer(mac).unwrap();
+        assert_eq!(reply.operation, ArpOperation::Reply);
+        assert_eq!(reply.sender(), ("192.168.0.4".parse().unwrap(), mac));
+        assert_eq!(reply.target(), request.sender());
+        assert_eq!(reply.answer(mac), None);
+    }
+
+    #[tokio::test]
+    async fn rarp() {
+        // Reply telling 36:1f:b8:a8:1b:c5 it's 192.168.0.5
+        let raw = [
+            0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0xc0, 0xa8, 0x00, 0x01, 0x36, 0x1f, 0xb8, 0xa8, 0x1b, 0xc5, 0xc0, 0xa8, 0x00, 0x05,
+        ];
+        let arp = ArpPacket::from_reader(raw.as_slice()).await.unwrap();
+        assert_eq!(arp.operation(), ArpOperation::RarpReply);
+        assert_eq!(arp.target_protocol_address().to_string(), "192.168.0.5");
+        assert_eq!(arp.answer(Mac6::new([2, 0, 0, 0, 0, 1])), None);
+
+        let mut request = ArpPacket::rarp_request(arp.target_hw_address());
+        let mut buffer = Vec::new();
+        request.onto_writer(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[6..8], [0, 3]);
+        assert!(
+            ArpPacket::from_reader([0, 1, 8, 0, 6, 4, 0, 5].as_slice())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn gratuitous() {
+        let ip = "192.168.0.1".parse().unwrap();
+        let mac = Mac6::new([2, 0, 0, 0, 0, 1]);
+        let arp = ArpPacket::gratuitous(ip, mac);
+        assert_eq!(arp.operation, ArpOperation::Request);
+        assert_eq!(arp.sender(), (ip, mac));
+        assert_eq!(arp.target().0, ip);
+    }
+
     #[tokio::test]
     async fn write() {
-        let mut arp = ArpPacket {
-            operation: ArpOperation::Request,
-            sender_hw_address: [0x31, 0x41, 0x59, 0x26, 0x53, 0x58].into(),
-            sender_protocol_address: "3.1.4.1".parse().unwrap(),
-            target_hw_address: [0x27, 0x18, 0x28, 0x18, 0x28, 0x45].into(),
-            target_protocol_address: "2.7.1.8".parse().unwrap(),
-        };
+        let mut arp = ArpPacket::reply(
+            "3.1.4.1".parse().unwrap(),
+            [0x31, 0x41, 0x59, 0x26, 0x53, 0x58].into(),
+            "2.7.1.8".parse().unwrap(),
+            [0x27, 0x18, 0x28, 0x18, 0x28, 0x45].into(),
+        );
+        assert_eq!(arp.operation(), ArpOperation::Reply);
+        assert_eq!(arp.target_hw_address().to_string(), "27:18:28:18:28:45");
         let mut buffer = Vec::new();
         arp.onto_writer(&mut buffer).await.unwrap();
 
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The kind of EAPOL packet
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EapolType {
+    /// Carries an EAP packet
+    Eap,
+    Start,
+    Logoff,
+    /// Carries key material, e.g. the WPA 4-way handshake
+    Key,
+    Unknown(u8),
+}
+
+impl From<u8> for EapolType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Eap,
+            1 => Self::Start,
+            2 => Self::Logoff,
+            3 => Self::Key,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<EapolType> for u8 {
+    fn from(value: EapolType) -> Self {
+        match value {
+            EapolType::Eap => 0,
+            EapolType::Start => 1,
+            EapolType::Logoff => 2,
+            EapolType::Key => 3,
+            EapolType::Unknown(value) => value,
+        }
+    }
+}
+
+/// An 802.1X EAP over LAN packet, with its body left unparsed
+#[derive(Clone, PartialEq, Debug)]
+pub struct EapolPacket {
+    pub version: u8,
+    pub packet_type: EapolType,
+    pub body: Vec<u8>,
+}
+
+impl EapolPacket {
+    /// Parse an EAPOL packet from a reader
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let version = reader.read_u8().await?;
+        let packet_type = EapolType::from(reader.read_u8().await?);
+        let mut body = vec![0; reader.read_u16().await? as usize];
+        reader.read_exact(&mut body).await?;
+        Ok(Self {
+            version,
+            packet_type,
+            body,
+        })
+    }
+
+    /// Serialize an EAPOL packet into a writer
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_u8(self.version).await?;
+        writer.write_u8(self.packet_type.into()).await?;
+        writer.write_u16(self.body.len().try_into()?).await?;
+        writer.write_all(&self.body).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_eap_request() -> Result<()> {
+        // EAP Request/Identity, followed by frame padding
+        let raw = [
+            0x02, 0x00, 0x00, 0x05, 0x01, 0x01, 0x00, 0x05, 0x01, 0x00, 0x00,
+        ];
+        let mut packet = EapolPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.version, 2);
+        assert_eq!(packet.packet_type, EapolType::Eap);
+        assert_eq!(packet.body, [0x01, 0x01, 0x00, 0x05, 0x01]);
+
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec, raw[..9]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_start() -> Result<()> {
+        let packet = EapolPacket::from_reader([0x01, 0x01, 0x00, 0x00].as_slice()).await?;
+        assert_eq!(packet.packet_type, EapolType::Start);
+        assert!(packet.body.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use super::{IpProtocol, Ipv4Packet};
+use anyhow::{Result, bail};
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_SOURCE_QUENCH: u8 = 4;
+const TYPE_REDIRECT: u8 = 5;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+const TYPE_PARAMETER_PROBLEM: u8 = 12;
+
+/// Bytes of the original payload an error has to quote (RFC 792)
+pub const ORIGINAL_PAYLOAD_LENGTH: usize = 8;
+
+/// The body of an echo request or reply
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Echo {
+    pub identifier: u16,
+    pub sequence: u16,
+    pub data: Vec<u8>,
+}
+
+/// True for types reporting an error, which must never be answered with
+/// another error (RFC 1122 3.2.2)
+pub const fn is_error_type(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        TYPE_DESTINATION_UNREACHABLE
+            | TYPE_SOURCE_QUENCH
+            | TYPE_REDIRECT
+            | TYPE_TIME_EXCEEDED
+            | TYPE_PARAMETER_PROBLEM
+    )
+}
+
+/// Why a destination was unreachable
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnreachableCode {
+    Network,
+    Host,
+    Protocol,
+    Port,
+    /// The packet had Don't Fragment set but is too big for the next hop
+    FragmentationNeeded,
+    SourceRouteFailed,
+    AdministrativelyProhibited,
+    Other(u8),
+}
+
+impl From<u8> for UnreachableCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Network,
+            1 => Self::Host,
+            2 => Self::Protocol,
+            3 => Self::Port,
+            4 => Self::FragmentationNeeded,
+            5 => Self::SourceRouteFailed,
+            13 => Self::AdministrativelyProhibited,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+impl From<UnreachableCode> for u8 {
+    fn from(value: UnreachableCode) -> Self {
+        match value {
+            UnreachableCode::Network => 0,
+            UnreachableCode::Host => 1,
+            UnreachableCode::Protocol => 2,
+            UnreachableCode::Port => 3,
+            UnreachableCode::FragmentationNeeded => 4,
+            UnreachableCode::SourceRouteFailed => 5,
+            UnreachableCode::AdministrativelyProhibited => 13,
+            UnreachableCode::Other(value) => value,
+        }
+    }
+}
+
+/// What ran out of time
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimeExceededCode {
+    /// TTL hit zero in transit
+    Ttl,
+    /// Not all fragments arrived in time
+    Reassembly,
+    Other(u8),
+}
+
+impl From<u8> for TimeExceededCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Ttl,
+            1 => Self::Reassembly,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+impl From<TimeExceededCode> for u8 {
+    fn from(value: TimeExceededCode) -> Self {
+        match value {
+            TimeExceededCode::Ttl => 0,
+            TimeExceededCode::Reassembly => 1,
+            TimeExceededCode::Other(value) => value,
+        }
+    }
+}
+
+/// The start of the datagram an error is about, as quoted in the error: its
+/// IPv4 header and at least the first 8 bytes of its payload
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OriginalDatagram(Vec<u8>);
+
+impl OriginalDatagram {
+    /// Check that `bytes` start with a plausible IPv4 header
+    pub fn new(bytes: Vec<u8>) -> Result<Self> {
+        let Some(&version_ihl) = bytes.first() else {
+            bail!("ICMP: missing original datagram");
+        };
+        if version_ihl >> 4 != 4 {
+            bail!("ICMP: original datagram isn't IPv4");
+        }
+        let header_length = usize::from(version_ihl & 0x0f) * 4;
+        if header_length < 20 || bytes.len() < header_length {
+            bail!("ICMP: original datagram header truncated");
+        }
+        Ok(Self(bytes))
+    }
+
+    /// Quote `packet`'s header and the start of its payload, as an error
+    /// about it should
+    pub async fn quote(packet: &Ipv4Packet) -> Result<Self> {
+        let mut bytes = Vec::new();
+        packet.clone().onto_writer(&mut bytes).await?;
+        let header_length = bytes.len() - packet.data.len();
+        bytes.truncate(header_length + ORIGINAL_PAYLOAD_LENGTH);
+        Self::new(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn header_length(&self) -> usize {
+        usize::from(self.0[0] & 0x0f) * 4
+    }
+
+    pub fn protocol(&self) -> IpProtocol {
+        IpProtocol::from(self.0[9])
+    }
+
+    pub fn source(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.0[12], self.0[13], self.0[14], self.0[15])
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.0[16], self.0[17], self.0[18], self.0[19])
+    }
+
+    /// What was quoted of the original payload, e.g. the UDP or TCP ports
+    pub fn payload(&self) -> &[u8] {
+        &self.0[self.header_length()..]
+    }
+}
+
+/// An ICMP message, as carried in [Ipv4Packet::data](super::Ipv4Packet)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum IcmpPacket {
+    EchoRequest(Echo),
+    EchoReply(Echo),
+    DestinationUnreachable {
+        code: UnreachableCode,
+        /// MTU of the next hop, for [UnreachableCode::FragmentationNeeded]
+        /// (RFC 1191); zero otherwise or from older routers
+        next_hop_mtu: u16,
+        original: OriginalDatagram,
+    },
+    TimeExceeded {
+        code: TimeExceededCode,
+        original: OriginalDatagram,
+    },
+    /// Use `gateway` instead of us to reach the original destination
+    Redirect {
+        code: u8,
+        gateway: Ipv4Addr,
+        original: OriginalDatagram,
+    },
+    /// Something in the original header was wrong, at byte `pointer`
+    ParameterProblem {
+        code: u8,
+        pointer: u8,
+        original: OriginalDatagram,
+    },
+    /// A message we don't interpret, with everything after the checksum
+    Other {
+        icmp_type: u8,
+        code: u8,
+        rest: Vec<u8>,
+    },
+}
+
+impl IcmpPacket {
+    /// The datagram this is an error about, if it's an error
+    pub const fn original(&self) -> Option<&OriginalDatagram> {
+        match self {
+            Self::DestinationUnreachable { original, .. }
+            | Self::TimeExceeded { original, .. }
+            | Self::Redirect { original, .. }
+            | Self::ParameterProblem { original, .. } => Some(original),
+            _ => None,
+        }
+    }
+
+    /// Parse an ICMP message from a reader, verifying its checksum
+    ///
+    /// The message runs to the end of the reader. Errors quoting something
+    /// that isn't an IPv4 header are left as [IcmpPacket::Other].
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        if raw.len() < 4 {
+            bail!("ICMP: message too short");
+        }
+        if internet_checksum::checksum(&raw) != [0, 0] {
+            bail!("ICMP: invalid checksum");
+        }
+
+        let (icmp_type, code) = (raw[0], raw[1]);
+        let rest = &raw[4..];
+        if let Some(packet) = Self::parse_error(icmp_type, code, rest) {
+            return Ok(packet);
+        }
+        Ok(match icmp_type {
+            TYPE_ECHO_REQUEST | TYPE_ECHO_REPLY if rest.len() >= 4 => {
+                let echo = Echo {
+                    identifier: u16::from_be_bytes([rest[0], rest[1]]),
+                    sequence: u16::from_be_bytes([rest[2], rest[3]]),
+                    data: rest[4..].to_vec(),
+                };
+                if icmp_type == TYPE_ECHO_REQUEST {
+                    Self::EchoRequest(echo)
+                } else {
+                    Self::EchoReply(echo)
+                }
+            }
+            _ => Self::Other {
+                icmp_type,
+                code,
+                rest: rest.to_vec(),
+            },
+        })
+    }
+
+    /// Parse the error types, given everything after the checksum
+    fn parse_error(icmp_type: u8, code: u8, rest: &[u8]) -> Option<Self> {
+        let (&[a, b, c, d], original) = rest.split_first_chunk::<4>()?;
+        let original = OriginalDatagram::new(original.to_vec()).ok()?;
+        Some(match icmp_type {
+            TYPE_DESTINATION_UNREACHABLE => Self::DestinationUnreachable {
+                code: code.into(),
+                next_hop_mtu: u16::from_be_bytes([c, d]),
+                original,
+            },
+            TYPE_TIME_EXCEEDED => Self::TimeExceeded {
+                code: code.into(),
+                original,
+            },
+            TYPE_REDIRECT => Self::Redirect {
+                code,
+                gateway: Ipv4Addr::new(a, b, c, d),
+                original,
+            },
+            TYPE_PARAMETER_PROBLEM => Self::ParameterProblem {
+                code,
+                pointer: a,
+                original,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Serialize an ICMP message into a writer, filling in the checksum
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IPv4 payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (icmp_type, code, rest) = match self {
+            Self::EchoRequest(echo) => (TYPE_ECHO_REQUEST, 0, echo.encode()),
+            Self::EchoReply(echo) => (TYPE_ECHO_REPLY, 0, echo.encode()),
+            Self::DestinationUnreachable {
+                code,
+                next_hop_mtu,
+                original,
+            } => {
+                let [high, low] = next_hop_mtu.to_be_bytes();
+                let header = [0, 0, high, low];
+                (
+                    TYPE_DESTINATION_UNREACHABLE,
+                    (*code).into(),
+                    quote(header, original),
+                )
+            }
+            Self::TimeExceeded { code, original } => {
+                (TYPE_TIME_EXCEEDED, (*code).into(), quote([0; 4], original))
+            }
+            Self::Redirect {
+                code,
+                gateway,
+                original,
+            } => (TYPE_REDIRECT, *code, quote(gateway.octets(), original)),
+            Self::ParameterProblem {
+                code,
+                pointer,
+                original,
+            } => (
+                TYPE_PARAMETER_PROBLEM,
+                *code,
+                quote([*pointer, 0, 0, 0], original),
+            ),
+            Self::Other {
+                icmp_type,
+                code,
+                rest,
+            } => (*icmp_type, *code, rest.clone()),
+        };
+
+        let mut raw = vec![icmp_type, code, 0, 0];
+        raw.extend_from_slice(&rest);
+        let checksum = internet_checksum::checksum(&raw);
+        raw[2..4].copy_from_slice(&checksum);
+        raw
+    }
+}
+
+/// The rest of an error message: four type-specific bytes, then the quote
+fn quote(header: [u8; 4], original: &OriginalDatagram) -> Vec<u8> {
+    let mut rest = header.to_vec();
+    rest.extend_from_slice(original.as_bytes());
+    rest
+}
+
+impl Echo {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.data.len());
+        bytes.extend_from_slice(&self.identifier.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_echo_request() -> Result<()> {
+        let raw = [
+            0x08, 0x00, 0x35, 0x12, 0x00, 0x2a, 0x00, 0x01, 0x61, 0x61, 0x61, 0x61,
+        ];
+        let packet = IcmpPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(
+            packet,
+            IcmpPacket::EchoRequest(Echo {
+                identifier: 42,
+                sequence: 1,
+                data: b"aaaa".to_vec(),
+            })
+        );
+        assert_eq!(packet.to_bytes(), raw);
+        Ok(())
+    }
+
+    /// A UDP packet from 10.0.0.2:1234 to 10.0.0.1:53, header and all
+    const UDP_PACKET: [u8; 28] = [
+        0x45, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x26, 0xcf, 10, 0, 0, 2, 10, 0,
+        0, 1, 0x04, 0xd2, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00,
+    ];
+
+    fn with_checksum(mut raw: Vec<u8>) -> Vec<u8> {
+        let checksum = internet_checksum::checksum(&raw);
+        raw[2..4].copy_from_slice(&checksum);
+        raw
+    }
+
+    #[tokio::test]
+    async fn parse_fragmentation_needed() -> Result<()> {
+        let mut raw = vec![0x03, 0x04, 0, 0, 0x00, 0x00, 0x05, 0x78];
+        raw.extend_from_slice(&UDP_PACKET);
+        let raw = with_checksum(raw);
+
+        let packet = IcmpPacket::from_reader(raw.as_slice()).await?;
+        let IcmpPacket::DestinationUnreachable {
+            code,
+            next_hop_mtu,
+            original,
+        } = &packet
+        else {
+            panic!("Wrong ICMP type!");
+        };
+        assert_eq!(*code, UnreachableCode::FragmentationNeeded);
+        assert_eq!(*next_hop_mtu, 1400);
+        assert_eq!(original.protocol(), IpProtocol::Udp);
+        assert_eq!(original.source(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(original.destination(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(&original.payload()[..4], [0x04, 0xd2, 0x00, 0x35]);
+        assert_eq!(packet.to_bytes(), raw);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_other_errors() -> Result<()> {
+        let original = OriginalDatagram::new(UDP_PACKET.to_vec())?;
+        for packet in [
+            IcmpPacket::TimeExceeded {
+                code: TimeExceededCode::Ttl,
+                original: original.clone(),
+            },
+            IcmpPacket::Redirect {
+                code: 1,
+                gateway: Ipv4Addr::new(10, 0, 0, 254),
+                original: original.clone(),
+            },
+            IcmpPacket::ParameterProblem {
+                code: 0,
+                pointer: 8,
+                original: original.clone(),
+            },
+        ] {
+            let raw = packet.to_bytes();
+            let parsed = IcmpPacket::from_reader(raw.as_slice()).await?;
+            assert_eq!(parsed, packet);
+            assert_eq!(parsed.original(), Some(&original));
+        }
+
+        // Quoting something that isn't IPv4 isn't an error we can use
+        let raw = with_checksum(vec![0x0b, 0x00, 0, 0, 0, 0, 0, 0, 0x60, 0, 0, 0]);
+        assert!(matches!(
+            IcmpPacket::from_reader(raw.as_slice()).await?,
+            IcmpPacket::Other { icmp_type: 11, .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quote_packet() -> Result<()> {
+        let packet = Ipv4Packet::from_reader(UDP_PACKET.as_slice()).await?;
+        let original = OriginalDatagram::quote(&packet).await?;
+        assert_eq!(original.as_bytes(), UDP_PACKET);
+
+        let mut packet = packet;
+        packet.data.extend_from_slice(b"and then some");
+        let original = OriginalDatagram::quote(&packet).await?;
+        assert_eq!(original.payload().len(), ORIGINAL_PAYLOAD_LENGTH);
+        assert_eq!(original.source(), packet.source);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_bad_checksum() {
+        let raw = [0x08, 0x00, 0xf7, 0xd5, 0x00, 0x2a, 0x00, 0x01];
+        assert!(IcmpPacket::from_reader(raw.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use super::IpProtocol;
+use super::icmp::{Echo, TimeExceededCode};
+use super::ipv6::{self, Ipv6Packet};
+use super::mld::{self, MldQuery, MldRecord};
+use super::ndp::{
+    NeighbourAdvertisement, NeighbourSolicitation, RouterAdvertisement, RouterSolicitation,
+};
+use anyhow::{Result, bail};
+use std::net::Ipv6Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+const TYPE_PACKET_TOO_BIG: u8 = 2;
+const TYPE_TIME_EXCEEDED: u8 = 3;
+const TYPE_PARAMETER_PROBLEM: u8 = 4;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+const TYPE_MLD_QUERY: u8 = 130;
+const TYPE_MLD_V1_REPORT: u8 = 131;
+const TYPE_MLD_DONE: u8 = 132;
+const TYPE_ROUTER_SOLICITATION: u8 = 133;
+const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+const TYPE_NEIGHBOUR_SOLICITATION: u8 = 135;
+const TYPE_NEIGHBOUR_ADVERTISEMENT: u8 = 136;
+const TYPE_REDIRECT: u8 = 137;
+const TYPE_MLD_V2_REPORT: u8 = 143;
+
+/// The smallest MTU every IPv6 link has (RFC 8200 §5)
+pub const MIN_MTU: usize = 1280;
+/// Most bytes of the original packet an error quotes, so the error fits in
+/// [MIN_MTU] (RFC 4443 §2.4)
+pub const MAX_ORIGINAL_LENGTH: usize = MIN_MTU - ipv6::HEADER_LENGTH - 8;
+
+/// True for types reporting an error, which must never be answered with
+/// another error (RFC 4443 §2.4)
+pub const fn is_error_type(icmp_type: u8) -> bool {
+    icmp_type < 128
+}
+
+/// True for types no error may be sent about: errors, and redirects
+/// (RFC 4443 §2.4)
+pub const fn is_unanswerable_type(icmp_type: u8) -> bool {
+    is_error_type(icmp_type) || icmp_type == TYPE_REDIRECT
+}
+
+/// Why a destination was unreachable
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnreachableCode {
+    NoRoute,
+    AdministrativelyProhibited,
+    /// The source address's scope doesn't reach the destination
+    BeyondScope,
+    Address,
+    Port,
+    SourcePolicyFailed,
+    RejectRoute,
+    Other(u8),
+}
+
+impl From<u8> for UnreachableCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::NoRoute,
+            1 => Self::AdministrativelyProhibited,
+            2 => Self::BeyondScope,
+            3 => Self::Address,
+            4 => Self::Port,
+            5 => Self::SourcePolicyFailed,
+            6 => Self::RejectRoute,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+impl From<UnreachableCode> for u8 {
+    fn from(value: UnreachableCode) -> Self {
+        match value {
+            UnreachableCode::NoRoute => 0,
+            UnreachableCode::AdministrativelyProhibited => 1,
+            UnreachableCode::BeyondScope => 2,
+            UnreachableCode::Address => 3,
+            UnreachableCode::Port => 4,
+            UnreachableCode::SourcePolicyFailed => 5,
+            UnreachableCode::RejectRoute => 6,
+            UnreachableCode::Other(value) => value,
+        }
+    }
+}
+
+/// An ICMPv6 message, as carried in [Ipv6Packet::data]
+///
+/// Errors quote as much of the original packet as fits, header first.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Icmpv6Packet {
+    EchoRequest(Echo),
+    EchoReply(Echo),
+    DestinationUnreachable {
+        code: UnreachableCode,
+        original: Vec<u8>,
+    },
+    /// The original packet was bigger than the next hop's `mtu`
+    PacketTooBig {
+        mtu: u32,
+        original: Vec<u8>,
+    },
+    /// The hop limit hit zero, or not all fragments arrived in time
+    TimeExceeded {
+        code: TimeExceededCode,
+        original: Vec<u8>,
+    },
+    /// Something in the original packet was wrong, at byte `pointer`
+    ParameterProblem {
+        code: u8,
+        pointer: u32,
+        original: Vec<u8>,
+    },
+    RouterSolicitation(RouterSolicitation),
+    RouterAdvertisement(RouterAdvertisement),
+    NeighbourSolicitation(NeighbourSolicitation),
+    NeighbourAdvertisement(NeighbourAdvertisement),
+    MldQuery(MldQuery),
+    MldV1Report(Ipv6Addr),
+    /// We've stopped listening to this group (v1)
+    MldDone(Ipv6Addr),
+    MldV2Report(Vec<MldRecord>),
+    /// A message we don't interpret, with everything after the checksum
+    Other {
+        icmp_type: u8,
+        code: u8,
+        rest: Vec<u8>,
+    },
+}
+
+impl Icmpv6Packet {
+    /// The start of `packet`, as much as an error about it can quote
+    pub fn quote(packet: &Ipv6Packet) -> Result<Vec<u8>> {
+        let mut original = packet.to_bytes()?;
+        original.truncate(MAX_ORIGINAL_LENGTH);
+        Ok(original)
+    }
+
+    /// The start of the packet this is an error about, if it's an error
+    pub fn original(&self) -> Option<&[u8]> {
+        match self {
+            Self::DestinationUnreachable { original, .. }
+            | Self::PacketTooBig { original, .. }
+            | Self::TimeExceeded { original, .. }
+            | Self::ParameterProblem { original, .. } => Some(original),
+            _ => None,
+        }
+    }
+
+    /// Parse a message sent from `source` to `destination`, verifying its
+    /// checksum
+    ///
+    /// The message runs to the end of the reader.
+    pub async fn from_reader(
+        mut reader: impl AsyncRead + Unpin,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+    ) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        if raw.len() < 8 {
+            bail!("ICMPv6: message too short");
+        }
+        if ipv6::checksum(source, destination, IpProtocol::Icmpv6, &raw) != [0, 0] {
+            bail!("ICMPv6: invalid checksum");
+        }
+
+        let (icmp_type, code) = (raw[0], raw[1]);
+        let (&[a, b, c, d], body) = raw[4..]
+            .split_first_chunk::<4>()
+            .expect("length was checked");
+        let word = u32::from_be_bytes([a, b, c, d]);
+        let body = body.to_vec();
+        Ok(match icmp_type {
+            TYPE_DESTINATION_UNREACHABLE => Self::DestinationUnreachable {
+                code: code.into(),
+                original: body,
+            },
+            TYPE_PACKET_TOO_BIG => Self::PacketTooBig {
+                mtu: word,
+                original: body,
+            },
+            TYPE_TIME_EXCEEDED => Self::TimeExceeded {
+                code: code.into(),
+                original: body,
+            },
+            TYPE_PARAMETER_PROBLEM => Self::ParameterProblem {
+                code,
+                pointer: word,
+                original: body,
+            },
+            TYPE_ECHO_REQUEST | TYPE_ECHO_REPLY => {
+                let echo = Echo {
+                    identifier: u16::from_be_bytes([a, b]),
+                    sequence: u16::from_be_bytes([c, d]),
+                    data: body,
+                };
+                if icmp_type == TYPE_ECHO_REQUEST {
+                    Self::EchoRequest(echo)
+                } else {
+                    Self::EchoReply(echo)
+                }
+            }
+            TYPE_ROUTER_SOLICITATION if code == 0 => {
+                Self::RouterSolicitation(RouterSolicitation::parse(&raw[4..])?)
+            }
+            TYPE_ROUTER_ADVERTISEMENT if code == 0 => {
+                Self::RouterAdvertisement(RouterAdvertisement::parse(&raw[4..])?)
+            }
+            TYPE_NEIGHBOUR_SOLICITATION if code == 0 => {
+                Self::NeighbourSolicitation(NeighbourSolicitation::parse(&raw[4..])?)
+            }
+            TYPE_NEIGHBOUR_ADVERTISEMENT if code == 0 => {
+                Self::NeighbourAdvertisement(NeighbourAdvertisement::parse(&raw[4..])?)
+            }
+            TYPE_MLD_QUERY => Self::MldQuery(MldQuery::parse(&raw[4..])?),
+            TYPE_MLD_V1_REPORT => Self::MldV1Report(mld::parse_group(&raw[4..])?),
+            TYPE_MLD_DONE => Self::MldDone(mld::parse_group(&raw[4..])?),
+            TYPE_MLD_V2_REPORT => Self::MldV2Report(mld::parse_records(&raw[4..])?),
+            _ => Self::Other {
+                icmp_type,
+                code,
+                rest: raw[4..].to_vec(),
+            },
+        })
+    }
+
+    /// Serialize a message from `source` to `destination` into a writer,
+    /// filling in the checksum
+    pub async fn onto_writer(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+    ) -> Result<()> {
+        writer
+            .write_all(&self.to_bytes(source, destination))
+            .await?;
+        Ok(())
+    }
+
+    /// Serialize a message from `source` to `destination` into a new
+    /// buffer, e.g. for an IPv6 payload
+    pub fn to_bytes(&self, source: Ipv6Addr, destination: Ipv6Addr) -> Vec<u8> {
+        let (icmp_type, code, word, body): (u8, u8, u32, &[u8]) = match self {
+            Self::EchoRequest(echo) | Self::EchoReply(echo) => {
+                let icmp_type = if matches!(self, Self::EchoRequest(_)) {
+                    TYPE_ECHO_REQUEST
+                } else {
+                    TYPE_ECHO_REPLY
+                };
+                let word = (u32::from(echo.identifier) << 16) | u32::from(echo.sequence);
+                (icmp_type, 0, word, &echo.data)
+            }
+            Self::DestinationUnreachable { code, original } => {
+                (TYPE_DESTINATION_UNREACHABLE, (*code).into(), 0, original)
+            }
+            Self::PacketTooBig { mtu, original } => (TYPE_PACKET_TOO_BIG, 0, *mtu, original),
+            Self::TimeExceeded { code, original } => {
+                (TYPE_TIME_EXCEEDED, (*code).into(), 0, original)
+            }
+            Self::ParameterProblem {
+                code,
+                pointer,
+                original,
+            } => (TYPE_PARAMETER_PROBLEM, *code, *pointer, original),
+            Self::RouterSolicitation(solicitation) => {
+                let rest = solicitation.encode();
+                return with_checksum(TYPE_ROUTER_SOLICITATION, 0, &rest, source, destination);
+            }
+            Self::RouterAdvertisement(advertisement) => {
+                let rest = advertisement.encode();
+                return with_checksum(TYPE_ROUTER_ADVERTISEMENT, 0, &rest, source, destination);
+            }
+            Self::NeighbourSolicitation(solicitation) => {
+                let rest = solicitation.encode();
+                return with_checksum(TYPE_NEIGHBOUR_SOLICITATION, 0, &rest, source, destination);
+            }
+            Self::NeighbourAdvertisement(advertisement) => {
+                let rest = advertisement.encode();
+                return with_checksum(TYPE_NEIGHBOUR_ADVERTISEMENT, 0, &rest, source, destination);
+            }
+            Self::MldQuery(query) => {
+                return with_checksum(TYPE_MLD_QUERY, 0, &query.encode(), source, destination);
+            }
+            Self::MldV1Report(group) => {
+                let rest = mld::encode_group(*group);
+                return with_checksum(TYPE_MLD_V1_REPORT, 0, &rest, source, destination);
+            }
+            Self::MldDone(group) => {
+                let rest = mld::encode_group(*group);
+                return with_checksum(TYPE_MLD_DONE, 0, &rest, source, destination);
+            }
+            Self::MldV2Report(records) => {
+                let rest = mld::encode_records(records);
+                return with_checksum(TYPE_MLD_V2_REPORT, 0, &rest, source, destination);
+            }
+            Self::Other {
+                icmp_type,
+                code,
+                rest,
+            } => return with_checksum(*icmp_type, *code, rest, source, destination),
+        };
+
+        let mut rest = word.to_be_bytes().to_vec();
+        rest.extend_from_slice(body);
+        with_checksum(icmp_type, code, &rest, source, destination)
+    }
+}
+
+/// A whole message, given everything after the checksum
+fn with_checksum(
+    icmp_type: u8,
+    code: u8,
+    rest: &[u8],
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+) -> Vec<u8> {
+    let mut raw = vec![icmp_type, code, 0, 0];
+    raw.extend_from_slice(rest);
+    let checksum = ipv6::checksum(source, destination, IpProtocol::Icmpv6, &raw);
+    raw[2..4].copy_from_slice(&checksum);
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const DESTINATION: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+
+    #[tokio::test]
+    async fn parse_echo_request() -> Result<()> {
+        let raw = [
+            0x80, 0x00, 0xbf, 0xc6, 0x00, 0x2a, 0x00, 0x01, 0x61, 0x61, 0x61, 0x61,
+        ];
+        let packet = Icmpv6Packet::from_reader(raw.as_slice(), SOURCE, DESTINATION).await?;
+        assert_eq!(
+            packet,
+            Icmpv6Packet::EchoRequest(Echo {
+                identifier: 42,
+                sequence: 1,
+                data: b"aaaa".to_vec(),
+            })
+        );
+        assert_eq!(packet.to_bytes(SOURCE, DESTINATION), raw);
+
+        // The pseudo-header is covered too
+        let err = Icmpv6Packet::from_reader(raw.as_slice(), DESTINATION, Ipv6Addr::LOCALHOST)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "ICMPv6: invalid checksum");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_round_trip() -> Result<()> {
+        let udp = Ipv6Packet::new(DESTINATION, SOURCE, IpProtocol::Udp, vec![0; 2000]);
+        let original = Icmpv6Packet::quote(&udp)?;
+        assert_eq!(original.len(), MAX_ORIGINAL_LENGTH);
+
+        for packet in [
+            Icmpv6Packet::DestinationUnreachable {
+                code: UnreachableCode::Port,
+                original: original.clone(),
+            },
+            Icmpv6Packet::PacketTooBig {
+                mtu: 1280,
+                original: original.clone(),
+            },
+            Icmpv6Packet::TimeExceeded {
+                code: TimeExceededCode::Ttl,
+                original: original.clone(),
+            },
+            Icmpv6Packet::ParameterProblem {
+                code: 1,
+                pointer: 40,
+                original: original.clone(),
+            },
+            Icmpv6Packet::Other {
+                icmp_type: 200,
+                code: 0,
+                rest: vec![1, 2, 3, 4],
+            },
+        ] {
+            let raw = packet.to_bytes(SOURCE, DESTINATION);
+            assert!(raw.len() + ipv6::HEADER_LENGTH <= MIN_MTU);
+            let parsed = Icmpv6Packet::from_reader(raw.as_slice(), SOURCE, DESTINATION).await?;
+            assert_eq!(parsed, packet);
+            assert_eq!(parsed.original().is_some(), is_error_type(raw[0]));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mld_round_trip() -> Result<()> {
+        let group: Ipv6Addr = "ff02::fb".parse()?;
+        for packet in [
+            Icmpv6Packet::MldQuery(MldQuery {
+                max_response_code: 10000,
+                group: Ipv6Addr::UNSPECIFIED,
+                v2: None,
+            }),
+            Icmpv6Packet::MldQuery(MldQuery {
+                max_response_code: 10000,
+                group,
+                v2: Some(mld::QueryV2 {
+                    suppress_router_processing: true,
+                    robustness: 2,
+                    interval_code: 125,
+                    sources: vec![SOURCE],
+                }),
+            }),
+            Icmpv6Packet::MldV1Report(group),
+            Icmpv6Packet::MldDone(group),
+            Icmpv6Packet::MldV2Report(vec![MldRecord::exclude_none(mld::MODE_IS_EXCLUDE, group)]),
+        ] {
+            let raw = packet.to_bytes(SOURCE, DESTINATION);
+            let parsed = Icmpv6Packet::from_reader(raw.as_slice(), SOURCE, DESTINATION).await?;
+            assert_eq!(parsed, packet);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::{Result, bail};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TYPE_QUERY: u8 = 0x11;
+const TYPE_V1_REPORT: u8 = 0x12;
+const TYPE_V2_REPORT: u8 = 0x16;
+const TYPE_LEAVE: u8 = 0x17;
+const TYPE_V3_REPORT: u8 = 0x22;
+
+/// Length of a v1/v2 message; v3 queries are longer
+const V2_LENGTH: usize = 8;
+
+/// Where IGMPv2 leaves go
+pub const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+/// Where IGMPv3 reports go
+pub const ALL_IGMPV3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+/// Group record types in v3 reports
+pub const MODE_IS_INCLUDE: u8 = 1;
+pub const MODE_IS_EXCLUDE: u8 = 2;
+pub const CHANGE_TO_INCLUDE: u8 = 3;
+pub const CHANGE_TO_EXCLUDE: u8 = 4;
+
+/// The extra fields of an IGMPv3 query
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct QueryV3 {
+    /// Routers shouldn't lower their timers on hearing this
+    pub suppress_router_processing: bool,
+    /// Querier's robustness variable
+    pub robustness: u8,
+    /// Querier's query interval, encoded like the max response code
+    pub interval_code: u8,
+    pub sources: Vec<Ipv4Addr>,
+}
+
+/// A membership query: who's in `group` (or any group, if unspecified)?
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IgmpQuery {
+    pub max_response_code: u8,
+    pub group: Ipv4Addr,
+    /// Present in v3 queries
+    pub v3: Option<QueryV3>,
+}
+
+impl IgmpQuery {
+    /// A general query is about every group
+    pub fn is_general(&self) -> bool {
+        self.group.is_unspecified()
+    }
+
+    /// How long we have to answer
+    pub fn max_response_time(&self) -> Duration {
+        let tenths = match &self.v3 {
+            Some(_) => decode_time(self.max_response_code),
+            None => u32::from(self.max_response_code),
+        };
+        Duration::from_millis(u64::from(tenths) * 100)
+    }
+}
+
+/// Decode a v3 time code: a plain number below 128, else a float
+fn decode_time(code: u8) -> u32 {
+    if code < 128 {
+        return u32::from(code);
+    }
+    let mantissa = u32::from(code & 0x0f);
+    let exponent = u32::from((code >> 4) & 0x07);
+    (mantissa | 0x10) << (exponent + 3)
+}
+
+/// One group's state in a v3 report
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GroupRecord {
+    pub record_type: u8,
+    pub group: Ipv4Addr,
+    pub sources: Vec<Ipv4Addr>,
+    pub auxiliary: Vec<u8>,
+}
+
+/// An Internet Group Management Protocol message, as carried in
+/// [Ipv4Packet::data](super::Ipv4Packet)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum IgmpPacket {
+    Query(IgmpQuery),
+    V1Report(Ipv4Addr),
+    V2Report(Ipv4Addr),
+    /// We're leaving this group (v2)
+    Leave(Ipv4Addr),
+    V3Report(Vec<GroupRecord>),
+    /// A message we don't interpret, with everything after the checksum
+    Other {
+        igmp_type: u8,
+        rest: Vec<u8>,
+    },
+}
+
+impl IgmpPacket {
+    /// Parse an IGMP message from a reader, verifying its checksum
+    ///
+    /// The message runs to the end of the reader.
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        if raw.len() < V2_LENGTH {
+            bail!("IGMP: message too short");
+        }
+        if internet_checksum::checksum(&raw) != [0, 0] {
+            bail!("IGMP: invalid checksum");
+        }
+
+        let (igmp_type, code) = (raw[0], raw[1]);
+        let group = Ipv4Addr::new(raw[4], raw[5], raw[6], raw[7]);
+        Ok(match igmp_type {
+            TYPE_QUERY => Self::Query(IgmpQuery {
+                max_response_code: code,
+                group,
+                v3: parse_query_v3(&raw[V2_LENGTH..])?,
+            }),
+            TYPE_V1_REPORT => Self::V1Report(group),
+            TYPE_V2_REPORT => Self::V2Report(group),
+            TYPE_LEAVE => Self::Leave(group),
+            TYPE_V3_REPORT => {
+                let count = u16::from_be_bytes([raw[6], raw[7]]);
+                let mut rest = &raw[V2_LENGTH..];
+                let mut records = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    records.push(parse_record(&mut rest)?);
+                }
+                Self::V3Report(records)
+            }
+            _ => Self::Other {
+                igmp_type,
+                rest: raw[2..].to_vec(),
+            },
+        })
+    }
+
+    /// Serialize an IGMP message into a writer, filling in the checksum
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_all(&self.to_bytes()?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IPv4 payload
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut raw = match self {
+            Self::Query(query) => {
+                let mut raw = header(TYPE_QUERY, query.max_response_code, query.group);
+                if let Some(v3) = &query.v3 {
+                    if v3.robustness > 7 {
+                        bail!("IGMP: robustness {} doesn't fit in 3 bits", v3.robustness);
+                    }
+                    raw.push(u8::from(v3.suppress_router_processing) << 3 | v3.robustness);
+                    raw.push(v3.interval_code);
+                    raw.extend_from_slice(&u16::try_from(v3.sources.len())?.to_be_bytes());
+                    for source in &v3.sources {
+                        raw.extend_from_slice(&source.octets());
+                    }
+                }
+                raw
+            }
+            Self::V1Report(group) => header(TYPE_V1_REPORT, 0, *group),
+            Self::V2Report(group) => header(TYPE_V2_REPORT, 0, *group),
+            Self::Leave(group) => header(TYPE_LEAVE, 0, *group),
+            Self::V3Report(records) => {
+                let [high, low] = u16::try_from(records.len())?.to_be_bytes();
+                let mut raw = vec![TYPE_V3_REPORT, 0, 0, 0, 0, 0, high, low];
+                for record in records {
+                    write_record(&mut raw, record)?;
+                }
+                raw
+            }
+            Self::Other { igmp_type, rest } => {
+                let mut raw = vec![*igmp_type];
+                raw.extend_from_slice(rest);
+                raw
+            }
+        };
+        let checksum = internet_checksum::checksum(&raw);
+        raw[2..4].copy_from_slice(&checksum);
+        Ok(raw)
+    }
+}
+
+/// Type, code, zeroed checksum and group
+fn header(igmp_type: u8, code: u8, group: Ipv4Addr) -> Vec<u8> {
+    let mut raw = vec![igmp_type, code, 0, 0];
+    raw.extend_from_slice(&group.octets());
+    raw
+}
+
+/// The v3 fields of a query, if there's more than a v2 query's worth
+fn parse_query_v3(rest: &[u8]) -> Result<Option<QueryV3>> {
+    let Some((&[flags, interval_code, high, low], mut sources)) = rest.split_first_chunk::<4>()
+    else {
+        return Ok(None);
+    };
+    Ok(Some(QueryV3 {
+        suppress_router_processing: flags & 0x08 != 0,
+        robustness: flags & 0x07,
+        interval_code,
+        sources: read_addresses(&mut sources, u16::from_be_bytes([high, low]))?,
+    }))
+}
+
+fn parse_record(rest: &mut &[u8]) -> Result<GroupRecord> {
+    let Some((&[record_type, aux_words, high, low], tail)) = rest.split_first_chunk::<4>() else {
+        bail!("IGMP: truncated group record");
+    };
+    *rest = tail;
+    let group = read_addresses(rest, 1)?[0];
+    let sources = read_addresses(rest, u16::from_be_bytes([high, low]))?;
+    let aux_length = usize::from(aux_words) * 4;
+    let Some((auxiliary, tail)) = rest.split_at_checked(aux_length) else {
+        bail!("IGMP: truncated auxiliary data");
+    };
+    *rest = tail;
+    Ok(GroupRecord {
+        record_type,
+        group,
+        sources,
+        auxiliary: auxiliary.to_vec(),
+    })
+}
+
+fn write_record(raw: &mut Vec<u8>, record: &GroupRecord) -> Result<()> {
+    if !record.auxiliary.len().is_multiple_of(4) {
+        bail!("IGMP: auxiliary data must be whole words");
+    }
+    raw.push(record.record_type);
+    raw.push(u8::try_from(record.auxiliary.len() / 4)?);
+    raw.extend_from_slice(&u16::try_from(record.sources.len())?.to_be_bytes());
+    raw.extend_from_slice(&record.group.octets());
+    for source in &record.sources {
+        raw.extend_from_slice(&source.octets());
+    }
+    raw.extend_from_slice(&record.auxiliary);
+    Ok(())
+}
+
+fn read_addresses(rest: &mut &[u8], count: u16) -> Result<Vec<Ipv4Addr>> {
+    let Some((addresses, tail)) = rest.split_at_checked(usize::from(count) * 4) else {
+        bail!("IGMP: truncated address list");
+    };
+    *rest = tail;
+    Ok(addresses
+        .chunks(4)
+        .map(|addr| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_queries() -> Result<()> {
+        // v2 general query, 10 s to respond
+        let raw = [0x11, 0x64, 0xee, 0x9b, 0, 0, 0, 0];
+        let query = IgmpPacket::from_reader(raw.as_slice()).await?;
+        let IgmpPacket::Query(query) = &query else {
+            panic!("Wrong IGMP type!");
+        };
+        assert!(query.is_general());
+        assert_eq!(query.v3, None);
+        assert_eq!(query.max_response_time(), Duration::from_secs(10));
+
+        // v3 group-specific query with a float-encoded response time
+        let mut query = IgmpPacket::Query(IgmpQuery {
+            max_response_code: 0x8a,
+            group: Ipv4Addr::new(239, 1, 2, 3),
+            v3: Some(QueryV3 {
+                suppress_router_processing: false,
+                robustness: 2,
+                interval_code: 125,
+                sources: vec![Ipv4Addr::new(10, 0, 0, 1)],
+            }),
+        });
+        let mut vec = Vec::new();
+        query.onto_writer(&mut vec).await?;
+        assert_eq!(vec.len(), 16);
+        let parsed = IgmpPacket::from_reader(vec.as_slice()).await?;
+        assert_eq!(parsed, query);
+        let IgmpPacket::Query(parsed) = parsed else {
+            unreachable!();
+        };
+        // (0x10 | 0xa) << 3 tenths
+        assert_eq!(parsed.max_response_time(), Duration::from_millis(20800));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_round_trip() -> Result<()> {
+        let group = Ipv4Addr::new(224, 0, 0, 251);
+        for packet in [
+            IgmpPacket::V2Report(group),
+            IgmpPacket::Leave(group),
+            IgmpPacket::V3Report(vec![
+                GroupRecord {
+                    record_type: CHANGE_TO_EXCLUDE,
+                    group,
+                    sources: Vec::new(),
+                    auxiliary: Vec::new(),
+                },
+                GroupRecord {
+                    record_type: MODE_IS_INCLUDE,
+                    group: Ipv4Addr::new(239, 0, 0, 1),
+                    sources: vec![Ipv4Addr::new(10, 0, 0, 1)],
+                    auxiliary: vec![1, 2, 3, 4],
+                },
+            ]),
+        ] {
+            let raw = packet.to_bytes()?;
+            assert_eq!(IgmpPacket::from_reader(raw.as_slice()).await?, packet);
+        }
+
+        let mut raw = IgmpPacket::V2Report(group).to_bytes()?;
+        raw[7] ^= 1;
+        assert!(IgmpPacket::from_reader(raw.as_slice()).await.is_err());
+        Ok(())
+    }
+}
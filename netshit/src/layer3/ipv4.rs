@@ -1,29 +1,381 @@
+use super::IpProtocol;
+use crate::checksum::{self, ChecksumCapabilities};
+use crate::layer4::Layer4Packet;
+use crate::limits::ParseLimits;
 use anyhow::{Result, bail};
 use std::net::Ipv4Addr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MIN_HEADER_LENGTH: u8 = 20; // in bytes
+pub const MIN_HEADER_LENGTH: u8 = 20; // in bytes
+const MAX_HEADER_LENGTH: usize = 60;
+const DEFAULT_TTL: u8 = 64;
 const DONT_FRAGMENT: u16 = 0x2;
 
-/// A parsed Internet Protocol version 4 packet
+const OPTION_END_OF_LIST: u8 = 0;
+const OPTION_NO_OPERATION: u8 = 1;
+const OPTION_RECORD_ROUTE: u8 = 7;
+const OPTION_TIMESTAMP: u8 = 68;
+const OPTION_ROUTER_ALERT: u8 = 148;
+
+/// Differentiated Services Code Point: the six-bit traffic class
+///
+/// Anything over 63 can't be sent and fails serialization.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub struct Dscp(pub u8);
+
+impl Dscp {
+    /// Class selectors, compatible with the old IP precedence values
+    pub const CS0: Self = Self(0);
+    pub const CS1: Self = Self(8);
+    pub const CS2: Self = Self(16);
+    pub const CS3: Self = Self(24);
+    pub const CS4: Self = Self(32);
+    pub const CS5: Self = Self(40);
+    pub const CS6: Self = Self(48);
+    pub const CS7: Self = Self(56);
+    /// Assured forwarding: class, then drop precedence (RFC 2597)
+    pub const AF11: Self = Self(10);
+    pub const AF12: Self = Self(12);
+    pub const AF13: Self = Self(14);
+    pub const AF21: Self = Self(18);
+    pub const AF22: Self = Self(20);
+    pub const AF23: Self = Self(22);
+    pub const AF31: Self = Self(26);
+    pub const AF32: Self = Self(28);
+    pub const AF33: Self = Self(30);
+    pub const AF41: Self = Self(34);
+    pub const AF42: Self = Self(36);
+    pub const AF43: Self = Self(38);
+    /// Expedited forwarding, for low-latency traffic like voice
+    pub const EF: Self = Self(46);
+
+    pub const fn is_valid(self) -> bool {
+        self.0 <= 0b11_1111
+    }
+}
+
+/// Explicit Congestion Notification codepoint (RFC 3168)
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub enum Ecn {
+    /// Sender doesn't do ECN
+    #[default]
+    NotEct,
+    /// ECN-capable transport
+    Ect1,
+    Ect0,
+    /// Congestion experienced
+    Ce,
+}
+
+impl Ecn {
+    /// From the low two bits of `bits`
+    pub const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::NotEct,
+            0b01 => Self::Ect1,
+            0b10 => Self::Ect0,
+            _ => Self::Ce,
+        }
+    }
+
+    pub const fn bits(self) -> u8 {
+        match self {
+            Self::NotEct => 0b00,
+            Self::Ect1 => 0b01,
+            Self::Ect0 => 0b10,
+            Self::Ce => 0b11,
+        }
+    }
+}
+
+/// An option in an IPv4 header
 #[derive(Clone, Debug, PartialEq)]
+pub enum Ipv4Option {
+    /// Ends the list; only padding follows
+    EndOfList,
+    NoOperation,
+    /// Route slots, filled in up to `pointer`
+    RecordRoute {
+        pointer: u8,
+        route: Vec<Ipv4Addr>,
+    },
+    /// Timestamps (and maybe addresses, depending on `flags`), filled in up
+    /// to `pointer`
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flags: u8,
+        data: Vec<u8>,
+    },
+    /// Routers should look at this packet more closely (e.g. IGMP)
+    RouterAlert(u16),
+    /// Any option we don't interpret, or a malformed one
+    Other {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Ipv4Option {
+    /// Parse the options part of a header
+    fn parse_all(mut bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut options = Vec::new();
+        while let Some((&kind, rest)) = bytes.split_first() {
+            match kind {
+                OPTION_END_OF_LIST => {
+                    options.push(Self::EndOfList);
+                    break;
+                }
+                OPTION_NO_OPERATION => {
+                    options.push(Self::NoOperation);
+                    bytes = rest;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(&length) = rest.first() else {
+                bail!("Ipv4: option {kind} missing length");
+            };
+            if length < 2 || length as usize > bytes.len() {
+                bail!("Ipv4: bad length {length} for option {kind}");
+            }
+            let data = &bytes[2..length as usize];
+            bytes = &bytes[length as usize..];
+            options.push(Self::parse(kind, data));
+        }
+        Ok(options)
+    }
+
+    /// Parse one option with a length field, given its data
+    fn parse(kind: u8, data: &[u8]) -> Self {
+        match (kind, data) {
+            (OPTION_RECORD_ROUTE, [pointer, route @ ..]) if route.len() % 4 == 0 => {
+                Self::RecordRoute {
+                    pointer: *pointer,
+                    route: route
+                        .chunks(4)
+                        .map(|addr| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+                        .collect(),
+                }
+            }
+            (OPTION_TIMESTAMP, [pointer, overflow_flags, data @ ..]) => Self::Timestamp {
+                pointer: *pointer,
+                overflow: overflow_flags >> 4,
+                flags: overflow_flags & 0x0f,
+                data: data.to_vec(),
+            },
+            (OPTION_ROUTER_ALERT, &[a, b]) => Self::RouterAlert(u16::from_be_bytes([a, b])),
+            _ => Self::Other {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    /// Append this option's wire form to `out`
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        let (kind, data) = match self {
+            Self::EndOfList => {
+                out.push(OPTION_END_OF_LIST);
+                return Ok(());
+            }
+            Self::NoOperation => {
+                out.push(OPTION_NO_OPERATION);
+                return Ok(());
+            }
+            Self::RecordRoute { pointer, route } => {
+                let mut data = vec![*pointer];
+                for addr in route {
+                    data.extend_from_slice(&addr.octets());
+                }
+                (OPTION_RECORD_ROUTE, data)
+            }
+            Self::Timestamp {
+                pointer,
+                overflow,
+                flags,
+                data: stamps,
+            } => {
+                let mut data = vec![*pointer, (overflow << 4) | (flags & 0x0f)];
+                data.extend_from_slice(stamps);
+                (OPTION_TIMESTAMP, data)
+            }
+            Self::RouterAlert(value) => (OPTION_ROUTER_ALERT, value.to_be_bytes().to_vec()),
+            Self::Other { kind, data } => (*kind, data.clone()),
+        };
+        out.push(kind);
+        out.push(u8::try_from(data.len() + 2)?);
+        out.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// A parsed Internet Protocol version 4 packet
+#[derive(Clone, Debug)]
 pub struct Ipv4Packet {
-    /// Differentiated Service Code Point
-    pub dscp: u8,
-    /// Explicit congestion notification
-    pub ecn: u8,
+    pub dscp: Dscp,
+    pub ecn: Ecn,
     pub identification: u16,
     /// Time-to-live
     pub ttl: u8,
-    pub protocol: u8,
+    pub protocol: IpProtocol,
     pub source: Ipv4Addr,
     pub destination: Ipv4Addr,
+    pub options: Vec<Ipv4Option>,
     pub data: Vec<u8>,
+    /// Header checksum as received, which whoever changes the header must
+    /// update (RFC 1624) or clear; `None` computes it when written
+    pub header_checksum: Option<u16>,
+}
+
+/// Packets are the same whether or not they carry their checksum
+impl PartialEq for Ipv4Packet {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            dscp,
+            ecn,
+            identification,
+            ttl,
+            protocol,
+            source,
+            destination,
+            options,
+            data,
+            header_checksum: _,
+        } = self;
+        (dscp, ecn, identification, ttl, protocol)
+            == (
+                &other.dscp,
+                &other.ecn,
+                &other.identification,
+                &other.ttl,
+                &other.protocol,
+            )
+            && (source, destination, options, data)
+                == (
+                    &other.source,
+                    &other.destination,
+                    &other.options,
+                    &other.data,
+                )
+    }
+}
+
+/// Builds an [Ipv4Packet], checking fields fit on the wire
+///
+/// TTL defaults to 64. Don't Fragment is always set, and the total length
+/// and checksum are filled in when the packet is written.
+#[derive(Clone, Debug)]
+pub struct Ipv4PacketBuilder {
+    packet: Ipv4Packet,
+}
+
+impl Ipv4PacketBuilder {
+    pub const fn new(source: Ipv4Addr, destination: Ipv4Addr, protocol: IpProtocol) -> Self {
+        Self {
+            packet: Ipv4Packet {
+                dscp: Dscp::CS0,
+                ecn: Ecn::NotEct,
+                identification: 0,
+                ttl: DEFAULT_TTL,
+                protocol,
+                source,
+                destination,
+                options: Vec::new(),
+                data: Vec::new(),
+                header_checksum: None,
+            },
+        }
+    }
+
+    #[must_use]
+    pub const fn set_dscp(mut self, dscp: Dscp) -> Self {
+        self.packet.dscp = dscp;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_ecn(mut self, ecn: Ecn) -> Self {
+        self.packet.ecn = ecn;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_identification(mut self, identification: u16) -> Self {
+        self.packet.identification = identification;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_ttl(mut self, ttl: u8) -> Self {
+        self.packet.ttl = ttl;
+        self
+    }
+
+    #[must_use]
+    pub fn set_options(mut self, options: Vec<Ipv4Option>) -> Self {
+        self.packet.options = options;
+        self
+    }
+
+    #[must_use]
+    pub fn set_data(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.packet.data = data.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Ipv4Packet> {
+        let packet = self.packet;
+        if !packet.dscp.is_valid() {
+            bail!("IPv4: DSCP {} doesn't fit in 6 bits", packet.dscp.0);
+        }
+
+        let mut options = Vec::new();
+        for option in &packet.options {
+            option.write(&mut options)?;
+        }
+        let header_length = MIN_HEADER_LENGTH as usize + options.len().next_multiple_of(4);
+        if header_length > MAX_HEADER_LENGTH {
+            bail!("IPv4: {} bytes of options is too many", options.len());
+        }
+        if header_length + packet.data.len() > u16::MAX as usize {
+            bail!("IPv4: payload of {} bytes is too big", packet.data.len());
+        }
+        Ok(packet)
+    }
 }
 
 impl Ipv4Packet {
+    pub const fn builder(
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        protocol: IpProtocol,
+    ) -> Ipv4PacketBuilder {
+        Ipv4PacketBuilder::new(source, destination, protocol)
+    }
+
     /// Parse an IPv4 packet from a reader
-    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+    pub async fn from_reader(reader: impl AsyncRead + Unpin) -> Result<Self> {
+        Self::from_reader_with_limits(reader, &ParseLimits::default()).await
+    }
+
+    /// Parse an IPv4 packet from a reader, failing with
+    /// [TooLarge](crate::limits::TooLarge) if it's over `limits`
+    pub async fn from_reader_with_limits(
+        reader: impl AsyncRead + Unpin,
+        limits: &ParseLimits,
+    ) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, limits, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [Ipv4Packet::from_reader_with_limits], but verifying the header
+    /// checksum only if `checksums` says to
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        limits: &ParseLimits,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
         let mut hasher = internet_checksum::Checksum::new();
 
         let (version, ihl) = {
@@ -46,17 +398,19 @@ impl Ipv4Packet {
             // If >=5, ihl is number of 32-bit words in header
             _ => 4 * ihl,
         };
+        limits.check_header("IPv4 header", ihl.into())?;
 
         let (dscp, ecn) = {
             let byte = reader.read_u8().await?;
             hasher.add_bytes(&[byte]);
-            (byte >> 2, byte & 0x03)
+            (Dscp(byte >> 2), Ecn::from_bits(byte))
         };
 
         let total_length = reader.read_u16().await?;
         if total_length < (ihl as u16) {
             bail!("Bad packet length: 0x{total_length:02x}");
         }
+        limits.check_payload("IPv4 payload", (total_length - ihl as u16).into())?;
         hasher.add_bytes(&total_length.to_be_bytes());
 
         let identification = reader.read_u16().await?;
@@ -74,6 +428,7 @@ impl Ipv4Packet {
         }
         let protocol = reader.read_u8().await?;
         hasher.add_bytes(&[protocol]);
+        let protocol = IpProtocol::from(protocol);
         let header_checksum = reader.read_u16().await?;
         hasher.add_bytes(&header_checksum.to_be_bytes());
         let source = Ipv4Addr::from_bits(reader.read_u32().await?);
@@ -81,14 +436,12 @@ impl Ipv4Packet {
         let destination = Ipv4Addr::from_bits(reader.read_u32().await?);
         hasher.add_bytes(&destination.to_bits().to_be_bytes());
 
-        if ihl > MIN_HEADER_LENGTH {
-            let options_size = ihl - MIN_HEADER_LENGTH;
-            let mut buffer = vec![0; options_size as usize];
-            reader.read_exact(&mut buffer).await?;
-            bail!("Ipv4: options not supported");
-        }
+        let mut options_bytes = vec![0; (ihl - MIN_HEADER_LENGTH) as usize];
+        reader.read_exact(&mut options_bytes).await?;
+        hasher.add_bytes(&options_bytes);
+        let options = Ipv4Option::parse_all(&options_bytes)?;
 
-        if hasher.checksum() != [0, 0] {
+        if checksums.ipv4.rx() && hasher.checksum() != [0, 0] {
             bail!("Invalid checksum");
         }
 
@@ -109,12 +462,78 @@ impl Ipv4Packet {
             protocol,
             source,
             destination,
+            options,
             data,
+            header_checksum: Some(header_checksum),
         })
     }
 
+    /// Take one off the TTL, patching the header checksum to match
+    ///
+    /// Returns false, leaving the packet untouched, if the TTL is already 0
+    pub fn decrement_ttl(&mut self) -> bool {
+        let old = u16::from_be_bytes([self.ttl, self.protocol.into()]);
+        let Some(ttl) = self.ttl.checked_sub(1) else {
+            return false;
+        };
+        self.ttl = ttl;
+        let new = u16::from_be_bytes([self.ttl, self.protocol.into()]);
+        self.header_checksum = self
+            .header_checksum
+            .map(|sum| checksum::update(sum, old, new));
+        true
+    }
+
+    /// Length of the header in bytes, options and padding included
+    pub fn header_length(&self) -> Result<usize> {
+        let mut options = Vec::new();
+        for option in &self.options {
+            option.write(&mut options)?;
+        }
+        Ok(MIN_HEADER_LENGTH as usize + options.len().next_multiple_of(4))
+    }
+
+    /// Parse the payload, if it's a transport protocol we know
+    pub async fn payload(&self) -> Result<Layer4Packet> {
+        self.payload_with_checksums(&ChecksumCapabilities::default())
+            .await
+    }
+
+    /// Like [Ipv4Packet::payload], but verifying only the checksums
+    /// `checksums` says to
+    pub async fn payload_with_checksums(
+        &self,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Layer4Packet> {
+        Layer4Packet::parse_with_checksums(self.protocol, &self.data, &self.into(), checksums).await
+    }
+
     /// Serialize an IPv4 packet into a writer
-    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+    pub async fn onto_writer(&mut self, writer: impl AsyncWrite + Unpin) -> Result<()> {
+        self.onto_writer_with_checksums(writer, &ChecksumCapabilities::default())
+            .await
+    }
+
+    /// Like [Ipv4Packet::onto_writer], but leaving the header checksum zero
+    /// unless `checksums` says to compute it
+    ///
+    /// The payload is written as it is, its own checksum and all.
+    pub async fn onto_writer_with_checksums(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<()> {
+        // Options, padded to a whole number of 32-bit words
+        let mut options = Vec::new();
+        for option in &self.options {
+            option.write(&mut options)?;
+        }
+        options.resize(options.len().next_multiple_of(4), 0);
+        let header_length = u8::try_from(MIN_HEADER_LENGTH as usize + options.len())?;
+        if header_length as usize > MAX_HEADER_LENGTH {
+            bail!("IPv4: options too long");
+        }
+
         let mut hasher = internet_checksum::Checksum::new();
         let mut write_bytes = async |bytes| -> Result<()> {
             writer.write_all(bytes).await?;
@@ -122,18 +541,20 @@ impl Ipv4Packet {
             Ok(())
         };
 
-        // Write version(4) and IHL(5)
-        write_bytes(&[(4 << 4) | 5]).await?;
+        // Write version(4) and IHL
+        let version_ihl = [(4 << 4) | (header_length / 4)];
+        write_bytes(&version_ihl).await?;
 
         // Write DSCP|ECN
-        if self.ecn > 0b11 {
-            bail!("IPv4: Invalid ECN");
+        if !self.dscp.is_valid() {
+            bail!("IPv4: DSCP {} doesn't fit in 6 bits", self.dscp.0);
         }
-        let val = [(self.dscp << 2) | self.ecn];
+        let val = [(self.dscp.0 << 2) | self.ecn.bits()];
         write_bytes(&val).await?;
 
-        // Write total length (minimum header length is 20)
-        let total_length = (20u16 + u16::try_from(self.data.len())?).to_be_bytes();
+        // Write total length
+        let total_length =
+            (u16::from(header_length) + u16::try_from(self.data.len())?).to_be_bytes();
         write_bytes(&total_length).await?;
 
         // Write identification
@@ -144,7 +565,7 @@ impl Ipv4Packet {
         write_bytes(&[(DONT_FRAGMENT as u8) << 5, 0]).await?;
 
         // Write TTL | protocol
-        let ttl_plus_protocol = [self.ttl, self.protocol];
+        let ttl_plus_protocol = [self.ttl, self.protocol.into()];
         write_bytes(&ttl_plus_protocol).await?;
 
         // Write Header checksum
@@ -152,12 +573,20 @@ impl Ipv4Packet {
         // the checksum field - so we have to stop using `write_bytes` here
         hasher.add_bytes(&self.source.to_bits().to_be_bytes());
         hasher.add_bytes(&self.destination.to_bits().to_be_bytes());
-        writer.write_all(&hasher.checksum()).await?;
+        hasher.add_bytes(&options);
+        let checksum = match self.header_checksum {
+            _ if !checksums.ipv4.tx() => [0, 0],
+            Some(checksum) => checksum.to_be_bytes(),
+            None => hasher.checksum(),
+        };
+        writer.write_all(&checksum).await?;
 
         // Write IP addresses
         writer.write_u32(self.source.to_bits()).await?;
         writer.write_u32(self.destination.to_bits()).await?;
 
+        writer.write_all(&options).await?;
+
         // Write data
         writer.write_all(&self.data).await?;
 
@@ -191,9 +620,9 @@ mod tests {
         let mut packet = Ipv4Packet::from_reader(raw.as_slice()).await?;
         assert_eq!(packet.identification, 0xb2fe);
         assert_eq!(packet.ttl, 255);
-        assert_eq!(packet.dscp, 0);
-        assert_eq!(packet.ecn, 0);
-        assert_eq!(packet.protocol, 0x11);
+        assert_eq!(packet.dscp, Dscp::CS0);
+        assert_eq!(packet.ecn, Ecn::NotEct);
+        assert_eq!(packet.protocol, IpProtocol::Udp);
         assert_eq!(packet.source.to_string(), "192.168.0.5");
         assert_eq!(packet.destination.to_string(), "224.0.0.251");
 
@@ -210,14 +639,16 @@ mod tests {
     #[tokio::test]
     async fn write() -> Result<()> {
         let mut packet = Ipv4Packet {
-            dscp: 0,
+            dscp: Dscp::CS0,
             ttl: 8,
-            ecn: 0,
+            ecn: Ecn::NotEct,
             identification: 0x1234,
-            protocol: 0x11,
+            protocol: IpProtocol::Udp,
             source: "1.2.3.4".parse()?,
             destination: "5.6.7.8".parse()?,
+            options: Vec::new(),
             data: vec![3, 1, 4, 1],
+            header_checksum: None,
         };
 
         let mut vec = Vec::new();
@@ -230,4 +661,163 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn limits() -> Result<()> {
+        let mut packet = Ipv4Packet {
+            dscp: Dscp::CS0,
+            ttl: 8,
+            ecn: Ecn::NotEct,
+            identification: 0x1234,
+            protocol: IpProtocol::Udp,
+            source: "1.2.3.4".parse()?,
+            destination: "5.6.7.8".parse()?,
+            options: Vec::new(),
+            data: vec![0; 100],
+            header_checksum: None,
+        };
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+
+        let limits = ParseLimits::default().set_max_payload(99);
+        let err = Ipv4Packet::from_reader_with_limits(vec.as_slice(), &limits)
+            .await
+            .unwrap_err();
+        let too_large = err.downcast_ref::<crate::limits::TooLarge>().unwrap();
+        assert_eq!((too_large.size, too_large.limit), (100, 99));
+
+        let limits = limits.set_max_payload(100).set_max_header(19);
+        let err = Ipv4Packet::from_reader_with_limits(vec.as_slice(), &limits)
+            .await
+            .unwrap_err();
+        assert!(err.is::<crate::limits::TooLarge>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checksum_capabilities() -> Result<()> {
+        let mut packet =
+            Ipv4Packet::builder("1.2.3.4".parse()?, "5.6.7.8".parse()?, IpProtocol::Udp)
+                .set_data(vec![1, 2, 3])
+                .build()?;
+        let ignored = ChecksumCapabilities::ignored();
+        let mut vec = Vec::new();
+        packet
+            .onto_writer_with_checksums(&mut vec, &ignored)
+            .await?;
+        assert_eq!(vec[10..12], [0, 0]);
+
+        let limits = ParseLimits::default();
+        assert!(Ipv4Packet::from_reader(vec.as_slice()).await.is_err());
+        let parsed =
+            Ipv4Packet::from_reader_with_checksums(vec.as_slice(), &limits, &ignored).await?;
+        assert_eq!(parsed, packet);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn options_round_trip() -> Result<()> {
+        let mut packet = Ipv4Packet {
+            dscp: Dscp::CS0,
+            ttl: 1,
+            ecn: Ecn::NotEct,
+            identification: 7,
+            protocol: IpProtocol::Igmp,
+            source: "10.0.0.1".parse()?,
+            destination: "224.0.0.22".parse()?,
+            options: vec![
+                Ipv4Option::RouterAlert(0),
+                Ipv4Option::NoOperation,
+                Ipv4Option::RecordRoute {
+                    pointer: 8,
+                    route: vec!["10.0.0.254".parse()?, Ipv4Addr::UNSPECIFIED],
+                },
+                Ipv4Option::Timestamp {
+                    pointer: 5,
+                    overflow: 0,
+                    flags: 0,
+                    data: vec![0; 4],
+                },
+                Ipv4Option::Other {
+                    kind: 0x86,
+                    data: vec![1, 2],
+                },
+                Ipv4Option::EndOfList,
+            ],
+            data: vec![0x22, 0, 0xf9, 0x02],
+            header_checksum: None,
+        };
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec[0], 0x4d);
+        assert_eq!(Ipv4Packet::from_reader(vec.as_slice()).await?, packet);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_options() {
+        assert!(Ipv4Option::parse_all(&[OPTION_ROUTER_ALERT, 9, 0, 0]).is_err());
+        assert!(Ipv4Option::parse_all(&[OPTION_ROUTER_ALERT]).is_err());
+        // Known kinds with the wrong shape fall back to raw
+        assert_eq!(
+            Ipv4Option::parse_all(&[OPTION_RECORD_ROUTE, 4, 4, 0]).unwrap(),
+            [Ipv4Option::Other {
+                kind: OPTION_RECORD_ROUTE,
+                data: vec![4, 0],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn decrement_ttl() -> Result<()> {
+        let mut vec = Vec::new();
+        Ipv4Packet::builder("10.0.0.1".parse()?, "10.0.0.2".parse()?, IpProtocol::Udp)
+            .set_data([1, 2, 3])
+            .build()?
+            .onto_writer(&mut vec)
+            .await?;
+        let mut packet = Ipv4Packet::from_reader(vec.as_slice()).await?;
+
+        // The checksum's patched, not recomputed, and comes out the same
+        assert!(packet.decrement_ttl());
+        assert_eq!(packet.ttl, DEFAULT_TTL - 1);
+        let mut patched = Vec::new();
+        packet.onto_writer(&mut patched).await?;
+        packet.header_checksum = None;
+        let mut computed = Vec::new();
+        packet.onto_writer(&mut computed).await?;
+        assert_eq!(patched, computed);
+
+        packet.ttl = 0;
+        assert!(!packet.decrement_ttl());
+        assert_eq!(packet.ttl, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builder() -> Result<()> {
+        let source = "10.0.0.1".parse()?;
+        let destination = "10.0.0.2".parse()?;
+        let mut packet = Ipv4Packet::builder(source, destination, IpProtocol::Udp)
+            .set_dscp(Dscp::EF)
+            .set_ecn(Ecn::Ect0)
+            .set_data([1, 2, 3])
+            .build()?;
+        assert_eq!(packet.ttl, DEFAULT_TTL);
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec[1], 0xba);
+        assert_eq!(vec[2..4], [0, 23]);
+        assert_eq!(Ipv4Packet::from_reader(vec.as_slice()).await?, packet);
+
+        let builder = Ipv4Packet::builder(source, destination, IpProtocol::Udp);
+        assert!(builder.clone().set_dscp(Dscp(64)).build().is_err());
+        assert!(builder.clone().set_data(vec![0; 65516]).build().is_err());
+        let options = vec![Ipv4Option::RouterAlert(0); 11];
+        assert!(builder.set_options(options).build().is_err());
+
+        packet.dscp = Dscp(64);
+        assert!(packet.onto_writer(&mut Vec::new()).await.is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,267 @@
+use super::IpProtocol;
+use crate::layer4::Layer4Packet;
+use anyhow::{Result, bail};
+use std::net::Ipv6Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const HEADER_LENGTH: usize = 40;
+pub const FRAGMENT_HEADER_LENGTH: usize = 8;
+/// Hop-by-hop option asking routers to look at a packet that isn't for
+/// them, e.g. MLD (RFC 2711)
+const OPTION_ROUTER_ALERT: u8 = 5;
+const OPTION_PADN: u8 = 1;
+/// Router alert value for MLD
+const ROUTER_ALERT_MLD: u16 = 0;
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// An IPv6 packet; extension headers, if any, are left in `data`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Ipv6Packet {
+    pub traffic_class: u8,
+    /// 20 bits
+    pub flow_label: u32,
+    pub next_header: IpProtocol,
+    pub hop_limit: u8,
+    pub source: Ipv6Addr,
+    pub destination: Ipv6Addr,
+    pub data: Vec<u8>,
+}
+
+impl Ipv6Packet {
+    pub fn new(
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        next_header: IpProtocol,
+        data: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self {
+            traffic_class: 0,
+            flow_label: 0,
+            next_header,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            source,
+            destination,
+            data: data.into(),
+        }
+    }
+
+    #[must_use]
+    pub const fn set_hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    /// Put a Router Alert option in front of the payload, as MLD messages
+    /// need
+    #[must_use]
+    pub fn set_router_alert(mut self) -> Self {
+        let [high, low] = ROUTER_ALERT_MLD.to_be_bytes();
+        let header = [
+            self.next_header.into(),
+            0,
+            OPTION_ROUTER_ALERT,
+            2,
+            high,
+            low,
+            OPTION_PADN,
+            0,
+        ];
+        self.data.splice(0..0, header);
+        self.next_header = IpProtocol::Ipv6HopByHop;
+        self
+    }
+
+    /// Take off a Hop-by-Hop Options header, if the payload starts with one
+    ///
+    /// The options are ignored; none we'd act on are defined for hosts.
+    pub fn skip_hop_by_hop(&mut self) -> Result<()> {
+        if self.next_header != IpProtocol::Ipv6HopByHop {
+            return Ok(());
+        }
+        let Some(&[next_header, length]) = self.data.first_chunk::<2>() else {
+            bail!("IPv6: hop-by-hop header too short");
+        };
+        // In units of 8 bytes, not counting the first 8
+        let length = (usize::from(length) + 1) * 8;
+        if self.data.len() < length {
+            bail!("IPv6: hop-by-hop header too short");
+        }
+        self.data.drain(..length);
+        self.next_header = next_header.into();
+        Ok(())
+    }
+
+    /// Parse a packet from a reader, stopping at the end of its payload
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let mut header = [0; HEADER_LENGTH];
+        reader.read_exact(&mut header).await?;
+        if header[0] >> 4 != 6 {
+            bail!("IPv6: wrong version {}", header[0] >> 4);
+        }
+        let first = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let payload_length = u16::from_be_bytes([header[4], header[5]]);
+        let mut data = vec![0; usize::from(payload_length)];
+        reader.read_exact(&mut data).await?;
+        Ok(Self {
+            traffic_class: (first >> 20) as u8,
+            flow_label: first & 0xfffff,
+            next_header: header[6].into(),
+            hop_limit: header[7],
+            source: <[u8; 16]>::try_from(&header[8..24])?.into(),
+            destination: <[u8; 16]>::try_from(&header[24..40])?.into(),
+            data,
+        })
+    }
+
+    /// Serialize a packet into a writer
+    /// Parse the payload, if it's a transport protocol we know; extension
+    /// headers should have been dealt with first
+    pub async fn payload(&self) -> Result<Layer4Packet> {
+        Layer4Packet::parse(self.next_header, &self.data, &self.into()).await
+    }
+
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_all(&self.to_bytes()?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for [Layer3Packet::Ipv6](super::Layer3Packet)
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let Ok(payload_length) = u16::try_from(self.data.len()) else {
+            bail!("IPv6: {} byte payload needs a jumbogram", self.data.len());
+        };
+        let first = (6 << 28) | (u32::from(self.traffic_class) << 20) | (self.flow_label & 0xfffff);
+        let mut raw = Vec::with_capacity(HEADER_LENGTH + self.data.len());
+        raw.extend_from_slice(&first.to_be_bytes());
+        raw.extend_from_slice(&payload_length.to_be_bytes());
+        raw.push(self.next_header.into());
+        raw.push(self.hop_limit);
+        raw.extend_from_slice(&self.source.octets());
+        raw.extend_from_slice(&self.destination.octets());
+        raw.extend_from_slice(&self.data);
+        Ok(raw)
+    }
+}
+
+/// The Fragment extension header (RFC 8200 §4.5), at the start of each
+/// fragment's data
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FragmentHeader {
+    /// What the reassembled data is
+    pub next_header: IpProtocol,
+    /// Where this fragment goes in the reassembled data, in bytes; always a
+    /// multiple of 8
+    pub offset: u16,
+    /// More fragments follow this one
+    pub more: bool,
+    pub identification: u32,
+}
+
+impl FragmentHeader {
+    /// Split a fragment's data into its header and the fragment itself
+    pub fn parse(data: &[u8]) -> Result<(Self, &[u8])> {
+        let Some((header, rest)) = data.split_first_chunk::<FRAGMENT_HEADER_LENGTH>() else {
+            bail!("IPv6: fragment header too short");
+        };
+        let offset_and_flags = u16::from_be_bytes([header[2], header[3]]);
+        let header = Self {
+            next_header: header[0].into(),
+            offset: offset_and_flags & !0x7,
+            more: offset_and_flags & 1 != 0,
+            identification: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        };
+        Ok((header, rest))
+    }
+
+    pub fn encode(&self) -> [u8; FRAGMENT_HEADER_LENGTH] {
+        let [high, low] = ((self.offset & !0x7) | u16::from(self.more)).to_be_bytes();
+        let [a, b, c, d] = self.identification.to_be_bytes();
+        [self.next_header.into(), 0, high, low, a, b, c, d]
+    }
+}
+
+/// The checksum of `data` for an upper-layer protocol, covering the IPv6
+/// pseudo-header too (RFC 8200 §8.1)
+///
+/// Gives `[0, 0]` for data with a correct checksum already in it.
+pub fn checksum(
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    next_header: IpProtocol,
+    data: &[u8],
+) -> [u8; 2] {
+    let mut checksum = internet_checksum::Checksum::new();
+    checksum.add_bytes(&source.octets());
+    checksum.add_bytes(&destination.octets());
+    checksum.add_bytes(&(data.len() as u32).to_be_bytes());
+    checksum.add_bytes(&[0, 0, 0, u8::from(next_header)]);
+    checksum.add_bytes(data);
+    checksum.checksum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let mut packet = Ipv6Packet::new(
+            "fe80::1".parse()?,
+            "ff02::1".parse()?,
+            IpProtocol::Udp,
+            *b"data",
+        )
+        .set_hop_limit(1);
+        packet.traffic_class = 0xb8;
+        packet.flow_label = 0x12345;
+
+        let mut raw = Vec::new();
+        packet.onto_writer(&mut raw).await?;
+        assert_eq!(raw[..8], [0x6b, 0x81, 0x23, 0x45, 0, 4, 17, 1]);
+        // Trailing padding is left unread
+        raw.extend_from_slice(&[0; 6]);
+        assert_eq!(Ipv6Packet::from_reader(raw.as_slice()).await?, packet);
+
+        raw[0] = 0x45;
+        assert!(Ipv6Packet::from_reader(raw.as_slice()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hop_by_hop() -> Result<()> {
+        let original = Ipv6Packet::new(
+            "fe80::1".parse()?,
+            "ff02::16".parse()?,
+            IpProtocol::Icmpv6,
+            *b"data",
+        );
+        let mut alerted = original.clone().set_router_alert();
+        assert_eq!(alerted.next_header, IpProtocol::Ipv6HopByHop);
+        assert_eq!(alerted.data[..8], [58, 0, 5, 2, 0, 0, 1, 0]);
+        alerted.skip_hop_by_hop()?;
+        assert_eq!(alerted, original);
+
+        alerted.next_header = IpProtocol::Ipv6HopByHop;
+        assert!(alerted.skip_hop_by_hop().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_header() -> Result<()> {
+        let raw = [17, 0, 0x05, 0xa9, 0xde, 0xad, 0xbe, 0xef, 1, 2];
+        let (header, rest) = FragmentHeader::parse(&raw)?;
+        assert_eq!(
+            header,
+            FragmentHeader {
+                next_header: IpProtocol::Udp,
+                offset: 1448,
+                more: true,
+                identification: 0xdeadbeef,
+            }
+        );
+        assert_eq!(rest, [1, 2]);
+        assert_eq!(header.encode(), raw[..FRAGMENT_HEADER_LENGTH]);
+        assert!(FragmentHeader::parse(&raw[..7]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// SAP used by both ends when a SNAP header follows
+const SNAP_SAP: u8 = 0xaa;
+
+/// Control byte for unnumbered information (UI) frames
+const CONTROL_UI: u8 = 0x03;
+
+/// SAP for the spanning tree protocol
+pub const STP_SAP: u8 = 0x42;
+
+/// 802.2 LLC control field
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LlcControl {
+    /// U-format, one byte
+    Unnumbered(u8),
+    /// I- or S-format, two bytes
+    Sequenced(u16),
+}
+
+/// SNAP extension, identifying the protocol by OUI and protocol ID
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Snap {
+    pub oui: [u8; 3],
+    pub protocol_id: u16,
+}
+
+/// An 802.2 LLC header (and SNAP extension, if any) with its data, as
+/// carried by 802.3 frames, e.g. STP and CDP
+#[derive(Clone, PartialEq, Debug)]
+pub struct LlcPacket {
+    pub dsap: u8,
+    pub ssap: u8,
+    pub control: LlcControl,
+    pub snap: Option<Snap>,
+    pub data: Vec<u8>,
+}
+
+impl LlcPacket {
+    /// True if this carries spanning tree BPDUs (see
+    /// [Bpdu](super::stp::Bpdu))
+    pub fn is_stp(&self) -> bool {
+        self.dsap == STP_SAP && self.ssap == STP_SAP
+    }
+
+    /// Parse an LLC packet from a reader, taking everything left as data
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let dsap = reader.read_u8().await?;
+        let ssap = reader.read_u8().await?;
+        let first = reader.read_u8().await?;
+        let control = if first & 0b11 == 0b11 {
+            LlcControl::Unnumbered(first)
+        } else {
+            LlcControl::Sequenced(u16::from_be_bytes([first, reader.read_u8().await?]))
+        };
+
+        let snap = if dsap == SNAP_SAP && ssap == SNAP_SAP {
+            if control != LlcControl::Unnumbered(CONTROL_UI) {
+                bail!("LLC: SNAP with unexpected control {control:?}");
+            }
+            let mut oui = [0; 3];
+            reader.read_exact(&mut oui).await?;
+            let protocol_id = reader.read_u16().await?;
+            Some(Snap { oui, protocol_id })
+        } else {
+            None
+        };
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+
+        Ok(Self {
+            dsap,
+            ssap,
+            control,
+            snap,
+            data,
+        })
+    }
+
+    /// Serialize an LLC packet into a writer
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_u8(self.dsap).await?;
+        writer.write_u8(self.ssap).await?;
+        match self.control {
+            LlcControl::Unnumbered(control) => writer.write_u8(control).await?,
+            LlcControl::Sequenced(control) => writer.write_u16(control).await?,
+        }
+        if let Some(snap) = &self.snap {
+            writer.write_all(&snap.oui).await?;
+            writer.write_u16(snap.protocol_id).await?;
+        }
+        writer.write_all(&self.data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_stp() -> Result<()> {
+        // Start of an STP BPDU
+        let raw = [0x42, 0x42, 0x03, 0x00, 0x00, 0x00];
+        let mut packet = LlcPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.dsap, 0x42);
+        assert_eq!(packet.control, LlcControl::Unnumbered(0x03));
+        assert_eq!(packet.snap, None);
+        assert_eq!(packet.data, [0, 0, 0]);
+
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec, raw);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_cdp_snap() -> Result<()> {
+        let raw = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x0c, 0x20, 0x00, 0x02, 0xb4];
+        let mut packet = LlcPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(
+            packet.snap,
+            Some(Snap {
+                oui: [0x00, 0x00, 0x0c],
+                protocol_id: 0x2000,
+            })
+        );
+        assert_eq!(packet.data, [0x02, 0xb4]);
+
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec, raw);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sequenced_control() -> Result<()> {
+        let raw = [0x04, 0x05, 0x02, 0x10, 0xff];
+        let packet = LlcPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.control, LlcControl::Sequenced(0x0210));
+        assert_eq!(packet.data, [0xff]);
+        Ok(())
+    }
+}
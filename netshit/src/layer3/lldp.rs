@@ -0,0 +1,222 @@
+use crate::eth::{EthFrame, Mac6};
+use crate::layer3::Layer3Packet;
+use anyhow::{Result, bail};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Nearest-bridge multicast address LLDP frames are sent to
+pub const LLDP_MULTICAST: Mac6 = Mac6::new([0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e]);
+
+/// How often to advertise, as recommended by 802.1AB
+pub const ADVERTISE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default TTL: four missed advertisements
+const DEFAULT_TTL: u16 = 120;
+
+const TLV_END: u8 = 0;
+const TLV_CHASSIS_ID: u8 = 1;
+const TLV_PORT_ID: u8 = 2;
+const TLV_TTL: u8 = 3;
+const TLV_SYSTEM_NAME: u8 = 5;
+
+/// Chassis ID subtype for a MAC address
+const CHASSIS_SUBTYPE_MAC: u8 = 4;
+/// Port ID subtype for an interface name
+const PORT_SUBTYPE_NAME: u8 = 5;
+
+/// A chassis or port ID: a subtype saying how to interpret the ID, and the ID
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LldpId {
+    pub subtype: u8,
+    pub id: Vec<u8>,
+}
+
+impl LldpId {
+    fn from_value(value: &[u8]) -> Result<Self> {
+        let Some((&subtype, id)) = value.split_first() else {
+            bail!("LLDP: empty ID");
+        };
+        Ok(Self {
+            subtype,
+            id: id.to_vec(),
+        })
+    }
+}
+
+/// A Link Layer Discovery Protocol data unit
+#[derive(Clone, PartialEq, Debug)]
+pub struct LldpPacket {
+    pub chassis_id: LldpId,
+    pub port_id: LldpId,
+    /// Seconds the receiver should keep this information
+    pub ttl: u16,
+    pub system_name: Option<String>,
+    /// TLVs we don't interpret, as (type, value)
+    pub other: Vec<(u8, Vec<u8>)>,
+}
+
+impl LldpPacket {
+    /// Advertisement for an interface, identified by its MAC and name
+    pub fn new(mac: Mac6, port: &str) -> Self {
+        Self {
+            chassis_id: LldpId {
+                subtype: CHASSIS_SUBTYPE_MAC,
+                id: mac.as_bytes().to_vec(),
+            },
+            port_id: LldpId {
+                subtype: PORT_SUBTYPE_NAME,
+                id: port.as_bytes().to_vec(),
+            },
+            ttl: DEFAULT_TTL,
+            system_name: None,
+            other: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn set_ttl(mut self, ttl: u16) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    #[must_use]
+    pub fn set_system_name(mut self, name: impl Into<String>) -> Self {
+        self.system_name = Some(name.into());
+        self
+    }
+
+    /// Parse an LLDPDU from a reader, stopping at the End TLV
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let mut chassis_id = None;
+        let mut port_id = None;
+        let mut ttl = None;
+        let mut system_name = None;
+        let mut other = Vec::new();
+
+        loop {
+            let header = reader.read_u16().await?;
+            let tlv_type = (header >> 9) as u8;
+            let mut value = vec![0; (header & 0x1ff) as usize];
+            reader.read_exact(&mut value).await?;
+
+            match tlv_type {
+                TLV_END => break,
+                TLV_CHASSIS_ID => chassis_id = Some(LldpId::from_value(&value)?),
+                TLV_PORT_ID => port_id = Some(LldpId::from_value(&value)?),
+                TLV_TTL => {
+                    let Ok(bytes) = <[u8; 2]>::try_from(value.as_slice()) else {
+                        bail!("LLDP: bad TTL length {}", value.len());
+                    };
+                    ttl = Some(u16::from_be_bytes(bytes));
+                }
+                TLV_SYSTEM_NAME => system_name = Some(String::from_utf8_lossy(&value).into_owned()),
+                _ => other.push((tlv_type, value)),
+            }
+        }
+
+        let (Some(chassis_id), Some(port_id), Some(ttl)) = (chassis_id, port_id, ttl) else {
+            bail!("LLDP: missing mandatory TLV");
+        };
+        Ok(Self {
+            chassis_id,
+            port_id,
+            ttl,
+            system_name,
+            other,
+        })
+    }
+
+    /// Serialize an LLDPDU into a writer
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        let mut vec = Vec::new();
+        for (tlv_type, id) in [
+            (TLV_CHASSIS_ID, &self.chassis_id),
+            (TLV_PORT_ID, &self.port_id),
+        ] {
+            let mut value = vec![id.subtype];
+            value.extend_from_slice(&id.id);
+            write_tlv(&mut vec, tlv_type, &value)?;
+        }
+        write_tlv(&mut vec, TLV_TTL, &self.ttl.to_be_bytes())?;
+        if let Some(name) = &self.system_name {
+            write_tlv(&mut vec, TLV_SYSTEM_NAME, name.as_bytes())?;
+        }
+        for (tlv_type, value) in &self.other {
+            write_tlv(&mut vec, *tlv_type, value)?;
+        }
+        write_tlv(&mut vec, TLV_END, &[])?;
+
+        writer.write_all(&vec).await?;
+        Ok(())
+    }
+}
+
+fn write_tlv(vec: &mut Vec<u8>, tlv_type: u8, value: &[u8]) -> Result<()> {
+    if tlv_type > 0x7f || value.len() > 0x1ff {
+        bail!("LLDP: TLV {tlv_type} too large to encode");
+    }
+    let header = (u16::from(tlv_type) << 9) | value.len() as u16;
+    vec.extend_from_slice(&header.to_be_bytes());
+    vec.extend_from_slice(value);
+    Ok(())
+}
+
+/// Send `packet` from `src` every `interval`, forever
+pub async fn advertise(
+    mut writer: impl AsyncWrite + Unpin,
+    src: Mac6,
+    packet: LldpPacket,
+    interval: Duration,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut frame = EthFrame::new(LLDP_MULTICAST, src, Layer3Packet::Lldp(packet.clone()));
+        frame.onto_writer(&mut writer).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_lldpdu() -> Result<()> {
+        let raw = [
+            0x02, 0x07, 0x04, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, // chassis ID
+            0x04, 0x04, 0x05, b'e', b't', b'h', // port ID
+            0x06, 0x02, 0x00, 0x78, // TTL
+            0x0a, 0x03, b's', b'w', b'1', // system name
+            0x0c, 0x01, b'x', // system description
+            0x00, 0x00, // end
+            0xff, 0xff, // padding
+        ];
+        let mut packet = LldpPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.chassis_id.id, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(packet.port_id.id, b"eth");
+        assert_eq!(packet.ttl, 120);
+        assert_eq!(packet.system_name.as_deref(), Some("sw1"));
+        assert_eq!(packet.other, [(6, b"x".to_vec())]);
+
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec, raw[..raw.len() - 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn advertisement_round_trip() -> Result<()> {
+        let mac = Mac6::from([2, 0, 0, 0, 0, 1]);
+        let mut packet = LldpPacket::new(mac, "tap0").set_system_name("netshit");
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(LldpPacket::from_reader(vec.as_slice()).await?, packet);
+
+        assert!(
+            LldpPacket::from_reader([0x00, 0x00].as_slice())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
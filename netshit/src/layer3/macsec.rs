@@ -0,0 +1,184 @@
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length of the integrity check value with the default cipher suites
+const ICV_LENGTH: usize = 16;
+
+const TCI_VERSION: u8 = 0x80;
+const TCI_END_STATION: u8 = 0x40;
+const TCI_SCI_PRESENT: u8 = 0x20;
+const TCI_SINGLE_COPY_BROADCAST: u8 = 0x10;
+const TCI_ENCRYPTED: u8 = 0x08;
+const TCI_CHANGED: u8 = 0x04;
+const TCI_ASSOCIATION_NUMBER: u8 = 0x03;
+
+/// The security tag following the MACsec ethtype
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SecTag {
+    /// Tag control information, with the association number in the low bits
+    pub tci_an: u8,
+    /// Length of short secure data, or 0 if at least 48 bytes
+    pub short_length: u8,
+    pub packet_number: u32,
+    /// Secure channel identifier, if sent explicitly
+    pub sci: Option<u64>,
+}
+
+impl SecTag {
+    pub const fn association_number(&self) -> u8 {
+        self.tci_an & TCI_ASSOCIATION_NUMBER
+    }
+
+    /// True if the data is encrypted rather than only integrity protected
+    pub const fn encrypted(&self) -> bool {
+        self.tci_an & TCI_ENCRYPTED != 0
+    }
+
+    /// True if the data was modified (by encryption) and isn't the original
+    /// user data
+    pub const fn changed(&self) -> bool {
+        self.tci_an & TCI_CHANGED != 0
+    }
+
+    pub const fn end_station(&self) -> bool {
+        self.tci_an & TCI_END_STATION != 0
+    }
+
+    pub const fn single_copy_broadcast(&self) -> bool {
+        self.tci_an & TCI_SINGLE_COPY_BROADCAST != 0
+    }
+}
+
+/// Decrypts (or verifies) MACsec data, e.g. with keys from MKA
+pub trait MacsecDecryptor {
+    /// Return the user data protected by `packet`
+    fn decrypt(&self, packet: &MacsecPacket) -> Result<Vec<u8>>;
+}
+
+/// An 802.1AE frame payload: SecTAG, secure data, and ICV
+#[derive(Clone, PartialEq, Debug)]
+pub struct MacsecPacket {
+    pub tag: SecTag,
+    /// Secure data, which may be encrypted
+    pub data: Vec<u8>,
+    pub icv: [u8; ICV_LENGTH],
+}
+
+impl MacsecPacket {
+    /// Parse a MACsec packet from a reader
+    ///
+    /// Unless the SecTAG gives a short length, everything left is taken as
+    /// secure data and ICV, so the reader mustn't include an FCS.
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let tci_an = reader.read_u8().await?;
+        if tci_an & TCI_VERSION != 0 {
+            bail!("MACsec: unsupported version");
+        }
+        let short_length = reader.read_u8().await? & 0x3f;
+        let packet_number = reader.read_u32().await?;
+        let sci = if tci_an & TCI_SCI_PRESENT != 0 {
+            Some(reader.read_u64().await?)
+        } else {
+            None
+        };
+
+        let mut data = Vec::new();
+        if short_length == 0 {
+            reader.read_to_end(&mut data).await?;
+        } else {
+            data.resize(short_length as usize + ICV_LENGTH, 0);
+            reader.read_exact(&mut data).await?;
+        }
+        let Some(data_length) = data.len().checked_sub(ICV_LENGTH) else {
+            bail!("MACsec: too short for an ICV");
+        };
+        let icv = data.split_off(data_length).try_into().expect("ICV length");
+
+        Ok(Self {
+            tag: SecTag {
+                tci_an,
+                short_length,
+                packet_number,
+                sci,
+            },
+            data,
+            icv,
+        })
+    }
+
+    /// Serialize a MACsec packet into a writer
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        let mut tci_an = self.tag.tci_an & !TCI_SCI_PRESENT;
+        if self.tag.sci.is_some() {
+            tci_an |= TCI_SCI_PRESENT;
+        }
+        writer.write_u8(tci_an).await?;
+        writer.write_u8(self.tag.short_length).await?;
+        writer.write_u32(self.tag.packet_number).await?;
+        if let Some(sci) = self.tag.sci {
+            writer.write_u64(sci).await?;
+        }
+        writer.write_all(&self.data).await?;
+        writer.write_all(&self.icv).await?;
+        Ok(())
+    }
+
+    /// Recover the user data with `decryptor`
+    pub fn decrypt(&self, decryptor: &impl MacsecDecryptor) -> Result<Vec<u8>> {
+        decryptor.decrypt(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_sectag() -> Result<()> {
+        let mut raw = vec![0x2c, 0x04, 0x00, 0x00, 0x00, 0x2a];
+        raw.extend_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00, 0x01]);
+        raw.extend_from_slice(b"data");
+        raw.extend_from_slice(&[0xcc; ICV_LENGTH]);
+        // Padding to the minimum frame size
+        raw.extend_from_slice(&[0; 8]);
+
+        let mut packet = MacsecPacket::from_reader(raw.as_slice()).await?;
+        assert!(packet.tag.encrypted());
+        assert!(packet.tag.changed());
+        assert_eq!(packet.tag.association_number(), 0);
+        assert_eq!(packet.tag.packet_number, 42);
+        assert_eq!(packet.tag.sci, Some(0x0011_2233_4455_0001));
+        assert_eq!(packet.data, b"data");
+        assert_eq!(packet.icv, [0xcc; ICV_LENGTH]);
+
+        let mut vec = Vec::new();
+        packet.onto_writer(&mut vec).await?;
+        assert_eq!(vec, raw[..raw.len() - 8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pluggable_decryption() -> Result<()> {
+        /// "Decrypts" by flipping every bit
+        struct Invert;
+        impl MacsecDecryptor for Invert {
+            fn decrypt(&self, packet: &MacsecPacket) -> Result<Vec<u8>> {
+                Ok(packet.data.iter().map(|b| !b).collect())
+            }
+        }
+
+        let mut raw = vec![0x09, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfe, 0xfd];
+        raw.extend_from_slice(&[0; ICV_LENGTH]);
+        let packet = MacsecPacket::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.tag.sci, None);
+        assert_eq!(packet.tag.association_number(), 1);
+        assert_eq!(packet.decrypt(&Invert)?, [0x01, 0x02]);
+
+        assert!(
+            MacsecPacket::from_reader([0x80, 0, 0, 0, 0, 0].as_slice())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
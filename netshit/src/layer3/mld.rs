@@ -0,0 +1,268 @@
+//! Multicast Listener Discovery (RFC 2710, RFC 3810): IPv6's IGMP, carried
+//! in ICMPv6
+use anyhow::{Result, bail};
+use std::net::Ipv6Addr;
+use std::time::Duration;
+
+/// Every MLD message is sent with this hop limit, so never leaves the link
+pub const HOP_LIMIT: u8 = 1;
+/// Where MLDv2 reports go
+pub const ALL_MLDV2_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x16);
+
+/// Record types in v2 reports, as in IGMPv3
+pub const MODE_IS_INCLUDE: u8 = 1;
+pub const MODE_IS_EXCLUDE: u8 = 2;
+pub const CHANGE_TO_INCLUDE: u8 = 3;
+pub const CHANGE_TO_EXCLUDE: u8 = 4;
+
+/// Length of a v1 message after the checksum; v2 queries are longer
+const V1_LENGTH: usize = 20;
+
+/// The extra fields of an MLDv2 query
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct QueryV2 {
+    /// Routers shouldn't lower their timers on hearing this
+    pub suppress_router_processing: bool,
+    /// Querier's robustness variable
+    pub robustness: u8,
+    /// Querier's query interval, encoded like IGMPv3's
+    pub interval_code: u8,
+    pub sources: Vec<Ipv6Addr>,
+}
+
+/// A query: who's listening to `group` (or any group, if unspecified)?
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MldQuery {
+    pub max_response_code: u16,
+    pub group: Ipv6Addr,
+    /// Present in v2 queries
+    pub v2: Option<QueryV2>,
+}
+
+impl MldQuery {
+    /// A general query is about every group
+    pub fn is_general(&self) -> bool {
+        self.group.is_unspecified()
+    }
+
+    /// How long we have to answer
+    pub fn max_response_time(&self) -> Duration {
+        let millis = match &self.v2 {
+            Some(_) => decode_time(self.max_response_code),
+            None => u32::from(self.max_response_code),
+        };
+        Duration::from_millis(millis.into())
+    }
+
+    /// Parse everything after the ICMPv6 checksum
+    pub(super) fn parse(rest: &[u8]) -> Result<Self> {
+        if rest.len() < V1_LENGTH {
+            bail!("MLD: query too short");
+        }
+        let v2 = match rest[V1_LENGTH..].split_first_chunk::<4>() {
+            None => None,
+            Some((&[flags, interval_code, high, low], mut sources)) => {
+                let count = u16::from_be_bytes([high, low]);
+                let mut addresses = Vec::new();
+                for _ in 0..count {
+                    addresses.push(read_address(&mut sources)?);
+                }
+                Some(QueryV2 {
+                    suppress_router_processing: flags & 0x08 != 0,
+                    robustness: flags & 0x07,
+                    interval_code,
+                    sources: addresses,
+                })
+            }
+        };
+        Ok(Self {
+            max_response_code: u16::from_be_bytes([rest[0], rest[1]]),
+            group: read_address(&mut &rest[4..])?,
+            v2,
+        })
+    }
+
+    /// Robustness is capped at 7, the most its 3 bits hold
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut raw = self.max_response_code.to_be_bytes().to_vec();
+        raw.extend_from_slice(&[0, 0]);
+        raw.extend_from_slice(&self.group.octets());
+        if let Some(v2) = &self.v2 {
+            raw.push(u8::from(v2.suppress_router_processing) << 3 | v2.robustness.min(7));
+            raw.push(v2.interval_code);
+            raw.extend_from_slice(&count(v2.sources.len()).to_be_bytes());
+            for source in &v2.sources {
+                raw.extend_from_slice(&source.octets());
+            }
+        }
+        raw
+    }
+}
+
+/// Decode a v2 maximum response code: a plain number of milliseconds below
+/// 32768, else a float
+fn decode_time(code: u16) -> u32 {
+    if code < 0x8000 {
+        return u32::from(code);
+    }
+    let mantissa = u32::from(code & 0x0fff);
+    let exponent = u32::from((code >> 12) & 0x07);
+    (mantissa | 0x1000) << (exponent + 3)
+}
+
+/// One group's state in a v2 report
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MldRecord {
+    pub record_type: u8,
+    pub group: Ipv6Addr,
+    pub sources: Vec<Ipv6Addr>,
+    pub auxiliary: Vec<u8>,
+}
+
+impl MldRecord {
+    /// Exclude no sources, i.e. listen to everything sent to `group`
+    pub const fn exclude_none(record_type: u8, group: Ipv6Addr) -> Self {
+        Self {
+            record_type,
+            group,
+            sources: Vec::new(),
+            auxiliary: Vec::new(),
+        }
+    }
+}
+
+/// The group a v1 report or done message is about, from everything after
+/// the ICMPv6 checksum
+pub(super) fn parse_group(rest: &[u8]) -> Result<Ipv6Addr> {
+    let Some(mut group) = rest.get(4..V1_LENGTH) else {
+        bail!("MLD: message too short");
+    };
+    read_address(&mut group)
+}
+
+/// A v1 report or done message's fields after the checksum
+pub(super) fn encode_group(group: Ipv6Addr) -> Vec<u8> {
+    let mut raw = vec![0; 4];
+    raw.extend_from_slice(&group.octets());
+    raw
+}
+
+/// Parse a v2 report's records, from everything after the ICMPv6 checksum
+pub(super) fn parse_records(rest: &[u8]) -> Result<Vec<MldRecord>> {
+    let Some((&[_, _, high, low], mut rest)) = rest.split_first_chunk::<4>() else {
+        bail!("MLD: report too short");
+    };
+    let count = u16::from_be_bytes([high, low]);
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let Some((&[record_type, auxiliary_words, high, low], tail)) =
+            rest.split_first_chunk::<4>()
+        else {
+            bail!("MLD: record too short");
+        };
+        rest = tail;
+        let group = read_address(&mut rest)?;
+        let mut sources = Vec::new();
+        for _ in 0..u16::from_be_bytes([high, low]) {
+            sources.push(read_address(&mut rest)?);
+        }
+        let Some((auxiliary, tail)) = rest.split_at_checked(usize::from(auxiliary_words) * 4)
+        else {
+            bail!("MLD: record auxiliary data too short");
+        };
+        rest = tail;
+        records.push(MldRecord {
+            record_type,
+            group,
+            sources,
+            auxiliary: auxiliary.to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+/// A v2 report's fields after the checksum
+///
+/// Auxiliary data is zero-padded to whole 32-bit words.
+pub(super) fn encode_records(records: &[MldRecord]) -> Vec<u8> {
+    let [high, low] = count(records.len()).to_be_bytes();
+    let mut raw = vec![0, 0, high, low];
+    for record in records {
+        let words = u8::try_from(record.auxiliary.len().div_ceil(4)).unwrap_or(u8::MAX);
+        raw.push(record.record_type);
+        raw.push(words);
+        raw.extend_from_slice(&count(record.sources.len()).to_be_bytes());
+        raw.extend_from_slice(&record.group.octets());
+        for source in &record.sources {
+            raw.extend_from_slice(&source.octets());
+        }
+        let mut auxiliary = record.auxiliary.clone();
+        auxiliary.resize(usize::from(words) * 4, 0);
+        raw.extend_from_slice(&auxiliary);
+    }
+    raw
+}
+
+/// A count for a 16-bit field; more than fit could never be sent anyway,
+/// being far past the largest IPv6 payload
+fn count(length: usize) -> u16 {
+    u16::try_from(length).unwrap_or(u16::MAX)
+}
+
+fn read_address(raw: &mut &[u8]) -> Result<Ipv6Addr> {
+    let Some((address, rest)) = raw.split_first_chunk::<16>() else {
+        bail!("MLD: address cut short");
+    };
+    *raw = rest;
+    Ok(Ipv6Addr::from(*address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_times() {
+        let query = |max_response_code, v2: bool| MldQuery {
+            max_response_code,
+            group: Ipv6Addr::UNSPECIFIED,
+            v2: v2.then(|| QueryV2 {
+                suppress_router_processing: false,
+                robustness: 2,
+                interval_code: 125,
+                sources: Vec::new(),
+            }),
+        };
+        assert_eq!(
+            query(10000, false).max_response_time(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            query(10000, true).max_response_time(),
+            Duration::from_secs(10)
+        );
+        // 0x8000 is a mantissa of 0 and exponent of 0: 0x1000 << 3
+        assert_eq!(
+            query(0x8000, true).max_response_time(),
+            Duration::from_millis(32768)
+        );
+        assert!(query(0, false).is_general());
+    }
+
+    #[test]
+    fn records_round_trip() -> Result<()> {
+        let records = vec![
+            MldRecord::exclude_none(CHANGE_TO_EXCLUDE, "ff02::fb".parse()?),
+            MldRecord {
+                record_type: MODE_IS_INCLUDE,
+                group: "ff05::1:3".parse()?,
+                sources: vec!["2001:db8::1".parse()?],
+                auxiliary: vec![1, 2, 3, 4],
+            },
+        ];
+        let raw = encode_records(&records);
+        assert_eq!(parse_records(&raw)?, records);
+        assert!(parse_records(&raw[..raw.len() - 1]).is_err());
+        Ok(())
+    }
+}
@@ -1,25 +1,160 @@
-mod arp;
-mod ipv4;
+pub mod arp;
+mod eapol;
+pub mod icmp;
+pub mod icmpv6;
+pub mod igmp;
+pub mod ipv4;
+pub mod ipv6;
+mod llc;
+pub mod lldp;
+pub mod macsec;
+pub mod mld;
+pub mod ndp;
+pub mod stp;
+use crate::checksum::ChecksumCapabilities;
+use crate::eth::{EtherType, Mac6};
 use anyhow::Result;
 pub use arp::ArpPacket;
+pub use eapol::EapolPacket;
+pub use icmp::IcmpPacket;
 pub use ipv4::Ipv4Packet;
+pub use llc::LlcPacket;
+pub use lldp::LldpPacket;
+pub use macsec::MacsecPacket;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+/// The protocol carried by an IP packet
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum IpProtocol {
+    /// IPv6 Hop-by-Hop Options extension header
+    Ipv6HopByHop,
+    Icmp,
+    Igmp,
+    /// IP-in-IP encapsulation
+    IpInIp,
+    Tcp,
+    Udp,
+    /// IPv6 carried in IPv4 (6in4)
+    Ipv6,
+    Gre,
+    /// IPv6 Fragment extension header
+    Ipv6Fragment,
+    Icmpv6,
+    /// Stream Control Transmission Protocol
+    Sctp,
+    /// UDP with partial checksums (RFC 3828)
+    UdpLite,
+    Other(u8),
+}
+
+impl From<u8> for IpProtocol {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Ipv6HopByHop,
+            1 => Self::Icmp,
+            2 => Self::Igmp,
+            4 => Self::IpInIp,
+            6 => Self::Tcp,
+            17 => Self::Udp,
+            41 => Self::Ipv6,
+            44 => Self::Ipv6Fragment,
+            47 => Self::Gre,
+            58 => Self::Icmpv6,
+            132 => Self::Sctp,
+            136 => Self::UdpLite,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+impl From<IpProtocol> for u8 {
+    fn from(value: IpProtocol) -> Self {
+        match value {
+            IpProtocol::Ipv6HopByHop => 0,
+            IpProtocol::Icmp => 1,
+            IpProtocol::Igmp => 2,
+            IpProtocol::IpInIp => 4,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Ipv6 => 41,
+            IpProtocol::Ipv6Fragment => 44,
+            IpProtocol::Gre => 47,
+            IpProtocol::Icmpv6 => 58,
+            IpProtocol::Sctp => 132,
+            IpProtocol::UdpLite => 136,
+            IpProtocol::Other(value) => value,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Layer3Packet {
     Ipv4(Ipv4Packet),
     Arp(ArpPacket),
+    /// Reverse ARP, in the same format as ARP
+    Rarp(ArpPacket),
+    /// IPv6 packet, header included, not parsed yet
+    Ipv6(Vec<u8>),
+    /// Link Layer Discovery Protocol
+    Lldp(LldpPacket),
+    /// 802.1X authentication
+    Eapol(EapolPacket),
+    /// 802.1AE secured frame, left encrypted
+    Macsec(MacsecPacket),
+    /// Wake-on-LAN magic packet, holding the MAC to wake
+    WakeOnLan(Mac6),
+    /// 802.2 LLC, carried by 802.3 frames
+    Llc(LlcPacket),
+    /// 802.3 payload we don't understand
     Unknown(Vec<u8>),
+    /// Payload of an ethtype we don't parse, kept so the frame round-trips
+    UnknownEthType {
+        ethtype: EtherType,
+        payload: Vec<u8>,
+    },
 }
 
 impl Layer3Packet {
-    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+    pub async fn onto_writer(&mut self, writer: impl AsyncWrite + Unpin) -> Result<()> {
+        self.onto_writer_with_checksums(writer, &ChecksumCapabilities::default())
+            .await
+    }
+
+    /// Like [Layer3Packet::onto_writer], but computing only the checksums
+    /// `checksums` says to
+    pub async fn onto_writer_with_checksums(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<()> {
         match self {
-            Self::Ipv4(packet) => packet.onto_writer(writer).await?,
-            Self::Arp(packet) => packet.onto_writer(writer).await?,
+            Self::Ipv4(packet) => packet.onto_writer_with_checksums(writer, checksums).await?,
+            Self::Arp(packet) | Self::Rarp(packet) => packet.onto_writer(writer).await?,
+            Self::Lldp(packet) => packet.onto_writer(writer).await?,
+            Self::Eapol(packet) => packet.onto_writer(writer).await?,
+            Self::Macsec(packet) => packet.onto_writer(writer).await?,
+            Self::WakeOnLan(target) => writer.write_all(&crate::wol::magic_packet(*target)).await?,
+            Self::Llc(packet) => packet.onto_writer(writer).await?,
+            Self::Ipv6(packet) => writer.write_all(packet).await?,
             Self::Unknown(packet) => writer.write_all(packet).await?,
+            Self::UnknownEthType { payload, .. } => writer.write_all(payload).await?,
         };
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_protocol_conversions() {
+        assert_eq!(IpProtocol::from(17), IpProtocol::Udp);
+        assert_eq!(IpProtocol::from(136), IpProtocol::UdpLite);
+        assert_eq!(IpProtocol::from(89), IpProtocol::Other(89));
+        for value in 0..=u8::MAX {
+            assert_eq!(u8::from(IpProtocol::from(value)), value);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Neighbour Discovery (RFC 4861): IPv6's replacement for ARP, carried in
+//! ICMPv6
+use crate::eth::Mac6;
+use anyhow::{Result, bail};
+use std::net::Ipv6Addr;
+
+/// Every NDP message is sent with this hop limit, and received ones with
+/// any other came from off-link, so are dropped
+pub const HOP_LIMIT: u8 = 255;
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+/// A lifetime, in seconds, that never runs out
+pub const INFINITE_LIFETIME: u32 = u32::MAX;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_MTU: u8 = 5;
+
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+const FLAG_MANAGED: u8 = 0x80;
+const FLAG_OTHER_CONFIG: u8 = 0x40;
+const FLAG_ON_LINK: u8 = 0x80;
+const FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// The multicast group `address`'s owner listens on for solicitations:
+/// `ff02::1:ff00:0/104` plus the address's low 24 bits
+pub const fn solicited_node(address: Ipv6Addr) -> Ipv6Addr {
+    let low = address.to_bits() & 0xff_ffff;
+    Ipv6Addr::from_bits(0xff02_0000_0000_0000_0000_0001_ff00_0000 | low)
+}
+
+/// Whether `address` is in `ff02::1:ff00:0/104`, where
+/// [solicited_node] groups are
+pub const fn is_solicited_node(address: Ipv6Addr) -> bool {
+    solicited_node(address).to_bits() == address.to_bits()
+}
+
+/// An option trailing an NDP message
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NdpOption {
+    /// The MAC of the message's sender
+    SourceLinkLayerAddress(Mac6),
+    /// The MAC of the target address
+    TargetLinkLayerAddress(Mac6),
+    /// A prefix on the link, from a router
+    PrefixInformation(PrefixInformation),
+    /// The link's MTU, from a router
+    Mtu(u32),
+    /// An option we don't interpret, which receivers must ignore
+    Other { option_type: u8, data: Vec<u8> },
+}
+
+impl NdpOption {
+    /// Parse every option in `raw`
+    fn parse_all(mut raw: &[u8]) -> Result<Vec<Self>> {
+        let mut options = Vec::new();
+        while let [option_type, length, ..] = *raw {
+            // Lengths are in units of 8 bytes, type and length included
+            let length = usize::from(length) * 8;
+            if length == 0 || raw.len() < length {
+                bail!("NDP: bad option length");
+            }
+            let data = &raw[2..length];
+            options.push(match (option_type, <[u8; 6]>::try_from(data)) {
+                (OPTION_SOURCE_LINK_LAYER_ADDRESS, Ok(mac)) => {
+                    Self::SourceLinkLayerAddress(mac.into())
+                }
+                (OPTION_TARGET_LINK_LAYER_ADDRESS, Ok(mac)) => {
+                    Self::TargetLinkLayerAddress(mac.into())
+                }
+                (OPTION_PREFIX_INFORMATION, _) if data.len() == 30 => {
+                    Self::PrefixInformation(PrefixInformation::parse(data))
+                }
+                (OPTION_MTU, _) if data.len() == 6 => {
+                    Self::Mtu(u32::from_be_bytes([data[2], data[3], data[4], data[5]]))
+                }
+                _ => Self::Other {
+                    option_type,
+                    data: data.to_vec(),
+                },
+            });
+            raw = &raw[length..];
+        }
+        if !raw.is_empty() {
+            bail!("NDP: truncated option");
+        }
+        Ok(options)
+    }
+
+    fn encode(&self, raw: &mut Vec<u8>) {
+        let (option_type, data) = match self {
+            Self::SourceLinkLayerAddress(mac) => {
+                (OPTION_SOURCE_LINK_LAYER_ADDRESS, mac.as_bytes().to_vec())
+            }
+            Self::TargetLinkLayerAddress(mac) => {
+                (OPTION_TARGET_LINK_LAYER_ADDRESS, mac.as_bytes().to_vec())
+            }
+            Self::PrefixInformation(prefix) => (OPTION_PREFIX_INFORMATION, prefix.encode()),
+            Self::Mtu(mtu) => {
+                let mut data = vec![0, 0];
+                data.extend_from_slice(&mtu.to_be_bytes());
+                (OPTION_MTU, data)
+            }
+            Self::Other { option_type, data } => (*option_type, data.clone()),
+        };
+        let length = (2 + data.len()).div_ceil(8);
+        raw.push(option_type);
+        raw.push(length as u8);
+        raw.extend_from_slice(&data);
+        raw.resize(raw.len() + length * 8 - 2 - data.len(), 0);
+    }
+}
+
+fn encode_options(raw: &mut Vec<u8>, options: &[NdpOption]) {
+    for option in options {
+        option.encode(raw);
+    }
+}
+
+/// A prefix advertised by a router (RFC 4861 §4.6.2)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PrefixInformation {
+    pub prefix: Ipv6Addr,
+    pub prefix_length: u8,
+    /// Addresses in the prefix are on-link, so reachable directly
+    pub on_link: bool,
+    /// Hosts may form addresses in the prefix themselves (SLAAC)
+    pub autonomous: bool,
+    /// Seconds the prefix stays valid, or [INFINITE_LIFETIME]
+    pub valid_lifetime: u32,
+    /// Seconds addresses formed from it stay preferred for new connections
+    pub preferred_lifetime: u32,
+}
+
+impl PrefixInformation {
+    /// Parse the option's data, after its type and length
+    fn parse(data: &[u8]) -> Self {
+        let word =
+            |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let prefix: [u8; 16] = data[14..30].try_into().expect("length was checked");
+        Self {
+            prefix: prefix.into(),
+            prefix_length: data[0],
+            on_link: data[1] & FLAG_ON_LINK != 0,
+            autonomous: data[1] & FLAG_AUTONOMOUS != 0,
+            valid_lifetime: word(2),
+            preferred_lifetime: word(6),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.on_link {
+            flags |= FLAG_ON_LINK;
+        }
+        if self.autonomous {
+            flags |= FLAG_AUTONOMOUS;
+        }
+        let mut data = vec![self.prefix_length, flags];
+        data.extend_from_slice(&self.valid_lifetime.to_be_bytes());
+        data.extend_from_slice(&self.preferred_lifetime.to_be_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&self.prefix.octets());
+        data
+    }
+}
+
+/// Any routers here? Sent to [ALL_ROUTERS] so hosts needn't wait for the
+/// next periodic advertisement
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RouterSolicitation {
+    pub options: Vec<NdpOption>,
+}
+
+impl RouterSolicitation {
+    /// The host's MAC, if it gave it
+    pub fn source_mac(&self) -> Option<Mac6> {
+        self.options.iter().find_map(|option| match option {
+            NdpOption::SourceLinkLayerAddress(mac) => Some(*mac),
+            _ => None,
+        })
+    }
+
+    /// Parse everything after the ICMPv6 checksum
+    pub(super) fn parse(rest: &[u8]) -> Result<Self> {
+        let Some((_reserved, options)) = rest.split_first_chunk::<4>() else {
+            bail!("NDP: router solicitation too short");
+        };
+        Ok(Self {
+            options: NdpOption::parse_all(options)?,
+        })
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut raw = vec![0; 4];
+        encode_options(&mut raw, &self.options);
+        raw
+    }
+}
+
+/// A router announcing itself and the link's configuration
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RouterAdvertisement {
+    /// Hop limit hosts should send with, or 0 for no opinion
+    pub hop_limit: u8,
+    /// Addresses are available from DHCPv6
+    pub managed: bool,
+    /// Other configuration is available from DHCPv6
+    pub other_config: bool,
+    /// Seconds to use the sender as a default router, or 0 for not at all
+    pub router_lifetime: u16,
+    /// Milliseconds a neighbour stays reachable after confirmation, or 0
+    pub reachable_time: u32,
+    /// Milliseconds between solicitations, or 0
+    pub retransmit_timer: u32,
+    pub options: Vec<NdpOption>,
+}
+
+impl RouterAdvertisement {
+    /// Every prefix advertised
+    pub fn prefixes(&self) -> impl Iterator<Item = &PrefixInformation> {
+        self.options.iter().filter_map(|option| match option {
+            NdpOption::PrefixInformation(prefix) => Some(prefix),
+            _ => None,
+        })
+    }
+
+    /// The link's MTU, if advertised
+    pub fn mtu(&self) -> Option<u32> {
+        self.options.iter().find_map(|option| match option {
+            NdpOption::Mtu(mtu) => Some(*mtu),
+            _ => None,
+        })
+    }
+
+    /// The router's MAC, if it gave it
+    pub fn source_mac(&self) -> Option<Mac6> {
+        self.options.iter().find_map(|option| match option {
+            NdpOption::SourceLinkLayerAddress(mac) => Some(*mac),
+            _ => None,
+        })
+    }
+
+    /// Parse everything after the ICMPv6 checksum
+    pub(super) fn parse(rest: &[u8]) -> Result<Self> {
+        let Some((&[hop_limit, flags, a, b, c, d, e, f, g, h, i, j], options)) =
+            rest.split_first_chunk::<12>()
+        else {
+            bail!("NDP: router advertisement too short");
+        };
+        Ok(Self {
+            hop_limit,
+            managed: flags & FLAG_MANAGED != 0,
+            other_config: flags & FLAG_OTHER_CONFIG != 0,
+            router_lifetime: u16::from_be_bytes([a, b]),
+            reachable_time: u32::from_be_bytes([c, d, e, f]),
+            retransmit_timer: u32::from_be_bytes([g, h, i, j]),
+            options: NdpOption::parse_all(options)?,
+        })
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.managed {
+            flags |= FLAG_MANAGED;
+        }
+        if self.other_config {
+            flags |= FLAG_OTHER_CONFIG;
+        }
+        let mut raw = vec![self.hop_limit, flags];
+        raw.extend_from_slice(&self.router_lifetime.to_be_bytes());
+        raw.extend_from_slice(&self.reachable_time.to_be_bytes());
+        raw.extend_from_slice(&self.retransmit_timer.to_be_bytes());
+        encode_options(&mut raw, &self.options);
+        raw
+    }
+}
+
+/// Who has `target`? Sent to its solicited-node group to resolve it, or to
+/// it directly to check it's still there
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NeighbourSolicitation {
+    pub target: Ipv6Addr,
+    pub options: Vec<NdpOption>,
+}
+
+impl NeighbourSolicitation {
+    /// Ask for `target`'s MAC, giving ours so it can answer directly
+    pub fn new(target: Ipv6Addr, mac: Mac6) -> Self {
+        Self {
+            target,
+            options: vec![NdpOption::SourceLinkLayerAddress(mac)],
+        }
+    }
+
+    /// The sender's MAC, if it gave it
+    pub fn source_mac(&self) -> Option<Mac6> {
+        self.options.iter().find_map(|option| match option {
+            NdpOption::SourceLinkLayerAddress(mac) => Some(*mac),
+            _ => None,
+        })
+    }
+
+    /// Parse everything after the ICMPv6 checksum
+    pub(super) fn parse(rest: &[u8]) -> Result<Self> {
+        let Some((_reserved, rest)) = rest.split_first_chunk::<4>() else {
+            bail!("NDP: solicitation too short");
+        };
+        let Some((&target, options)) = rest.split_first_chunk::<16>() else {
+            bail!("NDP: solicitation too short");
+        };
+        Ok(Self {
+            target: target.into(),
+            options: NdpOption::parse_all(options)?,
+        })
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut raw = vec![0; 4];
+        raw.extend_from_slice(&self.target.octets());
+        encode_options(&mut raw, &self.options);
+        raw
+    }
+}
+
+/// `target` is at the MAC given, in answer to a solicitation or unprompted
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NeighbourAdvertisement {
+    /// The sender is a router
+    pub router: bool,
+    /// In answer to a solicitation, so proof the sender is reachable
+    pub solicited: bool,
+    /// Replace any MAC already cached for `target`
+    pub override_cache: bool,
+    pub target: Ipv6Addr,
+    pub options: Vec<NdpOption>,
+}
+
+impl NeighbourAdvertisement {
+    /// Answer a solicitation for `target`, which is ours, at `mac`
+    pub fn answer(target: Ipv6Addr, mac: Mac6) -> Self {
+        Self {
+            router: false,
+            solicited: true,
+            override_cache: true,
+            target,
+            options: vec![NdpOption::TargetLinkLayerAddress(mac)],
+        }
+    }
+
+    /// The target's MAC, if given
+    pub fn target_mac(&self) -> Option<Mac6> {
+        self.options.iter().find_map(|option| match option {
+            NdpOption::TargetLinkLayerAddress(mac) => Some(*mac),
+            _ => None,
+        })
+    }
+
+    /// Parse everything after the ICMPv6 checksum
+    pub(super) fn parse(rest: &[u8]) -> Result<Self> {
+        let Some((&[flags, ..], rest)) = rest.split_first_chunk::<4>() else {
+            bail!("NDP: advertisement too short");
+        };
+        let Some((&target, options)) = rest.split_first_chunk::<16>() else {
+            bail!("NDP: advertisement too short");
+        };
+        Ok(Self {
+            router: flags & FLAG_ROUTER != 0,
+            solicited: flags & FLAG_SOLICITED != 0,
+            override_cache: flags & FLAG_OVERRIDE != 0,
+            target: target.into(),
+            options: NdpOption::parse_all(options)?,
+        })
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.router, FLAG_ROUTER),
+            (self.solicited, FLAG_SOLICITED),
+            (self.override_cache, FLAG_OVERRIDE),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        let mut raw = vec![flags, 0, 0, 0];
+        raw.extend_from_slice(&self.target.octets());
+        encode_options(&mut raw, &self.options);
+        raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: Mac6 = Mac6::new([2, 0, 0, 0, 0, 1]);
+
+    #[test]
+    fn solicited_node_group() {
+        let address: Ipv6Addr = "2001:db8::2aa:ff:fe28:9c5a".parse().unwrap();
+        assert_eq!(
+            solicited_node(address),
+            "ff02::1:ff28:9c5a".parse::<Ipv6Addr>().unwrap()
+        );
+        assert!(Mac6::from_ipv6_multicast(solicited_node(address)).is_some());
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let target = "fe80::1".parse()?;
+        let solicitation = NeighbourSolicitation::new(target, MAC);
+        let raw = solicitation.encode();
+        assert_eq!(raw.len(), 4 + 16 + 8);
+        let parsed = NeighbourSolicitation::parse(&raw)?;
+        assert_eq!(parsed.source_mac(), Some(MAC));
+        assert_eq!(parsed, solicitation);
+
+        let mut advertisement = NeighbourAdvertisement::answer(target, MAC);
+        advertisement.options.push(NdpOption::Other {
+            option_type: 99,
+            data: vec![1; 6],
+        });
+        let raw = advertisement.encode();
+        assert_eq!(raw[0], FLAG_SOLICITED | FLAG_OVERRIDE);
+        let parsed = NeighbourAdvertisement::parse(&raw)?;
+        assert_eq!(parsed.target_mac(), Some(MAC));
+        assert_eq!(parsed, advertisement);
+        Ok(())
+    }
+
+    #[test]
+    fn router_advertisement() -> Result<()> {
+        let prefix = PrefixInformation {
+            prefix: "2001:db8:1::".parse()?,
+            prefix_length: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: INFINITE_LIFETIME,
+            preferred_lifetime: 604_800,
+        };
+        let advertisement = RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other_config: true,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options: vec![
+                NdpOption::SourceLinkLayerAddress(MAC),
+                NdpOption::Mtu(1500),
+                NdpOption::PrefixInformation(prefix),
+            ],
+        };
+        let raw = advertisement.encode();
+        assert_eq!(raw.len(), 12 + 8 + 8 + 32);
+        let parsed = RouterAdvertisement::parse(&raw)?;
+        assert_eq!(parsed, advertisement);
+        assert_eq!(parsed.prefixes().collect::<Vec<_>>(), [&prefix]);
+        assert_eq!(parsed.mtu(), Some(1500));
+        assert_eq!(parsed.source_mac(), Some(MAC));
+
+        let solicitation = RouterSolicitation {
+            options: vec![NdpOption::SourceLinkLayerAddress(MAC)],
+        };
+        assert_eq!(
+            RouterSolicitation::parse(&solicitation.encode())?,
+            solicitation
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bad_options() {
+        let mut raw = NeighbourSolicitation::new(Ipv6Addr::LOCALHOST, MAC).encode();
+        raw[21] = 0;
+        assert!(NeighbourSolicitation::parse(&raw).is_err());
+        raw[21] = 2;
+        assert!(NeighbourSolicitation::parse(&raw).is_err());
+    }
+}
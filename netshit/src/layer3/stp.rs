@@ -0,0 +1,187 @@
+use crate::eth::Mac6;
+use anyhow::{Result, bail};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PROTOCOL_ID: u16 = 0;
+
+const TYPE_CONFIG: u8 = 0x00;
+const TYPE_RST: u8 = 0x02;
+const TYPE_TOPOLOGY_CHANGE: u8 = 0x80;
+
+/// Flag: the topology is changing
+pub const FLAG_TOPOLOGY_CHANGE: u8 = 0x01;
+/// Flag: acknowledges a topology change notification
+pub const FLAG_TOPOLOGY_CHANGE_ACK: u8 = 0x80;
+
+/// A bridge (or root) identifier: priority, then MAC as a tie breaker
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct BridgeId {
+    pub priority: u16,
+    pub mac: Mac6,
+}
+
+impl BridgeId {
+    async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let priority = reader.read_u16().await?;
+        let mac = Mac6::from_reader(&mut reader).await?;
+        Ok(Self { priority, mac })
+    }
+
+    async fn onto_writer(&self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_u16(self.priority).await?;
+        writer.write_all(self.mac.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// A configuration BPDU, advertising the sender's view of the spanning tree
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ConfigBpdu {
+    /// 0 for STP, 2 for RSTP
+    pub version: u8,
+    pub flags: u8,
+    pub root_id: BridgeId,
+    pub root_path_cost: u32,
+    pub bridge_id: BridgeId,
+    pub port_id: u16,
+    pub message_age: Duration,
+    pub max_age: Duration,
+    pub hello_time: Duration,
+    pub forward_delay: Duration,
+}
+
+impl ConfigBpdu {
+    pub const fn topology_change(&self) -> bool {
+        self.flags & FLAG_TOPOLOGY_CHANGE != 0
+    }
+}
+
+/// An 802.1D bridge protocol data unit, as carried in LLC frames to SAP 0x42
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Bpdu {
+    /// Configuration BPDU (including RSTP BPDUs)
+    Config(ConfigBpdu),
+    /// Topology change notification
+    TopologyChange,
+}
+
+impl Bpdu {
+    /// Parse a BPDU from the data of an LLC packet
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        let protocol_id = reader.read_u16().await?;
+        if protocol_id != PROTOCOL_ID {
+            bail!("STP: bad protocol ID 0x{protocol_id:04x}");
+        }
+        let version = reader.read_u8().await?;
+        match reader.read_u8().await? {
+            TYPE_TOPOLOGY_CHANGE => Ok(Self::TopologyChange),
+            TYPE_CONFIG | TYPE_RST => Ok(Self::Config(ConfigBpdu {
+                version,
+                flags: reader.read_u8().await?,
+                root_id: BridgeId::from_reader(&mut reader).await?,
+                root_path_cost: reader.read_u32().await?,
+                bridge_id: BridgeId::from_reader(&mut reader).await?,
+                port_id: reader.read_u16().await?,
+                message_age: read_time(&mut reader).await?,
+                max_age: read_time(&mut reader).await?,
+                hello_time: read_time(&mut reader).await?,
+                forward_delay: read_time(&mut reader).await?,
+            })),
+            other => bail!("STP: unknown BPDU type 0x{other:02x}"),
+        }
+    }
+
+    /// Serialize a BPDU into a writer
+    pub async fn onto_writer(&self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_u16(PROTOCOL_ID).await?;
+        match self {
+            Self::TopologyChange => {
+                writer.write_u8(0).await?;
+                writer.write_u8(TYPE_TOPOLOGY_CHANGE).await?;
+            }
+            Self::Config(bpdu) => {
+                writer.write_u8(bpdu.version).await?;
+                let bpdu_type = if bpdu.version >= 2 {
+                    TYPE_RST
+                } else {
+                    TYPE_CONFIG
+                };
+                writer.write_u8(bpdu_type).await?;
+                writer.write_u8(bpdu.flags).await?;
+                bpdu.root_id.onto_writer(&mut writer).await?;
+                writer.write_u32(bpdu.root_path_cost).await?;
+                bpdu.bridge_id.onto_writer(&mut writer).await?;
+                writer.write_u16(bpdu.port_id).await?;
+                for time in [
+                    bpdu.message_age,
+                    bpdu.max_age,
+                    bpdu.hello_time,
+                    bpdu.forward_delay,
+                ] {
+                    write_time(&mut writer, time).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Timers are sent in units of 1/256 seconds
+async fn read_time(mut reader: impl AsyncRead + Unpin) -> Result<Duration> {
+    let ticks = reader.read_u16().await?;
+    Ok(Duration::from_secs(u64::from(ticks)) / 256)
+}
+
+async fn write_time(mut writer: impl AsyncWrite + Unpin, time: Duration) -> Result<()> {
+    let ticks = u16::try_from(time.as_millis() * 256 / 1000)?;
+    writer.write_u16(ticks).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::LlcPacket;
+
+    #[tokio::test]
+    async fn parse_config_bpdu() -> Result<()> {
+        let raw = [
+            0x42, 0x42, 0x03, // LLC
+            0x00, 0x00, 0x00, 0x00, 0x01, // protocol, version, type, flags
+            0x80, 0x00, 0x00, 0x1c, 0x0e, 0x87, 0x78, 0x00, // root ID
+            0x00, 0x00, 0x00, 0x04, // root path cost
+            0x80, 0x00, 0x00, 0x1c, 0x0e, 0x87, 0x85, 0x00, // bridge ID
+            0x80, 0x04, // port ID
+            0x01, 0x00, 0x14, 0x00, 0x02, 0x00, 0x0f, 0x00, // timers
+        ];
+        let llc = LlcPacket::from_reader(raw.as_slice()).await?;
+        assert!(llc.is_stp());
+        let bpdu = Bpdu::from_reader(llc.data.as_slice()).await?;
+        let Bpdu::Config(config) = &bpdu else {
+            panic!("Wrong BPDU type!");
+        };
+        assert!(config.topology_change());
+        assert_eq!(config.root_id.priority, 0x8000);
+        assert_eq!(config.root_id.mac.to_string(), "00:1C:0E:87:78:00");
+        assert!(config.root_id < config.bridge_id);
+        assert_eq!(config.root_path_cost, 4);
+        assert_eq!(config.port_id, 0x8004);
+        assert_eq!(config.message_age, Duration::from_secs(1));
+        assert_eq!(config.max_age, Duration::from_secs(20));
+        assert_eq!(config.forward_delay, Duration::from_secs(15));
+
+        let mut vec = Vec::new();
+        bpdu.onto_writer(&mut vec).await?;
+        assert_eq!(vec, llc.data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_topology_change() -> Result<()> {
+        let bpdu = Bpdu::from_reader([0, 0, 0, 0x80].as_slice()).await?;
+        assert_eq!(bpdu, Bpdu::TopologyChange);
+        assert!(Bpdu::from_reader([0, 1, 0, 0x80].as_slice()).await.is_err());
+        Ok(())
+    }
+}
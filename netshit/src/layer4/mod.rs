@@ -0,0 +1,130 @@
+//! Transport protocols, carried by IPv4 and IPv6 packets
+pub mod sctp;
+pub mod tcp;
+pub mod udp;
+pub mod udplite;
+use crate::checksum::ChecksumCapabilities;
+use crate::layer3::ipv6::Ipv6Packet;
+use crate::layer3::{IpProtocol, Ipv4Packet};
+use anyhow::Result;
+pub use sctp::SctpPacket;
+use std::net::{Ipv4Addr, Ipv6Addr};
+pub use tcp::TcpSegment;
+pub use udp::UdpDatagram;
+pub use udplite::UdpLiteDatagram;
+
+/// The addresses a transport checksum covers, besides the segment itself
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PseudoHeader {
+    V4 {
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    },
+    V6 {
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+    },
+}
+
+impl PseudoHeader {
+    /// The checksum of `data`, a `protocol` segment, pseudo-header included
+    pub fn checksum(&self, protocol: IpProtocol, data: &[u8]) -> [u8; 2] {
+        self.partial_checksum(protocol, data, data.len())
+    }
+
+    /// The checksum of the first `covered` bytes of `data`, and the
+    /// pseudo-header, which has the length of all of it, as UDP-Lite's is
+    pub fn partial_checksum(&self, protocol: IpProtocol, data: &[u8], covered: usize) -> [u8; 2] {
+        let mut checksum = internet_checksum::Checksum::new();
+        match *self {
+            Self::V4 {
+                source,
+                destination,
+            } => {
+                checksum.add_bytes(&source.octets());
+                checksum.add_bytes(&destination.octets());
+                checksum.add_bytes(&[0, protocol.into()]);
+                checksum.add_bytes(&(data.len() as u16).to_be_bytes());
+            }
+            Self::V6 {
+                source,
+                destination,
+            } => {
+                checksum.add_bytes(&source.octets());
+                checksum.add_bytes(&destination.octets());
+                checksum.add_bytes(&(data.len() as u32).to_be_bytes());
+                checksum.add_bytes(&[0, 0, 0, protocol.into()]);
+            }
+        }
+        checksum.add_bytes(&data[..covered]);
+        checksum.checksum()
+    }
+
+    /// Whether a zero checksum means there isn't one; only UDP over IPv4
+    /// may leave it out
+    pub const fn is_v4(&self) -> bool {
+        matches!(self, Self::V4 { .. })
+    }
+}
+
+impl From<&Ipv4Packet> for PseudoHeader {
+    fn from(packet: &Ipv4Packet) -> Self {
+        Self::V4 {
+            source: packet.source,
+            destination: packet.destination,
+        }
+    }
+}
+
+impl From<&Ipv6Packet> for PseudoHeader {
+    fn from(packet: &Ipv6Packet) -> Self {
+        Self::V6 {
+            source: packet.source,
+            destination: packet.destination,
+        }
+    }
+}
+
+/// What an IP packet carries, parsed if it's a protocol we know
+#[derive(Clone, Debug, PartialEq)]
+pub enum Layer4Packet {
+    Tcp(TcpSegment),
+    Udp(UdpDatagram),
+    UdpLite(UdpLiteDatagram),
+    Sctp(SctpPacket),
+    /// Payload of a protocol not parsed here, e.g. ICMP, which the stack
+    /// handles itself
+    Other(Vec<u8>),
+}
+
+impl Layer4Packet {
+    /// Parse `data`, a `protocol` payload between the addresses in `pseudo`
+    pub async fn parse(protocol: IpProtocol, data: &[u8], pseudo: &PseudoHeader) -> Result<Self> {
+        Self::parse_with_checksums(protocol, data, pseudo, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [Layer4Packet::parse], but verifying only the checksums
+    /// `checksums` says to
+    pub async fn parse_with_checksums(
+        protocol: IpProtocol,
+        data: &[u8],
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
+        Ok(match protocol {
+            IpProtocol::Tcp => {
+                Self::Tcp(TcpSegment::from_reader_with_checksums(data, pseudo, checksums).await?)
+            }
+            IpProtocol::Udp => {
+                Self::Udp(UdpDatagram::from_reader_with_checksums(data, pseudo, checksums).await?)
+            }
+            IpProtocol::UdpLite => Self::UdpLite(
+                UdpLiteDatagram::from_reader_with_checksums(data, pseudo, checksums).await?,
+            ),
+            IpProtocol::Sctp => {
+                Self::Sctp(SctpPacket::from_reader_with_checksums(data, checksums).await?)
+            }
+            _ => Self::Other(data.to_vec()),
+        })
+    }
+}
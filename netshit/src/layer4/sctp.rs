@@ -0,0 +1,521 @@
+//! Stream Control Transmission Protocol packets (RFC 9260): a common header,
+//! then a list of chunks
+//!
+//! Only their structure's parsed, so they can be looked at; nothing here
+//! keeps an association.
+use crate::checksum::ChecksumCapabilities;
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const COMMON_HEADER_LENGTH: usize = 12;
+const CHUNK_HEADER_LENGTH: usize = 4;
+const PARAMETER_HEADER_LENGTH: usize = 4;
+
+const CHUNK_DATA: u8 = 0;
+const CHUNK_INIT: u8 = 1;
+const CHUNK_SACK: u8 = 3;
+const CHUNK_HEARTBEAT: u8 = 4;
+
+const PARAMETER_HEARTBEAT_INFO: u16 = 1;
+
+const DATA_FLAG_END: u8 = 0x01;
+const DATA_FLAG_BEGINNING: u8 = 0x02;
+const DATA_FLAG_UNORDERED: u8 = 0x04;
+const DATA_FLAG_IMMEDIATE: u8 = 0x08;
+
+/// Covers the whole packet, with the checksum field zeroed (RFC 9260
+/// Appendix A)
+const CHECKSUM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// A parameter of an INIT chunk, kept as is
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SctpParameter {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+impl SctpParameter {
+    /// Parse every parameter in `raw`; each is padded to 4 bytes, bar
+    /// perhaps the last
+    fn parse_all(mut raw: &[u8]) -> Result<Vec<Self>> {
+        let mut parameters = Vec::new();
+        while let Some(&[a, b, c, d]) = raw.first_chunk::<PARAMETER_HEADER_LENGTH>() {
+            let length = usize::from(u16::from_be_bytes([c, d]));
+            if length < PARAMETER_HEADER_LENGTH || raw.len() < length {
+                bail!("SCTP: bad parameter length {length}");
+            }
+            parameters.push(Self {
+                kind: u16::from_be_bytes([a, b]),
+                value: raw[PARAMETER_HEADER_LENGTH..length].to_vec(),
+            });
+            raw = &raw[padded(length).min(raw.len())..];
+        }
+        if !raw.is_empty() {
+            bail!("SCTP: {} stray bytes after parameters", raw.len());
+        }
+        Ok(parameters)
+    }
+
+    /// Append the parameter to `raw`, padded to 4 bytes
+    fn write(&self, raw: &mut Vec<u8>) -> Result<()> {
+        raw.extend_from_slice(&self.kind.to_be_bytes());
+        let length = u16::try_from(PARAMETER_HEADER_LENGTH + self.value.len())?;
+        raw.extend_from_slice(&length.to_be_bytes());
+        raw.extend_from_slice(&self.value);
+        raw.resize(padded(raw.len()), 0);
+        Ok(())
+    }
+}
+
+/// Flags of a DATA chunk
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct DataFlags {
+    /// Delivered as soon as it's received, out of order
+    pub unordered: bool,
+    /// The first fragment of a message
+    pub beginning: bool,
+    /// The last fragment of a message
+    pub end: bool,
+    /// The receiver should acknowledge it at once (RFC 7053)
+    pub immediate: bool,
+}
+
+impl From<u8> for DataFlags {
+    fn from(flags: u8) -> Self {
+        Self {
+            unordered: flags & DATA_FLAG_UNORDERED != 0,
+            beginning: flags & DATA_FLAG_BEGINNING != 0,
+            end: flags & DATA_FLAG_END != 0,
+            immediate: flags & DATA_FLAG_IMMEDIATE != 0,
+        }
+    }
+}
+
+impl From<DataFlags> for u8 {
+    fn from(flags: DataFlags) -> Self {
+        [
+            (flags.unordered, DATA_FLAG_UNORDERED),
+            (flags.beginning, DATA_FLAG_BEGINNING),
+            (flags.end, DATA_FLAG_END),
+            (flags.immediate, DATA_FLAG_IMMEDIATE),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+}
+
+/// A chunk of an SCTP packet
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SctpChunk {
+    /// A message, or a fragment of one
+    Data {
+        flags: DataFlags,
+        /// Transmission sequence number, counting chunks across streams
+        tsn: u32,
+        stream: u16,
+        /// The message's sequence number within its stream
+        stream_sequence: u16,
+        /// Payload protocol identifier, for the application's use
+        protocol: u32,
+        data: Vec<u8>,
+    },
+    /// Opens an association
+    Init {
+        /// Tag the peer puts in the packets it sends us
+        initiate_tag: u32,
+        /// Advertised receiver window credit
+        window: u32,
+        outbound_streams: u16,
+        inbound_streams: u16,
+        initial_tsn: u32,
+        parameters: Vec<SctpParameter>,
+    },
+    /// Selective acknowledgement of DATA chunks
+    Sack {
+        /// Every TSN up to this one's been received
+        cumulative_tsn: u32,
+        window: u32,
+        /// Blocks received past the cumulative TSN, as offsets from it of
+        /// their first and last TSNs
+        gaps: Vec<(u16, u16)>,
+        /// TSNs received more than once
+        duplicates: Vec<u32>,
+    },
+    /// Checks the peer's reachable; it sends `info` back as is
+    Heartbeat { info: Vec<u8> },
+    /// A chunk we don't interpret, kept as is
+    Other { kind: u8, flags: u8, value: Vec<u8> },
+}
+
+impl SctpChunk {
+    /// Parse every chunk in `raw`, each padded to 4 bytes
+    fn parse_all(mut raw: &[u8]) -> Result<Vec<Self>> {
+        let mut chunks = Vec::new();
+        while !raw.is_empty() {
+            let Some(&[kind, flags, c, d]) = raw.first_chunk::<CHUNK_HEADER_LENGTH>() else {
+                bail!("SCTP: truncated chunk header");
+            };
+            // Lengths include the header, but not the padding
+            let length = usize::from(u16::from_be_bytes([c, d]));
+            if length < CHUNK_HEADER_LENGTH || raw.len() < length {
+                bail!("SCTP: bad length {length} for chunk {kind}");
+            }
+            chunks.push(Self::parse(kind, flags, &raw[CHUNK_HEADER_LENGTH..length])?);
+            raw = &raw[padded(length).min(raw.len())..];
+        }
+        Ok(chunks)
+    }
+
+    fn parse(kind: u8, flags: u8, value: &[u8]) -> Result<Self> {
+        let u16_at = |value: &[u8], at: usize| u16::from_be_bytes([value[at], value[at + 1]]);
+        let u32_at = |value: &[u8], at: usize| {
+            u32::from_be_bytes(value[at..at + 4].try_into().expect("4 bytes"))
+        };
+        Ok(match kind {
+            CHUNK_DATA => {
+                let Some((header, data)) = value.split_first_chunk::<12>() else {
+                    bail!("SCTP: DATA chunk too short");
+                };
+                Self::Data {
+                    flags: flags.into(),
+                    tsn: u32_at(header, 0),
+                    stream: u16_at(header, 4),
+                    stream_sequence: u16_at(header, 6),
+                    protocol: u32_at(header, 8),
+                    data: data.to_vec(),
+                }
+            }
+            CHUNK_INIT => {
+                let Some((header, parameters)) = value.split_first_chunk::<16>() else {
+                    bail!("SCTP: INIT chunk too short");
+                };
+                Self::Init {
+                    initiate_tag: u32_at(header, 0),
+                    window: u32_at(header, 4),
+                    outbound_streams: u16_at(header, 8),
+                    inbound_streams: u16_at(header, 10),
+                    initial_tsn: u32_at(header, 12),
+                    parameters: SctpParameter::parse_all(parameters)?,
+                }
+            }
+            CHUNK_SACK => {
+                let Some((header, blocks)) = value.split_first_chunk::<12>() else {
+                    bail!("SCTP: SACK chunk too short");
+                };
+                let (gaps, duplicates) = (u16_at(header, 8), u16_at(header, 10));
+                let (gaps, duplicates) = (usize::from(gaps), usize::from(duplicates));
+                if blocks.len() != (gaps + duplicates) * 4 {
+                    bail!("SCTP: SACK chunk has {} bytes of blocks", blocks.len());
+                }
+                let (gap_blocks, duplicate_tsns) = blocks.split_at(gaps * 4);
+                Self::Sack {
+                    cumulative_tsn: u32_at(header, 0),
+                    window: u32_at(header, 4),
+                    gaps: gap_blocks
+                        .chunks_exact(4)
+                        .map(|block| (u16_at(block, 0), u16_at(block, 2)))
+                        .collect(),
+                    duplicates: duplicate_tsns
+                        .chunks_exact(4)
+                        .map(|tsn| u32_at(tsn, 0))
+                        .collect(),
+                }
+            }
+            CHUNK_HEARTBEAT => match SctpParameter::parse_all(value)?.as_mut_slice() {
+                [info] if info.kind == PARAMETER_HEARTBEAT_INFO => Self::Heartbeat {
+                    info: std::mem::take(&mut info.value),
+                },
+                _ => bail!("SCTP: HEARTBEAT chunk without its info"),
+            },
+            kind => Self::Other {
+                kind,
+                flags,
+                value: value.to_vec(),
+            },
+        })
+    }
+
+    /// Append the chunk to `raw`, padded to 4 bytes
+    fn write(&self, raw: &mut Vec<u8>) -> Result<()> {
+        let mut value = Vec::new();
+        let (kind, flags) = match self {
+            Self::Data {
+                flags,
+                tsn,
+                stream,
+                stream_sequence,
+                protocol,
+                data,
+            } => {
+                value.extend_from_slice(&tsn.to_be_bytes());
+                value.extend_from_slice(&stream.to_be_bytes());
+                value.extend_from_slice(&stream_sequence.to_be_bytes());
+                value.extend_from_slice(&protocol.to_be_bytes());
+                value.extend_from_slice(data);
+                (CHUNK_DATA, u8::from(*flags))
+            }
+            Self::Init {
+                initiate_tag,
+                window,
+                outbound_streams,
+                inbound_streams,
+                initial_tsn,
+                parameters,
+            } => {
+                value.extend_from_slice(&initiate_tag.to_be_bytes());
+                value.extend_from_slice(&window.to_be_bytes());
+                value.extend_from_slice(&outbound_streams.to_be_bytes());
+                value.extend_from_slice(&inbound_streams.to_be_bytes());
+                value.extend_from_slice(&initial_tsn.to_be_bytes());
+                for parameter in parameters {
+                    parameter.write(&mut value)?;
+                }
+                (CHUNK_INIT, 0)
+            }
+            Self::Sack {
+                cumulative_tsn,
+                window,
+                gaps,
+                duplicates,
+            } => {
+                value.extend_from_slice(&cumulative_tsn.to_be_bytes());
+                value.extend_from_slice(&window.to_be_bytes());
+                value.extend_from_slice(&u16::try_from(gaps.len())?.to_be_bytes());
+                value.extend_from_slice(&u16::try_from(duplicates.len())?.to_be_bytes());
+                for (start, end) in gaps {
+                    value.extend_from_slice(&start.to_be_bytes());
+                    value.extend_from_slice(&end.to_be_bytes());
+                }
+                for tsn in duplicates {
+                    value.extend_from_slice(&tsn.to_be_bytes());
+                }
+                (CHUNK_SACK, 0)
+            }
+            Self::Heartbeat { info } => {
+                let info = SctpParameter {
+                    kind: PARAMETER_HEARTBEAT_INFO,
+                    value: info.clone(),
+                };
+                info.write(&mut value)?;
+                (CHUNK_HEARTBEAT, 0)
+            }
+            Self::Other {
+                kind,
+                flags,
+                value: other,
+            } => {
+                value.extend_from_slice(other);
+                (*kind, *flags)
+            }
+        };
+        // The last parameter's padding isn't counted, being the chunk's
+        let padding = match self {
+            Self::Init { parameters, .. } => parameters
+                .last()
+                .map_or(0, |last| padded(last.value.len()) - last.value.len()),
+            Self::Heartbeat { info } => padded(info.len()) - info.len(),
+            _ => 0,
+        };
+        let length = u16::try_from(CHUNK_HEADER_LENGTH + value.len() - padding)?;
+        raw.push(kind);
+        raw.push(flags);
+        raw.extend_from_slice(&length.to_be_bytes());
+        raw.extend_from_slice(&value);
+        raw.resize(padded(raw.len()), 0);
+        Ok(())
+    }
+}
+
+/// A parsed SCTP packet
+///
+/// The checksum is checked when it's parsed, and filled in when it's
+/// written.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SctpPacket {
+    pub source_port: u16,
+    pub destination_port: u16,
+    /// Tells the receiver the packet's from its peer in the association
+    pub verification_tag: u32,
+    pub chunks: Vec<SctpChunk>,
+}
+
+impl SctpPacket {
+    pub const fn new(source_port: u16, destination_port: u16, verification_tag: u32) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            verification_tag,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Parse a packet, verifying its checksum
+    ///
+    /// Unlike TCP's and UDP's, the checksum doesn't cover the addresses.
+    pub async fn from_reader(reader: impl AsyncRead + Unpin) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [SctpPacket::from_reader], but verifying the checksum only if
+    /// `checksums` says to
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        let Some(&[a, b, c, d, e, f, g, h, i, j, k, l]) = raw.first_chunk::<COMMON_HEADER_LENGTH>()
+        else {
+            bail!("SCTP: packet too short");
+        };
+        if checksums.sctp.rx() {
+            raw[8..12].copy_from_slice(&[0; 4]);
+            if CHECKSUM.checksum(&raw).to_le_bytes() != [i, j, k, l] {
+                bail!("SCTP: invalid checksum");
+            }
+        }
+        Ok(Self {
+            source_port: u16::from_be_bytes([a, b]),
+            destination_port: u16::from_be_bytes([c, d]),
+            verification_tag: u32::from_be_bytes([e, f, g, h]),
+            chunks: SctpChunk::parse_all(&raw[COMMON_HEADER_LENGTH..])?,
+        })
+    }
+
+    /// Serialize a packet into a writer
+    pub async fn onto_writer(&mut self, mut writer: impl AsyncWrite + Unpin) -> Result<()> {
+        writer.write_all(&self.to_bytes()?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IP payload
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.to_bytes_with_checksums(&ChecksumCapabilities::default())
+    }
+
+    /// Like [SctpPacket::to_bytes], but leaving the checksum zero unless
+    /// `checksums` says to compute it
+    pub fn to_bytes_with_checksums(&self, checksums: &ChecksumCapabilities) -> Result<Vec<u8>> {
+        let mut raw = Vec::with_capacity(COMMON_HEADER_LENGTH);
+        raw.extend_from_slice(&self.source_port.to_be_bytes());
+        raw.extend_from_slice(&self.destination_port.to_be_bytes());
+        raw.extend_from_slice(&self.verification_tag.to_be_bytes());
+        raw.extend_from_slice(&[0; 4]);
+        for chunk in &self.chunks {
+            chunk.write(&mut raw)?;
+        }
+        if checksums.sctp.tx() {
+            // Sent least significant byte first
+            let checksum = CHECKSUM.checksum(&raw);
+            raw[8..12].copy_from_slice(&checksum.to_le_bytes());
+        }
+        Ok(raw)
+    }
+}
+
+/// `length`, rounded up to a multiple of 4
+const fn padded(length: usize) -> usize {
+    length.next_multiple_of(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init() -> SctpPacket {
+        SctpPacket {
+            chunks: vec![SctpChunk::Init {
+                initiate_tag: 0x1234_5678,
+                window: 106_496,
+                outbound_streams: 10,
+                inbound_streams: 65535,
+                initial_tsn: 1,
+                parameters: vec![
+                    // Supported address types: IPv4, and an odd length
+                    SctpParameter {
+                        kind: 12,
+                        value: vec![0, 5],
+                    },
+                    SctpParameter {
+                        kind: 0xc000,
+                        value: vec![1, 2, 3],
+                    },
+                ],
+            }],
+            ..SctpPacket::new(5000, 36412, 0)
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let mut packet = init();
+        let mut raw = Vec::new();
+        packet.onto_writer(&mut raw).await?;
+        // 16 bytes of INIT, 8 of one parameter and 7 of the other, unpadded
+        assert_eq!(raw[12..16], [CHUNK_INIT, 0, 0, 4 + 16 + 8 + 7]);
+        assert_eq!(raw.len(), COMMON_HEADER_LENGTH + 36);
+        assert_eq!(SctpPacket::from_reader(raw.as_slice()).await?, packet);
+
+        let data = SctpPacket {
+            chunks: vec![
+                SctpChunk::Sack {
+                    cumulative_tsn: 100,
+                    window: 65535,
+                    gaps: vec![(2, 3), (5, 5)],
+                    duplicates: vec![99],
+                },
+                SctpChunk::Data {
+                    flags: DataFlags {
+                        beginning: true,
+                        end: true,
+                        ..DataFlags::default()
+                    },
+                    tsn: 7,
+                    stream: 1,
+                    stream_sequence: 0,
+                    protocol: 46,
+                    data: b"hello".to_vec(),
+                },
+                SctpChunk::Heartbeat {
+                    info: b"now".to_vec(),
+                },
+                // A SHUTDOWN
+                SctpChunk::Other {
+                    kind: 7,
+                    flags: 0,
+                    value: vec![0, 0, 0, 7],
+                },
+            ],
+            ..SctpPacket::new(36412, 5000, 0x1234_5678)
+        };
+        let raw = data.to_bytes()?;
+        assert_eq!(raw[40], CHUNK_DATA);
+        assert_eq!(raw[41], DATA_FLAG_BEGINNING | DATA_FLAG_END);
+        assert_eq!(SctpPacket::from_reader(raw.as_slice()).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed() -> Result<()> {
+        let raw = init().to_bytes()?;
+        let mut corrupt = raw.clone();
+        corrupt[20] ^= 1;
+        assert!(SctpPacket::from_reader(corrupt.as_slice()).await.is_err());
+        assert!(SctpPacket::from_reader(&raw[..11]).await.is_err());
+
+        // A chunk running past the packet, and a SACK short of its blocks
+        let bad = [CHUNK_DATA, 0, 0, 40, 0, 0, 0, 0];
+        assert!(SctpChunk::parse_all(&bad).is_err());
+        let mut sack = vec![CHUNK_SACK, 0, 0, 16];
+        sack.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0, 1, 0, 0]);
+        assert!(SctpChunk::parse_all(&sack).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn checksum() {
+        // RFC 3720 B.4's check value for CRC-32C
+        assert_eq!(CHECKSUM.checksum(&[0; 32]), 0x8a91_36aa);
+    }
+}
@@ -0,0 +1,469 @@
+//! Transmission Control Protocol segments (RFC 9293), and the options
+//! connections negotiate with (RFC 7323, RFC 2018, RFC 7413)
+use super::PseudoHeader;
+use crate::checksum::ChecksumCapabilities;
+use crate::layer3::IpProtocol;
+use anyhow::{Result, bail};
+use std::ops::RangeInclusive;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MIN_HEADER_LENGTH: usize = 20;
+/// Room for options, after the fixed header
+pub const MAX_OPTIONS_LENGTH: usize = 40;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_SACK: u8 = 5;
+const OPTION_TIMESTAMPS: u8 = 8;
+const OPTION_FAST_OPEN: u8 = 34;
+
+/// Lengths a Fast Open cookie may have (RFC 7413 §4.1.1)
+pub const FAST_OPEN_COOKIE_LENGTHS: RangeInclusive<usize> = 4..=16;
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+const FLAG_URG: u8 = 0x20;
+const FLAG_ECE: u8 = 0x40;
+const FLAG_CWR: u8 = 0x80;
+
+/// Control bits, besides ACK, which is implied by an acknowledgement number
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct TcpFlags {
+    /// No more data from the sender
+    pub fin: bool,
+    /// Synchronize sequence numbers, opening a connection
+    pub syn: bool,
+    /// Reset the connection
+    pub rst: bool,
+    /// Push buffered data to the application
+    pub psh: bool,
+    /// The urgent pointer is significant
+    pub urg: bool,
+    /// ECN echo
+    pub ece: bool,
+    /// Congestion window reduced
+    pub cwr: bool,
+}
+
+/// A TCP option
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TcpOption {
+    /// Largest segment the sender can receive; only on SYNs
+    MaxSegmentSize(u16),
+    /// How far the sender shifts the windows it advertises; only on SYNs
+    WindowScale(u8),
+    /// The sender understands selective acknowledgements; only on SYNs
+    SackPermitted,
+    /// Blocks received beyond the acknowledgement number, as the first
+    /// sequence number of each and the one after it
+    Sack(Vec<(u32, u32)>),
+    /// The sender's clock, and the latest value it's seen from us
+    Timestamps { value: u32, echo_reply: u32 },
+    /// A Fast Open cookie, or a request for one if it's empty; only on SYNs
+    FastOpen(Vec<u8>),
+    /// An option we don't interpret, kept as is
+    Other { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// Parse every option in `raw`, up to the end of the header or an End of
+    /// Option List
+    fn parse_all(mut raw: &[u8]) -> Result<Vec<Self>> {
+        let mut options = Vec::new();
+        while let Some((&kind, rest)) = raw.split_first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => {
+                    raw = rest;
+                    continue;
+                }
+                _ => {}
+            }
+            // Lengths include the kind and length bytes
+            let length = usize::from(rest.first().copied().unwrap_or(0));
+            if length < 2 || raw.len() < length {
+                bail!("TCP: bad length for option {kind}");
+            }
+            let data = &raw[2..length];
+            options.push(match (kind, data) {
+                (OPTION_MSS, &[a, b]) => Self::MaxSegmentSize(u16::from_be_bytes([a, b])),
+                (OPTION_WINDOW_SCALE, &[shift]) => Self::WindowScale(shift),
+                (OPTION_SACK_PERMITTED, []) => Self::SackPermitted,
+                (OPTION_SACK, blocks) if !blocks.is_empty() && blocks.len() % 8 == 0 => Self::Sack(
+                    blocks
+                        .chunks_exact(8)
+                        .map(|block| {
+                            let (left, right) = block.split_at(4);
+                            (
+                                u32::from_be_bytes(left.try_into().expect("4 bytes")),
+                                u32::from_be_bytes(right.try_into().expect("4 bytes")),
+                            )
+                        })
+                        .collect(),
+                ),
+                (OPTION_TIMESTAMPS, &[a, b, c, d, e, f, g, h]) => Self::Timestamps {
+                    value: u32::from_be_bytes([a, b, c, d]),
+                    echo_reply: u32::from_be_bytes([e, f, g, h]),
+                },
+                (OPTION_FAST_OPEN, cookie)
+                    if cookie.is_empty() || FAST_OPEN_COOKIE_LENGTHS.contains(&cookie.len()) =>
+                {
+                    Self::FastOpen(cookie.to_vec())
+                }
+                (
+                    OPTION_MSS
+                    | OPTION_WINDOW_SCALE
+                    | OPTION_SACK_PERMITTED
+                    | OPTION_SACK
+                    | OPTION_TIMESTAMPS
+                    | OPTION_FAST_OPEN,
+                    _,
+                ) => bail!("TCP: bad length for option {kind}"),
+                (kind, data) => Self::Other {
+                    kind,
+                    data: data.to_vec(),
+                },
+            });
+            raw = &raw[length..];
+        }
+        Ok(options)
+    }
+
+    /// Append the option to `raw`
+    fn write(&self, raw: &mut Vec<u8>) -> Result<()> {
+        let (kind, data) = match self {
+            Self::MaxSegmentSize(mss) => (OPTION_MSS, mss.to_be_bytes().to_vec()),
+            Self::WindowScale(shift) => (OPTION_WINDOW_SCALE, vec![*shift]),
+            Self::SackPermitted => (OPTION_SACK_PERMITTED, Vec::new()),
+            Self::Sack(blocks) => (
+                OPTION_SACK,
+                blocks
+                    .iter()
+                    .flat_map(|(left, right)| [left.to_be_bytes(), right.to_be_bytes()])
+                    .flatten()
+                    .collect(),
+            ),
+            Self::Timestamps { value, echo_reply } => (
+                OPTION_TIMESTAMPS,
+                [value.to_be_bytes(), echo_reply.to_be_bytes()].concat(),
+            ),
+            Self::FastOpen(cookie) => (OPTION_FAST_OPEN, cookie.clone()),
+            Self::Other { kind, data } => (*kind, data.clone()),
+        };
+        if matches!(kind, OPTION_END | OPTION_NOP) {
+            bail!("TCP: option {kind} has no length");
+        }
+        raw.push(kind);
+        raw.push(u8::try_from(data.len() + 2)?);
+        raw.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// A parsed TCP segment
+///
+/// The header length and checksum are checked when it's parsed, and filled
+/// in when it's written.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TcpSegment {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence: u32,
+    /// Next sequence number the sender expects, if ACK is set
+    pub acknowledgement: Option<u32>,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub urgent_pointer: u16,
+    pub options: Vec<TcpOption>,
+    pub data: Vec<u8>,
+}
+
+impl TcpSegment {
+    /// A segment with no flags, options or data
+    pub const fn new(source_port: u16, destination_port: u16, sequence: u32) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            sequence,
+            acknowledgement: None,
+            flags: TcpFlags {
+                fin: false,
+                syn: false,
+                rst: false,
+                psh: false,
+                urg: false,
+                ece: false,
+                cwr: false,
+            },
+            window: 0,
+            urgent_pointer: 0,
+            options: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Sequence numbers the segment takes up: its data, and SYN and FIN
+    /// count one each
+    pub fn sequence_length(&self) -> u32 {
+        let length = u32::try_from(self.data.len()).unwrap_or(u32::MAX);
+        length + u32::from(self.flags.syn) + u32::from(self.flags.fin)
+    }
+
+    /// Parse a segment sent between the addresses in `pseudo`, verifying its
+    /// checksum
+    pub async fn from_reader(
+        reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, pseudo, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [TcpSegment::from_reader], but verifying the checksum only if
+    /// `checksums` says to
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        let Some(header) = raw.first_chunk::<MIN_HEADER_LENGTH>() else {
+            bail!("TCP: segment too short");
+        };
+        let word = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+        let long = |at: usize| {
+            u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+        let header_length = usize::from(header[12] >> 4) * 4;
+        if header_length < MIN_HEADER_LENGTH || header_length > raw.len() {
+            bail!("TCP: bad header length {header_length}");
+        }
+        if checksums.tcp.rx() && pseudo.checksum(IpProtocol::Tcp, &raw) != [0, 0] {
+            bail!("TCP: invalid checksum");
+        }
+        let flags = header[13];
+        Ok(Self {
+            source_port: word(0),
+            destination_port: word(2),
+            sequence: long(4),
+            acknowledgement: (flags & FLAG_ACK != 0).then(|| long(8)),
+            flags: TcpFlags {
+                fin: flags & FLAG_FIN != 0,
+                syn: flags & FLAG_SYN != 0,
+                rst: flags & FLAG_RST != 0,
+                psh: flags & FLAG_PSH != 0,
+                urg: flags & FLAG_URG != 0,
+                ece: flags & FLAG_ECE != 0,
+                cwr: flags & FLAG_CWR != 0,
+            },
+            window: word(14),
+            urgent_pointer: word(18),
+            options: TcpOption::parse_all(&raw[MIN_HEADER_LENGTH..header_length])?,
+            data: raw.split_off(header_length),
+        })
+    }
+
+    /// Serialize a segment sent between the addresses in `pseudo` into a
+    /// writer
+    pub async fn onto_writer(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<()> {
+        writer.write_all(&self.to_bytes(pseudo)?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IP payload
+    pub fn to_bytes(&self, pseudo: &PseudoHeader) -> Result<Vec<u8>> {
+        self.to_bytes_with_checksums(pseudo, &ChecksumCapabilities::default())
+    }
+
+    /// Like [TcpSegment::to_bytes], but leaving the checksum zero unless
+    /// `checksums` says to compute it
+    pub fn to_bytes_with_checksums(
+        &self,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Vec<u8>> {
+        let mut options = Vec::new();
+        for option in &self.options {
+            option.write(&mut options)?;
+        }
+        // Padded with End of Option List to a whole number of words
+        options.resize(options.len().next_multiple_of(4), OPTION_END);
+        if options.len() > MAX_OPTIONS_LENGTH {
+            bail!("TCP: {} bytes of options is too many", options.len());
+        }
+
+        let flags = [
+            (self.flags.fin, FLAG_FIN),
+            (self.flags.syn, FLAG_SYN),
+            (self.flags.rst, FLAG_RST),
+            (self.flags.psh, FLAG_PSH),
+            (self.acknowledgement.is_some(), FLAG_ACK),
+            (self.flags.urg, FLAG_URG),
+            (self.flags.ece, FLAG_ECE),
+            (self.flags.cwr, FLAG_CWR),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        let header_length = MIN_HEADER_LENGTH + options.len();
+
+        let mut raw = Vec::with_capacity(header_length + self.data.len());
+        raw.extend_from_slice(&self.source_port.to_be_bytes());
+        raw.extend_from_slice(&self.destination_port.to_be_bytes());
+        raw.extend_from_slice(&self.sequence.to_be_bytes());
+        raw.extend_from_slice(&self.acknowledgement.unwrap_or(0).to_be_bytes());
+        // Header length in words, in the top four bits
+        raw.push(u8::try_from(header_length / 4)? << 4);
+        raw.push(flags);
+        raw.extend_from_slice(&self.window.to_be_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw.extend_from_slice(&self.urgent_pointer.to_be_bytes());
+        raw.extend_from_slice(&options);
+        raw.extend_from_slice(&self.data);
+        if checksums.tcp.tx() {
+            let checksum = pseudo.checksum(IpProtocol::Tcp, &raw);
+            raw[16..18].copy_from_slice(&checksum);
+        }
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const V4: PseudoHeader = PseudoHeader::V4 {
+        source: Ipv4Addr::new(10, 0, 0, 1),
+        destination: Ipv4Addr::new(10, 0, 0, 2),
+    };
+
+    fn syn() -> TcpSegment {
+        TcpSegment {
+            flags: TcpFlags {
+                syn: true,
+                ..TcpFlags::default()
+            },
+            window: 65535,
+            options: vec![
+                TcpOption::MaxSegmentSize(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamps {
+                    value: 1,
+                    echo_reply: 0,
+                },
+                TcpOption::WindowScale(7),
+            ],
+            ..TcpSegment::new(49152, 80, 0x1234_5678)
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let mut segment = syn();
+        let mut raw = Vec::new();
+        segment.onto_writer(&mut raw, &V4).await?;
+        // 4 + 2 + 10 + 3 bytes of options, padded to 20
+        assert_eq!(raw.len(), MIN_HEADER_LENGTH + 20);
+        assert_eq!(TcpSegment::from_reader(raw.as_slice(), &V4).await?, segment);
+
+        let reply = TcpSegment {
+            acknowledgement: Some(0x1234_5679),
+            flags: TcpFlags {
+                psh: true,
+                ..TcpFlags::default()
+            },
+            options: vec![TcpOption::Sack(vec![(10, 20), (30, 40)])],
+            data: b"hello".to_vec(),
+            ..TcpSegment::new(80, 49152, 7)
+        };
+        let raw = reply.to_bytes(&V4)?;
+        assert_eq!(raw[13], FLAG_PSH | FLAG_ACK);
+        assert_eq!(TcpSegment::from_reader(raw.as_slice(), &V4).await?, reply);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checksum_capabilities() -> Result<()> {
+        use crate::checksum::Checksum;
+
+        let segment = syn();
+        let skipped = ChecksumCapabilities::default().set_tcp(Checksum::Rx);
+        let raw = segment.to_bytes_with_checksums(&V4, &skipped)?;
+        assert_eq!(raw[16..18], [0, 0]);
+        assert!(TcpSegment::from_reader(raw.as_slice(), &V4).await.is_err());
+
+        // Nor is it checked if it's trusted
+        let trusted = ChecksumCapabilities::default().set_tcp(Checksum::Tx);
+        let parsed = TcpSegment::from_reader_with_checksums(raw.as_slice(), &V4, &trusted).await?;
+        assert_eq!(parsed, segment);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_options() -> Result<()> {
+        // An experimental option (RFC 6994), behind a couple of NOPs
+        let mut raw = TcpSegment::new(1, 2, 3).to_bytes(&V4)?;
+        raw.extend_from_slice(&[OPTION_NOP, OPTION_NOP, 253, 6, 1, 2, 3, 4]);
+        raw[12] = 7 << 4;
+        raw[16..18].copy_from_slice(&[0, 0]);
+        let checksum = V4.checksum(IpProtocol::Tcp, &raw);
+        raw[16..18].copy_from_slice(&checksum);
+
+        let segment = TcpSegment::from_reader(raw.as_slice(), &V4).await?;
+        let experiment = TcpOption::Other {
+            kind: 253,
+            data: vec![1, 2, 3, 4],
+        };
+        assert_eq!(segment.options, [experiment]);
+        let raw = segment.to_bytes(&V4)?;
+        assert_eq!(raw[MIN_HEADER_LENGTH..], [253, 6, 1, 2, 3, 4, 0, 0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed() -> Result<()> {
+        let raw = syn().to_bytes(&V4)?;
+        let mut corrupt = raw.clone();
+        corrupt[4] ^= 1;
+        assert!(
+            TcpSegment::from_reader(corrupt.as_slice(), &V4)
+                .await
+                .is_err()
+        );
+        assert!(
+            TcpSegment::from_reader(&raw[..MIN_HEADER_LENGTH], &V4)
+                .await
+                .is_err()
+        );
+
+        // An option running past the header
+        let bad = [OPTION_MSS, 8, 0, 0];
+        assert!(TcpOption::parse_all(&bad).is_err());
+        assert!(TcpOption::parse_all(&[OPTION_WINDOW_SCALE, 4, 0, 0]).is_err());
+        // Fast Open cookies are requested, or 4 to 16 bytes
+        let request = TcpOption::parse_all(&[OPTION_FAST_OPEN, 2])?;
+        assert_eq!(request, [TcpOption::FastOpen(Vec::new())]);
+        let cookie = TcpOption::parse_all(&[OPTION_FAST_OPEN, 6, 1, 2, 3, 4])?;
+        assert_eq!(cookie, [TcpOption::FastOpen(vec![1, 2, 3, 4])]);
+        assert!(TcpOption::parse_all(&[OPTION_FAST_OPEN, 4, 1, 2]).is_err());
+
+        let mut huge = syn();
+        huge.options.push(TcpOption::Other {
+            kind: 99,
+            data: vec![0; 30],
+        });
+        assert!(huge.to_bytes(&V4).is_err());
+        Ok(())
+    }
+}
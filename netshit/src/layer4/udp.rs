@@ -0,0 +1,226 @@
+//! User Datagram Protocol (RFC 768)
+use super::PseudoHeader;
+use crate::checksum::{self, ChecksumCapabilities};
+use crate::layer3::IpProtocol;
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const HEADER_LENGTH: usize = 8;
+
+/// A parsed UDP datagram
+///
+/// The length and checksum are checked when it's parsed, and filled in when
+/// it's written.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UdpDatagram {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub data: Vec<u8>,
+}
+
+impl UdpDatagram {
+    pub fn new(source_port: u16, destination_port: u16, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            data: data.into(),
+        }
+    }
+
+    /// Parse a datagram sent between the addresses in `pseudo`, verifying
+    /// its checksum
+    ///
+    /// The datagram runs to the end of the reader; anything past its length
+    /// field is padding, and ignored.
+    pub async fn from_reader(
+        reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, pseudo, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [UdpDatagram::from_reader], but verifying the checksum only if
+    /// `checksums` says to
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        let Some(&[a, b, c, d, e, f, g, h]) = raw.first_chunk::<HEADER_LENGTH>() else {
+            bail!("UDP: datagram too short");
+        };
+        let length = usize::from(u16::from_be_bytes([e, f]));
+        if length < HEADER_LENGTH || length > raw.len() {
+            bail!("UDP: bad length {length} for {} bytes", raw.len());
+        }
+        raw.truncate(length);
+        if !checksums.udp.rx() {
+            // Not checked at all
+        } else if [g, h] == [0, 0] {
+            // Only IPv4 lets the sender skip the checksum
+            if !pseudo.is_v4() {
+                bail!("UDP: missing checksum");
+            }
+        } else if pseudo.checksum(IpProtocol::Udp, &raw) != [0, 0] {
+            bail!("UDP: invalid checksum");
+        }
+        Ok(Self {
+            source_port: u16::from_be_bytes([a, b]),
+            destination_port: u16::from_be_bytes([c, d]),
+            data: raw.split_off(HEADER_LENGTH),
+        })
+    }
+
+    /// Serialize a datagram sent between the addresses in `pseudo` into a
+    /// writer
+    pub async fn onto_writer(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<()> {
+        writer.write_all(&self.to_bytes(pseudo)?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IP payload
+    pub fn to_bytes(&self, pseudo: &PseudoHeader) -> Result<Vec<u8>> {
+        self.to_bytes_with_checksums(pseudo, &ChecksumCapabilities::default())
+    }
+
+    /// Like [UdpDatagram::to_bytes], but leaving the checksum zero, i.e.
+    /// absent, unless `checksums` says to compute it
+    pub fn to_bytes_with_checksums(
+        &self,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Vec<u8>> {
+        let length = u16::try_from(HEADER_LENGTH + self.data.len())?;
+        let mut raw = Vec::with_capacity(length.into());
+        raw.extend_from_slice(&self.source_port.to_be_bytes());
+        raw.extend_from_slice(&self.destination_port.to_be_bytes());
+        raw.extend_from_slice(&length.to_be_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw.extend_from_slice(&self.data);
+        if !checksums.udp.tx() {
+            return Ok(raw);
+        }
+        let checksum = match pseudo.checksum(IpProtocol::Udp, &raw) {
+            // Zero means no checksum, so a real zero is sent as its
+            // ones'-complement twin
+            [0, 0] => [0xff, 0xff],
+            checksum => checksum,
+        };
+        raw[6..8].copy_from_slice(&checksum);
+        Ok(raw)
+    }
+}
+
+/// Patch the checksum of `raw`, a serialized datagram, after an address its
+/// pseudo-header covers changes from `old` to `new`
+pub fn update_checksum(raw: &mut [u8], old: &[u8], new: &[u8]) {
+    let Some(checksum) = raw.get_mut(6..8) else {
+        return;
+    };
+    // Zero means the sender didn't checksum
+    if checksum == [0, 0] {
+        return;
+    }
+    let updated = match checksum::update_bytes([checksum[0], checksum[1]], old, new) {
+        // Would read as no checksum at all (RFC 768)
+        [0, 0] => [0xff, 0xff],
+        updated => updated,
+    };
+    checksum.copy_from_slice(&updated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const V4: PseudoHeader = PseudoHeader::V4 {
+        source: Ipv4Addr::new(10, 0, 0, 1),
+        destination: Ipv4Addr::new(10, 0, 0, 2),
+    };
+
+    fn v6() -> PseudoHeader {
+        PseudoHeader::V6 {
+            source: "2001:db8::1".parse().unwrap(),
+            destination: "2001:db8::2".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let mut datagram = UdpDatagram::new(5353, 53, b"query".to_vec());
+        for pseudo in [V4, v6()] {
+            let mut raw = Vec::new();
+            datagram.onto_writer(&mut raw, &pseudo).await?;
+            assert_eq!(raw.len(), HEADER_LENGTH + 5);
+            assert_eq!(u16::from_be_bytes([raw[4], raw[5]]), 13);
+            assert_eq!(
+                UdpDatagram::from_reader(raw.as_slice(), &pseudo).await?,
+                datagram
+            );
+
+            // Padding past the length is dropped
+            raw.extend_from_slice(&[0, 0]);
+            assert_eq!(
+                UdpDatagram::from_reader(raw.as_slice(), &pseudo).await?,
+                datagram
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checksums() -> Result<()> {
+        let raw = UdpDatagram::new(1, 2, [3; 4]).to_bytes(&V4)?;
+        // The addresses are covered too
+        let other = PseudoHeader::V4 {
+            source: Ipv4Addr::new(10, 0, 0, 9),
+            destination: Ipv4Addr::new(10, 0, 0, 2),
+        };
+        assert!(
+            UdpDatagram::from_reader(raw.as_slice(), &other)
+                .await
+                .is_err()
+        );
+
+        // No checksum is fine over IPv4 only
+        let mut unchecked = raw.clone();
+        unchecked[6..8].copy_from_slice(&[0, 0]);
+        assert!(
+            UdpDatagram::from_reader(unchecked.as_slice(), &V4)
+                .await
+                .is_ok()
+        );
+        assert!(
+            UdpDatagram::from_reader(unchecked.as_slice(), &v6())
+                .await
+                .is_err()
+        );
+
+        assert!(UdpDatagram::from_reader(&raw[..7], &V4).await.is_err());
+
+        // Sent from an address chosen later
+        let unspecified = PseudoHeader::V4 {
+            source: Ipv4Addr::UNSPECIFIED,
+            destination: Ipv4Addr::new(10, 0, 0, 2),
+        };
+        let mut raw = UdpDatagram::new(1, 2, [3; 4]).to_bytes(&unspecified)?;
+        update_checksum(&mut raw, &[0; 4], &[10, 0, 0, 1]);
+        assert!(UdpDatagram::from_reader(raw.as_slice(), &V4).await.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn updated_checksum_never_zero() {
+        // RFC 1624's example, which updates to 0x0000
+        let mut raw = [0, 1, 0, 2, 0, 8, 0xdd, 0x2f];
+        update_checksum(&mut raw, &[0x55, 0x55], &[0x32, 0x85]);
+        assert_eq!(raw[6..8], [0xff, 0xff]);
+    }
+}
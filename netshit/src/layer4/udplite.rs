@@ -0,0 +1,212 @@
+//! Lightweight User Datagram Protocol (RFC 3828): UDP whose checksum may
+//! cover only the start of each datagram, so damage past that is let
+//! through rather than losing the whole datagram
+use super::PseudoHeader;
+use super::udp::HEADER_LENGTH;
+use crate::checksum::ChecksumCapabilities;
+use crate::layer3::IpProtocol;
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A parsed UDP-Lite datagram
+///
+/// It runs to the end of its IP packet, having a checksum coverage where
+/// UDP has a length. The checksum is checked when it's parsed, and filled
+/// in when it's written.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UdpLiteDatagram {
+    pub source_port: u16,
+    pub destination_port: u16,
+    /// Bytes the checksum covers, header included; 0 covers all of them
+    pub coverage: u16,
+    pub data: Vec<u8>,
+}
+
+impl UdpLiteDatagram {
+    /// A datagram whose checksum covers all of it
+    pub fn new(source_port: u16, destination_port: u16, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            coverage: 0,
+            data: data.into(),
+        }
+    }
+
+    /// Checksum only the first `coverage` bytes, header included; less
+    /// than a header covers the header, and 0 everything
+    #[must_use]
+    pub const fn set_coverage(mut self, coverage: u16) -> Self {
+        self.coverage = coverage;
+        self
+    }
+
+    /// Parse a datagram sent between the addresses in `pseudo`, verifying
+    /// the part its checksum covers
+    pub async fn from_reader(
+        reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<Self> {
+        Self::from_reader_with_checksums(reader, pseudo, &ChecksumCapabilities::default()).await
+    }
+
+    /// Like [UdpLiteDatagram::from_reader], but verifying the checksum only
+    /// if `checksums` says to
+    pub async fn from_reader_with_checksums(
+        mut reader: impl AsyncRead + Unpin,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Self> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        let Some(&[a, b, c, d, e, f, g, h]) = raw.first_chunk::<HEADER_LENGTH>() else {
+            bail!("UDP-Lite: datagram too short");
+        };
+        let coverage = u16::from_be_bytes([e, f]);
+        let covered = match usize::from(coverage) {
+            0 => raw.len(),
+            covered if (HEADER_LENGTH..=raw.len()).contains(&covered) => covered,
+            covered => bail!("UDP-Lite: bad coverage {covered} for {} bytes", raw.len()),
+        };
+        // Unlike UDP's, the checksum can't be left out
+        if checksums.udp.rx() {
+            if [g, h] == [0, 0] {
+                bail!("UDP-Lite: missing checksum");
+            }
+            if pseudo.partial_checksum(IpProtocol::UdpLite, &raw, covered) != [0, 0] {
+                bail!("UDP-Lite: invalid checksum");
+            }
+        }
+        Ok(Self {
+            source_port: u16::from_be_bytes([a, b]),
+            destination_port: u16::from_be_bytes([c, d]),
+            coverage,
+            data: raw.split_off(HEADER_LENGTH),
+        })
+    }
+
+    /// Serialize a datagram sent between the addresses in `pseudo` into a
+    /// writer
+    pub async fn onto_writer(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        pseudo: &PseudoHeader,
+    ) -> Result<()> {
+        writer.write_all(&self.to_bytes(pseudo)?).await?;
+        Ok(())
+    }
+
+    /// Serialize into a new buffer, e.g. for an IP payload
+    ///
+    /// A coverage past the end of the datagram covers all of it.
+    pub fn to_bytes(&self, pseudo: &PseudoHeader) -> Result<Vec<u8>> {
+        self.to_bytes_with_checksums(pseudo, &ChecksumCapabilities::default())
+    }
+
+    /// Like [UdpLiteDatagram::to_bytes], but leaving the checksum zero
+    /// unless `checksums` says to compute it
+    pub fn to_bytes_with_checksums(
+        &self,
+        pseudo: &PseudoHeader,
+        checksums: &ChecksumCapabilities,
+    ) -> Result<Vec<u8>> {
+        let length = u16::try_from(HEADER_LENGTH + self.data.len())?;
+        let coverage = match self.coverage {
+            0 => 0,
+            coverage => coverage.clamp(HEADER_LENGTH as u16, length),
+        };
+        let mut raw = Vec::with_capacity(length.into());
+        raw.extend_from_slice(&self.source_port.to_be_bytes());
+        raw.extend_from_slice(&self.destination_port.to_be_bytes());
+        raw.extend_from_slice(&coverage.to_be_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw.extend_from_slice(&self.data);
+        if !checksums.udp.tx() {
+            return Ok(raw);
+        }
+        let covered = match coverage {
+            0 => raw.len(),
+            coverage => coverage.into(),
+        };
+        let checksum = match pseudo.partial_checksum(IpProtocol::UdpLite, &raw, covered) {
+            [0, 0] => [0xff, 0xff],
+            checksum => checksum,
+        };
+        raw[6..8].copy_from_slice(&checksum);
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const V4: PseudoHeader = PseudoHeader::V4 {
+        source: Ipv4Addr::new(10, 0, 0, 1),
+        destination: Ipv4Addr::new(10, 0, 0, 2),
+    };
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let v6 = PseudoHeader::V6 {
+            source: "2001:db8::1".parse()?,
+            destination: "2001:db8::2".parse()?,
+        };
+        for pseudo in [V4, v6] {
+            for coverage in [0, 8, 10] {
+                let mut datagram =
+                    UdpLiteDatagram::new(5004, 5004, *b"frame").set_coverage(coverage);
+                let mut raw = Vec::new();
+                datagram.onto_writer(&mut raw, &pseudo).await?;
+                assert_eq!(raw.len(), HEADER_LENGTH + 5);
+                assert_eq!(u16::from_be_bytes([raw[4], raw[5]]), coverage);
+                assert_eq!(
+                    UdpLiteDatagram::from_reader(raw.as_slice(), &pseudo).await?,
+                    datagram
+                );
+            }
+        }
+
+        // Coverage is at least the header, and at most everything
+        let short = UdpLiteDatagram::new(1, 2, [0; 4]).set_coverage(3);
+        assert_eq!(short.to_bytes(&V4)?[4..6], [0, 8]);
+        let long = UdpLiteDatagram::new(1, 2, [0; 4]).set_coverage(100);
+        assert_eq!(long.to_bytes(&V4)?[4..6], [0, 12]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_coverage() -> Result<()> {
+        let raw = UdpLiteDatagram::new(1, 2, [3; 8])
+            .set_coverage(10)
+            .to_bytes(&V4)?;
+
+        // Damage past the coverage gets through
+        let mut damaged = raw.clone();
+        damaged[12] ^= 0xff;
+        let datagram = UdpLiteDatagram::from_reader(damaged.as_slice(), &V4).await?;
+        assert_eq!(datagram.data, [3, 3, 3, 3, 0xfc, 3, 3, 3]);
+
+        // But not within it
+        let mut damaged = raw.clone();
+        damaged[9] ^= 0xff;
+        assert!(
+            UdpLiteDatagram::from_reader(damaged.as_slice(), &V4)
+                .await
+                .is_err()
+        );
+
+        // Nor coverage that's too short or long, or no checksum at all
+        for bad in [[0, 7, 0xab, 0xcd], [0, 17, 0xab, 0xcd], [0, 0, 0, 0]] {
+            let mut bad_header = raw.clone();
+            bad_header[4..8].copy_from_slice(&bad);
+            assert!(
+                UdpLiteDatagram::from_reader(bad_header.as_slice(), &V4)
+                    .await
+                    .is_err()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+//! Limits on what parsers will accept from untrusted length fields
+use crate::eth::MAX_MTU;
+
+/// Largest IPv4 header, options included
+const MAX_IPV4_HEADER: usize = 60;
+
+/// Caps on sizes read from packets, checked before anything is allocated
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParseLimits {
+    /// Largest payload of any layer
+    pub max_payload: usize,
+    /// Largest header of any layer
+    pub max_header: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_payload: MAX_MTU,
+            max_header: MAX_IPV4_HEADER,
+        }
+    }
+}
+
+impl ParseLimits {
+    #[must_use]
+    pub const fn set_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_max_header(mut self, max_header: usize) -> Self {
+        self.max_header = max_header;
+        self
+    }
+
+    /// Fail with [TooLarge] if a `what` payload of `size` bytes is over the
+    /// limit
+    pub fn check_payload(&self, what: &'static str, size: usize) -> Result<(), TooLarge> {
+        check(what, size, self.max_payload)
+    }
+
+    /// Fail with [TooLarge] if a `what` header of `size` bytes is over the
+    /// limit
+    pub fn check_header(&self, what: &'static str, size: usize) -> Result<(), TooLarge> {
+        check(what, size, self.max_header)
+    }
+}
+
+fn check(what: &'static str, size: usize, limit: usize) -> Result<(), TooLarge> {
+    if size > limit {
+        Err(TooLarge { what, size, limit })
+    } else {
+        Ok(())
+    }
+}
+
+/// Error for a length field over a [ParseLimits] limit
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TooLarge {
+    pub what: &'static str,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes is over the limit of {}",
+            self.what, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
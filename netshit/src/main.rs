@@ -1,17 +1,227 @@
 #![allow(dead_code)]
 use anyhow::Result;
+mod acd;
+mod address;
+mod advertiser;
+mod arp_cache;
+mod bridge;
+mod checksum;
+use checksum::ChecksumCapabilities;
+mod conntrack;
+mod dad;
+mod demux;
 mod eth;
 use eth::EthFrame;
+mod filter;
+use filter::FrameFilter;
+mod icmp_error;
+mod icmpv6_error;
 mod layer3;
+mod layer4;
+mod limits;
+use limits::ParseLimits;
+mod martian;
+mod mirror;
+mod multicast;
+mod multicast6;
+mod nat;
+mod ppp;
+mod rate_limit;
+mod reassembly;
+mod resolver;
+mod route;
+mod rtt;
+mod slaac;
+mod slip;
+mod socket;
+mod stack;
+mod tcp;
+mod tunnel;
+mod wol;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Run the stack over a SLIP link on a virtual serial port instead of tun
+async fn run_slip() -> Result<()> {
+    let port = virtser::VirtSerBuilder::new().build_async().await?;
+    println!("SLIP link on {}", port.path().display());
+    let mut link = slip::SlipLink::new(port);
+
+    loop {
+        match link.recv().await {
+            Ok(packet) => println!("{packet:?}"),
+            Err(err) => println!("error: {err}"),
+        }
+    }
+}
+
+/// Run the stack over PPP on a virtual serial port, e.g. against
+/// `pppd <pts> noauth nodetach`
+async fn run_ppp() -> Result<()> {
+    let port = virtser::VirtSerBuilder::new().build_async().await?;
+    println!("PPP link on {}", port.path().display());
+    let config = ppp::PppConfig {
+        local_address: std::net::Ipv4Addr::new(192, 168, 0, 5),
+        peer_address: Some(std::net::Ipv4Addr::new(192, 168, 0, 1)),
+        ..Default::default()
+    };
+    let mut link = ppp::PppLink::new(port, config);
+    link.negotiate().await?;
+    println!("PPP link up: peer is {:?}", link.peer_address());
+
+    loop {
+        match link.recv().await {
+            Ok(packet) => println!("{packet:?}"),
+            Err(err) => println!("error: {err}"),
+        }
+    }
+}
+
+/// Locally administered MAC we send from
+const LOCAL_MAC: eth::Mac6 = eth::Mac6::new([0x02, 0x6e, 0x65, 0x74, 0x00, 0x01]);
+
+/// Our address on the tap
+const LOCAL_ADDRESS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(192, 168, 0, 1);
+
+/// The host's end of the tap
+const HOST_ADDRESS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(192, 168, 0, 5);
+
+/// Length of the tap network's prefix
+const TAP_PREFIX_LENGTH: u8 = 24;
+
+/// Frames the bridge can get ahead of its capture file by
+const MIRROR_QUEUE: usize = 256;
+
+/// Value of `--<name> <value>`, anywhere on the command line
+fn arg_value(name: &str) -> Result<Option<String>> {
+    Ok(arg_values(name)?.into_iter().next())
+}
+
+/// Values of every `--<name> <value>` on the command line, in order
+fn arg_values(name: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut args = std::env::args();
+    while args.any(|arg| arg == name) {
+        match args.next() {
+            Some(value) => values.push(value),
+            None => anyhow::bail!("{name} needs a value"),
+        }
+    }
+    Ok(values)
+}
+
+/// MTU given with `--mtu <bytes>`, if any
+fn mtu_from_args() -> Result<usize> {
+    let Some(mtu) = arg_value("--mtu")? else {
+        return Ok(eth::DEFAULT_MTU);
+    };
+    let mtu = mtu.parse()?;
+    if !(576..=eth::MAX_MTU).contains(&mtu) {
+        anyhow::bail!("MTU must be between 576 and {}", eth::MAX_MTU);
+    }
+    Ok(mtu)
+}
+
+/// With `--trust-checksums`, neither verify nor compute checksums, leaving
+/// them to whatever's on the other end of the tap
+fn checksums_from_args() -> ChecksumCapabilities {
+    if std::env::args().any(|arg| arg == "--trust-checksums") {
+        ChecksumCapabilities::ignored()
+    } else {
+        ChecksumCapabilities::default()
+    }
+}
+
+/// Default gateways given with `--gateway <address>`, of either family
+fn gateways_from_args() -> Result<Vec<std::net::IpAddr>> {
+    arg_values("--gateway")?
+        .iter()
+        .map(|gateway| Ok(gateway.parse()?))
+        .collect()
+}
+
+/// The tap network, plus a default route through an IPv4 `--gateway` if
+/// given
+fn routes_from_args() -> Result<route::RoutingTable> {
+    let mut routes = route::RoutingTable::new();
+    let tap = route::Ipv4Prefix::new(LOCAL_ADDRESS, TAP_PREFIX_LENGTH)?;
+    routes.add(route::Route::connected(tap, 0));
+    for gateway in gateways_from_args()? {
+        let std::net::IpAddr::V4(gateway) = gateway else {
+            continue;
+        };
+        if !tap.contains(gateway) {
+            anyhow::bail!("Gateway {gateway} isn't on {tap}");
+        }
+        routes.add(route::Route::via(route::Ipv4Prefix::DEFAULT, gateway, 0));
+    }
+    Ok(routes)
+}
+
+/// A default route through an IPv6 `--gateway`, if given; routers
+/// advertising themselves are used too
+fn ipv6_routes_from_args() -> Result<route::Ipv6RoutingTable> {
+    let mut routes = route::Ipv6RoutingTable::new();
+    for gateway in gateways_from_args()? {
+        if let std::net::IpAddr::V6(gateway) = gateway {
+            routes.add(route::Ipv6Route::via(
+                route::Ipv6Prefix::DEFAULT,
+                gateway,
+                0,
+            ));
+        }
+    }
+    Ok(routes)
+}
+
+/// Extra address on the tap given with `--alias <address>/<length>`, if any
+fn alias_from_args() -> Result<Option<address::InterfaceAddress>> {
+    arg_value("--alias")?.map(|alias| alias.parse()).transpose()
+}
+
+/// Neighbour pinned with `--static-arp <address>,<mac>`, if any
+fn static_arp_from_args() -> Result<Option<(std::net::Ipv4Addr, eth::Mac6)>> {
+    let Some(entry) = arg_value("--static-arp")? else {
+        return Ok(None);
+    };
+    let Some((address, mac)) = entry.split_once(',') else {
+        anyhow::bail!("--static-arp takes <address>,<mac>");
+    };
+    Ok(Some((address.parse()?, mac.parse()?)))
+}
+
+/// IPv6 addresses given with `--ipv6 <address>`, which may be repeated;
+/// `--ipv6 link-local` picks the one formed from our MAC
+fn ipv6_from_args() -> Result<Vec<std::net::Ipv6Addr>> {
+    arg_values("--ipv6")?
+        .iter()
+        .map(|address| match address.as_str() {
+            "link-local" => Ok(LOCAL_MAC.link_local()),
+            _ => Ok(address.parse()?),
+        })
+        .collect()
+}
+
+/// Router mode, advertising the prefix given with `--advertise <prefix>`,
+/// if any
+fn advertiser_from_args(mtu: usize) -> Result<Option<advertiser::RouterAdvertiser>> {
+    let Some(prefix) = arg_value("--advertise")? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        advertiser::RouterAdvertiser::new()
+            .add_prefix(prefix.parse()?)
+            .set_mtu(mtu.try_into()?),
+    ))
+}
+
+/// Create the tap device the stack runs on
+fn open_tap(mtu: usize) -> Result<tun::AsyncDevice> {
     let mut config = tun::Configuration::default();
     config
-        .address((192, 168, 0, 5))
+        .mtu(mtu.try_into()?)
+        .address(HOST_ADDRESS)
         .netmask((255, 255, 255, 0))
         .layer(tun::Layer::L2)
-        .destination((192, 168, 0, 1))
+        .destination(LOCAL_ADDRESS)
         .up();
 
     config.platform_config(|config| {
@@ -19,15 +229,214 @@ async fn main() -> Result<()> {
         config.ensure_root_privileges(true);
     });
 
-    let dev: tun::AsyncDevice = tun::create_as_async(&config)?;
-    let mut buf = [0; 4096];
+    Ok(tun::create_as_async(&config)?)
+}
+
+/// Broadcast a Wake-on-LAN magic packet for `target` through the tap
+async fn run_wake(target: Option<String>) -> Result<()> {
+    let Some(target) = target else {
+        anyhow::bail!("usage: netshit --wake <mac>");
+    };
+    let target: eth::Mac6 = target.parse()?;
+    let dev = open_tap(eth::DEFAULT_MTU)?;
+
+    let mut frame = Vec::new();
+    wol::frame(LOCAL_MAC, target)
+        .onto_writer(&mut frame)
+        .await?;
+    dev.send(&frame).await?;
+    println!("Sent magic packet for {target}");
+    Ok(())
+}
 
+/// Mirror to the capture file given with `--pcap <path>`, if any
+fn mirror_from_args() -> Result<mirror::Mirror> {
+    let mut mirror = mirror::Mirror::new();
+    if let Some(path) = arg_value("--pcap")? {
+        let file = std::fs::File::create(path)?;
+        mirror = mirror.add(mirror::PcapWriter::new(file)?);
+    }
+    Ok(mirror)
+}
+
+/// Bridge two tap devices together as a software switch
+async fn run_bridge() -> Result<()> {
+    let mut bridge = bridge::Bridge::new();
+    let mut tasks = tokio::task::JoinSet::new();
+    // Captures are written on their own thread, so the bridge never waits
+    // for the disk
+    let mut mirror = mirror_from_args()?;
+    if !mirror.is_empty() {
+        let (tx, mut mirrored) = tokio::sync::mpsc::channel::<Vec<u8>>(MIRROR_QUEUE);
+        bridge = bridge.set_mirror(mirror::Mirror::new().add(tx));
+        tasks.spawn_blocking(move || {
+            while let Some(frame) = mirrored.blocking_recv() {
+                for err in mirror.tee(&frame) {
+                    println!("Dropping mirror: {err}");
+                }
+            }
+            Ok(())
+        });
+    }
+    for name in ["netshit0", "netshit1"] {
+        let mut config = tun::Configuration::default();
+        config.tun_name(name).layer(tun::Layer::L2).up();
+        let dev = tun::create_as_async(&config)?;
+        println!("Bridging {name}");
+        tasks.spawn(attach_tap(dev, bridge.add_port()));
+    }
+    tasks.spawn(bridge.run());
+
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Pump frames between a tap device and a bridge port
+async fn attach_tap(dev: tun::AsyncDevice, mut port: bridge::BridgePort) -> Result<()> {
+    let mut buf = vec![0; eth::MAX_FRAME_LENGTH];
     loop {
-        dev.recv(&mut buf).await?;
+        tokio::select! {
+            n = dev.recv(&mut buf) => port.send(buf[..n?].to_vec()).await?,
+            frame = port.recv() => match frame {
+                Some(frame) => {
+                    dev.send(&frame).await?;
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
 
-        match EthFrame::from_reader(buf.as_slice()).await {
-            Ok(frame) => println!("{frame:?}"),
+#[tokio::main]
+async fn main() -> Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("--slip") => return run_slip().await,
+        Some("--ppp") => return run_ppp().await,
+        Some("--bridge") => return run_bridge().await,
+        Some("--wake") => return run_wake(std::env::args().nth(2)).await,
+        _ => {}
+    }
+
+    let mtu = mtu_from_args()?;
+    let dev = open_tap(mtu)?;
+    let mut buf = vec![0; eth::MAX_FRAME_LENGTH];
+    let filter: Box<dyn FrameFilter> = Box::new(filter::All);
+    let mut mirror = mirror_from_args()?;
+    let mut stack = stack::Stack::new(
+        LOCAL_MAC,
+        address::InterfaceAddress::new(LOCAL_ADDRESS, TAP_PREFIX_LENGTH)?,
+    )
+    .set_routes(routes_from_args()?)
+    .set_ipv6_routes(ipv6_routes_from_args()?)
+    .set_mtu(mtu)
+    .set_forwarding(std::env::args().any(|arg| arg == "--forward"))
+    .set_checksums(checksums_from_args());
+    let checksums = *stack.checksums();
+    for address in ipv6_from_args()? {
+        stack.assign_ipv6_address(address, std::time::Instant::now())?;
+    }
+    if let Some(advertiser) = advertiser_from_args(mtu)? {
+        stack = stack.set_router_advertiser(advertiser);
+    }
+    if let Some((address, mac)) = static_arp_from_args()? {
+        stack.arp_cache_mut().add_static(address, mac);
+    }
+    if let Some(alias) = alias_from_args()? {
+        stack.assign_address(alias, std::time::Instant::now())?;
+        stack
+            .routes_mut()
+            .add(route::Route::connected(alias.network(), 0));
+    }
+
+    let addresses: Vec<_> = stack
+        .addresses()
+        .iter()
+        .map(|entry| entry.address)
+        .collect();
+    for address in addresses {
+        send_frame(&dev, stack.announce_address(address)?, mtu, &checksums).await?;
+    }
+
+    loop {
+        let deadline = stack.next_deadline();
+        let n = tokio::select! {
+            n = dev.recv(&mut buf) => n?,
+            frame = stack.next_outgoing() => {
+                if let Err(err) = send_frame(&dev, frame, mtu, &checksums).await {
+                    println!("error: {err}");
+                }
+                continue;
+            }
+            _ = sleep_until(deadline), if deadline.is_some() => {
+                for frame in stack.poll(std::time::Instant::now()).await? {
+                    if let Err(err) = send_frame(&dev, frame, mtu, &checksums).await {
+                        println!("error: {err}");
+                    }
+                }
+                for event in stack.take_acd_events() {
+                    println!("{event:?}");
+                }
+                for event in stack.take_dad_events() {
+                    println!("{event:?}");
+                }
+                continue;
+            }
+        };
+        let raw = &buf[..n];
+        for err in mirror.tee(raw) {
+            println!("Dropping mirror: {err}");
+        }
+        if !filter.accept_raw(raw) {
+            continue;
+        }
+
+        match EthFrame::from_reader_with_checksums(raw, &ParseLimits::default(), &checksums).await {
+            Ok(frame) if filter.accept(&frame) => {
+                println!("{frame:?}");
+                if let Err(err) = respond(&dev, &mut stack, &frame, mtu).await {
+                    println!("error: {err}");
+                }
+            }
+            Ok(_) => {}
             Err(err) => println!("error: {err}"),
         }
     }
 }
+
+/// Let the stack handle a frame, and send whatever it replies with
+async fn respond(
+    dev: &tun::AsyncDevice,
+    stack: &mut stack::Stack,
+    frame: &EthFrame,
+    mtu: usize,
+) -> Result<()> {
+    let checksums = *stack.checksums();
+    for reply in stack.handle(frame).await? {
+        send_frame(dev, reply, mtu, &checksums).await?;
+    }
+    Ok(())
+}
+
+/// Sleep until `deadline`, or forever if there isn't one
+async fn sleep_until(deadline: Option<std::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send_frame(
+    dev: &tun::AsyncDevice,
+    mut frame: EthFrame,
+    mtu: usize,
+    checksums: &ChecksumCapabilities,
+) -> Result<()> {
+    let mut raw = Vec::new();
+    frame
+        .onto_writer_with_checksums(&mut raw, mtu, checksums)
+        .await?;
+    dev.send(&raw).await?;
+    Ok(())
+}
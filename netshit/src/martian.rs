@@ -0,0 +1,179 @@
+//! Martian filtering: dropping received packets whose addresses can't be
+//! genuine (RFC 1812 §5.3.7), before they reach the upper layers
+use crate::address::Addresses;
+use crate::layer3::Ipv4Packet;
+
+/// Why a packet was dropped as martian
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Martian {
+    /// From 127.0.0.0/8, which never leaves a host
+    LoopbackSource,
+    /// To 127.0.0.0/8 or 0.0.0.0
+    InvalidDestination,
+    /// From one of our own addresses, so spoofed or looped back
+    OwnSource,
+    /// From a multicast group
+    MulticastSource,
+    /// From the limited broadcast address
+    BroadcastSource,
+    /// From 0.0.0.0 other than to the limited broadcast address, which only
+    /// hosts yet to learn their address (e.g. DHCP) may send
+    UnspecifiedSource,
+    /// From 240.0.0.0/4, which is reserved
+    ReservedSource,
+}
+
+impl Martian {
+    const ALL: [Self; 7] = [
+        Self::LoopbackSource,
+        Self::InvalidDestination,
+        Self::OwnSource,
+        Self::MulticastSource,
+        Self::BroadcastSource,
+        Self::UnspecifiedSource,
+        Self::ReservedSource,
+    ];
+
+    /// What's wrong with a packet received by a host with `addresses`, if
+    /// anything
+    pub fn check(packet: &Ipv4Packet, addresses: &Addresses) -> Option<Self> {
+        let (source, destination) = (packet.source, packet.destination);
+        Some(if source.is_loopback() {
+            Self::LoopbackSource
+        } else if destination.is_loopback() || destination.is_unspecified() {
+            Self::InvalidDestination
+        } else if addresses.contains(source) {
+            Self::OwnSource
+        } else if source.is_multicast() {
+            Self::MulticastSource
+        } else if source.is_broadcast() {
+            Self::BroadcastSource
+        } else if source.is_unspecified() && !destination.is_broadcast() {
+            Self::UnspecifiedSource
+        } else if source.octets()[0] >= 240 {
+            Self::ReservedSource
+        } else {
+            return None;
+        })
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// How many packets were dropped as martian, by reason
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct MartianCounters([u64; Martian::ALL.len()]);
+
+impl MartianCounters {
+    pub const fn new() -> Self {
+        Self([0; Martian::ALL.len()])
+    }
+
+    /// Check a received packet, counting it if it's martian
+    ///
+    /// Returns whether to accept it.
+    pub fn screen(&mut self, packet: &Ipv4Packet, addresses: &Addresses) -> bool {
+        match Martian::check(packet, addresses) {
+            Some(martian) => {
+                self.0[martian.index()] += 1;
+                false
+            }
+            None => true,
+        }
+    }
+
+    pub const fn get(&self, martian: Martian) -> u64 {
+        self.0[martian.index()]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// Every reason with a non-zero count
+    pub fn iter(&self) -> impl Iterator<Item = (Martian, u64)> {
+        Martian::ALL
+            .into_iter()
+            .map(|martian| (martian, self.get(martian)))
+            .filter(|&(_, count)| count > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::IpProtocol;
+    use anyhow::Result;
+
+    fn us() -> Addresses {
+        Addresses::new("192.168.0.1/24".parse().unwrap())
+    }
+
+    fn packet(source: [u8; 4], destination: [u8; 4]) -> Result<Ipv4Packet> {
+        Ipv4Packet::builder(source.into(), destination.into(), IpProtocol::Udp).build()
+    }
+
+    #[test]
+    fn classifies() -> Result<()> {
+        for (source, destination, expected) in [
+            ([192, 168, 0, 5], [192, 168, 0, 1], None),
+            (
+                [127, 0, 0, 1],
+                [192, 168, 0, 1],
+                Some(Martian::LoopbackSource),
+            ),
+            (
+                [192, 168, 0, 5],
+                [127, 0, 0, 1],
+                Some(Martian::InvalidDestination),
+            ),
+            ([192, 168, 0, 1], [192, 168, 0, 1], Some(Martian::OwnSource)),
+            (
+                [224, 0, 0, 1],
+                [192, 168, 0, 1],
+                Some(Martian::MulticastSource),
+            ),
+            (
+                [255, 255, 255, 255],
+                [192, 168, 0, 1],
+                Some(Martian::BroadcastSource),
+            ),
+            (
+                [0, 0, 0, 0],
+                [192, 168, 0, 1],
+                Some(Martian::UnspecifiedSource),
+            ),
+            // A DHCP client without an address yet
+            ([0, 0, 0, 0], [255, 255, 255, 255], None),
+            (
+                [240, 0, 0, 1],
+                [192, 168, 0, 1],
+                Some(Martian::ReservedSource),
+            ),
+        ] {
+            let packet = packet(source, destination)?;
+            assert_eq!(Martian::check(&packet, &us()), expected, "{packet:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn counts() -> Result<()> {
+        let mut counters = MartianCounters::new();
+        let us = us();
+        assert!(counters.screen(&packet([10, 0, 0, 1], [192, 168, 0, 1])?, &us));
+        assert!(!counters.screen(&packet([127, 0, 0, 1], [192, 168, 0, 1])?, &us));
+        assert!(!counters.screen(&packet([127, 0, 0, 2], [192, 168, 0, 1])?, &us));
+        assert!(!counters.screen(&packet([192, 168, 0, 1], [192, 168, 0, 1])?, &us));
+
+        assert_eq!(counters.get(Martian::LoopbackSource), 2);
+        assert_eq!(counters.total(), 3);
+        assert_eq!(
+            counters.iter().collect::<Vec<_>>(),
+            [(Martian::LoopbackSource, 2), (Martian::OwnSource, 1)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! Port mirroring: copies of frames for passive debugging
+//!
+//! A [Mirror] hands every frame to its sinks without ever holding up the
+//! path it's tapping; sinks that fail are dropped, and their errors handed
+//! back for the caller to report.
+use anyhow::{Result, anyhow};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// How often a [PcapWriter] flushes what it's buffered
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// pcap file magic, for microsecond timestamps
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+
+/// pcap link type for Ethernet frames
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Somewhere to send copies of frames
+pub trait FrameSink: Send {
+    fn send(&mut self, frame: &[u8]) -> Result<()>;
+}
+
+/// Frames are dropped when the channel is full, and the sink fails once the
+/// receiver is gone
+impl FrameSink for mpsc::Sender<Vec<u8>> {
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        match self.try_send(frame.to_vec()) {
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(anyhow!("Mirror channel closed")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes frames to a pcap capture file
+///
+/// Frames are buffered and flushed at most every [FLUSH_INTERVAL], so a
+/// busy link isn't held up by a write for each one.
+pub struct PcapWriter<W: Write> {
+    writer: BufWriter<W>,
+    flushed: Instant,
+}
+
+impl<W: Write + Send> PcapWriter<W> {
+    /// Write the pcap header to `writer`
+    pub fn new(writer: W) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        // Version 2.4
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        // Timezone offset and timestamp accuracy
+        writer.write_all(&[0; 8])?;
+        // Snapshot length
+        writer.write_all(&u32::MAX.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            flushed: Instant::now(),
+        })
+    }
+
+    /// Flush anything buffered and get the writer back
+    pub fn into_inner(self) -> Result<W> {
+        self.writer
+            .into_inner()
+            .map_err(|err| err.into_error().into())
+    }
+}
+
+impl<W: Write + Send> FrameSink for PcapWriter<W> {
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let length = u32::try_from(frame.len())?;
+        self.writer
+            .write_all(&u32::try_from(now.as_secs())?.to_le_bytes())?;
+        self.writer.write_all(&now.subsec_micros().to_le_bytes())?;
+        // Captured and original length
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(frame)?;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.flushed = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// A set of sinks each frame is copied to
+#[derive(Default)]
+pub struct Mirror {
+    sinks: Vec<Box<dyn FrameSink>>,
+}
+
+impl Mirror {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn add(mut self, sink: impl FrameSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Copy `frame` to every sink, dropping any that fail and returning
+    /// why they did
+    pub fn tee(&mut self, frame: &[u8]) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        self.sinks.retain_mut(|sink| match sink.send(frame) {
+            Ok(()) => true,
+            Err(err) => {
+                errors.push(err);
+                false
+            }
+        });
+        errors
+    }
+}
+
+impl std::fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mirror")
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcap_records() -> Result<()> {
+        let mut pcap = PcapWriter::new(Vec::new())?;
+        pcap.send(&[1, 2, 3])?;
+        // Buffered until it's time to flush
+        assert!(pcap.writer.get_ref().is_empty());
+        let bytes = pcap.into_inner()?;
+        assert_eq!(bytes.len(), 24 + 16 + 3);
+        assert_eq!(bytes[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(bytes[20..24], [1, 0, 0, 0]);
+        assert_eq!(bytes[32..40], [3, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(bytes[40..], [1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn tee_drops_closed_sinks() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (closed, _) = mpsc::channel::<Vec<u8>>(1);
+        let mut mirror = Mirror::new().add(tx).add(closed);
+
+        assert_eq!(mirror.tee(&[1]).len(), 1);
+        // Full: dropped, but the sink stays
+        assert!(mirror.tee(&[2]).is_empty());
+        assert_eq!(rx.try_recv().unwrap(), [1]);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(mirror.sinks.len(), 1);
+
+        drop(rx);
+        assert_eq!(mirror.tee(&[3]).len(), 1);
+        assert!(mirror.is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Multicast group membership (IGMP host side): telling routers and snooping
+//! switches which groups we're in, so they send us the traffic
+use crate::layer3::igmp::{
+    self, CHANGE_TO_EXCLUDE, CHANGE_TO_INCLUDE, GroupRecord, IgmpPacket, IgmpQuery, MODE_IS_EXCLUDE,
+};
+use anyhow::{Result, bail};
+use std::collections::BTreeSet;
+use std::hash::{BuildHasher, RandomState};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Every host is in this group, and never reports it
+pub const ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// Delay before repeating an unsolicited report, in case the first was lost
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which IGMP version we speak; we drop to v2 when a v2 querier is about
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IgmpVersion {
+    V2,
+    V3,
+}
+
+/// A report we owe, and when
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Pending {
+    due: Instant,
+    /// `None` to report every group
+    group: Option<Ipv4Addr>,
+}
+
+/// The groups we're in, and the reports we owe about them
+#[derive(Debug)]
+pub struct Memberships {
+    version: IgmpVersion,
+    groups: BTreeSet<Ipv4Addr>,
+    pending: Vec<Pending>,
+    /// Source of the random delays the protocol uses to avoid report storms
+    random: RandomState,
+}
+
+impl Default for Memberships {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memberships {
+    pub fn new() -> Self {
+        Self {
+            version: IgmpVersion::V3,
+            groups: BTreeSet::new(),
+            pending: Vec::new(),
+            random: RandomState::new(),
+        }
+    }
+
+    pub const fn version(&self) -> IgmpVersion {
+        self.version
+    }
+
+    pub fn is_member(&self, group: Ipv4Addr) -> bool {
+        group == ALL_HOSTS || self.groups.contains(&group)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = Ipv4Addr> {
+        self.groups.iter().copied()
+    }
+
+    /// Join `group`, returning the report announcing it
+    ///
+    /// The report is repeated once from [Memberships::poll].
+    pub fn join(&mut self, group: Ipv4Addr, now: Instant) -> Result<Option<IgmpPacket>> {
+        if !group.is_multicast() {
+            bail!("{group} isn't a multicast group");
+        }
+        if group == ALL_HOSTS || !self.groups.insert(group) {
+            return Ok(None);
+        }
+        self.pending.push(Pending {
+            due: now + self.delay(UNSOLICITED_REPORT_INTERVAL, group),
+            group: Some(group),
+        });
+        Ok(Some(self.change(group, CHANGE_TO_EXCLUDE)))
+    }
+
+    /// Leave `group`, returning the message announcing it
+    pub fn leave(&mut self, group: Ipv4Addr) -> Option<IgmpPacket> {
+        if !self.groups.remove(&group) {
+            return None;
+        }
+        self.pending.retain(|pending| pending.group != Some(group));
+        Some(match self.version {
+            IgmpVersion::V2 => IgmpPacket::Leave(group),
+            IgmpVersion::V3 => self.change(group, CHANGE_TO_INCLUDE),
+        })
+    }
+
+    /// React to an IGMP message heard on the link
+    pub fn handle(&mut self, message: &IgmpPacket, now: Instant) {
+        match message {
+            IgmpPacket::Query(query) => self.handle_query(query, now),
+            // Someone else answered for the group, so we needn't (v2 only:
+            // v3 routers track each member)
+            IgmpPacket::V1Report(group) | IgmpPacket::V2Report(group)
+                if self.version == IgmpVersion::V2 =>
+            {
+                self.pending.retain(|pending| pending.group != Some(*group));
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_query(&mut self, query: &IgmpQuery, now: Instant) {
+        self.version = match query.v3 {
+            Some(_) => IgmpVersion::V3,
+            None => IgmpVersion::V2,
+        };
+        let group = (!query.is_general()).then_some(query.group);
+        if let Some(group) = group
+            && !self.groups.contains(&group)
+        {
+            return;
+        }
+
+        let due = now + self.delay(query.max_response_time(), query.group);
+        // An earlier report covering this already will do
+        if self.pending.iter().any(|pending| {
+            pending.due <= due && (pending.group.is_none() || pending.group == group)
+        }) {
+            return;
+        }
+        self.pending.push(Pending { due, group });
+    }
+
+    /// Reports that are due by `now`
+    pub fn poll(&mut self, now: Instant) -> Vec<IgmpPacket> {
+        let mut due = Vec::new();
+        self.pending.retain(|pending| {
+            if pending.due <= now {
+                due.push(*pending);
+            }
+            pending.due > now
+        });
+        due.into_iter()
+            .flat_map(|pending| self.report(pending.group))
+            .collect()
+    }
+
+    /// When [Memberships::poll] next has something to send
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|pending| pending.due).min()
+    }
+
+    /// Current-state reports for `group`, or every group
+    fn report(&self, group: Option<Ipv4Addr>) -> Vec<IgmpPacket> {
+        let groups: Vec<Ipv4Addr> = match group {
+            Some(group) => vec![group],
+            None => self.groups.iter().copied().collect(),
+        };
+        match self.version {
+            IgmpVersion::V2 => groups.into_iter().map(IgmpPacket::V2Report).collect(),
+            IgmpVersion::V3 if groups.is_empty() => Vec::new(),
+            IgmpVersion::V3 => vec![IgmpPacket::V3Report(
+                groups
+                    .into_iter()
+                    .map(|group| record(MODE_IS_EXCLUDE, group))
+                    .collect(),
+            )],
+        }
+    }
+
+    /// Announce a change of our membership of `group`
+    fn change(&self, group: Ipv4Addr, record_type: u8) -> IgmpPacket {
+        match self.version {
+            IgmpVersion::V2 => IgmpPacket::V2Report(group),
+            IgmpVersion::V3 => IgmpPacket::V3Report(vec![record(record_type, group)]),
+        }
+    }
+
+    /// A random delay up to `max`
+    fn delay(&self, max: Duration, salt: Ipv4Addr) -> Duration {
+        let millis = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+        if millis == 0 {
+            return Duration::ZERO;
+        }
+        let random = self.random.hash_one((salt, self.pending.len()));
+        Duration::from_millis(random % millis)
+    }
+}
+
+/// Exclude no sources, i.e. everything sent to the group
+fn record(record_type: u8, group: Ipv4Addr) -> GroupRecord {
+    GroupRecord {
+        record_type,
+        group,
+        sources: Vec::new(),
+        auxiliary: Vec::new(),
+    }
+}
+
+/// Where an IGMP message we send goes
+pub fn destination(message: &IgmpPacket) -> Ipv4Addr {
+    match message {
+        IgmpPacket::V1Report(group) | IgmpPacket::V2Report(group) => *group,
+        IgmpPacket::Leave(_) => igmp::ALL_ROUTERS,
+        _ => igmp::ALL_IGMPV3_ROUTERS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: Ipv4Addr = Ipv4Addr::new(239, 1, 1, 1);
+
+    fn general_query(v3: bool) -> IgmpPacket {
+        IgmpPacket::Query(IgmpQuery {
+            max_response_code: 100,
+            group: Ipv4Addr::UNSPECIFIED,
+            v3: v3.then(|| igmp::QueryV3 {
+                suppress_router_processing: false,
+                robustness: 2,
+                interval_code: 125,
+                sources: Vec::new(),
+            }),
+        })
+    }
+
+    #[test]
+    fn join_and_leave() -> Result<()> {
+        let mut groups = Memberships::new();
+        let now = Instant::now();
+        assert!(groups.join(Ipv4Addr::new(10, 0, 0, 1), now).is_err());
+        assert_eq!(groups.join(ALL_HOSTS, now)?, None);
+
+        let report = groups.join(GROUP, now)?.unwrap();
+        assert_eq!(destination(&report), igmp::ALL_IGMPV3_ROUTERS);
+        assert!(groups.is_member(GROUP));
+        assert_eq!(groups.join(GROUP, now)?, None);
+
+        // The report is repeated within a second
+        let later = now + UNSOLICITED_REPORT_INTERVAL;
+        assert!(groups.next_deadline().unwrap() <= later);
+        assert_eq!(groups.poll(later).len(), 1);
+        assert_eq!(groups.next_deadline(), None);
+
+        let IgmpPacket::V3Report(records) = groups.leave(GROUP).unwrap() else {
+            panic!("Wrong IGMP type!");
+        };
+        assert_eq!(records[0].record_type, CHANGE_TO_INCLUDE);
+        assert!(!groups.is_member(GROUP));
+        assert_eq!(groups.leave(GROUP), None);
+        Ok(())
+    }
+
+    #[test]
+    fn answers_queries() -> Result<()> {
+        let mut groups = Memberships::new();
+        let now = Instant::now();
+        groups.join(GROUP, now)?;
+        groups.join(Ipv4Addr::new(224, 0, 0, 251), now)?;
+        groups.poll(now + UNSOLICITED_REPORT_INTERVAL);
+
+        groups.handle(&general_query(true), now);
+        let reports = groups.poll(now + Duration::from_secs(10));
+        let [IgmpPacket::V3Report(records)] = reports.as_slice() else {
+            panic!("Expected one v3 report, got {reports:?}");
+        };
+        assert_eq!(records.len(), 2);
+
+        // A v2 querier gets a v2 report per group, unless someone beats us
+        groups.handle(&general_query(false), now);
+        assert_eq!(groups.version(), IgmpVersion::V2);
+        let reports = groups.poll(now + Duration::from_secs(10));
+        assert_eq!(reports.len(), 2);
+        assert!(reports.contains(&IgmpPacket::V2Report(GROUP)));
+
+        groups.handle(
+            &IgmpPacket::Query(IgmpQuery {
+                max_response_code: 100,
+                group: GROUP,
+                v3: None,
+            }),
+            now,
+        );
+        groups.handle(&IgmpPacket::V2Report(GROUP), now);
+        assert!(groups.poll(now + Duration::from_secs(10)).is_empty());
+        Ok(())
+    }
+}
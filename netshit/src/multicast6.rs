@@ -0,0 +1,301 @@
+//! IPv6 multicast group membership (MLD listener side): what
+//! [multicast](crate::multicast) does with IGMP, done with MLD, so snooping
+//! switches send us our solicitations and the like
+use crate::layer3::icmpv6::Icmpv6Packet;
+use crate::layer3::mld::{
+    self, CHANGE_TO_EXCLUDE, CHANGE_TO_INCLUDE, MODE_IS_EXCLUDE, MldQuery, MldRecord,
+};
+use crate::layer3::ndp;
+use anyhow::{Result, bail};
+use std::collections::BTreeSet;
+use std::hash::{BuildHasher, RandomState};
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// Delay before repeating an unsolicited report, in case the first was lost
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which MLD version we speak; we drop to v1 when a v1 querier is about
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MldVersion {
+    V1,
+    V2,
+}
+
+/// A report we owe, and when
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Pending {
+    due: Instant,
+    /// `None` to report every group
+    group: Option<Ipv6Addr>,
+}
+
+/// The IPv6 groups we listen to, and the reports we owe about them
+#[derive(Debug)]
+pub struct Ipv6Memberships {
+    version: MldVersion,
+    groups: BTreeSet<Ipv6Addr>,
+    pending: Vec<Pending>,
+    /// Source of the random delays the protocol uses to avoid report storms
+    random: RandomState,
+}
+
+impl Default for Ipv6Memberships {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ipv6Memberships {
+    pub fn new() -> Self {
+        Self {
+            version: MldVersion::V2,
+            groups: BTreeSet::new(),
+            pending: Vec::new(),
+            random: RandomState::new(),
+        }
+    }
+
+    pub const fn version(&self) -> MldVersion {
+        self.version
+    }
+
+    /// Whether we listen to `group`; every node listens to all-nodes
+    pub fn is_member(&self, group: Ipv6Addr) -> bool {
+        group == ndp::ALL_NODES || self.groups.contains(&group)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = Ipv6Addr> {
+        self.groups.iter().copied()
+    }
+
+    /// Join `group`, returning the report announcing it
+    ///
+    /// The report is repeated once from [Ipv6Memberships::poll].
+    pub fn join(&mut self, group: Ipv6Addr, now: Instant) -> Result<Option<Icmpv6Packet>> {
+        if !group.is_multicast() {
+            bail!("{group} isn't a multicast group");
+        }
+        if group == ndp::ALL_NODES || !self.groups.insert(group) || !reported(group) {
+            return Ok(None);
+        }
+        self.pending.push(Pending {
+            due: now + self.delay(UNSOLICITED_REPORT_INTERVAL, group),
+            group: Some(group),
+        });
+        Ok(Some(self.change(group, CHANGE_TO_EXCLUDE)))
+    }
+
+    /// Leave `group`, returning the message announcing it
+    pub fn leave(&mut self, group: Ipv6Addr) -> Option<Icmpv6Packet> {
+        if !self.groups.remove(&group) {
+            return None;
+        }
+        self.pending.retain(|pending| pending.group != Some(group));
+        if !reported(group) {
+            return None;
+        }
+        Some(match self.version {
+            MldVersion::V1 => Icmpv6Packet::MldDone(group),
+            MldVersion::V2 => self.change(group, CHANGE_TO_INCLUDE),
+        })
+    }
+
+    /// React to an MLD message heard on the link
+    pub fn handle(&mut self, message: &Icmpv6Packet, now: Instant) {
+        match message {
+            Icmpv6Packet::MldQuery(query) => self.handle_query(query, now),
+            // Someone else answered for the group, so we needn't (v1 only:
+            // v2 routers track each listener)
+            Icmpv6Packet::MldV1Report(group) if self.version == MldVersion::V1 => {
+                self.pending.retain(|pending| pending.group != Some(*group));
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_query(&mut self, query: &MldQuery, now: Instant) {
+        self.version = match query.v2 {
+            Some(_) => MldVersion::V2,
+            None => MldVersion::V1,
+        };
+        let group = (!query.is_general()).then_some(query.group);
+        if let Some(group) = group
+            && !(self.groups.contains(&group) && reported(group))
+        {
+            return;
+        }
+
+        let due = now + self.delay(query.max_response_time(), query.group);
+        // An earlier report covering this already will do
+        if self.pending.iter().any(|pending| {
+            pending.due <= due && (pending.group.is_none() || pending.group == group)
+        }) {
+            return;
+        }
+        self.pending.push(Pending { due, group });
+    }
+
+    /// Reports that are due by `now`
+    pub fn poll(&mut self, now: Instant) -> Vec<Icmpv6Packet> {
+        let mut due = Vec::new();
+        self.pending.retain(|pending| {
+            if pending.due <= now {
+                due.push(*pending);
+            }
+            pending.due > now
+        });
+        due.into_iter()
+            .flat_map(|pending| self.report(pending.group))
+            .collect()
+    }
+
+    /// When [Ipv6Memberships::poll] next has something to send
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|pending| pending.due).min()
+    }
+
+    /// Current-state reports for `group`, or every group
+    fn report(&self, group: Option<Ipv6Addr>) -> Vec<Icmpv6Packet> {
+        let groups: Vec<Ipv6Addr> = match group {
+            Some(group) => vec![group],
+            None => self
+                .groups
+                .iter()
+                .copied()
+                .filter(|&group| reported(group))
+                .collect(),
+        };
+        match self.version {
+            MldVersion::V1 => groups.into_iter().map(Icmpv6Packet::MldV1Report).collect(),
+            MldVersion::V2 if groups.is_empty() => Vec::new(),
+            MldVersion::V2 => vec![Icmpv6Packet::MldV2Report(
+                groups
+                    .into_iter()
+                    .map(|group| MldRecord::exclude_none(MODE_IS_EXCLUDE, group))
+                    .collect(),
+            )],
+        }
+    }
+
+    /// Announce a change of our membership of `group`
+    fn change(&self, group: Ipv6Addr, record_type: u8) -> Icmpv6Packet {
+        match self.version {
+            MldVersion::V1 => Icmpv6Packet::MldV1Report(group),
+            MldVersion::V2 => {
+                Icmpv6Packet::MldV2Report(vec![MldRecord::exclude_none(record_type, group)])
+            }
+        }
+    }
+
+    /// A random delay up to `max`
+    fn delay(&self, max: Duration, salt: Ipv6Addr) -> Duration {
+        let millis = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+        if millis == 0 {
+            return Duration::ZERO;
+        }
+        let random = self.random.hash_one((salt, self.pending.len()));
+        Duration::from_millis(random % millis)
+    }
+}
+
+/// Whether we report listening to `group`: not for all-nodes, nor for
+/// interface-local groups, which never leave the node (RFC 3810 §6)
+fn reported(group: Ipv6Addr) -> bool {
+    let scope = group.octets()[1] & 0x0f;
+    group != ndp::ALL_NODES && scope > 1
+}
+
+/// Where an MLD message we send goes
+pub fn destination(message: &Icmpv6Packet) -> Ipv6Addr {
+    match message {
+        Icmpv6Packet::MldV1Report(group) => *group,
+        Icmpv6Packet::MldDone(_) => ndp::ALL_ROUTERS,
+        _ => mld::ALL_MLDV2_ROUTERS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::mld::QueryV2;
+
+    const GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+    fn general_query(v2: bool) -> Icmpv6Packet {
+        Icmpv6Packet::MldQuery(MldQuery {
+            max_response_code: 10000,
+            group: Ipv6Addr::UNSPECIFIED,
+            v2: v2.then(|| QueryV2 {
+                suppress_router_processing: false,
+                robustness: 2,
+                interval_code: 125,
+                sources: Vec::new(),
+            }),
+        })
+    }
+
+    #[test]
+    fn join_and_leave() -> Result<()> {
+        let mut groups = Ipv6Memberships::new();
+        let now = Instant::now();
+        assert!(groups.join("fe80::1".parse()?, now).is_err());
+        assert_eq!(groups.join(ndp::ALL_NODES, now)?, None);
+
+        let report = groups.join(GROUP, now)?.unwrap();
+        assert_eq!(destination(&report), mld::ALL_MLDV2_ROUTERS);
+        assert!(groups.is_member(GROUP));
+        assert_eq!(groups.join(GROUP, now)?, None);
+
+        // The report is repeated within a second
+        let later = now + UNSOLICITED_REPORT_INTERVAL;
+        assert!(groups.next_deadline().unwrap() <= later);
+        assert_eq!(groups.poll(later).len(), 1);
+        assert_eq!(groups.next_deadline(), None);
+
+        let Icmpv6Packet::MldV2Report(records) = groups.leave(GROUP).unwrap() else {
+            panic!("Wrong MLD type!");
+        };
+        assert_eq!(records[0].record_type, CHANGE_TO_INCLUDE);
+        assert!(!groups.is_member(GROUP));
+        assert_eq!(groups.leave(GROUP), None);
+        Ok(())
+    }
+
+    #[test]
+    fn answers_queries() -> Result<()> {
+        let mut groups = Ipv6Memberships::new();
+        let now = Instant::now();
+        groups.join(GROUP, now)?;
+        groups.join(ndp::solicited_node("fe80::1".parse()?), now)?;
+        groups.poll(now + UNSOLICITED_REPORT_INTERVAL);
+
+        groups.handle(&general_query(true), now);
+        let reports = groups.poll(now + Duration::from_secs(10));
+        let [Icmpv6Packet::MldV2Report(records)] = reports.as_slice() else {
+            panic!("Expected one v2 report, got {reports:?}");
+        };
+        assert_eq!(records.len(), 2);
+
+        // A v1 querier gets a v1 report per group, unless someone beats us
+        groups.handle(&general_query(false), now);
+        assert_eq!(groups.version(), MldVersion::V1);
+        let reports = groups.poll(now + Duration::from_secs(10));
+        assert_eq!(reports.len(), 2);
+        assert!(reports.contains(&Icmpv6Packet::MldV1Report(GROUP)));
+        assert_eq!(destination(&groups.leave(GROUP).unwrap()), ndp::ALL_ROUTERS);
+
+        groups.join(GROUP, now)?;
+        groups.handle(
+            &Icmpv6Packet::MldQuery(MldQuery {
+                max_response_code: 10000,
+                group: GROUP,
+                v2: None,
+            }),
+            now,
+        );
+        groups.handle(&Icmpv6Packet::MldV1Report(GROUP), now);
+        assert!(groups.poll(now + Duration::from_secs(10)).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+//! Source NAT (masquerading): hosts behind us share our external address
+//!
+//! Outgoing TCP and UDP flows get their source port, and ICMP echoes their
+//! identifier, rewritten to one we allocate; replies to that port are
+//! rewritten back. Mappings are per internal address and port, whoever the
+//! remote end is (RFC 4787 endpoint-independent mapping), and are tracked by
+//! [Conntrack], which forgets them after sitting idle. ICMP errors about
+//! translated flows aren't rewritten.
+use crate::checksum;
+use crate::conntrack::{self, Conntrack, Flow, FlowEntry};
+use crate::layer3::{IpProtocol, Ipv4Packet};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+/// Ports handed out by default: the dynamic range
+const DEFAULT_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// An address and port (or ICMP identifier)
+pub type Endpoint = (Ipv4Addr, u16);
+
+/// One translated flow
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct NatMapping {
+    pub protocol: IpProtocol,
+    pub internal: Endpoint,
+    pub external_port: u16,
+    last_seen: Instant,
+}
+
+/// A masquerading NAT and its table of mappings
+#[derive(Debug)]
+pub struct Nat {
+    external: Ipv4Addr,
+    ports: RangeInclusive<u16>,
+    next_port: u16,
+    /// External ports, by a flow from the internal endpoint to anywhere
+    conntrack: Conntrack<u16>,
+    /// Internal endpoint by protocol and external port
+    reverse: HashMap<(IpProtocol, u16), Endpoint>,
+}
+
+impl Nat {
+    /// Translate to `external`, which should be our address on the outside
+    pub fn new(external: Ipv4Addr) -> Self {
+        Self {
+            external,
+            ports: DEFAULT_PORTS,
+            next_port: *DEFAULT_PORTS.start(),
+            conntrack: Conntrack::new(),
+            reverse: HashMap::new(),
+        }
+    }
+
+    /// Allocate external ports from `ports`
+    #[must_use]
+    pub fn set_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.next_port = *ports.start();
+        self.ports = ports;
+        self
+    }
+
+    /// Track mappings with `conntrack`, e.g. to time them out differently
+    #[must_use]
+    pub fn set_conntrack(mut self, conntrack: Conntrack<u16>) -> Self {
+        self.conntrack = conntrack;
+        self
+    }
+
+    /// The mappings' flows, with their external ports
+    pub const fn conntrack(&self) -> &Conntrack<u16> {
+        &self.conntrack
+    }
+
+    pub const fn external(&self) -> Ipv4Addr {
+        self.external
+    }
+
+    /// Rewrite the source of a packet leaving for the outside, creating a
+    /// mapping if the flow is new
+    pub fn outbound(&mut self, packet: &mut Ipv4Packet, now: Instant) -> Result<()> {
+        let Some(offset) = port_offset(packet, true) else {
+            bail!("NAT: can't translate {:?} packet", packet.protocol);
+        };
+        let internal = (packet.source, read_port(packet, offset));
+        let key = mapping_flow(packet.protocol, internal);
+
+        let seen = self.conntrack.see(&key, conntrack::closes(packet), now);
+        let external_port = match seen.and_then(|_| self.conntrack.get(&key, now)) {
+            Some(entry) => *entry.value(),
+            None => {
+                if let Some(stale) = self.conntrack.remove(&key) {
+                    self.forget(&stale);
+                }
+                let external_port = self.allocate(packet.protocol, now)?;
+                if let Some(evicted) = self.conntrack.insert(key, external_port, now) {
+                    self.forget(&evicted);
+                }
+                self.reverse
+                    .insert((packet.protocol, external_port), internal);
+                external_port
+            }
+        };
+
+        let external = (self.external, external_port);
+        packet.source = self.external;
+        write_port(packet, offset, external_port);
+        update_checksum(packet, internal, external);
+        Ok(())
+    }
+
+    /// Rewrite the destination of a packet arriving from the outside back to
+    /// the internal host, returning false if no mapping matches it
+    pub fn inbound(&mut self, packet: &mut Ipv4Packet, now: Instant) -> bool {
+        if packet.destination != self.external {
+            return false;
+        }
+        let Some(offset) = port_offset(packet, false) else {
+            return false;
+        };
+        let port = read_port(packet, offset);
+        let Some(&internal) = self.reverse.get(&(packet.protocol, port)) else {
+            return false;
+        };
+        let key = mapping_flow(packet.protocol, internal);
+        if self.conntrack.get(&key, now).map(FlowEntry::value) != Some(&port) {
+            return false;
+        }
+        self.conntrack.see(&key, conntrack::closes(packet), now);
+
+        packet.destination = internal.0;
+        write_port(packet, offset, internal.1);
+        update_checksum(packet, (self.external, port), internal);
+        true
+    }
+
+    /// Forget mappings that have been idle too long
+    pub fn expire(&mut self, now: Instant) {
+        for entry in self.conntrack.expire(now) {
+            self.forget(&entry);
+        }
+    }
+
+    pub fn mappings(&self) -> impl Iterator<Item = NatMapping> {
+        self.conntrack.flows().map(|entry| {
+            let internal = entry.flow().source;
+            NatMapping {
+                protocol: entry.flow().protocol,
+                internal: (*internal.ip(), internal.port()),
+                external_port: *entry.value(),
+                last_seen: entry.last_seen(),
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.conntrack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conntrack.is_empty()
+    }
+
+    /// Free the external port of a mapping that's no longer tracked
+    fn forget(&mut self, entry: &FlowEntry<u16>) {
+        self.reverse
+            .remove(&(entry.flow().protocol, *entry.value()));
+    }
+
+    /// Find a free external port, expiring idle mappings if there isn't one
+    fn allocate(&mut self, protocol: IpProtocol, now: Instant) -> Result<u16> {
+        if let Some(port) = self.free_port(protocol) {
+            return Ok(port);
+        }
+        self.expire(now);
+        match self.free_port(protocol) {
+            Some(port) => Ok(port),
+            None => bail!("NAT: out of {protocol:?} ports"),
+        }
+    }
+
+    fn free_port(&mut self, protocol: IpProtocol) -> Option<u16> {
+        let (start, end) = (*self.ports.start(), *self.ports.end());
+        for _ in self.ports.clone() {
+            let port = self.next_port;
+            self.next_port = if port >= end { start } else { port + 1 };
+            if !self.reverse.contains_key(&(protocol, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+/// The flow a mapping's tracked as: from the internal endpoint, to anywhere
+fn mapping_flow(protocol: IpProtocol, internal: Endpoint) -> Flow {
+    let anywhere = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    Flow::new(
+        protocol,
+        SocketAddrV4::new(internal.0, internal.1),
+        anywhere,
+    )
+}
+
+/// Offset in the payload of the port on our side of the NAT: the source
+/// port going out, the destination port coming in, or the echo identifier
+fn port_offset(packet: &Ipv4Packet, outbound: bool) -> Option<usize> {
+    let (offset, min_length) = match packet.protocol {
+        IpProtocol::Tcp => (if outbound { 0 } else { 2 }, 20),
+        IpProtocol::Udp => (if outbound { 0 } else { 2 }, 8),
+        IpProtocol::Icmp => {
+            let echo = if outbound {
+                ICMP_ECHO_REQUEST
+            } else {
+                ICMP_ECHO_REPLY
+            };
+            if packet.data.first() != Some(&echo) {
+                return None;
+            }
+            (4, 8)
+        }
+        _ => return None,
+    };
+    (packet.data.len() >= min_length).then_some(offset)
+}
+
+fn read_port(packet: &Ipv4Packet, offset: usize) -> u16 {
+    u16::from_be_bytes([packet.data[offset], packet.data[offset + 1]])
+}
+
+fn write_port(packet: &mut Ipv4Packet, offset: usize, port: u16) {
+    packet.data[offset..offset + 2].copy_from_slice(&port.to_be_bytes());
+}
+
+/// Patch the header and transport checksums after rewriting an address and
+/// port
+fn update_checksum(packet: &mut Ipv4Packet, old: Endpoint, new: Endpoint) {
+    packet.header_checksum = packet.header_checksum.map(|sum| {
+        let sum = checksum::update_bytes(sum.to_be_bytes(), &old.0.octets(), &new.0.octets());
+        u16::from_be_bytes(sum)
+    });
+    let offset = match packet.protocol {
+        IpProtocol::Tcp => 16,
+        // Zero means the sender didn't checksum
+        IpProtocol::Udp if packet.data[6..8] == [0, 0] => return,
+        IpProtocol::Udp => 6,
+        IpProtocol::Icmp => 2,
+        _ => return,
+    };
+    let mut sum = [packet.data[offset], packet.data[offset + 1]];
+    // TCP and UDP cover the addresses through a pseudo-header; ICMP doesn't
+    if packet.protocol != IpProtocol::Icmp {
+        sum = checksum::update_bytes(sum, &old.0.octets(), &new.0.octets());
+    }
+    sum = checksum::update_bytes(sum, &old.1.to_be_bytes(), &new.1.to_be_bytes());
+    if packet.protocol == IpProtocol::Udp && sum == [0, 0] {
+        sum = [0xff, 0xff];
+    }
+    packet.data[offset..offset + 2].copy_from_slice(&sum);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    /// Compute the transport checksum from scratch
+    fn fix_checksum(packet: &mut Ipv4Packet) {
+        let offset = match packet.protocol {
+            IpProtocol::Tcp => 16,
+            // Zero means the sender didn't checksum
+            IpProtocol::Udp if packet.data[6..8] == [0, 0] => return,
+            IpProtocol::Udp => 6,
+            IpProtocol::Icmp => 2,
+            _ => return,
+        };
+        packet.data[offset..offset + 2].fill(0);
+
+        let mut hasher = internet_checksum::Checksum::new();
+        // TCP and UDP cover a pseudo-header; ICMP doesn't
+        if packet.protocol != IpProtocol::Icmp {
+            hasher.add_bytes(&packet.source.octets());
+            hasher.add_bytes(&packet.destination.octets());
+            hasher.add_bytes(&[0, packet.protocol.into()]);
+            hasher.add_bytes(&(packet.data.len() as u16).to_be_bytes());
+        }
+        hasher.add_bytes(&packet.data);
+        let mut checksum = hasher.checksum();
+        if packet.protocol == IpProtocol::Udp && checksum == [0, 0] {
+            checksum = [0xff, 0xff];
+        }
+        packet.data[offset..offset + 2].copy_from_slice(&checksum);
+    }
+
+    fn udp(source: Ipv4Addr, destination: Ipv4Addr, ports: [u16; 2]) -> Ipv4Packet {
+        let [a, b] = ports[0].to_be_bytes();
+        let [c, d] = ports[1].to_be_bytes();
+        let mut packet = Ipv4Packet::builder(source, destination, IpProtocol::Udp)
+            .set_data([a, b, c, d, 0, 10, 0xff, 0xff, b'h', b'i'])
+            .build()
+            .unwrap();
+        fix_checksum(&mut packet);
+        packet
+    }
+
+    /// True if the UDP checksum (pseudo-header included) verifies
+    fn checksum_ok(packet: &Ipv4Packet) -> bool {
+        let mut hasher = internet_checksum::Checksum::new();
+        hasher.add_bytes(&packet.source.octets());
+        hasher.add_bytes(&packet.destination.octets());
+        hasher.add_bytes(&[0, packet.protocol.into()]);
+        hasher.add_bytes(&(packet.data.len() as u16).to_be_bytes());
+        hasher.add_bytes(&packet.data);
+        hasher.checksum() == [0, 0]
+    }
+
+    #[test]
+    fn translates_both_ways() -> Result<()> {
+        let mut nat = Nat::new(EXTERNAL);
+        let now = Instant::now();
+
+        let mut out = udp(HOST, SERVER, [5000, 53]);
+        nat.outbound(&mut out, now)?;
+        assert_eq!(out.source, EXTERNAL);
+        let port = read_port(&out, 0);
+        assert!(DEFAULT_PORTS.contains(&port));
+        assert!(checksum_ok(&out));
+
+        // Same flow, same mapping
+        let mut again = udp(HOST, SERVER, [5000, 53]);
+        nat.outbound(&mut again, now)?;
+        assert_eq!(read_port(&again, 0), port);
+        assert_eq!(nat.len(), 1);
+
+        let mut reply = udp(SERVER, EXTERNAL, [53, port]);
+        assert!(nat.inbound(&mut reply, now));
+        assert_eq!(reply.destination, HOST);
+        assert_eq!(read_port(&reply, 2), 5000);
+        assert!(checksum_ok(&reply));
+
+        let mut stray = udp(SERVER, EXTERNAL, [53, port.wrapping_add(1)]);
+        assert!(!nat.inbound(&mut stray, now));
+        Ok(())
+    }
+
+    #[test]
+    fn translates_echo_identifiers() -> Result<()> {
+        let mut nat = Nat::new(EXTERNAL);
+        let now = Instant::now();
+        let echo = |source, destination, icmp_type| {
+            let mut packet = Ipv4Packet::builder(source, destination, IpProtocol::Icmp)
+                .set_data([icmp_type, 0, 0, 0, 0x12, 0x34, 0, 1])
+                .build()
+                .unwrap();
+            fix_checksum(&mut packet);
+            packet
+        };
+
+        let mut request = echo(HOST, SERVER, ICMP_ECHO_REQUEST);
+        nat.outbound(&mut request, now)?;
+        let identifier = read_port(&request, 4);
+        assert_eq!(internet_checksum::checksum(&request.data), [0, 0]);
+
+        let mut reply = echo(SERVER, EXTERNAL, ICMP_ECHO_REPLY);
+        write_port(&mut reply, 4, identifier);
+        fix_checksum(&mut reply);
+        assert!(nat.inbound(&mut reply, now));
+        assert_eq!(read_port(&reply, 4), 0x1234);
+        Ok(())
+    }
+
+    #[test]
+    fn mappings_expire_and_ports_run_out() -> Result<()> {
+        let mut nat = Nat::new(EXTERNAL).set_ports(40000..=40001);
+        let now = Instant::now();
+        for port in 1..=2 {
+            nat.outbound(&mut udp(HOST, SERVER, [port, 53]), now)?;
+        }
+        assert!(nat.outbound(&mut udp(HOST, SERVER, [3, 53]), now).is_err());
+
+        // Idle mappings make way for new ones
+        let later = now + conntrack::UDP_TIMEOUT;
+        let mut reply = udp(SERVER, EXTERNAL, [53, 40000]);
+        assert!(!nat.inbound(&mut reply, later));
+        nat.outbound(&mut udp(HOST, SERVER, [3, 53]), later)?;
+        assert_eq!(nat.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+//! The option negotiation automaton shared by LCP and the NCPs (RFC 1661)
+use anyhow::{Result, bail};
+
+pub mod code {
+    pub const CONFIGURE_REQUEST: u8 = 1;
+    pub const CONFIGURE_ACK: u8 = 2;
+    pub const CONFIGURE_NAK: u8 = 3;
+    pub const CONFIGURE_REJECT: u8 = 4;
+    pub const TERMINATE_REQUEST: u8 = 5;
+    pub const TERMINATE_ACK: u8 = 6;
+    pub const CODE_REJECT: u8 = 7;
+    pub const PROTOCOL_REJECT: u8 = 8;
+    pub const ECHO_REQUEST: u8 = 9;
+    pub const ECHO_REPLY: u8 = 10;
+    pub const DISCARD_REQUEST: u8 = 11;
+}
+
+/// A LCP/NCP packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlPacket {
+    pub code: u8,
+    pub identifier: u8,
+    pub data: Vec<u8>,
+}
+
+impl ControlPacket {
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let [code, identifier, length_high, length_low, ..] = *raw else {
+            bail!("PPP: truncated control packet");
+        };
+        let length = u16::from_be_bytes([length_high, length_low]) as usize;
+        if length < 4 || length > raw.len() {
+            bail!("PPP: bad control packet length: {length}");
+        }
+        // Anything past `length` is padding
+        Ok(Self {
+            code,
+            identifier,
+            data: raw[4..length].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let length = 4 + self.data.len() as u16;
+        let mut raw = vec![self.code, self.identifier];
+        raw.extend_from_slice(&length.to_be_bytes());
+        raw.extend_from_slice(&self.data);
+        raw
+    }
+}
+
+/// A single configuration option (type-length-value)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigOption {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl ConfigOption {
+    pub fn new(kind: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            data: data.into(),
+        }
+    }
+
+    pub fn parse_list(mut raw: &[u8]) -> Result<Vec<Self>> {
+        let mut options = Vec::new();
+        while !raw.is_empty() {
+            let [kind, length, ..] = *raw else {
+                bail!("PPP: truncated option");
+            };
+            let length = length as usize;
+            if length < 2 || length > raw.len() {
+                bail!("PPP: bad option length: {length}");
+            }
+            options.push(Self::new(kind, &raw[2..length]));
+            raw = &raw[length..];
+        }
+        Ok(options)
+    }
+
+    pub fn list_to_bytes(options: &[Self]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for option in options {
+            raw.push(option.kind);
+            raw.push(2 + option.data.len() as u8);
+            raw.extend_from_slice(&option.data);
+        }
+        raw
+    }
+}
+
+/// How we respond to the peer's Configure-Request
+pub enum Verdict {
+    Ack,
+    Nak(Vec<ConfigOption>),
+    Reject(Vec<ConfigOption>),
+}
+
+/// The protocol-specific half of a control protocol
+pub trait Options {
+    /// Options to send in our Configure-Request
+    fn request(&self) -> Vec<ConfigOption>;
+    /// Judge the peer's Configure-Request
+    fn judge(&mut self, options: &[ConfigOption]) -> Verdict;
+    /// The peer Nak'd our request, suggesting these values instead
+    fn nakked(&mut self, options: &[ConfigOption]);
+    /// The peer doesn't recognize these options at all
+    fn rejected(&mut self, options: &[ConfigOption]);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Not started or terminated
+    Closed,
+    /// Our Configure-Request is outstanding
+    RequestSent,
+    /// Peer acked our request, we haven't acked theirs
+    AckReceived,
+    /// We acked the peer's request, they haven't acked ours
+    AckSent,
+    Opened,
+}
+
+/// Simplified RFC 1661 option negotiation automaton
+///
+/// There's no Starting/Stopping distinction since we only ever run on a
+/// single always-up serial link
+pub struct Automaton<O> {
+    state: State,
+    options: O,
+    identifier: u8,
+    /// Identifier of our outstanding Configure-Request
+    request_identifier: u8,
+}
+
+impl<O: Options> Automaton<O> {
+    pub const fn new(options: O) -> Self {
+        Self {
+            state: State::Closed,
+            options,
+            identifier: 0,
+            request_identifier: 0,
+        }
+    }
+
+    pub const fn state(&self) -> State {
+        self.state
+    }
+
+    pub const fn is_opened(&self) -> bool {
+        matches!(self.state, State::Opened)
+    }
+
+    pub const fn options(&self) -> &O {
+        &self.options
+    }
+
+    fn next_identifier(&mut self) -> u8 {
+        self.identifier = self.identifier.wrapping_add(1);
+        self.identifier
+    }
+
+    fn configure_request(&mut self) -> ControlPacket {
+        let identifier = self.next_identifier();
+        self.request_identifier = identifier;
+        ControlPacket {
+            code: code::CONFIGURE_REQUEST,
+            identifier,
+            data: ConfigOption::list_to_bytes(&self.options.request()),
+        }
+    }
+
+    /// Start negotiating, returning our initial Configure-Request
+    pub fn open(&mut self) -> ControlPacket {
+        self.state = State::RequestSent;
+        self.configure_request()
+    }
+
+    /// Restart timer expired - resend our Configure-Request if it's still
+    /// outstanding
+    pub fn timeout(&mut self) -> Option<ControlPacket> {
+        match self.state {
+            State::RequestSent | State::AckSent => Some(self.configure_request()),
+            State::AckReceived => {
+                self.state = State::RequestSent;
+                Some(self.configure_request())
+            }
+            State::Closed | State::Opened => None,
+        }
+    }
+
+    /// Build a Protocol-Reject for a frame of an unsupported protocol
+    pub fn protocol_reject(&mut self, protocol: u16, information: &[u8]) -> ControlPacket {
+        let mut data = protocol.to_be_bytes().to_vec();
+        data.extend_from_slice(information);
+        data.truncate(1500);
+        ControlPacket {
+            code: code::PROTOCOL_REJECT,
+            identifier: self.next_identifier(),
+            data,
+        }
+    }
+
+    /// Handle a packet from the peer, returning packets to send in response
+    pub fn receive(&mut self, packet: ControlPacket) -> Result<Vec<ControlPacket>> {
+        let reply = |code, data| ControlPacket {
+            code,
+            identifier: packet.identifier,
+            data,
+        };
+        let mut out = Vec::new();
+
+        match packet.code {
+            code::CONFIGURE_REQUEST => {
+                let options = ConfigOption::parse_list(&packet.data)?;
+                // Peer is renegotiating - we have to as well
+                if matches!(self.state, State::Opened | State::Closed) {
+                    out.push(self.open());
+                }
+                match self.options.judge(&options) {
+                    Verdict::Ack => {
+                        out.push(reply(code::CONFIGURE_ACK, packet.data.clone()));
+                        self.state = match self.state {
+                            State::AckReceived => State::Opened,
+                            _ => State::AckSent,
+                        };
+                    }
+                    Verdict::Nak(options) => {
+                        let data = ConfigOption::list_to_bytes(&options);
+                        out.push(reply(code::CONFIGURE_NAK, data));
+                        if self.state == State::AckSent {
+                            self.state = State::RequestSent;
+                        }
+                    }
+                    Verdict::Reject(options) => {
+                        let data = ConfigOption::list_to_bytes(&options);
+                        out.push(reply(code::CONFIGURE_REJECT, data));
+                        if self.state == State::AckSent {
+                            self.state = State::RequestSent;
+                        }
+                    }
+                }
+            }
+            code::CONFIGURE_ACK => {
+                if packet.identifier != self.request_identifier {
+                    // Stale
+                    return Ok(out);
+                }
+                self.state = match self.state {
+                    State::RequestSent => State::AckReceived,
+                    State::AckSent => State::Opened,
+                    State::AckReceived | State::Opened => {
+                        out.push(self.configure_request());
+                        State::RequestSent
+                    }
+                    State::Closed => State::Closed,
+                };
+            }
+            code::CONFIGURE_NAK | code::CONFIGURE_REJECT => {
+                if packet.identifier != self.request_identifier {
+                    return Ok(out);
+                }
+                let options = ConfigOption::parse_list(&packet.data)?;
+                if packet.code == code::CONFIGURE_NAK {
+                    self.options.nakked(&options);
+                } else {
+                    self.options.rejected(&options);
+                }
+                if self.state != State::Closed {
+                    if self.state != State::AckSent {
+                        self.state = State::RequestSent;
+                    }
+                    out.push(self.configure_request());
+                }
+            }
+            code::TERMINATE_REQUEST => {
+                self.state = State::Closed;
+                out.push(reply(code::TERMINATE_ACK, Vec::new()));
+            }
+            code::TERMINATE_ACK | code::CODE_REJECT | code::PROTOCOL_REJECT => {}
+            code::ECHO_REQUEST => {
+                if self.state == State::Opened {
+                    out.push(reply(code::ECHO_REPLY, packet.data.clone()));
+                }
+            }
+            code::ECHO_REPLY | code::DISCARD_REQUEST => {}
+            _ => {
+                let mut data = packet.to_bytes();
+                data.truncate(1500);
+                out.push(ControlPacket {
+                    code: code::CODE_REJECT,
+                    identifier: self.next_identifier(),
+                    data,
+                });
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Requests nothing, acks everything
+    struct Agreeable;
+
+    impl Options for Agreeable {
+        fn request(&self) -> Vec<ConfigOption> {
+            Vec::new()
+        }
+        fn judge(&mut self, _options: &[ConfigOption]) -> Verdict {
+            Verdict::Ack
+        }
+        fn nakked(&mut self, _options: &[ConfigOption]) {}
+        fn rejected(&mut self, _options: &[ConfigOption]) {}
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = ControlPacket {
+            code: code::CONFIGURE_REQUEST,
+            identifier: 7,
+            data: ConfigOption::list_to_bytes(&[ConfigOption::new(1, [5, 220])]),
+        };
+        let raw = packet.to_bytes();
+        assert_eq!(raw, [1, 7, 0, 8, 1, 4, 5, 220]);
+        assert_eq!(ControlPacket::parse(&raw).unwrap(), packet);
+        assert_eq!(
+            ConfigOption::parse_list(&packet.data).unwrap(),
+            [ConfigOption::new(1, [5, 220])]
+        );
+    }
+
+    #[test]
+    fn two_automatons_open() {
+        let mut a = Automaton::new(Agreeable);
+        let mut b = Automaton::new(Agreeable);
+
+        let mut to_b = vec![a.open()];
+        let mut to_a = vec![b.open()];
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let mut next_to_a = Vec::new();
+            let mut next_to_b = Vec::new();
+            for packet in to_b.drain(..) {
+                next_to_a.extend(b.receive(packet).unwrap());
+            }
+            for packet in to_a.drain(..) {
+                next_to_b.extend(a.receive(packet).unwrap());
+            }
+            to_a = next_to_a;
+            to_b = next_to_b;
+        }
+
+        assert!(a.is_opened());
+        assert!(b.is_opened());
+    }
+}
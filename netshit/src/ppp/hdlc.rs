@@ -0,0 +1,157 @@
+//! HDLC-like framing for PPP (RFC 1662)
+use anyhow::{Result, bail};
+
+const FLAG: u8 = 0x7E;
+const ESCAPE: u8 = 0x7D;
+const ESCAPE_XOR: u8 = 0x20;
+const ADDRESS: u8 = 0xFF;
+const CONTROL: u8 = 0x03;
+
+/// Largest frame we'll buffer - the default MRU plus generous slack
+const MAX_FRAME_SIZE: usize = 4096;
+
+const FCS: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+
+/// Frame a PPP packet
+///
+/// All control characters are escaped, which is always acceptable to the
+/// peer no matter what ACCM it negotiated
+pub fn encode(protocol: u16, information: &[u8]) -> Vec<u8> {
+    let mut raw = vec![ADDRESS, CONTROL];
+    raw.extend_from_slice(&protocol.to_be_bytes());
+    raw.extend_from_slice(information);
+    let fcs = FCS.checksum(&raw);
+    raw.extend_from_slice(&fcs.to_le_bytes());
+
+    let mut frame = Vec::with_capacity(raw.len() + 8);
+    frame.push(FLAG);
+    for byte in raw {
+        if byte < 0x20 || byte == FLAG || byte == ESCAPE {
+            frame.extend_from_slice(&[ESCAPE, byte ^ ESCAPE_XOR]);
+        } else {
+            frame.push(byte);
+        }
+    }
+    frame.push(FLAG);
+    frame
+}
+
+/// Incremental HDLC-like frame decoder
+#[derive(Clone, Debug, Default)]
+pub struct HdlcDecoder {
+    buffer: Vec<u8>,
+    escaped: bool,
+}
+
+impl HdlcDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            escaped: false,
+        }
+    }
+
+    /// Feed a byte to the decoder, returning `(protocol, information)` if
+    /// this byte completed a frame
+    pub fn push(&mut self, byte: u8) -> Result<Option<(u16, Vec<u8>)>> {
+        match byte {
+            FLAG => {
+                self.escaped = false;
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let raw = std::mem::take(&mut self.buffer);
+                return Self::parse(raw).map(Some);
+            }
+            ESCAPE => self.escaped = true,
+            _ if self.escaped => {
+                self.escaped = false;
+                self.buffer.push(byte ^ ESCAPE_XOR);
+            }
+            // Unescaped control characters are inserted by the link, not the peer
+            0..0x20 => {}
+            _ => self.buffer.push(byte),
+        }
+
+        if self.buffer.len() > MAX_FRAME_SIZE {
+            self.buffer.clear();
+            bail!("PPP: frame too large");
+        }
+
+        Ok(None)
+    }
+
+    fn parse(raw: Vec<u8>) -> Result<(u16, Vec<u8>)> {
+        if raw.len() < 4 {
+            bail!("PPP: runt frame");
+        }
+        if FCS.checksum(&raw[..raw.len() - 2]).to_le_bytes() != raw[raw.len() - 2..] {
+            bail!("PPP: bad FCS");
+        }
+        let mut body = &raw[..raw.len() - 2];
+
+        // Address and control fields may be compressed away
+        if body.starts_with(&[ADDRESS, CONTROL]) {
+            body = &body[2..];
+        }
+
+        // Protocol field compression drops the leading zero byte. Protocol
+        // numbers always have an odd low byte and even high byte
+        let (protocol, information) = match body {
+            [first, rest @ ..] if first & 1 == 1 => (u16::from(*first), rest),
+            [high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]), rest),
+            _ => bail!("PPP: missing protocol field"),
+        };
+
+        Ok((protocol, information.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut decoder = HdlcDecoder::new();
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.push(*byte).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let information = [1, FLAG, 2, ESCAPE, 0x11, 0x13, 0xff];
+        let frame = encode(0xc021, &information);
+        assert!(!frame[1..frame.len() - 1].contains(&FLAG));
+        assert_eq!(decode_all(&frame), [(0xc021, information.to_vec())]);
+    }
+
+    #[test]
+    fn compressed_header() {
+        // Frame with ACFC and PFC applied: protocol 0x21 (IPv4), payload [0xAA]
+        let mut raw = vec![0x21, 0xAA];
+        let fcs = FCS.checksum(&raw);
+        raw.extend_from_slice(&fcs.to_le_bytes());
+        let mut frame = vec![FLAG];
+        for byte in raw {
+            if byte < 0x20 || byte == FLAG || byte == ESCAPE {
+                frame.extend_from_slice(&[ESCAPE, byte ^ ESCAPE_XOR]);
+            } else {
+                frame.push(byte);
+            }
+        }
+        frame.push(FLAG);
+
+        assert_eq!(decode_all(&frame), [(0x0021, vec![0xAA])]);
+    }
+
+    #[test]
+    fn bad_fcs() {
+        let mut frame = encode(0x0021, &[1, 2, 3]);
+        frame[5] ^= 1;
+        let mut decoder = HdlcDecoder::new();
+        let results: Vec<_> = frame.iter().map(|byte| decoder.push(*byte)).collect();
+        assert!(results.last().unwrap().is_err());
+    }
+}
@@ -0,0 +1,421 @@
+//! Point-to-Point Protocol over a serial byte stream
+//!
+//! Just enough PPP to bring up IPv4 with pppd on the other end of a PTY:
+//! HDLC-like framing, LCP, and IPCP address assignment. We never
+//! authenticate, so run pppd with `noauth`.
+mod control;
+mod hdlc;
+use crate::layer3::Ipv4Packet;
+use anyhow::{Result, bail};
+use control::{Automaton, ConfigOption, ControlPacket, Options, Verdict};
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod protocol {
+    pub const IPV4: u16 = 0x0021;
+    pub const IPCP: u16 = 0x8021;
+    pub const LCP: u16 = 0xc021;
+}
+
+mod lcp_option {
+    pub const MRU: u8 = 1;
+    pub const ACCM: u8 = 2;
+    pub const MAGIC_NUMBER: u8 = 5;
+}
+
+mod ipcp_option {
+    pub const IP_ADDRESS: u8 = 3;
+}
+
+/// Link Control Protocol options
+struct LcpOptions {
+    magic_number: Option<u32>,
+}
+
+impl Options for LcpOptions {
+    fn request(&self) -> Vec<ConfigOption> {
+        self.magic_number
+            .map(|magic| ConfigOption::new(lcp_option::MAGIC_NUMBER, magic.to_be_bytes()))
+            .into_iter()
+            .collect()
+    }
+
+    fn judge(&mut self, options: &[ConfigOption]) -> Verdict {
+        // We always send full headers and escape all control characters, so
+        // MRU and ACCM need no action. Anything else (auth, compression)
+        // we don't support
+        let rejects: Vec<_> = options
+            .iter()
+            .filter(|option| {
+                !matches!(
+                    option.kind,
+                    lcp_option::MRU | lcp_option::ACCM | lcp_option::MAGIC_NUMBER
+                )
+            })
+            .cloned()
+            .collect();
+
+        if rejects.is_empty() {
+            Verdict::Ack
+        } else {
+            Verdict::Reject(rejects)
+        }
+    }
+
+    fn nakked(&mut self, options: &[ConfigOption]) {
+        if options
+            .iter()
+            .any(|option| option.kind == lcp_option::MAGIC_NUMBER)
+        {
+            // Possibly a looped-back link - pick another number
+            self.magic_number = Some(new_magic_number());
+        }
+    }
+
+    fn rejected(&mut self, options: &[ConfigOption]) {
+        if options
+            .iter()
+            .any(|option| option.kind == lcp_option::MAGIC_NUMBER)
+        {
+            self.magic_number = None;
+        }
+    }
+}
+
+/// Internet Protocol Control Protocol options
+struct IpcpOptions {
+    local_address: Ipv4Addr,
+    /// Address to hand out if the peer asks for one
+    assign_address: Option<Ipv4Addr>,
+    /// Address the peer acked
+    peer_address: Option<Ipv4Addr>,
+}
+
+impl Options for IpcpOptions {
+    fn request(&self) -> Vec<ConfigOption> {
+        vec![ConfigOption::new(
+            ipcp_option::IP_ADDRESS,
+            self.local_address.octets(),
+        )]
+    }
+
+    fn judge(&mut self, options: &[ConfigOption]) -> Verdict {
+        let mut rejects = Vec::new();
+        let mut naks = Vec::new();
+        let mut address = None;
+
+        for option in options {
+            match (option.kind, <[u8; 4]>::try_from(option.data.as_slice())) {
+                (ipcp_option::IP_ADDRESS, Ok(octets)) => {
+                    let requested = Ipv4Addr::from(octets);
+                    match self.assign_address {
+                        Some(assign) if assign != requested => {
+                            naks.push(ConfigOption::new(ipcp_option::IP_ADDRESS, assign.octets()))
+                        }
+                        // Peer wants an address and we don't have one to give
+                        None if requested.is_unspecified() => rejects.push(option.clone()),
+                        _ => address = Some(requested),
+                    }
+                }
+                _ => rejects.push(option.clone()),
+            }
+        }
+
+        if !rejects.is_empty() {
+            Verdict::Reject(rejects)
+        } else if !naks.is_empty() {
+            Verdict::Nak(naks)
+        } else {
+            self.peer_address = address.or(self.peer_address);
+            Verdict::Ack
+        }
+    }
+
+    fn nakked(&mut self, options: &[ConfigOption]) {
+        for option in options {
+            if let (ipcp_option::IP_ADDRESS, Ok(octets)) =
+                (option.kind, <[u8; 4]>::try_from(option.data.as_slice()))
+            {
+                self.local_address = Ipv4Addr::from(octets);
+            }
+        }
+    }
+
+    fn rejected(&mut self, _options: &[ConfigOption]) {
+        // Peer doesn't do addresses - nothing we can do but carry on
+    }
+}
+
+fn new_magic_number() -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or_default();
+    nanos ^ std::process::id().rotate_left(16)
+}
+
+/// PPP link configuration
+#[derive(Clone, Debug)]
+pub struct PppConfig {
+    /// Our address. If unspecified (0.0.0.0), ask the peer to assign one
+    pub local_address: Ipv4Addr,
+    /// Address to assign the peer if it asks for one
+    pub peer_address: Option<Ipv4Addr>,
+    /// How long to wait before resending a Configure-Request
+    pub restart_interval: Duration,
+    /// How many times to resend a Configure-Request before giving up
+    pub max_configure: u32,
+}
+
+impl Default for PppConfig {
+    fn default() -> Self {
+        Self {
+            local_address: Ipv4Addr::UNSPECIFIED,
+            peer_address: None,
+            restart_interval: Duration::from_secs(3),
+            max_configure: 10,
+        }
+    }
+}
+
+/// IPv4 over PPP over a byte stream, e.g. a [virtser::async_io::AsyncVirtSer]
+pub struct PppLink<T> {
+    io: T,
+    decoder: hdlc::HdlcDecoder,
+    config: PppConfig,
+    lcp: Automaton<LcpOptions>,
+    ipcp: Automaton<IpcpOptions>,
+    /// IPv4 packets that arrived while we were busy negotiating
+    received: VecDeque<Ipv4Packet>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> PppLink<T> {
+    pub fn new(io: T, config: PppConfig) -> Self {
+        let lcp = Automaton::new(LcpOptions {
+            magic_number: Some(new_magic_number()),
+        });
+        let ipcp = Automaton::new(IpcpOptions {
+            local_address: config.local_address,
+            assign_address: config.peer_address,
+            peer_address: None,
+        });
+        Self {
+            io,
+            decoder: hdlc::HdlcDecoder::new(),
+            config,
+            lcp,
+            ipcp,
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Our address, as negotiated
+    pub fn local_address(&self) -> Ipv4Addr {
+        self.ipcp.options().local_address
+    }
+
+    /// The peer's address, as negotiated
+    pub fn peer_address(&self) -> Option<Ipv4Addr> {
+        self.ipcp.options().peer_address
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.lcp.is_opened() && self.ipcp.is_opened()
+    }
+
+    /// Bring up LCP then IPCP
+    pub async fn negotiate(&mut self) -> Result<()> {
+        let request = self.lcp.open();
+        self.send_control(protocol::LCP, request).await?;
+
+        let mut retries = 0;
+        while !self.is_up() {
+            match tokio::time::timeout(self.config.restart_interval, self.recv_frame()).await {
+                Ok(frame) => {
+                    let (protocol, information) = frame?;
+                    self.handle_frame(protocol, information).await?;
+                }
+                Err(_elapsed) => {
+                    retries += 1;
+                    if retries > self.config.max_configure {
+                        bail!("PPP: negotiation timed out");
+                    }
+                    if let Some(request) = self.lcp.timeout() {
+                        self.send_control(protocol::LCP, request).await?;
+                    } else if let Some(request) = self.ipcp.timeout() {
+                        self.send_control(protocol::IPCP, request).await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the next IPv4 packet, handling control traffic as it comes
+    pub async fn recv(&mut self) -> Result<Ipv4Packet> {
+        loop {
+            if let Some(packet) = self.received.pop_front() {
+                return Ok(packet);
+            }
+            let (protocol, information) = self.recv_frame().await?;
+            self.handle_frame(protocol, information).await?;
+        }
+    }
+
+    /// Send an IPv4 packet
+    pub async fn send(&mut self, packet: &mut Ipv4Packet) -> Result<()> {
+        if !self.is_up() {
+            bail!("PPP: link is not up");
+        }
+        let mut information = Vec::new();
+        packet.onto_writer(&mut information).await?;
+        self.send_frame(protocol::IPV4, &information).await
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    async fn recv_frame(&mut self) -> Result<(u16, Vec<u8>)> {
+        loop {
+            let byte = self.io.read_u8().await?;
+            // Corrupt frames are silently discarded (RFC 1662 §4.3)
+            if let Ok(Some(frame)) = self.decoder.push(byte) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    async fn send_frame(&mut self, protocol: u16, information: &[u8]) -> Result<()> {
+        self.io
+            .write_all(&hdlc::encode(protocol, information))
+            .await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    async fn send_control(&mut self, protocol: u16, packet: ControlPacket) -> Result<()> {
+        self.send_frame(protocol, &packet.to_bytes()).await
+    }
+
+    /// Handle a frame from the peer, silently discarding malformed packets
+    /// (RFC 1661 §5)
+    async fn handle_frame(&mut self, protocol: u16, information: Vec<u8>) -> Result<()> {
+        match protocol {
+            protocol::LCP => {
+                let Ok(replies) =
+                    ControlPacket::parse(&information).and_then(|packet| self.lcp.receive(packet))
+                else {
+                    return Ok(());
+                };
+                for reply in replies {
+                    self.send_control(protocol::LCP, reply).await?;
+                }
+                if self.lcp.is_opened() && self.ipcp.state() == control::State::Closed {
+                    let request = self.ipcp.open();
+                    self.send_control(protocol::IPCP, request).await?;
+                }
+            }
+            protocol::IPCP => {
+                let Ok(replies) =
+                    ControlPacket::parse(&information).and_then(|packet| self.ipcp.receive(packet))
+                else {
+                    return Ok(());
+                };
+                for reply in replies {
+                    self.send_control(protocol::IPCP, reply).await?;
+                }
+            }
+            protocol::IPV4 => {
+                if let Ok(packet) = Ipv4Packet::from_reader(information.as_slice()).await {
+                    self.received.push_back(packet);
+                }
+            }
+            _ => {
+                if self.lcp.is_opened() {
+                    let reject = self.lcp.protocol_reject(protocol, &information);
+                    self.send_control(protocol::LCP, reject).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::IpProtocol;
+    use crate::layer3::ipv4::{Dscp, Ecn};
+
+    fn test_packet() -> Ipv4Packet {
+        Ipv4Packet {
+            dscp: Dscp::CS0,
+            ecn: Ecn::NotEct,
+            identification: 1,
+            ttl: 64,
+            protocol: IpProtocol::Udp,
+            source: "10.0.0.1".parse().unwrap(),
+            destination: "10.0.0.2".parse().unwrap(),
+            options: Vec::new(),
+            data: vec![0x7e, 0x7d, 0x00, 0xff],
+            header_checksum: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn discards_bad_frames() -> Result<()> {
+        let (a, mut b) = tokio::io::duplex(4096);
+        let mut link = PppLink::new(a, PppConfig::default());
+
+        let mut packet = test_packet();
+        let mut information = Vec::new();
+        packet.onto_writer(&mut information).await?;
+
+        // Bad FCS
+        let mut corrupt = hdlc::encode(protocol::IPV4, &information);
+        corrupt[10] ^= 0x01;
+        b.write_all(&corrupt).await?;
+        // Runt
+        b.write_all(&[0x7e, 0x21, 0x7e]).await?;
+        // Control packet with a bad length
+        b.write_all(&hdlc::encode(protocol::LCP, &[1, 1, 0, 99]))
+            .await?;
+        b.write_all(&hdlc::encode(protocol::IPV4, &information))
+            .await?;
+
+        assert_eq!(link.recv().await?, packet);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negotiate_and_exchange() -> Result<()> {
+        let (a, b) = tokio::io::duplex(4096);
+        let mut server = PppLink::new(
+            a,
+            PppConfig {
+                local_address: "10.0.0.1".parse()?,
+                peer_address: Some("10.0.0.2".parse()?),
+                ..Default::default()
+            },
+        );
+        let mut client = PppLink::new(b, PppConfig::default());
+
+        let (server_result, client_result) = tokio::join!(server.negotiate(), client.negotiate());
+        server_result?;
+        client_result?;
+
+        assert_eq!(client.local_address().to_string(), "10.0.0.2");
+        assert_eq!(client.peer_address(), Some("10.0.0.1".parse()?));
+        assert_eq!(server.peer_address(), Some("10.0.0.2".parse()?));
+
+        let mut packet = test_packet();
+        server.send(&mut packet).await?;
+        assert_eq!(client.recv().await?, packet);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! Token buckets: letting something happen in bursts, but no more often
+//! than a steady rate overall
+use std::time::{Duration, Instant};
+
+/// Earns a token every `interval`, holding at most `capacity`; each event
+/// takes one
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TokenBucket {
+    interval: Duration,
+    capacity: u32,
+    tokens: u32,
+    /// When tokens were last earned, or `None` before the first is taken
+    refilled: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub const fn new(interval: Duration, capacity: u32) -> Self {
+        Self {
+            interval,
+            capacity,
+            tokens: capacity,
+            refilled: None,
+        }
+    }
+
+    /// Take a token, returning whether there was one
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// Tokens left
+    pub const fn tokens(&self) -> u32 {
+        self.tokens
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let Some(refilled) = self.refilled else {
+            self.refilled = Some(now);
+            return;
+        };
+        if self.interval.is_zero() {
+            self.tokens = self.capacity;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(refilled);
+        let earned = elapsed.as_nanos() / self.interval.as_nanos();
+        if earned == 0 {
+            return;
+        }
+        let earned = u32::try_from(earned).unwrap_or(u32::MAX);
+        self.tokens = self.tokens.saturating_add(earned).min(self.capacity);
+        // Keep the part of an interval not yet earned, unless we're full
+        self.refilled = Some(if self.tokens == self.capacity {
+            now
+        } else {
+            refilled + self.interval * earned
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn bursts_then_limits() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(SECOND, 3);
+        assert!((0..3).all(|_| bucket.take(now)));
+        assert!(!bucket.take(now));
+        assert!(!bucket.take(now + SECOND / 2));
+
+        // A token a second from then on
+        assert!(bucket.take(now + SECOND));
+        assert!(!bucket.take(now + SECOND));
+        assert!(bucket.take(now + SECOND * 2));
+    }
+
+    #[test]
+    fn fills_to_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(SECOND, 3);
+        bucket.take(now);
+        assert!(bucket.take(now + SECOND * 100));
+        assert_eq!(bucket.tokens(), 2);
+
+        // Part of an interval isn't lost
+        let mut bucket = TokenBucket::new(SECOND, 2);
+        bucket.take(now);
+        bucket.take(now);
+        assert!(bucket.take(now + SECOND * 3 / 2));
+        assert!(bucket.take(now + SECOND * 2));
+    }
+}
@@ -0,0 +1,292 @@
+//! IPv6 fragmentation (RFC 8200 §4.5): splitting packets too big for the
+//! link, and putting received fragments back together
+//!
+//! Only packets whose Fragment header comes straight after the IPv6 header
+//! are handled; any other extension headers go in the fragmentable part.
+use crate::layer3::IpProtocol;
+use crate::layer3::ipv6::{FRAGMENT_HEADER_LENGTH, FragmentHeader, HEADER_LENGTH, Ipv6Packet};
+use anyhow::{Result, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// How long fragments wait for the rest to arrive
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Packets reassembled at once; fragments of any more are dropped
+const DEFAULT_CAPACITY: usize = 64;
+/// Largest payload a reassembled packet may have
+const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// Fragments belong to the same packet if they share these
+type Key = (Ipv6Addr, Ipv6Addr, u32);
+
+/// A packet some of whose fragments have arrived
+#[derive(Debug)]
+struct Partial {
+    /// The fragment at offset 0, once it's arrived
+    first: Option<Ipv6Packet>,
+    /// Fragment data by offset
+    pieces: BTreeMap<usize, Vec<u8>>,
+    /// Length of the reassembled data, once the last fragment's arrived
+    length: Option<usize>,
+    received: usize,
+    expires: Instant,
+}
+
+impl Partial {
+    /// Whether `start..end` overlaps any fragment we have
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        let before = self.pieces.range(..start).next_back();
+        let after = self.pieces.range(start..).next();
+        before.is_some_and(|(offset, data)| offset + data.len() > start)
+            || after.is_some_and(|(&offset, _)| offset < end)
+    }
+}
+
+/// Received fragments, waiting to be put back together
+#[derive(Debug)]
+pub struct Reassembler {
+    partials: HashMap<Key, Partial>,
+    capacity: usize,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            partials: HashMap::new(),
+            capacity: DEFAULT_CAPACITY,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Reassemble at most `capacity` packets at once
+    #[must_use]
+    pub const fn set_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Give up on a packet `timeout` after its first fragment arrives
+    #[must_use]
+    pub const fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Packets being reassembled
+    pub fn len(&self) -> usize {
+        self.partials.len()
+    }
+
+    /// Take a fragment, returning the reassembled packet if that was the
+    /// last one missing
+    ///
+    /// Overlapping fragments are an attack or a mistake, so the whole packet
+    /// is dropped (RFC 5722).
+    pub fn add(&mut self, packet: &Ipv6Packet, now: Instant) -> Result<Option<Ipv6Packet>> {
+        let (header, data) = FragmentHeader::parse(&packet.data)?;
+        let start = usize::from(header.offset);
+        let end = start + data.len();
+        if header.more && data.len() % 8 != 0 {
+            bail!("IPv6: {} byte fragment isn't a multiple of 8", data.len());
+        }
+        if end > MAX_PAYLOAD {
+            bail!("IPv6: fragment ends past {MAX_PAYLOAD} bytes");
+        }
+        // An atomic fragment: the whole packet, for whatever reason
+        // fragmented into one (RFC 6946)
+        if start == 0 && !header.more {
+            return Ok(Some(reassembled(packet, header.next_header, data.to_vec())));
+        }
+
+        let key = (packet.source, packet.destination, header.identification);
+        if !self.partials.contains_key(&key) && self.partials.len() >= self.capacity {
+            bail!("IPv6: already reassembling {} packets", self.capacity);
+        }
+        let partial = self.partials.entry(key).or_insert_with(|| Partial {
+            first: None,
+            pieces: BTreeMap::new(),
+            length: None,
+            received: 0,
+            expires: now + self.timeout,
+        });
+        let bad_end = match partial.length {
+            Some(length) => end > length || (!header.more && end != length),
+            None => !header.more && partial.pieces.keys().next_back() >= Some(&end),
+        };
+        if bad_end || partial.overlaps(start, end) {
+            self.partials.remove(&key);
+            bail!(
+                "IPv6: fragments of {} don't fit together",
+                header.identification
+            );
+        }
+        if !header.more {
+            partial.length = Some(end);
+        }
+        if start == 0 {
+            partial.first = Some(packet.clone());
+        }
+        partial.pieces.insert(start, data.to_vec());
+        partial.received += data.len();
+        if partial.length != Some(partial.received) {
+            return Ok(None);
+        }
+
+        let Some(partial) = self.partials.remove(&key) else {
+            return Ok(None);
+        };
+        let Some(first) = partial.first else {
+            bail!(
+                "IPv6: fragments of {} complete without the first",
+                header.identification
+            );
+        };
+        let data = partial.pieces.into_values().flatten().collect();
+        Ok(Some(reassembled(&first, header.next_header, data)))
+    }
+
+    /// Give up on packets that have taken too long, returning their first
+    /// fragments, if they arrived, to report to the sender
+    pub fn expire(&mut self, now: Instant) -> Vec<Ipv6Packet> {
+        let mut first = Vec::new();
+        self.partials.retain(|_, partial| {
+            let alive = partial.expires > now;
+            if !alive {
+                first.extend(partial.first.take());
+            }
+            alive
+        });
+        first
+    }
+
+    /// When [Reassembler::expire] next has something to give up on
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.partials.values().map(|partial| partial.expires).min()
+    }
+}
+
+/// The packet `fragment` was part of, holding `data`
+fn reassembled(fragment: &Ipv6Packet, next_header: IpProtocol, data: Vec<u8>) -> Ipv6Packet {
+    Ipv6Packet {
+        next_header,
+        data,
+        ..fragment.clone()
+    }
+}
+
+/// Split `packet` into fragments that fit in `mtu`, or leave it whole if it
+/// already does
+pub fn fragment(packet: Ipv6Packet, mtu: usize, identification: u32) -> Result<Vec<Ipv6Packet>> {
+    if HEADER_LENGTH + packet.data.len() <= mtu {
+        return Ok(vec![packet]);
+    }
+    if packet.data.len() > MAX_PAYLOAD {
+        bail!("IPv6: can't fragment a {} byte payload", packet.data.len());
+    }
+    let size = mtu.saturating_sub(HEADER_LENGTH + FRAGMENT_HEADER_LENGTH) & !0x7;
+    if size == 0 {
+        bail!("IPv6: MTU of {mtu} is too small to fragment for");
+    }
+    let chunks = packet.data.chunks(size);
+    let count = chunks.len();
+    chunks
+        .enumerate()
+        .map(|(i, chunk)| {
+            let header = FragmentHeader {
+                next_header: packet.next_header,
+                offset: u16::try_from(i * size)?,
+                more: i + 1 < count,
+                identification,
+            };
+            let mut data = header.encode().to_vec();
+            data.extend_from_slice(chunk);
+            Ok(Ipv6Packet {
+                next_header: IpProtocol::Ipv6Fragment,
+                data,
+                ..packet.clone()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(length: usize) -> Ipv6Packet {
+        let data: Vec<u8> = (0..length).map(|i| i as u8).collect();
+        Ipv6Packet::new(
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+            IpProtocol::Udp,
+            data,
+        )
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let now = Instant::now();
+        let original = packet(3000);
+        let fragments = fragment(original.clone(), 1280, 7)?;
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| {
+            fragment.to_bytes().unwrap().len() <= 1280
+                && fragment.next_header == IpProtocol::Ipv6Fragment
+        }));
+
+        // Out of order is fine
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(&fragments[2], now)?, None);
+        assert_eq!(reassembler.add(&fragments[0], now)?, None);
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.add(&fragments[1], now)?, Some(original));
+        assert_eq!(reassembler.len(), 0);
+
+        // Small enough packets aren't fragmented
+        assert_eq!(fragment(packet(100), 1280, 8)?, [packet(100)]);
+        Ok(())
+    }
+
+    #[test]
+    fn drops_overlaps() -> Result<()> {
+        let now = Instant::now();
+        let fragments = fragment(packet(3000), 1280, 7)?;
+        let mut reassembler = Reassembler::new();
+        reassembler.add(&fragments[0], now)?;
+        let mut overlapping = fragments[1].clone();
+        overlapping.data[3] -= 8;
+        assert!(reassembler.add(&overlapping, now).is_err());
+        assert_eq!(reassembler.len(), 0);
+
+        // Nor can fragments run past the end
+        reassembler.add(&fragments[2], now)?;
+        let mut early_end = fragments[1].clone();
+        early_end.data[3] &= !1;
+        assert!(reassembler.add(&early_end, now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expires() -> Result<()> {
+        let now = Instant::now();
+        let fragments = fragment(packet(3000), 1280, 7)?;
+        let mut reassembler = Reassembler::new().set_capacity(1);
+        reassembler.add(&fragments[0], now)?;
+        let other = fragment(packet(3000), 1280, 8)?;
+        assert!(reassembler.add(&other[0], now).is_err());
+
+        let deadline = reassembler.next_deadline().unwrap();
+        assert_eq!(deadline, now + DEFAULT_TIMEOUT);
+        assert_eq!(reassembler.expire(deadline), [fragments[0].clone()]);
+        assert_eq!(reassembler.next_deadline(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! Address resolution in progress, by ARP or Neighbour Discovery: which
+//! addresses we're asking about, who's waiting on the answer, and the
+//! packets parked until it comes
+use crate::eth::Mac6;
+use crate::layer3::Ipv4Packet;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Wait before the first retry; each one after waits twice as long
+const INITIAL_RETRY: Duration = Duration::from_secs(1);
+/// Requests sent before giving up
+const MAX_REQUESTS: u32 = 3;
+/// Packets parked per address; more are dropped, as Linux's `unres_qlen`
+const MAX_PARKED: usize = 8;
+
+#[derive(Debug)]
+struct Pending<P> {
+    /// Requests sent so far
+    requests: u32,
+    /// When to send the next request, or give up
+    due: Instant,
+    waiters: Vec<oneshot::Sender<Result<Mac6>>>,
+    parked: Vec<P>,
+}
+
+/// What [Resolver::poll] wants done
+#[derive(Clone, PartialEq, Debug)]
+pub enum Resolution<A = Ipv4Addr, P = Ipv4Packet> {
+    /// Send a request for this address
+    Request(A),
+    /// Nobody answered for this address; these packets were dropped
+    Failed(A, Vec<P>),
+}
+
+/// Addresses being resolved, with packets of type `P` parked for them
+#[derive(Debug)]
+pub struct Resolver<A = Ipv4Addr, P = Ipv4Packet> {
+    pending: HashMap<A, Pending<P>>,
+}
+
+impl<A, P> Default for Resolver<A, P> {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<A: Copy + Eq + Hash + Display, P> Resolver<A, P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_resolving(&self, address: A) -> bool {
+        self.pending.contains_key(&address)
+    }
+
+    /// Start resolving `address`, if we aren't already; the first request
+    /// is due immediately
+    fn start(&mut self, address: A, now: Instant) -> &mut Pending<P> {
+        self.pending.entry(address).or_insert_with(|| Pending {
+            requests: 0,
+            due: now,
+            waiters: Vec::new(),
+            parked: Vec::new(),
+        })
+    }
+
+    /// Hold `packet` until `address` resolves, returning whether there was
+    /// room
+    pub fn park(&mut self, address: A, packet: P, now: Instant) -> bool {
+        let pending = self.start(address, now);
+        if pending.parked.len() >= MAX_PARKED {
+            return false;
+        }
+        pending.parked.push(packet);
+        true
+    }
+
+    /// Be told when `address` resolves
+    pub fn wait(&mut self, address: A, now: Instant) -> oneshot::Receiver<Result<Mac6>> {
+        let (tx, rx) = oneshot::channel();
+        self.start(address, now).waiters.push(tx);
+        rx
+    }
+
+    /// `address` is at `mac`: wake its waiters and return the packets
+    /// parked for it
+    pub fn resolved(&mut self, address: A, mac: Mac6) -> Vec<P> {
+        let Some(pending) = self.pending.remove(&address) else {
+            return Vec::new();
+        };
+        for waiter in pending.waiters {
+            // The waiter may have lost interest
+            let _ = waiter.send(Ok(mac));
+        }
+        pending.parked
+    }
+
+    /// Requests due, and resolutions given up on, by `now`
+    pub fn poll(&mut self, now: Instant) -> Vec<Resolution<A, P>> {
+        let mut actions = Vec::new();
+        let mut failed = Vec::new();
+        for (&address, pending) in &mut self.pending {
+            if pending.due > now {
+                continue;
+            }
+            if pending.requests == MAX_REQUESTS {
+                failed.push(address);
+                continue;
+            }
+            pending.due = now + INITIAL_RETRY * 2u32.pow(pending.requests);
+            pending.requests += 1;
+            actions.push(Resolution::Request(address));
+        }
+        for address in failed {
+            let pending = self.pending.remove(&address).expect("address is pending");
+            for waiter in pending.waiters {
+                let _ = waiter.send(Err(anyhow!("No reply for {address}")));
+            }
+            actions.push(Resolution::Failed(address, pending.parked));
+        }
+        actions
+    }
+
+    /// When [Resolver::poll] next has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.due).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::IpProtocol;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 5);
+
+    fn packet() -> Ipv4Packet {
+        Ipv4Packet::builder(Ipv4Addr::new(192, 168, 0, 1), ADDRESS, IpProtocol::Udp)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolves() -> Result<()> {
+        let now = Instant::now();
+        let mut resolver = Resolver::new();
+        let waiter = resolver.wait(ADDRESS, now);
+        assert!(resolver.park(ADDRESS, packet(), now));
+        assert_eq!(resolver.poll(now), [Resolution::Request(ADDRESS)]);
+        assert!(resolver.poll(now).is_empty());
+
+        let mac = Mac6::new([2, 0, 0, 0, 0, 5]);
+        assert_eq!(resolver.resolved(ADDRESS, mac), [packet()]);
+        assert_eq!(waiter.await??, mac);
+        assert!(!resolver.is_resolving(ADDRESS));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backs_off_then_fails() {
+        let now = Instant::now();
+        let mut resolver = Resolver::new();
+        let waiter = resolver.wait(ADDRESS, now);
+        for _ in 0..=MAX_PARKED {
+            resolver.park(ADDRESS, packet(), now);
+        }
+
+        let mut requests = Vec::new();
+        let failed = loop {
+            let due = resolver.next_deadline().unwrap();
+            match resolver.poll(due).pop().unwrap() {
+                Resolution::Request(_) => requests.push(due - now),
+                Resolution::Failed(_, parked) => break parked,
+            }
+        };
+        assert_eq!(requests, [0, 1, 3].map(Duration::from_secs));
+        assert_eq!(failed.len(), MAX_PARKED);
+        assert!(waiter.await.unwrap().is_err());
+        assert_eq!(resolver.next_deadline(), None);
+    }
+}
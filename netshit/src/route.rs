@@ -0,0 +1,431 @@
+//! IP routing: which next hop and interface a packet leaves through
+use anyhow::{Result, bail};
+use std::fmt::Debug;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+pub type InterfaceId = usize;
+
+/// Routes to IPv6 networks
+pub type Ipv6Route = Route<Ipv6Prefix>;
+pub type Ipv6RoutingTable = RoutingTable<Ipv6Prefix>;
+
+/// What routes are to: an [Ipv4Prefix] or an [Ipv6Prefix]
+pub trait Prefix: Copy + Eq + Debug {
+    type Address: Copy + Eq + Debug;
+
+    fn length(&self) -> u8;
+    fn contains(&self, address: Self::Address) -> bool;
+}
+
+/// A network, as an address and prefix length, e.g. `192.168.0.0/24`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Ipv4Prefix {
+    address: Ipv4Addr,
+    length: u8,
+}
+
+impl Ipv4Prefix {
+    /// Everything: the prefix of a default route
+    pub const DEFAULT: Self = Self {
+        address: Ipv4Addr::UNSPECIFIED,
+        length: 0,
+    };
+
+    /// The network of `length` bits containing `address`; host bits are
+    /// cleared
+    pub fn new(address: Ipv4Addr, length: u8) -> Result<Self> {
+        if length > 32 {
+            bail!("Prefix length {length} is over 32");
+        }
+        Ok(Self {
+            address: Ipv4Addr::from_bits(address.to_bits() & mask(length)),
+            length,
+        })
+    }
+
+    pub const fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub const fn length(&self) -> u8 {
+        self.length
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        address.to_bits() & mask(self.length) == self.address.to_bits()
+    }
+}
+
+impl Prefix for Ipv4Prefix {
+    type Address = Ipv4Addr;
+
+    fn length(&self) -> u8 {
+        self.length
+    }
+
+    fn contains(&self, address: Ipv4Addr) -> bool {
+        Self::contains(self, address)
+    }
+}
+
+fn mask(length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(length)).unwrap_or(0)
+}
+
+impl std::fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+impl std::str::FromStr for Ipv4Prefix {
+    type Err = anyhow::Error;
+
+    /// Accepts `a.b.c.d/n`, or a bare address as a /32
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((address, length)) => Self::new(address.parse()?, length.parse()?),
+            None => Self::new(s.parse()?, 32),
+        }
+    }
+}
+
+/// An IPv6 network, e.g. `2001:db8::/32`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Ipv6Prefix {
+    address: Ipv6Addr,
+    length: u8,
+}
+
+impl Ipv6Prefix {
+    /// Everything: the prefix of a default route
+    pub const DEFAULT: Self = Self {
+        address: Ipv6Addr::UNSPECIFIED,
+        length: 0,
+    };
+
+    /// The network of `length` bits containing `address`; host bits are
+    /// cleared
+    pub fn new(address: Ipv6Addr, length: u8) -> Result<Self> {
+        if length > 128 {
+            bail!("Prefix length {length} is over 128");
+        }
+        Ok(Self {
+            address: Ipv6Addr::from_bits(address.to_bits() & mask6(length)),
+            length,
+        })
+    }
+
+    pub const fn address(&self) -> Ipv6Addr {
+        self.address
+    }
+
+    pub const fn length(&self) -> u8 {
+        self.length
+    }
+
+    pub fn contains(&self, address: Ipv6Addr) -> bool {
+        address.to_bits() & mask6(self.length) == self.address.to_bits()
+    }
+}
+
+impl Prefix for Ipv6Prefix {
+    type Address = Ipv6Addr;
+
+    fn length(&self) -> u8 {
+        self.length
+    }
+
+    fn contains(&self, address: Ipv6Addr) -> bool {
+        Self::contains(self, address)
+    }
+}
+
+fn mask6(length: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(length)).unwrap_or(0)
+}
+
+impl std::fmt::Display for Ipv6Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+impl std::str::FromStr for Ipv6Prefix {
+    type Err = anyhow::Error;
+
+    /// Accepts `address/n`, or a bare address as a /128
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((address, length)) => Self::new(address.parse()?, length.parse()?),
+            None => Self::new(s.parse()?, 128),
+        }
+    }
+}
+
+/// One entry in a [RoutingTable]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Route<P: Prefix = Ipv4Prefix> {
+    pub prefix: P,
+    /// Gateway to send through, or `None` if the network is on-link
+    pub gateway: Option<P::Address>,
+    pub interface: InterfaceId,
+    /// Preference between routes to the same prefix; lower wins
+    pub metric: u32,
+    /// When the route goes away, or `None` for never
+    pub valid_until: Option<Instant>,
+    /// When the route stops being preferred, or `None` for never
+    pub preferred_until: Option<Instant>,
+}
+
+impl<P: Prefix> Route<P> {
+    /// A directly connected network
+    pub const fn connected(prefix: P, interface: InterfaceId) -> Self {
+        Self {
+            prefix,
+            gateway: None,
+            interface,
+            metric: 0,
+            valid_until: None,
+            preferred_until: None,
+        }
+    }
+
+    /// A network reached through `gateway`
+    pub const fn via(prefix: P, gateway: P::Address, interface: InterfaceId) -> Self {
+        Self {
+            prefix,
+            gateway: Some(gateway),
+            interface,
+            metric: 0,
+            valid_until: None,
+            preferred_until: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn set_metric(mut self, metric: u32) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Keep the route until `valid_until`, preferring it until
+    /// `preferred_until`; `None` for either is forever
+    #[must_use]
+    pub const fn set_lifetimes(
+        mut self,
+        valid_until: Option<Instant>,
+        preferred_until: Option<Instant>,
+    ) -> Self {
+        self.valid_until = valid_until;
+        self.preferred_until = preferred_until;
+        self
+    }
+
+    /// Whether the route's preferred lifetime has yet to run out
+    pub fn is_preferred(&self, now: Instant) -> bool {
+        self.preferred_until.is_none_or(|until| until > now)
+    }
+
+    /// The address to hand a packet for `destination` to on the link
+    pub fn next_hop(&self, destination: P::Address) -> P::Address {
+        self.gateway.unwrap_or(destination)
+    }
+}
+
+/// Routes, looked up by longest prefix match
+#[derive(Clone, Debug)]
+pub struct RoutingTable<P: Prefix = Ipv4Prefix> {
+    /// Kept most specific first, then by metric, so the first match wins
+    routes: Vec<Route<P>>,
+}
+
+impl<P: Prefix> Default for RoutingTable<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Prefix> RoutingTable<P> {
+    pub const fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Add a route, replacing any to the same prefix through the same
+    /// gateway and interface
+    pub fn add(&mut self, route: Route<P>) {
+        self.routes.retain(|other| {
+            (other.prefix, other.gateway, other.interface)
+                != (route.prefix, route.gateway, route.interface)
+        });
+        let index = self.routes.partition_point(|other| {
+            other.prefix.length() > route.prefix.length()
+                || (other.prefix.length() == route.prefix.length() && other.metric <= route.metric)
+        });
+        self.routes.insert(index, route);
+    }
+
+    /// Remove the route to `prefix` through `gateway`, returning whether
+    /// there was one
+    pub fn remove_via(&mut self, prefix: P, gateway: Option<P::Address>) -> bool {
+        let len = self.routes.len();
+        self.routes
+            .retain(|route| (route.prefix, route.gateway) != (prefix, gateway));
+        self.routes.len() != len
+    }
+
+    /// Remove every route to `prefix`, returning whether there were any
+    pub fn remove(&mut self, prefix: P) -> bool {
+        let len = self.routes.len();
+        self.routes.retain(|route| route.prefix != prefix);
+        self.routes.len() != len
+    }
+
+    /// The best route to `destination`: longest prefix, then lowest metric
+    pub fn lookup(&self, destination: P::Address) -> Option<&Route<P>> {
+        self.routes
+            .iter()
+            .find(|route| route.prefix.contains(destination))
+    }
+
+    pub fn routes(&self) -> &[Route<P>] {
+        &self.routes
+    }
+
+    /// Forget routes whose valid lifetimes have run out, returning them
+    pub fn expire(&mut self, now: Instant) -> Vec<Route<P>> {
+        let mut expired = Vec::new();
+        self.routes.retain(|route| {
+            let alive = route.valid_until.is_none_or(|until| until > now);
+            if !alive {
+                expired.push(*route);
+            }
+            alive
+        });
+        expired
+    }
+
+    /// When [RoutingTable::expire] next has something to forget
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.routes
+            .iter()
+            .filter_map(|route| route.valid_until)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Ipv4Prefix {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes() -> Result<()> {
+        let net = prefix("192.168.0.77/24");
+        assert_eq!(net.to_string(), "192.168.0.0/24");
+        assert!(net.contains(Ipv4Addr::new(192, 168, 0, 200)));
+        assert!(!net.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(Ipv4Prefix::DEFAULT.contains(Ipv4Addr::BROADCAST));
+        assert_eq!(
+            prefix("10.0.0.1"),
+            Ipv4Prefix::new(Ipv4Addr::new(10, 0, 0, 1), 32)?
+        );
+        assert!("10.0.0.0/33".parse::<Ipv4Prefix>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ipv6_prefixes() -> Result<()> {
+        let net: Ipv6Prefix = "2001:db8::1/32".parse()?;
+        assert_eq!(net.to_string(), "2001:db8::/32");
+        assert!(net.contains("2001:db8:ffff::1".parse()?));
+        assert!(!net.contains("2001:db9::1".parse()?));
+        assert!(Ipv6Prefix::DEFAULT.contains(Ipv6Addr::LOCALHOST));
+        assert_eq!("::1".parse::<Ipv6Prefix>()?.length(), 128);
+        assert!("::/129".parse::<Ipv6Prefix>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let gateway = Ipv4Addr::new(192, 168, 0, 254);
+        let mut table = RoutingTable::new();
+        table.add(Route::via(Ipv4Prefix::DEFAULT, gateway, 0));
+        table.add(Route::connected(prefix("192.168.0.0/24"), 0));
+        table.add(Route::connected(prefix("10.0.0.0/8"), 1));
+        table.add(Route::connected(prefix("10.1.0.0/16"), 2));
+
+        let route = |a, b, c, d| table.lookup(Ipv4Addr::new(a, b, c, d)).unwrap();
+        assert_eq!(route(10, 1, 2, 3).interface, 2);
+        assert_eq!(route(10, 2, 2, 3).interface, 1);
+        let local = Ipv4Addr::new(192, 168, 0, 9);
+        assert_eq!(route(192, 168, 0, 9).next_hop(local), local);
+        let remote = Ipv4Addr::new(8, 8, 8, 8);
+        assert_eq!(route(8, 8, 8, 8).next_hop(remote), gateway);
+
+        assert!(table.remove(Ipv4Prefix::DEFAULT));
+        assert!(!table.remove(Ipv4Prefix::DEFAULT));
+        assert_eq!(table.lookup(remote), None);
+    }
+
+    #[test]
+    fn ipv6_routes() -> Result<()> {
+        let gateway: Ipv6Addr = "fe80::1".parse()?;
+        let mut table = Ipv6RoutingTable::new();
+        table.add(Ipv6Route::via(Ipv6Prefix::DEFAULT, gateway, 0));
+        table.add(Ipv6Route::connected("2001:db8::/64".parse()?, 0));
+
+        let local: Ipv6Addr = "2001:db8::9".parse()?;
+        assert_eq!(table.lookup(local).unwrap().next_hop(local), local);
+        let remote: Ipv6Addr = "2001:4860::8888".parse()?;
+        assert_eq!(table.lookup(remote).unwrap().next_hop(remote), gateway);
+        Ok(())
+    }
+
+    #[test]
+    fn lifetimes() -> Result<()> {
+        let now = Instant::now();
+        let later = now + std::time::Duration::from_secs(60);
+        let gateway: Ipv6Addr = "fe80::1".parse()?;
+        let mut table = Ipv6RoutingTable::new();
+        table.add(Ipv6Route::via(Ipv6Prefix::DEFAULT, gateway, 0).set_lifetimes(Some(later), None));
+        let route =
+            Ipv6Route::connected("2001:db8::/64".parse()?, 0).set_lifetimes(None, Some(now));
+        table.add(route);
+        assert!(!route.is_preferred(now));
+        assert_eq!(table.next_deadline(), Some(later));
+
+        let expired = table.expire(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].gateway, Some(gateway));
+        assert_eq!(table.next_deadline(), None);
+        assert!(table.remove_via("2001:db8::/64".parse()?, None));
+        assert!(table.routes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn metric_breaks_ties() {
+        let net = prefix("10.0.0.0/8");
+        let mut table = RoutingTable::new();
+        table.add(Route::connected(net, 0).set_metric(20));
+        table.add(Route::connected(net, 1).set_metric(10));
+        table.add(Route::connected(net, 2).set_metric(30));
+        assert_eq!(
+            table.lookup(Ipv4Addr::new(10, 0, 0, 1)).unwrap().interface,
+            1
+        );
+
+        // Re-adding replaces rather than duplicates
+        table.add(Route::connected(net, 1).set_metric(40));
+        assert_eq!(table.routes().len(), 3);
+        assert_eq!(
+            table.lookup(Ipv4Addr::new(10, 0, 0, 1)).unwrap().interface,
+            0
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Round-trip time estimation, for how long to wait for an acknowledgement
+//! before retransmitting (RFC 6298)
+use std::time::Duration;
+
+/// Timeout before any round trip's been measured
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// Resolution of the clock round trips are measured with
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Smoothed round-trip time and its variation, giving a retransmission
+/// timeout
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RttEstimator {
+    /// Smoothed round-trip time, once there's been a sample
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RttEstimator {
+    pub const fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// The smoothed round-trip time, if any have been measured
+    pub const fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How long to wait for an acknowledgement
+    pub const fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Take a measured round trip into account; it mustn't be of a
+    /// retransmitted segment, which could be answering either copy
+    pub fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                // β = 1/4, α = 1/8
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + GRANULARITY.max(self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Double the timeout, after it expired
+    pub fn back_off(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn estimates() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        rtt.sample(MS * 400);
+        // 400 + 4 × 200
+        assert_eq!(rtt.rto(), MS * 1200);
+        rtt.sample(MS * 800);
+        assert_eq!(rtt.srtt(), Some(MS * 450));
+        // rttvar = (3 × 200 + 400) / 4 = 250
+        assert_eq!(rtt.rto(), MS * 1450);
+
+        // Quick, steady round trips still wait a second
+        let mut rtt = RttEstimator::new();
+        (0..10).for_each(|_| rtt.sample(MS * 10));
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn backs_off() {
+        let mut rtt = RttEstimator::new();
+        rtt.back_off();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 2);
+        (0..10).for_each(|_| rtt.back_off());
+        assert_eq!(rtt.rto(), MAX_RTO);
+        // A fresh measurement undoes it
+        rtt.sample(MS * 100);
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+}
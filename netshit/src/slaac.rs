@@ -0,0 +1,379 @@
+//! Stateless address autoconfiguration (RFC 4862): forming IPv6 addresses
+//! from the prefixes routers advertise, and routing through those routers
+//! and to their on-link prefixes, each until its lifetime runs out
+use crate::eth::Mac6;
+use crate::layer3::ndp::{INFINITE_LIFETIME, PrefixInformation, RouterAdvertisement};
+use crate::route::{InterfaceId, Ipv6Prefix, Ipv6Route, Ipv6RoutingTable};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// An advertisement can't cut an address's remaining valid lifetime below
+/// this, so a spoofed one can't take the address away (RFC 4862 §5.5.3)
+const MIN_VALID_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+/// Prefix length we form addresses in: 64 bits of prefix, 64 of interface
+/// identifier
+const PREFIX_LENGTH: u8 = 64;
+/// Metric of routes learnt from advertisements, as Linux gives them, so
+/// static routes win
+const ROUTER_METRIC: u32 = 1024;
+
+/// How to pick the low 64 bits of an address, the interface identifier
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AddressGeneration {
+    /// From the MAC (RFC 4291 appendix A), so the same on every network
+    Eui64,
+    /// From a hash of the prefix, the MAC and this secret (RFC 7217), so
+    /// stable on each network but unlinkable between them
+    StablePrivacy([u8; 16]),
+}
+
+/// An address formed from an advertised prefix
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SlaacAddress {
+    pub address: Ipv6Addr,
+    pub prefix: Ipv6Prefix,
+    /// When the address goes away, or `None` for never
+    pub valid_until: Option<Instant>,
+    /// When the address stops being used for new connections, or `None` for
+    /// never
+    pub preferred_until: Option<Instant>,
+}
+
+impl SlaacAddress {
+    /// Whether the address should still be picked as a source
+    pub fn is_preferred(&self, now: Instant) -> bool {
+        self.preferred_until.is_none_or(|until| until > now)
+    }
+}
+
+/// Addresses learnt from router advertisements
+///
+/// The on-link prefixes and default routers advertised go in a routing
+/// table, expiring with it.
+#[derive(Clone, Debug)]
+pub struct Slaac {
+    mac: Mac6,
+    /// Interface routes learnt are through
+    interface: InterfaceId,
+    generation: AddressGeneration,
+    addresses: Vec<SlaacAddress>,
+}
+
+impl Slaac {
+    /// Autoconfigure addresses for the interface at `mac`, EUI-64 style
+    pub const fn new(mac: Mac6) -> Self {
+        Self {
+            mac,
+            interface: 0,
+            generation: AddressGeneration::Eui64,
+            addresses: Vec::new(),
+        }
+    }
+
+    /// Learn routes through `interface`, rather than interface 0
+    #[must_use]
+    pub const fn set_interface(mut self, interface: InterfaceId) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_address_generation(mut self, generation: AddressGeneration) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = &SlaacAddress> {
+        self.addresses.iter()
+    }
+
+    /// Whether `address` is one we formed
+    pub fn contains(&self, address: Ipv6Addr) -> bool {
+        self.addresses.iter().any(|entry| entry.address == address)
+    }
+
+    /// Give up an address we formed, e.g. because it's a duplicate,
+    /// returning whether we had it
+    pub fn remove(&mut self, address: Ipv6Addr) -> bool {
+        let len = self.addresses.len();
+        self.addresses.retain(|entry| entry.address != address);
+        self.addresses.len() != len
+    }
+
+    /// Learn from an advertisement sent by `router`, updating `routes` and
+    /// returning any addresses newly formed
+    pub fn handle(
+        &mut self,
+        router: Ipv6Addr,
+        advertisement: &RouterAdvertisement,
+        routes: &mut Ipv6RoutingTable,
+        now: Instant,
+    ) -> Vec<Ipv6Addr> {
+        if advertisement.router_lifetime > 0 {
+            let lifetime = Duration::from_secs(advertisement.router_lifetime.into());
+            let until = Some(now + lifetime);
+            routes.add(
+                Ipv6Route::via(Ipv6Prefix::DEFAULT, router, self.interface)
+                    .set_metric(ROUTER_METRIC)
+                    .set_lifetimes(until, until),
+            );
+        } else {
+            routes.remove_via(Ipv6Prefix::DEFAULT, Some(router));
+        }
+
+        let mut formed = Vec::new();
+        for prefix in advertisement.prefixes() {
+            if prefix.prefix.is_unicast_link_local()
+                || prefix.preferred_lifetime > prefix.valid_lifetime
+            {
+                continue;
+            }
+            let Ok(network) = Ipv6Prefix::new(prefix.prefix, prefix.prefix_length) else {
+                continue;
+            };
+            if prefix.on_link && prefix.valid_lifetime > 0 {
+                routes.add(
+                    Ipv6Route::connected(network, self.interface)
+                        .set_metric(ROUTER_METRIC)
+                        .set_lifetimes(
+                            until(now, prefix.valid_lifetime),
+                            until(now, prefix.preferred_lifetime),
+                        ),
+                );
+            } else if prefix.on_link {
+                routes.remove_via(network, None);
+            }
+            if prefix.autonomous
+                && prefix.prefix_length == PREFIX_LENGTH
+                && let Some(address) = self.autoconfigure(network, prefix, now)
+            {
+                formed.push(address);
+            }
+        }
+        formed
+    }
+
+    /// Form an address in `network`, or refresh the one we have, returning
+    /// it if it's new
+    fn autoconfigure(
+        &mut self,
+        network: Ipv6Prefix,
+        prefix: &PrefixInformation,
+        now: Instant,
+    ) -> Option<Ipv6Addr> {
+        let preferred_until = until(now, prefix.preferred_lifetime);
+        if let Some(entry) = self
+            .addresses
+            .iter_mut()
+            .find(|entry| entry.prefix == network)
+        {
+            entry.preferred_until = preferred_until;
+            let remaining = entry
+                .valid_until
+                .map_or(Duration::MAX, |valid| valid.saturating_duration_since(now));
+            let received = lifetime(prefix.valid_lifetime);
+            if received > MIN_VALID_LIFETIME || received > remaining {
+                entry.valid_until = until(now, prefix.valid_lifetime);
+            } else if remaining > MIN_VALID_LIFETIME {
+                entry.valid_until = Some(now + MIN_VALID_LIFETIME);
+            }
+            return None;
+        }
+        if prefix.valid_lifetime == 0 {
+            return None;
+        }
+        let address = Ipv6Addr::from_bits(
+            network.address().to_bits() | u128::from(self.interface_id(network)),
+        );
+        self.addresses.push(SlaacAddress {
+            address,
+            prefix: network,
+            valid_until: until(now, prefix.valid_lifetime),
+            preferred_until,
+        });
+        Some(address)
+    }
+
+    fn interface_id(&self, network: Ipv6Prefix) -> u64 {
+        match self.generation {
+            AddressGeneration::Eui64 => self.mac.eui64(),
+            AddressGeneration::StablePrivacy(secret) => {
+                let mut hasher = DefaultHasher::new();
+                (network, self.mac.into_inner(), secret).hash(&mut hasher);
+                hasher.finish()
+            }
+        }
+    }
+
+    /// Forget addresses whose lifetimes have run out
+    pub fn expire(&mut self, now: Instant) {
+        self.addresses
+            .retain(|entry| entry.valid_until.is_none_or(|until| until > now));
+    }
+
+    /// When [Slaac::expire] next has something to forget
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.addresses
+            .iter()
+            .filter_map(|entry| entry.valid_until)
+            .min()
+    }
+}
+
+/// A lifetime in seconds, [INFINITE_LIFETIME] being the longest there is
+fn lifetime(seconds: u32) -> Duration {
+    if seconds == INFINITE_LIFETIME {
+        Duration::MAX
+    } else {
+        Duration::from_secs(seconds.into())
+    }
+}
+
+/// When a lifetime starting `now` runs out, or `None` for never
+fn until(now: Instant, seconds: u32) -> Option<Instant> {
+    now.checked_add(lifetime(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::ndp::NdpOption;
+
+    const MAC: Mac6 = Mac6::new([0x00, 0xaa, 0x00, 0x28, 0x9c, 0x5a]);
+    const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+    fn advertisement(prefixes: &[(&str, u32, u32)]) -> RouterAdvertisement {
+        let options = prefixes
+            .iter()
+            .map(|&(prefix, valid_lifetime, preferred_lifetime)| {
+                NdpOption::PrefixInformation(PrefixInformation {
+                    prefix: prefix.parse().unwrap(),
+                    prefix_length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime,
+                    preferred_lifetime,
+                })
+            })
+            .collect();
+        RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other_config: false,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options,
+        }
+    }
+
+    /// Where `routes` sends packets for `destination`
+    fn next_hop(routes: &Ipv6RoutingTable, destination: &str) -> Option<Ipv6Addr> {
+        let destination = destination.parse().unwrap();
+        routes
+            .lookup(destination)
+            .map(|route| route.next_hop(destination))
+    }
+
+    #[test]
+    fn forms_addresses() {
+        let now = Instant::now();
+        let mut slaac = Slaac::new(MAC);
+        let mut routes = Ipv6RoutingTable::new();
+        let formed = slaac.handle(
+            ROUTER,
+            &advertisement(&[
+                ("2001:db8:1::", INFINITE_LIFETIME, 3600),
+                // Ignored: link-local, and preferred longer than valid
+                ("fe80::", 3600, 3600),
+                ("2001:db8:2::", 60, 3600),
+            ]),
+            &mut routes,
+            now,
+        );
+        let expected: Ipv6Addr = "2001:db8:1:0:2aa:ff:fe28:9c5a".parse().unwrap();
+        assert_eq!(formed, [expected]);
+        assert!(slaac.contains(expected));
+        // On-link prefixes are reached directly, the rest through the router
+        assert_eq!(
+            next_hop(&routes, "2001:db8:1::99"),
+            "2001:db8:1::99".parse().ok()
+        );
+        assert_eq!(next_hop(&routes, "2001:db8:2::99"), Some(ROUTER));
+        assert_eq!(routes.routes().len(), 2);
+
+        let address = slaac.addresses().next().unwrap();
+        assert!(address.is_preferred(now));
+        assert!(!address.is_preferred(now + Duration::from_secs(3600)));
+
+        // Again, and nothing new is formed
+        assert!(
+            slaac
+                .handle(
+                    ROUTER,
+                    &advertisement(&[("2001:db8:1::", 7200, 60)]),
+                    &mut routes,
+                    now
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn lifetimes() {
+        let now = Instant::now();
+        let mut slaac = Slaac::new(MAC);
+        let mut routes = Ipv6RoutingTable::new();
+        slaac.handle(
+            ROUTER,
+            &advertisement(&[("2001:db8:1::", 86400, 3600)]),
+            &mut routes,
+            now,
+        );
+        // Can't be cut to under two hours
+        slaac.handle(
+            ROUTER,
+            &advertisement(&[("2001:db8:1::", 10, 10)]),
+            &mut routes,
+            now,
+        );
+        let valid = slaac.addresses().next().unwrap().valid_until;
+        assert_eq!(valid, Some(now + MIN_VALID_LIFETIME));
+        // The on-link prefix has no such protection
+        assert_eq!(routes.next_deadline(), Some(now + Duration::from_secs(10)));
+        assert_eq!(routes.expire(now + Duration::from_secs(10)).len(), 1);
+
+        let mut withdrawn = advertisement(&[]);
+        withdrawn.router_lifetime = 0;
+        slaac.handle(ROUTER, &withdrawn, &mut routes, now);
+        assert_eq!(next_hop(&routes, "2001:db8:2::99"), None);
+
+        slaac.expire(now + MIN_VALID_LIFETIME);
+        assert_eq!(slaac.addresses().count(), 0);
+    }
+
+    #[test]
+    fn stable_privacy() {
+        let now = Instant::now();
+        let generation = AddressGeneration::StablePrivacy([7; 16]);
+        let formed = |prefix| {
+            Slaac::new(MAC).set_address_generation(generation).handle(
+                ROUTER,
+                &advertisement(&[(prefix, 3600, 3600)]),
+                &mut Ipv6RoutingTable::new(),
+                now,
+            )[0]
+        };
+        let address = formed("2001:db8:1::");
+        assert_eq!(address, formed("2001:db8:1::"));
+        assert_ne!(
+            address.segments()[4..],
+            formed("2001:db8:2::").segments()[4..]
+        );
+        assert_ne!(
+            address,
+            "2001:db8:1:0:2aa:ff:fe28:9c5a".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+}
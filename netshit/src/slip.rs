@@ -0,0 +1,209 @@
+//! Serial Line Internet Protocol (RFC 1055)
+use crate::layer3::Ipv4Packet;
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Largest datagram we'll buffer before giving up on a frame
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Escape a datagram and wrap it in END bytes
+///
+/// A leading END is sent too, which flushes any line noise the peer has
+/// accumulated (as recommended by the RFC)
+pub fn encode(datagram: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(datagram.len() + 2);
+    frame.push(END);
+    for &byte in datagram {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            _ => frame.push(byte),
+        }
+    }
+    frame.push(END);
+    frame
+}
+
+/// Incremental SLIP decoder
+#[derive(Clone, Debug, Default)]
+pub struct SlipDecoder {
+    buffer: Vec<u8>,
+    escaped: bool,
+    /// Skipping the rest of a bad datagram, up to the next END
+    discarding: bool,
+}
+
+impl SlipDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            escaped: false,
+            discarding: false,
+        }
+    }
+
+    /// Feed a byte to the decoder, returning a datagram if this byte completed one
+    ///
+    /// Empty frames (back-to-back END bytes) are skipped. After an error,
+    /// the rest of the bad datagram is dropped
+    pub fn push(&mut self, byte: u8) -> Result<Option<Vec<u8>>> {
+        if self.discarding {
+            self.discarding = byte != END;
+            return Ok(None);
+        }
+
+        if self.escaped {
+            self.escaped = false;
+            let byte = match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                // An escaped END both ends the frame and corrupts it
+                END => {
+                    self.buffer.clear();
+                    bail!("SLIP: invalid escape sequence: 0x{byte:02x}");
+                }
+                _ => {
+                    self.buffer.clear();
+                    self.discarding = true;
+                    bail!("SLIP: invalid escape sequence: 0x{byte:02x}");
+                }
+            };
+            self.buffer.push(byte);
+        } else {
+            match byte {
+                END if self.buffer.is_empty() => {}
+                END => return Ok(Some(std::mem::take(&mut self.buffer))),
+                ESC => self.escaped = true,
+                _ => self.buffer.push(byte),
+            }
+        }
+
+        if self.buffer.len() > MAX_DATAGRAM_SIZE {
+            self.buffer.clear();
+            self.discarding = true;
+            bail!("SLIP: datagram too large");
+        }
+
+        Ok(None)
+    }
+}
+
+/// IPv4 over a SLIP-framed byte stream, e.g. a [virtser::async_io::AsyncVirtSer]
+pub struct SlipLink<T> {
+    io: T,
+    decoder: SlipDecoder,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SlipLink<T> {
+    pub const fn new(io: T) -> Self {
+        Self {
+            io,
+            decoder: SlipDecoder::new(),
+        }
+    }
+
+    /// Wait for the next datagram
+    pub async fn recv_datagram(&mut self) -> Result<Vec<u8>> {
+        loop {
+            let byte = self.io.read_u8().await?;
+            if let Some(datagram) = self.decoder.push(byte)? {
+                return Ok(datagram);
+            }
+        }
+    }
+
+    /// Wait for the next IPv4 packet
+    pub async fn recv(&mut self) -> Result<Ipv4Packet> {
+        let datagram = self.recv_datagram().await?;
+        Ipv4Packet::from_reader(datagram.as_slice()).await
+    }
+
+    /// Send a raw datagram
+    pub async fn send_datagram(&mut self, datagram: &[u8]) -> Result<()> {
+        self.io.write_all(&encode(datagram)).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Send an IPv4 packet
+    pub async fn send(&mut self, packet: &mut Ipv4Packet) -> Result<()> {
+        let mut datagram = Vec::new();
+        packet.onto_writer(&mut datagram).await?;
+        self.send_datagram(&datagram).await
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer3::IpProtocol;
+    use crate::layer3::ipv4::{Dscp, Ecn};
+
+    #[test]
+    fn escaping() {
+        let frame = encode(&[1, END, 2, ESC, 3]);
+        assert_eq!(frame, [END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]);
+
+        let mut decoder = SlipDecoder::new();
+        let mut datagrams = Vec::new();
+        for byte in frame {
+            if let Some(datagram) = decoder.push(byte).unwrap() {
+                datagrams.push(datagram);
+            }
+        }
+        assert_eq!(datagrams, [vec![1, END, 2, ESC, 3]]);
+    }
+
+    #[test]
+    fn bad_escape() {
+        let mut decoder = SlipDecoder::new();
+        decoder.push(1).unwrap();
+        decoder.push(ESC).unwrap();
+        assert!(decoder.push(2).is_err());
+        // The rest of the datagram is dropped
+        assert_eq!(decoder.push(3).unwrap(), None);
+        assert_eq!(decoder.push(END).unwrap(), None);
+        // Decoder recovers on the next frame
+        assert_eq!(decoder.push(4).unwrap(), None);
+        assert_eq!(decoder.push(END).unwrap(), Some(vec![4]));
+
+        // ESC END ends the bad frame there and then
+        decoder.push(ESC).unwrap();
+        assert!(decoder.push(END).is_err());
+        assert_eq!(decoder.push(5).unwrap(), None);
+        assert_eq!(decoder.push(END).unwrap(), Some(vec![5]));
+    }
+
+    #[tokio::test]
+    async fn packet_over_link() -> Result<()> {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = SlipLink::new(a);
+        let mut b = SlipLink::new(b);
+
+        let mut packet = Ipv4Packet {
+            dscp: Dscp::CS0,
+            ecn: Ecn::NotEct,
+            identification: 0xc0db,
+            ttl: 64,
+            protocol: IpProtocol::Udp,
+            source: "10.0.0.1".parse()?,
+            destination: "10.0.0.2".parse()?,
+            options: Vec::new(),
+            data: vec![END, ESC, 0, END],
+            header_checksum: None,
+        };
+        a.send(&mut packet).await?;
+
+        assert_eq!(b.recv().await?, packet);
+        Ok(())
+    }
+}
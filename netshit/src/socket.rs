@@ -0,0 +1,414 @@
+//! Handles applications use to send and receive through a
+//! [Stack](crate::stack::Stack)
+use crate::checksum::ChecksumCapabilities;
+use crate::layer3::{IpProtocol, Ipv4Packet};
+use crate::layer4::{PseudoHeader, UdpDatagram, UdpLiteDatagram};
+use crate::tcp::TcpConnection;
+use anyhow::{Result, anyhow, bail};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{Notify, mpsc};
+
+/// Packets queued for a socket before the stack starts dropping them
+pub(crate) const SOCKET_QUEUE: usize = 64;
+/// Ports handed out to sockets bound to port 0 (RFC 6335)
+pub(crate) const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// A datagram's payload, and who sent it
+pub(crate) type Received = (SocketAddrV4, Vec<u8>);
+
+/// Sends and receives whole IPv4 packets of one protocol, bypassing any
+/// transport layer
+///
+/// Received packets are copies: the stack still handles them as usual.
+#[derive(Debug)]
+pub struct RawSocket {
+    protocol: IpProtocol,
+    /// Address we're bound to, if any
+    address: Option<Ipv4Addr>,
+    incoming: mpsc::Receiver<Ipv4Packet>,
+    outgoing: mpsc::Sender<Ipv4Packet>,
+}
+
+impl RawSocket {
+    pub(crate) const fn new(
+        protocol: IpProtocol,
+        address: Option<Ipv4Addr>,
+        incoming: mpsc::Receiver<Ipv4Packet>,
+        outgoing: mpsc::Sender<Ipv4Packet>,
+    ) -> Self {
+        Self {
+            protocol,
+            address,
+            incoming,
+            outgoing,
+        }
+    }
+
+    pub const fn protocol(&self) -> IpProtocol {
+        self.protocol
+    }
+
+    /// The address this socket is bound to, if any
+    pub const fn local_address(&self) -> Option<Ipv4Addr> {
+        self.address
+    }
+
+    /// Send a packet as is; it must be of this socket's protocol
+    ///
+    /// An unspecified source is filled in by the stack.
+    pub async fn send(&self, packet: Ipv4Packet) -> Result<()> {
+        if packet.protocol != self.protocol {
+            bail!(
+                "Raw socket for {:?} can't send {:?}",
+                self.protocol,
+                packet.protocol
+            );
+        }
+        self.outgoing
+            .send(packet)
+            .await
+            .map_err(|_| anyhow!("Stack stopped"))
+    }
+
+    /// Send `data` to `destination` from the bound address, or else the
+    /// one the stack picks for the destination
+    pub async fn send_to(&self, destination: Ipv4Addr, data: impl Into<Vec<u8>>) -> Result<()> {
+        let source = self.address.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let packet = Ipv4Packet::builder(source, destination, self.protocol)
+            .set_data(data)
+            .build()?;
+        self.send(packet).await
+    }
+
+    /// Next packet of this protocol addressed to us, or `None` once the
+    /// stack has stopped
+    pub async fn recv(&mut self) -> Option<Ipv4Packet> {
+        self.incoming.recv().await
+    }
+}
+
+/// Sends and receives UDP or UDP-Lite datagrams on one port, like
+/// [std::net::UdpSocket]
+///
+/// Datagrams arriving faster than they're received are dropped.
+#[derive(Debug)]
+pub struct UdpSocket {
+    protocol: IpProtocol,
+    local: SocketAddrV4,
+    /// Bytes of each UDP-Lite datagram sent that its checksum covers
+    coverage: u16,
+    checksums: ChecksumCapabilities,
+    incoming: mpsc::Receiver<Received>,
+    outgoing: mpsc::Sender<Ipv4Packet>,
+}
+
+impl UdpSocket {
+    pub(crate) const fn new(
+        protocol: IpProtocol,
+        local: SocketAddrV4,
+        checksums: ChecksumCapabilities,
+        incoming: mpsc::Receiver<Received>,
+        outgoing: mpsc::Sender<Ipv4Packet>,
+    ) -> Self {
+        Self {
+            protocol,
+            local,
+            coverage: 0,
+            checksums,
+            incoming,
+            outgoing,
+        }
+    }
+
+    /// UDP, or UDP-Lite
+    pub const fn protocol(&self) -> IpProtocol {
+        self.protocol
+    }
+
+    pub const fn checksum_coverage(&self) -> u16 {
+        self.coverage
+    }
+
+    /// Checksum only the first `coverage` bytes of each datagram sent,
+    /// header included, so damage past them doesn't lose it; 0, the
+    /// default, covers everything
+    ///
+    /// Only UDP-Lite sockets can.
+    pub fn set_checksum_coverage(&mut self, coverage: u16) -> Result<()> {
+        if self.protocol != IpProtocol::UdpLite {
+            bail!("Only UDP-Lite checksums part of a datagram");
+        }
+        self.coverage = coverage;
+        Ok(())
+    }
+
+    /// The address and port this socket is bound to; the address is
+    /// unspecified if it's bound to all of ours
+    pub const fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    /// Send `buf` to `target` as one datagram, returning its length
+    ///
+    /// It's sent from the bound address, or else the one the stack picks for
+    /// `target`.
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddrV4) -> Result<usize> {
+        let (source, destination) = (*self.local.ip(), *target.ip());
+        let pseudo = PseudoHeader::V4 {
+            source,
+            destination,
+        };
+        let (source_port, destination_port) = (self.local.port(), target.port());
+        let data = match self.protocol {
+            IpProtocol::UdpLite => UdpLiteDatagram::new(source_port, destination_port, buf)
+                .set_coverage(self.coverage)
+                .to_bytes_with_checksums(&pseudo, &self.checksums)?,
+            _ => UdpDatagram::new(source_port, destination_port, buf)
+                .to_bytes_with_checksums(&pseudo, &self.checksums)?,
+        };
+        let packet = Ipv4Packet::builder(source, destination, self.protocol)
+            .set_data(data)
+            .build()?;
+        self.outgoing
+            .send(packet)
+            .await
+            .map_err(|_| anyhow!("Stack stopped"))?;
+        Ok(buf.len())
+    }
+
+    /// Receive the next datagram into `buf`, returning its length and who
+    /// sent it
+    ///
+    /// As with [std::net::UdpSocket::recv_from], whatever doesn't fit in
+    /// `buf` is discarded.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4)> {
+        let (source, data) = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Stack stopped"))?;
+        let length = data.len().min(buf.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        Ok((length, source))
+    }
+}
+
+/// A connection, shared between the stack, which feeds it segments, and
+/// its [TcpStream]
+#[derive(Debug)]
+pub(crate) struct TcpShared {
+    pub(crate) connection: TcpConnection,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl TcpShared {
+    pub(crate) fn new(connection: TcpConnection) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            connection,
+            reader: None,
+            writer: None,
+        }))
+    }
+
+    /// Wake the stream, which may now be able to read or write
+    pub(crate) fn wake(&mut self) {
+        for waker in [self.reader.take(), self.writer.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+}
+
+/// Accepts TCP connections on one port, like [tokio::net::TcpListener]
+#[derive(Debug)]
+pub struct TcpListener {
+    local: SocketAddrV4,
+    incoming: mpsc::Receiver<TcpStream>,
+}
+
+impl TcpListener {
+    pub(crate) const fn new(local: SocketAddrV4, incoming: mpsc::Receiver<TcpStream>) -> Self {
+        Self { local, incoming }
+    }
+
+    /// The address and port this listener is bound to; the address is
+    /// unspecified if it's bound to all of ours
+    pub const fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    /// Next connection to finish its handshake, and who it's from
+    pub async fn accept(&mut self) -> Result<(TcpStream, SocketAddrV4)> {
+        let stream = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Stack stopped"))?;
+        let peer = stream.peer_addr();
+        Ok((stream, peer))
+    }
+}
+
+/// One end of a TCP connection, like [tokio::net::TcpStream]
+///
+/// Dropping it closes the connection, once what's been written is sent.
+#[derive(Debug)]
+pub struct TcpStream {
+    local: SocketAddrV4,
+    peer: SocketAddrV4,
+    shared: Arc<Mutex<TcpShared>>,
+    /// Wakes the stack to send what's been written
+    stack: Arc<Notify>,
+}
+
+impl TcpStream {
+    pub(crate) const fn new(
+        local: SocketAddrV4,
+        peer: SocketAddrV4,
+        shared: Arc<Mutex<TcpShared>>,
+        stack: Arc<Notify>,
+    ) -> Self {
+        Self {
+            local,
+            peer,
+            shared,
+            stack,
+        }
+    }
+
+    pub const fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    pub const fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer
+    }
+
+    /// Bytes that may be written before writing waits for the peer
+    pub fn send_buffer_size(&self) -> usize {
+        self.shared.lock().unwrap().connection.send_buffer_size()
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.connection.set_send_buffer_size(size);
+        shared.wake();
+    }
+
+    /// Bytes received that are held until they're read, which bounds how
+    /// much the peer may send ahead
+    pub fn recv_buffer_size(&self) -> usize {
+        self.shared.lock().unwrap().connection.recv_buffer_size()
+    }
+
+    /// Change the receive buffer; a larger one is advertised to the peer at
+    /// once
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        self.shared
+            .lock()
+            .unwrap()
+            .connection
+            .set_recv_buffer_size(size);
+        self.stack.notify_one();
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.shared.lock().unwrap().connection.nodelay()
+    }
+
+    /// Send small writes at once, rather than gathering them up while
+    /// earlier ones are unacknowledged, as interactive traffic wants
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.shared.lock().unwrap().connection.set_nodelay(nodelay);
+        self.stack.notify_one();
+    }
+
+    pub fn ack_delay(&self) -> Duration {
+        self.shared.lock().unwrap().connection.ack_delay()
+    }
+
+    /// Longest we hold an acknowledgement back for a reply to carry it; zero
+    /// acknowledges everything at once
+    pub fn set_ack_delay(&self, delay: Duration) {
+        self.shared.lock().unwrap().connection.set_ack_delay(delay);
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let length = shared.connection.recv(buf.initialize_unfilled());
+        if length > 0 || buf.remaining() == 0 {
+            buf.advance(length);
+            // The window may have opened
+            if length > 0 {
+                self.stack.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if shared.connection.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if shared.connection.is_finished() {
+            return Poll::Ready(Ok(()));
+        }
+        shared.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.connection.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if !shared.connection.may_send() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let length = shared.connection.send(buf);
+        if length == 0 && !buf.is_empty() {
+            shared.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.stack.notify_one();
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().unwrap().connection.close();
+        self.stack.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.connection.close();
+        }
+        self.stack.notify_one();
+    }
+}
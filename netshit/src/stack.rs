@@ -0,0 +1,3521 @@
+//! The network stack: what we do with frames addressed to us, and with
+//! packets passing through when forwarding
+use crate::acd::{self, AcdEvent, Probe};
+use crate::address::{Addresses, InterfaceAddress};
+use crate::advertiser::RouterAdvertiser;
+use crate::arp_cache::{ArpCache, NeighbourCache};
+use crate::checksum::ChecksumCapabilities;
+use crate::dad::{self, DadEvent, DadProbe};
+use crate::demux::{FiveTuple, SocketTable};
+use crate::eth::{self, EthFrame, Mac6};
+use crate::filter::{Action, Firewall};
+use crate::icmp_error::IcmpErrors;
+use crate::icmpv6_error::Icmpv6Errors;
+use crate::layer3::icmpv6::Icmpv6Packet;
+use crate::layer3::igmp::IgmpPacket;
+use crate::layer3::ipv4::{self, Ipv4Option};
+use crate::layer3::ipv6::{self, Ipv6Packet};
+use crate::layer3::mld;
+use crate::layer3::ndp::{
+    self, NeighbourAdvertisement, NeighbourSolicitation, RouterAdvertisement, RouterSolicitation,
+};
+use crate::layer3::{ArpPacket, IcmpPacket, IpProtocol, Ipv4Packet, Layer3Packet};
+use crate::layer4::tcp::{self, TcpSegment};
+use crate::layer4::{Layer4Packet, PseudoHeader, udp};
+use crate::martian::MartianCounters;
+use crate::multicast::{self, Memberships};
+use crate::multicast6::{self, Ipv6Memberships};
+use crate::nat::Nat;
+use crate::reassembly::{self, Reassembler};
+use crate::resolver::{Resolution, Resolver};
+use crate::route::{InterfaceId, Ipv6RoutingTable, RoutingTable};
+use crate::slaac::Slaac;
+use crate::socket::{
+    RawSocket, Received, SOCKET_QUEUE, TcpListener, TcpShared, TcpStream, UdpSocket,
+};
+use crate::tcp::{TcpConfig, TcpConnection, TcpState};
+use crate::tunnel::{self, TunnelInterface};
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{Notify, mpsc};
+
+/// What's at one entry in the socket table
+#[derive(Debug)]
+enum Socket {
+    Udp(mpsc::Sender<Received>),
+    TcpListener(mpsc::Sender<TcpStream>),
+    Tcp(TcpEntry),
+}
+
+impl Socket {
+    /// Whether the application's dropped its end; closed connections are
+    /// left to [Stack::tidy_tcp]
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Udp(socket) => socket.is_closed(),
+            Self::TcpListener(listener) => listener.is_closed(),
+            Self::Tcp(_) => false,
+        }
+    }
+}
+
+/// A TCP connection
+#[derive(Debug)]
+struct TcpEntry {
+    shared: Arc<Mutex<TcpShared>>,
+    /// Where to hand the connection once it's established, if it was
+    /// accepted and hasn't been yet
+    listener: Option<mpsc::Sender<TcpStream>>,
+}
+
+/// Our end of an Ethernet link
+#[derive(Debug)]
+pub struct Stack {
+    mac: Mac6,
+    addresses: Addresses,
+    /// IPv6 addresses we answer to, if any
+    ipv6_addresses: Vec<Ipv6Addr>,
+    slaac: Slaac,
+    /// Router mode: advertising ourselves and prefixes to hosts
+    advertiser: Option<RouterAdvertiser>,
+    mtu: usize,
+    /// Which checksums to verify on the way in and compute on the way out
+    checksums: ChecksumCapabilities,
+    /// Answer pings
+    echo_replies: bool,
+    /// Route packets that aren't for us
+    forwarding: bool,
+    /// Masquerades packets we forward to other networks, if set
+    nat: Option<Nat>,
+    routes: RoutingTable,
+    /// IPv6 routes, static and learnt from routers
+    ipv6_routes: Ipv6RoutingTable,
+    /// Next hops' MACs, from ARP and gleaned from the frames they send us
+    arp: ArpCache,
+    /// Next hops we're asking for the MACs of
+    resolver: Resolver,
+    /// IPv6 neighbours' MACs, from Neighbour Discovery
+    neighbours: NeighbourCache,
+    /// IPv6 neighbours we're soliciting the MACs of
+    ipv6_resolver: Resolver<Ipv6Addr, Ipv6Packet>,
+    /// IPv6 fragments waiting for the rest of their packets
+    reassembler: Reassembler,
+    /// Identification for the next IPv6 packet we fragment
+    fragment_id: u32,
+    /// Rate-limited sources of ICMP and ICMPv6 errors
+    icmp_errors: IcmpErrors,
+    icmpv6_errors: Icmpv6Errors,
+    /// Addresses being checked for conflicts before we use them
+    probes: Vec<Probe>,
+    /// When we last defended each address against a conflicting host
+    defended: HashMap<Ipv4Addr, Instant>,
+    acd_events: Vec<AcdEvent>,
+    /// IPv6 addresses we're checking nobody else has
+    tentative: Vec<DadProbe>,
+    /// Solicitations to send checking each IPv6 address; none skips the
+    /// check
+    dad_transmits: u8,
+    dad_events: Vec<DadEvent>,
+    multicast: Memberships,
+    /// IPv6 groups we listen to, our solicited-node groups included
+    ipv6_multicast: Ipv6Memberships,
+    /// MLD reports for groups joined outside async code, sent from
+    /// [Stack::poll]
+    mld_backlog: Vec<Icmpv6Packet>,
+    martians: MartianCounters,
+    firewall: Firewall,
+    /// Tunnels, by the interface routes send through them with
+    tunnels: HashMap<InterfaceId, TunnelInterface>,
+    raw_sockets: Vec<(IpProtocol, Option<Ipv4Addr>, mpsc::Sender<Ipv4Packet>)>,
+    /// UDP sockets, TCP listeners and connections, by what they're bound or
+    /// connected to
+    sockets: SocketTable<Socket>,
+    tcp_config: TcpConfig,
+    /// What every connection's timestamps count from, so they only go up
+    tcp_epoch: Instant,
+    /// Keys the Fast Open cookies we hand out
+    fast_open_key: RandomState,
+    /// Fast Open cookies servers have given us, to present next time
+    fast_open_cookies: HashMap<Ipv4Addr, Vec<u8>>,
+    /// Woken by streams with something to send
+    tcp_ready: Arc<Notify>,
+    /// TCP packets waiting for [Stack::next_outgoing]
+    tcp_backlog: VecDeque<Ipv4Packet>,
+    /// Packets sockets want sent; we keep a sender so this never closes
+    outgoing_tx: mpsc::Sender<Ipv4Packet>,
+    outgoing: mpsc::Receiver<Ipv4Packet>,
+}
+
+impl Stack {
+    pub fn new(mac: Mac6, address: InterfaceAddress) -> Self {
+        let (outgoing_tx, outgoing) = mpsc::channel(SOCKET_QUEUE);
+        Self {
+            mac,
+            addresses: Addresses::new(address),
+            ipv6_addresses: Vec::new(),
+            slaac: Slaac::new(mac),
+            advertiser: None,
+            mtu: eth::DEFAULT_MTU,
+            checksums: ChecksumCapabilities::default(),
+            echo_replies: true,
+            forwarding: false,
+            nat: None,
+            routes: RoutingTable::new(),
+            ipv6_routes: Ipv6RoutingTable::new(),
+            arp: ArpCache::new(),
+            resolver: Resolver::new(),
+            neighbours: NeighbourCache::new(),
+            ipv6_resolver: Resolver::new(),
+            reassembler: Reassembler::new(),
+            fragment_id: 0,
+            icmp_errors: IcmpErrors::new(),
+            icmpv6_errors: Icmpv6Errors::new(),
+            probes: Vec::new(),
+            defended: HashMap::new(),
+            acd_events: Vec::new(),
+            tentative: Vec::new(),
+            dad_transmits: dad::DEFAULT_TRANSMITS,
+            dad_events: Vec::new(),
+            multicast: Memberships::new(),
+            ipv6_multicast: Ipv6Memberships::new(),
+            mld_backlog: Vec::new(),
+            martians: MartianCounters::new(),
+            firewall: Firewall::new(),
+            tunnels: HashMap::new(),
+            raw_sockets: Vec::new(),
+            sockets: SocketTable::new(),
+            tcp_config: TcpConfig::new(),
+            tcp_epoch: Instant::now(),
+            fast_open_key: RandomState::new(),
+            fast_open_cookies: HashMap::new(),
+            tcp_ready: Arc::new(Notify::new()),
+            tcp_backlog: VecDeque::new(),
+            outgoing_tx,
+            outgoing,
+        }
+    }
+
+    /// Cache of neighbours' MACs, e.g. to change its timeouts
+    #[must_use]
+    pub fn set_arp_cache(mut self, arp: ArpCache) -> Self {
+        self.arp = arp;
+        self
+    }
+
+    pub const fn arp_cache(&self) -> &ArpCache {
+        &self.arp
+    }
+
+    /// The ARP cache, e.g. to pin static entries
+    pub const fn arp_cache_mut(&mut self) -> &mut ArpCache {
+        &mut self.arp
+    }
+
+    /// The MAC of a neighbour, asking for it with ARP if we don't know it
+    ///
+    /// Requests go out from [Stack::poll], so the stack must keep running
+    /// for this to finish.
+    pub fn resolve(&mut self, address: Ipv4Addr) -> impl Future<Output = Result<Mac6>> + use<> {
+        let now = Instant::now();
+        let known = self
+            .arp
+            .lookup(address, now)
+            .ok_or_else(|| self.resolver.wait(address, now));
+        async move {
+            match known {
+                Ok(mac) => Ok(mac),
+                Err(waiter) => waiter.await.map_err(|_| anyhow!("Stack stopped"))?,
+            }
+        }
+    }
+
+    /// Routes used to decide whether, and where, to send packets
+    #[must_use]
+    pub fn set_routes(mut self, routes: RoutingTable) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub const fn routes_mut(&mut self) -> &mut RoutingTable {
+        &mut self.routes
+    }
+
+    /// Route IPv6 packets with `routes`, before any default router learnt
+    /// from advertisements
+    #[must_use]
+    pub fn set_ipv6_routes(mut self, routes: Ipv6RoutingTable) -> Self {
+        self.ipv6_routes = routes;
+        self
+    }
+
+    pub const fn ipv6_routes(&self) -> &Ipv6RoutingTable {
+        &self.ipv6_routes
+    }
+
+    pub const fn ipv6_routes_mut(&mut self) -> &mut Ipv6RoutingTable {
+        &mut self.ipv6_routes
+    }
+
+    /// Settings for TCP connections opened from now on
+    #[must_use]
+    pub fn set_tcp_config(mut self, config: TcpConfig) -> Self {
+        self.tcp_config = config;
+        self
+    }
+
+    /// Send packets routed through `interface` down `tunnel`, and accept
+    /// packets coming up it
+    #[must_use]
+    pub fn add_tunnel(mut self, interface: InterfaceId, tunnel: TunnelInterface) -> Self {
+        self.tunnels.insert(interface, tunnel);
+        self
+    }
+
+    /// Largest IPv4 packet we'll send
+    #[must_use]
+    pub const fn set_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Which checksums to verify and compute, e.g. none where the other end
+    /// is trusted not to damage anything
+    ///
+    /// We apply the transport ones. The IPv4 header's is applied where
+    /// frames are parsed and written, so pass these to
+    /// [EthFrame::from_reader_with_checksums] and
+    /// [EthFrame::onto_writer_with_checksums] too; headers inside tunnels
+    /// are always checked, as are ICMP and ICMPv6 messages.
+    #[must_use]
+    pub const fn set_checksums(mut self, checksums: ChecksumCapabilities) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    pub const fn checksums(&self) -> &ChecksumCapabilities {
+        &self.checksums
+    }
+
+    /// Whether to answer echo requests (pings) to our address
+    #[must_use]
+    pub const fn set_echo_replies(mut self, echo_replies: bool) -> Self {
+        self.echo_replies = echo_replies;
+        self
+    }
+
+    /// Whether to act as a router, forwarding packets addressed elsewhere,
+    /// IPv4 and IPv6 alike
+    #[must_use]
+    pub const fn set_forwarding(mut self, forwarding: bool) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    /// Rewrite packets we forward through a gateway to come from `nat`'s
+    /// external address, which should be one of ours, and replies to it
+    /// back to the host behind us
+    #[must_use]
+    pub fn set_nat(mut self, nat: Nat) -> Self {
+        self.nat = Some(nat);
+        self
+    }
+
+    pub const fn nat(&self) -> Option<&Nat> {
+        self.nat.as_ref()
+    }
+
+    pub const fn mac(&self) -> Mac6 {
+        self.mac
+    }
+
+    /// Our primary address
+    pub fn address(&self) -> Ipv4Addr {
+        self.addresses.primary()
+    }
+
+    /// A gratuitous ARP for one of our addresses, to broadcast after
+    /// configuring or changing it so neighbours' caches update
+    pub fn announce_address(&self, address: Ipv4Addr) -> Result<EthFrame> {
+        if !self.addresses.contains(address) {
+            bail!("Can't announce {address}, which isn't ours");
+        }
+        Ok(EthFrame::new(
+            Mac6::BROADCAST,
+            self.mac,
+            Layer3Packet::Arp(ArpPacket::gratuitous(address, self.mac)),
+        ))
+    }
+
+    /// Start using `address` once probing shows nobody else is; see
+    /// [Stack::take_acd_events] for the outcome
+    pub fn assign_address(&mut self, address: InterfaceAddress, now: Instant) -> Result<()> {
+        let ip = address.address;
+        if self.addresses.contains(ip)
+            || self
+                .probes
+                .iter()
+                .any(|probe| probe.address().address == ip)
+        {
+            bail!("{ip} is already assigned");
+        }
+        self.probes.push(Probe::new(address, self.mac, now));
+        Ok(())
+    }
+
+    /// Addresses claimed and conflicts found since last called
+    pub fn take_acd_events(&mut self) -> Vec<AcdEvent> {
+        std::mem::take(&mut self.acd_events)
+    }
+
+    pub const fn addresses(&self) -> &Addresses {
+        &self.addresses
+    }
+
+    /// Addresses we answer to; routes to their networks aren't added for
+    /// them
+    pub const fn addresses_mut(&mut self) -> &mut Addresses {
+        &mut self.addresses
+    }
+
+    /// Answer to `address` over IPv6 too
+    ///
+    /// Its solicited-node group is joined, and reported from [Stack::poll].
+    #[must_use]
+    pub fn add_ipv6_address(mut self, address: Ipv6Addr) -> Self {
+        if !self.ipv6_addresses.contains(&address) {
+            self.ipv6_addresses.push(address);
+        }
+        let messages = self.sync_solicited_nodes(Instant::now());
+        self.mld_backlog.extend(messages);
+        self
+    }
+
+    /// Start using `address` for IPv6 once duplicate address detection
+    /// shows nobody else is; see [Stack::take_dad_events] for the outcome
+    ///
+    /// Its solicited-node group is joined straight away, and reported from
+    /// [Stack::poll].
+    pub fn assign_ipv6_address(&mut self, address: Ipv6Addr, now: Instant) -> Result<()> {
+        if self.has_ipv6_address(address) || self.is_tentative(address) {
+            bail!("{address} is already assigned");
+        }
+        if address.is_multicast() || address.is_unspecified() {
+            bail!("{address} can't be assigned");
+        }
+        self.ipv6_addresses.push(address);
+        self.start_dad(address, now);
+        Ok(())
+    }
+
+    /// Send `transmits` solicitations checking each IPv6 address is free
+    /// before using it; 0 uses addresses straight away
+    #[must_use]
+    pub const fn set_dad_transmits(mut self, transmits: u8) -> Self {
+        self.dad_transmits = transmits;
+        self
+    }
+
+    /// IPv6 addresses assigned and duplicates found since last called
+    pub fn take_dad_events(&mut self) -> Vec<DadEvent> {
+        std::mem::take(&mut self.dad_events)
+    }
+
+    /// Whether `address` is being checked, so can't be used yet
+    pub fn is_tentative(&self, address: Ipv6Addr) -> bool {
+        self.tentative
+            .iter()
+            .any(|probe| probe.address() == address)
+    }
+
+    /// Hold `address`, one of ours, back until nobody else turns out to
+    /// have it
+    fn start_dad(&mut self, address: Ipv6Addr, now: Instant) {
+        if self.dad_transmits > 0 {
+            self.tentative
+                .push(DadProbe::new(address, self.dad_transmits, now));
+        }
+        let messages = self.sync_solicited_nodes(now);
+        self.mld_backlog.extend(messages);
+    }
+
+    /// Another host has `address`, which we were checking: give it up, and
+    /// leave its solicited-node group
+    async fn duplicate(&mut self, address: Ipv6Addr, mac: Option<Mac6>) -> Result<Vec<EthFrame>> {
+        self.tentative.retain(|probe| probe.address() != address);
+        self.ipv6_addresses.retain(|&ours| ours != address);
+        self.slaac.remove(address);
+        self.dad_events.push(DadEvent::Duplicate { address, mac });
+        let messages = self.sync_solicited_nodes(Instant::now());
+        self.send_mld(messages).await
+    }
+
+    /// Every IPv6 address we answer to: those added, then those
+    /// autoconfigured, less any still being checked
+    pub fn ipv6_addresses(&self) -> Vec<Ipv6Addr> {
+        let autoconfigured = self.slaac.addresses().map(|entry| entry.address);
+        self.ipv6_addresses
+            .iter()
+            .copied()
+            .chain(autoconfigured)
+            .filter(|&address| !self.is_tentative(address))
+            .collect()
+    }
+
+    fn has_ipv6_address(&self, address: Ipv6Addr) -> bool {
+        (self.ipv6_addresses.contains(&address) || self.slaac.contains(address))
+            && !self.is_tentative(address)
+    }
+
+    /// Autoconfiguration from router advertisements, e.g. to pick how
+    /// addresses are formed
+    #[must_use]
+    pub fn set_slaac(mut self, slaac: Slaac) -> Self {
+        self.slaac = slaac;
+        self
+    }
+
+    /// Addresses and default routers learnt from router advertisements
+    pub const fn slaac(&self) -> &Slaac {
+        &self.slaac
+    }
+
+    /// Act as an IPv6 router, advertising from our link-local address
+    /// starting now
+    ///
+    /// Without [Stack::set_forwarding], a router lifetime of zero hands out
+    /// prefixes without attracting traffic we'd drop.
+    #[must_use]
+    pub fn set_router_advertiser(mut self, mut advertiser: RouterAdvertiser) -> Self {
+        advertiser.start(Instant::now());
+        self.advertiser = Some(advertiser);
+        self
+    }
+
+    /// Reassemble received IPv6 fragments with `reassembler`, e.g. to bound
+    /// its table differently
+    #[must_use]
+    pub fn set_reassembler(mut self, reassembler: Reassembler) -> Self {
+        self.reassembler = reassembler;
+        self
+    }
+
+    /// Send ICMP errors with `errors`, e.g. to rate limit them differently
+    #[must_use]
+    pub const fn set_icmp_errors(mut self, errors: IcmpErrors) -> Self {
+        self.icmp_errors = errors;
+        self
+    }
+
+    pub const fn icmp_errors(&self) -> &IcmpErrors {
+        &self.icmp_errors
+    }
+
+    /// Send ICMPv6 errors with `errors`, e.g. to rate limit them
+    /// differently
+    #[must_use]
+    pub const fn set_icmpv6_errors(mut self, errors: Icmpv6Errors) -> Self {
+        self.icmpv6_errors = errors;
+        self
+    }
+
+    pub const fn icmpv6_errors(&self) -> &Icmpv6Errors {
+        &self.icmpv6_errors
+    }
+
+    /// Cache of IPv6 neighbours' MACs, e.g. to pin static entries
+    pub const fn neighbour_cache_mut(&mut self) -> &mut NeighbourCache {
+        &mut self.neighbours
+    }
+
+    /// Whether to accept IPv6 packets sent to `destination`: our addresses,
+    /// their solicited-node groups, all nodes, and all routers if we are one
+    fn accepts_ipv6(&self, destination: Ipv6Addr) -> bool {
+        let addresses = self.ipv6_addresses();
+        addresses
+            .iter()
+            .any(|&address| address == destination || ndp::solicited_node(address) == destination)
+            || (destination == ndp::ALL_NODES
+                && !(addresses.is_empty() && self.tentative.is_empty()))
+            || (destination == ndp::ALL_ROUTERS && self.advertiser.is_some())
+            || self
+                .ipv6_multicast
+                .groups()
+                .any(|group| group == destination)
+    }
+
+    /// Join or leave solicited-node groups to match our addresses as they
+    /// come and go, returning the MLD messages saying so
+    ///
+    /// Tentative addresses' groups are joined too, to hear them defended.
+    /// Any solicited-node group that isn't one of ours is left, even if it
+    /// was joined by hand.
+    fn sync_solicited_nodes(&mut self, now: Instant) -> Vec<Icmpv6Packet> {
+        let wanted: Vec<Ipv6Addr> = self
+            .ipv6_addresses()
+            .into_iter()
+            .chain(self.tentative.iter().map(DadProbe::address))
+            .map(ndp::solicited_node)
+            .collect();
+        let unwanted: Vec<Ipv6Addr> = self
+            .ipv6_multicast
+            .groups()
+            .filter(|group| ndp::is_solicited_node(*group) && !wanted.contains(group))
+            .collect();
+        let mut messages = Vec::new();
+        for group in unwanted {
+            messages.extend(self.ipv6_multicast.leave(group));
+        }
+        for group in wanted {
+            // Solicited-node groups are always multicast
+            messages.extend(self.ipv6_multicast.join(group, now).ok().flatten());
+        }
+        messages
+    }
+
+    /// Send duplicate address detection's solicitations due by `now`, and
+    /// start using addresses nobody's defended
+    async fn poll_dad(&mut self, now: Instant) -> Result<Vec<EthFrame>> {
+        let mut solicitations = Vec::new();
+        for probe in &mut self.tentative {
+            if let Some(solicitation) = probe.poll(now) {
+                solicitations.push(solicitation);
+            }
+            if probe.is_done(now) {
+                self.dad_events.push(DadEvent::Assigned(probe.address()));
+            }
+        }
+        self.tentative.retain(|probe| !probe.is_done(now));
+        let mut frames = Vec::new();
+        for solicitation in solicitations {
+            let destination = ndp::solicited_node(solicitation.target);
+            let message = Icmpv6Packet::NeighbourSolicitation(solicitation);
+            let packet = ndp_packet(Ipv6Addr::UNSPECIFIED, destination, &message);
+            frames.extend(self.send_ipv6(packet).await?);
+        }
+        Ok(frames)
+    }
+
+    /// Send MLD messages from our link-local address, or the unspecified
+    /// address if we've none yet (RFC 3810 §5.2.13)
+    async fn send_mld(&mut self, messages: Vec<Icmpv6Packet>) -> Result<Vec<EthFrame>> {
+        let source = self
+            .ipv6_addresses()
+            .into_iter()
+            .find(Ipv6Addr::is_unicast_link_local)
+            .unwrap_or(Ipv6Addr::UNSPECIFIED);
+        let mut frames = Vec::new();
+        for message in messages {
+            frames.extend(self.send_ipv6(mld_packet(source, &message)).await?);
+        }
+        Ok(frames)
+    }
+
+    /// Listen to an IPv6 multicast group, returning the frames announcing
+    /// it
+    pub async fn join_ipv6(&mut self, group: Ipv6Addr) -> Result<Vec<EthFrame>> {
+        let report = self.ipv6_multicast.join(group, Instant::now())?;
+        self.send_mld(report.into_iter().collect()).await
+    }
+
+    pub async fn leave_ipv6(&mut self, group: Ipv6Addr) -> Result<Vec<EthFrame>> {
+        let message = self.ipv6_multicast.leave(group);
+        self.send_mld(message.into_iter().collect()).await
+    }
+
+    pub const fn ipv6_memberships(&self) -> &Ipv6Memberships {
+        &self.ipv6_multicast
+    }
+
+    /// Join a multicast group, so packets sent to it are delivered to us
+    pub fn join(&mut self, group: Ipv4Addr) -> Result<()> {
+        if let Some(report) = self.multicast.join(group, Instant::now())? {
+            self.queue(igmp_packet(self.address(), &report)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, group: Ipv4Addr) -> Result<()> {
+        if let Some(message) = self.multicast.leave(group) {
+            self.queue(igmp_packet(self.address(), &message)?)?;
+        }
+        Ok(())
+    }
+
+    pub const fn memberships(&self) -> &Memberships {
+        &self.multicast
+    }
+
+    /// Check packets we receive or forward against `firewall`
+    #[must_use]
+    pub fn set_firewall(mut self, firewall: Firewall) -> Self {
+        self.firewall = firewall;
+        self
+    }
+
+    pub const fn firewall(&self) -> &Firewall {
+        &self.firewall
+    }
+
+    pub const fn firewall_mut(&mut self) -> &mut Firewall {
+        &mut self.firewall
+    }
+
+    /// Received packets dropped for impossible addresses
+    pub const fn martians(&self) -> &MartianCounters {
+        &self.martians
+    }
+
+    /// When [Stack::poll] next has work to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.probes
+            .iter()
+            .filter_map(Probe::next_deadline)
+            .chain(self.multicast.next_deadline())
+            .chain(self.ipv6_multicast.next_deadline())
+            .chain(self.resolver.next_deadline())
+            .chain(self.ipv6_resolver.next_deadline())
+            .chain(self.tentative.iter().map(DadProbe::next_deadline))
+            .chain(self.slaac.next_deadline())
+            .chain(self.ipv6_routes.next_deadline())
+            .chain(self.reassembler.next_deadline())
+            .chain(self.sockets.iter().filter_map(|(_, socket)| match socket {
+                Socket::Tcp(entry) => entry.shared.lock().unwrap().connection.next_deadline(),
+                _ => None,
+            }))
+            .chain(
+                self.advertiser
+                    .as_ref()
+                    .and_then(RouterAdvertiser::next_deadline),
+            )
+            .min()
+    }
+
+    /// Run timers due by `now`, returning any frames to send
+    pub async fn poll(&mut self, now: Instant) -> Result<Vec<EthFrame>> {
+        self.arp.expire(now);
+        if let Some(nat) = &mut self.nat {
+            nat.expire(now);
+        }
+        let mut frames = Vec::new();
+        for probe in &mut self.probes {
+            let claimed = probe.is_claimed();
+            if let Some(arp) = probe.poll(now) {
+                frames.push(EthFrame::new(
+                    Mac6::BROADCAST,
+                    self.mac,
+                    Layer3Packet::Arp(arp),
+                ));
+            }
+            if probe.is_claimed() && !claimed {
+                self.addresses.add(probe.address());
+                self.acd_events
+                    .push(AcdEvent::Claimed(probe.address().address));
+            }
+        }
+        self.probes.retain(|probe| probe.next_deadline().is_some());
+        for resolution in self.resolver.poll(now) {
+            match resolution {
+                Resolution::Request(address) => {
+                    let source = self.addresses.source_for(address);
+                    let arp = ArpPacket::request(source, self.mac, address);
+                    frames.push(EthFrame::new(
+                        Mac6::BROADCAST,
+                        self.mac,
+                        Layer3Packet::Arp(arp),
+                    ));
+                }
+                // Nothing more to be done for the parked packets
+                Resolution::Failed(_, _) => {}
+            }
+        }
+
+        self.neighbours.expire(now);
+        self.slaac.expire(now);
+        self.ipv6_routes.expire(now);
+        frames.extend(self.poll_dad(now).await?);
+        let mut messages = std::mem::take(&mut self.mld_backlog);
+        messages.extend(self.sync_solicited_nodes(now));
+        frames.extend(self.send_mld(messages).await?);
+        let reports = self.ipv6_multicast.poll(now);
+        frames.extend(self.send_mld(reports).await?);
+        for first in self.reassembler.expire(now) {
+            let Some(source) = self.ipv6_source(first.destination) else {
+                continue;
+            };
+            let error = self.icmpv6_errors.reassembly_timeout(source, &first, now)?;
+            frames.extend(self.send_icmpv6_error(error).await?);
+        }
+        let link_local = self
+            .ipv6_addresses()
+            .into_iter()
+            .find(Ipv6Addr::is_unicast_link_local);
+        if let Some(advertiser) = &mut self.advertiser
+            && let Some(advertisement) = advertiser.poll(now, self.mac)
+            && let Some(source) = link_local
+        {
+            let advertisement = Icmpv6Packet::RouterAdvertisement(advertisement);
+            frames.extend(
+                self.send_ipv6(ndp_packet(source, ndp::ALL_NODES, &advertisement))
+                    .await?,
+            );
+        }
+        for resolution in self.ipv6_resolver.poll(now) {
+            // Without an address there's nothing to solicit from
+            if let Resolution::Request(target) = resolution
+                && let Some(&source) = self.ipv6_addresses().first()
+            {
+                let solicitation = NeighbourSolicitation::new(target, self.mac);
+                frames.extend(
+                    self.send_ipv6(ndp_packet(
+                        source,
+                        ndp::solicited_node(target),
+                        &Icmpv6Packet::NeighbourSolicitation(solicitation),
+                    ))
+                    .await?,
+                );
+            }
+        }
+
+        for report in self.multicast.poll(now) {
+            frames.extend(self.send(igmp_packet(self.address(), &report)?).await?);
+        }
+        for packet in self.poll_tcp(now)? {
+            frames.extend(self.send(packet).await?);
+        }
+        Ok(frames)
+    }
+
+    /// Send a packet from the stack itself, through the same queue as
+    /// sockets
+    fn queue(&self, packet: Ipv4Packet) -> Result<()> {
+        self.outgoing_tx
+            .try_send(packet)
+            .map_err(|_| anyhow!("Stack: send queue full"))
+    }
+
+    /// Open a socket receiving every packet of `protocol` addressed to us
+    pub fn raw_socket(&mut self, protocol: IpProtocol) -> RawSocket {
+        let (tx, rx) = mpsc::channel(SOCKET_QUEUE);
+        self.raw_sockets.push((protocol, None, tx));
+        RawSocket::new(protocol, None, rx, self.outgoing_tx.clone())
+    }
+
+    /// Open a socket receiving packets of `protocol` addressed to `address`
+    /// only, and sending from it
+    pub fn bind_raw_socket(
+        &mut self,
+        protocol: IpProtocol,
+        address: Ipv4Addr,
+    ) -> Result<RawSocket> {
+        if !self.addresses.contains(address) {
+            bail!("Can't bind to {address}, which isn't ours");
+        }
+        let (tx, rx) = mpsc::channel(SOCKET_QUEUE);
+        self.raw_sockets.push((protocol, Some(address), tx));
+        Ok(RawSocket::new(
+            protocol,
+            Some(address),
+            rx,
+            self.outgoing_tx.clone(),
+        ))
+    }
+
+    /// Open a UDP socket on `port` at `address`, which is ours, a group, or
+    /// unspecified for all of ours; port 0 picks a free one
+    ///
+    /// Each port is used by one socket, unless they're bound to different
+    /// addresses.
+    pub fn bind_udp(&mut self, address: Ipv4Addr, port: u16) -> Result<UdpSocket> {
+        self.bind_datagram(IpProtocol::Udp, address, port)
+            .context("UDP")
+    }
+
+    /// Open a UDP-Lite socket, as [Stack::bind_udp] does a UDP one; its
+    /// ports are apart from UDP's
+    pub fn bind_udp_lite(&mut self, address: Ipv4Addr, port: u16) -> Result<UdpSocket> {
+        self.bind_datagram(IpProtocol::UdpLite, address, port)
+            .context("UDP-Lite")
+    }
+
+    fn bind_datagram(
+        &mut self,
+        protocol: IpProtocol,
+        address: Ipv4Addr,
+        port: u16,
+    ) -> Result<UdpSocket> {
+        if !address.is_unspecified() && !address.is_multicast() && !self.addresses.contains(address)
+        {
+            bail!("Can't bind to {address}, which isn't ours");
+        }
+        self.sockets.retain(|_, socket| !socket.is_closed());
+        let port = self.sockets.pick_port(protocol, address, port)?;
+        let (tx, rx) = mpsc::channel(SOCKET_QUEUE);
+        let local = SocketAddrV4::new(address, port);
+        self.sockets
+            .insert(FiveTuple::bound(protocol, local), Socket::Udp(tx))?;
+        Ok(UdpSocket::new(
+            protocol,
+            local,
+            self.checksums,
+            rx,
+            self.outgoing_tx.clone(),
+        ))
+    }
+
+    /// Listen for TCP connections on `port` at `address`, which is ours or
+    /// unspecified for all of ours; port 0 picks a free one
+    pub fn bind_tcp(&mut self, address: Ipv4Addr, port: u16) -> Result<TcpListener> {
+        if !address.is_unspecified() && !self.addresses.contains(address) {
+            bail!("Can't bind to {address}, which isn't ours");
+        }
+        self.sockets.retain(|_, socket| !socket.is_closed());
+        let port = self
+            .sockets
+            .pick_port(IpProtocol::Tcp, address, port)
+            .context("TCP")?;
+        let (tx, rx) = mpsc::channel(SOCKET_QUEUE);
+        let local = SocketAddrV4::new(address, port);
+        self.sockets.insert(
+            FiveTuple::bound(IpProtocol::Tcp, local),
+            Socket::TcpListener(tx),
+        )?;
+        Ok(TcpListener::new(local, rx))
+    }
+
+    /// Open a TCP connection to `remote`, from a free port on the address
+    /// we'd send to it from
+    ///
+    /// The stream's returned at once: what's written is sent once the
+    /// handshake is done, and reading fails if it's refused. With Fast Open,
+    /// what's written before the stack next runs goes in the SYN, if we've a
+    /// cookie from `remote`. Out of ports, one lingering in TIME-WAIT to
+    /// `remote` is taken over, if [TcpConfig::set_time_wait_reuse] allows.
+    pub fn connect_tcp(&mut self, remote: SocketAddrV4) -> Result<TcpStream> {
+        let address = self.addresses.source_for(*remote.ip());
+        let port = match self.sockets.pick_port(IpProtocol::Tcp, address, 0) {
+            Ok(port) => port,
+            Err(err) => self
+                .reuse_time_wait(address, remote)
+                .ok_or(err)
+                .context("TCP")?,
+        };
+        let local = SocketAddrV4::new(address, port);
+        let mut connection =
+            TcpConnection::connect(port, remote.port(), tcp_iss(local, remote), self.tcp_mss())
+                .set_config(self.tcp_config);
+        connection.set_timestamp_epoch(self.tcp_epoch);
+        if self.tcp_config.fast_open() {
+            let cookie = self.fast_open_cookies.get(remote.ip()).cloned();
+            connection.set_fast_open_cookie(cookie);
+        }
+        let shared = TcpShared::new(connection);
+        let entry = TcpEntry {
+            shared: shared.clone(),
+            listener: None,
+        };
+        self.sockets.insert(
+            FiveTuple::new(IpProtocol::Tcp, local, remote),
+            Socket::Tcp(entry),
+        )?;
+        self.tcp_ready.notify_one();
+        Ok(TcpStream::new(
+            local,
+            remote,
+            shared,
+            self.tcp_ready.clone(),
+        ))
+    }
+
+    /// Out of ports, forget a connection from `address` to `remote` that's
+    /// lingering in TIME-WAIT and may give up its port, returning the port
+    fn reuse_time_wait(&mut self, address: Ipv4Addr, remote: SocketAddrV4) -> Option<u16> {
+        let now = Instant::now();
+        let (tuple, _) = self.sockets.iter().find(|(tuple, socket)| {
+            *tuple.local.ip() == address
+                && tuple.remote == remote
+                && matches!(socket, Socket::Tcp(entry)
+                    if entry.shared.lock().unwrap().connection.is_reusable(now))
+        })?;
+        let tuple = *tuple;
+        self.sockets.remove(&tuple);
+        Some(tuple.local.port())
+    }
+
+    /// Largest TCP segment we can take, without fragmenting
+    fn tcp_mss(&self) -> u16 {
+        let overhead = ipv4::MIN_HEADER_LENGTH as usize + tcp::MIN_HEADER_LENGTH;
+        u16::try_from(self.mtu.saturating_sub(overhead)).unwrap_or(u16::MAX)
+    }
+
+    /// Next frame a socket wants sent
+    ///
+    /// Packets that can't be routed are dropped. Cancel safe.
+    pub async fn next_outgoing(&mut self) -> EthFrame {
+        loop {
+            let mut packet = match self.tcp_backlog.pop_front() {
+                Some(packet) => packet,
+                None => tokio::select! {
+                    packet = self.outgoing.recv() => packet.expect("the stack holds a sender"),
+                    () = self.tcp_ready.notified() => {
+                        if let Ok(packets) = self.poll_tcp(Instant::now()) {
+                            self.tcp_backlog.extend(packets);
+                        }
+                        continue;
+                    }
+                },
+            };
+            if packet.source.is_unspecified() {
+                let source = self.addresses.source_for(packet.destination);
+                // UDP-Lite's checksum is where UDP's is, and never left out
+                if matches!(packet.protocol, IpProtocol::Udp | IpProtocol::UdpLite) {
+                    udp::update_checksum(&mut packet.data, &[0; 4], &source.octets());
+                }
+                packet.source = source;
+                packet.header_checksum = None;
+            }
+            if let Ok(Some(frame)) = self.send(packet).await {
+                return frame;
+            }
+        }
+    }
+
+    /// Handle a received frame, returning any frames to send in response
+    pub async fn handle(&mut self, frame: &EthFrame) -> Result<Vec<EthFrame>> {
+        let packet = match frame.payload() {
+            Layer3Packet::Ipv4(packet) => packet,
+            Layer3Packet::Arp(arp) => return Ok(self.handle_arp(frame, arp)),
+            Layer3Packet::Ipv6(packet) => return self.handle_ipv6(packet).await,
+            _ => return Ok(Vec::new()),
+        };
+        if !self.martians.screen(packet, &self.addresses) {
+            return Ok(Vec::new());
+        }
+        self.glean(packet.source, frame.src());
+        if packet.protocol == IpProtocol::Ipv6 && self.addresses.contains(packet.destination) {
+            return self.receive_6in4(packet).await;
+        }
+
+        let mut frames = Vec::new();
+        for packet in self.handle_packet(packet).await? {
+            frames.extend(self.send(packet).await?);
+        }
+        Ok(frames)
+    }
+
+    /// Handle a received packet, returning any packets to send in response
+    async fn handle_packet(&mut self, packet: &Ipv4Packet) -> Result<Vec<Ipv4Packet>> {
+        if self.firewall.evaluate(packet, Instant::now()) == Action::Drop {
+            return Ok(Vec::new());
+        }
+        // Replies to hosts behind the NAT are theirs, not ours
+        if let Some(nat) = &mut self.nat
+            && packet.destination == nat.external()
+        {
+            let mut packet = packet.clone();
+            if nat.inbound(&mut packet, Instant::now()) {
+                return self.forward(&packet).await;
+            }
+        }
+        if self.addresses.contains(packet.destination)
+            || self.is_broadcast(packet.destination)
+            || self.multicast.is_member(packet.destination)
+        {
+            self.deliver(packet).await
+        } else if self.forwarding {
+            self.forward(packet).await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Handle a packet addressed to us, returning any replies
+    async fn deliver(&mut self, packet: &Ipv4Packet) -> Result<Vec<Ipv4Packet>> {
+        // A full socket drops the packet, a closed one is forgotten
+        self.raw_sockets.retain(|(protocol, bound, socket)| {
+            *protocol != packet.protocol
+                || bound.is_some_and(|bound| bound != packet.destination)
+                || !matches!(
+                    socket.try_send(packet.clone()),
+                    Err(mpsc::error::TrySendError::Closed(_))
+                )
+        });
+
+        Ok(match packet.protocol {
+            IpProtocol::Icmp => self.handle_icmp(packet).await?,
+            IpProtocol::Igmp => {
+                let message = IgmpPacket::from_reader(packet.data.as_slice()).await?;
+                self.multicast.handle(&message, Instant::now());
+                Vec::new()
+            }
+            IpProtocol::IpInIp => self.decapsulate(packet).await?,
+            IpProtocol::Tcp => match packet.payload_with_checksums(&self.checksums).await? {
+                Layer4Packet::Tcp(segment) => self.handle_tcp(packet, &segment)?,
+                _ => Vec::new(),
+            },
+            // Malformed datagrams don't get an error
+            IpProtocol::Udp | IpProtocol::UdpLite => {
+                let datagram = match packet.payload_with_checksums(&self.checksums).await? {
+                    Layer4Packet::Udp(datagram) => Some((
+                        datagram.source_port,
+                        datagram.destination_port,
+                        datagram.data,
+                    )),
+                    Layer4Packet::UdpLite(datagram) => Some((
+                        datagram.source_port,
+                        datagram.destination_port,
+                        datagram.data,
+                    )),
+                    _ => None,
+                };
+                if let Some((source_port, destination_port, data)) = datagram
+                    && self.deliver_udp(packet, source_port, destination_port, data)
+                {
+                    Vec::new()
+                } else if self.is_broadcast(packet.destination) {
+                    // Nobody listening to a broadcast isn't an error
+                    // (RFC 1122 §3.2.2)
+                    Vec::new()
+                } else {
+                    let source = self.reply_source(packet);
+                    self.icmp_errors
+                        .port_unreachable(source, packet, Instant::now())
+                        .await?
+                        .into_iter()
+                        .collect()
+                }
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Hand `segment`, from `packet`, to its connection, or start one if
+    /// it's a SYN someone's listening for
+    fn handle_tcp(&mut self, packet: &Ipv4Packet, segment: &TcpSegment) -> Result<Vec<Ipv4Packet>> {
+        if !self.addresses.contains(packet.destination) {
+            return Ok(Vec::new());
+        }
+        let now = Instant::now();
+        let local = SocketAddrV4::new(packet.destination, segment.destination_port);
+        let remote = SocketAddrV4::new(packet.source, segment.source_port);
+        let tuple = FiveTuple::new(IpProtocol::Tcp, local, remote);
+        // A new connection between the same ports, which the one lingering
+        // in TIME-WAIT may make way for
+        if let Some(Socket::Tcp(entry)) = self.socket_for(&tuple)
+            && entry
+                .shared
+                .lock()
+                .unwrap()
+                .connection
+                .is_reusable_by(segment)
+        {
+            self.sockets.remove(&tuple);
+        }
+        let listener = match self.socket_for(&tuple) {
+            Some(Socket::Tcp(entry)) => {
+                let mut shared = entry.shared.lock().unwrap();
+                let connecting = shared.connection.state() == TcpState::SynSent;
+                let replies = shared.connection.handle(segment, now);
+                // Keep any Fast Open cookie the server's given us
+                let cookie = shared
+                    .connection
+                    .fast_open_cookie()
+                    .filter(|_| connecting && shared.connection.config().fast_open())
+                    .map(<[u8]>::to_vec);
+                shared.wake();
+                drop(shared);
+                if let Some(cookie) = cookie {
+                    self.fast_open_cookies.insert(packet.source, cookie);
+                }
+                self.tidy_tcp();
+                return replies
+                    .iter()
+                    .map(|reply| tcp_packet(local, remote, reply, &self.checksums))
+                    .collect();
+            }
+            Some(Socket::TcpListener(listener)) => Some(listener.clone()),
+            _ => None,
+        };
+        let flags = segment.flags;
+        let opening = flags.syn && !flags.rst && segment.acknowledgement.is_none();
+        let listening = listener.is_some();
+        let Some(listener) = listener.filter(|_| opening) else {
+            // A listener only refuses acknowledgements; otherwise nothing's
+            // there, and the peer needn't wait to find out
+            if listening && segment.acknowledgement.is_none() {
+                return Ok(Vec::new());
+            }
+            return crate::tcp::refusal(segment)
+                .iter()
+                .map(|reset| tcp_packet(local, remote, reset, &self.checksums))
+                .collect();
+        };
+        let mut connection = TcpConnection::accept(segment, tcp_iss(local, remote), self.tcp_mss())
+            .set_config(self.tcp_config);
+        connection.set_timestamp_epoch(self.tcp_epoch);
+        let cookie = self.fast_open_key.hash_one(packet.source).to_be_bytes();
+        connection.fast_open(segment, cookie.to_vec());
+        let replies = connection.poll(now);
+        let entry = TcpEntry {
+            shared: TcpShared::new(connection),
+            listener: Some(listener),
+        };
+        self.sockets.insert(tuple, Socket::Tcp(entry))?;
+        self.tidy_tcp();
+        replies
+            .iter()
+            .map(|reply| tcp_packet(local, remote, reply, &self.checksums))
+            .collect()
+    }
+
+    /// Run TCP timers, and send what streams have written, returning the
+    /// packets
+    fn poll_tcp(&mut self, now: Instant) -> Result<Vec<Ipv4Packet>> {
+        let mut packets = Vec::new();
+        for (tuple, socket) in self.sockets.iter() {
+            let Socket::Tcp(entry) = socket else {
+                continue;
+            };
+            let mut shared = entry.shared.lock().unwrap();
+            for segment in shared.connection.poll(now) {
+                packets.push(tcp_packet(
+                    tuple.local,
+                    tuple.remote,
+                    &segment,
+                    &self.checksums,
+                )?);
+            }
+            shared.wake();
+        }
+        self.tidy_tcp();
+        Ok(packets)
+    }
+
+    /// Hand newly established connections to their listeners, and forget
+    /// closed ones
+    fn tidy_tcp(&mut self) {
+        let ready = &self.tcp_ready;
+        self.sockets.retain(|tuple, socket| {
+            let Socket::Tcp(entry) = socket else {
+                return true;
+            };
+            let shared = entry.shared.lock().unwrap();
+            let (state, fast_open) = (shared.connection.state(), shared.connection.is_fast_open());
+            drop(shared);
+            if state == TcpState::Closed {
+                return false;
+            }
+            // Fast Open data's handed over before the handshake's done
+            if (state != TcpState::SynReceived || fast_open)
+                && let Some(listener) = entry.listener.take()
+            {
+                let stream = TcpStream::new(
+                    tuple.local,
+                    tuple.remote,
+                    entry.shared.clone(),
+                    ready.clone(),
+                );
+                // If nobody takes it, dropping it closes it
+                let _ = listener.try_send(stream);
+            }
+            true
+        });
+    }
+
+    /// Hand a datagram's `data`, from `packet`, to the socket bound to its
+    /// destination, returning whether there is one
+    ///
+    /// A socket bound to the address itself wins over one bound to all of
+    /// ours. A full socket drops the datagram.
+    fn deliver_udp(
+        &mut self,
+        packet: &Ipv4Packet,
+        source_port: u16,
+        destination_port: u16,
+        data: Vec<u8>,
+    ) -> bool {
+        let source = SocketAddrV4::new(packet.source, source_port);
+        let destination = SocketAddrV4::new(packet.destination, destination_port);
+        let tuple = FiveTuple::new(packet.protocol, destination, source);
+        let Some(Socket::Udp(socket)) = self.socket_for(&tuple) else {
+            return false;
+        };
+        let _ = socket.try_send((source, data));
+        true
+    }
+
+    /// The open socket a packet between `tuple`'s ends is for, forgetting
+    /// any the application's dropped
+    fn socket_for(&mut self, tuple: &FiveTuple) -> Option<&Socket> {
+        while let Some((&found, socket)) = self.sockets.lookup(tuple)
+            && socket.is_closed()
+        {
+            self.sockets.remove(&found);
+        }
+        self.sockets.lookup(tuple).map(|(_, socket)| socket)
+    }
+
+    /// Handle a received IPv6 packet: take part in Neighbour Discovery and
+    /// autoconfiguration, answer pings, and report protocols nothing
+    /// listens on
+    async fn handle_ipv6(&mut self, raw: &[u8]) -> Result<Vec<EthFrame>> {
+        let packet = Ipv6Packet::from_reader(raw).await?;
+        self.handle_ipv6_packet(packet).await
+    }
+
+    /// Handle an IPv6 packet, from the link or out of a tunnel
+    async fn handle_ipv6_packet(&mut self, mut packet: Ipv6Packet) -> Result<Vec<EthFrame>> {
+        if !self.accepts_ipv6(packet.destination) {
+            return match self.forwarding {
+                true => self.forward_ipv6(packet).await,
+                false => Ok(Vec::new()),
+            };
+        }
+        packet.skip_hop_by_hop()?;
+        if packet.next_header == IpProtocol::Ipv6Fragment {
+            if packet.source.is_unspecified() || packet.source.is_multicast() {
+                return Ok(Vec::new());
+            }
+            match self.reassembler.add(&packet, Instant::now())? {
+                Some(reassembled) => packet = reassembled,
+                None => return Ok(Vec::new()),
+            }
+        }
+        let (source, destination) = (packet.source, packet.destination);
+        let message = match packet.next_header {
+            IpProtocol::Icmpv6 => {
+                Icmpv6Packet::from_reader(packet.data.as_slice(), source, destination).await?
+            }
+            // Nothing listens on UDP ports yet
+            IpProtocol::Udp => {
+                packet.payload().await?;
+                let error =
+                    self.icmpv6_errors
+                        .port_unreachable(destination, &packet, Instant::now())?;
+                return self.send_icmpv6_error(error).await;
+            }
+            _ => return Ok(Vec::new()),
+        };
+        match message {
+            // The only messages that may come from nowhere: checking an
+            // address is free, and asking for routers before having one
+            // Someone else checking for an address we are
+            Icmpv6Packet::NeighbourSolicitation(solicitation)
+                if self
+                    .tentative
+                    .iter()
+                    .any(|probe| probe.contested(&packet, &solicitation)) =>
+            {
+                self.duplicate(solicitation.target, None).await
+            }
+            Icmpv6Packet::NeighbourSolicitation(solicitation) if !source.is_multicast() => {
+                self.handle_solicitation(&packet, &solicitation).await
+            }
+            Icmpv6Packet::RouterSolicitation(solicitation) if !source.is_multicast() => {
+                Ok(self.handle_router_solicitation(&packet, &solicitation))
+            }
+            _ if source.is_unspecified() || source.is_multicast() => Ok(Vec::new()),
+            Icmpv6Packet::EchoRequest(echo) if self.echo_replies => {
+                self.icmpv6_reply(&packet, &Icmpv6Packet::EchoReply(echo))
+                    .await
+            }
+            // Someone defending an address we're checking
+            Icmpv6Packet::NeighbourAdvertisement(advertisement)
+                if packet.hop_limit == ndp::HOP_LIMIT
+                    && self
+                        .tentative
+                        .iter()
+                        .any(|probe| probe.defended(&advertisement)) =>
+            {
+                self.duplicate(advertisement.target, advertisement.target_mac())
+                    .await
+            }
+            Icmpv6Packet::NeighbourAdvertisement(advertisement) => {
+                Ok(self.handle_advertisement(&packet, &advertisement))
+            }
+            Icmpv6Packet::RouterAdvertisement(advertisement) => {
+                let mut frames = self.handle_router_advertisement(&packet, &advertisement);
+                let messages = self.sync_solicited_nodes(Instant::now());
+                frames.extend(self.send_mld(messages).await?);
+                Ok(frames)
+            }
+            // Queriers and other listeners are on the link
+            Icmpv6Packet::MldQuery(_) | Icmpv6Packet::MldV1Report(_)
+                if packet.hop_limit == mld::HOP_LIMIT && source.is_unicast_link_local() =>
+            {
+                self.ipv6_multicast.handle(&message, Instant::now());
+                Ok(Vec::new())
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Send an ICMPv6 message back to the source of `packet`
+    async fn icmpv6_reply(
+        &mut self,
+        packet: &Ipv6Packet,
+        message: &Icmpv6Packet,
+    ) -> Result<Vec<EthFrame>> {
+        // Nothing to answer from, e.g. while our only address is tentative
+        let Some(source) = self.ipv6_source(packet.destination) else {
+            return Ok(Vec::new());
+        };
+        let reply = Ipv6Packet::new(
+            source,
+            packet.source,
+            IpProtocol::Icmpv6,
+            message.to_bytes(source, packet.source),
+        );
+        self.send_ipv6(reply).await
+    }
+
+    /// Advertise soon, if we're a router, learning the sender's MAC on the
+    /// way
+    fn handle_router_solicitation(
+        &mut self,
+        packet: &Ipv6Packet,
+        solicitation: &RouterSolicitation,
+    ) -> Vec<EthFrame> {
+        let Some(advertiser) = &mut self.advertiser else {
+            return Vec::new();
+        };
+        if packet.hop_limit != ndp::HOP_LIMIT {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        advertiser.solicited(now);
+        match solicitation.source_mac() {
+            Some(mac) if !packet.source.is_unspecified() => {
+                self.neighbours.observe(packet.source, mac, now);
+                self.release_parked(packet.source)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Autoconfigure from a router's advertisement, returning whatever was
+    /// waiting for the router's MAC
+    fn handle_router_advertisement(
+        &mut self,
+        packet: &Ipv6Packet,
+        advertisement: &RouterAdvertisement,
+    ) -> Vec<EthFrame> {
+        // Routers advertise from their link-local address, and never from
+        // off-link
+        if packet.hop_limit != ndp::HOP_LIMIT || !packet.source.is_unicast_link_local() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let formed = self
+            .slaac
+            .handle(packet.source, advertisement, &mut self.ipv6_routes, now);
+        for address in formed {
+            self.start_dad(address, now);
+        }
+        let Some(mac) = advertisement.source_mac() else {
+            return Vec::new();
+        };
+        self.neighbours.observe(packet.source, mac, now);
+        self.release_parked(packet.source)
+    }
+
+    /// Answer a solicitation for one of our addresses, learning the
+    /// sender's MAC on the way
+    async fn handle_solicitation(
+        &mut self,
+        packet: &Ipv6Packet,
+        solicitation: &NeighbourSolicitation,
+    ) -> Result<Vec<EthFrame>> {
+        if packet.hop_limit != ndp::HOP_LIMIT || !self.has_ipv6_address(solicitation.target) {
+            return Ok(Vec::new());
+        }
+        let mut frames = Vec::new();
+        let mut advertisement = NeighbourAdvertisement::answer(solicitation.target, self.mac);
+        let destination = if packet.source.is_unspecified() {
+            // Someone checking whether the address is free: it isn't, and
+            // they've no address to answer at
+            advertisement.solicited = false;
+            ndp::ALL_NODES
+        } else {
+            if let Some(mac) = solicitation.source_mac() {
+                self.neighbours.observe(packet.source, mac, Instant::now());
+                frames.extend(self.release_parked(packet.source));
+            }
+            packet.source
+        };
+        let reply = ndp_packet(
+            solicitation.target,
+            destination,
+            &Icmpv6Packet::NeighbourAdvertisement(advertisement),
+        );
+        frames.extend(self.send_ipv6(reply).await?);
+        Ok(frames)
+    }
+
+    /// Learn a neighbour's MAC from its advertisement
+    fn handle_advertisement(
+        &mut self,
+        packet: &Ipv6Packet,
+        advertisement: &NeighbourAdvertisement,
+    ) -> Vec<EthFrame> {
+        if packet.hop_limit != ndp::HOP_LIMIT
+            || advertisement.target.is_multicast()
+            || (advertisement.solicited && packet.destination.is_multicast())
+        {
+            return Vec::new();
+        }
+        let (target, now) = (advertisement.target, Instant::now());
+        let Some(mac) = advertisement
+            .target_mac()
+            .or_else(|| self.neighbours.lookup(target, now))
+        else {
+            return Vec::new();
+        };
+        if advertisement.solicited {
+            self.neighbours.confirm(target, mac, now);
+        } else if !self.neighbours.update(target, mac, now)
+            && self.ipv6_resolver.is_resolving(target)
+        {
+            self.neighbours.observe(target, mac, now);
+        }
+        self.release_parked(target)
+    }
+
+    /// Send whatever was waiting for `address`'s MAC, now that we know it
+    fn release_parked(&mut self, address: Ipv6Addr) -> Vec<EthFrame> {
+        let Some(mac) = self.neighbours.lookup(address, Instant::now()) else {
+            return Vec::new();
+        };
+        self.ipv6_resolver
+            .resolved(address, mac)
+            .into_iter()
+            .filter_map(|packet| {
+                Some(EthFrame::new(
+                    mac,
+                    self.mac,
+                    Layer3Packet::Ipv6(packet.to_bytes().ok()?),
+                ))
+            })
+            .collect()
+    }
+
+    /// The address to answer a packet sent to `destination` from, if we've
+    /// one we may use; tentative addresses can't be
+    fn ipv6_source(&self, destination: Ipv6Addr) -> Option<Ipv6Addr> {
+        if self.has_ipv6_address(destination) {
+            Some(destination)
+        } else {
+            self.ipv6_addresses().first().copied()
+        }
+    }
+
+    /// Our address to send to `peer` from when it's not answering a packet
+    /// to us: one as local as `peer` if we have it, so off-link peers
+    /// don't get link-local sources
+    fn ipv6_source_toward(&self, peer: Ipv6Addr) -> Ipv6Addr {
+        let addresses = self.ipv6_addresses();
+        addresses
+            .iter()
+            .copied()
+            .find(|address| address.is_unicast_link_local() == peer.is_unicast_link_local())
+            .unwrap_or(addresses[0])
+    }
+
+    /// Where to send a packet for `destination`: straight there if it's
+    /// link-local, otherwise by our routes, static or advertised
+    ///
+    /// Without a route, everything is assumed to be on-link.
+    fn ipv6_next_hop(&self, destination: Ipv6Addr) -> Ipv6Addr {
+        if destination.is_unicast_link_local() {
+            return destination;
+        }
+        self.ipv6_routes
+            .lookup(destination)
+            .map_or(destination, |route| route.next_hop(destination))
+    }
+
+    /// Frame an IPv6 packet for its next hop, fragmenting it if it's too
+    /// big for the link
+    ///
+    /// Returns nothing if the packet's parked until the next hop's MAC is
+    /// resolved.
+    async fn send_ipv6(&mut self, packet: Ipv6Packet) -> Result<Vec<EthFrame>> {
+        if let Some(tunnel) = self.ipv6_tunnel(packet.destination) {
+            return self.send_6in4(&tunnel, packet).await;
+        }
+        let dst = match Mac6::from_ipv6_multicast(packet.destination) {
+            Some(dst) => Some(dst),
+            None => {
+                let next_hop = self.ipv6_next_hop(packet.destination);
+                self.neighbours.lookup(next_hop, Instant::now())
+            }
+        };
+        let identification = self.fragment_id;
+        let fragments = reassembly::fragment(packet, self.mtu, identification)?;
+        if fragments.len() > 1 {
+            self.fragment_id = identification.wrapping_add(1);
+        }
+        let mut frames = Vec::new();
+        for fragment in fragments {
+            match dst {
+                Some(dst) => frames.push(EthFrame::new(
+                    dst,
+                    self.mac,
+                    Layer3Packet::Ipv6(fragment.to_bytes()?),
+                )),
+                None => {
+                    let next_hop = self.ipv6_next_hop(fragment.destination);
+                    self.ipv6_resolver.park(next_hop, fragment, Instant::now());
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Pass on a packet addressed elsewhere, or say why we can't
+    async fn forward(&mut self, packet: &Ipv4Packet) -> Result<Vec<Ipv4Packet>> {
+        let destination = packet.destination;
+        if destination.is_broadcast()
+            || destination.is_multicast()
+            || self.routes.lookup(destination).is_none()
+        {
+            return Ok(Vec::new());
+        }
+        let (source, now) = (self.reply_source(packet), Instant::now());
+        if packet.ttl <= 1 {
+            return Ok(self
+                .icmp_errors
+                .ttl_exceeded(source, packet, now)
+                .await?
+                .into_iter()
+                .collect());
+        }
+        if packet.header_length()? + packet.data.len() > self.mtu {
+            // We always send with Don't Fragment
+            let mtu = u16::try_from(self.mtu)?;
+            let error = self
+                .icmp_errors
+                .fragmentation_needed(source, packet, mtu, now)
+                .await?;
+            return Ok(error.into_iter().collect());
+        }
+
+        let mut packet = packet.clone();
+        // Can't fail, having checked the TTL above
+        packet.decrement_ttl();
+        let routed = self
+            .routes
+            .lookup(destination)
+            .is_some_and(|route| route.gateway.is_some());
+        if let Some(nat) = &mut self.nat
+            && routed
+            && packet.source != nat.external()
+            // What it can't translate would give away the hosts behind us
+            && nat.outbound(&mut packet, now).is_err()
+        {
+            return Ok(Vec::new());
+        }
+        Ok(vec![packet])
+    }
+
+    /// Pass on an IPv6 packet addressed elsewhere, or say why we can't
+    async fn forward_ipv6(&mut self, mut packet: Ipv6Packet) -> Result<Vec<EthFrame>> {
+        let (source, destination) = (packet.source, packet.destination);
+        if source.is_unspecified()
+            || source.is_multicast()
+            || destination.is_multicast()
+            || destination.is_unicast_link_local()
+            || self.ipv6_addresses().is_empty()
+        {
+            return Ok(Vec::new());
+        }
+        let now = Instant::now();
+        let ours = self.ipv6_source_toward(source);
+        let error = if source.is_unicast_link_local() {
+            self.icmpv6_errors.beyond_scope(ours, &packet, now)?
+        } else if self.ipv6_routes.lookup(destination).is_none() {
+            self.icmpv6_errors.no_route(ours, &packet, now)?
+        } else if packet.hop_limit <= 1 {
+            self.icmpv6_errors.hop_limit_exceeded(ours, &packet, now)?
+        } else if ipv6::HEADER_LENGTH + packet.data.len() > self.ipv6_mtu(destination) {
+            let mtu = u32::try_from(self.ipv6_mtu(destination))?;
+            self.icmpv6_errors.packet_too_big(ours, &packet, mtu, now)?
+        } else {
+            packet.hop_limit -= 1;
+            return self.send_ipv6(packet).await;
+        };
+        self.send_icmpv6_error(error).await
+    }
+
+    /// Send an ICMPv6 error, if there is one
+    async fn send_icmpv6_error(&mut self, error: Option<Ipv6Packet>) -> Result<Vec<EthFrame>> {
+        match error {
+            Some(error) => self.send_ipv6(error).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Largest IPv6 packet we can send toward `destination`: the link's
+    /// MTU, less a tunnel's header if it's tunnelled
+    fn ipv6_mtu(&self, destination: Ipv6Addr) -> usize {
+        match self.ipv6_tunnel(destination) {
+            Some(_) => self.mtu.saturating_sub(tunnel::OVERHEAD),
+            None => self.mtu,
+        }
+    }
+
+    /// The tunnel our routes send IPv6 packets for `destination` through,
+    /// if any; on-link destinations never go through one
+    fn ipv6_tunnel(&self, destination: Ipv6Addr) -> Option<TunnelInterface> {
+        if destination.is_multicast() || destination.is_unicast_link_local() {
+            return None;
+        }
+        let route = self.ipv6_routes.lookup(destination)?;
+        self.tunnels.get(&route.interface).copied()
+    }
+
+    /// Send an IPv6 packet through a 6in4 tunnel, fragmenting it to fit
+    /// inside the IPv4 header
+    async fn send_6in4(
+        &mut self,
+        tunnel: &TunnelInterface,
+        packet: Ipv6Packet,
+    ) -> Result<Vec<EthFrame>> {
+        let identification = self.fragment_id;
+        let mtu = self.ipv6_mtu(packet.destination);
+        let fragments = reassembly::fragment(packet, mtu, identification)?;
+        if fragments.len() > 1 {
+            self.fragment_id = identification.wrapping_add(1);
+        }
+        let mut frames = Vec::new();
+        for fragment in fragments {
+            let outer = tunnel.encapsulate_ipv6(&fragment)?;
+            frames.extend(self.send(outer).await?);
+        }
+        Ok(frames)
+    }
+
+    /// Handle the IPv6 packet inside a 6in4 one addressed to us
+    async fn receive_6in4(&mut self, outer: &Ipv4Packet) -> Result<Vec<EthFrame>> {
+        if self.firewall.evaluate(outer, Instant::now()) == Action::Drop {
+            return Ok(Vec::new());
+        }
+        let mut inner = None;
+        for tunnel in self.tunnels.values() {
+            inner = tunnel.decapsulate_ipv6(outer).await?;
+            if inner.is_some() {
+                break;
+            }
+        }
+        match inner {
+            Some(inner) => Box::pin(self.handle_ipv6_packet(inner)).await,
+            // Not from a tunnel we know
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Handle the packet inside one that came up a tunnel
+    async fn decapsulate(&mut self, outer: &Ipv4Packet) -> Result<Vec<Ipv4Packet>> {
+        let mut inner = None;
+        for tunnel in self.tunnels.values() {
+            inner = tunnel.decapsulate(outer).await?;
+            if inner.is_some() {
+                break;
+            }
+        }
+        match inner {
+            Some(inner) if self.martians.screen(&inner, &self.addresses) => {
+                Box::pin(self.handle_packet(&inner)).await
+            }
+            // Martian, or not from a tunnel we know
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Our address to answer `packet` from: the one it was sent to, or else
+    /// the one on its source's network
+    fn reply_source(&self, packet: &Ipv4Packet) -> Ipv4Addr {
+        if self.addresses.contains(packet.destination) {
+            packet.destination
+        } else {
+            self.addresses.source_for(packet.source)
+        }
+    }
+
+    /// Whether `address` is the limited broadcast address, or the directed
+    /// broadcast address of one of our networks
+    fn is_broadcast(&self, address: Ipv4Addr) -> bool {
+        address.is_broadcast() || self.addresses.is_broadcast(address)
+    }
+
+    /// Answer ARP requests for our addresses, and learn from ARP packets:
+    /// requests to us and replies confirm their sender, anything else
+    /// refreshes what we already know about it
+    fn handle_arp(&mut self, frame: &EthFrame, arp: &ArpPacket) -> Vec<EthFrame> {
+        let ours = self.addresses.contains(arp.target().0);
+        let (sender, mac) = arp.sender();
+        let now = Instant::now();
+        let mut frames: Vec<EthFrame> = self.detect_conflicts(arp, now).into_iter().collect();
+        // Someone else claiming our address isn't to be believed
+        if !self.addresses.contains(sender) && !sender.is_unspecified() && !mac.is_multicast() {
+            if ours {
+                self.arp.confirm(sender, mac, now);
+            } else {
+                self.arp.update(sender, mac, now);
+            }
+        }
+        if let Some(mac) = self.arp.lookup(sender, now) {
+            for packet in self.resolver.resolved(sender, mac) {
+                frames.push(EthFrame::new(mac, self.mac, Layer3Packet::Ipv4(packet)));
+            }
+        }
+
+        if let Some(reply) = arp.answer(self.mac).filter(|_| ours) {
+            frames.push(EthFrame::new(
+                frame.src(),
+                self.mac,
+                Layer3Packet::Arp(reply),
+            ));
+        }
+        frames
+    }
+
+    /// Check an ARP packet for another host using, or probing for, an
+    /// address we're probing for or have, returning an announcement to
+    /// defend the latter
+    fn detect_conflicts(&mut self, arp: &ArpPacket, now: Instant) -> Option<EthFrame> {
+        let (sender, mac) = arp.sender();
+        if let Some(index) = self.probes.iter().position(|probe| probe.conflicts(arp)) {
+            let address = self.probes.remove(index).address().address;
+            self.acd_events.push(AcdEvent::Conflict { address, mac });
+            return None;
+        }
+        if mac == self.mac || !self.addresses.contains(sender) {
+            return None;
+        }
+        self.acd_events.push(AcdEvent::Conflict {
+            address: sender,
+            mac,
+        });
+        // Defend, but not so often that two hosts fight forever
+        if self
+            .defended
+            .get(&sender)
+            .is_some_and(|&last| now.saturating_duration_since(last) < acd::DEFEND_INTERVAL)
+        {
+            return None;
+        }
+        self.defended.insert(sender, now);
+        self.announce_address(sender).ok()
+    }
+
+    /// Remember that the next hop towards `source` has `mac`
+    fn glean(&mut self, source: Ipv4Addr, mac: Mac6) {
+        if mac.is_multicast() {
+            return;
+        }
+        if let Some(route) = self.routes.lookup(source) {
+            self.arp
+                .observe(route.next_hop(source), mac, Instant::now());
+        }
+    }
+
+    /// Frame a packet for its next hop, encapsulating it first if it's
+    /// routed through a tunnel
+    ///
+    /// Returns `None` if there's no route, or if the packet's parked until
+    /// the next hop's MAC is resolved.
+    async fn send(&mut self, mut packet: Ipv4Packet) -> Result<Option<EthFrame>> {
+        if let Some(dst) = Mac6::from_ipv4_multicast(packet.destination) {
+            return Ok(Some(EthFrame::new(
+                dst,
+                self.mac,
+                Layer3Packet::Ipv4(packet),
+            )));
+        }
+        let Some(mut route) = self.routes.lookup(packet.destination) else {
+            return Ok(None);
+        };
+        if let Some(tunnel) = self.tunnels.get(&route.interface) {
+            packet = tunnel.encapsulate(&packet).await?;
+            // The tunnel's far end must be reachable some other way
+            match self.routes.lookup(packet.destination) {
+                Some(outer) if !self.tunnels.contains_key(&outer.interface) => route = outer,
+                _ => return Ok(None),
+            }
+        }
+        let next_hop = route.next_hop(packet.destination);
+        let now = Instant::now();
+        let Some(dst) = self.arp.lookup(next_hop, now) else {
+            self.resolver.park(next_hop, packet, now);
+            return Ok(None);
+        };
+        Ok(Some(EthFrame::new(
+            dst,
+            self.mac,
+            Layer3Packet::Ipv4(packet),
+        )))
+    }
+
+    async fn handle_icmp(&mut self, packet: &Ipv4Packet) -> Result<Vec<Ipv4Packet>> {
+        match IcmpPacket::from_reader(packet.data.as_slice()).await? {
+            // Pings to everyone on a network go unanswered (RFC 1122
+            // §3.2.2.6)
+            IcmpPacket::EchoRequest(echo)
+                if self.echo_replies && !self.is_broadcast(packet.destination) =>
+            {
+                Ok(vec![self.icmp_reply(packet, &IcmpPacket::EchoReply(echo))?])
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// An ICMP message sent back to the source of `packet`
+    fn icmp_reply(&self, packet: &Ipv4Packet, message: &IcmpPacket) -> Result<Ipv4Packet> {
+        Ipv4Packet::builder(self.reply_source(packet), packet.source, IpProtocol::Icmp)
+            .set_data(message.to_bytes())
+            .build()
+    }
+}
+
+/// An NDP message, with the hop limit receivers insist on
+fn ndp_packet(source: Ipv6Addr, destination: Ipv6Addr, message: &Icmpv6Packet) -> Ipv6Packet {
+    Ipv6Packet::new(
+        source,
+        destination,
+        IpProtocol::Icmpv6,
+        message.to_bytes(source, destination),
+    )
+    .set_hop_limit(ndp::HOP_LIMIT)
+}
+
+/// An IGMP message from `source`, to wherever that kind of message goes
+fn mld_packet(source: Ipv6Addr, message: &Icmpv6Packet) -> Ipv6Packet {
+    // Never routed, and routers should look at it even if they aren't
+    // listening to the group (RFC 3810 §5)
+    let destination = multicast6::destination(message);
+    Ipv6Packet::new(
+        source,
+        destination,
+        IpProtocol::Icmpv6,
+        message.to_bytes(source, destination),
+    )
+    .set_hop_limit(mld::HOP_LIMIT)
+    .set_router_alert()
+}
+
+/// A packet carrying a TCP segment between `local` and `remote`
+fn tcp_packet(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    segment: &TcpSegment,
+    checksums: &ChecksumCapabilities,
+) -> Result<Ipv4Packet> {
+    let (source, destination) = (*local.ip(), *remote.ip());
+    let pseudo = PseudoHeader::V4 {
+        source,
+        destination,
+    };
+    Ipv4Packet::builder(source, destination, IpProtocol::Tcp)
+        .set_data(segment.to_bytes_with_checksums(&pseudo, checksums)?)
+        .build()
+}
+
+/// An initial sequence number for a connection, unpredictable to anyone
+/// who might spoof segments for it (RFC 6528)
+fn tcp_iss(local: SocketAddrV4, remote: SocketAddrV4) -> u32 {
+    RandomState::new().hash_one((local, remote)) as u32
+}
+
+fn igmp_packet(source: Ipv4Addr, message: &IgmpPacket) -> Result<Ipv4Packet> {
+    // Never routed, and routers should look at it even if they aren't in
+    // the group (RFC 2236, RFC 3376)
+    Ipv4Packet::builder(source, multicast::destination(message), IpProtocol::Igmp)
+        .set_ttl(1)
+        .set_options(vec![Ipv4Option::RouterAlert(0)])
+        .set_data(message.to_bytes()?)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acd::AcdEvent;
+    use crate::arp_cache::ArpState;
+    use crate::dad::DadEvent;
+    use crate::filter::Rule;
+    use crate::layer3::icmp::{self, Echo, TimeExceededCode};
+    use crate::layer3::icmpv6;
+    use crate::layer3::mld::MldQuery;
+    use crate::layer3::ndp::{NdpOption, PrefixInformation};
+    use crate::layer4::{PseudoHeader, UdpDatagram, UdpLiteDatagram};
+    use crate::martian::Martian;
+    use crate::route::{Ipv4Prefix, Ipv6Prefix, Ipv6Route, Route};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const US: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const THEM: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 5);
+    const OUR_ADDRESS: InterfaceAddress = InterfaceAddress {
+        address: US,
+        prefix_length: 24,
+    };
+
+    fn stack() -> Stack {
+        let mut routes = RoutingTable::new();
+        routes.add(Route::connected("192.168.0.0/24".parse().unwrap(), 0));
+        Stack::new(Mac6::new([2, 0, 0, 0, 0, 1]), OUR_ADDRESS).set_routes(routes)
+    }
+
+    fn ping(destination: Ipv4Addr) -> Result<EthFrame> {
+        ping_from(THEM, Mac6::new([2, 0, 0, 0, 0, 5]), destination, 64)
+    }
+
+    fn ping_from(source: Ipv4Addr, mac: Mac6, destination: Ipv4Addr, ttl: u8) -> Result<EthFrame> {
+        let request = IcmpPacket::EchoRequest(Echo {
+            identifier: 7,
+            sequence: 3,
+            data: b"ping".to_vec(),
+        });
+        let packet = Ipv4Packet::builder(source, destination, IpProtocol::Icmp)
+            .set_ttl(ttl)
+            .set_data(request.to_bytes())
+            .build()?;
+        Ok(EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, 1]),
+            mac,
+            Layer3Packet::Ipv4(packet),
+        ))
+    }
+
+    #[tokio::test]
+    async fn answers_pings() -> Result<()> {
+        let mut stack = stack();
+        let replies = stack.handle(&ping(US)?).await?;
+        assert_eq!(replies.len(), 1);
+        let reply = &replies[0];
+        assert_eq!(reply.dst(), Mac6::new([2, 0, 0, 0, 0, 5]));
+        let Layer3Packet::Ipv4(packet) = reply.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((packet.source, packet.destination), (US, THEM));
+        assert_eq!(
+            IcmpPacket::from_reader(packet.data.as_slice()).await?,
+            IcmpPacket::EchoReply(Echo {
+                identifier: 7,
+                sequence: 3,
+                data: b"ping".to_vec(),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_udp() -> Result<()> {
+        let errors = IcmpErrors::new().set_rate_limit(Duration::from_secs(60), 2);
+        let mut stack = stack().set_icmp_errors(errors);
+        let datagram = [0x04, 0xd2, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00];
+        let packet = Ipv4Packet::builder(THEM, US, IpProtocol::Udp)
+            .set_data(datagram)
+            .build()?;
+        let frame = EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, 1]),
+            Mac6::new([2, 0, 0, 0, 0, 5]),
+            Layer3Packet::Ipv4(packet),
+        );
+        let replies = stack.handle(&frame).await?;
+        let Layer3Packet::Ipv4(reply) = replies[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((reply.source, reply.destination), (US, THEM));
+        let IcmpPacket::DestinationUnreachable { code, original, .. } =
+            IcmpPacket::from_reader(reply.data.as_slice()).await?
+        else {
+            panic!("Wrong ICMP type!");
+        };
+        // Quoting the header, so the sender can tell which socket it's for
+        assert_eq!(code, icmp::UnreachableCode::Port);
+        assert_eq!(original.protocol(), IpProtocol::Udp);
+        assert_eq!(original.payload(), datagram);
+
+        // Only so many, however many datagrams come
+        assert_eq!(stack.handle(&frame).await?.len(), 1);
+        assert!(stack.handle(&frame).await?.is_empty());
+        assert_eq!(stack.icmp_errors().limited(), 1);
+
+        // A datagram longer than its packet isn't worth an error
+        let packet = Ipv4Packet::builder(THEM, US, IpProtocol::Udp)
+            .set_data([0x04, 0xd2, 0x00, 0x35, 0x00, 0x10, 0x00, 0x00])
+            .build()?;
+        let frame = EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, 1]),
+            Mac6::new([2, 0, 0, 0, 0, 5]),
+            Layer3Packet::Ipv4(packet),
+        );
+        assert!(stack.handle(&frame).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forwards() -> Result<()> {
+        let gateway = Ipv4Addr::new(192, 168, 0, 254);
+        let gateway_mac = Mac6::new([2, 0, 0, 0, 0, 0xfe]);
+        let remote = Ipv4Addr::new(8, 8, 8, 8);
+        let mut stack = stack().set_forwarding(true);
+        stack
+            .routes_mut()
+            .add(Route::via(Ipv4Prefix::DEFAULT, gateway, 0));
+        // Learn the gateway's MAC
+        stack
+            .handle(&ping_from(gateway, gateway_mac, US, 64)?)
+            .await?;
+
+        let out = stack
+            .handle(&ping_from(THEM, Mac6::new([2, 0, 0, 0, 0, 5]), remote, 64)?)
+            .await?;
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].dst(), gateway_mac);
+        let Layer3Packet::Ipv4(packet) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(
+            (packet.source, packet.destination, packet.ttl),
+            (THEM, remote, 63)
+        );
+
+        // Out of hops: tell the sender
+        let out = stack
+            .handle(&ping_from(THEM, Mac6::new([2, 0, 0, 0, 0, 5]), remote, 1)?)
+            .await?;
+        assert_eq!(out[0].dst(), Mac6::new([2, 0, 0, 0, 0, 5]));
+        let Layer3Packet::Ipv4(packet) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert!(matches!(
+            IcmpPacket::from_reader(packet.data.as_slice()).await?,
+            IcmpPacket::TimeExceeded { .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn masquerades() -> Result<()> {
+        let gateway = Ipv4Addr::new(192, 168, 0, 254);
+        let gateway_mac = Mac6::new([2, 0, 0, 0, 0, 0xfe]);
+        let host_mac = Mac6::new([2, 0, 0, 0, 0, 5]);
+        let remote = Ipv4Addr::new(8, 8, 8, 8);
+        let mut stack = stack().set_forwarding(true).set_nat(Nat::new(US));
+        stack
+            .routes_mut()
+            .add(Route::via(Ipv4Prefix::DEFAULT, gateway, 0));
+        let now = Instant::now();
+        stack.arp_cache_mut().confirm(gateway, gateway_mac, now);
+        stack.arp_cache_mut().confirm(THEM, host_mac, now);
+
+        // Pings from behind us go out as ours
+        let out = stack
+            .handle(&ping_from(THEM, host_mac, remote, 64)?)
+            .await?;
+        assert_eq!(out[0].dst(), gateway_mac);
+        let Layer3Packet::Ipv4(packet) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((packet.source, packet.destination), (US, remote));
+        let IcmpPacket::EchoRequest(echo) = IcmpPacket::from_reader(packet.data.as_slice()).await?
+        else {
+            panic!("Wrong ICMP type!");
+        };
+        assert_eq!(stack.nat().unwrap().len(), 1);
+
+        // And the reply finds its way back
+        let reply = Ipv4Packet::builder(remote, US, IpProtocol::Icmp)
+            .set_data(IcmpPacket::EchoReply(echo).to_bytes())
+            .build()?;
+        let frame = EthFrame::new(stack.mac(), gateway_mac, Layer3Packet::Ipv4(reply));
+        let out = stack.handle(&frame).await?;
+        assert_eq!(out[0].dst(), host_mac);
+        let Layer3Packet::Ipv4(packet) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((packet.source, packet.destination), (remote, THEM));
+        let IcmpPacket::EchoReply(echo) = IcmpPacket::from_reader(packet.data.as_slice()).await?
+        else {
+            panic!("Wrong ICMP type!");
+        };
+        assert_eq!(echo.identifier, 7);
+
+        // Pings to us are still ours to answer
+        let out = stack
+            .handle(&ping_from(remote, gateway_mac, US, 64)?)
+            .await?;
+        let Layer3Packet::Ipv4(packet) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((packet.source, packet.destination), (US, remote));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raw_sockets() -> Result<()> {
+        let mut stack = stack();
+        let mut icmp = stack.raw_socket(IpProtocol::Icmp);
+        let udp = stack.raw_socket(IpProtocol::Udp);
+
+        // Gets a copy; the stack still answers
+        let request = ping(US)?;
+        assert_eq!(stack.handle(&request).await?.len(), 1);
+        let Layer3Packet::Ipv4(packet) = request.payload() else {
+            unreachable!();
+        };
+        assert_eq!(icmp.recv().await.as_ref(), Some(packet));
+
+        udp.send_to(THEM, [1, 2, 3, 4, 0, 8, 0, 0]).await?;
+        assert!(icmp.send_to(THEM, []).await.is_ok());
+        assert!(udp.send(packet.clone()).await.is_err());
+        let frame = stack.next_outgoing().await;
+        assert_eq!(frame.dst(), Mac6::new([2, 0, 0, 0, 0, 5]));
+        let Layer3Packet::Ipv4(sent) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((sent.source, sent.protocol), (US, IpProtocol::Udp));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_sockets() -> Result<()> {
+        let mut stack = stack();
+        let mut any = stack.bind_udp(Ipv4Addr::UNSPECIFIED, 53)?;
+        assert!(stack.bind_udp(US, 53).is_err());
+        assert!(stack.bind_udp(THEM, 54).is_err());
+        let ephemeral = stack.bind_udp(US, 0)?;
+        assert!(crate::socket::EPHEMERAL_PORTS.contains(&ephemeral.local_addr().port()));
+
+        let mac = stack.mac();
+        let query = |port| -> Result<EthFrame> {
+            let pseudo = PseudoHeader::V4 {
+                source: THEM,
+                destination: US,
+            };
+            let packet = Ipv4Packet::builder(THEM, US, IpProtocol::Udp)
+                .set_data(UdpDatagram::new(5353, port, b"query".to_vec()).to_bytes(&pseudo)?)
+                .build()?;
+            Ok(EthFrame::new(
+                mac,
+                Mac6::new([2, 0, 0, 0, 0, 5]),
+                Layer3Packet::Ipv4(packet),
+            ))
+        };
+        let frame = query(53)?;
+        assert!(stack.handle(&frame).await?.is_empty());
+        let mut buf = [0; 3];
+        let (length, from) = any.recv_from(&mut buf).await?;
+        assert_eq!((length, &buf), (3, b"que"));
+        assert_eq!(from, SocketAddrV4::new(THEM, 5353));
+
+        // Replies are checksummed for the source the stack picks
+        assert_eq!(any.send_to(b"answer", from).await?, 6);
+        let frame = stack.next_outgoing().await;
+        let Layer3Packet::Ipv4(sent) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(sent.source, US);
+        assert_eq!(
+            sent.payload().await?,
+            Layer4Packet::Udp(UdpDatagram::new(53, 5353, b"answer".to_vec()))
+        );
+
+        // Once closed, the port is unreachable and free again
+        drop(any);
+        let frame = query(53)?;
+        assert_eq!(stack.handle(&frame).await?.len(), 1);
+        stack.bind_udp(US, 53)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_broadcasts() -> Result<()> {
+        let mut stack = stack();
+        let mut any = stack.bind_udp(Ipv4Addr::UNSPECIFIED, 68)?;
+        let broadcast = |destination, port| -> Result<EthFrame> {
+            let pseudo = PseudoHeader::V4 {
+                source: THEM,
+                destination,
+            };
+            let packet = Ipv4Packet::builder(THEM, destination, IpProtocol::Udp)
+                .set_data(UdpDatagram::new(67, port, b"offer".to_vec()).to_bytes(&pseudo)?)
+                .build()?;
+            Ok(EthFrame::new(
+                Mac6::BROADCAST,
+                THEIR_MAC,
+                Layer3Packet::Ipv4(packet),
+            ))
+        };
+        let mut buf = [0; 5];
+        for destination in [Ipv4Addr::BROADCAST, Ipv4Addr::new(192, 168, 0, 255)] {
+            assert!(stack.handle(&broadcast(destination, 68)?).await?.is_empty());
+            let (length, from) = any.recv_from(&mut buf).await?;
+            assert_eq!((length, &buf), (5, b"offer"));
+            assert_eq!(from, SocketAddrV4::new(THEM, 67));
+            // Nobody listening isn't worth an error
+            assert!(stack.handle(&broadcast(destination, 69)?).await?.is_empty());
+        }
+
+        // Nor are broadcast pings answered
+        let request = IcmpPacket::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        let packet = Ipv4Packet::builder(THEM, Ipv4Addr::BROADCAST, IpProtocol::Icmp)
+            .set_data(request.to_bytes())
+            .build()?;
+        let frame = EthFrame::new(Mac6::BROADCAST, THEIR_MAC, Layer3Packet::Ipv4(packet));
+        assert!(stack.handle(&frame).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_lite_sockets() -> Result<()> {
+        let mut stack = stack();
+        stack.handle(&ping(US)?).await?;
+        let mut socket = stack.bind_udp_lite(US, 5004)?;
+        // Apart from UDP's ports
+        let mut udp = stack.bind_udp(US, 5004)?;
+        assert!(udp.set_checksum_coverage(8).is_err());
+
+        // Damage past the coverage doesn't lose the datagram
+        let pseudo = PseudoHeader::V4 {
+            source: THEM,
+            destination: US,
+        };
+        let datagram = UdpLiteDatagram::new(5004, 5004, *b"frame").set_coverage(8);
+        let mut data = datagram.to_bytes(&pseudo)?;
+        data[10] ^= 0xff;
+        let packet = Ipv4Packet::builder(THEM, US, IpProtocol::UdpLite)
+            .set_data(data)
+            .build()?;
+        let frame = EthFrame::new(
+            stack.mac(),
+            Mac6::new([2, 0, 0, 0, 0, 5]),
+            Layer3Packet::Ipv4(packet),
+        );
+        assert!(stack.handle(&frame).await?.is_empty());
+        let mut buf = [0; 8];
+        let (length, from) = socket.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"fr\x9eme");
+
+        socket.set_checksum_coverage(10)?;
+        socket.send_to(b"reply", from).await?;
+        let frame = stack.next_outgoing().await;
+        let Layer3Packet::Ipv4(sent) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(sent.protocol, IpProtocol::UdpLite);
+        let expected = UdpLiteDatagram::new(5004, 5004, *b"reply").set_coverage(10);
+        assert_eq!(sent.payload().await?, Layer4Packet::UdpLite(expected));
+        Ok(())
+    }
+
+    /// A frame carrying `segment` from them to us
+    fn tcp_frame(segment: &TcpSegment) -> Result<EthFrame> {
+        let packet = tcp_packet(
+            SocketAddrV4::new(THEM, segment.source_port),
+            SocketAddrV4::new(US, segment.destination_port),
+            segment,
+            &ChecksumCapabilities::default(),
+        )?;
+        Ok(EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, 1]),
+            Mac6::new([2, 0, 0, 0, 0, 5]),
+            Layer3Packet::Ipv4(packet),
+        ))
+    }
+
+    async fn tcp_sent(frame: &EthFrame) -> Result<TcpSegment> {
+        let Layer3Packet::Ipv4(packet) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        let Layer4Packet::Tcp(segment) = packet.payload().await? else {
+            panic!("Not TCP!");
+        };
+        Ok(segment)
+    }
+
+    /// Pass `segments`, and whatever follows, between `peer` and the stack
+    /// until both are quiet
+    async fn tcp_exchange(
+        stack: &mut Stack,
+        peer: &mut TcpConnection,
+        mut segments: Vec<TcpSegment>,
+    ) -> Result<()> {
+        let now = Instant::now();
+        segments.extend(peer.poll(now));
+        while !segments.is_empty() {
+            let mut replies = Vec::new();
+            for segment in &segments {
+                for frame in stack.handle(&tcp_frame(segment)?).await? {
+                    replies.extend(peer.handle(&tcp_sent(&frame).await?, now));
+                }
+            }
+            segments = replies;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_listener() -> Result<()> {
+        let mut stack = stack();
+        let mut listener = stack.bind_tcp(Ipv4Addr::UNSPECIFIED, 80)?;
+        assert!(stack.bind_tcp(US, 80).is_err());
+        assert!(stack.bind_tcp(THEM, 81).is_err());
+
+        let mut peer = TcpConnection::connect(5555, 80, 0, 1460);
+        tcp_exchange(&mut stack, &mut peer, Vec::new()).await?;
+        assert_eq!(peer.state(), TcpState::Established);
+        let (mut stream, from) = listener.accept().await?;
+        assert_eq!(from, SocketAddrV4::new(THEM, 5555));
+        assert_eq!(stream.local_addr(), SocketAddrV4::new(US, 80));
+
+        stream.write_all(b"hello").await?;
+        let sent = tcp_sent(&stack.next_outgoing().await).await?;
+        let acks = peer.handle(&sent, Instant::now());
+        let mut buf = [0; 8];
+        assert_eq!(peer.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        peer.send(b"bye");
+        peer.close();
+        tcp_exchange(&mut stack, &mut peer, acks).await?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await?;
+        assert_eq!(received, b"bye");
+
+        // Dropping the stream closes our end too, and it's forgotten
+        drop(stream);
+        let fin = tcp_sent(&stack.next_outgoing().await).await?;
+        assert!(fin.flags.fin);
+        let ack = peer.handle(&fin, Instant::now());
+        tcp_exchange(&mut stack, &mut peer, ack).await?;
+        assert_eq!(peer.state(), TcpState::TimeWait);
+        let connections = stack.sockets.iter().filter(|(tuple, _)| !tuple.is_bound());
+        assert_eq!(connections.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_time_wait_reuse() -> Result<()> {
+        let config = TcpConfig::new().set_timestamps(true);
+        let mut stack = stack().set_tcp_config(config.set_time_wait_reuse(true));
+        let mut listener = stack.bind_tcp(Ipv4Addr::UNSPECIFIED, 80)?;
+        let epoch = Instant::now();
+        let mut peer = TcpConnection::connect(5555, 80, 0, 1460).set_config(config);
+        peer.set_timestamp_epoch(epoch);
+        tcp_exchange(&mut stack, &mut peer, Vec::new()).await?;
+        let (stream, _) = listener.accept().await?;
+
+        // We close first, so it's us left in TIME-WAIT
+        drop(stream);
+        let fin = tcp_sent(&stack.next_outgoing().await).await?;
+        let ack = peer.handle(&fin, Instant::now());
+        peer.close();
+        tcp_exchange(&mut stack, &mut peer, ack).await?;
+        assert_eq!(peer.state(), TcpState::Closed);
+        let Some(Socket::Tcp(entry)) = stack.socket_for(&FiveTuple::new(
+            IpProtocol::Tcp,
+            SocketAddrV4::new(US, 80),
+            SocketAddrV4::new(THEM, 5555),
+        )) else {
+            panic!("Connection forgotten");
+        };
+        let state = entry.shared.lock().unwrap().connection.state();
+        assert_eq!(state, TcpState::TimeWait);
+
+        // The same ports connect again, with later timestamps
+        let mut again = TcpConnection::connect(5555, 80, 1 << 31, 1460).set_config(config);
+        again.set_timestamp_epoch(epoch - Duration::from_secs(1));
+        tcp_exchange(&mut stack, &mut again, Vec::new()).await?;
+        assert_eq!(again.state(), TcpState::Established);
+        let (_, from) = listener.accept().await?;
+        assert_eq!(from, SocketAddrV4::new(THEM, 5555));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_connect() -> Result<()> {
+        let mut stack = stack();
+        // Learn their MAC
+        stack.handle(&ping(US)?).await?;
+        let remote = SocketAddrV4::new(THEM, 80);
+
+        let mut refused = stack.connect_tcp(remote)?;
+        let syn = tcp_sent(&stack.next_outgoing().await).await?;
+        assert!(syn.flags.syn);
+        assert_eq!(syn.source_port, refused.local_addr().port());
+        let mut reset = TcpSegment::new(80, syn.source_port, 0);
+        reset.flags.rst = true;
+        reset.acknowledgement = Some(syn.sequence.wrapping_add(1));
+        stack.handle(&tcp_frame(&reset)?).await?;
+        let error = refused.read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // Written before the handshake's done, sent after
+        let mut stream = stack.connect_tcp(remote)?;
+        stream.write_all(b"early").await?;
+        let syn = tcp_sent(&stack.next_outgoing().await).await?;
+        let mut peer = TcpConnection::accept(&syn, 0, 1460);
+        tcp_exchange(&mut stack, &mut peer, Vec::new()).await?;
+        assert_eq!(peer.state(), TcpState::Established);
+        let mut buf = [0; 8];
+        assert_eq!(peer.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"early");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_fast_open() -> Result<()> {
+        let config = TcpConfig::new().set_fast_open(true);
+        let mut stack = stack().set_tcp_config(config);
+        stack.handle(&ping(US)?).await?;
+        let mut listener = stack.bind_tcp(US, 80)?;
+        let fast_open = |segment: &TcpSegment| {
+            segment.options.iter().find_map(|option| match option {
+                tcp::TcpOption::FastOpen(cookie) => Some(cookie.clone()),
+                _ => None,
+            })
+        };
+
+        // Accepting: the first SYN gets a cookie, and the next, presenting
+        // it, has its data handed over with the connection
+        let mut peer = TcpConnection::connect(5555, 80, 0, 1460).set_config(config);
+        let syn = peer.poll(Instant::now());
+        let replies = stack.handle(&tcp_frame(&syn[0])?).await?;
+        let cookie = fast_open(&tcp_sent(&replies[0]).await?).unwrap();
+        assert_eq!(cookie.len(), 8);
+
+        let mut peer = TcpConnection::connect(5556, 80, 0, 1460).set_config(config);
+        peer.set_fast_open_cookie(Some(cookie));
+        peer.send(b"GET /");
+        let syn = peer.poll(Instant::now());
+        assert_eq!(syn[0].data, b"GET /");
+        stack.handle(&tcp_frame(&syn[0])?).await?;
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).await?, 5);
+        assert_eq!(&buf[..5], b"GET /");
+
+        // Connecting: we ask for a cookie, and present it next time
+        let remote = SocketAddrV4::new(THEM, 80);
+        let _stream = stack.connect_tcp(remote)?;
+        let syn = tcp_sent(&stack.next_outgoing().await).await?;
+        assert_eq!(fast_open(&syn), Some(Vec::new()));
+        let mut peer = TcpConnection::accept(&syn, 0, 1460).set_config(config);
+        peer.fast_open(&syn, vec![7; 8]);
+        tcp_exchange(&mut stack, &mut peer, Vec::new()).await?;
+        assert_eq!(stack.fast_open_cookies[&THEM], [7; 8]);
+
+        let mut stream = stack.connect_tcp(remote)?;
+        stream.write_all(b"early").await?;
+        let syn = tcp_sent(&stack.next_outgoing().await).await?;
+        assert_eq!(fast_open(&syn), Some(vec![7; 8]));
+        assert_eq!(syn.data, b"early");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_refused() -> Result<()> {
+        let mut stack = stack();
+        stack.handle(&ping(US)?).await?;
+        let _listener = stack.bind_tcp(US, 80)?;
+
+        // Nothing on the port: a reset acknowledging the SYN
+        let mut peer = TcpConnection::connect(5555, 81, 0, 1460);
+        let syn = peer.poll(Instant::now());
+        let replies = stack.handle(&tcp_frame(&syn[0])?).await?;
+        let reset = tcp_sent(&replies[0]).await?;
+        assert!(reset.flags.rst);
+        assert_eq!(reset.acknowledgement, Some(1));
+        peer.handle(&reset, Instant::now());
+        assert!(peer.is_reset());
+
+        // Left over from an old connection: a reset where it expects one
+        let mut stray = TcpSegment::new(5555, 80, 100);
+        stray.acknowledgement = Some(200);
+        let replies = stack.handle(&tcp_frame(&stray)?).await?;
+        let reset = tcp_sent(&replies[0]).await?;
+        assert!(reset.flags.rst);
+        assert_eq!((reset.sequence, reset.acknowledgement), (200, None));
+
+        // Resets aren't answered
+        stray.flags.rst = true;
+        assert!(stack.handle(&tcp_frame(&stray)?).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multicast_membership() -> Result<()> {
+        let group = Ipv4Addr::new(224, 0, 0, 251);
+        let mut stack = stack();
+        let mut udp = stack.raw_socket(IpProtocol::Udp);
+        stack.join(group)?;
+
+        let frame = stack.next_outgoing().await;
+        let routers = crate::layer3::igmp::ALL_IGMPV3_ROUTERS;
+        assert_eq!(frame.dst(), Mac6::from_ipv4_multicast(routers).unwrap());
+        let Layer3Packet::Ipv4(report) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!((report.protocol, report.ttl), (IpProtocol::Igmp, 1));
+        assert_eq!(report.options, [Ipv4Option::RouterAlert(0)]);
+        assert!(matches!(
+            IgmpPacket::from_reader(report.data.as_slice()).await?,
+            IgmpPacket::V3Report(_)
+        ));
+
+        // Traffic to the group is now ours
+        let packet = Ipv4Packet::builder(THEM, group, IpProtocol::Udp)
+            .set_data([0x14, 0xe9, 0x14, 0xe9, 0, 8, 0, 0])
+            .build()?;
+        let frame = EthFrame::new(
+            Mac6::from_ipv4_multicast(group).unwrap(),
+            Mac6::new([2, 0, 0, 0, 0, 5]),
+            Layer3Packet::Ipv4(packet.clone()),
+        );
+        // ...but isn't answered with errors
+        assert!(stack.handle(&frame).await?.is_empty());
+        assert_eq!(udp.recv().await, Some(packet));
+
+        let deadline = stack.next_deadline().unwrap();
+        assert_eq!(stack.poll(deadline).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tunnels() -> Result<()> {
+        let remote = Ipv4Addr::new(192, 168, 0, 9);
+        let remote_mac = Mac6::new([2, 0, 0, 0, 0, 9]);
+        let overlay = Ipv4Addr::new(10, 9, 0, 1);
+        let mut stack = stack()
+            .set_forwarding(true)
+            .add_tunnel(1, TunnelInterface::new(US, remote));
+        stack
+            .routes_mut()
+            .add(Route::connected("10.9.0.0/16".parse()?, 1));
+        stack
+            .handle(&ping_from(remote, remote_mac, US, 64)?)
+            .await?;
+
+        // Out through the tunnel
+        let out = stack.handle(&ping(overlay)?).await?;
+        assert_eq!(out[0].dst(), remote_mac);
+        let Layer3Packet::Ipv4(outer) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(
+            (outer.destination, outer.protocol),
+            (remote, IpProtocol::IpInIp)
+        );
+        let inner = crate::tunnel::decapsulate(outer).await?;
+        assert_eq!((inner.destination, inner.ttl), (overlay, 63));
+
+        // In from the tunnel: a ping from the far side is answered back
+        // through it
+        let request = ping_from(overlay, remote_mac, US, 64)?;
+        let Layer3Packet::Ipv4(inner) = request.payload() else {
+            unreachable!();
+        };
+        let outer = TunnelInterface::new(remote, US).encapsulate(inner).await?;
+        let frame = EthFrame::new(stack.mac(), remote_mac, Layer3Packet::Ipv4(outer));
+        let out = stack.handle(&frame).await?;
+        let Layer3Packet::Ipv4(outer) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        let reply = crate::tunnel::decapsulate(outer).await?;
+        assert_eq!((reply.source, reply.destination), (US, overlay));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv6_tunnels() -> Result<()> {
+        let remote = Ipv4Addr::new(192, 168, 0, 9);
+        let remote_mac = Mac6::new([2, 0, 0, 0, 0, 9]);
+        let us: Ipv6Addr = "2001:db8::2".parse()?;
+        let broker: Ipv6Addr = "2001:db8::1".parse()?;
+        let far: Ipv6Addr = "2001:db8:ff::9".parse()?;
+        let mut routes = Ipv6RoutingTable::new();
+        routes.add(Ipv6Route::via(Ipv6Prefix::DEFAULT, broker, 1));
+        let mut stack = stack()
+            .add_ipv6_address(us)
+            .set_ipv6_routes(routes)
+            .add_tunnel(1, TunnelInterface::new(US, remote));
+        stack
+            .handle(&ping_from(remote, remote_mac, US, 64)?)
+            .await?;
+
+        // A ping from beyond the broker is answered back through the tunnel
+        let echo = Echo {
+            identifier: 1,
+            sequence: 1,
+            data: b"ping".to_vec(),
+        };
+        let request = Icmpv6Packet::EchoRequest(echo.clone());
+        let inner = Ipv6Packet::new(far, us, IpProtocol::Icmpv6, request.to_bytes(far, us));
+        let outer = TunnelInterface::new(remote, US).encapsulate_ipv6(&inner)?;
+        let frame = EthFrame::new(stack.mac(), remote_mac, Layer3Packet::Ipv4(outer));
+        let out = stack.handle(&frame).await?;
+        assert_eq!(out[0].dst(), remote_mac);
+        let Layer3Packet::Ipv4(outer) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(
+            (outer.destination, outer.protocol),
+            (remote, IpProtocol::Ipv6)
+        );
+        let reply = crate::tunnel::decapsulate_ipv6(outer).await?;
+        assert_eq!((reply.source, reply.destination), (us, far));
+        let message = Icmpv6Packet::from_reader(reply.data.as_slice(), us, far).await?;
+        assert_eq!(message, Icmpv6Packet::EchoReply(echo));
+
+        // Only from the tunnel's far end
+        let outer = TunnelInterface::new(THEM, US).encapsulate_ipv6(&inner)?;
+        let frame = EthFrame::new(stack.mac(), THEIR_MAC, Layer3Packet::Ipv4(outer));
+        assert!(stack.handle(&frame).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secondary_addresses() -> Result<()> {
+        let secondary = Ipv4Addr::new(10, 0, 0, 1);
+        let mut stack = stack();
+        stack.addresses_mut().add("10.0.0.1/24".parse()?);
+        stack
+            .routes_mut()
+            .add(Route::connected("10.0.0.0/24".parse()?, 0));
+        stack
+            .handle(&ping_from(
+                Ipv4Addr::new(10, 0, 0, 2),
+                Mac6::new([2, 0, 0, 0, 0, 2]),
+                US,
+                64,
+            )?)
+            .await?;
+
+        // Answered from the address pinged
+        let out = stack.handle(&ping(secondary)?).await?;
+        let Layer3Packet::Ipv4(reply) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(reply.source, secondary);
+
+        // Bound sockets only see their own address
+        assert!(stack.bind_raw_socket(IpProtocol::Icmp, THEM).is_err());
+        let mut bound = stack.bind_raw_socket(IpProtocol::Icmp, US)?;
+        stack.handle(&ping(secondary)?).await?;
+        stack.handle(&ping(US)?).await?;
+        assert_eq!(bound.recv().await.unwrap().destination, US);
+
+        // Unbound sockets send from the address on the destination's network
+        let raw = stack.raw_socket(IpProtocol::Udp);
+        raw.send_to(Ipv4Addr::new(10, 0, 0, 2), [1, 2, 3, 4, 0, 8, 0, 0])
+            .await?;
+        let frame = stack.next_outgoing().await;
+        let Layer3Packet::Ipv4(sent) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(sent.source, secondary);
+        Ok(())
+    }
+
+    /// An ARP request from `sender` at `mac` for `target`
+    const US6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const THEM6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 5);
+    const THEIR_MAC: Mac6 = Mac6::new([2, 0, 0, 0, 0, 5]);
+
+    fn icmpv6_frame(
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        message: &Icmpv6Packet,
+    ) -> Result<EthFrame> {
+        let packet = ndp_packet(source, destination, message);
+        Ok(EthFrame::new(
+            Mac6::new([2, 0, 0, 0, 0, 1]),
+            THEIR_MAC,
+            Layer3Packet::Ipv6(packet.to_bytes()?),
+        ))
+    }
+
+    async fn icmpv6_sent(frame: &EthFrame) -> Result<(Ipv6Packet, Icmpv6Packet)> {
+        let Layer3Packet::Ipv6(raw) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        let packet = Ipv6Packet::from_reader(raw.as_slice()).await?;
+        let message =
+            Icmpv6Packet::from_reader(packet.data.as_slice(), packet.source, packet.destination)
+                .await?;
+        Ok((packet, message))
+    }
+
+    #[tokio::test]
+    async fn ipv6() -> Result<()> {
+        let mut stack = stack().add_ipv6_address(US6);
+        // They resolve us, so we learn them too
+        let solicitation = NeighbourSolicitation::new(US6, THEIR_MAC);
+        let out = stack
+            .handle(&icmpv6_frame(
+                THEM6,
+                ndp::solicited_node(US6),
+                &Icmpv6Packet::NeighbourSolicitation(solicitation),
+            )?)
+            .await?;
+        assert_eq!(out[0].dst(), THEIR_MAC);
+        let (packet, message) = icmpv6_sent(&out[0]).await?;
+        assert_eq!(packet.hop_limit, ndp::HOP_LIMIT);
+        assert_eq!(
+            message,
+            Icmpv6Packet::NeighbourAdvertisement(NeighbourAdvertisement::answer(US6, stack.mac()))
+        );
+
+        let echo = Echo {
+            identifier: 7,
+            sequence: 3,
+            data: b"ping".to_vec(),
+        };
+        let request = Icmpv6Packet::EchoRequest(echo.clone());
+        let out = stack.handle(&icmpv6_frame(THEM6, US6, &request)?).await?;
+        assert_eq!(out[0].dst(), THEIR_MAC);
+        let (packet, message) = icmpv6_sent(&out[0]).await?;
+        assert_eq!((packet.source, packet.destination), (US6, THEM6));
+        assert_eq!(message, Icmpv6Packet::EchoReply(echo));
+
+        let pseudo = PseudoHeader::V6 {
+            source: THEM6,
+            destination: US6,
+        };
+        let datagram = UdpDatagram::new(1234, 53, Vec::new()).to_bytes(&pseudo)?;
+        let udp = Ipv6Packet::new(THEM6, US6, IpProtocol::Udp, datagram);
+        let frame = EthFrame::new(stack.mac(), THEIR_MAC, Layer3Packet::Ipv6(udp.to_bytes()?));
+        let out = stack.handle(&frame).await?;
+        let (_, message) = icmpv6_sent(&out[0]).await?;
+        assert_eq!(
+            message,
+            Icmpv6Packet::DestinationUnreachable {
+                code: icmpv6::UnreachableCode::Port,
+                original: udp.to_bytes()?,
+            }
+        );
+
+        // Not for us
+        let other = Ipv6Packet::new(THEM6, "fe80::9".parse()?, IpProtocol::Udp, [0; 8]);
+        let frame = EthFrame::new(
+            stack.mac(),
+            THEIR_MAC,
+            Layer3Packet::Ipv6(other.to_bytes()?),
+        );
+        assert!(stack.handle(&frame).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv6_routes() -> Result<()> {
+        let us: Ipv6Addr = "2001:db8::1".parse()?;
+        let remote: Ipv6Addr = "2001:db8:ff::9".parse()?;
+        let gateway_mac = Mac6::new([2, 0, 0, 0, 0, 0xfe]);
+        let mut routes = Ipv6RoutingTable::new();
+        routes.add(Ipv6Route::via(Ipv6Prefix::DEFAULT, THEM6, 0));
+        let mut stack = stack()
+            .add_ipv6_address(US6)
+            .add_ipv6_address(us)
+            .set_ipv6_routes(routes);
+        stack
+            .neighbour_cache_mut()
+            .observe(THEM6, gateway_mac, Instant::now());
+
+        // Off-link replies go through the gateway
+        let echo = Echo {
+            identifier: 1,
+            sequence: 1,
+            data: b"ping".to_vec(),
+        };
+        let request = Icmpv6Packet::EchoRequest(echo.clone());
+        let packet = Ipv6Packet::new(remote, us, IpProtocol::Icmpv6, request.to_bytes(remote, us));
+        let frame = EthFrame::new(
+            stack.mac(),
+            gateway_mac,
+            Layer3Packet::Ipv6(packet.to_bytes()?),
+        );
+        let out = stack.handle(&frame).await?;
+        assert_eq!(out[0].dst(), gateway_mac);
+        let (packet, message) = icmpv6_sent(&out[0]).await?;
+        assert_eq!((packet.source, packet.destination), (us, remote));
+        assert_eq!(message, Icmpv6Packet::EchoReply(echo));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forwards_ipv6() -> Result<()> {
+        let us: Ipv6Addr = "2001:db8:1::1".parse()?;
+        let host: Ipv6Addr = "2001:db8:1::9".parse()?;
+        let gateway: Ipv6Addr = "fe80::fe".parse()?;
+        let gateway_mac = Mac6::new([2, 0, 0, 0, 0, 0xfe]);
+        let mut routes = Ipv6RoutingTable::new();
+        routes.add(Ipv6Route::connected("2001:db8:1::/64".parse()?, 0));
+        routes.add(Ipv6Route::via("2001:db8:2::/64".parse()?, gateway, 0));
+        let errors = Icmpv6Errors::new().set_rate_limit(Duration::from_secs(60), 3);
+        let mut stack = stack()
+            .set_forwarding(true)
+            .add_ipv6_address(US6)
+            .add_ipv6_address(us)
+            .set_ipv6_routes(routes)
+            .set_icmpv6_errors(errors);
+        let now = Instant::now();
+        stack
+            .neighbour_cache_mut()
+            .observe(gateway, gateway_mac, now);
+        stack.neighbour_cache_mut().observe(host, THEIR_MAC, now);
+        let mac = stack.mac();
+        let frame = |destination: Ipv6Addr, hop_limit, length| {
+            let mut packet = Ipv6Packet::new(host, destination, IpProtocol::Udp, vec![0; length]);
+            packet.hop_limit = hop_limit;
+            Ok::<_, anyhow::Error>(EthFrame::new(
+                mac,
+                THEIR_MAC,
+                Layer3Packet::Ipv6(packet.to_bytes()?),
+            ))
+        };
+
+        // Routed on, a hop closer
+        let remote: Ipv6Addr = "2001:db8:2::9".parse()?;
+        let out = stack.handle(&frame(remote, 64, 8)?).await?;
+        assert_eq!(out[0].dst(), gateway_mac);
+        let Layer3Packet::Ipv6(raw) = out[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        let forwarded = Ipv6Packet::from_reader(raw.as_slice()).await?;
+        assert_eq!((forwarded.destination, forwarded.hop_limit), (remote, 63));
+
+        // Or an error, from our global address
+        let error = |out: Vec<EthFrame>| async move {
+            assert_eq!(out[0].dst(), THEIR_MAC);
+            let (packet, message) = icmpv6_sent(&out[0]).await?;
+            assert_eq!((packet.source, packet.destination), (us, host));
+            Ok::<_, anyhow::Error>(message)
+        };
+        let out = stack.handle(&frame(remote, 1, 8)?).await?;
+        assert!(matches!(
+            error(out).await?,
+            Icmpv6Packet::TimeExceeded {
+                code: TimeExceededCode::Ttl,
+                ..
+            }
+        ));
+        let out = stack
+            .handle(&frame("2001:db8:3::9".parse()?, 64, 8)?)
+            .await?;
+        assert!(matches!(
+            error(out).await?,
+            Icmpv6Packet::DestinationUnreachable {
+                code: icmpv6::UnreachableCode::NoRoute,
+                ..
+            }
+        ));
+        let out = stack.handle(&frame(remote, 64, 1500)?).await?;
+        assert!(matches!(
+            error(out).await?,
+            Icmpv6Packet::PacketTooBig { mtu: 1500, .. }
+        ));
+
+        // Until the rate limit's reached
+        assert!(stack.handle(&frame(remote, 1, 8)?).await?.is_empty());
+        assert_eq!(stack.icmpv6_errors().limited(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv6_fragments() -> Result<()> {
+        let mut stack = stack().add_ipv6_address(US6);
+        stack
+            .neighbour_cache_mut()
+            .observe(THEM6, THEIR_MAC, Instant::now());
+        let echo = Echo {
+            identifier: 7,
+            sequence: 3,
+            data: vec![0x55; 3000],
+        };
+        let request = Icmpv6Packet::EchoRequest(echo.clone());
+        let packet = Ipv6Packet::new(THEM6, US6, IpProtocol::Icmpv6, request.to_bytes(THEM6, US6));
+        let fragments = reassembly::fragment(packet, 1280, 1)?;
+        let frame = |packet: &Ipv6Packet| -> Result<EthFrame> {
+            Ok(EthFrame::new(
+                stack.mac(),
+                THEIR_MAC,
+                Layer3Packet::Ipv6(packet.to_bytes()?),
+            ))
+        };
+        let frames = fragments.iter().map(frame).collect::<Result<Vec<_>>>()?;
+
+        // The reply's as big, so goes back in fragments too
+        assert!(stack.handle(&frames[0]).await?.is_empty());
+        assert!(stack.handle(&frames[1]).await?.is_empty());
+        let out = stack.handle(&frames[2]).await?;
+        assert_eq!(out.len(), 3);
+        let mut reassembler = Reassembler::new();
+        let mut reply = None;
+        for frame in &out {
+            let Layer3Packet::Ipv6(raw) = frame.payload() else {
+                panic!("Wrong packet type!");
+            };
+            assert!(raw.len() <= stack.mtu);
+            let packet = Ipv6Packet::from_reader(raw.as_slice()).await?;
+            reply = reassembler.add(&packet, Instant::now())?;
+        }
+        let reply = reply.unwrap();
+        let message = Icmpv6Packet::from_reader(reply.data.as_slice(), US6, THEM6).await?;
+        assert_eq!(message, Icmpv6Packet::EchoReply(echo));
+
+        // Missing fragments get the sender told off, once we give up
+        assert!(stack.handle(&frames[0]).await?.is_empty());
+        let out = stack.poll(Instant::now() + Duration::from_secs(60)).await?;
+        let (_, message) = icmpv6_sent(out.last().unwrap()).await?;
+        assert!(matches!(
+            message,
+            Icmpv6Packet::TimeExceeded {
+                code: TimeExceededCode::Reassembly,
+                ..
+            }
+        ));
+        Ok(())
+    }
+
+    async fn mld_sent(frame: &EthFrame) -> Result<(Ipv6Packet, Icmpv6Packet)> {
+        let Layer3Packet::Ipv6(raw) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        let mut packet = Ipv6Packet::from_reader(raw.as_slice()).await?;
+        assert_eq!(packet.next_header, IpProtocol::Ipv6HopByHop);
+        packet.skip_hop_by_hop()?;
+        let message =
+            Icmpv6Packet::from_reader(packet.data.as_slice(), packet.source, packet.destination)
+                .await?;
+        Ok((packet, message))
+    }
+
+    #[tokio::test]
+    async fn multicast_listener() -> Result<()> {
+        let now = Instant::now();
+        let mut stack = stack().add_ipv6_address(US6);
+        let solicited = ndp::solicited_node(US6);
+        assert!(stack.ipv6_memberships().is_member(solicited));
+
+        // Our solicited-node group is reported on the first poll
+        let out = stack.poll(now).await?;
+        let (packet, message) = mld_sent(&out[0]).await?;
+        assert_eq!(packet.hop_limit, mld::HOP_LIMIT);
+        assert_eq!(packet.source, US6);
+        assert_eq!(packet.destination, mld::ALL_MLDV2_ROUTERS);
+        let Icmpv6Packet::MldV2Report(records) = message else {
+            panic!("Wrong MLD type!");
+        };
+        assert_eq!(records[0].group, solicited);
+
+        let group = "ff02::fb".parse()?;
+        let out = stack.join_ipv6(group).await?;
+        assert_eq!(
+            out[0].dst(),
+            Mac6::from_ipv6_multicast(mld::ALL_MLDV2_ROUTERS).unwrap()
+        );
+
+        // A v1 querier gets v1 reports, each sent to its group
+        let query = Icmpv6Packet::MldQuery(MldQuery {
+            max_response_code: 1000,
+            group: Ipv6Addr::UNSPECIFIED,
+            v2: None,
+        });
+        let packet = Ipv6Packet::new(
+            THEM6,
+            ndp::ALL_NODES,
+            IpProtocol::Icmpv6,
+            query.to_bytes(THEM6, ndp::ALL_NODES),
+        )
+        .set_hop_limit(mld::HOP_LIMIT)
+        .set_router_alert();
+        let frame = EthFrame::new(
+            Mac6::from_ipv6_multicast(ndp::ALL_NODES).unwrap(),
+            THEIR_MAC,
+            Layer3Packet::Ipv6(packet.to_bytes()?),
+        );
+        assert!(stack.handle(&frame).await?.is_empty());
+        let out = stack.poll(now + Duration::from_secs(2)).await?;
+        let mut reported = Vec::new();
+        for frame in &out {
+            let (packet, message) = mld_sent(frame).await?;
+            assert_eq!(message, Icmpv6Packet::MldV1Report(packet.destination));
+            reported.push(packet.destination);
+        }
+        reported.sort();
+        reported.dedup();
+        assert_eq!(reported, [group, solicited]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn neighbour_discovery() -> Result<()> {
+        let mut stack = stack().add_ipv6_address(US6);
+        // Our solicited-node group's report goes out first
+        stack.poll(Instant::now()).await?;
+        // We don't know them, so the reply waits while we ask
+        let request = Icmpv6Packet::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        assert!(
+            stack
+                .handle(&icmpv6_frame(THEM6, US6, &request)?)
+                .await?
+                .is_empty()
+        );
+        let out = stack.poll(stack.next_deadline().unwrap()).await?;
+        assert_eq!(
+            out[0].dst(),
+            Mac6::from_ipv6_multicast(ndp::solicited_node(THEM6)).unwrap()
+        );
+        let (packet, message) = icmpv6_sent(&out[0]).await?;
+        assert_eq!(packet.destination, ndp::solicited_node(THEM6));
+        assert_eq!(
+            message,
+            Icmpv6Packet::NeighbourSolicitation(NeighbourSolicitation::new(THEM6, stack.mac()))
+        );
+
+        let answer =
+            Icmpv6Packet::NeighbourAdvertisement(NeighbourAdvertisement::answer(THEM6, THEIR_MAC));
+        let out = stack.handle(&icmpv6_frame(THEM6, US6, &answer)?).await?;
+        assert_eq!(out[0].dst(), THEIR_MAC);
+        assert!(matches!(
+            icmpv6_sent(&out[0]).await?.1,
+            Icmpv6Packet::EchoReply(_)
+        ));
+        assert_eq!(stack.ipv6_resolver.next_deadline(), None);
+
+        // Someone checking whether our address is free is told it isn't
+        let probe = Icmpv6Packet::NeighbourSolicitation(NeighbourSolicitation {
+            target: US6,
+            options: Vec::new(),
+        });
+        let out = stack
+            .handle(&icmpv6_frame(
+                Ipv6Addr::UNSPECIFIED,
+                ndp::solicited_node(US6),
+                &probe,
+            )?)
+            .await?;
+        let (packet, _) = icmpv6_sent(&out[0]).await?;
+        assert_eq!(packet.destination, ndp::ALL_NODES);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detects_duplicates() -> Result<()> {
+        let mut stack = stack();
+        let now = Instant::now();
+        stack.assign_ipv6_address(US6, now)?;
+        assert!(stack.assign_ipv6_address(US6, now).is_err());
+        assert!(stack.is_tentative(US6));
+        assert!(stack.ipv6_addresses().is_empty());
+
+        // A solicitation for it, from nowhere, to its solicited-node group
+        let out = stack.poll(now + Duration::from_secs(1)).await?;
+        let mut solicitations = Vec::new();
+        for frame in &out {
+            let Layer3Packet::Ipv6(raw) = frame.payload() else {
+                panic!("Wrong packet type!");
+            };
+            let mut packet = Ipv6Packet::from_reader(raw.as_slice()).await?;
+            // MLD reports joining the group go out too
+            packet.skip_hop_by_hop()?;
+            let message = Icmpv6Packet::from_reader(
+                packet.data.as_slice(),
+                packet.source,
+                packet.destination,
+            )
+            .await?;
+            if let Icmpv6Packet::NeighbourSolicitation(solicitation) = message {
+                solicitations.push((packet.source, packet.destination, solicitation));
+            }
+        }
+        let [(source, destination, solicitation)] = solicitations.as_slice() else {
+            panic!("Expected one solicitation, got {solicitations:?}");
+        };
+        assert_eq!(
+            (*source, *destination, solicitation.target),
+            (Ipv6Addr::UNSPECIFIED, ndp::solicited_node(US6), US6)
+        );
+
+        // Meanwhile, pings to it go unanswered, as do those to all nodes,
+        // with no other address to answer from
+        let request = Icmpv6Packet::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        for destination in [US6, ndp::ALL_NODES] {
+            assert!(
+                stack
+                    .handle(&icmpv6_frame(THEM6, destination, &request)?)
+                    .await?
+                    .is_empty()
+            );
+        }
+
+        // Someone defends it, so we won't use it
+        let defence = Icmpv6Packet::NeighbourAdvertisement(NeighbourAdvertisement {
+            solicited: false,
+            ..NeighbourAdvertisement::answer(US6, THEIR_MAC)
+        });
+        stack
+            .handle(&icmpv6_frame(THEM6, ndp::ALL_NODES, &defence)?)
+            .await?;
+        assert_eq!(
+            stack.take_dad_events(),
+            [DadEvent::Duplicate {
+                address: US6,
+                mac: Some(THEIR_MAC),
+            }]
+        );
+        assert!(!stack.is_tentative(US6));
+        stack.poll(now + Duration::from_secs(3)).await?;
+        assert!(stack.ipv6_addresses().is_empty());
+        assert!(stack.take_dad_events().is_empty());
+        assert!(!stack.ipv6_memberships().is_member(ndp::solicited_node(US6)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn autoconfigures() -> Result<()> {
+        let mut stack = stack().add_ipv6_address(US6);
+        let advertisement = Icmpv6Packet::RouterAdvertisement(RouterAdvertisement {
+            hop_limit: 64,
+            managed: false,
+            other_config: false,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retransmit_timer: 0,
+            options: vec![
+                NdpOption::SourceLinkLayerAddress(THEIR_MAC),
+                NdpOption::PrefixInformation(PrefixInformation {
+                    prefix: "2001:db8:1::".parse()?,
+                    prefix_length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: 86400,
+                    preferred_lifetime: 14400,
+                }),
+            ],
+        });
+        let frame = icmpv6_frame(THEM6, ndp::ALL_NODES, &advertisement)?;
+        assert!(stack.handle(&frame).await?.is_empty());
+        let global: Ipv6Addr = "2001:db8:1::ff:fe00:1".parse()?;
+        assert!(stack.is_tentative(global));
+        assert_eq!(stack.ipv6_addresses(), [US6]);
+
+        // Usable once nobody's defended it
+        let now = Instant::now();
+        stack.poll(now + Duration::from_secs(1)).await?;
+        stack.poll(now + Duration::from_secs(3)).await?;
+        assert_eq!(stack.take_dad_events(), [DadEvent::Assigned(global)]);
+        assert_eq!(stack.ipv6_addresses(), [US6, global]);
+        let remote: Ipv6Addr = "2001:db8:99::1".parse()?;
+        assert_eq!(stack.ipv6_next_hop(remote), THEM6);
+
+        // Pings from off-link are answered through the router
+        let request = Icmpv6Packet::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        let out = stack
+            .handle(&icmpv6_frame(remote, global, &request)?)
+            .await?;
+        assert_eq!(out[0].dst(), THEIR_MAC);
+        let (packet, _) = icmpv6_sent(&out[0]).await?;
+        assert_eq!((packet.source, packet.destination), (global, remote));
+
+        // Until the addresses and routes run out
+        stack
+            .poll(Instant::now() + Duration::from_secs(86400))
+            .await?;
+        assert_eq!(stack.ipv6_addresses(), [US6]);
+        assert!(stack.ipv6_routes().routes().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn advertises_as_router() -> Result<()> {
+        let advertiser = RouterAdvertiser::new().add_prefix("2001:db8:1::/64".parse()?);
+        let mut router = stack()
+            .add_ipv6_address(US6)
+            .set_router_advertiser(advertiser);
+        let first = router.next_deadline().unwrap();
+        let out = router.poll(first).await?;
+        // Our solicited-node group's report goes out alongside it
+        assert_eq!(out.len(), 2);
+        let advert = out
+            .iter()
+            .find(|frame| frame.dst() == Mac6::from_ipv6_multicast(ndp::ALL_NODES).unwrap())
+            .unwrap();
+
+        // A host hearing it autoconfigures
+        let mut host = Stack::new(THEIR_MAC, OUR_ADDRESS)
+            .add_ipv6_address(THEM6)
+            .set_dad_transmits(0);
+        host.handle(advert).await?;
+        assert_eq!(host.ipv6_next_hop("2001:db8:99::1".parse()?), US6);
+        assert_eq!(host.ipv6_addresses().len(), 2);
+
+        // Soliciting gets another one sooner than it'd otherwise come
+        let solicitation = Icmpv6Packet::RouterSolicitation(RouterSolicitation {
+            options: Vec::new(),
+        });
+        let frame = icmpv6_frame(Ipv6Addr::UNSPECIFIED, ndp::ALL_ROUTERS, &solicitation)?;
+        assert!(router.handle(&frame).await?.is_empty());
+        assert!(router.next_deadline().unwrap() <= first + Duration::from_secs(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn router_without_address() -> Result<()> {
+        let mut router = stack().set_router_advertiser(RouterAdvertiser::new());
+        // Pings to all routers are taken, but there's nothing to answer from
+        let request = Icmpv6Packet::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        let frame = icmpv6_frame(THEM6, ndp::ALL_ROUTERS, &request)?;
+        assert!(router.handle(&frame).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn group_without_address() -> Result<()> {
+        let mut stack = stack();
+        let group = "ff02::fb".parse()?;
+        stack.join_ipv6(group).await?;
+        // Pings to groups we've joined are taken, but there's nothing to
+        // answer from
+        let request = Icmpv6Packet::EchoRequest(Echo {
+            identifier: 1,
+            sequence: 1,
+            data: Vec::new(),
+        });
+        let frame = icmpv6_frame(THEM6, group, &request)?;
+        assert!(stack.handle(&frame).await?.is_empty());
+        Ok(())
+    }
+
+    fn arp_request(sender: Ipv4Addr, mac: Mac6, target: Ipv4Addr) -> EthFrame {
+        let arp = ArpPacket::request(sender, mac, target);
+        EthFrame::new(Mac6::BROADCAST, mac, Layer3Packet::Arp(arp))
+    }
+
+    #[tokio::test]
+    async fn learns_from_arp() -> Result<()> {
+        let mut stack = stack();
+        let neighbour = Ipv4Addr::new(192, 168, 0, 9);
+        let mac = Mac6::new([2, 0, 0, 0, 0, 9]);
+        let now = Instant::now();
+
+        // Requests for someone else only refresh what we already know
+        stack.handle(&arp_request(neighbour, mac, THEM)).await?;
+        assert_eq!(stack.arp_cache().lookup(neighbour, now), None);
+
+        stack.handle(&arp_request(neighbour, mac, US)).await?;
+        assert_eq!(
+            stack.arp_cache().state(neighbour, Instant::now()),
+            Some(ArpState::Reachable)
+        );
+        let raw = stack.raw_socket(IpProtocol::Udp);
+        raw.send_to(neighbour, [1, 2, 3, 4, 0, 8, 0, 0]).await?;
+        assert_eq!(stack.next_outgoing().await.dst(), mac);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_arp() -> Result<()> {
+        let mut stack = stack();
+        stack.addresses_mut().add("10.0.0.1/24".parse()?);
+        let mac = Mac6::new([2, 0, 0, 0, 0, 9]);
+        for address in [US, Ipv4Addr::new(10, 0, 0, 1)] {
+            let out = stack
+                .handle(&arp_request(Ipv4Addr::new(192, 168, 0, 9), mac, address))
+                .await?;
+            assert_eq!((out[0].dst(), out[0].src()), (mac, stack.mac()));
+            let Layer3Packet::Arp(reply) = out[0].payload() else {
+                panic!("Wrong packet type!");
+            };
+            assert_eq!(reply.sender(), (address, stack.mac()));
+            assert_eq!(reply.target().0, Ipv4Addr::new(192, 168, 0, 9));
+        }
+
+        // Only our own addresses
+        let out = stack
+            .handle(&arp_request(Ipv4Addr::new(192, 168, 0, 9), mac, THEM))
+            .await?;
+        assert!(out.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn announces_addresses() -> Result<()> {
+        let stack = stack();
+        let frame = stack.announce_address(US)?;
+        assert_eq!(frame.dst(), Mac6::BROADCAST);
+        let Layer3Packet::Arp(arp) = frame.payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(arp.sender(), (US, stack.mac()));
+        assert!(stack.announce_address(THEM).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detects_conflicts() -> Result<()> {
+        let mut stack = stack();
+        let now = Instant::now();
+        let other = Mac6::new([2, 0, 0, 0, 0, 9]);
+        let claimed = Ipv4Addr::new(192, 168, 0, 2);
+        let contested = Ipv4Addr::new(192, 168, 0, 3);
+        stack.assign_address("192.168.0.2/24".parse()?, now)?;
+        stack.assign_address("192.168.0.3/24".parse()?, now)?;
+        assert!(
+            stack
+                .assign_address("192.168.0.3/24".parse()?, now)
+                .is_err()
+        );
+
+        // The first probes go out within a second
+        assert_eq!(stack.poll(now + Duration::from_secs(1)).await?.len(), 2);
+        // Someone else has one of the addresses
+        let arp = arp_request(contested, other, contested);
+        stack.handle(&arp).await?;
+        let mut sent = 0;
+        while let Some(deadline) = stack.next_deadline() {
+            sent += stack.poll(deadline).await?.len();
+        }
+        // The rest of the probes and announcements for the other
+        assert_eq!(sent, 4);
+        assert_eq!(
+            stack.take_acd_events(),
+            [
+                AcdEvent::Conflict {
+                    address: contested,
+                    mac: other
+                },
+                AcdEvent::Claimed(claimed)
+            ]
+        );
+        assert!(stack.addresses().contains(claimed));
+        assert!(!stack.addresses().contains(contested));
+
+        // Defended once, then left alone for a while
+        let arp = arp_request(claimed, other, THEM);
+        assert_eq!(stack.handle(&arp).await?.len(), 1);
+        assert!(stack.handle(&arp).await?.is_empty());
+        assert_eq!(stack.take_acd_events().len(), 2);
+        assert_eq!(stack.arp_cache().lookup(claimed, Instant::now()), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolves_neighbours() -> Result<()> {
+        let mut stack = stack();
+        let neighbour = Ipv4Addr::new(192, 168, 0, 9);
+        let mac = Mac6::new([2, 0, 0, 0, 0, 9]);
+        let resolved = stack.resolve(neighbour);
+        let raw = stack.raw_socket(IpProtocol::Udp);
+        raw.send_to(neighbour, [1, 2, 3, 4, 0, 8, 0, 0]).await?;
+        // Parked, not sent
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), stack.next_outgoing())
+                .await
+                .is_err()
+        );
+
+        let deadline = stack.next_deadline().unwrap();
+        let requests = stack.poll(deadline).await?;
+        let Layer3Packet::Arp(request) = requests[0].payload() else {
+            panic!("Wrong packet type!");
+        };
+        assert_eq!(request.target_protocol_address(), neighbour);
+
+        let reply = ArpPacket::reply(neighbour, mac, US, stack.mac());
+        let out = stack
+            .handle(&EthFrame::new(stack.mac(), mac, Layer3Packet::Arp(reply)))
+            .await?;
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].dst(), mac);
+        assert_eq!(resolved.await?, mac);
+        assert_eq!(stack.resolve(neighbour).await?, mac);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_martians() -> Result<()> {
+        let mut stack = stack();
+        let spoofer = Mac6::new([2, 0, 0, 0, 0, 6]);
+        // Our own address from the wire isn't answered, nor learnt
+        let spoofed = ping_from(US, spoofer, US, 64)?;
+        assert!(stack.handle(&spoofed).await?.is_empty());
+        let loopback = ping_from(Ipv4Addr::LOCALHOST, spoofer, US, 64)?;
+        assert!(stack.handle(&loopback).await?.is_empty());
+        assert_eq!(stack.martians().get(Martian::OwnSource), 1);
+        assert_eq!(stack.martians().total(), 2);
+
+        let out = stack.handle(&ping(US)?).await?;
+        assert_ne!(out[0].dst(), spoofer);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn firewall() -> Result<()> {
+        let mut firewall = Firewall::new();
+        firewall.push(
+            Rule::new(Action::Drop)
+                .set_source("192.168.0.64/26".parse()?)
+                .set_protocol(IpProtocol::Icmp),
+        );
+        let mut stack = stack().set_forwarding(true).set_firewall(firewall);
+        let blocked = Ipv4Addr::new(192, 168, 0, 70);
+        let blocked_mac = Mac6::new([2, 0, 0, 0, 0, 70]);
+        assert!(
+            stack
+                .handle(&ping_from(blocked, blocked_mac, US, 64)?)
+                .await?
+                .is_empty()
+        );
+        assert!(
+            stack
+                .handle(&ping_from(blocked, blocked_mac, THEM, 64)?)
+                .await?
+                .is_empty()
+        );
+        assert_eq!(stack.handle(&ping(US)?).await?.len(), 1);
+        assert_eq!(stack.firewall().rules().next().unwrap().1, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_others() -> Result<()> {
+        let mut stack = stack();
+        assert!(stack.handle(&ping(THEM)?).await?.is_empty());
+
+        let mut stack = stack.set_echo_replies(false);
+        assert!(stack.handle(&ping(US)?).await?.is_empty());
+
+        // No route back
+        let mut stack = Stack::new(Mac6::new([2, 0, 0, 0, 0, 1]), OUR_ADDRESS);
+        assert!(stack.handle(&ping(US)?).await?.is_empty());
+        Ok(())
+    }
+}
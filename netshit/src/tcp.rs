@@ -0,0 +1,1985 @@
+//! TCP connections (RFC 9293): the state machine for one connection, fed
+//! the peer's segments and the application's data, giving back segments to
+//! send
+use crate::layer4::tcp::{TcpOption, TcpSegment};
+use crate::rtt::{self, RttEstimator};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Largest segment the peer can take, if it doesn't say (RFC 9293 §3.7.1)
+pub const DEFAULT_MSS: u16 = 536;
+/// Bytes buffered in each direction, by default
+pub const DEFAULT_BUFFER: usize = 64 * 1024;
+/// Maximum segment lifetime
+const MSL: Duration = Duration::from_secs(30);
+/// How long a connection lingers in TIME-WAIT, by default: twice the
+/// maximum segment lifetime
+pub const DEFAULT_TIME_WAIT: Duration = Duration::from_secs(MSL.as_secs() * 2);
+/// How long after entering TIME-WAIT a connection's ports may be reused,
+/// with [TcpConfig::set_time_wait_reuse]
+pub const TIME_WAIT_REUSE_AFTER: Duration = Duration::from_secs(1);
+/// Bytes the timestamps option takes in each segment, padding included
+const TIMESTAMPS_LENGTH: u16 = 12;
+/// Times a segment's resent before giving up on the connection, by default
+pub const DEFAULT_MAX_RETRIES: u32 = 12;
+/// Most SACK blocks that fit in an acknowledgement's options
+const MAX_SACK_BLOCKS: usize = 4;
+/// How long an acknowledgement may wait for something to ride along with,
+/// by default
+pub const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(200);
+/// Largest shift a window can be scaled by (RFC 7323 §2.3)
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Settings for connections, applied when they're opened
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TcpConfig {
+    max_retries: u32,
+    send_buffer: usize,
+    recv_buffer: usize,
+    nodelay: bool,
+    ack_delay: Duration,
+    fast_open: bool,
+    timestamps: bool,
+    time_wait: Duration,
+    time_wait_reuse: bool,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpConfig {
+    pub const fn new() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            send_buffer: DEFAULT_BUFFER,
+            recv_buffer: DEFAULT_BUFFER,
+            nodelay: false,
+            ack_delay: DEFAULT_ACK_DELAY,
+            fast_open: false,
+            timestamps: false,
+            time_wait: DEFAULT_TIME_WAIT,
+            time_wait_reuse: false,
+        }
+    }
+
+    /// Reset the connection once a segment's been resent this many times
+    /// without being acknowledged
+    #[must_use]
+    pub const fn set_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    pub const fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Bytes the application may queue to be sent
+    #[must_use]
+    pub const fn set_send_buffer(mut self, size: usize) -> Self {
+        self.send_buffer = size;
+        self
+    }
+
+    pub const fn send_buffer(&self) -> usize {
+        self.send_buffer
+    }
+
+    /// Bytes received and not yet read that are held, which bounds the
+    /// window we advertise
+    #[must_use]
+    pub const fn set_recv_buffer(mut self, size: usize) -> Self {
+        self.recv_buffer = size;
+        self
+    }
+
+    pub const fn recv_buffer(&self) -> usize {
+        self.recv_buffer
+    }
+
+    /// Send small segments at once, rather than holding them back while
+    /// earlier data's unacknowledged (Nagle's algorithm, RFC 9293 §3.7.4)
+    #[must_use]
+    pub const fn set_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub const fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Longest an acknowledgement of data waits, in case a reply can carry
+    /// it; zero acknowledges every segment at once
+    #[must_use]
+    pub const fn set_ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = delay;
+        self
+    }
+
+    pub const fn ack_delay(&self) -> Duration {
+        self.ack_delay
+    }
+
+    /// Use TCP Fast Open (RFC 7413): connecting, send data in the SYN once
+    /// the server's given us a cookie; accepting, hand out cookies and take
+    /// data from SYNs that present one
+    #[must_use]
+    pub const fn set_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    pub const fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    /// Offer timestamps (RFC 7323), and use them if the peer agrees:
+    /// segments older than the latest it's sent are dropped, so a
+    /// connection's ports can safely be reused sooner
+    #[must_use]
+    pub const fn set_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    pub const fn timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    /// How long to linger in TIME-WAIT, acknowledging a repeated FIN, before
+    /// the connection's forgotten; zero forgets it at once
+    #[must_use]
+    pub const fn set_time_wait(mut self, time_wait: Duration) -> Self {
+        self.time_wait = time_wait;
+        self
+    }
+
+    pub const fn time_wait(&self) -> Duration {
+        self.time_wait
+    }
+
+    /// Let a connection that used timestamps give up its ports while in
+    /// TIME-WAIT, to a new connection between them (RFC 6191): one opened
+    /// to us whose SYN is newer, or one we open once we're out of ports
+    #[must_use]
+    pub const fn set_time_wait_reuse(mut self, reuse: bool) -> Self {
+        self.time_wait_reuse = reuse;
+        self
+    }
+
+    pub const fn time_wait_reuse(&self) -> bool {
+        self.time_wait_reuse
+    }
+}
+
+/// Where a connection is in its life
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TcpState {
+    /// We've sent a SYN, and wait for the peer's
+    SynSent,
+    /// We've had a SYN and answered it, and wait for that to be acknowledged
+    SynReceived,
+    Established,
+    /// We've closed, and wait for our FIN to be acknowledged
+    FinWait1,
+    /// Our FIN's acknowledged; we wait for the peer's
+    FinWait2,
+    /// The peer's closed; we may still send
+    CloseWait,
+    /// Both sides closed at once, and we wait for our FIN to be acknowledged
+    Closing,
+    /// The peer closed first, and we wait for our FIN to be acknowledged
+    LastAck,
+    /// Both sides are done; we linger to acknowledge a repeated FIN
+    TimeWait,
+    Closed,
+}
+
+/// One end of a connection, between two ports
+///
+/// Addresses are left to the caller, which demultiplexes segments to the
+/// right connection.
+#[derive(Debug)]
+pub struct TcpConnection {
+    state: TcpState,
+    local_port: u16,
+    remote_port: u16,
+    /// Our initial sequence number, that of our SYN
+    iss: u32,
+    /// Oldest sequence number not yet acknowledged
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// How much the peer's willing to take, from `snd_una`
+    snd_wnd: u32,
+    /// Sequence and acknowledgement numbers of the segment `snd_wnd` was
+    /// last taken from, so older ones don't undo it
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// Next sequence number expected from the peer
+    rcv_nxt: u32,
+    /// Right edge of the window we last advertised, which we don't take
+    /// back
+    rcv_adv: u32,
+    /// Both ends scale their windows, by these shifts
+    window_scaling: bool,
+    snd_scale: u8,
+    rcv_scale: u8,
+    /// Largest segment the peer can take
+    mss: u16,
+    /// Largest segment we can take, as we advertise
+    local_mss: u16,
+    /// Data not yet acknowledged, sent or not, from `snd_una`
+    send_buffer: VecDeque<u8>,
+    send_capacity: usize,
+    /// Data received in order, not yet read
+    recv_buffer: VecDeque<u8>,
+    recv_capacity: usize,
+    /// Data received past a gap, as separate blocks in sequence order
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    /// Start of the segment last put in `out_of_order`, whose block is
+    /// reported first
+    latest_out_of_order: u32,
+    /// Both ends understand selective acknowledgements
+    sack_permitted: bool,
+    /// Connecting, the Fast Open cookie to present, or the one the server
+    /// gave us; accepting, the one to give the client
+    fast_open_cookie: Option<Vec<u8>>,
+    /// Bytes taken from the peer's SYN, by Fast Open
+    syn_data: u32,
+    /// The application's done sending; a FIN follows the data
+    closing: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    /// The connection was reset, by the peer or for want of an answer
+    reset: bool,
+    ack_due: bool,
+    /// When an acknowledgement held back must be sent
+    ack_at: Option<Instant>,
+    /// Segments received since we last acknowledged
+    unacknowledged: u32,
+    time_wait_until: Option<Instant>,
+    config: TcpConfig,
+    rtt: RttEstimator,
+    /// When to resend the oldest unacknowledged segment, if there is one
+    retransmit_at: Option<Instant>,
+    /// Times it's been resent
+    retries: u32,
+    /// Both ends send timestamps
+    timestamps: bool,
+    /// What our timestamps count milliseconds from; the first time we're
+    /// polled or handed a segment, unless it's set
+    ts_epoch: Option<Instant>,
+    /// Our timestamp, as of when we were last polled or handed a segment
+    ts_clock: u32,
+    /// The peer's latest timestamp, which we echo
+    ts_recent: u32,
+    /// A segment being timed, for a round-trip sample: the acknowledgement
+    /// that covers it, and when it was sent
+    timing: Option<(u32, Instant)>,
+    /// When to probe the peer's window, if it's shut with data waiting and
+    /// nothing in flight to hear of it reopening by
+    persist_at: Option<Instant>,
+    /// Probes sent since the window shut, each waiting twice as long
+    probes: u32,
+}
+
+impl TcpConnection {
+    fn new(local_port: u16, remote_port: u16, iss: u32, local_mss: u16) -> Self {
+        Self {
+            state: TcpState::SynSent,
+            local_port,
+            remote_port,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: iss,
+            rcv_nxt: 0,
+            rcv_adv: 0,
+            window_scaling: false,
+            snd_scale: 0,
+            rcv_scale: 0,
+            mss: DEFAULT_MSS,
+            local_mss,
+            send_buffer: VecDeque::new(),
+            send_capacity: DEFAULT_BUFFER,
+            recv_buffer: VecDeque::new(),
+            recv_capacity: DEFAULT_BUFFER,
+            out_of_order: Vec::new(),
+            latest_out_of_order: 0,
+            sack_permitted: false,
+            fast_open_cookie: None,
+            syn_data: 0,
+            closing: false,
+            fin_sent: false,
+            fin_received: false,
+            reset: false,
+            ack_due: false,
+            ack_at: None,
+            unacknowledged: 0,
+            time_wait_until: None,
+            config: TcpConfig::new(),
+            rtt: RttEstimator::new(),
+            retransmit_at: None,
+            retries: 0,
+            timestamps: false,
+            ts_epoch: None,
+            ts_clock: 0,
+            ts_recent: 0,
+            timing: None,
+            persist_at: None,
+            probes: 0,
+        }
+    }
+
+    /// Open a connection to `remote_port`, starting at sequence number `iss`
+    /// and taking segments of up to `mss`; the SYN's sent on the first poll
+    pub fn connect(local_port: u16, remote_port: u16, iss: u32, mss: u16) -> Self {
+        Self::new(local_port, remote_port, iss, mss)
+    }
+
+    /// Answer `syn`, opening a connection from the port it was sent from;
+    /// the SYN-ACK's sent on the first poll
+    pub fn accept(syn: &TcpSegment, iss: u32, mss: u16) -> Self {
+        let mut connection = Self::new(syn.destination_port, syn.source_port, iss, mss);
+        connection.state = TcpState::SynReceived;
+        connection.synchronize(syn);
+        connection
+    }
+
+    #[must_use]
+    pub fn set_config(mut self, config: TcpConfig) -> Self {
+        self.send_capacity = config.send_buffer;
+        self.recv_capacity = config.recv_buffer;
+        self.config = config;
+        self
+    }
+
+    pub const fn config(&self) -> &TcpConfig {
+        &self.config
+    }
+
+    pub const fn state(&self) -> TcpState {
+        self.state
+    }
+
+    pub const fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub const fn remote_port(&self) -> u16 {
+        self.remote_port
+    }
+
+    pub const fn send_buffer_size(&self) -> usize {
+        self.send_capacity
+    }
+
+    /// Change how much the application may queue to be sent; what's
+    /// already queued stays
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.send_capacity = size;
+    }
+
+    pub const fn recv_buffer_size(&self) -> usize {
+        self.recv_capacity
+    }
+
+    /// Change how much received data is held for the application
+    ///
+    /// The window never shrinks below what's been advertised, and past the
+    /// handshake it can only be advertised as far as the window scale agreed
+    /// then allows.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_capacity = size;
+    }
+
+    pub const fn nodelay(&self) -> bool {
+        self.config.nodelay
+    }
+
+    /// See [TcpConfig::set_nodelay]
+    pub const fn set_nodelay(&mut self, nodelay: bool) {
+        self.config.nodelay = nodelay;
+    }
+
+    pub const fn ack_delay(&self) -> Duration {
+        self.config.ack_delay
+    }
+
+    /// See [TcpConfig::set_ack_delay]; an acknowledgement already waiting
+    /// keeps its time
+    pub const fn set_ack_delay(&mut self, delay: Duration) {
+        self.config.ack_delay = delay;
+    }
+
+    /// See [TcpConfig::set_fast_open]
+    pub const fn set_fast_open(&mut self, fast_open: bool) {
+        self.config.fast_open = fast_open;
+    }
+
+    /// Connecting, the Fast Open cookie the server gave us, or the one
+    /// we'll present; accepting, the one we'll give the client
+    pub fn fast_open_cookie(&self) -> Option<&[u8]> {
+        self.fast_open_cookie.as_deref()
+    }
+
+    /// Present `cookie`, from an earlier connection to the same server, so
+    /// data can go in our SYN; without one, we ask for one
+    pub fn set_fast_open_cookie(&mut self, cookie: Option<Vec<u8>>) {
+        self.fast_open_cookie = cookie;
+    }
+
+    /// Check the Fast Open cookie in the `syn` we're answering against the
+    /// one the client should have, `cookie`: if they match, take the SYN's
+    /// data at once; otherwise, give the client `cookie` in our SYN-ACK
+    pub fn fast_open(&mut self, syn: &TcpSegment, cookie: Vec<u8>) {
+        if !self.config.fast_open || self.state != TcpState::SynReceived {
+            return;
+        }
+        let Some(presented) = syn.options.iter().find_map(|option| match option {
+            TcpOption::FastOpen(presented) => Some(presented),
+            _ => None,
+        }) else {
+            return;
+        };
+        if *presented != cookie {
+            self.fast_open_cookie = Some(cookie);
+            return;
+        }
+        let length = syn.data.len().min(self.receive_window() as usize);
+        self.recv_buffer.extend(&syn.data[..length]);
+        self.syn_data = length as u32;
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(self.syn_data);
+    }
+
+    /// Count our timestamps from `epoch`, which connections should share so
+    /// that a new one's are newer than an old one's between the same ports
+    pub const fn set_timestamp_epoch(&mut self, epoch: Instant) {
+        self.ts_epoch = Some(epoch);
+    }
+
+    /// Whether, lingering in TIME-WAIT, we may give up our ports by `now`
+    /// to a new connection we open
+    ///
+    /// Only once we've used timestamps, and a little while has passed, so
+    /// the new connection's timestamps are newer and the peer can tell its
+    /// segments from ours.
+    pub fn is_reusable(&self, now: Instant) -> bool {
+        let Some(until) = self.time_wait_until else {
+            return false;
+        };
+        let since = until - self.config.time_wait;
+        self.config.time_wait_reuse && self.timestamps && since + TIME_WAIT_REUSE_AFTER <= now
+    }
+
+    /// Whether, lingering in TIME-WAIT, we may give up our ports to the
+    /// connection `syn` opens: it has to be timestamped later than anything
+    /// the peer sent before (RFC 6191 §2)
+    pub fn is_reusable_by(&self, syn: &TcpSegment) -> bool {
+        let flags = syn.flags;
+        let opening = flags.syn && !flags.rst && syn.acknowledgement.is_none();
+        let newer = timestamp(syn).is_some_and(|(value, _)| seq_lt(self.ts_recent, value));
+        self.state == TcpState::TimeWait
+            && self.config.time_wait_reuse
+            && self.timestamps
+            && opening
+            && newer
+    }
+
+    /// Whether we took data from the peer's SYN, so the application may
+    /// have it before the handshake's done
+    pub const fn is_fast_open(&self) -> bool {
+        self.syn_data > 0
+    }
+
+    /// Whether the connection was reset rather than closed
+    pub const fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// Whether the application may still queue data to send
+    pub const fn may_send(&self) -> bool {
+        !self.closing
+            && matches!(
+                self.state,
+                TcpState::SynSent
+                    | TcpState::SynReceived
+                    | TcpState::Established
+                    | TcpState::CloseWait
+            )
+    }
+
+    /// Whether everything the peer will send has been read
+    pub fn is_finished(&self) -> bool {
+        self.recv_buffer.is_empty() && (self.fin_received || self.state == TcpState::Closed)
+    }
+
+    /// Queue as much of `data` as fits to be sent, returning how much did
+    pub fn send(&mut self, data: &[u8]) -> usize {
+        if !self.may_send() {
+            return 0;
+        }
+        let room = self.send_capacity.saturating_sub(self.send_buffer.len());
+        let length = data.len().min(room);
+        self.send_buffer.extend(&data[..length]);
+        length
+    }
+
+    /// Read received data into `buf`, returning how much
+    pub fn recv(&mut self, buf: &mut [u8]) -> usize {
+        let length = buf.len().min(self.recv_buffer.len());
+        for (to, from) in buf.iter_mut().zip(self.recv_buffer.drain(..length)) {
+            *to = from;
+        }
+        length
+    }
+
+    /// Send a FIN once all queued data's been sent
+    pub fn close(&mut self) {
+        self.closing = true;
+        if self.state == TcpState::SynSent {
+            self.state = TcpState::Closed;
+        }
+    }
+
+    /// Round-trip time estimates, and the retransmission timeout they give
+    pub const fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// When [TcpConnection::poll] next has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.time_wait_until
+            .into_iter()
+            .chain(self.retransmit_at)
+            .chain(self.persist_at)
+            .chain(self.ack_at)
+            .min()
+    }
+
+    /// Run timers due by `now`, and send whatever can be, returning the
+    /// segments
+    pub fn poll(&mut self, now: Instant) -> Vec<TcpSegment> {
+        let mut out = Vec::new();
+        self.tick(now);
+        if let Some(until) = self.time_wait_until
+            && until <= now
+        {
+            self.time_wait_until = None;
+            self.state = TcpState::Closed;
+        }
+        if let Some(at) = self.retransmit_at
+            && at <= now
+        {
+            self.retransmit(now, &mut out);
+        }
+        if let Some(at) = self.persist_at
+            && at <= now
+        {
+            self.probe(now, &mut out);
+        }
+        if let Some(at) = self.ack_at
+            && at <= now
+        {
+            self.ack_due = true;
+        }
+        self.transmit(now, &mut out);
+        out
+    }
+
+    /// Handle a segment from the peer, returning segments to send in reply
+    pub fn handle(&mut self, segment: &TcpSegment, now: Instant) -> Vec<TcpSegment> {
+        let mut out = Vec::new();
+        self.tick(now);
+        match self.state {
+            TcpState::Closed => return out,
+            TcpState::SynSent => self.handle_syn_sent(segment, now, &mut out),
+            _ => self.handle_synchronized(segment, now, &mut out),
+        }
+        self.transmit(now, &mut out);
+        out
+    }
+
+    /// Bring our timestamp up to `now`
+    fn tick(&mut self, now: Instant) {
+        let epoch = *self.ts_epoch.get_or_insert(now);
+        // Milliseconds, wrapping around as sequence numbers do
+        self.ts_clock = now.saturating_duration_since(epoch).as_millis() as u32;
+    }
+
+    /// Take the peer's initial sequence number and options from its SYN
+    fn synchronize(&mut self, syn: &TcpSegment) {
+        self.rcv_nxt = syn.sequence.wrapping_add(1);
+        self.rcv_adv = self.rcv_nxt;
+        // A SYN's window is never scaled
+        self.snd_wnd = syn.window.into();
+        self.snd_wl1 = syn.sequence;
+        self.snd_wl2 = syn.acknowledgement.unwrap_or(self.iss);
+        self.window_scaling = false;
+        self.timestamps = false;
+        for option in &syn.options {
+            match *option {
+                TcpOption::MaxSegmentSize(mss) => self.mss = mss,
+                TcpOption::SackPermitted => self.sack_permitted = true,
+                TcpOption::WindowScale(scale) => {
+                    self.window_scaling = true;
+                    self.snd_scale = scale.min(MAX_WINDOW_SCALE);
+                }
+                TcpOption::Timestamps { value, .. } => {
+                    self.timestamps = true;
+                    self.ts_recent = value;
+                }
+                _ => {}
+            }
+        }
+        if !self.window_scaling {
+            self.snd_scale = 0;
+            self.rcv_scale = 0;
+        }
+    }
+
+    fn handle_syn_sent(&mut self, segment: &TcpSegment, now: Instant, out: &mut Vec<TcpSegment>) {
+        if let Some(ack) = segment.acknowledgement
+            && (seq_le(ack, self.iss) || seq_lt(self.snd_nxt, ack))
+        {
+            // Not about our SYN; likely left over from an old connection
+            if !segment.flags.rst {
+                out.push(self.reset(ack));
+            }
+            return;
+        }
+        if segment.flags.rst {
+            // Refused, if it's about our SYN
+            if segment.acknowledgement.is_some() {
+                self.abort();
+            }
+            return;
+        }
+        if !segment.flags.syn {
+            return;
+        }
+        self.synchronize(segment);
+        self.timestamps &= self.config.timestamps;
+        match segment.acknowledgement {
+            Some(ack) => {
+                if self.config.fast_open
+                    && let Some(cookie) = segment.options.iter().find_map(|option| match option {
+                        TcpOption::FastOpen(cookie) if !cookie.is_empty() => Some(cookie),
+                        _ => None,
+                    })
+                {
+                    self.fast_open_cookie = Some(cookie.clone());
+                }
+                // Whatever data of the SYN's the server didn't take is sent
+                // again as usual
+                let data = ack.wrapping_sub(self.iss.wrapping_add(1)) as usize;
+                self.send_buffer.drain(..data.min(self.send_buffer.len()));
+                self.snd_una = ack;
+                self.snd_nxt = ack;
+                self.state = TcpState::Established;
+                self.ack_due = true;
+                self.acknowledged(now);
+            }
+            // Both sides opened at once: send our SYN again, acknowledging
+            // theirs
+            None => {
+                self.state = TcpState::SynReceived;
+                self.snd_nxt = self.iss;
+            }
+        }
+    }
+
+    fn handle_synchronized(
+        &mut self,
+        segment: &TcpSegment,
+        now: Instant,
+        out: &mut Vec<TcpSegment>,
+    ) {
+        let flags = segment.flags;
+        // Their SYN again: our SYN-ACK was lost
+        if self.state == TcpState::SynReceived
+            && flags.syn
+            && segment.sequence.wrapping_add(1 + self.syn_data) == self.rcv_nxt
+        {
+            self.snd_nxt = self.iss;
+            return;
+        }
+        // Older than the latest the peer's sent, so likely left over from an
+        // earlier connection between the same ports (PAWS, RFC 7323 §5.3)
+        if self.timestamps
+            && !flags.rst
+            && let Some((value, _)) = timestamp(segment)
+            && seq_lt(value, self.ts_recent)
+        {
+            self.ack_due = true;
+            return;
+        }
+        if !self.is_acceptable(segment) {
+            if !flags.rst {
+                self.ack_due = true;
+            }
+            // Nothing more's accepted through a closed window
+            if self.receive_window() != 0 || segment.sequence != self.rcv_nxt {
+                return;
+            }
+        }
+        if flags.rst {
+            // Only an exact match resets us; anything else in the window
+            // gets a challenge ACK (RFC 5961 §3.2)
+            if segment.sequence == self.rcv_nxt {
+                self.abort();
+            } else {
+                self.ack_due = true;
+            }
+            return;
+        }
+        if flags.syn {
+            // A challenge ACK, in case the peer's restarted (RFC 5961 §4.2)
+            self.ack_due = true;
+            return;
+        }
+        // Echoed from now on, unless it's from past what we've acknowledged
+        if self.timestamps
+            && let Some((value, _)) = timestamp(segment)
+            && seq_le(segment.sequence, self.rcv_nxt)
+        {
+            self.ts_recent = value;
+        }
+        let Some(ack) = segment.acknowledgement else {
+            return;
+        };
+
+        if self.state == TcpState::SynReceived {
+            if !seq_lt(self.snd_una, ack) || seq_lt(self.snd_nxt, ack) {
+                out.push(self.reset(ack));
+                return;
+            }
+            self.state = TcpState::Established;
+            self.snd_una = self.iss.wrapping_add(1);
+            self.acknowledged(now);
+        }
+        if seq_lt(self.snd_nxt, ack) {
+            // Acknowledges something we haven't sent
+            self.ack_due = true;
+            return;
+        }
+        if seq_lt(self.snd_una, ack) {
+            self.acknowledge(ack, now);
+        }
+        self.update_window(segment, ack);
+        // However shut its window, the peer's still there
+        if self.persist_at.is_some() {
+            self.retries = 0;
+        }
+        if self.fin_sent && self.snd_una == self.snd_nxt {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => self.state = TcpState::Closed,
+                _ => {}
+            }
+        }
+        self.receive(segment, now);
+    }
+
+    /// Take data and a FIN from an acceptable segment
+    fn receive(&mut self, segment: &TcpSegment, now: Instant) {
+        if !matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) {
+            return;
+        }
+        // Skip anything we already have
+        let mut data = segment.data.as_slice();
+        let mut sequence = segment.sequence;
+        if seq_lt(sequence, self.rcv_nxt) {
+            let old = self.rcv_nxt.wrapping_sub(sequence) as usize;
+            if old > data.len() {
+                return;
+            }
+            data = &data[old..];
+            sequence = self.rcv_nxt;
+        }
+        if sequence != self.rcv_nxt {
+            // Keep it for when the gap's filled, and acknowledge what we do
+            // have, so the peer knows what's missing
+            self.queue_out_of_order(sequence, data);
+            self.ack_due = true;
+            return;
+        }
+        let length = data.len().min(self.receive_window() as usize);
+        self.recv_buffer.extend(&data[..length]);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(length as u32);
+        if !data.is_empty() {
+            // Filling a gap is acknowledged at once, so the peer knows
+            if self.out_of_order.is_empty() {
+                self.delay_ack(now);
+            } else {
+                self.ack_due = true;
+                self.fill_gap();
+            }
+        }
+        if segment.flags.fin && length == data.len() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_due = true;
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+    }
+
+    /// Acknowledge a segment of data once there's a reply to carry it, or
+    /// another segment, or the delay's up (RFC 9293 §3.8.6.3)
+    fn delay_ack(&mut self, now: Instant) {
+        self.unacknowledged += 1;
+        if self.config.ack_delay.is_zero() || self.unacknowledged >= 2 {
+            self.ack_due = true;
+        } else if self.ack_at.is_none() {
+            self.ack_at = Some(now + self.config.ack_delay);
+        }
+    }
+
+    /// Keep `data`, starting at `sequence` past a gap, merging it with any
+    /// blocks it touches; what's beyond the window's dropped
+    fn queue_out_of_order(&mut self, sequence: u32, data: &[u8]) {
+        let offset = sequence.wrapping_sub(self.rcv_nxt) as usize;
+        let window = self.receive_window() as usize;
+        if data.is_empty() || offset >= window {
+            return;
+        }
+        let data = &data[..data.len().min(window - offset)];
+        self.latest_out_of_order = sequence;
+
+        // Offsets from `rcv_nxt` of the new block, as it grows
+        let (mut start, mut block) = (offset, data.to_vec());
+        let mut kept = Vec::new();
+        for (sequence, other) in self.out_of_order.drain(..) {
+            let other_start = sequence.wrapping_sub(self.rcv_nxt) as usize;
+            let (end, other_end) = (start + block.len(), other_start + other.len());
+            if other_end < start || other_start > end {
+                kept.push((sequence, other));
+                continue;
+            }
+            let joined_start = start.min(other_start);
+            let mut joined = vec![0; end.max(other_end) - joined_start];
+            joined[other_start - joined_start..other_end - joined_start].copy_from_slice(&other);
+            joined[start - joined_start..end - joined_start].copy_from_slice(&block);
+            (start, block) = (joined_start, joined);
+        }
+        kept.push((self.rcv_nxt.wrapping_add(start as u32), block));
+        kept.sort_by_key(|(sequence, _)| sequence.wrapping_sub(self.rcv_nxt));
+        self.out_of_order = kept;
+    }
+
+    /// Move blocks from `out_of_order` that now follow on from `rcv_nxt`
+    /// into the receive buffer
+    fn fill_gap(&mut self) {
+        while let Some((sequence, _)) = self.out_of_order.first()
+            && seq_le(*sequence, self.rcv_nxt)
+        {
+            let (sequence, block) = self.out_of_order.remove(0);
+            let old = self.rcv_nxt.wrapping_sub(sequence) as usize;
+            if let Some(new) = block.get(old..) {
+                self.recv_buffer.extend(new);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(new.len() as u32);
+            }
+        }
+    }
+
+    /// Blocks received past a gap, the latest first, as a SACK option
+    fn sack(&self) -> Option<TcpOption> {
+        if !self.sack_permitted || self.out_of_order.is_empty() {
+            return None;
+        }
+        let edges = |(sequence, block): &(u32, Vec<u8>)| {
+            (*sequence, sequence.wrapping_add(block.len() as u32))
+        };
+        let latest = self
+            .out_of_order
+            .iter()
+            .map(edges)
+            .position(|(left, right)| {
+                seq_le(left, self.latest_out_of_order) && seq_lt(self.latest_out_of_order, right)
+            });
+        let mut blocks: Vec<_> = self.out_of_order.iter().map(edges).collect();
+        if let Some(latest) = latest {
+            blocks[..=latest].rotate_right(1);
+        }
+        // Timestamps leave room for one block less
+        blocks.truncate(MAX_SACK_BLOCKS - usize::from(self.timestamps));
+        Some(TcpOption::Sack(blocks))
+    }
+
+    /// Take the peer's window from `segment`, unless it's older than the one
+    /// we have (RFC 9293 §3.10.7.4)
+    fn update_window(&mut self, segment: &TcpSegment, ack: u32) {
+        let newer = seq_lt(self.snd_wl1, segment.sequence)
+            || (self.snd_wl1 == segment.sequence && seq_le(self.snd_wl2, ack));
+        if seq_le(self.snd_una, ack) && newer {
+            self.snd_wnd = u32::from(segment.window) << self.snd_scale;
+            self.snd_wl1 = segment.sequence;
+            self.snd_wl2 = ack;
+        }
+    }
+
+    /// Drop data the peer's acknowledged, up to `ack`
+    fn acknowledge(&mut self, ack: u32, now: Instant) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        let data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.snd_una = ack;
+        self.acknowledged(now);
+    }
+
+    /// Take a round-trip sample and restart the retransmission timer, for
+    /// `snd_una` having moved on
+    fn acknowledged(&mut self, now: Instant) {
+        if let Some((end, sent)) = self.timing
+            && seq_le(end, self.snd_una)
+        {
+            self.rtt.sample(now - sent);
+            self.timing = None;
+        }
+        self.retries = 0;
+        self.retransmit_at = (self.snd_una != self.snd_nxt).then(|| now + self.rtt.rto());
+    }
+
+    /// Note a segment ending before `end` has been sent, starting the
+    /// retransmission timer, and timing it if it's `fresh` rather than resent
+    fn sent(&mut self, end: u32, fresh: bool, now: Instant) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rtt.rto());
+        }
+        if fresh && self.timing.is_none() {
+            self.timing = Some((end, now));
+        }
+    }
+
+    /// Resend the oldest unacknowledged segment, having waited long enough
+    /// for it to be acknowledged, or give up and reset the connection
+    fn retransmit(&mut self, now: Instant, out: &mut Vec<TcpSegment>) {
+        if self.retries >= self.config.max_retries {
+            if self.state != TcpState::SynSent {
+                out.push(self.reset(self.snd_nxt));
+            }
+            self.abort();
+            return;
+        }
+        self.retries += 1;
+        self.rtt.back_off();
+        // Karn's algorithm: an acknowledgement could be for either copy
+        self.timing = None;
+        self.retransmit_at = Some(now + self.rtt.rto());
+        match self.state {
+            // Sent again as usual
+            TcpState::SynSent | TcpState::SynReceived => self.snd_nxt = self.iss,
+            _ => {
+                let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let data = sent.min(self.send_buffer.len());
+                let mut segment = self.segment(self.snd_una);
+                if data > 0 {
+                    let length = data.min(self.send_mss());
+                    segment.data = self.send_buffer.range(..length).copied().collect();
+                } else if self.fin_sent {
+                    segment.flags.fin = true;
+                } else {
+                    return;
+                }
+                self.ack_due = false;
+                out.push(segment);
+            }
+        }
+    }
+
+    /// Send a byte past the peer's shut window, so its answer says whether
+    /// the window's reopened, in case the update saying so was lost (RFC 9293
+    /// §3.8.6.1), or give up if it's stopped answering at all
+    ///
+    /// The byte counts as sent, so if the window was open after all, the
+    /// acknowledgement for it is taken.
+    fn probe(&mut self, now: Instant, out: &mut Vec<TcpSegment>) {
+        let Some(&byte) = self.send_buffer.front() else {
+            self.persist_at = None;
+            return;
+        };
+        if self.retries >= self.config.max_retries {
+            out.push(self.reset(self.snd_nxt));
+            self.abort();
+            return;
+        }
+        self.retries += 1;
+        self.probes += 1;
+        self.persist_at = Some(now + self.persist_interval());
+        let mut probe = self.segment(self.snd_una);
+        probe.data = vec![byte];
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.ack_due = false;
+        out.push(probe);
+    }
+
+    /// How long to wait before the next window probe: the retransmission
+    /// timeout, doubled for each probe so far, up to its maximum
+    fn persist_interval(&self) -> Duration {
+        let backoff = 1 << self.probes.min(16);
+        self.rtt.rto().saturating_mul(backoff).min(rtt::MAX_RTO)
+    }
+
+    /// Whether any of `segment` falls in our receive window (RFC 9293
+    /// §3.10.7.4)
+    fn is_acceptable(&self, segment: &TcpSegment) -> bool {
+        let length = segment.sequence_length();
+        let window = self.receive_window();
+        let end = self.rcv_nxt.wrapping_add(window);
+        let in_window = |sequence| seq_le(self.rcv_nxt, sequence) && seq_lt(sequence, end);
+        match (length, window) {
+            (0, 0) => segment.sequence == self.rcv_nxt,
+            (0, _) => in_window(segment.sequence),
+            (_, 0) => false,
+            _ => {
+                in_window(segment.sequence) || in_window(segment.sequence.wrapping_add(length - 1))
+            }
+        }
+    }
+
+    /// Room left for received data, or what we've already advertised if
+    /// that's more, since a window mustn't shrink
+    fn receive_window(&self) -> u32 {
+        let room = self.recv_capacity.saturating_sub(self.recv_buffer.len());
+        let room = u32::try_from(room).unwrap_or(u32::MAX);
+        room.max(self.advertised_window())
+    }
+
+    /// What's left of the window we last advertised
+    fn advertised_window(&self) -> u32 {
+        match seq_lt(self.rcv_nxt, self.rcv_adv) {
+            true => self.rcv_adv.wrapping_sub(self.rcv_nxt),
+            false => 0,
+        }
+    }
+
+    /// The window to advertise next: it only grows once it can by a
+    /// segment, or half the buffer if that's less, so the peer isn't invited
+    /// to send in dribs (RFC 9293 §3.8.6.2.2)
+    fn offered_window(&self) -> u32 {
+        let most = u32::from(u16::MAX) << self.rcv_scale;
+        let (window, advertised) = (self.receive_window().min(most), self.advertised_window());
+        let step = (self.recv_capacity / 2)
+            .min(self.local_mss.into())
+            .max(1 << self.rcv_scale);
+        match window.saturating_sub(advertised) >= u32::try_from(step).unwrap_or(u32::MAX) {
+            true => window,
+            false => advertised,
+        }
+    }
+
+    /// Put the window in a segment's field, shifted by `scale`, noting how
+    /// far it reaches
+    fn advertise(&mut self, window: u32, scale: u8) -> u16 {
+        let field = u16::try_from(window >> scale).unwrap_or(u16::MAX);
+        self.rcv_adv = self.rcv_nxt.wrapping_add(u32::from(field) << scale);
+        field
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        if self.config.time_wait.is_zero() {
+            self.state = TcpState::Closed;
+            return;
+        }
+        self.state = TcpState::TimeWait;
+        self.time_wait_until = Some(now + self.config.time_wait);
+    }
+
+    /// Most data a segment can carry, after its options
+    fn send_mss(&self) -> usize {
+        let options = if self.timestamps {
+            TIMESTAMPS_LENGTH
+        } else {
+            0
+        };
+        self.mss.saturating_sub(options).max(1).into()
+    }
+
+    fn abort(&mut self) {
+        self.state = TcpState::Closed;
+        self.reset = true;
+        self.time_wait_until = None;
+        self.retransmit_at = None;
+        self.persist_at = None;
+    }
+
+    /// A segment from us at `sequence`, acknowledging what we've received
+    fn segment(&mut self, sequence: u32) -> TcpSegment {
+        self.ack_at = None;
+        self.unacknowledged = 0;
+        let timestamps = self.timestamps.then_some(TcpOption::Timestamps {
+            value: self.ts_clock,
+            echo_reply: self.ts_recent,
+        });
+        TcpSegment {
+            acknowledgement: Some(self.rcv_nxt),
+            window: self.advertise(self.offered_window(), self.rcv_scale),
+            options: timestamps.into_iter().chain(self.sack()).collect(),
+            ..TcpSegment::new(self.local_port, self.remote_port, sequence)
+        }
+    }
+
+    /// A reset at `sequence`
+    fn reset(&self, sequence: u32) -> TcpSegment {
+        let mut reset = TcpSegment::new(self.local_port, self.remote_port, sequence);
+        reset.flags.rst = true;
+        reset
+    }
+
+    /// Send our SYN, data, FIN, and an ACK, whichever are due
+    fn transmit(&mut self, now: Instant, out: &mut Vec<TcpSegment>) {
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived if self.snd_nxt == self.iss => {
+                let mut syn = self.segment(self.iss);
+                if self.state == TcpState::SynSent {
+                    syn.acknowledgement = None;
+                }
+                syn.flags.syn = true;
+                syn.options = vec![TcpOption::MaxSegmentSize(self.local_mss)];
+                // Offered when connecting, agreed to if it was offered
+                if self.state == TcpState::SynSent || self.sack_permitted {
+                    syn.options.push(TcpOption::SackPermitted);
+                }
+                if self.state == TcpState::SynSent || self.window_scaling {
+                    self.rcv_scale = window_scale(self.recv_capacity);
+                    syn.options.push(TcpOption::WindowScale(self.rcv_scale));
+                }
+                self.timestamps &= self.config.timestamps;
+                if (self.state == TcpState::SynSent && self.config.timestamps) || self.timestamps {
+                    syn.options.push(TcpOption::Timestamps {
+                        value: self.ts_clock,
+                        echo_reply: self.ts_recent,
+                    });
+                }
+                if self.config.fast_open {
+                    self.fast_open_syn(&mut syn);
+                }
+                let window = self.receive_window();
+                syn.window = self.advertise(window, 0);
+                let length = syn.data.len() as u32;
+                self.snd_nxt = self.iss.wrapping_add(1 + length);
+                self.ack_due = false;
+                self.sent(self.snd_nxt, self.retries == 0, now);
+                out.push(syn);
+                return;
+            }
+            TcpState::Established | TcpState::CloseWait if !self.fin_sent => {
+                self.transmit_data(now, out);
+            }
+            TcpState::Closed | TcpState::SynSent => return,
+            _ => {}
+        }
+        // Let the peer know there's room again, once the application's read
+        // enough
+        if matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) && self.offered_window() != self.advertised_window()
+        {
+            self.ack_due = true;
+        }
+        if self.ack_due {
+            self.ack_due = false;
+            out.push(self.segment(self.snd_nxt));
+        }
+    }
+
+    /// Put Fast Open's part in our SYN: connecting, our cookie and what data
+    /// fits, or else a request for a cookie; accepting, a cookie if the
+    /// client needs one
+    fn fast_open_syn(&self, syn: &mut TcpSegment) {
+        let cookie = self.fast_open_cookie.clone();
+        if self.state == TcpState::SynReceived {
+            syn.options.extend(cookie.map(TcpOption::FastOpen));
+            return;
+        }
+        let cookie = cookie.unwrap_or_default();
+        // A SYN that's resent goes without data, in case that's why it
+        // wasn't answered
+        if !cookie.is_empty() && self.retries == 0 {
+            let length = self.send_buffer.len().min(self.mss.into());
+            syn.data = self.send_buffer.range(..length).copied().collect();
+        }
+        syn.options.push(TcpOption::FastOpen(cookie));
+    }
+
+    /// Send queued data the peer has room for, then a FIN if we're closing
+    fn transmit_data(&mut self, now: Instant, out: &mut Vec<TcpSegment>) {
+        if self.snd_wnd != 0 && self.persist_at.is_some() {
+            // Reopened; a probe it didn't take is sent again as usual
+            self.persist_at = None;
+            self.probes = 0;
+            self.retries = 0;
+            self.snd_nxt = self.snd_una;
+        }
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send_buffer.len() - sent;
+            let window_end = self.snd_una.wrapping_add(self.snd_wnd);
+            let room = match seq_lt(self.snd_nxt, window_end) {
+                true => window_end.wrapping_sub(self.snd_nxt) as usize,
+                false => 0,
+            };
+            let length = unsent.min(room).min(self.send_mss());
+            // Nagle's algorithm: one small segment at a time, unless what's
+            // left is all that's coming before a FIN
+            let in_flight = self.snd_nxt != self.snd_una;
+            let small = length < self.send_mss();
+            if length == 0 || (small && in_flight && !self.config.nodelay && !self.closing) {
+                break;
+            }
+            let mut segment = self.segment(self.snd_nxt);
+            segment.data = self
+                .send_buffer
+                .range(sent..sent + length)
+                .copied()
+                .collect();
+            segment.flags.psh = length == unsent;
+            self.snd_nxt = self.snd_nxt.wrapping_add(length as u32);
+            self.ack_due = false;
+            self.sent(self.snd_nxt, true, now);
+            out.push(segment);
+        }
+
+        let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        // Shut on data we have waiting, and nothing's in flight whose
+        // acknowledgement would say when it reopens
+        if self.snd_wnd == 0
+            && sent == 0
+            && !self.send_buffer.is_empty()
+            && self.persist_at.is_none()
+        {
+            self.persist_at = Some(now + self.persist_interval());
+        }
+        if self.closing && sent == self.send_buffer.len() {
+            let mut fin = self.segment(self.snd_nxt);
+            fin.flags.fin = true;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.ack_due = false;
+            self.sent(self.snd_nxt, true, now);
+            self.state = match self.state {
+                TcpState::CloseWait => TcpState::LastAck,
+                _ => TcpState::FinWait1,
+            };
+            out.push(fin);
+        }
+    }
+}
+
+/// The reset a port with no connection answers `segment` with, unless it's
+/// a reset itself (RFC 9293 §3.10.7.1)
+pub fn refusal(segment: &TcpSegment) -> Option<TcpSegment> {
+    if segment.flags.rst {
+        return None;
+    }
+    let mut reset = TcpSegment::new(segment.destination_port, segment.source_port, 0);
+    reset.flags.rst = true;
+    match segment.acknowledgement {
+        Some(ack) => reset.sequence = ack,
+        None => {
+            let end = segment.sequence.wrapping_add(segment.sequence_length());
+            reset.acknowledgement = Some(end);
+        }
+    }
+    Some(reset)
+}
+
+/// The timestamp and echoed timestamp `segment` carries, if it does
+fn timestamp(segment: &TcpSegment) -> Option<(u32, u32)> {
+    segment.options.iter().find_map(|option| match *option {
+        TcpOption::Timestamps { value, echo_reply } => Some((value, echo_reply)),
+        _ => None,
+    })
+}
+
+/// Smallest window scale that lets `capacity` be advertised in full
+fn window_scale(capacity: usize) -> u8 {
+    let mut scale = 0;
+    while capacity >> scale > usize::from(u16::MAX) && scale < MAX_WINDOW_SCALE {
+        scale += 1;
+    }
+    scale
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrapping
+const fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+const fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtt;
+
+    /// Pass segments between two ends until they've nothing more to say
+    fn exchange(a: &mut TcpConnection, b: &mut TcpConnection, now: Instant) {
+        let mut to_b = a.poll(now);
+        let mut to_a = b.poll(now);
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let from_a: Vec<_> = to_b.iter().flat_map(|s| b.handle(s, now)).collect();
+            let from_b: Vec<_> = to_a.iter().flat_map(|s| a.handle(s, now)).collect();
+            to_a = from_a;
+            to_b = from_b;
+        }
+    }
+
+    /// Sends every segment at once, and acknowledges each, so they can be
+    /// followed one by one
+    const EAGER: TcpConfig = TcpConfig::new()
+        .set_nodelay(true)
+        .set_ack_delay(Duration::ZERO);
+
+    fn connected(now: Instant) -> (TcpConnection, TcpConnection) {
+        let mut client = TcpConnection::connect(49152, 80, u32::MAX - 10, 1460).set_config(EAGER);
+        let syn = client.poll(now);
+        assert!(syn[0].flags.syn && syn[0].acknowledgement.is_none());
+        let mut server = TcpConnection::accept(&syn[0], 1000, 1460).set_config(EAGER);
+        exchange(&mut client, &mut server, now);
+        (client, server)
+    }
+
+    #[test]
+    fn handshake() {
+        let now = Instant::now();
+        let (client, server) = connected(now);
+        assert_eq!(client.state(), TcpState::Established);
+        assert_eq!(server.state(), TcpState::Established);
+        assert_eq!((server.local_port(), server.remote_port()), (80, 49152));
+        assert_eq!(client.mss, 1460);
+    }
+
+    #[test]
+    fn transfers_data() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        // Across the wrap in sequence numbers, in several segments
+        let request = vec![7; 4000];
+        assert_eq!(client.send(&request), 4000);
+        let segments = client.poll(now);
+        assert_eq!(segments.len(), 3);
+        assert!(segments[2].flags.psh);
+        for segment in &segments {
+            client.handle(&server.handle(segment, now)[0], now);
+        }
+        assert!(client.send_buffer.is_empty());
+
+        let mut buf = vec![0; 5000];
+        assert_eq!(server.recv(&mut buf), 4000);
+        assert_eq!(buf[..4000], request);
+        assert_eq!(server.send(b"reply"), 5);
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"reply");
+    }
+
+    #[test]
+    fn closes() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        client.send(b"bye");
+        client.close();
+        assert!(!client.may_send());
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), TcpState::FinWait2);
+        assert_eq!(server.state(), TcpState::CloseWait);
+        assert!(!server.is_finished());
+        server.recv(&mut [0; 3]);
+        assert!(server.is_finished());
+
+        server.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(server.state(), TcpState::Closed);
+        assert_eq!(client.state(), TcpState::TimeWait);
+        let deadline = client.next_deadline().unwrap();
+        assert!(client.poll(deadline).is_empty());
+        assert_eq!(client.state(), TcpState::Closed);
+        assert!(!client.is_reset());
+    }
+
+    #[test]
+    fn time_wait() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        client.config = EAGER.set_time_wait(Duration::from_secs(5));
+        client.close();
+        exchange(&mut client, &mut server, now);
+        server.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), TcpState::TimeWait);
+        assert_eq!(client.next_deadline(), Some(now + Duration::from_secs(5)));
+
+        // Or none at all
+        let (mut client, mut server) = connected(now);
+        client.config = EAGER.set_time_wait(Duration::ZERO);
+        client.close();
+        exchange(&mut client, &mut server, now);
+        server.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), TcpState::Closed);
+        assert!(!client.is_reset());
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = Instant::now();
+        let config = EAGER.set_timestamps(true);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        client.set_timestamp_epoch(now - Duration::from_secs(1));
+        let syn = client.poll(now).remove(0);
+        assert!(syn.options.contains(&TcpOption::Timestamps {
+            value: 1000,
+            echo_reply: 0
+        }));
+        let mut server = TcpConnection::accept(&syn, 0, 1460).set_config(config);
+        exchange(&mut client, &mut server, now);
+        assert!(client.timestamps && server.timestamps);
+
+        // Each end echoes the other's, and fits less data in a segment
+        let later = now + Duration::from_millis(5);
+        client.send(&[1; 2000]);
+        let sent = client.poll(later);
+        assert_eq!(sent[0].data.len(), 1448);
+        assert_eq!(timestamp(&sent[0]), Some((1005, server.ts_clock)));
+        let ack = server.handle(&sent[0], later).remove(0);
+        assert_eq!(timestamp(&ack).map(|(_, echo)| echo), Some(1005));
+
+        // Anything older than the latest is dropped, and acknowledged
+        let mut stale = sent[1].clone();
+        stale.options = vec![TcpOption::Timestamps {
+            value: 999,
+            echo_reply: 0,
+        }];
+        let replies = server.handle(&stale, later);
+        assert_eq!(replies[0].acknowledgement, ack.acknowledgement);
+        assert_eq!(server.recv(&mut [0; 2000]), 1448);
+
+        // Not used unless both ends want them
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        let syn = client.poll(now).remove(0);
+        let mut server = TcpConnection::accept(&syn, 0, 1460).set_config(EAGER);
+        exchange(&mut client, &mut server, now);
+        assert!(!client.timestamps && !server.timestamps);
+    }
+
+    #[test]
+    fn time_wait_reuse() {
+        let now = Instant::now();
+        let config = EAGER.set_timestamps(true).set_time_wait_reuse(true);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        client.set_timestamp_epoch(now);
+        let syn = client.poll(now).remove(0);
+        let mut server = TcpConnection::accept(&syn, 0, 1460).set_config(config);
+        exchange(&mut client, &mut server, now);
+        server.close();
+        exchange(&mut client, &mut server, now);
+        client.close();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(server.state(), TcpState::TimeWait);
+
+        // Only after a moment may we reuse the ports ourselves
+        assert!(!server.is_reusable(now));
+        assert!(server.is_reusable(now + TIME_WAIT_REUSE_AFTER));
+
+        // And only to a SYN newer than what came before
+        let later = now + Duration::from_millis(10);
+        let mut again = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        again.set_timestamp_epoch(now);
+        let syn = again.poll(later).remove(0);
+        assert!(server.is_reusable_by(&syn));
+        let mut untimed = syn.clone();
+        untimed
+            .options
+            .retain(|option| !matches!(option, TcpOption::Timestamps { .. }));
+        assert!(!server.is_reusable_by(&untimed));
+        let old = TcpConnection::connect(49152, 80, 0, 1460)
+            .set_config(config)
+            .poll(now)
+            .remove(0);
+        assert!(!server.is_reusable_by(&old));
+
+        // Nor unless it's been allowed
+        server.config = config.set_time_wait_reuse(false);
+        assert!(!server.is_reusable_by(&syn));
+        assert!(!server.is_reusable(now + TIME_WAIT_REUSE_AFTER));
+    }
+
+    #[test]
+    fn retransmits() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        client.send(&[1; 3000]);
+        let sent = client.poll(now);
+        assert_eq!(sent.len(), 3);
+        let deadline = client.next_deadline().unwrap();
+        assert_eq!(deadline, now + client.rtt().rto());
+
+        // The first segment's lost, so only it's sent again, later each time
+        let acks = server.handle(&sent[1], now);
+        assert!(client.handle(&acks[0], now).is_empty());
+        assert!(client.poll(deadline - Duration::from_millis(1)).is_empty());
+        let resent = client.poll(deadline);
+        assert_eq!(resent, [sent[0].clone()]);
+        let next = client.next_deadline().unwrap();
+        assert_eq!(next - deadline, rtt::INITIAL_RTO * 2);
+
+        // Once it's through, the timer's stopped
+        let later = next - Duration::from_millis(1);
+        for segment in resent.iter().chain(&sent[1..]) {
+            for ack in server.handle(segment, later) {
+                client.handle(&ack, later);
+            }
+        }
+        assert_eq!(client.next_deadline(), None);
+        assert_eq!(server.recv(&mut [0; 4000]), 3000);
+    }
+
+    #[test]
+    fn gives_up() {
+        let now = Instant::now();
+        let config = TcpConfig::new().set_max_retries(2);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        let syn = client.poll(now);
+        let mut sent = Vec::new();
+        while let Some(deadline) = client.next_deadline() {
+            sent.extend(client.poll(deadline));
+        }
+        // Never answered: two more tries, then it's over
+        assert_eq!(sent, [syn[0].clone(), syn[0].clone()]);
+        assert!(client.is_reset());
+
+        let (mut client, _) = connected(now);
+        client.config = config;
+        client.send(b"anyone?");
+        let mut sent = client.poll(now);
+        while let Some(deadline) = client.next_deadline() {
+            sent.extend(client.poll(deadline));
+        }
+        assert_eq!(sent.len(), 4);
+        assert!(sent[3].flags.rst);
+        assert_eq!(client.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn reorders() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        assert!(client.sack_permitted && server.sack_permitted);
+        client.send(&(0..=255).cycle().take(4000).collect::<Vec<u8>>());
+        let sent = client.poll(now);
+        let edges = |segment: &TcpSegment| {
+            let start = segment.sequence;
+            (start, start.wrapping_add(segment.data.len() as u32))
+        };
+
+        // Duplicate ACKs, saying what's arrived past the gap
+        let ack = server.handle(&sent[2], now).remove(0);
+        assert_eq!(ack.acknowledgement, Some(sent[0].sequence));
+        assert_eq!(ack.options, [TcpOption::Sack(vec![edges(&sent[2])])]);
+        let ack = server.handle(&sent[1], now).remove(0);
+        assert_eq!(ack.acknowledgement, Some(sent[0].sequence));
+        let merged = (edges(&sent[1]).0, edges(&sent[2]).1);
+        assert_eq!(ack.options, [TcpOption::Sack(vec![merged])]);
+
+        // Filling the gap delivers the lot
+        let ack = server.handle(&sent[0], now).remove(0);
+        assert_eq!(ack.acknowledgement, Some(client.snd_nxt));
+        assert!(ack.options.is_empty());
+        let mut buf = vec![0; 5000];
+        assert_eq!(server.recv(&mut buf), 4000);
+        assert!(
+            buf[..4000]
+                .iter()
+                .zip((0..=255).cycle())
+                .all(|(a, b)| *a == b)
+        );
+    }
+
+    #[test]
+    fn sack_blocks() {
+        let now = Instant::now();
+        let (_, mut server) = connected(now);
+        let start = server.rcv_nxt;
+        let mut blocks = Vec::new();
+        for offset in [100, 300, 500, 700, 900, 200] {
+            let mut segment = TcpSegment::new(49152, 80, start.wrapping_add(offset));
+            segment.acknowledgement = Some(server.snd_nxt);
+            segment.data = vec![0; 50];
+            blocks = server.handle(&segment, now).remove(0).options;
+        }
+        // The latest first, then the rest in order, as many as fit
+        let block = |offset: u32| (start.wrapping_add(offset), start.wrapping_add(offset + 50));
+        assert_eq!(
+            blocks,
+            [TcpOption::Sack(vec![
+                block(200),
+                block(100),
+                block(300),
+                block(500)
+            ])]
+        );
+
+        // Without SACK, just duplicate ACKs
+        let mut syn = TcpSegment::new(49152, 80, 0);
+        syn.flags.syn = true;
+        let mut server = TcpConnection::accept(&syn, 0, 1460);
+        assert_eq!(
+            server.poll(now)[0].options,
+            [TcpOption::MaxSegmentSize(1460)]
+        );
+        let mut ack = TcpSegment::new(49152, 80, 1);
+        ack.acknowledgement = Some(1);
+        server.handle(&ack, now);
+        let mut segment = TcpSegment::new(49152, 80, 100);
+        segment.acknowledgement = Some(1);
+        segment.data = vec![0; 50];
+        let dup = server.handle(&segment, now).remove(0);
+        assert_eq!((dup.acknowledgement, dup.options), (Some(1), Vec::new()));
+    }
+
+    #[test]
+    fn scales_windows() {
+        let now = Instant::now();
+        let config = TcpConfig::new().set_recv_buffer(1 << 20);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        let syn = client.poll(now).remove(0);
+        assert!(syn.options.contains(&TcpOption::WindowScale(5)));
+        assert_eq!(syn.window, u16::MAX);
+        let mut server = TcpConnection::accept(&syn, 1000, 1460);
+        exchange(&mut client, &mut server, now);
+        // The default buffer needs a shift of one
+        assert_eq!((server.snd_scale, server.rcv_scale), (5, 1));
+        assert_eq!(server.snd_wnd, 1 << 20);
+        assert_eq!(client.snd_wnd, u32::from(u16::MAX));
+
+        // Unless both SYNs have the option, neither side scales
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460).set_config(config);
+        let mut syn = client.poll(now).remove(0);
+        syn.options
+            .retain(|option| !matches!(option, TcpOption::WindowScale(_)));
+        let mut server = TcpConnection::accept(&syn, 1000, 1460);
+        let syn_ack = server.poll(now).remove(0);
+        assert!(!syn_ack.options.contains(&TcpOption::WindowScale(1)));
+        for ack in client.handle(&syn_ack, now) {
+            assert!(server.handle(&ack, now).is_empty());
+        }
+        assert_eq!(client.rcv_scale, 0);
+        assert_eq!(server.snd_wnd, u32::from(u16::MAX));
+        assert!(client.poll(now).is_empty());
+    }
+
+    #[test]
+    fn flow_control() {
+        let now = Instant::now();
+        let config = EAGER.set_recv_buffer(3000);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1000).set_config(EAGER);
+        let syn = client.poll(now).remove(0);
+        let mut server = TcpConnection::accept(&syn, 1000, 1000).set_config(config);
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.snd_wnd, 3000);
+
+        // Only what the server has room for is sent
+        assert_eq!(client.send(&[1; 5000]), 5000);
+        let sent = client.poll(now);
+        assert_eq!(sent.iter().map(|s| s.data.len()).sum::<usize>(), 3000);
+        for segment in &sent {
+            for ack in server.handle(segment, now) {
+                assert!(client.handle(&ack, now).is_empty());
+            }
+        }
+        assert_eq!(client.snd_wnd, 0);
+        assert!(client.poll(now).is_empty());
+
+        // Reading a little doesn't reopen the window; reading a segment's
+        // worth does
+        server.recv(&mut [0; 100]);
+        assert!(server.poll(now).is_empty());
+        server.recv(&mut [0; 1000]);
+        let update = server.poll(now).remove(0);
+        assert_eq!(update.window, 1100);
+        let sent = client.handle(&update, now);
+        let lengths: Vec<_> = sent.iter().map(|s| s.data.len()).collect();
+        assert_eq!(lengths, [1000, 100]);
+    }
+
+    #[test]
+    fn persists() {
+        let now = Instant::now();
+        let config = EAGER.set_recv_buffer(1000).set_max_retries(3);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1000).set_config(config);
+        let syn = client.poll(now).remove(0);
+        let mut server = TcpConnection::accept(&syn, 1000, 1000).set_config(config);
+        exchange(&mut client, &mut server, now);
+
+        // The server's application is stuck, so its window shuts
+        client.send(&[1; 1500]);
+        for segment in client.poll(now) {
+            for ack in server.handle(&segment, now) {
+                client.handle(&ack, now);
+            }
+        }
+        assert_eq!(client.snd_wnd, 0);
+
+        // Probed, later each time, and never given up on while it answers
+        let mut at = now;
+        let mut intervals = Vec::new();
+        for _ in 0..5 {
+            let deadline = client.next_deadline().unwrap();
+            intervals.push(deadline - at);
+            at = deadline;
+            let probe = client.poll(at);
+            assert_eq!(probe.len(), 1);
+            assert_eq!(probe[0].data, [1]);
+            let answer = server.handle(&probe[0], at);
+            assert_eq!(answer[0].window, 0);
+            assert!(client.handle(&answer[0], at).is_empty());
+        }
+        let rto = rtt::INITIAL_RTO;
+        assert_eq!(intervals, [rto, rto * 2, rto * 4, rto * 8, rto * 16]);
+        assert_eq!(client.state(), TcpState::Established);
+
+        // It reads, but the update saying so is lost; the next probe finds
+        // the window open, and the rest follows
+        server.recv(&mut [0; 1000]);
+        assert_eq!(server.poll(at).len(), 1);
+        let at = client.next_deadline().unwrap();
+        let probe = client.poll(at).remove(0);
+        let answer = server.handle(&probe, at).remove(0);
+        assert_eq!(answer.acknowledgement, Some(probe.sequence + 1));
+        let rest = client.handle(&answer, at);
+        assert_eq!(rest.iter().map(|s| s.data.len()).sum::<usize>(), 499);
+        for segment in &rest {
+            server.handle(segment, at);
+        }
+        assert_eq!(server.recv(&mut [0; 1000]), 500);
+        assert_eq!(client.persist_at, None);
+    }
+
+    #[test]
+    fn gives_up_probing() {
+        let now = Instant::now();
+        let config = EAGER.set_recv_buffer(1000).set_max_retries(2);
+        let mut client = TcpConnection::connect(49152, 80, 0, 1000).set_config(config);
+        let syn = client.poll(now).remove(0);
+        let mut server = TcpConnection::accept(&syn, 1000, 1000).set_config(config);
+        exchange(&mut client, &mut server, now);
+        client.send(&[1; 1500]);
+        for segment in client.poll(now) {
+            for ack in server.handle(&segment, now) {
+                client.handle(&ack, now);
+            }
+        }
+
+        // The server's gone: two probes, then it's over
+        let mut sent = Vec::new();
+        while let Some(deadline) = client.next_deadline() {
+            sent.extend(client.poll(deadline));
+        }
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].data, [1]);
+        assert_eq!(sent[1], sent[0]);
+        assert!(sent[2].flags.rst);
+        assert!(client.is_reset());
+    }
+
+    #[test]
+    fn nagle() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        client.set_nodelay(false);
+
+        // Keystrokes: the first goes at once, the rest wait for its ACK
+        client.send(b"l");
+        let first = client.poll(now);
+        assert_eq!(first.len(), 1);
+        client.send(b"s");
+        client.send(b" -l");
+        assert!(client.poll(now).is_empty());
+        let ack = server.handle(&first[0], now).remove(0);
+        let rest = client.handle(&ack, now);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].data, b"s -l");
+
+        // Full segments aren't held back, only the remainder
+        client.send(&[0; 2000]);
+        let sent = client.poll(now);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].data.len(), 1460);
+
+        // Without it, the remainder goes at once, as does what follows
+        client.set_nodelay(true);
+        assert_eq!(client.poll(now).len(), 1);
+        client.send(b"x");
+        assert_eq!(client.poll(now).len(), 1);
+    }
+
+    #[test]
+    fn delays_acks() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        server.set_ack_delay(DEFAULT_ACK_DELAY);
+        let segments: Vec<_> = (0..3)
+            .flat_map(|_| {
+                client.send(&[0; 100]);
+                client.poll(now)
+            })
+            .collect();
+
+        // Every second segment's acknowledged at once
+        assert!(server.handle(&segments[0], now).is_empty());
+        let ack = server.handle(&segments[1], now);
+        assert_eq!(ack[0].acknowledgement, Some(segments[2].sequence));
+        assert!(server.handle(&segments[2], now).is_empty());
+
+        // A reply carries the acknowledgement, and stops the timer
+        assert_eq!(server.next_deadline(), Some(now + DEFAULT_ACK_DELAY));
+        server.send(b"ok");
+        let reply = server.poll(now);
+        assert_eq!(reply[0].acknowledgement, Some(client.snd_nxt));
+        assert_eq!(server.next_deadline(), Some(now + server.rtt().rto()));
+
+        // Otherwise it's sent once the delay's up
+        for ack in client.handle(&reply[0], now) {
+            server.handle(&ack, now);
+        }
+        client.send(&[0; 100]);
+        let segment = client.poll(now).remove(0);
+        assert!(server.handle(&segment, now).is_empty());
+        let deadline = server.next_deadline().unwrap();
+        assert_eq!(deadline, now + DEFAULT_ACK_DELAY);
+        assert_eq!(server.poll(deadline).len(), 1);
+        assert_eq!(server.next_deadline(), None);
+    }
+
+    #[test]
+    fn fast_open() {
+        let now = Instant::now();
+        let config = EAGER.set_fast_open(true);
+        let cookie = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let open = |iss, cookie| {
+            let mut client = TcpConnection::connect(49152, 80, iss, 1460).set_config(config);
+            client.set_fast_open_cookie(cookie);
+            assert_eq!(client.send(b"hello"), 5);
+            let syn = client.poll(now).remove(0);
+            (client, syn)
+        };
+        let answer = |syn: &TcpSegment| {
+            let mut server = TcpConnection::accept(syn, 1000, 1460).set_config(config);
+            server.fast_open(syn, cookie.clone());
+            let syn_ack = server.poll(now).remove(0);
+            (server, syn_ack)
+        };
+
+        // Without a cookie, the SYN asks for one, and its data waits
+        let (mut client, syn) = open(0, None);
+        assert!(syn.data.is_empty());
+        assert!(syn.options.contains(&TcpOption::FastOpen(Vec::new())));
+        let (mut server, syn_ack) = answer(&syn);
+        assert!(!server.is_fast_open());
+        let ack = client.handle(&syn_ack, now);
+        assert_eq!(client.fast_open_cookie(), Some(cookie.as_slice()));
+        assert_eq!(ack[0].data, b"hello");
+        server.handle(&ack[0], now);
+        assert_eq!(server.recv(&mut [0; 8]), 5);
+
+        // With it, the data's in the SYN, and the server has it at once
+        let (mut client, syn) = open(5000, Some(cookie.clone()));
+        assert_eq!(syn.data, b"hello");
+        let (mut server, syn_ack) = answer(&syn);
+        assert!(server.is_fast_open());
+        let mut buf = [0; 8];
+        assert_eq!(server.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(syn_ack.acknowledgement, Some(5006));
+        assert!(
+            !syn_ack
+                .options
+                .iter()
+                .any(|option| matches!(option, TcpOption::FastOpen(_)))
+        );
+        let ack = client.handle(&syn_ack, now);
+        assert!(ack[0].data.is_empty());
+        assert!(client.send_buffer.is_empty());
+        server.handle(&ack[0], now);
+        assert_eq!(server.state(), TcpState::Established);
+
+        // A stale cookie: the server wants the data again, and says what
+        // the cookie is now
+        let (mut client, syn) = open(9000, Some(vec![9; 8]));
+        assert_eq!(syn.data, b"hello");
+        let (server, syn_ack) = answer(&syn);
+        assert!(!server.is_fast_open());
+        assert_eq!(syn_ack.acknowledgement, Some(9001));
+        let ack = client.handle(&syn_ack, now);
+        assert_eq!(ack[0].data, b"hello");
+        assert_eq!(client.fast_open_cookie(), Some(cookie.as_slice()));
+    }
+
+    #[test]
+    fn refuses() {
+        let mut syn = TcpSegment::new(49152, 80, 1000);
+        syn.flags.syn = true;
+        syn.data = vec![0; 10];
+        let reset = refusal(&syn).unwrap();
+        assert_eq!((reset.source_port, reset.destination_port), (80, 49152));
+        assert!(reset.flags.rst);
+        assert_eq!((reset.sequence, reset.acknowledgement), (0, Some(1011)));
+
+        let mut ack = TcpSegment::new(49152, 80, 1000);
+        ack.acknowledgement = Some(5000);
+        let reset = refusal(&ack).unwrap();
+        assert_eq!((reset.sequence, reset.acknowledgement), (5000, None));
+
+        let mut rst = TcpSegment::new(49152, 80, 1000);
+        rst.flags.rst = true;
+        assert_eq!(refusal(&rst), None);
+    }
+
+    #[test]
+    fn resets() {
+        let now = Instant::now();
+        let (mut client, mut server) = connected(now);
+        let mut reset = client.segment(client.snd_nxt);
+        reset.flags.rst = true;
+
+        // Not exactly where expected: challenged instead
+        reset.sequence = reset.sequence.wrapping_add(1);
+        let challenge = server.handle(&reset, now);
+        assert_eq!(challenge[0].acknowledgement, Some(server.rcv_nxt));
+        assert_eq!(server.state(), TcpState::Established);
+
+        reset.sequence = reset.sequence.wrapping_sub(1);
+        assert!(server.handle(&reset, now).is_empty());
+        assert_eq!(server.state(), TcpState::Closed);
+        assert!(server.is_reset() && server.is_finished());
+
+        // A refused connection
+        let mut client = TcpConnection::connect(49152, 80, 0, 1460);
+        let syn = client.poll(now).remove(0);
+        let mut refusal = TcpSegment::new(80, 49152, 0);
+        refusal.flags.rst = true;
+        refusal.acknowledgement = Some(syn.sequence_length());
+        client.handle(&refusal, now);
+        assert!(client.is_reset());
+    }
+}
@@ -0,0 +1,160 @@
+//! IP-in-IP tunnels (RFC 2003): whole IPv4 packets carried as the payload
+//! of another, between two fixed endpoints
+//!
+//! The same tunnels carry IPv6 too (6in4, RFC 4213), e.g. to a tunnel
+//! broker.
+use crate::layer3::ipv4::Dscp;
+use crate::layer3::ipv6::Ipv6Packet;
+use crate::layer3::{IpProtocol, Ipv4Packet};
+use anyhow::{Result, bail};
+use std::net::Ipv4Addr;
+
+/// TTL of outer packets, as Linux uses
+const DEFAULT_TTL: u8 = 64;
+/// Bytes the outer header adds to each packet; it never has options
+pub const OVERHEAD: usize = 20;
+
+/// One end of a tunnel to `remote`
+///
+/// Attach it to a [Stack](crate::stack::Stack), then route through its
+/// interface to send packets down it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TunnelInterface {
+    local: Ipv4Addr,
+    remote: Ipv4Addr,
+    ttl: u8,
+}
+
+impl TunnelInterface {
+    pub const fn new(local: Ipv4Addr, remote: Ipv4Addr) -> Self {
+        Self {
+            local,
+            remote,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    #[must_use]
+    pub const fn set_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub const fn local(&self) -> Ipv4Addr {
+        self.local
+    }
+
+    pub const fn remote(&self) -> Ipv4Addr {
+        self.remote
+    }
+
+    /// Wrap `inner` in a packet to the far end
+    pub async fn encapsulate(&self, inner: &Ipv4Packet) -> Result<Ipv4Packet> {
+        let mut data = Vec::new();
+        inner.clone().onto_writer(&mut data).await?;
+        Ipv4Packet::builder(self.local, self.remote, IpProtocol::IpInIp)
+            .set_ttl(self.ttl)
+            .set_dscp(inner.dscp)
+            .set_data(data)
+            .build()
+    }
+
+    /// Wrap an IPv6 packet in one to the far end
+    pub fn encapsulate_ipv6(&self, inner: &Ipv6Packet) -> Result<Ipv4Packet> {
+        Ipv4Packet::builder(self.local, self.remote, IpProtocol::Ipv6)
+            .set_ttl(self.ttl)
+            .set_dscp(Dscp(inner.traffic_class >> 2))
+            .set_data(inner.to_bytes()?)
+            .build()
+    }
+
+    /// Unwrap a packet that came through this tunnel, or `None` if it
+    /// isn't from the far end
+    pub async fn decapsulate(&self, outer: &Ipv4Packet) -> Result<Option<Ipv4Packet>> {
+        if !self.is_from_far_end(outer) {
+            return Ok(None);
+        }
+        decapsulate(outer).await.map(Some)
+    }
+
+    /// Unwrap an IPv6 packet that came through this tunnel, or `None` if it
+    /// isn't from the far end
+    pub async fn decapsulate_ipv6(&self, outer: &Ipv4Packet) -> Result<Option<Ipv6Packet>> {
+        if !self.is_from_far_end(outer) {
+            return Ok(None);
+        }
+        decapsulate_ipv6(outer).await.map(Some)
+    }
+
+    fn is_from_far_end(&self, outer: &Ipv4Packet) -> bool {
+        (outer.source, outer.destination) == (self.remote, self.local)
+    }
+}
+
+/// The packet carried by an IP-in-IP packet
+pub async fn decapsulate(outer: &Ipv4Packet) -> Result<Ipv4Packet> {
+    if outer.protocol != IpProtocol::IpInIp {
+        bail!("IPIP: {:?} packet doesn't carry IPv4", outer.protocol);
+    }
+    Ipv4Packet::from_reader(outer.data.as_slice()).await
+}
+
+/// The packet carried by a 6in4 packet
+pub async fn decapsulate_ipv6(outer: &Ipv4Packet) -> Result<Ipv6Packet> {
+    if outer.protocol != IpProtocol::Ipv6 {
+        bail!("6in4: {:?} packet doesn't carry IPv6", outer.protocol);
+    }
+    Ipv6Packet::from_reader(outer.data.as_slice()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let inner = Ipv4Packet::builder(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 1, 0, 1),
+            IpProtocol::Udp,
+        )
+        .set_data([0, 1, 0, 2, 0, 8, 0, 0])
+        .build()?;
+
+        let ours = TunnelInterface::new(LOCAL, REMOTE);
+        let outer = ours.encapsulate(&inner).await?;
+        assert_eq!((outer.source, outer.destination), (LOCAL, REMOTE));
+        assert_eq!(outer.protocol, IpProtocol::IpInIp);
+        assert_eq!(outer.data.len(), 28);
+
+        let theirs = TunnelInterface::new(REMOTE, LOCAL);
+        assert_eq!(theirs.decapsulate(&outer).await?, Some(inner));
+        // Not from our far end
+        assert_eq!(ours.decapsulate(&outer).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn six_in_four() -> Result<()> {
+        let inner = Ipv6Packet::new(
+            "2001:db8::1".parse()?,
+            "2001:db8:1::1".parse()?,
+            IpProtocol::Udp,
+            [0, 1, 0, 2, 0, 8, 0, 0],
+        );
+        let ours = TunnelInterface::new(LOCAL, REMOTE);
+        let outer = ours.encapsulate_ipv6(&inner)?;
+        assert_eq!(outer.protocol, IpProtocol::Ipv6);
+        assert_eq!(outer.data.len(), 48);
+        // Only 6in4 unwraps as IPv6
+        assert!(decapsulate(&outer).await.is_err());
+
+        let theirs = TunnelInterface::new(REMOTE, LOCAL);
+        assert_eq!(theirs.decapsulate_ipv6(&outer).await?, Some(inner));
+        assert_eq!(ours.decapsulate_ipv6(&outer).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Wake-on-LAN magic packets
+use crate::eth::{EthFrame, Mac6};
+use crate::layer3::Layer3Packet;
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// UDP port magic packets are conventionally broadcast to
+pub const UDP_PORT: u16 = 9;
+
+/// Six 0xff bytes, then the target MAC sixteen times
+const MAGIC_PACKET_LENGTH: usize = 6 + 16 * 6;
+
+/// The magic packet waking `target`, for use as an 0x0842 payload or as
+/// the payload of a UDP broadcast
+pub fn magic_packet(target: Mac6) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(target.as_bytes());
+    }
+    packet
+}
+
+/// A broadcast frame waking `target`
+pub fn frame(src: Mac6, target: Mac6) -> EthFrame {
+    EthFrame::new(Mac6::new([0xff; 6]), src, Layer3Packet::WakeOnLan(target))
+}
+
+/// Parse a magic packet, returning the MAC it wakes
+pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Mac6> {
+    let mut packet = [0; MAGIC_PACKET_LENGTH];
+    reader.read_exact(&mut packet).await?;
+    let target = Mac6::from(<[u8; 6]>::try_from(&packet[6..12])?);
+    if packet.as_slice() != magic_packet(target) {
+        bail!("Wake-on-LAN: malformed magic packet");
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eth::EtherType;
+
+    #[tokio::test]
+    async fn magic_frame_round_trip() -> Result<()> {
+        let target: Mac6 = "00:11:22:33:44:55".parse()?;
+        let packet = magic_packet(target);
+        assert_eq!(packet.len(), MAGIC_PACKET_LENGTH);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert_eq!(packet[96..], *target.as_bytes());
+
+        let mut frame = frame(Mac6::new([2, 0, 0, 0, 0, 1]), target);
+        assert_eq!(frame.ethtype(), Some(EtherType::WakeOnLan));
+        let mut vec = Vec::new();
+        frame.onto_writer(&mut vec).await?;
+        assert_eq!(vec[..6], [0xff; 6]);
+        assert_eq!(EthFrame::from_reader(vec.as_slice()).await?, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_malformed() {
+        let mut packet = magic_packet(Mac6::new([1, 2, 3, 4, 5, 6]));
+        packet[50] ^= 1;
+        assert!(from_reader(packet.as_slice()).await.is_err());
+    }
+}
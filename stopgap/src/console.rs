@@ -0,0 +1,188 @@
+//! Interactive console state: what to show for received bytes and what to
+//! do with typed lines
+use anyhow::Result;
+use clap::ValueEnum;
+use std::io::Write;
+use std::time::Instant;
+use utf8_parser::Utf8Parser;
+
+/// Lines typed at the console starting with this are commands, not data
+pub const ESCAPE: char = '~';
+
+const HELP: &str = "\
+~h  toggle hex view
+~q  quit
+~?  show this help
+~~  send a line starting with ~";
+
+/// What to terminate sent lines with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Cr,
+    Crlf,
+    None,
+}
+
+impl LineEnding {
+    const fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Self::Lf => b"\n",
+            Self::Cr => b"\r",
+            Self::Crlf => b"\r\n",
+            Self::None => b"",
+        }
+    }
+}
+
+/// What the caller should do after a typed line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    Send(Vec<u8>),
+    Quit,
+    Nothing,
+}
+
+pub struct Console<W: Write> {
+    out: W,
+    hex: bool,
+    line_ending: LineEnding,
+    parser: Utf8Parser,
+    log: Option<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            hex: false,
+            line_ending: LineEnding::Lf,
+            parser: Utf8Parser::new(),
+            log: None,
+            start: Instant::now(),
+        }
+    }
+
+    #[must_use]
+    pub const fn set_hex(mut self, hex: bool) -> Self {
+        self.hex = hex;
+        self
+    }
+
+    #[must_use]
+    pub const fn set_line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Log a hex dump of traffic in both directions to `log`
+    #[must_use]
+    pub fn set_log(mut self, log: impl Write + Send + 'static) -> Self {
+        self.log = Some(Box::new(log));
+        self
+    }
+
+    /// Show bytes received from the serial port
+    pub fn receive(&mut self, data: &[u8]) -> Result<()> {
+        self.record("rx", data)?;
+        if self.hex {
+            write!(self.out, "<<")?;
+            for byte in data {
+                write!(self.out, " {byte:02x}")?;
+            }
+            let printable: String = data
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(self.out, "  |{printable}|")?;
+        } else {
+            for &byte in data {
+                match self.parser.push(byte) {
+                    Ok(Some(c)) => write!(self.out, "{c}")?,
+                    Ok(None) => {}
+                    Err(_) => write!(self.out, "{}", char::REPLACEMENT_CHARACTER)?,
+                }
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Handle a line typed at the console (without its newline)
+    pub fn line(&mut self, line: &str) -> Result<Action> {
+        let data = match line.strip_prefix(ESCAPE) {
+            Some("h") => {
+                self.hex = !self.hex;
+                let view = if self.hex { "hex" } else { "text" };
+                writeln!(self.out, "[{view} view]")?;
+                return Ok(Action::Nothing);
+            }
+            Some("q") => return Ok(Action::Quit),
+            Some("?") => {
+                writeln!(self.out, "{HELP}")?;
+                return Ok(Action::Nothing);
+            }
+            Some(rest) if rest.starts_with(ESCAPE) => rest,
+            Some(other) => {
+                writeln!(self.out, "Unknown command ~{other} (~? for help)")?;
+                return Ok(Action::Nothing);
+            }
+            None => line,
+        };
+
+        let mut data = data.as_bytes().to_vec();
+        data.extend_from_slice(self.line_ending.as_bytes());
+        self.record("tx", &data)?;
+        Ok(Action::Send(data))
+    }
+
+    fn record(&mut self, direction: &str, data: &[u8]) -> Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        let line = virtser::util::hex_log_line(self.start.elapsed(), direction, data);
+        log.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_and_hex_views() {
+        let mut console = Console::new(Vec::new());
+        console.receive("héllo\n".as_bytes()).unwrap();
+        console.receive(b"\xff").unwrap();
+        assert_eq!(console.line("~h").unwrap(), Action::Nothing);
+        console.receive(b"hi\x00").unwrap();
+        assert_eq!(
+            String::from_utf8(console.out).unwrap(),
+            "héllo\n\u{fffd}[hex view]\n<< 68 69 00  |hi.|\n"
+        );
+    }
+
+    #[test]
+    fn lines_and_escapes() {
+        let mut console = Console::new(Vec::new()).set_line_ending(LineEnding::Crlf);
+        assert_eq!(
+            console.line("AT").unwrap(),
+            Action::Send(b"AT\r\n".to_vec())
+        );
+        assert_eq!(
+            console.line("~~tilde").unwrap(),
+            Action::Send(b"~tilde\r\n".to_vec())
+        );
+        assert_eq!(console.line("~q").unwrap(), Action::Quit);
+        assert_eq!(console.line("~x").unwrap(), Action::Nothing);
+    }
+}
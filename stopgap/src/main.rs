@@ -1,25 +1,144 @@
-use std::io::{Read, Write};
-
-use anyhow::Result;
-use utf8_parser::Utf8Parser;
-use virtser::VirtSerBuilder;
-
-fn main() -> Result<()> {
-    let mut parser = Utf8Parser::new();
-    println!("Opening serial device");
-    let mut ser = VirtSerBuilder::new().build()?;
-    println!("Looping");
-    ser.write_all(b"Hello darling\n")?;
-    ser.write_all(b"Hello lovelies\n")?;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use virtser::async_io::AsyncVirtSer;
+use virtser::{VirtSer, VirtSerBuilder};
+mod console;
+mod script;
+mod shell;
+use console::{Action, Console, LineEnding};
+use script::Script;
+use shell::Shell;
+
+/// Interactive console, expect-style scripting, or a device-like command
+/// shell on a virtual serial port
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Baud rate of the virtual port
+    #[arg(long, default_value_t = 115200)]
+    baud: u32,
+    /// Turn echo on for the slave side
+    #[arg(long)]
+    echo: bool,
+    /// Create a symlink to the PTS at this path
+    #[arg(long)]
+    symlink: Option<PathBuf>,
+    /// Log traffic as hex to this file
+    #[arg(long)]
+    log: Option<PathBuf>,
+    /// Start in hex view
+    #[arg(long)]
+    hex: bool,
+    /// What to end sent lines with
+    #[arg(long, value_enum, default_value_t)]
+    line_ending: LineEnding,
+    /// Run this send/expect script instead of the interactive console
+    #[arg(long, conflicts_with = "shell")]
+    script: Option<PathBuf>,
+    /// Serve a command shell over the port instead of the interactive console
+    #[arg(long)]
+    shell: bool,
+    /// Run the script against this real serial device instead of a virtual port
+    #[arg(long, requires = "script")]
+    device: Option<PathBuf>,
+}
+
+/// Build the virtual port described by `args`
+fn open_port(args: &Args) -> Result<VirtSer> {
+    let mut ser = VirtSerBuilder::new()
+        .set_baud_bps(args.baud)?
+        .set_echo(args.echo)
+        .build()?;
+    if let Some(symlink) = &args.symlink {
+        // Clean up after a previous run that was killed
+        if std::fs::symlink_metadata(symlink).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            std::fs::remove_file(symlink)?;
+        }
+        ser.create_symlink(symlink)
+            .with_context(|| format!("Failed to create symlink {}", symlink.display()))?;
+    }
+    Ok(ser)
+}
+
+/// Run `path` against the device from `args`, or a virtual port
+fn run_script(args: &Args, path: &Path) -> Result<()> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let script = Script::parse(&source)?;
+    let mut transcript = std::io::stdout();
+
+    match &args.device {
+        Some(device) => {
+            let mut port = virtser::bridge::open_device(device, args.baud)
+                .with_context(|| format!("Failed to open {}", device.display()))?;
+            script.run(&mut port, &mut transcript)
+        }
+        None => {
+            let mut ser = open_port(args)?;
+            eprintln!("Serial port: {}", ser.path().display());
+            script.run(&mut ser, &mut transcript)
+        }
+    }
+}
+
+/// Act as a device with a command shell on its serial console
+async fn run_shell(ser: VirtSer) -> Result<()> {
+    let mut ser = AsyncVirtSer::new(ser)?;
+    println!("Serving shell on {}", ser.path().display());
+    let mut shell = Shell::new();
+    ser.write_all(&shell.greeting()).await?;
+
+    let mut buf = [0; 256];
     loop {
-        let mut buf = [0; 1];
-        ser.read_exact(&mut buf)?;
-        match parser.push(buf[0])? {
-            None => {}
-            Some(c) => {
-                print!("{c}")
+        let n = ser.read(&mut buf).await?;
+        let reply = shell.feed(&buf[..n]);
+        ser.write_all(&reply).await?;
+    }
+}
+
+async fn run_console(args: &Args, ser: VirtSer) -> Result<()> {
+    let mut ser = AsyncVirtSer::new(ser)?;
+    let mut console = Console::new(std::io::stdout())
+        .set_hex(args.hex)
+        .set_line_ending(args.line_ending);
+    if let Some(path) = &args.log {
+        console = console.set_log(File::create(path)?);
+    }
+    println!("Serial port: {} (~? for help)", ser.path().display());
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut buf = [0; 4096];
+    loop {
+        tokio::select! {
+            n = ser.read(&mut buf) => console.receive(&buf[..n?])?,
+            line = lines.next_line() => {
+                // End of stdin is as good as ~q
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                match console.line(&line)? {
+                    Action::Send(data) => ser.write_all(&data).await?,
+                    Action::Quit => return Ok(()),
+                    Action::Nothing => {}
+                }
             }
-        };
-        ser.write_all(&buf)?; // echo
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(path) = args.script.clone() {
+        // Scripts poll their port, so keep them off the async workers
+        return tokio::task::spawn_blocking(move || run_script(&args, &path)).await?;
+    }
+    let ser = open_port(&args)?;
+    if args.shell {
+        return run_shell(ser).await;
     }
+    run_console(&args, ser).await
 }
@@ -0,0 +1,248 @@
+//! Expect-style scripts: declarative send/expect exchanges over a port
+//!
+//! One step per line; blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! timeout 2s          # how long later expects wait (default 5s)
+//! send "AT\r"         # quotes keep surrounding whitespace
+//! expect OK           # wait for literal bytes
+//! expect /\+CSQ: \d+/ # wait for a regex match
+//! sleep 100ms
+//! ```
+//!
+//! `send` and literal `expect` arguments understand `\r`, `\n`, `\t`, `\\`,
+//! `\"` and `\xNN`.
+use anyhow::{Context, Result, anyhow, bail};
+use regex::bytes::Regex;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+use virtser::util::{self, POLL_INTERVAL};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Matcher {
+    Literal(Vec<u8>),
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// End of the first match in `data`
+    fn find(&self, data: &[u8]) -> Option<usize> {
+        match self {
+            Self::Literal(needle) if needle.is_empty() => Some(0),
+            Self::Literal(needle) => data
+                .windows(needle.len())
+                .position(|window| window == needle)
+                .map(|start| start + needle.len()),
+            Self::Regex(regex) => regex.find(data).map(|m| m.end()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Step {
+    Send(Vec<u8>),
+    Expect(Matcher),
+    Timeout(Duration),
+    Sleep(Duration),
+}
+
+/// A parsed script, with source line numbers for error messages
+#[derive(Debug)]
+pub struct Script {
+    steps: Vec<(usize, Step)>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut steps = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let step = parse_step(line).with_context(|| format!("line {line_number}"))?;
+            steps.push((line_number, step));
+        }
+        Ok(Self { steps })
+    }
+
+    /// Run against a nonblocking port, echoing received data to `transcript`
+    pub fn run(&self, port: &mut (impl Read + Write), transcript: &mut impl Write) -> Result<()> {
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut received = Vec::new();
+
+        for (line_number, step) in &self.steps {
+            match step {
+                Step::Send(data) => util::write_all(port, data)
+                    .with_context(|| format!("line {line_number}: send failed"))?,
+                Step::Expect(matcher) => {
+                    let deadline = Instant::now() + timeout;
+                    loop {
+                        if let Some(end) = matcher.find(&received) {
+                            received.drain(..end);
+                            break;
+                        }
+                        if Instant::now() >= deadline {
+                            bail!(
+                                "line {line_number}: timed out waiting for {matcher:?}, got {:?}",
+                                String::from_utf8_lossy(&received)
+                            );
+                        }
+                        let mut buf = [0; 256];
+                        match port.read(&mut buf) {
+                            Ok(0) => bail!("line {line_number}: port closed"),
+                            Ok(n) => {
+                                transcript.write_all(&buf[..n])?;
+                                transcript.flush()?;
+                                received.extend_from_slice(&buf[..n]);
+                            }
+                            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                                std::thread::sleep(POLL_INTERVAL);
+                            }
+                            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                }
+                Step::Timeout(duration) => timeout = *duration,
+                Step::Sleep(duration) => std::thread::sleep(*duration),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_step(line: &str) -> Result<Step> {
+    let (keyword, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let argument = argument.trim();
+    Ok(match keyword {
+        "send" => Step::Send(unescape(unquote(argument))?),
+        "expect" => match argument.strip_prefix('/').and_then(|a| a.strip_suffix('/')) {
+            Some(pattern) => Step::Expect(Matcher::Regex(Regex::new(pattern)?)),
+            None => Step::Expect(Matcher::Literal(unescape(unquote(argument))?)),
+        },
+        "timeout" => Step::Timeout(parse_duration(argument)?),
+        "sleep" => Step::Sleep(parse_duration(argument)?),
+        _ => bail!("Unknown command {keyword:?}"),
+    })
+}
+
+fn unquote(argument: &str) -> &str {
+    argument
+        .strip_prefix('"')
+        .and_then(|a| a.strip_suffix('"'))
+        .unwrap_or(argument)
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => out.push(b'\r'),
+            Some('n') => out.push(b'\n'),
+            Some('t') => out.push(b'\t'),
+            Some('\\') => out.push(b'\\'),
+            Some('"') => out.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("Bad escape \\x{hex}: needs two hex digits");
+                }
+                out.push(u8::from_str_radix(&hex, 16)?);
+            }
+            other => bail!(
+                "Bad escape \\{}",
+                other.map(String::from).unwrap_or_default()
+            ),
+        }
+    }
+    Ok(out)
+}
+
+/// `250ms`, `2s`, or a bare number of seconds
+fn parse_duration(text: &str) -> Result<Duration> {
+    let bad = || anyhow!("Bad duration {text:?}");
+    if let Some(ms) = text.strip_suffix("ms") {
+        return Ok(Duration::from_millis(ms.parse().map_err(|_| bad())?));
+    }
+    let seconds: f64 = text
+        .strip_suffix('s')
+        .unwrap_or(text)
+        .parse()
+        .map_err(|_| bad())?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| bad())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn parse_steps() {
+        let script = Script::parse(
+            "# modem check\n\
+             timeout 250ms\n\
+             send \"AT\\r\"\n\
+             expect /OK|ERROR/\n\
+             \n\
+             expect \\x4f\\x4B\n\
+             sleep 1.5",
+        )
+        .unwrap();
+        let steps: Vec<_> = script
+            .steps
+            .iter()
+            .map(|(line, step)| (*line, step))
+            .collect();
+        assert!(matches!(steps[0], (2, Step::Timeout(d)) if *d == Duration::from_millis(250)));
+        assert!(matches!(steps[1], (3, Step::Send(data)) if data == b"AT\r"));
+        assert!(matches!(steps[2], (4, Step::Expect(Matcher::Regex(_)))));
+        assert!(matches!(steps[3], (6, Step::Expect(Matcher::Literal(data))) if data == b"OK"));
+        assert!(matches!(steps[4], (7, Step::Sleep(d)) if *d == Duration::from_millis(1500)));
+
+        let err = Script::parse("send ok\nfrobnicate").unwrap_err();
+        assert_eq!(err.to_string(), "line 2");
+        assert!(Script::parse("send \\q").is_err());
+        for bad in ["send \\x4", "send \\x4g", "send \\x+f", "send \\x"] {
+            assert!(Script::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn run_against_responder() {
+        let (mut port, mut device) = UnixStream::pair().unwrap();
+        port.set_nonblocking(true).unwrap();
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0; 3];
+            device.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"AT\r");
+            device.write_all(b"\r\n+CSQ: 17,99\r\nOK\r\n").unwrap();
+            device
+        });
+
+        let script = Script::parse("send AT\\r\nexpect /CSQ: \\d+/\nexpect OK").unwrap();
+        let mut transcript = Vec::new();
+        script.run(&mut port, &mut transcript).unwrap();
+        assert!(transcript.starts_with(b"\r\n+CSQ"));
+        let _device = responder.join().unwrap();
+    }
+
+    #[test]
+    fn expect_times_out() {
+        let (mut port, _device) = UnixStream::pair().unwrap();
+        port.set_nonblocking(true).unwrap();
+        let script = Script::parse("timeout 20ms\nexpect never").unwrap();
+        let err = script.run(&mut port, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().starts_with("line 2: timed out"));
+    }
+}
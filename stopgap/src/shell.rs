@@ -0,0 +1,210 @@
+//! A tiny command shell served over the serial link, as a stand-in device
+//! under test
+//!
+//! Input is echoed and line-edited like a real device's console would, and
+//! output lines end in CRLF.
+use std::fmt::Write as _;
+
+const PROMPT: &str = "> ";
+
+/// Size of the byte buffer that `peek` and `poke` operate on
+const MEMORY_SIZE: usize = 256;
+
+/// Longest line we'll buffer before refusing more input
+const MAX_LINE: usize = 256;
+
+const HELP: &str = "\
+help                  show this help\r
+echo <text>           print text\r
+peek <addr> [len]     dump memory\r
+poke <addr> <byte>... write memory\r
+stats                 show counters\r
+";
+
+#[derive(Debug, Default)]
+struct Stats {
+    rx_bytes: usize,
+    tx_bytes: usize,
+    commands: usize,
+    errors: usize,
+}
+
+pub struct Shell {
+    memory: [u8; MEMORY_SIZE],
+    line: Vec<u8>,
+    /// Last byte was a CR, so an LF now doesn't end another line
+    after_cr: bool,
+    stats: Stats,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self {
+            memory: [0; MEMORY_SIZE],
+            line: Vec::new(),
+            after_cr: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Banner and first prompt, sent when the shell starts
+    pub fn greeting(&mut self) -> Vec<u8> {
+        self.output(format!("stopgap shell - type help\r\n{PROMPT}"))
+    }
+
+    /// Handle received bytes, returning what to send back
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        self.stats.rx_bytes += data.len();
+        let mut out = String::new();
+        for &byte in data {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    out.push_str("\r\n");
+                    let line =
+                        String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                    out.push_str(&self.execute(line.trim()));
+                    out.push_str(PROMPT);
+                }
+                0x08 | 0x7f => {
+                    if self.line.pop().is_some() {
+                        out.push_str("\x08 \x08");
+                    }
+                }
+                byte if (byte.is_ascii_graphic() || byte == b' ') && self.line.len() < MAX_LINE => {
+                    self.line.push(byte);
+                    out.push(byte as char);
+                }
+                _ => out.push('\x07'),
+            }
+        }
+        self.output(out)
+    }
+
+    fn output(&mut self, out: String) -> Vec<u8> {
+        self.stats.tx_bytes += out.len();
+        out.into_bytes()
+    }
+
+    /// Run one command line, returning its output
+    fn execute(&mut self, line: &str) -> String {
+        if line.is_empty() {
+            return String::new();
+        }
+        self.stats.commands += 1;
+        match self.try_execute(line) {
+            Ok(out) => out,
+            Err(err) => {
+                self.stats.errors += 1;
+                format!("error: {err}\r\n")
+            }
+        }
+    }
+
+    fn try_execute(&mut self, line: &str) -> Result<String, String> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let mut args = rest.split_whitespace();
+        match command {
+            "help" => Ok(HELP.into()),
+            "echo" => Ok(format!("{}\r\n", rest.trim())),
+            "peek" => {
+                let addr = parse_number(args.next().ok_or("missing address")?)?;
+                let len = args.next().map(parse_number).transpose()?.unwrap_or(1);
+                let bytes = self.memory_range(addr, len)?;
+                let mut out = String::new();
+                for (i, chunk) in bytes.chunks(16).enumerate() {
+                    let _ = write!(out, "{:02x}:", addr + i * 16);
+                    for byte in chunk {
+                        let _ = write!(out, " {byte:02x}");
+                    }
+                    out.push_str("\r\n");
+                }
+                Ok(out)
+            }
+            "poke" => {
+                let addr = parse_number(args.next().ok_or("missing address")?)?;
+                let values = args
+                    .map(|arg| {
+                        parse_number(arg).and_then(|n| {
+                            u8::try_from(n).map_err(|_| format!("{arg} isn't a byte"))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if values.is_empty() {
+                    return Err("missing bytes".into());
+                }
+                self.memory_range(addr, values.len())?;
+                self.memory[addr..addr + values.len()].copy_from_slice(&values);
+                Ok(String::new())
+            }
+            "stats" => Ok(format!(
+                "rx {} bytes\r\ntx {} bytes\r\ncommands {}\r\nerrors {}\r\n",
+                self.stats.rx_bytes, self.stats.tx_bytes, self.stats.commands, self.stats.errors
+            )),
+            _ => Err(format!("unknown command {command:?}")),
+        }
+    }
+
+    fn memory_range(&self, addr: usize, len: usize) -> Result<&[u8], String> {
+        addr.checked_add(len)
+            .and_then(|end| self.memory.get(addr..end))
+            .ok_or_else(|| format!("out of range (memory is {MEMORY_SIZE} bytes)"))
+    }
+}
+
+/// Decimal, or hex with a `0x` prefix
+fn parse_number(text: &str) -> Result<usize, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("bad number {text:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(shell: &mut Shell, input: &str) -> String {
+        String::from_utf8(shell.feed(input.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn echo_and_line_editing() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(&mut shell, "echx\x7fo hi\r\n"),
+            "echx\x08 \x08o hi\r\nhi\r\n> "
+        );
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        let mut shell = Shell::new();
+        assert!(run(&mut shell, "help\r").ends_with("stats                 show counters\r\n> "));
+        assert_eq!(run(&mut shell, "\n"), "");
+        assert_eq!(run(&mut shell, "\n"), "\r\n> ");
+    }
+
+    #[test]
+    fn peek_and_poke() {
+        let mut shell = Shell::new();
+        run(&mut shell, "poke 0x10 1 0x2 255\r");
+        assert_eq!(
+            run(&mut shell, "peek 16 3\r"),
+            "peek 16 3\r\n10: 01 02 ff\r\n> "
+        );
+        assert!(run(&mut shell, "poke 0 256\r").contains("error: 256 isn't a byte"));
+        assert!(run(&mut shell, "peek 250 10\r").contains("error: out of range"));
+    }
+
+    #[test]
+    fn stats_count_commands_and_errors() {
+        let mut shell = Shell::new();
+        run(&mut shell, "bogus\r\r\n");
+        let out = run(&mut shell, "stats\r");
+        assert!(out.contains("commands 2\r\n"));
+        assert!(out.contains("errors 1\r\n"));
+    }
+}
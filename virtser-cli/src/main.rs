@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::fs::File;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use virtser::bridge::{self, Bridge};
+use virtser::{VirtSer, VirtSerBuilder};
+
+/// Bridge a virtual serial port to a TCP or UNIX socket, or a real serial device
+#[derive(Parser, Debug)]
+#[command(version, about)]
+#[command(group(clap::ArgGroup::new("peer").required(true)))]
+struct Args {
+    /// Listen for TCP connections on this address
+    #[arg(long, group = "peer")]
+    listen: Option<SocketAddr>,
+    /// Connect to this TCP address
+    #[arg(long, group = "peer")]
+    connect: Option<String>,
+    /// Listen for connections on a UNIX stream socket at this path
+    #[arg(long, group = "peer")]
+    unix: Option<PathBuf>,
+    /// Bridge to this real serial device, e.g. /dev/ttyUSB0
+    #[arg(long, group = "peer")]
+    device: Option<PathBuf>,
+    /// Baud rate of the virtual port (and the real device, if any)
+    #[arg(long, default_value_t = 115200)]
+    baud: u32,
+    /// Limit each direction to this many bytes per second
+    #[arg(long)]
+    rate_limit: Option<u32>,
+    /// Create a symlink to the PTS at this path
+    #[arg(long)]
+    symlink: Option<PathBuf>,
+    /// Log traffic as hex to this file ("-" for stderr)
+    #[arg(long)]
+    log: Option<PathBuf>,
+}
+
+/// A listening UNIX socket whose path is removed when it's dropped
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    fn bind(path: &Path) -> Result<Self> {
+        // A socket left behind by a previous run would make bind fail
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Bridge the virtual port to a TCP stream until the peer disconnects
+fn pump(ser: &mut VirtSer, stream: &mut TcpStream, bridge: &mut Bridge) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    bridge.run(ser, stream)?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut ser = VirtSerBuilder::new().set_baud_bps(args.baud)?.build()?;
+    if let Some(symlink) = &args.symlink {
+        // Clean up after a previous run that was killed
+        if std::fs::symlink_metadata(symlink).is_ok_and(|meta| meta.file_type().is_symlink()) {
+            std::fs::remove_file(symlink)?;
+        }
+        ser.create_symlink(symlink)
+            .with_context(|| format!("Failed to create symlink {}", symlink.display()))?;
+    }
+    println!("Serial port: {}", ser.path().display());
+
+    let mut bridge = Bridge::new().set_rate_limit(args.rate_limit);
+    if let Some(path) = &args.log {
+        bridge = if path.as_os_str() == "-" {
+            bridge.set_log(std::io::stderr())
+        } else {
+            bridge.set_log(File::create(path)?)
+        };
+    }
+
+    if let Some(path) = &args.device {
+        let mut device = bridge::open_device(path, args.baud)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        println!("Bridging to {}", path.display());
+        bridge
+            .set_names("serial", "device")
+            .run(&mut ser, &mut device)?;
+        return Ok(());
+    }
+
+    if let Some(path) = &args.unix {
+        let socket = UnixSocket::bind(path)?;
+        println!("Listening on {}", path.display());
+        bridge = bridge.set_names("serial", "unix");
+        loop {
+            let (mut stream, _) = socket.listener.accept()?;
+            println!("Connection on {}", path.display());
+            stream.set_nonblocking(true)?;
+            if let Err(err) = bridge.run(&mut ser, &mut stream) {
+                println!("Connection error: {err}");
+            }
+            println!("Disconnected");
+        }
+    }
+    bridge = bridge.set_names("serial", "tcp");
+
+    if let Some(address) = &args.connect {
+        let mut stream = TcpStream::connect(address)?;
+        println!("Connected to {address}");
+        return pump(&mut ser, &mut stream, &mut bridge);
+    }
+
+    let listener = TcpListener::bind(args.listen.expect("clap requires a peer"))?;
+    println!("Listening on {}", listener.local_addr()?);
+    loop {
+        let (mut stream, peer) = listener.accept()?;
+        println!("Connection from {peer}");
+        if let Err(err) = pump(&mut ser, &mut stream, &mut bridge) {
+            println!("Connection error: {err}");
+        }
+        println!("{peer} disconnected");
+    }
+}
@@ -0,0 +1,13 @@
+use cfg_aliases::cfg_aliases;
+
+// Same aliases nix uses, so our cfg gates line up with its BaudRate variants
+fn main() {
+    cfg_aliases! {
+        apple_targets: { any(target_os = "ios", target_os = "macos", target_os = "watchos", target_os = "tvos", target_os = "visionos") },
+        bsd: { any(target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd", apple_targets) },
+        linux_android: { any(target_os = "android", target_os = "linux") },
+    }
+    println!("cargo:rustc-check-cfg=cfg(apple_targets)");
+    println!("cargo:rustc-check-cfg=cfg(bsd)");
+    println!("cargo:rustc-check-cfg=cfg(linux_android)");
+}
@@ -0,0 +1,223 @@
+//! tokio support, behind the `tokio` feature
+use crate::{Error, Result, RetryPolicy, VirtSer, VirtSerBuilder};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A [VirtSer] registered with the tokio reactor
+pub struct AsyncVirtSer {
+    inner: AsyncFd<VirtSer>,
+}
+
+impl AsyncVirtSer {
+    /// Register `ser` with the current tokio reactor
+    ///
+    /// `ser` must have been built in nonblocking mode (the default)
+    pub fn new(ser: VirtSer) -> Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::new(ser)?,
+        })
+    }
+
+    /// Get PTS path
+    pub fn path(&self) -> &Path {
+        self.inner.get_ref().path()
+    }
+
+    /// Get the underlying [VirtSer]
+    pub fn get_ref(&self) -> &VirtSer {
+        self.inner.get_ref()
+    }
+
+    /// Get the underlying [VirtSer] mutably
+    pub fn get_mut(&mut self) -> &mut VirtSer {
+        self.inner.get_mut()
+    }
+
+    /// Read into `buf`, waiting until data is available
+    ///
+    /// Cancellation safe: the actual read happens synchronously once the
+    /// port is ready, so dropping this future (e.g. in `select!`) never
+    /// loses data
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            match guard.try_io(|inner| inner.get_mut().read(buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Write from `buf`, waiting until the port can accept data
+    ///
+    /// The port's [RetryPolicy] isn't used: sleeping here would hold up the
+    /// executor, so writes that would block wait for the reactor instead.
+    ///
+    /// Cancellation safe: if the future is dropped, nothing was written
+    pub async fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            let mut guard = self.inner.writable_mut().await?;
+            match guard.try_io(|inner| inner.get_mut().write_with(buf, RetryPolicy::FailFast)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Deregister from the reactor and get the [VirtSer] back
+    pub fn into_inner(self) -> VirtSer {
+        self.inner.into_inner()
+    }
+}
+
+impl VirtSerBuilder {
+    /// Build a new [AsyncVirtSer] without blocking the executor
+    ///
+    /// Opening the PTY and setting it up are blocking syscalls, so they run
+    /// on tokio's blocking pool. Nonblocking mode is always turned on.
+    pub async fn build_async(self) -> Result<AsyncVirtSer> {
+        let builder = self.set_nonblocking(true);
+        let ser = tokio::task::spawn_blocking(move || builder.build())
+            .await
+            .map_err(|err| Error::Generic(format!("PTY setup task failed: {err}")))??;
+        AsyncVirtSer::new(ser)
+    }
+}
+
+/// Polling is cancellation safe for the same reason as [AsyncVirtSer::read]
+impl AsyncRead for AsyncVirtSer {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.inner.poll_read_ready_mut(cx))?;
+            match guard.try_io(|inner| inner.get_mut().read(buf.initialize_unfilled())) {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncVirtSer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.inner.poll_write_ready_mut(cx))?;
+            match guard.try_io(|inner| inner.get_mut().write_with(buf, RetryPolicy::FailFast)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(self.get_mut().inner.get_mut().flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn build_async_round_trip() {
+        let mut ser = VirtSerBuilder::new()
+            .set_nonblocking(false)
+            .build_async()
+            .await
+            .unwrap();
+        let mut app = tokio::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(ser.path())
+            .await
+            .unwrap();
+
+        ser.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        app.write_all(b"pong").await.unwrap();
+        ser.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn write_waits_without_sleeping() {
+        let mut ser = VirtSerBuilder::new()
+            .set_write_retry(RetryPolicy::Fixed {
+                interval: Duration::from_secs(5),
+                max_retries: 1,
+            })
+            .build_async()
+            .await
+            .unwrap();
+
+        // Fill the PTY until writes would block
+        let chunk = [0x55; 1024];
+        while ser.get_mut().master_file.write(&chunk).is_ok() {}
+
+        // The write waits on the reactor, leaving the timer free to fire
+        let start = Instant::now();
+        let write = tokio::time::timeout(Duration::from_millis(20), ser.write(&chunk));
+        assert!(write.await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn read_survives_cancellation() {
+        let mut ser = VirtSerBuilder::new().build_async().await.unwrap();
+        let mut app = tokio::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(ser.path())
+            .await
+            .unwrap();
+
+        let sent: Vec<u8> = (0..=255).collect();
+        let writer = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                for chunk in sent.chunks(7) {
+                    app.write_all(chunk).await.unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+                app
+            }
+        });
+
+        // Race every read against a timer that usually wins
+        let mut received = Vec::new();
+        let mut buf = [0; 64];
+        while received.len() < sent.len() {
+            tokio::select! {
+                n = ser.read(&mut buf) => received.extend_from_slice(&buf[..n.unwrap()]),
+                _ = tokio::time::sleep(std::time::Duration::from_micros(100)) => {}
+            }
+        }
+        assert_eq!(received, sent);
+        writer.await.unwrap();
+    }
+}
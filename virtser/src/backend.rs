@@ -0,0 +1,184 @@
+//! Byte transports for serial links
+//!
+//! Protocol code in this crate only needs `Read + Write`, so [MemoryPort]
+//! lets it be tested (or simulated) without allocating a PTY.
+use crate::{Result, VirtSer, util};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Something that carries the bytes of a serial link
+pub trait SerialBackend: Read + Write + Send {
+    /// Path peers can open, if the backend has one
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Number of bytes that can be read without blocking
+    fn bytes_available(&self) -> Result<usize>;
+}
+
+impl SerialBackend for VirtSer {
+    fn path(&self) -> Option<&Path> {
+        Some(VirtSer::path(self))
+    }
+
+    fn bytes_available(&self) -> Result<usize> {
+        util::bytes_queued(&self.master_file)
+    }
+}
+
+/// One direction of a [MemoryPort] pair
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    /// The writing end has been dropped
+    closed: bool,
+}
+
+/// In-memory end of a serial link, created with [MemoryPort::pair]
+///
+/// Like a [VirtSer], ports are nonblocking by default: reading with nothing
+/// buffered fails with `WouldBlock`. Once the peer is dropped, reads drain
+/// what's left and then return end of file.
+#[derive(Debug)]
+pub struct MemoryPort {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    nonblocking: bool,
+}
+
+impl MemoryPort {
+    /// Create two ports connected to each other
+    pub fn pair() -> (Self, Self) {
+        let a_to_b = Arc::new(Pipe::default());
+        let b_to_a = Arc::new(Pipe::default());
+        (
+            Self {
+                rx: b_to_a.clone(),
+                tx: a_to_b.clone(),
+                nonblocking: true,
+            },
+            Self {
+                rx: a_to_b,
+                tx: b_to_a,
+                nonblocking: true,
+            },
+        )
+    }
+
+    /// If true, reads fail with `WouldBlock` instead of waiting for data
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+}
+
+impl Drop for MemoryPort {
+    fn drop(&mut self) {
+        let mut state = self.tx.state.lock().unwrap();
+        state.closed = true;
+        self.tx.readable.notify_all();
+    }
+}
+
+impl Read for MemoryPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.rx.state.lock().unwrap();
+        while state.data.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            if self.nonblocking {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            state = self.rx.readable.wait(state).unwrap();
+        }
+
+        let n = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Our rx is closed iff the peer has been dropped
+        if self.rx.state.lock().unwrap().closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let mut state = self.tx.state.lock().unwrap();
+        state.data.extend(buf);
+        self.tx.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialBackend for MemoryPort {
+    fn bytes_available(&self) -> Result<usize> {
+        Ok(self.rx.state.lock().unwrap().data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplex() {
+        let (mut a, mut b) = MemoryPort::pair();
+        let mut buf = [0; 8];
+        assert_eq!(a.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        a.write_all(b"ping").unwrap();
+        b.write_all(b"pong").unwrap();
+        assert_eq!(b.bytes_available().unwrap(), 4);
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(a.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"pong");
+        assert!(a.path().is_none());
+    }
+
+    #[test]
+    fn blocking_read_and_close() {
+        let (mut a, mut b) = MemoryPort::pair();
+        b.set_nonblocking(false);
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            a.write_all(b"late").unwrap();
+        });
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received, b"late");
+        assert_eq!(b.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn virtser_backend() {
+        let mut ser = crate::VirtSerBuilder::new().build().unwrap();
+        ser._slave_file.write_all(b"abc").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert_eq!(SerialBackend::path(&ser), Some(ser.path()));
+        assert_eq!(ser.bytes_available().unwrap(), 3);
+    }
+}
@@ -0,0 +1,257 @@
+//! Copy bytes both ways between two ports
+//!
+//! Typically one side is a [VirtSer](crate::VirtSer) and the other is a real
+//! serial device (see [open_device]) or a socket, so a program opened on the
+//! PTS transparently talks to whatever is on the far side.
+use crate::{Error, Result, platform, util};
+use nix::{
+    fcntl::OFlag,
+    sys::termios::{self, SetArg, cfmakeraw},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How long to sleep when neither side has anything to say
+const IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Open a real serial device (e.g. `/dev/ttyUSB0`) in raw, nonblocking mode
+pub fn open_device(path: impl AsRef<Path>, bps: u32) -> Result<File> {
+    let path = path.as_ref();
+    let baud_rate = platform::baud_rate_from_bps(bps)
+        .ok_or_else(|| Error::Generic(format!("Unsupported baud rate: {bps}")))?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags((OFlag::O_NOCTTY | OFlag::O_NONBLOCK).bits())
+        .open(path)?;
+
+    let mut termios = termios::tcgetattr(&file)?;
+    cfmakeraw(&mut termios);
+    platform::set_speed(&mut termios, baud_rate)?;
+    termios::tcsetattr(&file, SetArg::TCSANOW, &termios)?;
+    Ok(file)
+}
+
+/// Token bucket limiting one direction to a number of bytes per second
+#[derive(Debug)]
+struct Throttle {
+    rate: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Bytes we may send right now
+    fn budget(&mut self) -> usize {
+        let now = Instant::now();
+        let burst = f64::from(self.rate.div_ceil(10).max(1));
+        self.tokens = (self.tokens
+            + now.duration_since(self.last).as_secs_f64() * f64::from(self.rate))
+        .min(burst);
+        self.last = now;
+        self.tokens as usize
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Bidirectional byte pump between two nonblocking ports
+pub struct Bridge {
+    rate_limit: Option<u32>,
+    names: (&'static str, &'static str),
+    log: Option<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl Bridge {
+    /// Create a new bridge with no rate limit and no logging
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            rate_limit: None,
+            names: ("a", "b"),
+            log: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// Limit each direction to `bytes_per_sec`, or `None` for no limit
+    #[must_use]
+    pub fn set_rate_limit(mut self, bytes_per_sec: Option<u32>) -> Self {
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// Name the two sides in the traffic log, e.g. `("serial", "tcp")`
+    #[must_use]
+    pub fn set_names(mut self, a: &'static str, b: &'static str) -> Self {
+        self.names = (a, b);
+        self
+    }
+
+    /// Log a timestamped hex dump of all traffic to `log`
+    #[must_use]
+    pub fn set_log(mut self, log: impl Write + Send + 'static) -> Self {
+        self.log = Some(Box::new(log));
+        self
+    }
+
+    /// Copy bytes between `a` and `b` until either side reaches end of file
+    ///
+    /// Both ports must be nonblocking
+    pub fn run(&mut self, a: &mut (impl Read + Write), b: &mut (impl Read + Write)) -> Result {
+        let mut a_to_b = self.rate_limit.map(Throttle::new);
+        let mut b_to_a = self.rate_limit.map(Throttle::new);
+        let (a_name, b_name) = self.names;
+        let mut buf = [0; 4096];
+
+        loop {
+            let a_moved = self.transfer(a, b, &mut a_to_b, &mut buf, a_name, b_name)?;
+            let b_moved = self.transfer(b, a, &mut b_to_a, &mut buf, b_name, a_name)?;
+            match (a_moved, b_moved) {
+                (None, _) | (_, None) => return Ok(()),
+                (Some(0), Some(0)) => std::thread::sleep(IDLE_INTERVAL),
+                _ => {}
+            }
+        }
+    }
+
+    /// Move one chunk from `from` to `to`
+    ///
+    /// Returns the number of bytes moved, or `None` at end of file
+    fn transfer(
+        &mut self,
+        from: &mut impl Read,
+        to: &mut impl Write,
+        throttle: &mut Option<Throttle>,
+        buf: &mut [u8],
+        from_name: &str,
+        to_name: &str,
+    ) -> Result<Option<usize>> {
+        let len = match throttle {
+            Some(throttle) => throttle.budget().min(buf.len()),
+            None => buf.len(),
+        };
+        if len == 0 {
+            return Ok(Some(0));
+        }
+
+        let n = match from.read(&mut buf[..len]) {
+            Ok(0) => return Ok(None),
+            Ok(n) => n,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                return Ok(Some(0));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(throttle) = throttle {
+            throttle.consume(n);
+        }
+        self.record(from_name, to_name, &buf[..n])?;
+        util::write_all(to, &buf[..n])?;
+        Ok(Some(n))
+    }
+
+    fn record(&mut self, from: &str, to: &str, data: &[u8]) -> Result {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        let line = util::hex_log_line(self.start.elapsed(), &format!("{from}->{to}"), data);
+        log.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Default for Bridge {
+    fn default() -> Self {
+        Bridge::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+
+    fn pair() -> (UnixStream, UnixStream) {
+        let (near, far) = UnixStream::pair().unwrap();
+        far.set_nonblocking(true).unwrap();
+        (near, far)
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn copies_both_ways_until_eof() {
+        let (mut a, mut a_far) = pair();
+        let (mut b, mut b_far) = pair();
+        let log = SharedLog::default();
+        let mut bridge = Bridge::new().set_names("pty", "dev").set_log(log.clone());
+        let handle = std::thread::spawn(move || bridge.run(&mut a_far, &mut b_far));
+
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        b.write_all(b"pong").unwrap();
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        drop(a);
+        handle.join().unwrap().unwrap();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("pty->dev 70 69 6e 67"));
+        assert!(log.contains("dev->pty 70 6f 6e 67"));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let (mut a, mut a_far) = pair();
+        let (mut b, mut b_far) = pair();
+        let mut bridge = Bridge::new().set_rate_limit(Some(1000));
+        std::thread::spawn(move || bridge.run(&mut a_far, &mut b_far));
+
+        let start = Instant::now();
+        a.write_all(&[0x55; 200]).unwrap();
+        let mut buf = [0; 200];
+        b.read_exact(&mut buf).unwrap();
+        // 200 bytes at 1000 B/s takes about 200ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn open_device_rejects_bad_baud() {
+        assert!(open_device("/dev/null", 12345).is_err());
+    }
+}
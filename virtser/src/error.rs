@@ -8,6 +8,10 @@ pub enum Error {
     Generic(String),
     #[error(transparent)]
     Nix(nix::errno::Errno),
+    #[error(transparent)]
+    Io(std::io::Error),
+    #[error("Timed out")]
+    Timeout,
 }
 
 impl From<nix::errno::Errno> for Error {
@@ -15,3 +19,9 @@ impl From<nix::errno::Errno> for Error {
         Self::Nix(value)
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
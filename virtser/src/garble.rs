@@ -0,0 +1,91 @@
+//! Baud-mismatch emulation
+//!
+//! When the two ends of a real serial line disagree on the baud rate, the
+//! receiver samples the sender's bits at the wrong times and sees garbage.
+//! [resample] reproduces that at the bit level for 8N1 framing.
+
+/// Bits per 8N1 frame: start, eight data bits, stop
+const FRAME_BITS: u64 = 10;
+
+/// Times per bit that a receiver samples the line while hunting for a start bit
+const OVERSAMPLE: u64 = 16;
+
+/// Decode `data`, sent at `sender_bps`, as seen by a receiver at `receiver_bps`
+///
+/// Equal rates return `data` unchanged. Frames with a bad stop bit are still
+/// delivered, as most UARTs do (with a framing error flag we don't model).
+pub fn resample(data: &[u8], sender_bps: u32, receiver_bps: u32) -> Vec<u8> {
+    if sender_bps == receiver_bps || sender_bps == 0 || receiver_bps == 0 {
+        return data.to_vec();
+    }
+
+    // Time is measured in ticks such that both bit lengths are integers
+    let sender_bit = u64::from(receiver_bps) * OVERSAMPLE;
+    let receiver_bit = u64::from(sender_bps) * OVERSAMPLE;
+    let step = u64::from(sender_bps);
+    let end = data.len() as u64 * FRAME_BITS * sender_bit;
+
+    // Line level at tick `t`, idling high once the data runs out
+    let level = |t: u64| -> bool {
+        let bit = t / sender_bit;
+        let (frame, index) = ((bit / FRAME_BITS) as usize, bit % FRAME_BITS);
+        match (data.get(frame), index) {
+            (None, _) => true,
+            (Some(_), 0) => false,
+            (Some(_), 9) => true,
+            (Some(byte), index) => byte & (1 << (index - 1)) != 0,
+        }
+    };
+
+    let mut out = Vec::new();
+    let mut t = 0;
+    while t < end {
+        if level(t) {
+            t += step;
+            continue;
+        }
+
+        // Falling edge; a glitch that's gone by mid-bit isn't a start bit
+        if level(t + receiver_bit / 2) {
+            t += step;
+            continue;
+        }
+        let mut byte = 0;
+        for i in 0..8 {
+            if level(t + receiver_bit * (2 * i + 3) / 2) {
+                byte |= 1 << i;
+            }
+        }
+        out.push(byte);
+        t += receiver_bit * (2 * FRAME_BITS - 1) / 2;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_rates_pass_through() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(resample(&data, 9600, 9600), data);
+    }
+
+    #[test]
+    fn mismatched_rates_garble() {
+        let data = b"Hello, world!";
+        let fast = resample(data, 9600, 115200);
+        let slow = resample(data, 115200, 9600);
+        assert_ne!(fast, data);
+        assert_ne!(slow, data);
+        // A slow receiver sees far fewer frames than were sent
+        assert!(slow.len() < data.len());
+    }
+
+    #[test]
+    fn deterministic() {
+        let data = b"\x55\xaa\x00\xff";
+        assert_eq!(resample(data, 19200, 57600), resample(data, 19200, 57600));
+    }
+}
@@ -0,0 +1,667 @@
+//! Kermit file transfer
+//!
+//! Basic Kermit: short packets, type 1 (single character) block checks,
+//! control prefixing, and optional 8th-bit prefixing for 7-bit links.
+use crate::util::read_byte;
+use crate::{Error, Result};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const MARK: u8 = 0x01;
+/// Largest packet length expressible in a basic packet
+const MAX_PACKET_LENGTH: u8 = 94;
+/// SEQ + TYPE + CHECK
+const PACKET_OVERHEAD: usize = 3;
+
+const fn tochar(x: u8) -> u8 {
+    x + 32
+}
+
+const fn unchar(x: u8) -> u8 {
+    x.wrapping_sub(32)
+}
+
+const fn ctl(x: u8) -> u8 {
+    x ^ 64
+}
+
+mod kind {
+    pub const SEND_INIT: u8 = b'S';
+    pub const FILE_HEADER: u8 = b'F';
+    pub const DATA: u8 = b'D';
+    pub const EOF: u8 = b'Z';
+    pub const BREAK: u8 = b'B';
+    pub const ACK: u8 = b'Y';
+    pub const NAK: u8 = b'N';
+    pub const ERROR: u8 = b'E';
+}
+
+/// A single Kermit packet, before framing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Sequence number (0-63)
+    pub seq: u8,
+    pub kind: u8,
+    /// Already-encoded data field
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    fn new(seq: u8, kind: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            seq: seq % 64,
+            kind,
+            data: data.into(),
+        }
+    }
+
+    /// Type 1 block check over everything from LEN through DATA
+    fn check(bytes: &[u8]) -> u8 {
+        let sum: u32 = bytes.iter().map(|byte| u32::from(*byte)).sum();
+        tochar(((sum + ((sum & 192) / 64)) & 63) as u8)
+    }
+
+    /// Frame the packet, ready to go on the wire
+    pub fn to_bytes(&self, eol: u8) -> Vec<u8> {
+        let len = (self.data.len() + PACKET_OVERHEAD) as u8;
+        let mut frame = vec![MARK, tochar(len), tochar(self.seq), self.kind];
+        frame.extend_from_slice(&self.data);
+        frame.push(Self::check(&frame[1..]));
+        frame.push(eol);
+        frame
+    }
+
+    /// Read the next packet, skipping anything before the MARK
+    ///
+    /// Returns `None` if the packet is corrupt or longer than `max_len`
+    fn read(port: &mut impl Read, deadline: Instant, max_len: u8) -> Result<Option<Self>> {
+        while read_byte(port, deadline)? != MARK {}
+
+        let len_char = read_byte(port, deadline)?;
+        let len = unchar(len_char) as usize;
+        if !(PACKET_OVERHEAD..=max_len as usize).contains(&len) {
+            return Ok(None);
+        }
+        let mut rest = vec![0; len];
+        for byte in rest.iter_mut() {
+            *byte = read_byte(port, deadline)?;
+            if *byte == MARK {
+                // Resynchronize on a new packet
+                return Ok(None);
+            }
+        }
+
+        let check = rest.pop().unwrap_or_default();
+        let mut checked = vec![len_char];
+        checked.extend_from_slice(&rest);
+        if Self::check(&checked) != check {
+            return Ok(None);
+        }
+
+        let seq = unchar(rest[0]);
+        let kind = rest[1];
+        Ok(Some(Self::new(seq, kind, &rest[2..])))
+    }
+}
+
+/// Link parameters exchanged in the Send-Init packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Params {
+    max_packet_length: u8,
+    /// Timeout, in seconds, the peer should use
+    timeout_secs: u8,
+    eol: u8,
+    control_prefix: u8,
+    /// 'Y' = willing, 'N' = won't, otherwise the prefix character
+    eight_bit: u8,
+}
+
+impl Params {
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            tochar(self.max_packet_length),
+            tochar(self.timeout_secs),
+            // No padding
+            tochar(0),
+            ctl(0),
+            tochar(self.eol),
+            self.control_prefix,
+            self.eight_bit,
+            // Type 1 block check
+            b'1',
+        ]
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        // Missing fields take their defaults, as do a blank or nonsensical
+        // MAXL
+        let field = |index: usize| data.get(index).copied();
+        let max_packet_length = match field(0).map(unchar) {
+            Some(len @ 10..=MAX_PACKET_LENGTH) => len,
+            Some(len) if len > MAX_PACKET_LENGTH => MAX_PACKET_LENGTH,
+            _ => 80,
+        };
+        Self {
+            max_packet_length,
+            timeout_secs: field(1).map(unchar).unwrap_or(5),
+            eol: field(4).map(unchar).unwrap_or(b'\r'),
+            control_prefix: field(5).unwrap_or(b'#'),
+            eight_bit: field(6).unwrap_or(b'N'),
+        }
+    }
+}
+
+/// Agree on an 8th-bit prefix given both sides' Send-Init fields
+fn negotiate_eight_bit(ours: u8, theirs: u8) -> Option<u8> {
+    let is_prefix = |c: u8| (33..=62).contains(&c) || (96..=126).contains(&c);
+    match (ours, theirs) {
+        (ours, b'Y') if is_prefix(ours) => Some(ours),
+        (b'Y', theirs) if is_prefix(theirs) => Some(theirs),
+        (ours, theirs) if ours == theirs && is_prefix(ours) => Some(ours),
+        _ => None,
+    }
+}
+
+/// Kermit transfer settings
+#[derive(Copy, Clone, Debug)]
+pub struct Kermit {
+    max_packet_length: u8,
+    timeout: Duration,
+    max_retries: u32,
+    control_prefix: u8,
+    eight_bit_prefix: Option<u8>,
+    eol: u8,
+}
+
+impl Kermit {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            max_packet_length: MAX_PACKET_LENGTH,
+            timeout: Duration::from_secs(5),
+            max_retries: 10,
+            control_prefix: b'#',
+            eight_bit_prefix: None,
+            eol: b'\r',
+        }
+    }
+
+    /// Set the longest packet we'll send or accept (clamped to 10..=94)
+    #[must_use]
+    pub const fn set_max_packet_length(mut self, len: u8) -> Self {
+        self.max_packet_length = if len < 10 {
+            10
+        } else if len > MAX_PACKET_LENGTH {
+            MAX_PACKET_LENGTH
+        } else {
+            len
+        };
+        self
+    }
+
+    /// Set how long to wait for each packet
+    #[must_use]
+    pub const fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many times a packet is retried before giving up
+    #[must_use]
+    pub const fn set_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set the control prefix character (default `#`)
+    #[must_use]
+    pub const fn set_control_prefix(mut self, prefix: u8) -> Self {
+        self.control_prefix = prefix;
+        self
+    }
+
+    /// Request 8th-bit prefixing with the given character (usually `&`),
+    /// for links that strip the high bit
+    #[must_use]
+    pub const fn set_eight_bit_prefix(mut self, prefix: Option<u8>) -> Self {
+        self.eight_bit_prefix = prefix;
+        self
+    }
+
+    fn params(&self) -> Params {
+        Params {
+            max_packet_length: self.max_packet_length,
+            timeout_secs: self.timeout.as_secs().clamp(1, 94) as u8,
+            eol: self.eol,
+            control_prefix: self.control_prefix,
+            eight_bit: self.eight_bit_prefix.unwrap_or(b'Y'),
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        Instant::now() + self.timeout
+    }
+
+    /// Send a single file
+    pub fn send_file(&self, port: &mut (impl Read + Write), name: &str, data: &[u8]) -> Result {
+        let ours = self.params();
+        let mut seq = 0;
+        let reply = self.exchange(port, Packet::new(seq, kind::SEND_INIT, ours.encode()))?;
+        let theirs = Params::decode(&reply.data);
+        let codec = Codec {
+            control_prefix: ours.control_prefix,
+            eight_bit_prefix: negotiate_eight_bit(ours.eight_bit, theirs.eight_bit),
+        };
+        let link = Link {
+            max_data: theirs.max_packet_length.min(ours.max_packet_length) as usize
+                - PACKET_OVERHEAD,
+            eol: theirs.eol,
+        };
+
+        seq = (seq + 1) % 64;
+        let name = codec.encode(name.as_bytes());
+        if name.len() > link.max_data {
+            let message = "File name too long";
+            let error = Packet::new(seq, kind::ERROR, message);
+            port.write_all(&error.to_bytes(link.eol))?;
+            return Err(Error::Generic(format!("Kermit: {message}")));
+        }
+        self.exchange_with(port, &link, Packet::new(seq, kind::FILE_HEADER, name))?;
+
+        for chunk in codec.chunks(data, link.max_data) {
+            seq = (seq + 1) % 64;
+            self.exchange_with(port, &link, Packet::new(seq, kind::DATA, chunk))?;
+        }
+
+        seq = (seq + 1) % 64;
+        self.exchange_with(port, &link, Packet::new(seq, kind::EOF, []))?;
+        seq = (seq + 1) % 64;
+        self.exchange_with(port, &link, Packet::new(seq, kind::BREAK, []))?;
+        Ok(())
+    }
+
+    /// Receive a single file, returning its name and contents
+    pub fn receive_file(&self, port: &mut (impl Read + Write)) -> Result<(String, Vec<u8>)> {
+        let ours = self.params();
+        let mut link = Link {
+            max_data: 0,
+            eol: self.eol,
+        };
+        let mut codec = Codec {
+            control_prefix: b'#',
+            eight_bit_prefix: None,
+        };
+        let mut name = None;
+        let mut data = Vec::new();
+        let mut expected = 0;
+        let mut last_ack: Option<Packet> = None;
+        let mut retries = 0;
+        // The sender can't know our limit until it sees our Send-Init
+        let mut max_len = MAX_PACKET_LENGTH;
+
+        loop {
+            if retries >= self.max_retries {
+                return Err(Error::Timeout);
+            }
+            let packet = match Packet::read(port, self.deadline(), max_len) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    retries += 1;
+                    port.write_all(&Packet::new(expected, kind::NAK, []).to_bytes(link.eol))?;
+                    continue;
+                }
+                Err(Error::Timeout) => {
+                    retries += 1;
+                    port.write_all(&Packet::new(expected, kind::NAK, []).to_bytes(link.eol))?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            if packet.kind == kind::ERROR {
+                return Err(Error::Generic(format!(
+                    "Kermit: peer error: {}",
+                    String::from_utf8_lossy(&packet.data)
+                )));
+            }
+
+            if packet.seq != expected {
+                // Our ACK was lost - repeat it
+                if let Some(ack) = &last_ack
+                    && packet.seq == ack.seq
+                {
+                    port.write_all(&ack.to_bytes(link.eol))?;
+                }
+                continue;
+            }
+            retries = 0;
+
+            let ack_data = match packet.kind {
+                kind::SEND_INIT => {
+                    let theirs = Params::decode(&packet.data);
+                    codec = Codec {
+                        control_prefix: theirs.control_prefix,
+                        eight_bit_prefix: negotiate_eight_bit(ours.eight_bit, theirs.eight_bit),
+                    };
+                    link.eol = theirs.eol;
+                    max_len = ours.max_packet_length;
+                    ours.encode()
+                }
+                kind::FILE_HEADER => {
+                    let decoded = codec.decode(&packet.data)?;
+                    name = Some(String::from_utf8_lossy(&decoded).into_owned());
+                    Vec::new()
+                }
+                kind::DATA => {
+                    data.extend(codec.decode(&packet.data)?);
+                    Vec::new()
+                }
+                kind::EOF => Vec::new(),
+                kind::BREAK => {
+                    let ack = Packet::new(packet.seq, kind::ACK, []);
+                    port.write_all(&ack.to_bytes(link.eol))?;
+                    let name =
+                        name.ok_or_else(|| Error::Generic("Kermit: no file header".into()))?;
+                    return Ok((name, data));
+                }
+                other => {
+                    let message = format!("Unexpected packet type {}", other as char);
+                    let error = Packet::new(packet.seq, kind::ERROR, message.clone());
+                    port.write_all(&error.to_bytes(link.eol))?;
+                    return Err(Error::Generic(format!("Kermit: {message}")));
+                }
+            };
+
+            let ack = Packet::new(packet.seq, kind::ACK, ack_data);
+            port.write_all(&ack.to_bytes(link.eol))?;
+            last_ack = Some(ack);
+            expected = (expected + 1) % 64;
+        }
+    }
+
+    /// Send the Send-Init, using default link settings
+    fn exchange(&self, port: &mut (impl Read + Write), packet: Packet) -> Result<Packet> {
+        let link = Link {
+            max_data: MAX_PACKET_LENGTH as usize - PACKET_OVERHEAD,
+            eol: self.eol,
+        };
+        self.exchange_with(port, &link, packet)
+    }
+
+    /// Send a packet and wait for its ACK, retrying as needed
+    fn exchange_with(
+        &self,
+        port: &mut (impl Read + Write),
+        link: &Link,
+        packet: Packet,
+    ) -> Result<Packet> {
+        let frame = packet.to_bytes(link.eol);
+        for _ in 0..self.max_retries {
+            port.write_all(&frame)?;
+            // Replies come after the peer has seen our Send-Init
+            match Packet::read(port, self.deadline(), self.max_packet_length) {
+                Ok(Some(reply)) if reply.kind == kind::ACK && reply.seq == packet.seq => {
+                    return Ok(reply);
+                }
+                // A NAK for the next packet implies an ACK for this one
+                Ok(Some(reply))
+                    if reply.kind == kind::NAK && reply.seq == (packet.seq + 1) % 64 =>
+                {
+                    return Ok(Packet::new(packet.seq, kind::ACK, []));
+                }
+                Ok(Some(reply)) if reply.kind == kind::ERROR => {
+                    return Err(Error::Generic(format!(
+                        "Kermit: peer error: {}",
+                        String::from_utf8_lossy(&reply.data)
+                    )));
+                }
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+impl Default for Kermit {
+    fn default() -> Self {
+        Kermit::new()
+    }
+}
+
+/// Negotiated link settings
+struct Link {
+    /// Most encoded data bytes per packet
+    max_data: usize,
+    /// Line terminator the peer wants
+    eol: u8,
+}
+
+/// Data field prefix encoding
+struct Codec {
+    control_prefix: u8,
+    eight_bit_prefix: Option<u8>,
+}
+
+impl Codec {
+    fn encode_byte(&self, byte: u8, out: &mut Vec<u8>) {
+        let mut low = byte;
+        if let Some(prefix) = self.eight_bit_prefix
+            && byte & 0x80 != 0
+        {
+            out.push(prefix);
+            low = byte & 0x7f;
+        }
+
+        let seven = low & 0x7f;
+        if seven < 32 || seven == 127 {
+            out.extend_from_slice(&[self.control_prefix, ctl(low)]);
+        } else if seven == self.control_prefix || Some(seven) == self.eight_bit_prefix {
+            out.extend_from_slice(&[self.control_prefix, low]);
+        } else {
+            out.push(low);
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for byte in data {
+            self.encode_byte(*byte, &mut out);
+        }
+        out
+    }
+
+    /// Encode data into packet-sized chunks, never splitting a prefixed byte
+    fn chunks(&self, data: &[u8], max_data: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut encoded = Vec::new();
+        for byte in data {
+            encoded.clear();
+            self.encode_byte(*byte, &mut encoded);
+            if chunk.len() + encoded.len() > max_data {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+            chunk.extend_from_slice(&encoded);
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut iter = data.iter().copied();
+        while let Some(mut byte) = iter.next() {
+            let mut high = 0;
+            if Some(byte) == self.eight_bit_prefix {
+                high = 0x80;
+                byte = iter.next().ok_or_else(truncated)?;
+            }
+            if byte == self.control_prefix {
+                byte = iter.next().ok_or_else(truncated)?;
+                let seven = byte & 0x7f;
+                // Prefixed control characters are ctl()'d, prefixed
+                // prefixes are literal
+                if (63..=95).contains(&seven) {
+                    byte = ctl(byte);
+                }
+            }
+            out.push(byte | high);
+        }
+        Ok(out)
+    }
+}
+
+fn truncated() -> Error {
+    Error::Generic("Kermit: truncated prefix sequence".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryPort;
+
+    fn round_trip(sender: Kermit, receiver: Kermit, data: Vec<u8>) {
+        let (mut ser, mut slave) = MemoryPort::pair();
+
+        let sent = data.clone();
+        let handle = std::thread::spawn(move || sender.send_file(&mut slave, "boot.img", &sent));
+        let (name, received) = receiver.receive_file(&mut ser).unwrap();
+        handle.join().unwrap().unwrap();
+
+        assert_eq!(name, "boot.img");
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn packet_framing() {
+        let packet = Packet::new(0, kind::SEND_INIT, Kermit::new().params().encode());
+        let frame = packet.to_bytes(b'\r');
+        let mut reader = frame.as_slice();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert_eq!(
+            Packet::read(&mut reader, deadline, MAX_PACKET_LENGTH).unwrap(),
+            Some(packet)
+        );
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        for eight_bit_prefix in [None, Some(b'&')] {
+            let codec = Codec {
+                control_prefix: b'#',
+                eight_bit_prefix,
+            };
+            let encoded = codec.encode(&data);
+            assert!(
+                encoded
+                    .iter()
+                    .all(|byte| (32..127).contains(byte) || eight_bit_prefix.is_none())
+            );
+            assert_eq!(codec.decode(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_long_packets() {
+        let (mut ser, mut slave) = MemoryPort::pair();
+        let receiver = std::thread::spawn(move || {
+            Kermit::new()
+                .set_max_packet_length(20)
+                .set_timeout(Duration::from_secs(1))
+                .receive_file(&mut ser)
+        });
+        let mut exchange = |packet: Packet| {
+            slave.write_all(&packet.to_bytes(b'\r')).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            Packet::read(&mut slave, deadline, MAX_PACKET_LENGTH)
+                .unwrap()
+                .unwrap()
+        };
+
+        let init = Kermit::new().params().encode();
+        let reply = exchange(Packet::new(0, kind::SEND_INIT, init));
+        assert_eq!(reply.kind, kind::ACK);
+        assert_eq!(Params::decode(&reply.data).max_packet_length, 20);
+
+        // 30 bytes of data makes a 33 byte packet
+        let reply = exchange(Packet::new(1, kind::FILE_HEADER, [b'a'; 30]));
+        assert_eq!((reply.kind, reply.seq), (kind::NAK, 1));
+        let reply = exchange(Packet::new(1, kind::FILE_HEADER, *b"boot.img"));
+        assert_eq!((reply.kind, reply.seq), (kind::ACK, 1));
+        for (seq, kind) in [(2, kind::EOF), (3, kind::BREAK)] {
+            assert_eq!(exchange(Packet::new(seq, kind, [])).kind, kind::ACK);
+        }
+
+        let (name, data) = receiver.join().unwrap().unwrap();
+        assert_eq!(name, "boot.img");
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn blank_max_packet_length() {
+        let (mut ser, mut slave) = MemoryPort::pair();
+        let data = vec![b'a'; 200];
+        let sent = data.clone();
+        let sender = std::thread::spawn(move || Kermit::new().send_file(&mut slave, "a", &sent));
+
+        let mut received = Vec::new();
+        loop {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let packet = Packet::read(&mut ser, deadline, MAX_PACKET_LENGTH)
+                .unwrap()
+                .unwrap();
+            let mut ack = Vec::new();
+            match packet.kind {
+                kind::SEND_INIT => {
+                    ack = Kermit::new().params().encode();
+                    ack[0] = b' ';
+                }
+                kind::DATA => {
+                    // Falls back to the default of 80
+                    assert!(packet.data.len() + PACKET_OVERHEAD <= 80);
+                    received.extend_from_slice(&packet.data);
+                }
+                _ => {}
+            }
+            let reply = Packet::new(packet.seq, kind::ACK, ack);
+            ser.write_all(&reply.to_bytes(b'\r')).unwrap();
+            if packet.kind == kind::BREAK {
+                break;
+            }
+        }
+
+        sender.join().unwrap().unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn long_file_name() {
+        let (mut ser, mut slave) = MemoryPort::pair();
+        let name = "a".repeat(300);
+        let sender = std::thread::spawn(move || Kermit::new().send_file(&mut slave, &name, b""));
+        assert!(Kermit::new().receive_file(&mut ser).is_err());
+        assert!(sender.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn transfer() {
+        let data: Vec<u8> = (0..2000).map(|i| (i * 13) as u8).collect();
+        round_trip(Kermit::new(), Kermit::new(), data);
+    }
+
+    #[test]
+    fn transfer_short_packets_eight_bit() {
+        let data: Vec<u8> = (0..500).map(|i| (i * 31) as u8).collect();
+        round_trip(
+            Kermit::new()
+                .set_max_packet_length(20)
+                .set_eight_bit_prefix(Some(b'&')),
+            Kermit::new(),
+            data,
+        );
+    }
+}
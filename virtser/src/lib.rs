@@ -1,6 +1,6 @@
 use nix::{
     pty::{OpenptyResult, openpty},
-    sys::termios::{self, BaudRate, LocalFlags, SetArg, cfmakeraw},
+    sys::termios::{self, BaudRate, FlushArg, LocalFlags, SetArg, cfmakeraw},
     unistd::ttyname,
 };
 use std::{
@@ -8,9 +8,30 @@ use std::{
     io::{Read, Write},
     os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod backend;
+pub mod bridge;
 mod error;
+pub mod garble;
+pub mod kermit;
+pub mod logged;
+pub mod modbus;
+pub mod modem;
+pub mod nmea;
+pub mod noise;
+pub mod platform;
+pub mod pool;
+mod retry;
+pub mod test;
+pub mod util;
+pub mod watch;
+pub mod xmodem;
 pub use error::{Error, Result};
+pub use retry::RetryPolicy;
+use watch::{TermiosSettings, TermiosWatcher};
 
 #[derive(Copy, Clone, Debug)]
 pub struct VirtSerBuilder {
@@ -18,6 +39,8 @@ pub struct VirtSerBuilder {
     echo: bool,
     raw: bool,
     nonblocking: bool,
+    garble: bool,
+    write_retry: RetryPolicy,
 }
 
 impl VirtSerBuilder {
@@ -29,6 +52,8 @@ impl VirtSerBuilder {
             echo: false,
             raw: true,
             nonblocking: true,
+            garble: false,
+            write_retry: RetryPolicy::FailFast,
         }
     }
 
@@ -39,6 +64,15 @@ impl VirtSerBuilder {
         self
     }
 
+    /// Set the baud rate from a bit rate, e.g. `115200`
+    ///
+    /// Fails if the rate isn't supported on this platform
+    pub fn set_baud_bps(self, bps: u32) -> Result<Self> {
+        let baud_rate = platform::baud_rate_from_bps(bps)
+            .ok_or_else(|| Error::Generic(format!("Unsupported baud rate: {bps}")))?;
+        Ok(self.set_baud_rate(baud_rate))
+    }
+
     /// If true, set echo on. If false, set echo off
     #[must_use]
     pub const fn set_echo(mut self, echo: bool) -> Self {
@@ -60,12 +94,29 @@ impl VirtSerBuilder {
         self
     }
 
+    /// If true, garble data when the slave side's baud rate doesn't match
+    /// ours, like real hardware would
+    #[must_use]
+    pub const fn set_garble_on_baud_mismatch(mut self, garble: bool) -> Self {
+        self.garble = garble;
+        self
+    }
+
+    /// Set how writes that would block are retried in nonblocking mode
+    ///
+    /// Async writes never retry; they wait for the reactor instead.
+    #[must_use]
+    pub const fn set_write_retry(mut self, write_retry: RetryPolicy) -> Self {
+        self.write_retry = write_retry;
+        self
+    }
+
     /// Build a new [VirtSer]
     pub fn build(self) -> Result<VirtSer> {
         let OpenptyResult { master, slave } = openpty(None, None)?;
 
         if self.nonblocking {
-            set_nonblocking(master.as_raw_fd())?;
+            platform::set_nonblocking(master.as_raw_fd())?;
         }
 
         let master_file = unsafe { File::from_raw_fd(master.into_raw_fd()) };
@@ -81,10 +132,26 @@ impl VirtSerBuilder {
             master_file,
             _slave_file: slave_file,
             slave_path,
+            symlinks: Vec::new(),
+            baud_rate: self.baud_rate,
+            garble: self.garble,
+            nonblocking: self.nonblocking,
+            write_retry: self.write_retry,
         })
     }
 }
 
+/// Which queued data [VirtSer::purge] discards
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Data sent by the slave side that we haven't read yet
+    Input,
+    /// Data we wrote that the slave side hasn't read yet
+    Output,
+    /// Both of the above
+    Both,
+}
+
 impl Default for VirtSerBuilder {
     fn default() -> Self {
         VirtSerBuilder::new()
@@ -97,6 +164,12 @@ pub struct VirtSer {
     master_file: File,
     _slave_file: File,
     slave_path: PathBuf,
+    /// Symlinks to `slave_path`, removed on drop
+    symlinks: Vec<PathBuf>,
+    baud_rate: BaudRate,
+    garble: bool,
+    nonblocking: bool,
+    write_retry: RetryPolicy,
 }
 
 impl VirtSer {
@@ -104,37 +177,149 @@ impl VirtSer {
     pub fn path(&self) -> &Path {
         &self.slave_path
     }
+
+    /// Create a symlink to the PTS at `path`, e.g. `/tmp/ttyVIRT0`
+    ///
+    /// PTS numbers aren't stable, so this gives peers a predictable name.
+    /// The symlink is removed when the VirtSer is dropped.
+    pub fn create_symlink(&mut self, path: impl AsRef<Path>) -> Result {
+        let path = path.as_ref();
+        std::os::unix::fs::symlink(&self.slave_path, path)?;
+        self.symlinks.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Wait until the slave side has read everything we've written, giving
+    /// up with [Error::Timeout] after `timeout`
+    ///
+    /// Unlike [flush](Write::flush), which only hands data to the kernel, this
+    /// blocks until the peer has consumed it, like `tcdrain` on a real port
+    pub fn drain(&self, timeout: Duration) -> Result {
+        let deadline = Instant::now() + timeout;
+        while util::pty_bytes_queued(&self._slave_file)? > 0 {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            std::thread::sleep(util::POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Discard queued data that hasn't been read yet, like `tcflush`
+    pub fn purge(&self, direction: Direction) -> Result {
+        // A PTY's queues live on the receiving side, so flush both ends
+        let (master, slave) = match direction {
+            Direction::Input => (FlushArg::TCIFLUSH, FlushArg::TCOFLUSH),
+            Direction::Output => (FlushArg::TCOFLUSH, FlushArg::TCIFLUSH),
+            Direction::Both => (FlushArg::TCIOFLUSH, FlushArg::TCIOFLUSH),
+        };
+        termios::tcflush(&self.master_file, master)?;
+        termios::tcflush(&self._slave_file, slave)?;
+        Ok(())
+    }
+
+    /// Current terminal settings of the slave side
+    pub fn termios(&self) -> Result<TermiosSettings> {
+        TermiosSettings::of(&self._slave_file)
+    }
+
+    /// Call `on_change` whenever the application on the slave side changes
+    /// its terminal settings, polling every `interval`
+    ///
+    /// Watching stops when the returned [TermiosWatcher] is dropped
+    pub fn watch_termios(
+        &self,
+        interval: Duration,
+        on_change: impl FnMut(TermiosSettings) + Send + 'static,
+    ) -> Result<TermiosWatcher> {
+        TermiosWatcher::new(self._slave_file.try_clone()?, interval, on_change)
+    }
+}
+
+impl VirtSer {
+    /// Bit rates of (our side, slave side), if they differ and garbling is on
+    fn baud_mismatch(&self) -> std::io::Result<Option<(u32, u32)>> {
+        if !self.garble {
+            return Ok(None);
+        }
+        let termios = termios::tcgetattr(&self._slave_file)?;
+        let ours = platform::bps_from_baud_rate(self.baud_rate);
+        let theirs = platform::get_speed(&termios).and_then(platform::bps_from_baud_rate);
+        Ok(match (ours, theirs) {
+            (Some(ours), Some(theirs)) if ours != theirs => Some((ours, theirs)),
+            _ => None,
+        })
+    }
+
+    /// Write from `buf`, retrying writes that would block as `retry` says
+    pub(crate) fn write_with(&mut self, buf: &[u8], retry: RetryPolicy) -> std::io::Result<usize> {
+        let mismatch = self.baud_mismatch()?;
+        let file = &mut self.master_file;
+        let Some((ours, theirs)) = mismatch else {
+            return retry.run(|| file.write(buf));
+        };
+        let garbled = garble::resample(buf, ours, theirs);
+        if garbled.is_empty() {
+            return Ok(buf.len());
+        }
+        let n = retry.run(|| file.write(&garbled))?;
+        // Round up, so writing anything never looks like writing nothing
+        Ok((n * buf.len()).div_ceil(garbled.len()))
+    }
+}
+
+impl Drop for VirtSer {
+    fn drop(&mut self) {
+        for symlink in &self.symlinks {
+            let _ = std::fs::remove_file(symlink);
+        }
+    }
+}
+
+impl AsRawFd for VirtSer {
+    /// The master side of the PTY
+    fn as_raw_fd(&self) -> RawFd {
+        self.master_file.as_raw_fd()
+    }
 }
 
 impl Read for VirtSer {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.master_file.read(buf)
+        loop {
+            let n = self.master_file.read(buf)?;
+            let Some((ours, theirs)) = self.baud_mismatch()? else {
+                return Ok(n);
+            };
+
+            // A faster receiver may see more frames than fit; drop the excess
+            let garbled = garble::resample(&buf[..n], theirs, ours);
+            let garbled = &garbled[..garbled.len().min(buf.len())];
+            buf[..garbled.len()].copy_from_slice(garbled);
+            if !garbled.is_empty() || n == 0 {
+                return Ok(garbled.len());
+            }
+            // Everything was lost in the noise
+            if self.nonblocking {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+        }
     }
 }
 
 impl Write for VirtSer {
+    /// With baud-mismatch garbling on, writing only part of the garbage
+    /// counts as writing the same share of `buf`
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.master_file.write(buf)
+        self.write_with(buf, self.write_retry)
     }
     fn flush(&mut self) -> std::io::Result<()> {
         self.master_file.flush()
     }
 }
 
-// Credit: Pavel Kuzmin (license: MIT)
-// https://github.com/s00d/virtualport/blob/ad3809c28ad942d8036e01f5669e5214d698c178/src/pty.rs
-fn set_nonblocking(fd: RawFd) -> Result {
-    use nix::fcntl::{F_GETFL, F_SETFL, OFlag, fcntl};
-    let flags = fcntl(fd, F_GETFL)?;
-    let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
-    fcntl(fd, F_SETFL(new_flags))?;
-    Ok(())
-}
-
 fn set_baud_rate(file: &File, baud: BaudRate) -> Result {
     let mut termio = termios::tcgetattr(file)?;
-    termios::cfsetispeed(&mut termio, baud)?;
-    termios::cfsetospeed(&mut termio, baud)?;
+    platform::set_speed(&mut termio, baud)?;
     termios::tcsetattr(file, SetArg::TCSANOW, &termio)?;
     Ok(())
 }
@@ -211,4 +396,154 @@ mod tests {
             assert_eq!(tv.as_bytes(), buf);
         }
     }
+
+    #[test]
+    fn symlink_removed_on_drop() {
+        let path = std::env::temp_dir().join(format!("virtser-test-{}", std::process::id()));
+        let mut ser = VirtSerBuilder::new().build().unwrap();
+        ser.create_symlink(&path).unwrap();
+        assert_eq!(std::fs::read_link(&path).unwrap(), ser.path());
+        drop(ser);
+        assert!(std::fs::symlink_metadata(&path).is_err());
+    }
+
+    #[test]
+    fn baud_rate_applied() {
+        let ser = VirtSerBuilder::new()
+            .set_baud_bps(9600)
+            .unwrap()
+            .build()
+            .unwrap();
+        let termios = termios::tcgetattr(&ser._slave_file).unwrap();
+        assert_eq!(platform::get_speed(&termios), Some(BaudRate::B9600));
+
+        assert!(VirtSerBuilder::new().set_baud_bps(12345).is_err());
+    }
+
+    #[test]
+    fn garbled_on_baud_mismatch() {
+        let mut ser = VirtSerBuilder::new()
+            .set_nonblocking(false)
+            .set_garble_on_baud_mismatch(true)
+            .build()
+            .unwrap();
+        let tv = b"Hello, world!";
+
+        ser.write_all(tv).unwrap();
+        let mut buf = vec![0; tv.len()];
+        ser._slave_file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, tv);
+
+        // The application on the slave side picks the wrong rate
+        let mut termios = termios::tcgetattr(&ser._slave_file).unwrap();
+        platform::set_speed(&mut termios, BaudRate::B9600).unwrap();
+        termios::tcsetattr(&ser._slave_file, SetArg::TCSANOW, &termios).unwrap();
+
+        let expected = garble::resample(tv, 115200, 9600);
+        ser.write_all(tv).unwrap();
+        let mut buf = vec![0; expected.len()];
+        ser._slave_file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_ne!(&buf[..], &tv[..]);
+    }
+
+    #[test]
+    fn garbled_short_write() {
+        let mut ser = VirtSerBuilder::new()
+            .set_garble_on_baud_mismatch(true)
+            .build()
+            .unwrap();
+        let mut termios = termios::tcgetattr(&ser._slave_file).unwrap();
+        platform::set_speed(&mut termios, BaudRate::B9600).unwrap();
+        termios::tcsetattr(&ser._slave_file, SetArg::TCSANOW, &termios).unwrap();
+
+        // Fill the PTY, then make a little room
+        let chunk = [0x55; 1024];
+        while ser.master_file.write(&chunk).is_ok() {}
+        let mut room = [0; 64];
+        ser._slave_file.read_exact(&mut room).unwrap();
+
+        // Only the share of the input whose garbage fit is written
+        let buf = vec![0x55; 1 << 20];
+        let n = ser.write(&buf).unwrap();
+        assert!(n > 0 && n < buf.len(), "{n}");
+    }
+
+    #[test]
+    fn purge_discards_queued_data() {
+        let mut ser = VirtSerBuilder::new().build().unwrap();
+        let mut slave = ser._slave_file.try_clone().unwrap();
+        platform::set_nonblocking(slave.as_raw_fd()).unwrap();
+
+        ser.write_all(b"stale").unwrap();
+        slave.write_all(b"stale").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        ser.purge(Direction::Both).unwrap();
+
+        let mut buf = [0; 16];
+        assert!(
+            ser.read(&mut buf)
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::WouldBlock)
+        );
+        assert!(
+            slave
+                .read(&mut buf)
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::WouldBlock)
+        );
+    }
+
+    #[test]
+    fn drain_waits_for_peer() {
+        let mut ser = VirtSerBuilder::new().build().unwrap();
+        let mut slave = ser._slave_file.try_clone().unwrap();
+        ser.write_all(b"data").unwrap();
+
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut buf = [0; 4];
+            slave.read_exact(&mut buf).unwrap();
+        });
+        let start = std::time::Instant::now();
+        ser.drain(Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn drain_times_out() {
+        let mut ser = VirtSerBuilder::new().build().unwrap();
+        ser.write_all(b"data").unwrap();
+        assert!(matches!(
+            ser.drain(Duration::from_millis(20)),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn write_retry_waits_for_room() {
+        let ms = Duration::from_millis;
+        let mut ser = VirtSerBuilder::new()
+            .set_write_retry(RetryPolicy::Fixed {
+                interval: ms(1),
+                max_retries: 1000,
+            })
+            .build()
+            .unwrap();
+        let mut slave = ser._slave_file.try_clone().unwrap();
+
+        // Fill the PTY until writes would block
+        let chunk = [0x55; 1024];
+        let mut filled = 0;
+        while let Ok(n) = ser.master_file.write(&chunk) {
+            filled += n;
+        }
+
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(ms(20));
+            let mut buf = vec![0; filled];
+            slave.read_exact(&mut buf).unwrap();
+        });
+        ser.write_all(&chunk).unwrap();
+        reader.join().unwrap();
+    }
 }
@@ -0,0 +1,131 @@
+//! `tracing` instrumentation for serial ports
+use crate::VirtSer;
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    time::Instant,
+};
+
+/// How many bytes of each chunk to include in events by default
+const DEFAULT_PREVIEW_LEN: usize = 32;
+
+/// Port wrapper that emits a `tracing` event for every chunk read or written
+///
+/// Events are at `DEBUG` level with target `virtser`, and carry the port
+/// name, direction, length, a hex preview, and seconds since the wrapper was
+/// created.
+#[derive(Debug)]
+pub struct LoggedVirtSer<P = VirtSer> {
+    inner: P,
+    name: String,
+    preview_len: usize,
+    start: Instant,
+}
+
+impl<P> LoggedVirtSer<P> {
+    /// Wrap `inner`, naming it `name` in events
+    pub fn new(inner: P, name: impl Into<String>) -> Self {
+        Self {
+            inner,
+            name: name.into(),
+            preview_len: DEFAULT_PREVIEW_LEN,
+            start: Instant::now(),
+        }
+    }
+
+    /// Set how many bytes of each chunk to include in the hex preview
+    #[must_use]
+    pub fn set_preview_len(mut self, preview_len: usize) -> Self {
+        self.preview_len = preview_len;
+        self
+    }
+
+    /// Get the wrapped port
+    pub fn get_ref(&self) -> &P {
+        &self.inner
+    }
+
+    /// Get the wrapped port mutably
+    pub fn get_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    /// Unwrap the port
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn event(&self, direction: &str, data: &[u8]) {
+        tracing::debug!(
+            target: "virtser",
+            port = %self.name,
+            direction,
+            len = data.len(),
+            elapsed = self.start.elapsed().as_secs_f64(),
+            data = %hex_preview(data, self.preview_len),
+        );
+    }
+}
+
+impl<P: Read> Read for LoggedVirtSer<P> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.event("read", &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<P: Write> Write for LoggedVirtSer<P> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.event("write", &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Space separated hex of the first `max` bytes of `data`
+fn hex_preview(data: &[u8], max: usize) -> String {
+    let mut out = String::with_capacity(max.min(data.len()) * 3 + 4);
+    for (i, byte) in data.iter().take(max).enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let _ = write!(out, "{byte:02x}");
+    }
+    if data.len() > max {
+        out.push_str(" ...");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview() {
+        assert_eq!(hex_preview(b"", 4), "");
+        assert_eq!(hex_preview(b"\x00\x7f\xff", 4), "00 7f ff");
+        assert_eq!(hex_preview(b"abcdef", 2), "61 62 ...");
+    }
+
+    #[test]
+    fn passes_data_through() {
+        let mut port = LoggedVirtSer::new(Vec::new(), "test");
+        port.write_all(b"hello").unwrap();
+        assert_eq!(port.into_inner(), b"hello");
+
+        let mut port = LoggedVirtSer::new(&b"world"[..], "test");
+        let mut buf = String::new();
+        port.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "world");
+    }
+}
@@ -0,0 +1,436 @@
+//! Modbus RTU framing and a simulated slave device
+use crate::util::read_byte;
+use crate::{Error, Result};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_MODBUS);
+
+/// Address 0 is broadcast - slaves act on it but never reply
+pub const BROADCAST_ADDRESS: u8 = 0;
+/// Address + function + CRC
+const MIN_FRAME_LENGTH: usize = 4;
+/// Serial line PDU limit
+const MAX_FRAME_LENGTH: usize = 256;
+
+pub mod function {
+    pub const READ_COILS: u8 = 0x01;
+    pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+    pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+    pub const READ_INPUT_REGISTERS: u8 = 0x04;
+    pub const WRITE_SINGLE_COIL: u8 = 0x05;
+    pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+    pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+    pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+}
+
+/// Exception codes sent back with the function's high bit set
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+}
+
+/// A Modbus RTU frame (ADU)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub address: u8,
+    pub function: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(address: u8, function: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            address,
+            function,
+            data: data.into(),
+        }
+    }
+
+    /// Parse a frame, checking its CRC
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        if raw.len() < MIN_FRAME_LENGTH || raw.len() > MAX_FRAME_LENGTH {
+            return Err(Error::Generic(format!(
+                "Modbus: bad frame length: {}",
+                raw.len()
+            )));
+        }
+        let (body, crc) = raw.split_at(raw.len() - 2);
+        if CRC.checksum(body).to_le_bytes() != crc {
+            return Err(Error::Generic("Modbus: bad CRC".into()));
+        }
+        Ok(Self::new(body[0], body[1], &body[2..]))
+    }
+
+    /// Serialize, appending the CRC (low byte first)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![self.address, self.function];
+        raw.extend_from_slice(&self.data);
+        let crc = CRC.checksum(&raw);
+        raw.extend_from_slice(&crc.to_le_bytes());
+        raw
+    }
+
+    /// Whether this is an exception response
+    pub const fn is_exception(&self) -> bool {
+        self.function & 0x80 != 0
+    }
+
+    fn exception(address: u8, function: u8, exception: Exception) -> Self {
+        Self::new(address, function | 0x80, [exception as u8])
+    }
+}
+
+/// The silent interval (3.5 character times) that delimits RTU frames
+///
+/// Above 19200 baud the spec fixes it at 1.75ms
+pub fn inter_frame_delay(bps: u32) -> Duration {
+    if bps == 0 || bps > 19200 {
+        return Duration::from_micros(1750);
+    }
+    // 11 bits per character: start, 8 data, parity (or second stop), stop
+    Duration::from_secs_f64(3.5 * 11.0 / f64::from(bps))
+}
+
+/// Read one frame, delimited by `silence`
+///
+/// Waits up to `timeout` for the frame to start. The port must be
+/// nonblocking, or the end of the frame can't be detected.
+pub fn read_frame(port: &mut impl Read, timeout: Duration, silence: Duration) -> Result<Frame> {
+    Frame::parse(&read_raw_frame(port, timeout, silence)?)
+}
+
+/// Read one frame's bytes, like [read_frame], without checking them
+fn read_raw_frame(port: &mut impl Read, timeout: Duration, silence: Duration) -> Result<Vec<u8>> {
+    let mut raw = vec![read_byte(port, Instant::now() + timeout)?];
+    // One byte past the limit is enough to tell it's too long
+    while raw.len() <= MAX_FRAME_LENGTH {
+        match read_byte(port, Instant::now() + silence) {
+            Ok(byte) => raw.push(byte),
+            Err(Error::Timeout) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(raw)
+}
+
+/// The data model of a simulated slave
+#[derive(Clone, Debug, Default)]
+pub struct RegisterMap {
+    pub coils: Vec<bool>,
+    pub discrete_inputs: Vec<bool>,
+    pub holding_registers: Vec<u16>,
+    pub input_registers: Vec<u16>,
+}
+
+impl RegisterMap {
+    /// A map with `count` of each kind of item, all zero
+    pub fn with_size(count: usize) -> Self {
+        Self {
+            coils: vec![false; count],
+            discrete_inputs: vec![false; count],
+            holding_registers: vec![0; count],
+            input_registers: vec![0; count],
+        }
+    }
+}
+
+/// Look up `count` items starting at `start`, or IllegalDataAddress
+fn range<T>(items: &[T], start: u16, count: u16) -> std::result::Result<&[T], Exception> {
+    let start = start as usize;
+    items
+        .get(start..start + count as usize)
+        .ok_or(Exception::IllegalDataAddress)
+}
+
+fn range_mut<T>(
+    items: &mut [T],
+    start: u16,
+    count: u16,
+) -> std::result::Result<&mut [T], Exception> {
+    let start = start as usize;
+    items
+        .get_mut(start..start + count as usize)
+        .ok_or(Exception::IllegalDataAddress)
+}
+
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0; bits.len().div_ceil(8)];
+    for (index, bit) in bits.iter().enumerate() {
+        if *bit {
+            bytes[index / 8] |= 1 << (index % 8);
+        }
+    }
+    bytes
+}
+
+fn u16_at(data: &[u8], index: usize) -> std::result::Result<u16, Exception> {
+    data.get(index..index + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or(Exception::IllegalDataValue)
+}
+
+/// A simulated Modbus slave
+#[derive(Clone, Debug)]
+pub struct ModbusServer {
+    address: u8,
+    pub registers: RegisterMap,
+    silence: Duration,
+}
+
+impl ModbusServer {
+    pub fn new(address: u8, registers: RegisterMap) -> Self {
+        Self {
+            address,
+            registers,
+            silence: inter_frame_delay(115200),
+        }
+    }
+
+    /// Set the line speed, which determines the inter-frame delay
+    #[must_use]
+    pub fn set_bps(mut self, bps: u32) -> Self {
+        self.silence = inter_frame_delay(bps);
+        self
+    }
+
+    /// Act on a request, returning the response if one should be sent
+    pub fn handle(&mut self, request: &Frame) -> Option<Frame> {
+        if request.address != self.address && request.address != BROADCAST_ADDRESS {
+            return None;
+        }
+
+        let response = match self.execute(request.function, &request.data) {
+            Ok(data) => Frame::new(self.address, request.function, data),
+            Err(exception) => Frame::exception(self.address, request.function, exception),
+        };
+
+        (request.address != BROADCAST_ADDRESS).then_some(response)
+    }
+
+    fn execute(&mut self, function: u8, data: &[u8]) -> std::result::Result<Vec<u8>, Exception> {
+        if !matches!(function, 0x01..=0x06 | 0x0F | 0x10) {
+            return Err(Exception::IllegalFunction);
+        }
+        let start = u16_at(data, 0)?;
+        let registers = &mut self.registers;
+
+        match function {
+            function::READ_COILS | function::READ_DISCRETE_INPUTS => {
+                let count = u16_at(data, 2)?;
+                if !(1..=2000).contains(&count) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let bits = if function == function::READ_COILS {
+                    &registers.coils
+                } else {
+                    &registers.discrete_inputs
+                };
+                let packed = pack_bits(range(bits, start, count)?);
+                let mut out = vec![packed.len() as u8];
+                out.extend(packed);
+                Ok(out)
+            }
+            function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+                let count = u16_at(data, 2)?;
+                if !(1..=125).contains(&count) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let words = if function == function::READ_HOLDING_REGISTERS {
+                    &registers.holding_registers
+                } else {
+                    &registers.input_registers
+                };
+                let mut out = vec![(count * 2) as u8];
+                for word in range(words, start, count)? {
+                    out.extend_from_slice(&word.to_be_bytes());
+                }
+                Ok(out)
+            }
+            function::WRITE_SINGLE_COIL => {
+                let value = match u16_at(data, 2)? {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                };
+                range_mut(&mut registers.coils, start, 1)?[0] = value;
+                Ok(data[..4].to_vec())
+            }
+            function::WRITE_SINGLE_REGISTER => {
+                let value = u16_at(data, 2)?;
+                range_mut(&mut registers.holding_registers, start, 1)?[0] = value;
+                Ok(data[..4].to_vec())
+            }
+            function::WRITE_MULTIPLE_COILS => {
+                let count = u16_at(data, 2)?;
+                let bytes = data.get(5..).ok_or(Exception::IllegalDataValue)?;
+                if count == 0 || bytes.len() < (count as usize).div_ceil(8) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                for (index, coil) in range_mut(&mut registers.coils, start, count)?
+                    .iter_mut()
+                    .enumerate()
+                {
+                    *coil = bytes[index / 8] & (1 << (index % 8)) != 0;
+                }
+                Ok(data[..4].to_vec())
+            }
+            function::WRITE_MULTIPLE_REGISTERS => {
+                let count = u16_at(data, 2)?;
+                if count == 0 || data.len() < 5 + count as usize * 2 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let values: Vec<u16> = (0..count as usize)
+                    .map(|index| u16_at(data, 5 + index * 2))
+                    .collect::<std::result::Result<_, _>>()?;
+                range_mut(&mut registers.holding_registers, start, count)?.copy_from_slice(&values);
+                Ok(data[..4].to_vec())
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    /// Wait up to `timeout` for a request and answer it
+    ///
+    /// Corrupt frames are silently dropped, as on a real bus; the port
+    /// closing or failing is an error
+    pub fn serve_one(&mut self, port: &mut (impl Read + Write), timeout: Duration) -> Result {
+        let raw = read_raw_frame(port, timeout, self.silence)?;
+        let Ok(request) = Frame::parse(&raw) else {
+            return Ok(());
+        };
+        if let Some(response) = self.handle(&request) {
+            // Leave a gap so the master sees a distinct frame
+            std::thread::sleep(self.silence);
+            port.write_all(&response.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Serve requests forever
+    pub fn run(&mut self, port: &mut (impl Read + Write)) -> Result {
+        loop {
+            match self.serve_one(port, Duration::from_secs(1)) {
+                Ok(()) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtSerBuilder;
+
+    #[test]
+    fn crc() {
+        let frame = Frame::new(1, function::READ_HOLDING_REGISTERS, [0, 0, 0, 0x0A]);
+        assert_eq!(
+            frame.to_bytes(),
+            [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]
+        );
+        assert_eq!(Frame::parse(&frame.to_bytes()).unwrap(), frame);
+
+        let mut corrupt = frame.to_bytes();
+        corrupt[3] ^= 1;
+        assert!(Frame::parse(&corrupt).is_err());
+    }
+
+    #[test]
+    fn timing() {
+        assert_eq!(inter_frame_delay(9600).as_micros(), 4010);
+        assert_eq!(inter_frame_delay(115200).as_micros(), 1750);
+    }
+
+    #[test]
+    fn registers() {
+        let mut server = ModbusServer::new(7, RegisterMap::with_size(16));
+        server.registers.holding_registers[2] = 0x1234;
+
+        let response = server
+            .handle(&Frame::new(
+                7,
+                function::READ_HOLDING_REGISTERS,
+                [0, 2, 0, 2],
+            ))
+            .unwrap();
+        assert_eq!(response.data, [4, 0x12, 0x34, 0, 0]);
+
+        server
+            .handle(&Frame::new(
+                7,
+                function::WRITE_MULTIPLE_COILS,
+                [0, 1, 0, 3, 1, 0b101],
+            ))
+            .unwrap();
+        assert_eq!(&server.registers.coils[..4], [false, true, false, true]);
+
+        let response = server
+            .handle(&Frame::new(7, function::READ_COILS, [0, 0, 0, 4]))
+            .unwrap();
+        assert_eq!(response.data, [1, 0b1010]);
+
+        // Wrong address and broadcast get no reply
+        assert!(
+            server
+                .handle(&Frame::new(8, function::READ_COILS, [0, 0, 0, 1]))
+                .is_none()
+        );
+        assert!(
+            server
+                .handle(&Frame::new(
+                    0,
+                    function::WRITE_SINGLE_REGISTER,
+                    [0, 0, 0xAB, 0xCD]
+                ))
+                .is_none()
+        );
+        assert_eq!(server.registers.holding_registers[0], 0xABCD);
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut server = ModbusServer::new(1, RegisterMap::with_size(4));
+        let response = server
+            .handle(&Frame::new(1, function::READ_INPUT_REGISTERS, [0, 3, 0, 2]))
+            .unwrap();
+        assert!(response.is_exception());
+        assert_eq!(response.data, [Exception::IllegalDataAddress as u8]);
+
+        let response = server.handle(&Frame::new(1, 0x2B, [0, 0])).unwrap();
+        assert_eq!(response.function, 0xAB);
+        assert_eq!(response.data, [Exception::IllegalFunction as u8]);
+    }
+
+    #[test]
+    fn serve_over_virtser() {
+        let mut ser = VirtSerBuilder::new().build().unwrap();
+        let mut slave = ser._slave_file.try_clone().unwrap();
+        let mut server = ModbusServer::new(1, RegisterMap::with_size(8));
+
+        let request = Frame::new(1, function::WRITE_SINGLE_REGISTER, [0, 5, 0xBE, 0xEF]);
+        slave.write_all(&request.to_bytes()).unwrap();
+        server.serve_one(&mut ser, Duration::from_secs(5)).unwrap();
+
+        let mut response = vec![0; request.to_bytes().len()];
+        slave.read_exact(&mut response).unwrap();
+        assert_eq!(Frame::parse(&response).unwrap(), request);
+        assert_eq!(server.registers.holding_registers[5], 0xBEEF);
+
+        // Garbage is ignored, but losing the port isn't
+        slave.write_all(&[1, 2, 3, 4, 5]).unwrap();
+        server.serve_one(&mut ser, Duration::from_secs(5)).unwrap();
+        let (mut master, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        master.set_nonblocking(true).unwrap();
+        drop(peer);
+        assert!(
+            server
+                .serve_one(&mut master, Duration::from_secs(5))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! A Hayes-compatible modem persona
+//!
+//! Answers AT commands on a serial port, and "dials" by opening a TCP
+//! connection, bridging the serial port to it until hang-up.
+use crate::Result;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Escape sequence to return to command mode while connected
+const ESCAPE_SEQUENCE: &[u8] = b"+++";
+/// Longest command line we'll buffer
+const MAX_LINE_LENGTH: usize = 256;
+
+/// What the modem is doing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ModemState {
+    /// Interpreting AT commands
+    Command,
+    /// Bridging data to the remote end
+    Online,
+    /// Connected, but interpreting AT commands after `+++`
+    OnlineCommand,
+}
+
+/// A modem answering AT commands on `port`
+pub struct Modem<P> {
+    port: P,
+    state: ModemState,
+    echo: bool,
+    signal_quality: u8,
+    guard_time: Duration,
+    /// Extra commands (uppercase, without "AT") and their responses
+    responses: HashMap<String, String>,
+    /// Dialed number to TCP address
+    phonebook: HashMap<String, String>,
+    connection: Option<TcpStream>,
+    line: Vec<u8>,
+    /// How many escape characters we've seen, and when the last data arrived
+    escape_count: usize,
+    last_data: Instant,
+}
+
+impl<P: Read + Write> Modem<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            state: ModemState::Command,
+            echo: true,
+            signal_quality: 20,
+            guard_time: Duration::from_secs(1),
+            responses: HashMap::new(),
+            phonebook: HashMap::new(),
+            connection: None,
+            line: Vec::new(),
+            escape_count: 0,
+            last_data: Instant::now(),
+        }
+    }
+
+    /// Answer `command` (e.g. `+CGMI`) with `response` followed by OK
+    #[must_use]
+    pub fn add_response(mut self, command: &str, response: &str) -> Self {
+        self.responses
+            .insert(command.to_ascii_uppercase(), response.into());
+        self
+    }
+
+    /// Make `ATD<number>` connect to `address` (`host:port`)
+    ///
+    /// Numbers not in the phonebook are dialed as addresses directly
+    #[must_use]
+    pub fn add_number(mut self, number: &str, address: &str) -> Self {
+        self.phonebook.insert(number.into(), address.into());
+        self
+    }
+
+    /// Set the signal quality reported by AT+CSQ (0-31)
+    #[must_use]
+    pub fn set_signal_quality(mut self, signal_quality: u8) -> Self {
+        self.signal_quality = signal_quality.min(31);
+        self
+    }
+
+    /// Set the silence required around `+++`
+    #[must_use]
+    pub fn set_guard_time(mut self, guard_time: Duration) -> Self {
+        self.guard_time = guard_time;
+        self
+    }
+
+    pub const fn state(&self) -> ModemState {
+        self.state
+    }
+
+    /// Do whatever work is pending without blocking, returning whether
+    /// anything happened
+    ///
+    /// The port and connection must be nonblocking
+    pub fn poll(&mut self) -> Result<bool> {
+        let mut busy = false;
+
+        let mut buf = [0; 1024];
+        match self.port.read(&mut buf) {
+            Ok(0) => {}
+            Ok(n) => {
+                busy = true;
+                match self.state {
+                    ModemState::Online => self.forward(&buf[..n])?,
+                    ModemState::Command | ModemState::OnlineCommand => {
+                        for byte in buf[..n].iter().copied() {
+                            self.command_byte(byte)?;
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        // Escape is only recognized after the trailing guard time
+        if self.state == ModemState::Online
+            && self.escape_count == ESCAPE_SEQUENCE.len()
+            && self.last_data.elapsed() >= self.guard_time
+        {
+            self.escape_count = 0;
+            self.state = ModemState::OnlineCommand;
+            self.respond("OK")?;
+        }
+
+        if let Some(connection) = &mut self.connection {
+            match connection.read(&mut buf) {
+                Ok(0) => {
+                    self.hang_up();
+                    self.respond("NO CARRIER")?;
+                    busy = true;
+                }
+                Ok(n) => {
+                    if self.state == ModemState::Online {
+                        self.port.write_all(&buf[..n])?;
+                    }
+                    busy = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    self.hang_up();
+                    self.respond("NO CARRIER")?;
+                    busy = true;
+                }
+            }
+        }
+
+        Ok(busy)
+    }
+
+    /// Run forever
+    pub fn run(&mut self) -> Result {
+        loop {
+            if !self.poll()? {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    /// Pass data from the port to the connection, watching for `+++`
+    fn forward(&mut self, data: &[u8]) -> Result {
+        let guarded = self.last_data.elapsed() >= self.guard_time;
+        let all_plus = data.iter().all(|byte| *byte == b'+');
+        if all_plus && (self.escape_count > 0 || guarded) {
+            self.escape_count = (self.escape_count + data.len()).min(ESCAPE_SEQUENCE.len() + 1);
+        } else {
+            self.escape_count = 0;
+        }
+        self.last_data = Instant::now();
+
+        if let Some(connection) = &mut self.connection {
+            connection.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    fn command_byte(&mut self, byte: u8) -> Result {
+        if self.echo {
+            self.port.write_all(&[byte])?;
+        }
+        match byte {
+            b'\r' => {
+                let line = String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                self.command_line(line.trim())?;
+            }
+            b'\n' => {}
+            // Backspace
+            0x08 | 0x7f => {
+                self.line.pop();
+            }
+            _ if self.line.len() < MAX_LINE_LENGTH => self.line.push(byte),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, response: &str) -> Result {
+        write!(self.port, "\r\n{response}\r\n")?;
+        Ok(())
+    }
+
+    fn hang_up(&mut self) {
+        self.connection = None;
+        self.state = ModemState::Command;
+        self.escape_count = 0;
+    }
+
+    fn command_line(&mut self, line: &str) -> Result {
+        if line.is_empty() {
+            return Ok(());
+        }
+        let Some(body) = line
+            .get(..2)
+            .filter(|at| at.eq_ignore_ascii_case("AT"))
+            .map(|_| &line[2..])
+        else {
+            return self.respond("ERROR");
+        };
+
+        if let Some(response) = self.responses.get(&body.to_ascii_uppercase()).cloned() {
+            self.respond(&response)?;
+            return self.respond("OK");
+        }
+
+        match self.execute(body)? {
+            Some(result) => self.respond(result),
+            None => Ok(()),
+        }
+    }
+
+    /// Execute the commands in an AT line, returning the final result code
+    /// (or `None` if the command produced its own)
+    fn execute(&mut self, body: &str) -> Result<Option<&'static str>> {
+        let mut chars = body.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c.to_ascii_uppercase() {
+                ' ' => {}
+                'E' => self.echo = chars.next_if(char::is_ascii_digit) != Some('0'),
+                // Verbose/quiet: we're always verbose
+                'V' | 'Q' | 'L' | 'M' | 'X' => {
+                    chars.next_if(char::is_ascii_digit);
+                }
+                'Z' => {
+                    self.hang_up();
+                    self.echo = true;
+                }
+                '&' => {
+                    chars.next();
+                    chars.next_if(char::is_ascii_digit);
+                }
+                'I' => {
+                    chars.next_if(char::is_ascii_digit);
+                    self.respond("netshit virtual modem")?;
+                }
+                'H' => {
+                    chars.next_if(char::is_ascii_digit);
+                    self.hang_up();
+                }
+                'O' => {
+                    chars.next_if(char::is_ascii_digit);
+                    if self.connection.is_none() {
+                        return Ok(Some("NO CARRIER"));
+                    }
+                    self.state = ModemState::Online;
+                    self.respond("CONNECT")?;
+                    return Ok(None);
+                }
+                'D' => {
+                    let number: String = chars
+                        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')'))
+                        .collect();
+                    // Tone/pulse modifiers
+                    let number = number.trim_start_matches(['T', 't', 'P', 'p']);
+                    return self.dial(number);
+                }
+                '+' => {
+                    let extended: String = chars.collect();
+                    return self.extended(&extended.to_ascii_uppercase());
+                }
+                // S-registers, e.g. S0=1 or S7?
+                'S' => {
+                    while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+                    if chars.next_if_eq(&'=').is_some() {
+                        while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+                    } else if chars.next_if_eq(&'?').is_some() {
+                        self.respond("000")?;
+                    }
+                }
+                _ => return Ok(Some("ERROR")),
+            }
+        }
+        Ok(Some("OK"))
+    }
+
+    fn extended(&mut self, command: &str) -> Result<Option<&'static str>> {
+        match command {
+            "CSQ" => {
+                let response = format!("+CSQ: {},99", self.signal_quality);
+                self.respond(&response)?;
+            }
+            "CSQ=?" => self.respond("+CSQ: (0-31,99),(0-7,99)")?,
+            "GMI" | "CGMI" => self.respond("netshit")?,
+            "GMM" | "CGMM" => self.respond("virtser")?,
+            _ => return Ok(Some("ERROR")),
+        }
+        Ok(Some("OK"))
+    }
+
+    fn dial(&mut self, number: &str) -> Result<Option<&'static str>> {
+        if self.connection.is_some() {
+            return Ok(Some("ERROR"));
+        }
+        let address = self
+            .phonebook
+            .get(number)
+            .cloned()
+            .unwrap_or_else(|| number.into());
+
+        let Ok(connection) = TcpStream::connect(&address) else {
+            return Ok(Some("NO CARRIER"));
+        };
+        connection.set_nonblocking(true)?;
+        connection.set_nodelay(true)?;
+        self.connection = Some(connection);
+        self.state = ModemState::Online;
+        self.last_data = Instant::now();
+        self.respond("CONNECT")?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{VirtSer, VirtSerBuilder, platform};
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+
+    fn setup() -> (Modem<VirtSer>, File) {
+        let ser = VirtSerBuilder::new().build().unwrap();
+        let slave = ser._slave_file.try_clone().unwrap();
+        platform::set_nonblocking(slave.as_raw_fd()).unwrap();
+        (Modem::new(ser), slave)
+    }
+
+    /// Poll the modem until the application side sees `expected`
+    fn expect(modem: &mut Modem<VirtSer>, slave: &mut File, expected: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut seen = Vec::new();
+        while Instant::now() < deadline {
+            modem.poll().unwrap();
+            let mut buf = [0; 256];
+            if let Ok(n) = slave.read(&mut buf) {
+                seen.extend_from_slice(&buf[..n]);
+            }
+            let text = String::from_utf8_lossy(&seen);
+            if text.contains(expected) {
+                return text.into_owned();
+            }
+        }
+        panic!(
+            "Never saw {expected:?}, got {:?}",
+            String::from_utf8_lossy(&seen)
+        );
+    }
+
+    #[test]
+    fn basic_commands() {
+        let (mut modem, mut slave) = setup();
+        slave.write_all(b"ATE0\r").unwrap();
+        expect(&mut modem, &mut slave, "OK");
+
+        slave.write_all(b"AT+CSQ\r").unwrap();
+        let text = expect(&mut modem, &mut slave, "OK");
+        assert!(text.contains("+CSQ: 20,99"));
+        assert!(!text.contains("AT+CSQ"), "Echo should be off");
+
+        slave.write_all(b"AT+BOGUS\r").unwrap();
+        expect(&mut modem, &mut slave, "ERROR");
+    }
+
+    #[test]
+    fn custom_response() {
+        let (modem, mut slave) = setup();
+        let mut modem = modem.add_response("+cgsn", "490154203237518");
+        slave.write_all(b"AT+CGSN\r").unwrap();
+        let text = expect(&mut modem, &mut slave, "OK");
+        assert!(text.contains("490154203237518"));
+    }
+
+    #[test]
+    fn dial_and_hang_up() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            // Dropping the stream hangs up
+        });
+
+        let (modem, mut slave) = setup();
+        let mut modem = modem.add_number("5551234", &address);
+        slave.write_all(b"ATDT555-1234\r").unwrap();
+        expect(&mut modem, &mut slave, "CONNECT");
+        assert_eq!(modem.state(), ModemState::Online);
+
+        slave.write_all(b"hello").unwrap();
+        expect(&mut modem, &mut slave, "hello");
+        server.join().unwrap();
+        expect(&mut modem, &mut slave, "NO CARRIER");
+        assert_eq!(modem.state(), ModemState::Command);
+    }
+}
@@ -0,0 +1,420 @@
+//! NMEA 0183 GPS simulator
+//!
+//! Emits GGA, RMC and GSV sentences for a position following a scripted
+//! route, so GPS consumers can be pointed at a [crate::VirtSer] instead of
+//! a receiver.
+use crate::{Error, Result};
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+const METERS_PER_SECOND_TO_KNOTS: f64 = 1.943_844;
+
+/// A point on a route, in degrees and meters above sea level
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Waypoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+}
+
+impl Waypoint {
+    pub const fn new(latitude: f64, longitude: f64, altitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+            altitude,
+        }
+    }
+
+    /// Great-circle distance in meters
+    fn distance_to(&self, other: &Self) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+    }
+
+    /// Initial bearing in degrees from true north
+    fn bearing_to(&self, other: &Self) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
+
+    /// Linear interpolation - fine over the short legs of a test route
+    fn lerp(&self, other: &Self, t: f64) -> Self {
+        Self {
+            latitude: self.latitude + (other.latitude - self.latitude) * t,
+            longitude: self.longitude + (other.longitude - self.longitude) * t,
+            altitude: self.altitude + (other.altitude - self.altitude) * t,
+        }
+    }
+}
+
+/// Where the simulated receiver is at some instant
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fix {
+    pub position: Waypoint,
+    /// Ground speed in meters per second
+    pub speed: f64,
+    /// Course over ground in degrees
+    pub course: f64,
+}
+
+/// A path followed at constant speed
+#[derive(Clone, Debug)]
+pub struct Route {
+    waypoints: Vec<Waypoint>,
+    speed: f64,
+    looped: bool,
+}
+
+impl Route {
+    /// Sit still at `position`
+    pub fn stationary(position: Waypoint) -> Self {
+        Self {
+            waypoints: vec![position],
+            speed: 0.0,
+            looped: false,
+        }
+    }
+
+    /// Travel through `waypoints` at `speed` meters per second, stopping at
+    /// the last one
+    pub fn new(waypoints: Vec<Waypoint>, speed: f64) -> Result<Self> {
+        if waypoints.is_empty() {
+            return Err(Error::Generic("Route needs at least one waypoint".into()));
+        }
+        Ok(Self {
+            waypoints,
+            speed,
+            looped: false,
+        })
+    }
+
+    /// Return to the first waypoint and start over after the last one
+    #[must_use]
+    pub fn looped(mut self) -> Self {
+        if self.waypoints.len() > 1 {
+            let first = self.waypoints[0];
+            self.waypoints.push(first);
+        }
+        self.looped = true;
+        self
+    }
+
+    /// Where we are `elapsed` after starting the route
+    pub fn fix_at(&self, elapsed: Duration) -> Fix {
+        let legs: Vec<_> = self.waypoints.windows(2).collect();
+        let total: f64 = legs.iter().map(|leg| leg[0].distance_to(&leg[1])).sum();
+
+        let mut travelled = self.speed * elapsed.as_secs_f64();
+        if self.looped && total > 0.0 {
+            travelled %= total;
+        }
+
+        for leg in &legs {
+            let length = leg[0].distance_to(&leg[1]);
+            if travelled < length {
+                return Fix {
+                    position: leg[0].lerp(&leg[1], travelled / length),
+                    speed: self.speed,
+                    course: leg[0].bearing_to(&leg[1]),
+                };
+            }
+            travelled -= length;
+        }
+
+        // End of the line
+        Fix {
+            position: *self.waypoints.last().expect("Route is never empty"),
+            speed: 0.0,
+            course: 0.0,
+        }
+    }
+}
+
+/// A satellite in view, for GSV
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Satellite {
+    pub prn: u8,
+    /// Degrees above the horizon
+    pub elevation: u8,
+    /// Degrees from true north
+    pub azimuth: u16,
+    /// Signal to noise ratio in dB-Hz
+    pub snr: u8,
+}
+
+/// Which sentences to emit each epoch
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sentence {
+    Gga,
+    Rmc,
+    Gsv,
+}
+
+/// Compute the `*hh` checksum for the body of a sentence (between `$` and `*`)
+pub fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |acc, byte| acc ^ byte)
+}
+
+fn sentence(body: &str) -> String {
+    format!("${body}*{:02X}\r\n", checksum(body))
+}
+
+/// Format a coordinate as NMEA `dddmm.mmmm,H`
+fn coordinate(degrees: f64, degree_digits: usize, positive: char, negative: char) -> String {
+    let hemisphere = if degrees < 0.0 { negative } else { positive };
+    let degrees = degrees.abs();
+    let whole = degrees.trunc();
+    let minutes = (degrees - whole) * 60.0;
+    format!(
+        "{:0width$}{:07.4},{hemisphere}",
+        whole as u32,
+        minutes,
+        width = degree_digits
+    )
+}
+
+/// UTC calendar date and time of day for a Unix time
+///
+/// Returns `(year, month, day, seconds since midnight)`
+fn civil_from_unix(time: SystemTime) -> (i64, u32, u32, f64) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86400) as i64;
+    let time_of_day = (secs % 86400) as f64 + f64::from(since_epoch.subsec_millis()) / 1000.0;
+
+    // Howard Hinnant's days_from_civil, in reverse
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, time_of_day)
+}
+
+/// Simulated GPS receiver
+#[derive(Clone, Debug)]
+pub struct GpsSimulator {
+    route: Route,
+    rate_hz: u32,
+    sentences: Vec<Sentence>,
+    satellites: Vec<Satellite>,
+    start_time: SystemTime,
+}
+
+impl GpsSimulator {
+    pub fn new(route: Route) -> Self {
+        Self {
+            route,
+            rate_hz: 1,
+            sentences: vec![Sentence::Gga, Sentence::Rmc, Sentence::Gsv],
+            satellites: vec![
+                Satellite {
+                    prn: 3,
+                    elevation: 62,
+                    azimuth: 142,
+                    snr: 44,
+                },
+                Satellite {
+                    prn: 8,
+                    elevation: 41,
+                    azimuth: 277,
+                    snr: 40,
+                },
+                Satellite {
+                    prn: 14,
+                    elevation: 25,
+                    azimuth: 45,
+                    snr: 36,
+                },
+                Satellite {
+                    prn: 17,
+                    elevation: 73,
+                    azimuth: 310,
+                    snr: 47,
+                },
+                Satellite {
+                    prn: 22,
+                    elevation: 12,
+                    azimuth: 198,
+                    snr: 29,
+                },
+                Satellite {
+                    prn: 27,
+                    elevation: 34,
+                    azimuth: 88,
+                    snr: 38,
+                },
+            ],
+            start_time: SystemTime::now(),
+        }
+    }
+
+    /// Set how many epochs per second to emit (1-10)
+    pub fn set_rate_hz(mut self, rate_hz: u32) -> Result<Self> {
+        if !(1..=10).contains(&rate_hz) {
+            return Err(Error::Generic(format!(
+                "Unsupported NMEA rate: {rate_hz} Hz"
+            )));
+        }
+        self.rate_hz = rate_hz;
+        Ok(self)
+    }
+
+    /// Choose which sentences are emitted, in order, each epoch
+    #[must_use]
+    pub fn set_sentences(mut self, sentences: Vec<Sentence>) -> Self {
+        self.sentences = sentences;
+        self
+    }
+
+    /// Set the satellites reported as in view
+    #[must_use]
+    pub fn set_satellites(mut self, satellites: Vec<Satellite>) -> Self {
+        self.satellites = satellites;
+        self
+    }
+
+    /// Set the UTC time reported at the start of the route
+    #[must_use]
+    pub fn set_start_time(mut self, start_time: SystemTime) -> Self {
+        self.start_time = start_time;
+        self
+    }
+
+    /// All sentences for the epoch `elapsed` into the route
+    pub fn epoch(&self, elapsed: Duration) -> Vec<String> {
+        let fix = self.route.fix_at(elapsed);
+        let (year, month, day, time_of_day) = civil_from_unix(self.start_time + elapsed);
+        let time = format!(
+            "{:02}{:02}{:05.2}",
+            (time_of_day / 3600.0) as u32,
+            (time_of_day % 3600.0 / 60.0) as u32,
+            time_of_day % 60.0
+        );
+        let date = format!("{day:02}{month:02}{:02}", year % 100);
+        let latitude = coordinate(fix.position.latitude, 2, 'N', 'S');
+        let longitude = coordinate(fix.position.longitude, 3, 'E', 'W');
+
+        let mut out = Vec::new();
+        for kind in &self.sentences {
+            match kind {
+                Sentence::Gga => out.push(sentence(&format!(
+                    "GPGGA,{time},{latitude},{longitude},1,{:02},0.9,{:.1},M,0.0,M,,",
+                    self.satellites.len(),
+                    fix.position.altitude
+                ))),
+                Sentence::Rmc => out.push(sentence(&format!(
+                    "GPRMC,{time},A,{latitude},{longitude},{:.1},{:.1},{date},,,A",
+                    fix.speed * METERS_PER_SECOND_TO_KNOTS,
+                    fix.course
+                ))),
+                Sentence::Gsv => {
+                    let total = self.satellites.len().div_ceil(4).max(1);
+                    for (index, group) in self.satellites.chunks(4).enumerate() {
+                        let mut body =
+                            format!("GPGSV,{total},{},{:02}", index + 1, self.satellites.len());
+                        for sat in group {
+                            body += &format!(
+                                ",{:02},{:02},{:03},{:02}",
+                                sat.prn, sat.elevation, sat.azimuth, sat.snr
+                            );
+                        }
+                        out.push(sentence(&body));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Emit sentences to `port` in real time until `duration` passes (or
+    /// forever if `None`)
+    pub fn run(&self, port: &mut impl Write, duration: Option<Duration>) -> Result {
+        let period = Duration::from_secs(1) / self.rate_hz;
+        let start = Instant::now();
+        let mut next = start;
+        loop {
+            let elapsed = next - start;
+            if duration.is_some_and(|duration| elapsed >= duration) {
+                return Ok(());
+            }
+            for line in self.epoch(elapsed) {
+                port.write_all(line.as_bytes())?;
+            }
+            next += period;
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_checksum() {
+        let body = "GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,";
+        assert_eq!(checksum(body), 0x47);
+    }
+
+    #[test]
+    fn coordinates() {
+        assert_eq!(coordinate(48.1173, 2, 'N', 'S'), "4807.0380,N");
+        assert_eq!(coordinate(-11.5166667, 3, 'E', 'W'), "01131.0000,W");
+    }
+
+    #[test]
+    fn date() {
+        // 2024-02-29 12:34:56 UTC
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        let (year, month, day, time_of_day) = civil_from_unix(time);
+        assert_eq!((year, month, day), (2024, 2, 29));
+        assert_eq!(time_of_day, 45296.0);
+    }
+
+    #[test]
+    fn route_progress() {
+        let start = Waypoint::new(0.0, 0.0, 10.0);
+        let end = Waypoint::new(0.0, 0.01, 20.0);
+        let length = start.distance_to(&end);
+        let route = Route::new(vec![start, end], length / 100.0).unwrap();
+
+        let halfway = route.fix_at(Duration::from_secs(50));
+        assert!((halfway.position.longitude - 0.005).abs() < 1e-9);
+        assert!((halfway.position.altitude - 15.0).abs() < 1e-9);
+        assert!((halfway.course - 90.0).abs() < 1e-6);
+
+        let done = route.fix_at(Duration::from_secs(500));
+        assert_eq!(done.position, end);
+        assert_eq!(done.speed, 0.0);
+    }
+
+    #[test]
+    fn epoch_sentences() {
+        let sim = GpsSimulator::new(Route::stationary(Waypoint::new(48.1173, 11.5166667, 545.4)))
+            .set_start_time(UNIX_EPOCH + Duration::from_secs(1_709_210_096));
+        let lines = sim.epoch(Duration::ZERO);
+
+        // GGA, RMC, and two GSV for six satellites
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("$GPGGA,123456.00,4807.0380,N,01131.0000,E,1,06,"));
+        assert!(lines[1].contains(",A,4807.0380,N,01131.0000,E,0.0,0.0,290224,"));
+        assert!(lines[2].starts_with("$GPGSV,2,1,06,03,62,142,44"));
+        for line in lines {
+            let (body, check) = line[1..].trim_end().split_once('*').unwrap();
+            assert_eq!(u8::from_str_radix(check, 16).unwrap(), checksum(body));
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Line noise simulation
+//!
+//! Wrap a port in [Noisy] to corrupt data crossing it, so CRC and retry
+//! logic in serial protocols can be exercised. Noise is driven by a seeded
+//! PRNG, so a failing run can be reproduced exactly.
+use std::io::{Read, Write};
+
+/// Small seeded PRNG (xorshift64*), good enough for noise and reproducible
+/// across platforms
+#[derive(Copy, Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    const fn new(seed: u64) -> Self {
+        // splitmix64 so that small or zero seeds still give a good state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Self(if z == 0 { 1 } else { z })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+/// Random bit errors plus occasional error bursts
+#[derive(Copy, Clone, Debug)]
+pub struct NoiseModel {
+    rng: Rng,
+    bit_error_rate: f64,
+    burst_rate: f64,
+    burst_length: usize,
+    /// Bytes left in the current burst
+    burst_remaining: usize,
+}
+
+impl NoiseModel {
+    /// Create a noiseless model with the given seed
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            bit_error_rate: 0.0,
+            burst_rate: 0.0,
+            burst_length: 8,
+            burst_remaining: 0,
+        }
+    }
+
+    /// Set the probability that any single bit is flipped
+    #[must_use]
+    pub const fn set_bit_error_rate(mut self, rate: f64) -> Self {
+        self.bit_error_rate = rate;
+        self
+    }
+
+    /// Set the probability, per byte, that an error burst starts
+    #[must_use]
+    pub const fn set_burst_rate(mut self, rate: f64) -> Self {
+        self.burst_rate = rate;
+        self
+    }
+
+    /// Set the number of consecutive bytes garbled by a burst
+    #[must_use]
+    pub const fn set_burst_length(mut self, length: usize) -> Self {
+        self.burst_length = length;
+        self
+    }
+
+    /// Corrupt `data` in place, returning the number of bytes changed
+    pub fn corrupt(&mut self, data: &mut [u8]) -> usize {
+        let mut changed = 0;
+        for byte in data {
+            let original = *byte;
+
+            if self.burst_remaining == 0 && self.rng.chance(self.burst_rate) {
+                self.burst_remaining = self.burst_length;
+            }
+            if self.burst_remaining > 0 {
+                self.burst_remaining -= 1;
+                *byte = self.rng.next_u64() as u8;
+            }
+
+            if self.bit_error_rate > 0.0 {
+                for bit in 0..8 {
+                    if self.rng.chance(self.bit_error_rate) {
+                        *byte ^= 1 << bit;
+                    }
+                }
+            }
+
+            if *byte != original {
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+/// Port wrapper that runs data through a [NoiseModel]
+///
+/// Reads and writes use independent copies of the model, so noise in one
+/// direction doesn't depend on traffic in the other.
+#[derive(Debug)]
+pub struct Noisy<P> {
+    inner: P,
+    rx: Option<NoiseModel>,
+    tx: Option<NoiseModel>,
+}
+
+impl<P> Noisy<P> {
+    /// Apply `model` to data in both directions
+    pub fn new(inner: P, model: NoiseModel) -> Self {
+        let mut tx = model;
+        // Decorrelate the two directions
+        tx.rng = Rng::new(tx.rng.next_u64());
+        Self {
+            inner,
+            rx: Some(model),
+            tx: Some(tx),
+        }
+    }
+
+    /// If false, data read from the port passes through untouched
+    #[must_use]
+    pub fn set_corrupt_reads(mut self, corrupt: bool) -> Self {
+        if !corrupt {
+            self.rx = None;
+        }
+        self
+    }
+
+    /// If false, data written to the port passes through untouched
+    #[must_use]
+    pub fn set_corrupt_writes(mut self, corrupt: bool) -> Self {
+        if !corrupt {
+            self.tx = None;
+        }
+        self
+    }
+
+    /// Get the wrapped port
+    pub fn get_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    /// Unwrap the port
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Read> Read for Noisy<P> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(rx) = &mut self.rx {
+            rx.corrupt(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<P: Write> Write for Noisy<P> {
+    /// Noise is applied to the whole buffer up front, so a short write
+    /// consumes noise for bytes that weren't sent
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.tx {
+            Some(tx) => {
+                let mut noisy = buf.to_vec();
+                tx.corrupt(&mut noisy);
+                self.inner.write(&noisy)
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noiseless_is_untouched() {
+        let mut data = [0xa5; 1000];
+        assert_eq!(NoiseModel::new(1).corrupt(&mut data), 0);
+        assert!(data.iter().all(|&b| b == 0xa5));
+    }
+
+    #[test]
+    fn same_seed_same_noise() {
+        let model = NoiseModel::new(42)
+            .set_bit_error_rate(0.01)
+            .set_burst_rate(0.01);
+        let mut a = [0; 4096];
+        let mut b = [0; 4096];
+        let (mut first, mut second) = (model, model);
+        first.corrupt(&mut a);
+        second.corrupt(&mut b);
+        assert_eq!(a, b);
+
+        let mut c = [0; 4096];
+        NoiseModel::new(43)
+            .set_bit_error_rate(0.01)
+            .set_burst_rate(0.01)
+            .corrupt(&mut c);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn bit_error_rate_roughly_respected() {
+        let mut data = [0; 10_000];
+        NoiseModel::new(7)
+            .set_bit_error_rate(0.01)
+            .corrupt(&mut data);
+        let flipped: u32 = data.iter().map(|b| b.count_ones()).sum();
+        // Expect about 800 flipped bits out of 80000
+        assert!((600..1000).contains(&flipped), "{flipped} bits flipped");
+    }
+
+    #[test]
+    fn bursts_garble_consecutive_bytes() {
+        let mut model = NoiseModel::new(3).set_burst_rate(1.0).set_burst_length(4);
+        let mut data = [0; 64];
+        // Random bytes may happen to be zero, but not most of them
+        assert!(model.corrupt(&mut data) > 48);
+    }
+
+    #[test]
+    fn noisy_wrapper_directions() {
+        let model = NoiseModel::new(9).set_bit_error_rate(0.5);
+        let mut port = Noisy::new(Vec::new(), model).set_corrupt_writes(false);
+        port.write_all(&[0; 32]).unwrap();
+        assert_eq!(port.into_inner(), [0; 32]);
+
+        let mut port = Noisy::new(&[0u8; 32][..], model);
+        let mut buf = [0; 32];
+        port.read_exact(&mut buf).unwrap();
+        assert_ne!(buf, [0; 32]);
+    }
+}
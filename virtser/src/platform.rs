@@ -0,0 +1,154 @@
+//! Platform-specific termios/PTY details
+//!
+//! Linux, macOS and the BSDs all provide openpty and termios, but they
+//! disagree on the details: which [BaudRate] variants exist, whether the
+//! variants are the literal bit rate or an opaque `Bxxx` constant, and
+//! whether `cfgetospeed` hands back a [BaudRate] or a plain integer.
+//! Everything that differs lives here, so the rest of the crate doesn't
+//! need any `cfg` attributes.
+use crate::Result;
+use nix::sys::termios::{self, BaudRate, Termios};
+use std::os::fd::RawFd;
+
+macro_rules! baud_table {
+    ($($(#[$cfg:meta])* $bps:literal => $variant:ident,)*) => {
+        /// Look up the [BaudRate] for a bit rate, if this platform supports it
+        pub fn baud_rate_from_bps(bps: u32) -> Option<BaudRate> {
+            match bps {
+                $($(#[$cfg])* $bps => Some(BaudRate::$variant),)*
+                _ => None,
+            }
+        }
+
+        /// Get the bit rate of a [BaudRate]
+        pub fn bps_from_baud_rate(baud: BaudRate) -> Option<u32> {
+            match baud {
+                $($(#[$cfg])* BaudRate::$variant => Some($bps),)*
+                #[allow(unreachable_patterns)]
+                _ => None,
+            }
+        }
+    };
+}
+
+baud_table! {
+    0 => B0,
+    50 => B50,
+    75 => B75,
+    110 => B110,
+    134 => B134,
+    150 => B150,
+    200 => B200,
+    300 => B300,
+    600 => B600,
+    1200 => B1200,
+    1800 => B1800,
+    2400 => B2400,
+    4800 => B4800,
+    #[cfg(bsd)]
+    7200 => B7200,
+    9600 => B9600,
+    #[cfg(bsd)]
+    14400 => B14400,
+    19200 => B19200,
+    #[cfg(bsd)]
+    28800 => B28800,
+    38400 => B38400,
+    57600 => B57600,
+    #[cfg(bsd)]
+    76800 => B76800,
+    115200 => B115200,
+    230400 => B230400,
+    #[cfg(any(linux_android, target_os = "freebsd", target_os = "netbsd"))]
+    460800 => B460800,
+    #[cfg(linux_android)]
+    500000 => B500000,
+    #[cfg(linux_android)]
+    576000 => B576000,
+    #[cfg(any(linux_android, target_os = "freebsd", target_os = "netbsd"))]
+    921600 => B921600,
+    #[cfg(linux_android)]
+    1000000 => B1000000,
+    #[cfg(linux_android)]
+    1152000 => B1152000,
+    #[cfg(linux_android)]
+    1500000 => B1500000,
+    #[cfg(linux_android)]
+    2000000 => B2000000,
+    #[cfg(all(linux_android, not(target_arch = "sparc64")))]
+    2500000 => B2500000,
+    #[cfg(all(linux_android, not(target_arch = "sparc64")))]
+    3000000 => B3000000,
+    #[cfg(all(linux_android, not(target_arch = "sparc64")))]
+    3500000 => B3500000,
+    #[cfg(all(linux_android, not(target_arch = "sparc64")))]
+    4000000 => B4000000,
+}
+
+/// Set both input and output speed
+///
+/// The BSDs have `cfsetspeed`, Linux only has the POSIX pair
+pub(crate) fn set_speed(termios: &mut Termios, baud: BaudRate) -> Result {
+    #[cfg(bsd)]
+    termios::cfsetspeed(termios, baud)?;
+    #[cfg(not(bsd))]
+    {
+        termios::cfsetispeed(termios, baud)?;
+        termios::cfsetospeed(termios, baud)?;
+    }
+    Ok(())
+}
+
+/// Get the output speed
+///
+/// Returns `None` if the speed isn't one of the standard rates (possible on
+/// the BSDs, where arbitrary rates are allowed)
+pub fn get_speed(termios: &Termios) -> Option<BaudRate> {
+    #[cfg(bsd)]
+    return BaudRate::try_from(termios::cfgetospeed(termios) as nix::libc::speed_t).ok();
+    #[cfg(not(bsd))]
+    return Some(termios::cfgetospeed(termios));
+}
+
+// Credit: Pavel Kuzmin (license: MIT)
+// https://github.com/s00d/virtualport/blob/ad3809c28ad942d8036e01f5669e5214d698c178/src/pty.rs
+pub(crate) fn set_nonblocking(fd: RawFd) -> Result {
+    use nix::fcntl::{F_GETFL, F_SETFL, OFlag, fcntl};
+    let flags = fcntl(fd, F_GETFL)?;
+    let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    fcntl(fd, F_SETFL(new_flags))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_rates_round_trip() {
+        for bps in [0, 300, 1200, 9600, 19200, 38400, 57600, 115200, 230400] {
+            let baud = baud_rate_from_bps(bps).unwrap();
+            assert_eq!(bps_from_baud_rate(baud), Some(bps));
+        }
+    }
+
+    #[test]
+    fn nonstandard_rate() {
+        assert_eq!(baud_rate_from_bps(12345), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_high_rates() {
+        assert_eq!(baud_rate_from_bps(4000000), Some(BaudRate::B4000000));
+        assert_eq!(bps_from_baud_rate(BaudRate::B921600), Some(921600));
+    }
+
+    #[test]
+    fn set_and_get_speed() {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let mut termios = termios::tcgetattr(&pty.slave).unwrap();
+        set_speed(&mut termios, BaudRate::B9600).unwrap();
+        assert_eq!(get_speed(&termios), Some(BaudRate::B9600));
+    }
+}
@@ -0,0 +1,153 @@
+//! Many virtual ports managed together, e.g. to simulate a multi-port
+//! serial server
+use crate::{Error, Result, VirtSer, VirtSerBuilder};
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Named set of [VirtSer]s that are torn down together
+///
+/// Dropping the pool closes every port and removes their symlinks
+#[derive(Debug)]
+pub struct VirtSerPool {
+    builder: VirtSerBuilder,
+    symlink_dir: Option<PathBuf>,
+    ports: BTreeMap<String, VirtSer>,
+}
+
+impl VirtSerPool {
+    /// Create an empty pool whose ports are built with `builder`
+    #[must_use]
+    pub fn new(builder: VirtSerBuilder) -> Self {
+        Self {
+            builder,
+            symlink_dir: None,
+            ports: BTreeMap::new(),
+        }
+    }
+
+    /// Give each port a symlink named after it in `dir`
+    #[must_use]
+    pub fn set_symlink_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.symlink_dir = Some(dir.into());
+        self
+    }
+
+    /// Create ports `{prefix}0` to `{prefix}{count - 1}`
+    pub fn create_many(&mut self, prefix: &str, count: usize) -> Result {
+        for i in 0..count {
+            self.create(format!("{prefix}{i}"))?;
+        }
+        Ok(())
+    }
+
+    /// Create a port called `name`
+    pub fn create(&mut self, name: impl Into<String>) -> Result<&mut VirtSer> {
+        let name = name.into();
+        if self.ports.contains_key(&name) {
+            return Err(Error::Generic(format!("Port {name} already exists")));
+        }
+
+        let mut ser = self.builder.build()?;
+        if let Some(dir) = &self.symlink_dir {
+            ser.create_symlink(dir.join(&name))?;
+        }
+        Ok(self.ports.entry(name).or_insert(ser))
+    }
+
+    /// Get the port called `name`
+    pub fn get(&self, name: &str) -> Option<&VirtSer> {
+        self.ports.get(name)
+    }
+
+    /// Get the port called `name` mutably
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut VirtSer> {
+        self.ports.get_mut(name)
+    }
+
+    /// Take the port called `name` out of the pool
+    pub fn remove(&mut self, name: &str) -> Option<VirtSer> {
+        self.ports.remove(name)
+    }
+
+    /// Names of all ports, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.ports.keys().map(String::as_str)
+    }
+
+    /// All ports with their names, in order
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut VirtSer)> {
+        self.ports
+            .iter_mut()
+            .map(|(name, ser)| (name.as_str(), ser))
+    }
+
+    /// Number of ports
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// True if there are no ports
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    /// Close every port and remove their symlinks
+    pub fn close_all(&mut self) {
+        self.ports.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("virtser-pool-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn symlinks_torn_down_together() {
+        let dir = temp_dir("teardown");
+        let mut pool = VirtSerPool::new(VirtSerBuilder::new()).set_symlink_dir(&dir);
+        pool.create_many("ttyS", 3).unwrap();
+        assert_eq!(
+            pool.names().collect::<Vec<_>>(),
+            ["ttyS0", "ttyS1", "ttyS2"]
+        );
+        for name in ["ttyS0", "ttyS1", "ttyS2"] {
+            assert_eq!(
+                std::fs::read_link(dir.join(name)).unwrap(),
+                pool.get(name).unwrap().path()
+            );
+        }
+
+        drop(pool);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn ports_are_independent() {
+        let mut pool = VirtSerPool::new(VirtSerBuilder::new().set_nonblocking(false));
+        pool.create("a").unwrap();
+        pool.create("b").unwrap();
+        assert!(pool.create("a").is_err());
+
+        let mut app = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(pool.get("b").unwrap().path())
+            .unwrap();
+        app.write_all(b"to b").unwrap();
+        let mut buf = [0; 4];
+        pool.get_mut("b").unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"to b");
+
+        assert!(pool.remove("a").is_some());
+        assert_eq!(pool.len(), 1);
+        pool.close_all();
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! What to do when a write to a nonblocking port would block
+use std::time::Duration;
+
+/// How [VirtSer](crate::VirtSer) retries writes that hit `WouldBlock`
+///
+/// Once retries run out the write fails with `WouldBlock` as usual
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Return `WouldBlock` straight away
+    #[default]
+    FailFast,
+    /// Retry without sleeping, yielding the thread between attempts
+    Immediate { max_retries: u32 },
+    /// Sleep `interval` between attempts
+    Fixed {
+        interval: Duration,
+        max_retries: u32,
+    },
+    /// Sleep `initial`, doubling each attempt up to `max`
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        max_retries: u32,
+    },
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry` (counting from 0), or
+    /// `None` to give up
+    pub fn delay(&self, retry: u32) -> Option<Duration> {
+        match *self {
+            Self::FailFast => None,
+            Self::Immediate { max_retries } => (retry < max_retries).then_some(Duration::ZERO),
+            Self::Fixed {
+                interval,
+                max_retries,
+            } => (retry < max_retries).then_some(interval),
+            Self::Exponential {
+                initial,
+                max,
+                max_retries,
+            } => (retry < max_retries).then(|| initial.saturating_mul(1 << retry.min(31)).min(max)),
+        }
+    }
+
+    /// Call `op` until it doesn't fail with `WouldBlock` or retries run out
+    pub(crate) fn run<T>(&self, mut op: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+        let mut retry = 0;
+        loop {
+            match op() {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    let delay = self.delay(retry).ok_or(err)?;
+                    if delay.is_zero() {
+                        std::thread::yield_now();
+                    } else {
+                        std::thread::sleep(delay);
+                    }
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        assert_eq!(RetryPolicy::FailFast.delay(0), None);
+
+        let immediate = RetryPolicy::Immediate { max_retries: 2 };
+        assert_eq!(immediate.delay(1), Some(Duration::ZERO));
+        assert_eq!(immediate.delay(2), None);
+
+        let ms = Duration::from_millis;
+        let exponential = RetryPolicy::Exponential {
+            initial: ms(1),
+            max: ms(5),
+            max_retries: 10,
+        };
+        let delays: Vec<_> = (0..5).map(|i| exponential.delay(i).unwrap()).collect();
+        assert_eq!(delays, [ms(1), ms(2), ms(4), ms(5), ms(5)]);
+        assert_eq!(exponential.delay(10), None);
+    }
+
+    #[test]
+    fn run_gives_up() {
+        let mut attempts = 0;
+        let result: std::io::Result<()> = RetryPolicy::Fixed {
+            interval: Duration::from_millis(1),
+            max_retries: 3,
+        }
+        .run(|| {
+            attempts += 1;
+            Err(std::io::ErrorKind::WouldBlock.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(attempts, 4);
+    }
+}
@@ -0,0 +1,219 @@
+//! Helpers for integration tests against serial links
+//!
+//! [Harness] wraps a nonblocking port (a [VirtSer](crate::VirtSer),
+//! [MemoryPort](crate::backend::MemoryPort), or a real device) with
+//! deadline-based assertions and records a transcript of everything that
+//! crossed it, so failures are easy to diagnose.
+use crate::util::{self, POLL_INTERVAL};
+use crate::{Error, Result};
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+/// Which way a transcript entry went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Traffic {
+    Sent(Vec<u8>),
+    Received(Vec<u8>),
+}
+
+/// One chunk of traffic and when it happened, relative to harness creation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub at: Duration,
+    pub traffic: Traffic,
+}
+
+/// Test driver for one end of a serial link
+#[derive(Debug)]
+pub struct Harness<P> {
+    port: P,
+    /// Received but not yet consumed by an expectation
+    pending: Vec<u8>,
+    transcript: Vec<Entry>,
+    start: Instant,
+}
+
+impl<P: Read + Write> Harness<P> {
+    /// Wrap `port`, which must be nonblocking for timeouts to work
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            pending: Vec::new(),
+            transcript: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Send `data`
+    pub fn send(&mut self, data: &[u8]) -> Result {
+        util::write_all(&mut self.port, data)?;
+        self.record(Traffic::Sent(data.to_vec()));
+        Ok(())
+    }
+
+    /// Read exactly `len` bytes within `timeout`
+    pub fn read_bytes(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        while self.pending.len() < len {
+            self.fill(deadline)?;
+        }
+        Ok(self.pending.drain(..len).collect())
+    }
+
+    /// Expect the next bytes received within `timeout` to be `expected`
+    pub fn expect_bytes(&mut self, expected: &[u8], timeout: Duration) -> Result {
+        let received = self.read_bytes(expected.len(), timeout)?;
+        if received != expected {
+            return Err(Error::Generic(format!(
+                "Expected {expected:02x?}, received {received:02x?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read until `pattern` has been received, returning everything up to
+    /// and including it
+    pub fn drain_until(&mut self, pattern: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let found = if pattern.is_empty() {
+                Some(0)
+            } else {
+                self.pending
+                    .windows(pattern.len())
+                    .position(|window| window == pattern)
+                    .map(|start| start + pattern.len())
+            };
+            if let Some(end) = found {
+                return Ok(self.pending.drain(..end).collect());
+            }
+            self.fill(deadline)?;
+        }
+    }
+
+    /// Expect nothing new to be received for `duration`
+    pub fn expect_silence(&mut self, duration: Duration) -> Result {
+        let before = self.pending.len();
+        match self.fill(Instant::now() + duration) {
+            Err(Error::Timeout) => Ok(()),
+            Err(err) => Err(err),
+            Ok(()) => Err(Error::Generic(format!(
+                "Expected silence, received {:02x?}",
+                &self.pending[before..]
+            ))),
+        }
+    }
+
+    /// Everything sent and received so far
+    pub fn transcript(&self) -> &[Entry] {
+        &self.transcript
+    }
+
+    /// The transcript as a timestamped hex dump, one line per chunk
+    pub fn transcript_text(&self) -> String {
+        let mut out = String::new();
+        for entry in &self.transcript {
+            let (direction, data) = match &entry.traffic {
+                Traffic::Sent(data) => ("->", data),
+                Traffic::Received(data) => ("<-", data),
+            };
+            out.push_str(&util::hex_log_line(entry.at, direction, data));
+        }
+        out
+    }
+
+    /// Get the wrapped port
+    pub fn get_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    /// Unwrap the port, discarding anything received but not consumed
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    /// Read whatever is available into `pending`, failing at `deadline`
+    fn fill(&mut self, deadline: Instant) -> Result {
+        let mut buf = [0; 1024];
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => return Err(Error::Generic("Unexpected end of stream".into())),
+                Ok(n) => {
+                    self.pending.extend_from_slice(&buf[..n]);
+                    self.record(Traffic::Received(buf[..n].to_vec()));
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn record(&mut self, traffic: Traffic) {
+        self.transcript.push(Entry {
+            at: self.start.elapsed(),
+            traffic,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryPort;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn expectations() {
+        let (port, mut device) = MemoryPort::pair();
+        let mut harness = Harness::new(port);
+
+        device.write_all(b"boot ok\r\nlogin: ").unwrap();
+        assert_eq!(
+            harness.drain_until(b"\r\n", TIMEOUT).unwrap(),
+            b"boot ok\r\n"
+        );
+        harness.expect_bytes(b"login: ", TIMEOUT).unwrap();
+        harness.expect_silence(Duration::from_millis(10)).unwrap();
+
+        device.write_all(b"x").unwrap();
+        assert!(harness.expect_bytes(b"y", TIMEOUT).is_err());
+        assert!(matches!(
+            harness.expect_bytes(b"z", Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn transcript_records_both_directions() {
+        let (port, mut device) = MemoryPort::pair();
+        let mut harness = Harness::new(port);
+
+        harness.send(b"AT\r").unwrap();
+        let mut buf = [0; 3];
+        device.read_exact(&mut buf).unwrap();
+        device.write_all(b"OK").unwrap();
+        harness.expect_bytes(b"OK", TIMEOUT).unwrap();
+
+        let traffic: Vec<_> = harness.transcript().iter().map(|e| &e.traffic).collect();
+        assert_eq!(
+            traffic,
+            [
+                &Traffic::Sent(b"AT\r".to_vec()),
+                &Traffic::Received(b"OK".to_vec())
+            ]
+        );
+        let text = harness.transcript_text();
+        assert!(text.contains("-> 41 54 0d\n"));
+        assert!(text.contains("<- 4f 4b\n"));
+    }
+}
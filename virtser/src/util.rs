@@ -0,0 +1,82 @@
+//! Helpers for ports that may be nonblocking
+use crate::{Error, Result};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::time::{Duration, Instant};
+
+/// How long to sleep between polls of a nonblocking port
+pub const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+nix::ioctl_read_bad!(fionread, nix::libc::FIONREAD, nix::libc::c_int);
+
+/// Number of bytes waiting to be read from `fd`
+pub(crate) fn bytes_queued(fd: impl AsFd) -> Result<usize> {
+    let mut count = 0;
+    unsafe { fionread(fd.as_fd().as_raw_fd(), &mut count) }?;
+    Ok(count as usize)
+}
+
+/// Number of bytes waiting to be read from the slave side of a PTY,
+/// counting any the kernel's still moving over from the master
+pub(crate) fn pty_bytes_queued(slave: impl AsFd) -> Result<usize> {
+    // Polling a terminal hands it any input still on its way
+    let mut fds = [PollFd::new(slave.as_fd(), PollFlags::POLLIN)];
+    poll(&mut fds, PollTimeout::ZERO)?;
+    bytes_queued(slave)
+}
+
+/// Read a single byte, giving up at `deadline`
+///
+/// The deadline only has teeth for nonblocking ports - a blocking port
+/// waits as long as its read does
+pub(crate) fn read_byte(port: &mut impl Read, deadline: Instant) -> Result<u8> {
+    let mut buf = [0; 1];
+    loop {
+        match port.read(&mut buf) {
+            Ok(1) => return Ok(buf[0]),
+            Ok(_) => return Err(Error::Generic("Unexpected end of stream".into())),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(Error::Timeout);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Write all of `data`, sleeping while a nonblocking port is full
+pub fn write_all(port: &mut impl Write, mut data: &[u8]) -> Result {
+    while !data.is_empty() {
+        match port.write(data) {
+            Ok(0) => return Err(Error::Generic("Peer stopped accepting data".into())),
+            Ok(n) => data = &data[n..],
+            Err(err) if err.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// A line of a traffic log: seconds since it started, which way `data`
+/// went, and `data` in hex
+pub fn hex_log_line(elapsed: Duration, direction: &str, data: &[u8]) -> String {
+    let mut line = format!("{:10.3} {direction}", elapsed.as_secs_f64());
+    for byte in data {
+        line.push_str(&format!(" {byte:02x}"));
+    }
+    line.push('\n');
+    line
+}
+
+/// Fill `buf`, giving up at `deadline`
+pub(crate) fn read_exact(port: &mut impl Read, buf: &mut [u8], deadline: Instant) -> Result {
+    for byte in buf.iter_mut() {
+        *byte = read_byte(port, deadline)?;
+    }
+    Ok(())
+}
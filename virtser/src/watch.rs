@@ -0,0 +1,145 @@
+//! Notification when the slave side changes its terminal settings
+//!
+//! There's no portable way to be told when the application on the PTS calls
+//! `tcsetattr`, so [TermiosWatcher] polls `tcgetattr` from a background thread
+//! and reports changes.
+use crate::{Result, platform};
+use nix::sys::termios::{self, ControlFlags, InputFlags, LocalFlags, OutputFlags, Termios};
+use std::{
+    fs::File,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// Snapshot of a terminal's configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TermiosSettings {
+    /// Bit rate, or `None` if it isn't a standard rate
+    pub baud: Option<u32>,
+    pub input_flags: InputFlags,
+    pub output_flags: OutputFlags,
+    pub control_flags: ControlFlags,
+    pub local_flags: LocalFlags,
+}
+
+impl TermiosSettings {
+    /// Read the current settings of `file`
+    pub fn of(file: &File) -> Result<Self> {
+        Ok(Self::from(&termios::tcgetattr(file)?))
+    }
+}
+
+impl From<&Termios> for TermiosSettings {
+    fn from(termios: &Termios) -> Self {
+        Self {
+            baud: platform::get_speed(termios).and_then(platform::bps_from_baud_rate),
+            input_flags: termios.input_flags,
+            output_flags: termios.output_flags,
+            control_flags: termios.control_flags,
+            local_flags: termios.local_flags,
+        }
+    }
+}
+
+/// Background thread reporting termios changes; stops when dropped
+#[derive(Debug)]
+pub struct TermiosWatcher {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TermiosWatcher {
+    /// Poll `file` every `interval`, calling `on_change` with the new
+    /// settings whenever they differ from the last poll
+    pub fn new(
+        file: File,
+        interval: Duration,
+        mut on_change: impl FnMut(TermiosSettings) + Send + 'static,
+    ) -> Result<Self> {
+        let mut last = TermiosSettings::of(&file)?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    // The terminal going away just ends the watch
+                    let Ok(settings) = TermiosSettings::of(&file) else {
+                        return;
+                    };
+                    if settings != last {
+                        on_change(settings.clone());
+                        last = settings;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for TermiosWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtSerBuilder;
+    use nix::sys::termios::{BaudRate, SetArg};
+    use std::sync::mpsc;
+
+    #[test]
+    fn reports_baud_change() {
+        let ser = VirtSerBuilder::new().build().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _watcher = ser
+            .watch_termios(Duration::from_millis(5), move |settings| {
+                let _ = tx.send(settings);
+            })
+            .unwrap();
+
+        // Pretend to be the application on the slave side
+        let app = File::options()
+            .read(true)
+            .write(true)
+            .open(ser.path())
+            .unwrap();
+        let mut termios = termios::tcgetattr(&app).unwrap();
+        platform::set_speed(&mut termios, BaudRate::B9600).unwrap();
+        termios.local_flags.insert(LocalFlags::ECHO);
+        termios::tcsetattr(&app, SetArg::TCSANOW, &termios).unwrap();
+
+        let settings = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(settings.baud, Some(9600));
+        assert!(settings.local_flags.contains(LocalFlags::ECHO));
+    }
+
+    #[test]
+    fn quiet_when_unchanged() {
+        let ser = VirtSerBuilder::new().build().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let watcher = ser
+            .watch_termios(Duration::from_millis(1), move |settings| {
+                let _ = tx.send(settings);
+            })
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        drop(watcher);
+        assert!(rx.try_recv().is_err());
+    }
+}
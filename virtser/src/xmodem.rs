@@ -0,0 +1,502 @@
+//! XMODEM and YMODEM file transfer
+//!
+//! Works over anything `Read + Write`, including [crate::VirtSer]. Both ends
+//! prefer CRC-16 mode and fall back to the original 8-bit checksum if the
+//! other end doesn't support it.
+use crate::util::{read_byte, read_exact};
+use crate::{Error, Result};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_REQUEST: u8 = b'C';
+/// Times the receiver asks for CRC mode before falling back to checksums
+const CRC_ATTEMPTS: u32 = 3;
+/// Padding for the final block
+const SUB: u8 = 0x1A;
+
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+/// Payload size of each block
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockSize {
+    /// 128 bytes, the original XMODEM block
+    Standard,
+    /// 1024 bytes (XMODEM-1K / YMODEM)
+    OneK,
+}
+
+impl BlockSize {
+    const fn len(self) -> usize {
+        match self {
+            Self::Standard => 128,
+            Self::OneK => 1024,
+        }
+    }
+
+    const fn header(self) -> u8 {
+        match self {
+            Self::Standard => SOH,
+            Self::OneK => STX,
+        }
+    }
+}
+
+/// How blocks are checked
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Check {
+    Checksum,
+    Crc16,
+}
+
+impl Check {
+    const fn len(self) -> usize {
+        match self {
+            Self::Checksum => 1,
+            Self::Crc16 => 2,
+        }
+    }
+
+    fn trailer(self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Checksum => vec![payload.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))],
+            Self::Crc16 => CRC.checksum(payload).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// XMODEM/YMODEM transfer settings
+#[derive(Copy, Clone, Debug)]
+pub struct Xmodem {
+    block_size: BlockSize,
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl Xmodem {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            block_size: BlockSize::Standard,
+            timeout: Duration::from_secs(10),
+            max_retries: 10,
+        }
+    }
+
+    /// Set the block size used when sending
+    ///
+    /// The receiver accepts either size regardless
+    #[must_use]
+    pub const fn set_block_size(mut self, block_size: BlockSize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set how long to wait for each response
+    #[must_use]
+    pub const fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many times a block is retried before giving up
+    #[must_use]
+    pub const fn set_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Send `data` with XMODEM
+    pub fn send(&self, port: &mut (impl Read + Write), data: &[u8]) -> Result {
+        let check = self.wait_for_start(port)?;
+        self.send_data(port, data, check, self.block_size)
+    }
+
+    /// Receive a file with XMODEM
+    ///
+    /// XMODEM has no notion of file length, so the result includes the
+    /// padding of the final block
+    pub fn receive(&self, port: &mut (impl Read + Write)) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.receive_data(port, &mut data, false)?;
+        Ok(data)
+    }
+
+    /// Send a single named file with YMODEM
+    pub fn send_ymodem(&self, port: &mut (impl Read + Write), name: &str, data: &[u8]) -> Result {
+        let mut header = Vec::new();
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(data.len().to_string().as_bytes());
+        header.push(0);
+        if header.len() > BlockSize::OneK.len() {
+            return Err(Error::Generic("YMODEM: file name too long".into()));
+        }
+        let header_size = if header.len() > BlockSize::Standard.len() {
+            BlockSize::OneK
+        } else {
+            BlockSize::Standard
+        };
+        header.resize(header_size.len(), 0);
+
+        let check = self.wait_for_start(port)?;
+        self.send_block(port, header_size, 0, &header, check)?;
+
+        let check = self.wait_for_start(port)?;
+        self.send_data(port, data, check, self.block_size)?;
+
+        // Empty block 0 ends the batch
+        let check = self.wait_for_start(port)?;
+        let terminator = [0; 128];
+        self.send_block(port, BlockSize::Standard, 0, &terminator, check)
+    }
+
+    /// Receive a single named file with YMODEM, returning its name and contents
+    pub fn receive_ymodem(&self, port: &mut (impl Read + Write)) -> Result<(String, Vec<u8>)> {
+        let header = self.receive_header(port)?;
+        let mut fields = header.split(|byte| *byte == 0);
+        let name = String::from_utf8_lossy(fields.next().unwrap_or_default()).into_owned();
+        if name.is_empty() {
+            return Err(Error::Generic("YMODEM: empty batch".into()));
+        }
+        // Size may be followed by space-separated mtime/mode fields
+        let size: Option<usize> = fields
+            .next()
+            .and_then(|field| std::str::from_utf8(field).ok())
+            .and_then(|field| field.split(' ').next())
+            .and_then(|size| size.parse().ok());
+
+        let mut data = Vec::new();
+        self.receive_data(port, &mut data, true)?;
+        if let Some(size) = size {
+            data.truncate(size);
+        }
+
+        // Consume the end-of-batch block
+        self.receive_header(port)?;
+
+        Ok((name, data))
+    }
+
+    fn deadline(&self) -> Instant {
+        Instant::now() + self.timeout
+    }
+
+    /// Wait for the receiver to ask us to start
+    fn wait_for_start(&self, port: &mut impl Read) -> Result<Check> {
+        for _ in 0..self.max_retries {
+            match read_byte(port, self.deadline()) {
+                Ok(CRC_REQUEST) => return Ok(Check::Crc16),
+                Ok(NAK) => return Ok(Check::Checksum),
+                Ok(CAN) => return Err(cancelled()),
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn send_data(
+        &self,
+        port: &mut (impl Read + Write),
+        data: &[u8],
+        check: Check,
+        block_size: BlockSize,
+    ) -> Result {
+        for (index, chunk) in data.chunks(block_size.len()).enumerate() {
+            let mut payload = chunk.to_vec();
+            payload.resize(block_size.len(), SUB);
+            // Block numbers start at 1 and wrap
+            let number = (index + 1) as u8;
+            self.send_block(port, block_size, number, &payload, check)?;
+        }
+
+        for _ in 0..self.max_retries {
+            port.write_all(&[EOT])?;
+            match read_byte(port, self.deadline()) {
+                Ok(ACK) => return Ok(()),
+                Ok(CAN) => return Err(cancelled()),
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn send_block(
+        &self,
+        port: &mut (impl Read + Write),
+        block_size: BlockSize,
+        number: u8,
+        payload: &[u8],
+        check: Check,
+    ) -> Result {
+        let mut frame = vec![block_size.header(), number, !number];
+        frame.extend_from_slice(payload);
+        frame.extend(check.trailer(payload));
+
+        for _ in 0..self.max_retries {
+            port.write_all(&frame)?;
+            match read_byte(port, self.deadline()) {
+                Ok(ACK) => return Ok(()),
+                Ok(CAN) => return Err(cancelled()),
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Read the remainder of a block after its header byte
+    ///
+    /// Returns `None` if the block is corrupt
+    fn read_block(
+        &self,
+        port: &mut impl Read,
+        header: u8,
+        check: Check,
+    ) -> Result<Option<(u8, Vec<u8>)>> {
+        let len = if header == STX {
+            BlockSize::OneK.len()
+        } else {
+            BlockSize::Standard.len()
+        };
+        let mut numbers = [0; 2];
+        read_exact(port, &mut numbers, self.deadline())?;
+        let mut payload = vec![0; len];
+        read_exact(port, &mut payload, self.deadline())?;
+        let mut trailer = vec![0; check.len()];
+        read_exact(port, &mut trailer, self.deadline())?;
+
+        if numbers[0] != !numbers[1] || trailer != check.trailer(&payload) {
+            return Ok(None);
+        }
+        Ok(Some((numbers[0], payload)))
+    }
+
+    /// Receive YMODEM block 0
+    fn receive_header(&self, port: &mut (impl Read + Write)) -> Result<Vec<u8>> {
+        for _ in 0..self.max_retries {
+            port.write_all(&[CRC_REQUEST])?;
+            match read_byte(port, self.deadline()) {
+                Ok(header @ (SOH | STX)) => {
+                    if let Some((0, payload)) = self.read_block(port, header, Check::Crc16)? {
+                        port.write_all(&[ACK])?;
+                        return Ok(payload);
+                    }
+                }
+                Ok(CAN) => return Err(cancelled()),
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Receive data blocks until EOT
+    ///
+    /// YMODEM senders expect their first EOT to be NAKed, guarding against
+    /// line noise ending the transfer early
+    fn receive_data(
+        &self,
+        port: &mut (impl Read + Write),
+        data: &mut Vec<u8>,
+        ymodem: bool,
+    ) -> Result {
+        let mut expected: u8 = 1;
+        let mut retries = 0;
+        let mut started = false;
+        let mut eot_seen = false;
+        // Ask for CRC mode until the sender starts or we give up on it
+        let mut check = Check::Crc16;
+        let mut prompt = CRC_REQUEST;
+
+        port.write_all(&[prompt])?;
+        loop {
+            if retries >= self.max_retries {
+                port.write_all(&[CAN, CAN])?;
+                return Err(Error::Timeout);
+            }
+
+            match read_byte(port, self.deadline()) {
+                Ok(header @ (SOH | STX)) => {
+                    started = true;
+                    prompt = NAK;
+                    match self.read_block(port, header, check)? {
+                        Some((number, payload)) if number == expected => {
+                            data.extend_from_slice(&payload);
+                            expected = expected.wrapping_add(1);
+                            retries = 0;
+                            port.write_all(&[ACK])?;
+                        }
+                        // Our ACK got lost and the sender repeated itself
+                        Some((number, _)) if number == expected.wrapping_sub(1) => {
+                            port.write_all(&[ACK])?;
+                        }
+                        Some((number, _)) => {
+                            port.write_all(&[CAN, CAN])?;
+                            return Err(Error::Generic(format!(
+                                "XMODEM: out of sequence block {number}, expected {expected}"
+                            )));
+                        }
+                        None => {
+                            retries += 1;
+                            port.write_all(&[NAK])?;
+                        }
+                    }
+                }
+                Ok(EOT) if ymodem && !eot_seen => {
+                    eot_seen = true;
+                    port.write_all(&[NAK])?;
+                }
+                Ok(EOT) => {
+                    port.write_all(&[ACK])?;
+                    return Ok(());
+                }
+                Ok(CAN) => return Err(cancelled()),
+                Ok(_) => {}
+                Err(Error::Timeout) => {
+                    retries += 1;
+                    if !started && check == Check::Crc16 && retries >= CRC_ATTEMPTS {
+                        check = Check::Checksum;
+                        prompt = NAK;
+                    }
+                    port.write_all(&[prompt])?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Default for Xmodem {
+    fn default() -> Self {
+        Xmodem::new()
+    }
+}
+
+fn cancelled() -> Error {
+    Error::Generic("Transfer cancelled by peer".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryPort;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(CRC.checksum(b"123456789"), 0x31c3);
+    }
+
+    fn frame(number: u8, payload: &[u8], check: Check) -> Vec<u8> {
+        let mut frame = vec![SOH, number, !number];
+        frame.extend_from_slice(payload);
+        frame.extend(check.trailer(payload));
+        frame
+    }
+
+    fn expect(port: &mut MemoryPort, byte: u8) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(read_byte(port, deadline).unwrap(), byte);
+    }
+
+    #[test]
+    fn checksum_fallback() {
+        let data = test_data(128);
+        let (mut ser, mut slave) = MemoryPort::pair();
+
+        let receiver = std::thread::spawn(move || {
+            Xmodem::new()
+                .set_timeout(Duration::from_millis(50))
+                .receive(&mut ser)
+        });
+
+        // A sender that only knows checksums ignores requests for CRC mode
+        for _ in 0..CRC_ATTEMPTS {
+            expect(&mut slave, CRC_REQUEST);
+        }
+        expect(&mut slave, NAK);
+        slave.write_all(&frame(1, &data, Check::Checksum)).unwrap();
+        expect(&mut slave, ACK);
+        slave.write_all(&[EOT]).unwrap();
+        expect(&mut slave, ACK);
+
+        assert_eq!(receiver.join().unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn ymodem_double_eot() {
+        let data = test_data(128);
+        let (mut ser, mut slave) = MemoryPort::pair();
+
+        let receiver = std::thread::spawn(move || Xmodem::new().receive_ymodem(&mut ser));
+
+        let mut header = b"a.bin\x00128\x00".to_vec();
+        header.resize(128, 0);
+        expect(&mut slave, CRC_REQUEST);
+        slave.write_all(&frame(0, &header, Check::Crc16)).unwrap();
+        expect(&mut slave, ACK);
+        expect(&mut slave, CRC_REQUEST);
+        slave.write_all(&frame(1, &data, Check::Crc16)).unwrap();
+        expect(&mut slave, ACK);
+
+        slave.write_all(&[EOT]).unwrap();
+        expect(&mut slave, NAK);
+        slave.write_all(&[EOT]).unwrap();
+        expect(&mut slave, ACK);
+
+        expect(&mut slave, CRC_REQUEST);
+        slave.write_all(&frame(0, &[0; 128], Check::Crc16)).unwrap();
+        expect(&mut slave, ACK);
+
+        let (name, received) = receiver.join().unwrap().unwrap();
+        assert_eq!(name, "a.bin");
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn xmodem_round_trip() {
+        let data = test_data(1000);
+        let (mut ser, mut slave) = MemoryPort::pair();
+
+        let sent = data.clone();
+        let sender = std::thread::spawn(move || Xmodem::new().send(&mut slave, &sent));
+        let received = Xmodem::new().receive(&mut ser).unwrap();
+        sender.join().unwrap().unwrap();
+
+        // Padded up to a whole number of blocks
+        assert_eq!(received.len(), 1024);
+        assert_eq!(&received[..data.len()], data);
+        assert!(received[data.len()..].iter().all(|byte| *byte == SUB));
+    }
+
+    #[test]
+    fn ymodem_round_trip() {
+        let data = test_data(3000);
+        let (mut ser, mut slave) = MemoryPort::pair();
+
+        let sent = data.clone();
+        let sender = std::thread::spawn(move || {
+            Xmodem::new().set_block_size(BlockSize::OneK).send_ymodem(
+                &mut slave,
+                "firmware.bin",
+                &sent,
+            )
+        });
+        let (name, received) = Xmodem::new().receive_ymodem(&mut ser).unwrap();
+        sender.join().unwrap().unwrap();
+
+        assert_eq!(name, "firmware.bin");
+        assert_eq!(received, data);
+    }
+}